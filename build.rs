@@ -0,0 +1,8 @@
+fn main() {
+    // The daemon's gRPC API
+    #[cfg(feature = "daemon")]
+    {
+        println!("cargo:rerun-if-changed=proto/rastos/v1/daemon.proto");
+        tonic_build::compile_protos("proto/rastos/v1/daemon.proto").expect("failed to compile the daemon API");
+    }
+}
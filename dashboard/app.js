@@ -0,0 +1,308 @@
+// rastOS dashboard
+//
+// Everything shown comes from the daemon's REST API. Signing in exchanges an
+// API key for a token carrying the read scopes the key is allowed; the token
+// is kept for the browser session only.
+
+"use strict";
+
+const READ_SCOPES = ["update:read", "snapshot:read", "backup:read", "container:read"];
+const TOKEN_KEY = "rastos.token";
+
+const $ = (selector) => document.querySelector(selector);
+
+// Element with text content; never parses markup
+function el(tag, text, className) {
+  const node = document.createElement(tag);
+  if (text !== undefined && text !== null) {
+    node.textContent = String(text);
+  }
+  if (className) {
+    node.className = className;
+  }
+  return node;
+}
+
+function badge(text, kind) {
+  return el("span", text, `badge ${kind}`);
+}
+
+function table(headers, rows) {
+  const node = el("table");
+  const head = node.createTHead().insertRow();
+  for (const header of headers) {
+    head.appendChild(el("th", header));
+  }
+  const body = node.createTBody();
+  for (const row of rows) {
+    const tr = body.insertRow();
+    for (const cell of row) {
+      const td = tr.insertCell();
+      td.append(cell instanceof Node ? cell : document.createTextNode(cell ?? ""));
+    }
+  }
+  return node;
+}
+
+function formatTime(rfc3339) {
+  const time = new Date(rfc3339);
+  return Number.isNaN(time.getTime()) ? rfc3339 : time.toLocaleString();
+}
+
+function formatSize(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let size = bytes;
+  let unit = 0;
+  while (size >= 1024 && unit < units.length - 1) {
+    size /= 1024;
+    unit += 1;
+  }
+  return `${size.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+// Nested list of `items`, each placed below the item `parentOf` names
+function tree(items, keyOf, parentOf, label) {
+  const children = new Map();
+  const keys = new Set(items.map(keyOf));
+  for (const item of items) {
+    const parent = keys.has(parentOf(item)) ? parentOf(item) : null;
+    if (!children.has(parent)) {
+      children.set(parent, []);
+    }
+    children.get(parent).push(item);
+  }
+  const build = (parent, seen) => {
+    const list = el("ul");
+    for (const item of children.get(parent) ?? []) {
+      const key = keyOf(item);
+      if (seen.has(key)) {
+        continue;
+      }
+      const li = el("li");
+      li.append(...label(item));
+      if (children.has(key)) {
+        li.appendChild(build(key, new Set([...seen, key])));
+      }
+      list.appendChild(li);
+    }
+    return list;
+  };
+  const root = build(null, new Set());
+  root.className = "tree";
+  return root;
+}
+
+class Unauthorized extends Error {}
+
+async function api(path, options = {}) {
+  const token = sessionStorage.getItem(TOKEN_KEY);
+  const headers = { "Content-Type": "application/json" };
+  if (token) {
+    headers.Authorization = `Bearer ${token}`;
+  }
+  const response = await fetch(path, { ...options, headers });
+  if (response.status === 401) {
+    throw new Unauthorized();
+  }
+  const body = await response.json().catch(() => null);
+  if (!response.ok) {
+    const error = new Error(body?.error ?? response.statusText);
+    error.status = response.status;
+    throw error;
+  }
+  return body;
+}
+
+async function exchange(service, apiKey, scopes) {
+  return api("/v1/auth/token", {
+    method: "POST",
+    body: JSON.stringify({ service, api_key: apiKey, scopes }),
+  });
+}
+
+// Token with every read scope the key is allowed
+async function signIn(service, apiKey) {
+  try {
+    return await exchange(service, apiKey, READ_SCOPES);
+  } catch (error) {
+    if (error.status !== 403) {
+      throw error;
+    }
+  }
+  const allowed = [];
+  for (const scope of READ_SCOPES) {
+    try {
+      await exchange(service, apiKey, [scope]);
+      allowed.push(scope);
+    } catch (error) {
+      if (error.status !== 403) {
+        throw error;
+      }
+    }
+  }
+  if (allowed.length === 0) {
+    throw new Error("The key may not read anything");
+  }
+  return exchange(service, apiKey, allowed);
+}
+
+function renderUpdates(status) {
+  const nodes = [];
+  const summary = el("p");
+  summary.append("Booted from ", el("code", status.booted), " ");
+  summary.append(status.pending ? badge("update pending reboot", "accent") : badge("up to date", "ok"));
+  nodes.push(summary);
+  if (status.deployments.length === 0) {
+    nodes.push(el("p", "No deployments", "empty"));
+    return nodes;
+  }
+  nodes.push(
+    tree(
+      status.deployments,
+      (d) => d.subvolume,
+      (d) => d.parent,
+      (d) => {
+        const parts = [el("code", d.subvolume), ` kernel ${d.kernel}, ${formatTime(d.created_at)} `];
+        if (d.subvolume === status.booted) {
+          parts.push(badge("booted", "ok"));
+        }
+        return parts;
+      },
+    ),
+  );
+  return nodes;
+}
+
+// Snapshots grouped by the name they were taken of, such as `@home` for `@home-20240101`
+function renderSnapshots(snapshots) {
+  if (snapshots.length === 0) {
+    return [el("p", "No snapshots", "empty")];
+  }
+  const groups = new Map();
+  for (const snapshot of snapshots) {
+    const base = snapshot.name.replace(/[-_.]?\d[\d\-_.T:]*$/, "") || snapshot.name;
+    if (!groups.has(base)) {
+      groups.set(base, []);
+    }
+    groups.get(base).push(snapshot);
+  }
+  const list = el("ul");
+  list.className = "tree";
+  for (const [base, members] of groups) {
+    const li = el("li");
+    li.append(el("code", base), el("span", ` ${members.length}`, "muted"));
+    const sub = el("ul");
+    for (const snapshot of members) {
+      const item = el("li");
+      item.append(el("code", snapshot.name), el("span", ` ${snapshot.path}`, "muted"));
+      sub.appendChild(item);
+    }
+    li.appendChild(sub);
+    list.appendChild(li);
+  }
+  return [list];
+}
+
+function renderBackups(backups) {
+  if (backups.length === 0) {
+    return [el("p", "No backups", "empty")];
+  }
+  const newest = [...backups].sort((a, b) => b.created_at.localeCompare(a.created_at));
+  const total = backups.reduce((sum, backup) => sum + backup.size, 0);
+  return [
+    el("p", `${backups.length} backups, ${formatSize(total)}`, "muted"),
+    table(
+      ["Created", "Subvolume", "Size", "Type", "Description"],
+      newest.map((backup) => [
+        formatTime(backup.created_at),
+        backup.subvolume,
+        formatSize(backup.size),
+        backup.incremental ? badge(`incremental of ${backup.parent_id ?? "?"}`, "accent") : badge("full", "ok"),
+        backup.description ?? "",
+      ]),
+    ),
+  ];
+}
+
+function renderContainers(containers) {
+  if (containers.length === 0) {
+    return [el("p", "No containers", "empty")];
+  }
+  const kinds = { running: "ok", error: "bad" };
+  return [
+    table(
+      ["ID", "State", "Bundle"],
+      containers.map((c) => [c.id, badge(c.state, kinds[c.state] ?? "accent"), c.bundle]),
+    ),
+  ];
+}
+
+const SECTIONS = [
+  ["#updates", "/v1/updates", renderUpdates],
+  ["#snapshots", "/v1/snapshots", renderSnapshots],
+  ["#backups", "/v1/backups", renderBackups],
+  ["#containers", "/v1/containers", renderContainers],
+];
+
+async function refresh() {
+  await Promise.all(
+    SECTIONS.map(async ([selector, path, render]) => {
+      const body = $(`${selector} .body`);
+      try {
+        body.replaceChildren(...render(await api(path)));
+      } catch (error) {
+        if (error instanceof Unauthorized) {
+          throw error;
+        }
+        const message = error.status === 403 ? "Not allowed for this key" : error.message;
+        body.replaceChildren(el("p", message, error.status === 403 ? "muted" : "error"));
+      }
+    }),
+  ).catch((error) => {
+    if (error instanceof Unauthorized) {
+      showSignIn("The session has expired");
+    }
+  });
+}
+
+function showSignIn(message = "") {
+  sessionStorage.removeItem(TOKEN_KEY);
+  $("#dashboard").hidden = true;
+  $("#refresh").hidden = true;
+  $("#sign-out").hidden = true;
+  $("#sign-in").hidden = false;
+  $("#sign-in-error").textContent = message;
+}
+
+function showDashboard() {
+  $("#sign-in").hidden = true;
+  $("#dashboard").hidden = false;
+  $("#refresh").hidden = false;
+  $("#sign-out").hidden = false;
+  refresh();
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+  $("#host").textContent = location.host;
+
+  $("#sign-in").addEventListener("submit", async (event) => {
+    event.preventDefault();
+    const form = new FormData(event.target);
+    try {
+      const token = await signIn(form.get("service"), form.get("api_key"));
+      sessionStorage.setItem(TOKEN_KEY, token.token);
+      event.target.reset();
+      showDashboard();
+    } catch (error) {
+      showSignIn(error instanceof Unauthorized ? "Unknown service or key" : error.message);
+    }
+  });
+  $("#refresh").addEventListener("click", refresh);
+  $("#sign-out").addEventListener("click", () => showSignIn());
+
+  if (sessionStorage.getItem(TOKEN_KEY)) {
+    showDashboard();
+  } else {
+    showSignIn();
+  }
+});
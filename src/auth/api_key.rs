@@ -0,0 +1,411 @@
+//! API keys: storage, validation and rotation
+
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+use thiserror::Error;
+
+use crate::auth::ApiKeyConfig;
+
+/// Remaining lifetime below which use of an expiring key is reported
+const EXPIRY_REMINDER_SECS: i64 = 24 * 60 * 60;
+
+/// Shortest time between two reminders about the same key
+const REMINDER_INTERVAL_SECS: i64 = 60 * 60;
+
+/// Error type for authentication operations
+#[derive(Error, Debug)]
+pub enum AuthError {
+    /// Missing API key
+    #[error("API key is required but not provided")]
+    MissingApiKey,
+    
+    /// Invalid API key
+    #[error("Invalid API key")]
+    InvalidApiKey,
+    
+    /// Malformed token or bad signature
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    
+    /// Token past its expiry time
+    #[error("Token has expired")]
+    ExpiredToken,
+    
+    /// Scope not granted to the key or token
+    #[error("Scope not granted: {0}")]
+    InsufficientScope(String),
+    
+    /// Remote connection without a client certificate
+    #[error("Remote connections require a client certificate")]
+    CertificateRequired,
+    
+    /// Client certificate that is not pinned
+    #[error("Client certificate not trusted: {0}")]
+    UntrustedCertificate(String),
+    
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    
+    /// Other errors
+    #[error("Authentication error: {0}")]
+    Other(String),
+}
+
+/// Result type for authentication operations
+pub type Result<T> = std::result::Result<T, AuthError>;
+
+/// Represents an API key with associated metadata
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    /// The actual key value
+    pub key: String,
+    /// Service this key is for (e.g., "backup", "llm")
+    pub service: String,
+    /// Optional description
+    pub description: Option<String>,
+    /// Optional expiration timestamp (UNIX timestamp)
+    pub expires_at: Option<i64>,
+}
+
+/// Manages API keys for different services
+#[derive(Default)]
+pub struct ApiKeyManager {
+    keys: RwLock<HashMap<String, ApiKey>>,
+    config_path: Option<PathBuf>,
+    /// When each expiring key was last reported as still in use
+    reminded: Mutex<HashMap<String, i64>>,
+}
+
+impl ApiKeyManager {
+    /// Create a new ApiKeyManager
+    pub fn new() -> Self {
+        Self {
+            keys: RwLock::new(HashMap::new()),
+            config_path: None,
+            reminded: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Set the configuration file that [`rotate`](Self::rotate) updates
+    pub fn with_config_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+    
+    /// Add a new API key
+    pub fn add_key(&self, key: ApiKey) -> Result<()> {
+        let mut keys = self.keys.write().map_err(|e| AuthError::Other(e.to_string()))?;
+        keys.insert(key.key.clone(), key);
+        Ok(())
+    }
+    
+    /// Remove an API key
+    pub fn remove_key(&self, key: &str) -> Result<()> {
+        let mut keys = self.keys.write().map_err(|e| AuthError::Other(e.to_string()))?;
+        keys.remove(key);
+        Ok(())
+    }
+    
+    /// Validate an API key for a specific service
+    pub fn validate_key(&self, key: &str, service: &str) -> Result<()> {
+        let keys = self.keys.read().map_err(|e| AuthError::Other(e.to_string()))?;
+        
+        if let Some(api_key) = keys.get(key) {
+            // Check if key is for the correct service
+            if api_key.service != service {
+                return Err(AuthError::InvalidApiKey);
+            }
+            
+            // Check if key has expired
+            if let Some(expires_at) = api_key.expires_at {
+                let now = unix_time()?;
+                
+                if now > expires_at {
+                    return Err(AuthError::Other("API key has expired".to_string()));
+                }
+                if expires_at - now <= EXPIRY_REMINDER_SECS {
+                    self.remind(api_key, expires_at - now, now);
+                }
+            }
+            
+            Ok(())
+        } else {
+            Err(AuthError::InvalidApiKey)
+        }
+    }
+    
+    /// Replace the primary key of a service with a new random key
+    ///
+    /// The old key stays valid for the grace period set in the configuration
+    /// file, so clients can switch over without an outage. The configuration
+    /// file given to [`with_config_file`](Self::with_config_file) is
+    /// replaced atomically, and this manager accepts the new key at once.
+    /// Returns the new key.
+    pub fn rotate(&self, service: &str) -> Result<String> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| AuthError::Other("No configuration file to rotate keys in".to_string()))?;
+        let mut config = ApiKeyConfig::from_file(path)?;
+        let new_key = generate_key();
+        let retired = config.rotate_key(service, &new_key, unix_time()?)?;
+        config.save_to_file(path)?;
+        
+        self.add_key(ApiKey {
+            key: new_key.clone(),
+            service: service.to_string(),
+            description: Some("Primary key from rotation".to_string()),
+            expires_at: None,
+        })?;
+        if let Some((old_key, expires_at)) = retired {
+            let mut keys = self.keys.write().map_err(|e| AuthError::Other(e.to_string()))?;
+            keys.entry(old_key.clone())
+                .or_insert_with(|| ApiKey {
+                    key: old_key,
+                    service: service.to_string(),
+                    description: Some("Retiring key".to_string()),
+                    expires_at: None,
+                })
+                .expires_at = Some(expires_at);
+        }
+        
+        info!("Rotated the primary API key for service '{}'", service);
+        Ok(new_key)
+    }
+    
+    /// Warn that an expiring key is still in use, at most once per interval
+    fn remind(&self, api_key: &ApiKey, remaining: i64, now: i64) {
+        let Ok(mut reminded) = self.reminded.lock() else {
+            return;
+        };
+        let last = reminded.get(&api_key.key).copied();
+        if last.is_some_and(|last| now - last < REMINDER_INTERVAL_SECS) {
+            return;
+        }
+        reminded.insert(api_key.key.clone(), now);
+        warn!(
+            "{} for service '{}' expires in {} minutes but is still in use; switch to the current key",
+            api_key.description.as_deref().unwrap_or("API key"),
+            api_key.service,
+            remaining / 60
+        );
+    }
+}
+
+/// Generate a random API key
+pub fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Current time as a UNIX timestamp
+fn unix_time() -> Result<i64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .map_err(|_| AuthError::Other("System time is before UNIX_EPOCH".to_string()))
+}
+
+/// Get an API key from environment variables
+/// 
+/// # Arguments
+/// * `env_var` - The environment variable name to look for
+/// * `service` - The service this key is for (for validation)
+/// * `key_manager` - The ApiKeyManager to validate against
+/// 
+/// # Returns
+/// The API key if found and valid, or an error
+pub fn get_api_key_from_env(
+    env_var: &str, 
+    service: &str, 
+    key_manager: &ApiKeyManager
+) -> Result<String> {
+    let key = env::var(env_var)
+        .map_err(|_| AuthError::MissingApiKey)?;
+    
+    key_manager.validate_key(&key, service)?;
+    
+    Ok(key)
+}
+
+/// Get an API key from command line arguments or environment variables
+/// 
+/// # Arguments
+/// * `arg_key` - The API key from command line arguments (if any)
+/// * `env_var` - The environment variable name to fall back to
+/// * `service` - The service this key is for (for validation)
+/// * `key_manager` - The ApiKeyManager to validate against
+/// 
+/// # Returns
+/// The API key if found and valid, or an error
+pub fn get_api_key(
+    arg_key: Option<String>,
+    env_var: &str,
+    service: &str,
+    key_manager: &ApiKeyManager
+) -> Result<String> {
+    if let Some(key) = arg_key {
+        key_manager.validate_key(&key, service)?;
+        return Ok(key);
+    }
+    
+    get_api_key_from_env(env_var, service, key_manager)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    
+    #[test]
+    fn test_api_key_validation() {
+        let manager = ApiKeyManager::new();
+        let key = "test-key".to_string();
+        
+        // Add a test key
+        manager.add_key(ApiKey {
+            key: key.clone(),
+            service: "backup".to_string(),
+            description: Some("Test key".to_string()),
+            expires_at: None,
+        }).unwrap();
+        
+        // Test valid key
+        assert!(manager.validate_key(&key, "backup").is_ok());
+        
+        // Test invalid service
+        assert!(matches!(
+            manager.validate_key(&key, "llm"),
+            Err(AuthError::InvalidApiKey)
+        ));
+        
+        // Test non-existent key
+        assert!(matches!(
+            manager.validate_key("invalid-key", "backup"),
+            Err(AuthError::InvalidApiKey)
+        ));
+    }
+    
+    #[test]
+    fn test_expired_key() {
+        let manager = ApiKeyManager::new();
+        let key = "expired-key".to_string();
+        
+        // Add an expired key (expired 1 hour ago)
+        let one_hour_ago = (std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64) - 3600;
+            
+        manager.add_key(ApiKey {
+            key: key.clone(),
+            service: "backup".to_string(),
+            description: Some("Expired key".to_string()),
+            expires_at: Some(one_hour_ago),
+        }).unwrap();
+        
+        // Should fail with expired key
+        assert!(matches!(
+            manager.validate_key(&key, "backup"),
+            Err(AuthError::Other(msg)) if msg.contains("expired")
+        ));
+    }
+    
+    #[test]
+    fn test_get_api_key_from_env() {
+        let manager = ApiKeyManager::new();
+        let test_key = "test-env-key";
+        
+        // Add a test key
+        manager.add_key(ApiKey {
+            key: test_key.to_string(),
+            service: "backup".to_string(),
+            description: None,
+            expires_at: None,
+        }).unwrap();
+        
+        // Set up test environment
+        // SAFETY: tests do not read the environment from other threads
+        unsafe { env::set_var("TEST_API_KEY", test_key) };
+        
+        // Test getting key from env
+        let key = get_api_key_from_env("TEST_API_KEY", "backup", &manager).unwrap();
+        assert_eq!(key, test_key);
+        
+        // Clean up
+        // SAFETY: as above
+        unsafe { env::remove_var("TEST_API_KEY") };
+        
+        // Test missing env var
+        assert!(matches!(
+            get_api_key_from_env("NON_EXISTENT_VAR", "backup", &manager),
+            Err(AuthError::MissingApiKey)
+        ));
+    }
+    
+    #[test]
+    fn test_get_api_key() {
+        let manager = ApiKeyManager::new();
+        let test_key = "test-arg-key";
+        
+        // Add a test key
+        manager.add_key(ApiKey {
+            key: test_key.to_string(),
+            service: "llm".to_string(),
+            description: None,
+            expires_at: None,
+        }).unwrap();
+        
+        // Test getting key from argument
+        let key = get_api_key(
+            Some(test_key.to_string()),
+            "LLM_API_KEY",
+            "llm",
+            &manager
+        ).unwrap();
+        assert_eq!(key, test_key);
+        
+        // Test getting key from env when arg is None
+        // SAFETY: tests do not read the environment from other threads
+        unsafe { env::set_var("LLM_API_KEY", test_key) };
+        let key = get_api_key(None, "LLM_API_KEY", "llm", &manager).unwrap();
+        assert_eq!(key, test_key);
+        // SAFETY: as above
+        unsafe { env::remove_var("LLM_API_KEY") };
+        
+        // Test missing key
+        assert!(matches!(
+            get_api_key(None, "LLM_API_KEY", "llm", &manager),
+            Err(AuthError::MissingApiKey)
+        ));
+    }
+    
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.toml");
+        std::fs::write(&path, "[keys.backup]\nprimary = \"old-key\"\n").unwrap();
+        
+        let manager = ApiKeyManager::new().with_config_file(&path);
+        ApiKeyConfig::from_file(&path).unwrap().add_to_manager(&manager).unwrap();
+        let new_key = manager.rotate("backup").unwrap();
+        
+        // Both keys are accepted during the grace period
+        assert!(manager.validate_key(&new_key, "backup").is_ok());
+        assert!(manager.validate_key("old-key", "backup").is_ok());
+        
+        let config = ApiKeyConfig::from_file(&path).unwrap();
+        assert_eq!(config.keys["backup"].primary.as_ref(), Some(&new_key));
+        assert_eq!(config.keys["backup"].retiring[0].key.as_deref(), Some("old-key"));
+        
+        assert!(ApiKeyManager::new().rotate("backup").is_err());
+    }
+}
@@ -1,11 +1,17 @@
 //! Command-line interface for managing API keys
 
 use clap::{Args, Subcommand};
+use serde::Serialize;
 use std::path::PathBuf;
 
-use crate::auth::{
-    ApiKey, ApiKeyConfig, ApiKeyManager, AuthError, ConfigError,
-};
+use crate::auth::{ApiKeyConfig, ApiKeyManager, ConfigError};
+use crate::auth::expiry::ExpiryMonitor;
+use crate::auth::mtls::CertificateAuthority;
+use crate::auth::sealing::{MachineKey, SealKind};
+use crate::output::Output;
+
+/// Remaining lifetime below which a key is listed as expiring
+const EXPIRING_SECS: i64 = 7 * 24 * 60 * 60;
 
 /// CLI commands for API key management
 #[derive(Debug, Subcommand)]
@@ -13,14 +19,115 @@ pub enum ApiKeyCommand {
     /// Add a new API key
     Add(AddKeyArgs),
     
-    /// List all API keys
-    List,
+    /// List API keys with their expiry status
+    List(ListKeyArgs),
     
     /// Remove an API key
     Remove(RemoveKeyArgs),
     
     /// Generate a new random API key
     Generate(GenerateKeyArgs),
+    
+    /// Replace the primary key of a service, keeping the old one valid for a grace period
+    Rotate(RotateKeyArgs),
+    
+    /// Encrypt the configuration file with the machine key, or decrypt it again
+    Encrypt(EncryptConfigArgs),
+    
+    /// Report keys that expire soon and run their renewal hooks
+    Check(CheckKeysArgs),
+    
+    /// Manage client certificates for remote management
+    #[command(subcommand)]
+    Cert(CertCommand),
+}
+
+/// CLI commands for client certificates
+#[derive(Debug, Subcommand)]
+pub enum CertCommand {
+    /// Create the certificate authority for client certificates
+    InitCa(InitCaArgs),
+    
+    /// Issue a client certificate and pin it
+    Issue(IssueCertArgs),
+    
+    /// Pin a client certificate issued elsewhere
+    Pin(PinCertArgs),
+    
+    /// Stop accepting a client's certificate
+    Revoke(RevokeCertArgs),
+}
+
+/// Arguments for creating the certificate authority
+#[derive(Debug, Args)]
+pub struct InitCaArgs {
+    /// Name of the certificate authority; by default it names this machine
+    #[arg(long)]
+    pub name: Option<String>,
+    
+    /// Validity in days
+    #[arg(long, default_value_t = crate::auth::mtls::DEFAULT_CA_DAYS)]
+    pub days: u32,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
+/// Arguments for issuing a client certificate
+#[derive(Debug, Args)]
+pub struct IssueCertArgs {
+    /// Name of the client (e.g., "laptop")
+    #[arg(short, long)]
+    pub name: String,
+    
+    /// Validity in days
+    #[arg(long, default_value_t = crate::auth::mtls::DEFAULT_CLIENT_DAYS)]
+    pub days: u32,
+    
+    /// Scopes granted to the client
+    #[arg(long = "scope")]
+    pub scopes: Vec<String>,
+    
+    /// Directory to write the certificate and key to
+    #[arg(short, long, default_value = ".")]
+    pub out_dir: PathBuf,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
+/// Arguments for pinning a client certificate
+#[derive(Debug, Args)]
+pub struct PinCertArgs {
+    /// Name of the client
+    #[arg(short, long)]
+    pub name: String,
+    
+    /// The client's certificate, PEM or DER
+    #[arg(long)]
+    pub cert: PathBuf,
+    
+    /// Scopes granted to the client
+    #[arg(long = "scope")]
+    pub scopes: Vec<String>,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
+/// Arguments for revoking a client certificate
+#[derive(Debug, Args)]
+pub struct RevokeCertArgs {
+    /// Name of the client
+    #[arg(short, long)]
+    pub name: String,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
 }
 
 /// Arguments for adding an API key
@@ -50,11 +157,65 @@ pub struct AddKeyArgs {
     #[arg(long)]
     pub env_var: Option<String>,
     
+    /// Keep the key in the secret store under this name instead of in the
+    /// configuration file; the key becomes the primary key
+    #[arg(long, value_name = "NAME")]
+    pub secret: Option<String>,
+    
     /// Path to the API key configuration file
     #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
     pub config: PathBuf,
 }
 
+/// Arguments for listing API keys
+#[derive(Debug, Args)]
+pub struct ListKeyArgs {
+    /// Only list keys for this service
+    #[arg(short, long)]
+    pub service: Option<String>,
+    
+    /// Only list keys that have expired
+    #[arg(long)]
+    pub expired: bool,
+    
+    /// List only this configuration file, instead of the merged system,
+    /// user and environment configuration
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+/// A key as shown by `auth key list`
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyListing {
+    /// Service the key is for
+    pub service: String,
+    /// `primary`, `additional`, `retiring` or `environment`
+    pub role: &'static str,
+    /// The key with all but its first and last characters hidden
+    pub key: String,
+    /// Where the key is kept: `config`, `secret:<name>` or `env:<variable>`
+    pub source: String,
+    /// Description of the key
+    pub description: Option<String>,
+    /// Expiry time (UNIX timestamp)
+    pub expires_at: Option<i64>,
+    /// `active`, `expiring`, `expired` or `unavailable`
+    pub status: &'static str,
+    /// Scopes tokens for the service may carry
+    pub scopes: Vec<String>,
+}
+
+/// A key as printed by `auth key generate` and `auth key rotate`
+#[derive(Debug, Clone, Serialize)]
+pub struct NewKey {
+    /// Service the key is for
+    pub service: String,
+    /// The new key
+    pub key: String,
+    /// Until when the replaced key is accepted (UNIX timestamp)
+    pub previous_expires_at: Option<i64>,
+}
+
 /// Arguments for removing an API key
 #[derive(Debug, Args)]
 pub struct RemoveKeyArgs {
@@ -95,20 +256,60 @@ pub struct GenerateKeyArgs {
     pub config: PathBuf,
 }
 
+/// Arguments for rotating an API key
+#[derive(Debug, Args)]
+pub struct RotateKeyArgs {
+    /// The service whose primary key to replace
+    #[arg(short, long)]
+    pub service: String,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
+/// Arguments for encrypting the configuration file
+#[derive(Debug, Args)]
+pub struct EncryptConfigArgs {
+    /// Seal the machine key to the TPM2 instead of keeping it in a root-only file
+    #[arg(long, conflicts_with = "decrypt")]
+    pub tpm2: bool,
+    
+    /// Store the configuration as plain text again
+    #[arg(long)]
+    pub decrypt: bool,
+    
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
+/// Arguments for checking key expiry
+#[derive(Debug, Args)]
+pub struct CheckKeysArgs {
+    /// Path to the API key configuration file
+    #[arg(long, default_value = "/etc/rast/auth/keys.toml")]
+    pub config: PathBuf,
+}
+
 /// Handle API key management commands
-pub async fn handle_api_key_command(cmd: ApiKeyCommand) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn handle_api_key_command(cmd: ApiKeyCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
     match cmd {
-        ApiKeyCommand::Add(args) => handle_add_key(args).await?,
-        ApiKeyCommand::List => handle_list_keys().await?,
-        ApiKeyCommand::Remove(args) => handle_remove_key(args).await?,
-        ApiKeyCommand::Generate(args) => handle_generate_key(args).await?,
+        ApiKeyCommand::Add(args) => handle_add_key(args, output).await?,
+        ApiKeyCommand::List(args) => handle_list_keys(args, output).await?,
+        ApiKeyCommand::Remove(args) => handle_remove_key(args, output).await?,
+        ApiKeyCommand::Generate(args) => handle_generate_key(args, output).await?,
+        ApiKeyCommand::Rotate(args) => handle_rotate_key(args, output).await?,
+        ApiKeyCommand::Encrypt(args) => handle_encrypt_config(args, output).await?,
+        ApiKeyCommand::Check(args) => handle_check_keys(args, output).await?,
+        ApiKeyCommand::Cert(cmd) => handle_cert_command(cmd, output).await?,
     }
     
     Ok(())
 }
 
 /// Handle adding a new API key
-async fn handle_add_key(args: AddKeyArgs) -> Result<(), Box<dyn std::error::Error>> {
+async fn handle_add_key(args: AddKeyArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
     // Load or create config
     let mut config = if args.config.exists() {
         ApiKeyConfig::from_file(&args.config)?
@@ -125,9 +326,9 @@ async fn handle_add_key(args: AddKeyArgs) -> Result<(), Box<dyn std::error::Erro
         key
     } else {
         // Generate a random key if none provided
-        use rand::RngCore;
+        use rand_core::{OsRng, RngCore};
         let mut key = vec![0u8; 32];
-        rand::thread_rng().fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut key);
         base64::encode_config(&key, base64::URL_SAFE_NO_PAD)
     };
     
@@ -135,21 +336,30 @@ async fn handle_add_key(args: AddKeyArgs) -> Result<(), Box<dyn std::error::Erro
     let expires_at = if let Some(expires) = &args.expires {
         use chrono::NaiveDate;
         let date = NaiveDate::parse_from_str(expires, "%Y-%m-%d")?;
-        Some(date.and_hms_opt(0, 0, 0).unwrap().timestamp())
+        Some(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
     } else {
         None
     };
     
+    // Store the key outside the configuration if requested
+    if let Some(name) = &args.secret {
+        config.secret_store()?.set(name, key.as_bytes())?;
+    }
+    
     // Add the key to the service
     let service_entry = config.keys.entry(args.service.clone()).or_default();
     
-    if args.primary || service_entry.primary.is_none() {
+    if let Some(name) = &args.secret {
+        service_entry.secret = Some(name.clone());
+        service_entry.expires_at = expires_at;
+    } else if args.primary || service_entry.primary.is_none() {
         // Set as primary key
         if let Some(old_primary) = service_entry.primary.take() {
             // Move old primary to additional keys
             service_entry.additional.push(old_primary);
         }
         service_entry.primary = Some(key.clone());
+        service_entry.expires_at = expires_at;
     } else {
         // Add as additional key
         service_entry.additional.push(key.clone());
@@ -163,30 +373,145 @@ async fn handle_add_key(args: AddKeyArgs) -> Result<(), Box<dyn std::error::Erro
     // Save the configuration
     config.save_to_file(&args.config)?;
     
-    println!("Added {} key for service '{}'", 
-        if args.primary { "primary" } else { "additional" }, 
+    output.status(format!("Added {} key for service '{}'", 
+        if args.primary || args.secret.is_some() { "primary" } else { "additional" }, 
         args.service
-    );
+    ));
     
     if let Some(expires_at) = expires_at {
-        use chrono::NaiveDateTime;
-        let dt = NaiveDateTime::from_timestamp_opt(expires_at, 0).unwrap();
-        println!("Key expires at: {}", dt.format("%Y-%m-%d %H:%M:%S"));
+        use chrono::DateTime;
+        let dt = DateTime::from_timestamp(expires_at, 0).unwrap();
+        output.status(format!("Key expires at: {}", dt.format("%Y-%m-%d %H:%M:%S")));
     }
     
     Ok(())
 }
 
 /// Handle listing API keys
-async fn handle_list_keys() -> Result<(), Box<dyn std::error::Error>> {
-    // For now, just list the keys in memory
-    // In a real implementation, we would load from the config file
-    println!("Listing API keys is not yet implemented");
+async fn handle_list_keys(args: ListKeyArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match &args.config {
+        Some(path) => ApiKeyConfig::from_file(path)?,
+        None => ApiKeyConfig::load()?,
+    };
+    let now = chrono::Utc::now().timestamp();
+    let listings: Vec<KeyListing> = list_keys(&config, now)
+        .into_iter()
+        .filter(|k| args.service.as_ref().is_none_or(|s| &k.service == s))
+        .filter(|k| !args.expired || k.status == "expired")
+        .collect();
+    
+    output.print(&listings, || {
+        if listings.is_empty() {
+            println!("No API keys found");
+        }
+        let mut service = None;
+        for listing in &listings {
+            if service != Some(&listing.service) {
+                service = Some(&listing.service);
+                if listing.scopes.is_empty() {
+                    println!("{}", listing.service);
+                } else {
+                    println!("{} (scopes: {})", listing.service, listing.scopes.join(", "));
+                }
+            }
+            let expiry = match listing.expires_at {
+                Some(expires_at) => {
+                    use chrono::DateTime;
+                    let dt = DateTime::from_timestamp(expires_at, 0).unwrap();
+                    format!("{} {}", listing.status, dt.format("%Y-%m-%d %H:%M"))
+                }
+                None => listing.status.to_string(),
+            };
+            println!(
+                "  {:<11} {:<14} {:<24} {:<26} {}",
+                listing.role,
+                listing.key,
+                listing.source,
+                expiry,
+                listing.description.as_deref().unwrap_or("")
+            );
+        }
+    })?;
+    
     Ok(())
 }
 
+/// All keys in the configuration, by service
+///
+/// Keys kept in the secret store are read to mask them; keys that cannot be
+/// read are listed as unavailable. Environment variables overriding a
+/// service's key are listed when they are set.
+pub fn list_keys(config: &ApiKeyConfig, now: i64) -> Vec<KeyListing> {
+    let store = config.secret_store().ok();
+    let read_secret = |name: &str| store.as_ref().and_then(|store| store.get_string(name).ok());
+    let mut services: Vec<_> = config.keys.iter().collect();
+    services.sort_by(|a, b| a.0.cmp(b.0));
+    
+    let mut listings = Vec::new();
+    for (service, keys) in services {
+        let mut push = |role, key: Option<String>, source: String, description: Option<String>, expires_at| {
+            let status = match (&key, expires_at) {
+                (None, _) => "unavailable",
+                (_, Some(expires_at)) if expires_at <= now => "expired",
+                (_, Some(expires_at)) if expires_at - now <= EXPIRING_SECS => "expiring",
+                _ => "active",
+            };
+            listings.push(KeyListing {
+                service: service.clone(),
+                role,
+                key: key.as_deref().map_or_else(|| "-".to_string(), mask_key),
+                source,
+                description,
+                expires_at,
+                status,
+                scopes: keys.scopes.clone(),
+            });
+        };
+        
+        if let Some(env_var) = &keys.env_var {
+            if let Ok(key) = std::env::var(env_var) {
+                push("environment", Some(key), format!("env:{}", env_var), None, None);
+            }
+        }
+        match (&keys.secret, &keys.primary) {
+            (Some(name), _) => push("primary", read_secret(name), format!("secret:{}", name), None, keys.expires_at),
+            (None, Some(key)) => push("primary", Some(key.clone()), "config".to_string(), None, keys.expires_at),
+            (None, None) => {}
+        }
+        for key in &keys.additional {
+            push("additional", Some(key.clone()), "config".to_string(), None, None);
+        }
+        for retiring in &keys.retiring {
+            let (key, source) = match (&retiring.key, &retiring.secret) {
+                (Some(key), _) => (Some(key.clone()), "config".to_string()),
+                (None, Some(name)) => (read_secret(name), format!("secret:{}", name)),
+                (None, None) => continue,
+            };
+            push(
+                "retiring",
+                key,
+                source,
+                Some("Replaced by rotation".to_string()),
+                Some(retiring.expires_at),
+            );
+        }
+    }
+    listings
+}
+
+/// Hide all but the first and last four characters of a key
+fn mask_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 12 {
+        return "*".repeat(chars.len().min(8));
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
 /// Handle removing an API key
-async fn handle_remove_key(args: RemoveKeyArgs) -> Result<(), Box<dyn std::error::Error>> {
+async fn handle_remove_key(args: RemoveKeyArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
     // Load config
     if !args.config.exists() {
         return Err(Box::new(std::io::Error::new(
@@ -202,15 +527,15 @@ async fn handle_remove_key(args: RemoveKeyArgs) -> Result<(), Box<dyn std::error
         if args.all {
             // Remove all keys for the service
             config.keys.remove(&args.service);
-            println!("Removed all keys for service '{}'", args.service);
+            output.status(format!("Removed all keys for service '{}'", args.service));
         } else if let Some(key) = &args.key {
             // Remove a specific key
             if keys.primary.as_ref() == Some(key) {
                 keys.primary = None;
-                println!("Removed primary key for service '{}'", args.service);
+                output.status(format!("Removed primary key for service '{}'", args.service));
             } else if let Some(pos) = keys.additional.iter().position(|k| k == key) {
                 keys.additional.remove(pos);
-                println!("Removed additional key for service '{}'", args.service);
+                output.status(format!("Removed additional key for service '{}'", args.service));
             } else {
                 return Err(Box::new(std::io::Error::new(
                     std::io::ErrorKind::NotFound,
@@ -237,15 +562,16 @@ async fn handle_remove_key(args: RemoveKeyArgs) -> Result<(), Box<dyn std::error
 }
 
 /// Handle generating a new API key
-async fn handle_generate_key(args: GenerateKeyArgs) -> Result<(), Box<dyn std::error::Error>> {
-    use rand::RngCore;
+async fn handle_generate_key(args: GenerateKeyArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    use rand_core::{OsRng, RngCore};
     
     // Generate a random key
     let mut key = vec![0u8; args.length];
-    rand::thread_rng().fill_bytes(&mut key);
+    OsRng.fill_bytes(&mut key);
     let key = base64::encode_config(&key, base64::URL_SAFE_NO_PAD);
     
-    println!("Generated API key: {}", key);
+    let generated = NewKey { service: args.service.clone(), key: key.clone(), previous_expires_at: None };
+    output.print(&generated, || println!("Generated API key: {}", key))?;
     
     if args.save {
         // Add the key to the config
@@ -256,10 +582,124 @@ async fn handle_generate_key(args: GenerateKeyArgs) -> Result<(), Box<dyn std::e
             expires: None,
             primary: false,
             env_var: None,
+            secret: None,
             config: args.config,
         };
         
-        handle_add_key(add_args).await?;
+        handle_add_key(add_args, output).await?;
+    }
+    
+    Ok(())
+}
+
+/// Handle rotating an API key
+async fn handle_rotate_key(args: RotateKeyArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    let manager = ApiKeyManager::new().with_config_file(&args.config);
+    let key = manager.rotate(&args.service)?;
+    let config = ApiKeyConfig::from_file(&args.config)?;
+    
+    let previous_expires_at = config.keys.get(&args.service).and_then(|keys| keys.retiring.last()).map(|r| r.expires_at);
+    let rotated = NewKey { service: args.service.clone(), key, previous_expires_at };
+    output.print(&rotated, || {
+        println!("New primary key for service '{}': {}", rotated.service, rotated.key);
+        if let Some(expires_at) = rotated.previous_expires_at {
+            use chrono::DateTime;
+            let dt = DateTime::from_timestamp(expires_at, 0).unwrap();
+            println!("The previous key is accepted until {}", dt.format("%Y-%m-%d %H:%M:%S"));
+        }
+    })?;
+    
+    Ok(())
+}
+
+/// Handle encrypting or decrypting the configuration file
+async fn handle_encrypt_config(args: EncryptConfigArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    let config = ApiKeyConfig::from_file(&args.config)?;
+    
+    if args.decrypt {
+        // Write directly; save_to_file would seal the file again
+        crate::fs::atomic_write(&args.config, toml::to_string_pretty(&config)?)
+            .map_err(std::io::Error::from)?;
+        output.status(format!("Decrypted {}", args.config.display()));
+    } else {
+        let kind = if args.tpm2 { SealKind::Tpm2 } else { SealKind::KeyFile };
+        let key = MachineKey::system(kind);
+        config.save_sealed(&args.config, &key)?;
+        output.status(format!("Encrypted {} with machine key {}", args.config.display(), key.path().display()));
+    }
+    
+    Ok(())
+}
+
+/// Handle checking key expiry
+async fn handle_check_keys(args: CheckKeysArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    let config = ApiKeyConfig::from_file(&args.config)?;
+    let monitor = ExpiryMonitor::new(&args.config, &config.expiry);
+    let expiring = tokio::task::spawn_blocking(move || monitor.check(chrono::Utc::now().timestamp())).await??;
+    
+    output.print(&expiring, || {
+        if expiring.is_empty() {
+            println!("No keys expire within {} days", config.expiry.warn_days);
+        }
+        for key in &expiring {
+            println!("{}", key.message());
+        }
+    })?;
+    
+    Ok(())
+}
+
+/// Load the configuration to change, or start a new one
+fn load_or_default(path: &std::path::Path) -> Result<ApiKeyConfig, ConfigError> {
+    if path.exists() {
+        ApiKeyConfig::from_file(path)
+    } else {
+        Ok(ApiKeyConfig::default())
+    }
+}
+
+/// Handle client certificate commands
+async fn handle_cert_command(cmd: CertCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+    match cmd {
+        CertCommand::InitCa(args) => {
+            let config = load_or_default(&args.config)?;
+            let name = match args.name {
+                Some(name) => name,
+                None => {
+                    let identity = crate::system::Identity::new().machine_identity()?;
+                    let machine = identity.hostname.unwrap_or(identity.id);
+                    format!("rastOS client CA {}", machine)
+                }
+            };
+            let ca = CertificateAuthority::create(&config.mtls.ca_dir, &name, args.days)?;
+            output.status(format!("Created certificate authority {}", ca.cert_path().display()));
+            output.status("Configure the daemon's TLS to verify clients against it");
+        }
+        CertCommand::Issue(args) => {
+            let mut config = load_or_default(&args.config)?;
+            let ca = CertificateAuthority::open(&config.mtls.ca_dir)?;
+            let issued = ca.issue(&args.name, args.days, &args.out_dir)?;
+            config.mtls.pin(&args.name, &issued.fingerprint, args.scopes, Some(issued.expires_at))?;
+            config.save_to_file(&args.config)?;
+            output.status(format!("Certificate: {}", issued.cert_path.display()));
+            output.status(format!("Private key: {}", issued.key_path.display()));
+            output.status(format!("Pinned '{}' with fingerprint {}", args.name, issued.fingerprint));
+        }
+        CertCommand::Pin(args) => {
+            let mut config = load_or_default(&args.config)?;
+            let fingerprint = crate::auth::mtls::fingerprint(&std::fs::read(&args.cert)?)?;
+            config.mtls.pin(&args.name, &fingerprint, args.scopes, None)?;
+            config.save_to_file(&args.config)?;
+            output.status(format!("Pinned '{}' with fingerprint {}", args.name, fingerprint));
+        }
+        CertCommand::Revoke(args) => {
+            let mut config = ApiKeyConfig::from_file(&args.config)?;
+            if !config.mtls.unpin(&args.name) {
+                return Err(format!("No certificate pinned for '{}'", args.name).into());
+            }
+            config.save_to_file(&args.config)?;
+            output.status(format!("Revoked the certificate of '{}'", args.name));
+        }
     }
     
     Ok(())
@@ -283,7 +723,7 @@ mod tests {
             config: config_path.clone(),
         };
         
-        handle_generate_key(gen_args).await.unwrap();
+        handle_generate_key(gen_args, Output::default()).await.unwrap();
         
         // Config file should not exist since we didn't save
         assert!(!config_path.exists());
@@ -296,7 +736,7 @@ mod tests {
             config: config_path.clone(),
         };
         
-        handle_generate_key(gen_args).await.unwrap();
+        handle_generate_key(gen_args, Output::default()).await.unwrap();
         
         // Config file should exist now
         assert!(config_path.exists());
@@ -320,10 +760,11 @@ mod tests {
             expires: None,
             primary: true,
             env_var: Some("TEST_API_KEY".to_string()),
+            secret: None,
             config: config_path.clone(),
         };
         
-        handle_add_key(add_args).await.unwrap();
+        handle_add_key(add_args, Output::default()).await.unwrap();
         
         // Load the config and verify the key was added
         let config = ApiKeyConfig::from_file(&config_path).unwrap();
@@ -339,10 +780,40 @@ mod tests {
             config: config_path.clone(),
         };
         
-        handle_remove_key(remove_args).await.unwrap();
+        handle_remove_key(remove_args, Output::default()).await.unwrap();
         
         // Verify the key was removed
         let config = ApiKeyConfig::from_file(&config_path).unwrap();
         assert!(config.keys.get("test-service").is_none());
     }
+    
+    #[test]
+    fn test_list_keys() {
+        let mut config = ApiKeyConfig::default();
+        config.keys.insert("backup".to_string(), crate::auth::config::ServiceKeys {
+            primary: Some("0123456789abcdef".to_string()),
+            expires_at: None,
+            secret: None,
+            additional: vec!["short".to_string()],
+            env_var: None,
+            retiring: vec![crate::auth::config::RetiringKey {
+                key: Some("fedcba9876543210".to_string()),
+                secret: None,
+                expires_at: 1000,
+            }],
+            scopes: vec!["backup:list".to_string()],
+        });
+        
+        let listings = list_keys(&config, 2000);
+        assert_eq!(listings.len(), 3);
+        assert_eq!(listings[0].role, "primary");
+        assert_eq!(listings[0].key, "0123...cdef");
+        assert_eq!(listings[0].status, "active");
+        assert_eq!(listings[1].key, "*****");
+        assert_eq!(listings[2].status, "expired");
+        assert_eq!(listings[2].scopes, vec!["backup:list".to_string()]);
+        
+        let listings = list_keys(&config, 900);
+        assert_eq!(listings[2].status, "expiring");
+    }
 }
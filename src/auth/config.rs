@@ -1,12 +1,34 @@
 //! Configuration for API key authentication
+//!
+//! The effective configuration is assembled from layers, each overriding
+//! the ones before it:
+//!
+//! 1. The system file, [`SYSTEM_CONFIG_PATH`]
+//! 2. The user's file, `$XDG_CONFIG_HOME/rast/keys.toml` (by default
+//!    `~/.config/rast/keys.toml`)
+//! 3. Environment variables named `<PREFIX>_<SERVICE>_API_KEY`, such as
+//!    `RAST_BACKUP_API_KEY`, which replace the primary key of a service
+//!
+//! Files are merged table by table, so a user file can override a single
+//! setting of a service and keep the rest from the system file. A per-service
+//! `env_var` still takes precedence over everything when a key is looked up.
+//!
+//! Files may be encrypted with the machine key (see [`crate::auth::sealing`]);
+//! they are decrypted transparently when read and stay encrypted when saved.
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use log::{debug, warn};
 use thiserror::Error;
 
 use super::{ApiKey, ApiKeyManager, AuthError};
+use crate::auth::expiry::ExpiryConfig;
+use crate::auth::mtls::MtlsConfig;
+use crate::auth::sealing::{self, MachineKey, SealError};
+use crate::secrets::{BackendKind, SecretError, SecretStore};
 
 /// Error type for API key configuration
 #[derive(Error, Debug)]
@@ -19,14 +41,42 @@ pub enum ConfigError {
     #[error("TOML deserialization error: {0}")]
     Toml(#[from] toml::de::Error),
     
+    /// TOML serialization error
+    #[error("TOML serialization error: {0}")]
+    TomlSer(#[from] toml::ser::Error),
+    
     /// Authentication error
     #[error("Authentication error: {0}")]
     Auth(#[from] AuthError),
+    
+    /// Secret store error
+    #[error("Secret store error: {0}")]
+    Secret(#[from] SecretError),
+    
+    /// Encrypted configuration error
+    #[error("Encrypted configuration error: {0}")]
+    Seal(#[from] SealError),
+}
+
+impl From<ConfigError> for AuthError {
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::Io(e) => AuthError::Io(e),
+            ConfigError::Auth(e) => e,
+            e => AuthError::Other(e.to_string()),
+        }
+    }
 }
 
 /// Result type for configuration operations
 pub type Result<T> = std::result::Result<T, ConfigError>;
 
+/// System-wide API key configuration file
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/rast/auth/keys.toml";
+
+/// Seconds in a day, for grace periods given in days
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
 /// Configuration for API keys
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiKeyConfig {
@@ -34,59 +84,234 @@ pub struct ApiKeyConfig {
     #[serde(default = "default_env_prefix")]
     pub env_prefix: String,
     
+    /// Secret store holding the keys referenced by name
+    #[serde(default)]
+    pub secret_backend: BackendKind,
+    
+    /// Days a replaced key stays valid after a rotation
+    #[serde(default = "default_rotation_grace_days")]
+    pub rotation_grace_days: u32,
+    
+    /// Warnings about expiring keys and their renewal
+    #[serde(default)]
+    pub expiry: ExpiryConfig,
+    
+    /// Client certificates accepted for remote management
+    #[serde(default)]
+    pub mtls: MtlsConfig,
+    
     /// API keys by service
     #[serde(default)]
     pub keys: HashMap<String, ServiceKeys>,
 }
 
 /// API keys for a specific service
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ServiceKeys {
     /// The primary API key for this service
     pub primary: Option<String>,
     
+    /// When the primary key expires (UNIX timestamp)
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    
+    /// Name of the secret holding the primary key, used instead of `primary`
+    #[serde(default)]
+    pub secret: Option<String>,
+    
     /// Additional API keys for this service
     #[serde(default)]
     pub additional: Vec<String>,
     
     /// Environment variable to override the API key
     pub env_var: Option<String>,
+    
+    /// Former primary keys still accepted until they expire
+    #[serde(default)]
+    pub retiring: Vec<RetiringKey>,
+    
+    /// Scopes that tokens issued for this service may carry
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// A replaced primary key in its grace period
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetiringKey {
+    /// The key, if it is kept in the config file
+    pub key: Option<String>,
+    
+    /// Name of the secret holding the key, if it is kept in the secret store
+    pub secret: Option<String>,
+    
+    /// When the key stops being accepted (UNIX timestamp)
+    pub expires_at: i64,
 }
 
 fn default_env_prefix() -> String {
     "RAST".to_string()
 }
 
+fn default_rotation_grace_days() -> u32 {
+    7
+}
+
+/// Per-user configuration file, if a home or config directory is known
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("rast/keys.toml"))
+}
+
+/// Decode a configuration file's contents, decrypting them if they are sealed
+fn read_config(content: Vec<u8>) -> Result<String> {
+    let content = sealing::unseal_if_sealed(content)?;
+    String::from_utf8(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+/// Merge `overlay` into `base`, recursing into tables present in both
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge_tables(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
 impl Default for ApiKeyConfig {
     fn default() -> Self {
         Self {
             env_prefix: default_env_prefix(),
+            secret_backend: BackendKind::default(),
+            rotation_grace_days: default_rotation_grace_days(),
+            expiry: ExpiryConfig::default(),
+            mtls: MtlsConfig::default(),
             keys: HashMap::new(),
         }
     }
 }
 
 impl ApiKeyConfig {
-    /// Load API key configuration from a TOML file
+    /// Load API key configuration from a TOML file, decrypting it if it is sealed
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
+        let content = read_config(fs::read(path)?)?;
         let config: Self = toml::from_str(&content)?;
         Ok(config)
     }
     
+    /// Load the layered configuration: system file, user file, then environment
+    ///
+    /// See the [module documentation](self) for the precedence.
+    pub fn load() -> Result<Self> {
+        let mut layers = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+        layers.extend(user_config_path());
+        let mut config = Self::from_layers(&layers)?;
+        config.apply_env_overrides(std::env::vars());
+        Ok(config)
+    }
+    
+    /// Merge configuration files, later files overriding earlier ones
+    ///
+    /// Missing files are skipped, and so are files the current user may
+    /// not read, such as a system file reserved for root.
+    pub fn from_layers<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut merged = toml::Table::new();
+        for path in paths {
+            let path = path.as_ref();
+            let content = match fs::read(path) {
+                Ok(content) => read_config(content)?,
+                Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) => {
+                    debug!("Skipping API key configuration {}: {}", path.display(), e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            merge_tables(&mut merged, toml::from_str(&content)?);
+        }
+        Ok(toml::Value::Table(merged).try_into()?)
+    }
+    
+    /// Replace primary keys with `<PREFIX>_<SERVICE>_API_KEY` variables from `vars`
+    ///
+    /// A variable for a service that is not configured adds it, with the
+    /// service name in lowercase.
+    pub fn apply_env_overrides<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        let prefix = format!("{}_", self.env_prefix.to_uppercase());
+        for (name, value) in vars {
+            let Some(service) = name.strip_prefix(&prefix).and_then(|s| s.strip_suffix("_API_KEY")) else {
+                continue;
+            };
+            if service.is_empty() || value.is_empty() {
+                continue;
+            }
+            let service = self
+                .keys
+                .keys()
+                .find(|s| self.env_var_for_service(s) == name)
+                .cloned()
+                .unwrap_or_else(|| service.to_lowercase());
+            debug!("Using {} for the API key of service '{}'", name, service);
+            let keys = self.keys.entry(service).or_default();
+            keys.primary = Some(value);
+            keys.secret = None;
+        }
+    }
+    
     /// Save API key configuration to a TOML file
+    ///
+    /// A file that is sealed stays sealed with the same kind of machine key.
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let sealed_with = match fs::read(path) {
+            Ok(existing) if sealing::is_sealed(&existing) => Some(sealing::sealed_kind(&existing)?),
+            Ok(_) => None,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match sealed_with {
+            Some(kind) => self.save_sealed(path, &MachineKey::system(kind)),
+            None => {
+                let content = toml::to_string_pretty(self)?;
+                crate::fs::atomic_write(path, content).map_err(std::io::Error::from)?;
+                Ok(())
+            }
+        }
+    }
+    
+    /// Save API key configuration encrypted with a machine key
+    ///
+    /// The file is only readable by its owner, and only on this machine.
+    pub fn save_sealed<P: AsRef<Path>>(&self, path: P, key: &MachineKey) -> Result<()> {
         let content = toml::to_string_pretty(self)?;
-        fs::write(path, content)?;
+        let sealed = key.seal(content.as_bytes())?;
+        let options = crate::fs::WriteOptions::default().with_mode(0o600);
+        crate::fs::atomic_write_with_options(path, sealed, &options).map_err(std::io::Error::from)?;
         Ok(())
     }
     
     /// Get the environment variable name for a service
     pub fn env_var_for_service(&self, service: &str) -> String {
-        format!("{}_API_KEY", self.env_prefix.to_uppercase())
+        format!(
+            "{}_{}_API_KEY",
+            self.env_prefix.to_uppercase(),
+            service.to_uppercase().replace('-', "_")
+        )
+    }
+    
+    /// Open the secret store holding keys referenced by name
+    pub fn secret_store(&self) -> Result<SecretStore> {
+        Ok(SecretStore::open(self.secret_backend)?)
     }
     
     /// Get the API key for a service, checking environment variables first
+    ///
+    /// A key referenced by name is read from the secret store; failures to
+    /// read it are logged and the key is treated as missing.
     pub fn get_key(&self, service: &str) -> Option<String> {
         // Check environment variable first
         if let Some(env_var) = self.keys.get(service).and_then(|s| s.env_var.as_ref()) {
@@ -95,21 +320,99 @@ impl ApiKeyConfig {
             }
         }
         
-        // Fall back to config file
-        self.keys
+        // Then the secret store and the config file
+        let keys = self.keys.get(service)?;
+        match self.primary_key(keys) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Cannot read the API key for service '{}': {}", service, e);
+                None
+            }
+        }
+    }
+    
+    /// Primary key of a service, from the secret store if it is referenced by name
+    fn primary_key(&self, keys: &ServiceKeys) -> Result<Option<String>> {
+        match &keys.secret {
+            Some(name) => Ok(Some(self.secret_store()?.get_string(name)?)),
+            None => Ok(keys.primary.clone()),
+        }
+    }
+    
+    /// Make `new_key` the primary key of `service`, retiring the old one
+    ///
+    /// The old key stays valid for `rotation_grace_days` after `now`. Keys
+    /// kept in the secret store stay there: the new key replaces the old one
+    /// under the same name, and the old one is kept under a new name.
+    /// Retiring keys that expired by `now` are removed. Returns the old key
+    /// and when it expires, if the service had a primary key. The new key
+    /// does not expire until `expires_at` is set again.
+    pub fn rotate_key(&mut self, service: &str, new_key: &str, now: i64) -> Result<Option<(String, i64)>> {
+        let keys = self
+            .keys
             .get(service)
-            .and_then(|s| s.primary.clone())
+            .ok_or_else(|| AuthError::Other(format!("No keys configured for service '{}'", service)))?;
+        let uses_store = keys.secret.is_some() || keys.retiring.iter().any(|r| r.secret.is_some());
+        let store = if uses_store { Some(self.secret_store()?) } else { None };
+        let expires_at = now + i64::from(self.rotation_grace_days) * SECONDS_PER_DAY;
+        let keys = self.keys.get_mut(service).expect("service checked above");
+        keys.expires_at = None;
+        
+        let (expired, retiring): (Vec<_>, Vec<_>) = keys.retiring.drain(..).partition(|r| r.expires_at <= now);
+        keys.retiring = retiring;
+        for name in expired.iter().filter_map(|r| r.secret.as_ref()) {
+            if let Some(store) = &store {
+                store.delete(name)?;
+            }
+        }
+        
+        let old_key = match (&keys.secret, &store) {
+            (Some(name), Some(store)) => {
+                let old_key = match store.get_string(name) {
+                    Ok(key) => Some(key),
+                    Err(SecretError::NotFound(_)) => None,
+                    Err(e) => return Err(e.into()),
+                };
+                // Keep the old key before it is overwritten
+                if let Some(old_key) = &old_key {
+                    let retired = format!("{}.retiring.{}", name, expires_at);
+                    store.set(&retired, old_key.as_bytes())?;
+                    keys.retiring.push(RetiringKey {
+                        key: None,
+                        secret: Some(retired),
+                        expires_at,
+                    });
+                }
+                store.set(name, new_key.as_bytes())?;
+                old_key
+            }
+            _ => {
+                let old_key = keys.primary.replace(new_key.to_string());
+                if let Some(old_key) = &old_key {
+                    keys.retiring.push(RetiringKey {
+                        key: Some(old_key.clone()),
+                        secret: None,
+                        expires_at,
+                    });
+                }
+                old_key
+            }
+        };
+        Ok(old_key.map(|key| (key, expires_at)))
     }
     
     /// Add all keys to an ApiKeyManager
+    ///
+    /// Retiring keys are added with their expiry, unless they have expired.
     pub fn add_to_manager(&self, manager: &ApiKeyManager) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
         for (service, keys) in &self.keys {
-            if let Some(key) = &keys.primary {
+            if let Some(key) = self.primary_key(keys)? {
                 manager.add_key(ApiKey {
-                    key: key.clone(),
+                    key,
                     service: service.clone(),
                     description: Some("Primary key from config".to_string()),
-                    expires_at: None,
+                    expires_at: keys.expires_at,
                 })?;
             }
             
@@ -121,6 +424,20 @@ impl ApiKeyConfig {
                     expires_at: None,
                 })?;
             }
+            
+            for retiring in keys.retiring.iter().filter(|r| r.expires_at > now) {
+                let key = match (&retiring.key, &retiring.secret) {
+                    (Some(key), _) => key.clone(),
+                    (None, Some(name)) => self.secret_store()?.get_string(name)?,
+                    (None, None) => continue,
+                };
+                manager.add_key(ApiKey {
+                    key,
+                    service: service.clone(),
+                    description: Some("Retiring key from rotation".to_string()),
+                    expires_at: Some(retiring.expires_at),
+                })?;
+            }
         }
         
         Ok(())
@@ -138,8 +455,12 @@ mod tests {
         let mut config = ApiKeyConfig::default();
         
         // Add some test keys
-        let mut service_keys = ServiceKeys {
+        let service_keys = ServiceKeys {
             primary: Some("test-primary-key".to_string()),
+            expires_at: None,
+            secret: None,
+            retiring: Vec::new(),
+            scopes: Vec::new(),
             additional: vec![
                 "test-additional-1".to_string(),
                 "test-additional-2".to_string(),
@@ -173,6 +494,10 @@ mod tests {
         // Add a test service with an environment variable
         let service_keys = ServiceKeys {
             primary: Some("test-primary-key".to_string()),
+            expires_at: None,
+            secret: None,
+            retiring: Vec::new(),
+            scopes: Vec::new(),
             additional: vec!["test-additional-1".to_string()],
             env_var: Some("TEST_API_KEY".to_string()),
         };
@@ -196,6 +521,10 @@ mod tests {
         // Add a test service with an environment variable
         let service_keys = ServiceKeys {
             primary: Some("test-primary-key".to_string()),
+            expires_at: None,
+            secret: None,
+            retiring: Vec::new(),
+            scopes: Vec::new(),
             additional: vec!["test-additional-1".to_string()],
             env_var: Some("TEST_API_KEY".to_string()),
         };
@@ -203,7 +532,8 @@ mod tests {
         config.keys.insert("test-service".to_string(), service_keys);
         
         // Set the environment variable
-        std::env::set_var("TEST_API_KEY", "env-var-key");
+        // SAFETY: tests do not read the environment from other threads
+        unsafe { std::env::set_var("TEST_API_KEY", "env-var-key") };
         
         // Should get the key from the environment variable
         assert_eq!(
@@ -212,7 +542,8 @@ mod tests {
         );
         
         // Clean up
-        std::env::remove_var("TEST_API_KEY");
+        // SAFETY: as above
+        unsafe { std::env::remove_var("TEST_API_KEY") };
     }
     
     #[test]
@@ -222,6 +553,10 @@ mod tests {
         // Add a test service
         let service_keys = ServiceKeys {
             primary: Some("test-primary-key".to_string()),
+            expires_at: None,
+            secret: None,
+            retiring: Vec::new(),
+            scopes: Vec::new(),
             additional: vec!["test-additional-1".to_string()],
             env_var: None,
         };
@@ -239,4 +574,60 @@ mod tests {
         // Verify invalid key
         assert!(manager.validate_key("invalid-key", "test-service").is_err());
     }
+    
+    #[test]
+    fn test_rotate_key() {
+        let mut config = ApiKeyConfig::default();
+        config.keys.insert("backup".to_string(), ServiceKeys {
+            primary: Some("old-key".to_string()),
+            expires_at: None,
+            secret: None,
+            retiring: vec![RetiringKey {
+                key: Some("ancient-key".to_string()),
+                secret: None,
+                expires_at: 500,
+            }],
+            scopes: Vec::new(),
+            additional: Vec::new(),
+            env_var: None,
+        });
+        
+        let retired = config.rotate_key("backup", "new-key", 1000).unwrap();
+        assert_eq!(retired, Some(("old-key".to_string(), 1000 + 7 * SECONDS_PER_DAY)));
+        
+        let keys = &config.keys["backup"];
+        assert_eq!(keys.primary.as_deref(), Some("new-key"));
+        // The expired key is dropped, the replaced one kept
+        assert_eq!(keys.retiring.len(), 1);
+        assert_eq!(keys.retiring[0].key.as_deref(), Some("old-key"));
+        
+        assert!(config.rotate_key("missing", "key", 1000).is_err());
+    }
+
+    
+    #[test]
+    fn test_layered_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = dir.path().join("system.toml");
+        let user = dir.path().join("user.toml");
+        fs::write(&system, "rotation_grace_days = 3\n\n[keys.backup]\nprimary = \"system-key\"\nscopes = [\"backup:list\"]\n").unwrap();
+        fs::write(&user, "[keys.backup]\nprimary = \"user-key\"\n\n[keys.llm]\nprimary = \"llm-key\"\n").unwrap();
+        
+        let mut config = ApiKeyConfig::from_layers(&[&system, &user, &dir.path().join("missing.toml")]).unwrap();
+        assert_eq!(config.rotation_grace_days, 3);
+        // The user's key replaces the system key; other settings are kept
+        assert_eq!(config.keys["backup"].primary.as_deref(), Some("user-key"));
+        assert_eq!(config.keys["backup"].scopes, vec!["backup:list".to_string()]);
+        assert_eq!(config.keys["llm"].primary.as_deref(), Some("llm-key"));
+        
+        config.apply_env_overrides(vec![
+            ("RAST_LLM_API_KEY".to_string(), "env-key".to_string()),
+            ("RAST_S3_BUCKET_API_KEY".to_string(), "s3-key".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+        assert_eq!(config.keys["llm"].primary.as_deref(), Some("env-key"));
+        assert_eq!(config.keys["s3_bucket"].primary.as_deref(), Some("s3-key"));
+        assert_eq!(config.env_var_for_service("s3-bucket"), "RAST_S3_BUCKET_API_KEY");
+    }
+
 }
@@ -0,0 +1,498 @@
+//! Warnings about expiring API keys and their automatic renewal
+//!
+//! An [`ExpiryMonitor`] periodically reads the key configuration and looks
+//! at the primary key of each service. Keys that expire within the warning
+//! window are either renewed, if the service has a [`RenewalHook`], or
+//! reported to every [`ExpiryNotifier`]. Renewal replaces the key the same
+//! way a rotation does, so the old key stays valid for the grace period.
+//!
+//! Renewal commands and notification targets are configured in the
+//! `[expiry]` table of `keys.toml`:
+//!
+//! ```toml
+//! [expiry]
+//! warn_days = 14
+//! desktop = true
+//! webhook = "https://hooks.example.com/rast"
+//!
+//! [expiry.renew]
+//! backup = "/usr/libexec/rast/renew-backup-key"
+//! ```
+//!
+//! A renewal command gets the service in `RAST_SERVICE` and the current
+//! expiry in `RAST_KEY_EXPIRES_AT`, and prints the new key, either alone
+//! or as JSON with `key` and `expires_at` fields. It runs in a
+//! [`Sandbox`] that can reach the network but not write any files,
+//! configured by the `[expiry.sandbox]` table.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{ApiKey, ApiKeyConfig, ApiKeyManager, AuthError, Result};
+use crate::sandbox::{Sandbox, SandboxConfig};
+
+/// Seconds in a day, for warning windows given in days
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Shortest time between two notifications about the same key
+const NOTIFY_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+/// Seconds to wait for a webhook
+const WEBHOOK_TIMEOUT_SECS: &str = "10";
+
+/// Seconds a renewal command may run
+const RENEWAL_TIMEOUT_SECS: u64 = 120;
+
+/// Settings for expiry warnings and renewal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpiryConfig {
+    /// Days before expiry from which keys are reported or renewed
+    #[serde(default = "default_warn_days")]
+    pub warn_days: u32,
+
+    /// Minutes between two checks of the background monitor
+    #[serde(default = "default_check_interval_mins")]
+    pub check_interval_mins: u64,
+
+    /// Show desktop notifications with `notify-send`
+    #[serde(default)]
+    pub desktop: bool,
+
+    /// URL to POST a JSON description of each expiring key to
+    #[serde(default)]
+    pub webhook: Option<String>,
+
+    /// Command that mints a replacement key, by service
+    #[serde(default)]
+    pub renew: HashMap<String, String>,
+
+    /// Sandbox the renewal commands run in
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+}
+
+fn default_warn_days() -> u32 {
+    7
+}
+
+fn default_check_interval_mins() -> u64 {
+    60
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            warn_days: default_warn_days(),
+            check_interval_mins: default_check_interval_mins(),
+            desktop: false,
+            webhook: None,
+            renew: HashMap::new(),
+            sandbox: SandboxConfig::default(),
+        }
+    }
+}
+
+/// A primary key that expires soon
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiringKey {
+    /// Service the key is for
+    pub service: String,
+    /// When the key expires (UNIX timestamp)
+    pub expires_at: i64,
+    /// Seconds left until then; negative once the key has expired
+    pub remaining_secs: i64,
+}
+
+impl ExpiringKey {
+    /// Describe the key's state for a notification
+    pub fn message(&self) -> String {
+        if self.remaining_secs <= 0 {
+            format!("The API key for service '{}' has expired", self.service)
+        } else {
+            format!(
+                "The API key for service '{}' expires in {}",
+                self.service,
+                describe_duration(self.remaining_secs)
+            )
+        }
+    }
+}
+
+/// Receives warnings about expiring keys
+pub trait ExpiryNotifier: Send + Sync {
+    /// Report a key that expires soon
+    fn notify(&self, key: &ExpiringKey) -> Result<()>;
+}
+
+/// Writes warnings to the log
+#[derive(Debug, Clone, Default)]
+pub struct LogNotifier;
+
+impl ExpiryNotifier for LogNotifier {
+    fn notify(&self, key: &ExpiringKey) -> Result<()> {
+        warn!("{}; rotate it with 'rast auth key rotate --service {}'", key.message(), key.service);
+        Ok(())
+    }
+}
+
+/// Shows desktop notifications with `notify-send`
+///
+/// Only reaches a desktop session of the user the monitor runs as.
+#[derive(Debug, Clone, Default)]
+pub struct DesktopNotifier;
+
+impl ExpiryNotifier for DesktopNotifier {
+    fn notify(&self, key: &ExpiringKey) -> Result<()> {
+        let urgency = if key.remaining_secs <= 0 { "critical" } else { "normal" };
+        run(Command::new("notify-send")
+            .args(["--app-name", "rast", "--urgency", urgency, "API key expiring"])
+            .arg(key.message()))
+    }
+}
+
+/// POSTs the [`ExpiringKey`] as JSON to a URL
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    /// Create a notifier for a webhook URL
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl ExpiryNotifier for WebhookNotifier {
+    fn notify(&self, key: &ExpiringKey) -> Result<()> {
+        let body = serde_json::to_string(key).map_err(|e| AuthError::Other(e.to_string()))?;
+        run(Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time", WEBHOOK_TIMEOUT_SECS])
+            .args(["--header", "Content-Type: application/json", "--data-binary", &body])
+            .arg(&self.url))
+    }
+}
+
+/// A replacement key minted by a [`RenewalHook`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenewedKey {
+    /// The new key
+    pub key: String,
+    /// When the new key expires (UNIX timestamp)
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+/// Mints a replacement for an expiring key, for example through a cloud API
+pub trait RenewalHook: Send + Sync {
+    /// Create a new key for the service of `expiring`
+    fn renew(&self, expiring: &ExpiringKey) -> Result<RenewedKey>;
+}
+
+/// Renews keys by running a command; see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct CommandHook {
+    command: String,
+    sandbox: Sandbox,
+}
+
+impl CommandHook {
+    /// Create a hook running `command` with `sh -c`
+    pub fn new<S: Into<String>>(command: S) -> Self {
+        Self {
+            command: command.into(),
+            sandbox: Self::sandbox(SandboxConfig::default()),
+        }
+    }
+
+    /// Run the command in a sandbox configured by `config`
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.sandbox = Self::sandbox(config);
+        self
+    }
+
+    fn sandbox(config: SandboxConfig) -> Sandbox {
+        Sandbox::new(config)
+            .with_network()
+            .with_timeout(Duration::from_secs(RENEWAL_TIMEOUT_SECS))
+    }
+}
+
+impl RenewalHook for CommandHook {
+    fn renew(&self, expiring: &ExpiringKey) -> Result<RenewedKey> {
+        let output = self.sandbox.output(
+            Command::new("sh")
+                .args(["-c", &self.command])
+                .env("RAST_SERVICE", &expiring.service)
+                .env("RAST_KEY_EXPIRES_AT", expiring.expires_at.to_string()),
+        )?;
+        if !output.status.success() {
+            return Err(AuthError::Other(format!(
+                "Renewal command failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        parse_renewed(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Parse a renewal command's output: a JSON object or just the key
+fn parse_renewed(output: &str) -> Result<RenewedKey> {
+    let output = output.trim();
+    if output.starts_with('{') {
+        return serde_json::from_str(output).map_err(|e| AuthError::Other(format!("Invalid renewal output: {}", e)));
+    }
+    match output.lines().next() {
+        Some(key) if !key.trim().is_empty() => Ok(RenewedKey {
+            key: key.trim().to_string(),
+            expires_at: None,
+        }),
+        _ => Err(AuthError::Other("Renewal command printed no key".to_string())),
+    }
+}
+
+/// Checks a key configuration for expiring keys
+pub struct ExpiryMonitor {
+    config_path: PathBuf,
+    warn_before: i64,
+    interval: Duration,
+    notifiers: Vec<Box<dyn ExpiryNotifier>>,
+    hooks: HashMap<String, Box<dyn RenewalHook>>,
+    manager: Option<Arc<ApiKeyManager>>,
+    /// When each key was last reported, by service and expiry
+    notified: Mutex<HashMap<(String, i64), i64>>,
+}
+
+impl ExpiryMonitor {
+    /// Create a monitor for a configuration file, set up from its `[expiry]` table
+    ///
+    /// Warnings always go to the log; desktop and webhook notifications and
+    /// renewal commands are added as configured.
+    pub fn new<P: AsRef<Path>>(config_path: P, config: &ExpiryConfig) -> Self {
+        let mut monitor = Self {
+            config_path: config_path.as_ref().to_path_buf(),
+            warn_before: i64::from(config.warn_days) * SECONDS_PER_DAY,
+            interval: Duration::from_secs(config.check_interval_mins.max(1) * 60),
+            notifiers: vec![Box::new(LogNotifier)],
+            hooks: HashMap::new(),
+            manager: None,
+            notified: Mutex::new(HashMap::new()),
+        };
+        if config.desktop {
+            monitor = monitor.with_notifier(DesktopNotifier);
+        }
+        if let Some(url) = &config.webhook {
+            monitor = monitor.with_notifier(WebhookNotifier::new(url.clone()));
+        }
+        for (service, command) in &config.renew {
+            let hook = CommandHook::new(command.clone()).with_sandbox(config.sandbox.clone());
+            monitor = monitor.with_renewal_hook(service.clone(), hook);
+        }
+        monitor
+    }
+
+    /// Also report expiring keys to `notifier`
+    pub fn with_notifier<N: ExpiryNotifier + 'static>(mut self, notifier: N) -> Self {
+        self.notifiers.push(Box::new(notifier));
+        self
+    }
+
+    /// Renew the keys of `service` with `hook` instead of reporting them
+    pub fn with_renewal_hook<S: Into<String>, H: RenewalHook + 'static>(mut self, service: S, hook: H) -> Self {
+        self.hooks.insert(service.into(), Box::new(hook));
+        self
+    }
+
+    /// Make renewed keys valid in `manager` at once
+    pub fn with_manager(mut self, manager: Arc<ApiKeyManager>) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+
+    /// Check the configuration once at time `now`
+    ///
+    /// Returns the keys that expire within the warning window and were not
+    /// renewed. Each of them is reported at most once a day.
+    pub fn check(&self, now: i64) -> Result<Vec<ExpiringKey>> {
+        let mut config = ApiKeyConfig::from_file(&self.config_path)?;
+        let mut services: Vec<_> = config
+            .keys
+            .iter()
+            .filter_map(|(service, keys)| Some((service.clone(), keys.expires_at?)))
+            .filter(|(_, expires_at)| expires_at - now <= self.warn_before)
+            .collect();
+        services.sort();
+
+        let mut expiring = Vec::new();
+        let mut renewed = false;
+        for (service, expires_at) in services {
+            let key = ExpiringKey {
+                service,
+                expires_at,
+                remaining_secs: expires_at - now,
+            };
+            if let Some(hook) = self.hooks.get(&key.service) {
+                match self.renew(&mut config, hook.as_ref(), &key, now) {
+                    Ok(()) => {
+                        renewed = true;
+                        continue;
+                    }
+                    Err(e) => error!("Cannot renew the API key for service '{}': {}", key.service, e),
+                }
+            }
+            self.notify(&key, now);
+            expiring.push(key);
+        }
+
+        if renewed {
+            config.save_to_file(&self.config_path)?;
+        }
+        Ok(expiring)
+    }
+
+    /// Check the configuration periodically in the background
+    ///
+    /// Errors are logged and the next check goes ahead.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        let monitor = Arc::new(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(monitor.interval);
+            loop {
+                interval.tick().await;
+                let monitor = Arc::clone(&monitor);
+                let result = tokio::task::spawn_blocking(move || monitor.check(chrono::Utc::now().timestamp())).await;
+                match result {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("API key expiry check failed: {}", e),
+                    Err(e) => error!("API key expiry check panicked: {}", e),
+                }
+            }
+        })
+    }
+
+    fn renew(&self, config: &mut ApiKeyConfig, hook: &dyn RenewalHook, key: &ExpiringKey, now: i64) -> Result<()> {
+        let renewed = hook.renew(key)?;
+        let retired = config.rotate_key(&key.service, &renewed.key, now)?;
+        if let Some(keys) = config.keys.get_mut(&key.service) {
+            keys.expires_at = renewed.expires_at;
+        }
+
+        if let Some(manager) = &self.manager {
+            manager.add_key(ApiKey {
+                key: renewed.key,
+                service: key.service.clone(),
+                description: Some("Primary key from renewal".to_string()),
+                expires_at: renewed.expires_at,
+            })?;
+            if let Some((old_key, expires_at)) = retired {
+                manager.add_key(ApiKey {
+                    key: old_key,
+                    service: key.service.clone(),
+                    description: Some("Retiring key".to_string()),
+                    expires_at: Some(expires_at),
+                })?;
+            }
+        }
+
+        info!("Renewed the API key for service '{}'", key.service);
+        Ok(())
+    }
+
+    fn notify(&self, key: &ExpiringKey, now: i64) {
+        if let Ok(mut notified) = self.notified.lock() {
+            let id = (key.service.clone(), key.expires_at);
+            if notified.get(&id).is_some_and(|last| now - last < NOTIFY_INTERVAL_SECS) {
+                return;
+            }
+            notified.insert(id, now);
+        }
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.notify(key) {
+                warn!("Cannot send expiry notification for service '{}': {}", key.service, e);
+            }
+        }
+    }
+}
+
+/// Run a notification command, failing if it does
+fn run(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(AuthError::Other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(())
+}
+
+/// Roughly describe a duration in days or hours
+fn describe_duration(secs: i64) -> String {
+    match secs {
+        s if s >= 2 * SECONDS_PER_DAY => format!("{} days", s / SECONDS_PER_DAY),
+        s if s >= 2 * 60 * 60 => format!("{} hours", s / (60 * 60)),
+        s => format!("{} minutes", (s / 60).max(1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::config::ServiceKeys;
+    use tempfile::tempdir;
+
+    struct FixedHook;
+
+    impl RenewalHook for FixedHook {
+        fn renew(&self, _expiring: &ExpiringKey) -> Result<RenewedKey> {
+            Ok(RenewedKey {
+                key: "renewed-key".to_string(),
+                expires_at: Some(4_000_000_000),
+            })
+        }
+    }
+
+    #[test]
+    fn test_check_renews_and_reports() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.toml");
+        let mut config = ApiKeyConfig::default();
+        for (service, expires_at) in [("backup", Some(2 * SECONDS_PER_DAY)), ("llm", Some(3 * SECONDS_PER_DAY)), ("s3", None)] {
+            config.keys.insert(service.to_string(), ServiceKeys {
+                primary: Some(format!("{}-key", service)),
+                expires_at,
+                ..ServiceKeys::default()
+            });
+        }
+        config.save_to_file(&path).unwrap();
+
+        let manager = Arc::new(ApiKeyManager::new());
+        let monitor = ExpiryMonitor::new(&path, &ExpiryConfig::default())
+            .with_renewal_hook("backup", FixedHook)
+            .with_manager(Arc::clone(&manager));
+        let expiring = monitor.check(SECONDS_PER_DAY).unwrap();
+
+        // The backup key is renewed, the LLM key only reported
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].service, "llm");
+        assert_eq!(expiring[0].remaining_secs, 2 * SECONDS_PER_DAY);
+
+        let config = ApiKeyConfig::from_file(&path).unwrap();
+        assert_eq!(config.keys["backup"].primary.as_deref(), Some("renewed-key"));
+        assert_eq!(config.keys["backup"].expires_at, Some(4_000_000_000));
+        assert_eq!(config.keys["backup"].retiring[0].key.as_deref(), Some("backup-key"));
+        assert!(manager.validate_key("renewed-key", "backup").is_ok());
+    }
+
+    #[test]
+    fn test_parse_renewed() {
+        assert_eq!(parse_renewed("new-key\n").unwrap().key, "new-key");
+        let renewed = parse_renewed(r#"{"key":"json-key","expires_at":1700000000}"#).unwrap();
+        assert_eq!(renewed.key, "json-key");
+        assert_eq!(renewed.expires_at, Some(1700000000));
+        assert!(parse_renewed("\n").is_err());
+    }
+}
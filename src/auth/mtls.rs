@@ -0,0 +1,434 @@
+//! Client certificate authentication for remote management
+//!
+//! Remote clients authenticate with a TLS client certificate instead of an
+//! API key. The machine runs a small certificate authority whose
+//! certificate the daemon's TLS stack trusts for client authentication.
+//! On top of the chain check, every accepted certificate is pinned by its
+//! SHA-256 fingerprint in the `[mtls]` table of `keys.toml`, so a
+//! certificate can be revoked by removing its pin:
+//!
+//! ```toml
+//! [mtls]
+//! require_for_remote = true
+//!
+//! [mtls.pinned.laptop]
+//! fingerprint = "3F:1A:...:C2"
+//! scopes = ["backup:*"]
+//! ```
+//!
+//! Keys and certificates are created with the `openssl` command.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{AuthError, Result};
+
+/// Directory of the machine's client certificate authority
+pub const CA_DIR: &str = "/etc/rast/auth/ca";
+
+/// Validity of a new certificate authority
+pub const DEFAULT_CA_DAYS: u32 = 3650;
+
+/// Validity of a new client certificate
+pub const DEFAULT_CLIENT_DAYS: u32 = 365;
+
+/// Private key of the certificate authority, inside its directory
+const CA_KEY_FILE: &str = "ca.key";
+
+/// Certificate of the certificate authority, inside its directory
+const CA_CERT_FILE: &str = "ca.crt";
+
+/// Serial number file kept by `openssl x509`
+const CA_SERIAL_FILE: &str = "ca.srl";
+
+/// Extensions of issued client certificates
+const CLIENT_EXTENSIONS: &str = "\
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature
+extendedKeyUsage = clientAuth
+";
+
+/// Settings for client certificate authentication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MtlsConfig {
+    /// Refuse remote connections without a pinned client certificate
+    #[serde(default = "default_require_for_remote")]
+    pub require_for_remote: bool,
+
+    /// Directory of the certificate authority
+    #[serde(default = "default_ca_dir")]
+    pub ca_dir: PathBuf,
+
+    /// Accepted client certificates, by client name
+    #[serde(default)]
+    pub pinned: HashMap<String, PinnedCert>,
+}
+
+fn default_require_for_remote() -> bool {
+    true
+}
+
+fn default_ca_dir() -> PathBuf {
+    PathBuf::from(CA_DIR)
+}
+
+impl Default for MtlsConfig {
+    fn default() -> Self {
+        Self {
+            require_for_remote: default_require_for_remote(),
+            ca_dir: default_ca_dir(),
+            pinned: HashMap::new(),
+        }
+    }
+}
+
+impl MtlsConfig {
+    /// Accept the certificate with `fingerprint` for client `name`
+    ///
+    /// Replaces an earlier pin of the same client.
+    pub fn pin(&mut self, name: &str, fingerprint: &str, scopes: Vec<String>, expires_at: Option<i64>) -> Result<()> {
+        validate_name(name)?;
+        self.pinned.insert(
+            name.to_string(),
+            PinnedCert {
+                fingerprint: format_fingerprint(&parse_fingerprint(fingerprint)?),
+                scopes,
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop accepting the certificate of client `name`; returns whether it was pinned
+    pub fn unpin(&mut self, name: &str) -> bool {
+        self.pinned.remove(name).is_some()
+    }
+}
+
+/// A client certificate accepted by fingerprint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedCert {
+    /// SHA-256 fingerprint of the DER certificate, as colon-separated hex
+    pub fingerprint: String,
+
+    /// Operations the client may perform, such as `backup:create`
+    #[serde(default)]
+    pub scopes: Vec<String>,
+
+    /// When the certificate expires (UNIX timestamp)
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+/// A client authenticated by its certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Name the certificate is pinned under
+    pub name: String,
+    /// Fingerprint of the certificate
+    pub fingerprint: String,
+    /// Operations the client may perform
+    pub scopes: Vec<String>,
+}
+
+impl ClientIdentity {
+    /// Check whether the client may perform `scope`
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == "*")
+    }
+
+    /// Fail unless the client may perform `scope`
+    pub fn require_scope(&self, scope: &str) -> Result<()> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientScope(scope.to_string()))
+        }
+    }
+}
+
+/// Decides whether a connection's client certificate is accepted
+///
+/// The TLS handshake must already have verified the certificate chain
+/// against [`CertificateAuthority::cert_path`]; this only checks the pins.
+#[derive(Debug, Clone)]
+pub struct ClientCertVerifier {
+    require_for_remote: bool,
+    /// Pins by fingerprint bytes
+    pins: HashMap<Vec<u8>, (String, PinnedCert)>,
+}
+
+impl ClientCertVerifier {
+    /// Create a verifier for the pins of a configuration
+    ///
+    /// Pins with malformed fingerprints are skipped.
+    pub fn new(config: &MtlsConfig) -> Self {
+        let pins = config
+            .pinned
+            .iter()
+            .filter_map(|(name, pin)| Some((parse_fingerprint(&pin.fingerprint).ok()?, (name.clone(), pin.clone()))))
+            .collect();
+        Self {
+            require_for_remote: config.require_for_remote,
+            pins,
+        }
+    }
+
+    /// Authenticate a connection by its client certificate at time `now`
+    ///
+    /// `peer_cert` is the DER certificate the client presented, if any.
+    /// Local connections without a certificate are let through with no
+    /// identity, to authenticate with an API key or token instead; remote
+    /// ones are refused if certificates are required.
+    pub fn authorize(&self, peer_cert: Option<&[u8]>, remote: bool, now: i64) -> Result<Option<ClientIdentity>> {
+        let Some(der) = peer_cert else {
+            if remote && self.require_for_remote {
+                return Err(AuthError::CertificateRequired);
+            }
+            return Ok(None);
+        };
+
+        let digest = Sha256::digest(der).to_vec();
+        let fingerprint = format_fingerprint(&digest);
+        let (name, pin) = self
+            .pins
+            .get(&digest)
+            .ok_or_else(|| AuthError::UntrustedCertificate(fingerprint.clone()))?;
+        if pin.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(AuthError::UntrustedCertificate(format!("{} (expired)", fingerprint)));
+        }
+        Ok(Some(ClientIdentity {
+            name: name.clone(),
+            fingerprint,
+            scopes: pin.scopes.clone(),
+        }))
+    }
+}
+
+/// A client certificate and its key, as written by [`CertificateAuthority::issue`]
+#[derive(Debug, Clone)]
+pub struct IssuedCert {
+    /// PEM certificate
+    pub cert_path: PathBuf,
+    /// PEM private key, readable only by its owner
+    pub key_path: PathBuf,
+    /// SHA-256 fingerprint of the certificate
+    pub fingerprint: String,
+    /// When the certificate expires (UNIX timestamp)
+    pub expires_at: i64,
+}
+
+/// The machine's certificate authority for client certificates
+#[derive(Debug, Clone)]
+pub struct CertificateAuthority {
+    dir: PathBuf,
+}
+
+impl CertificateAuthority {
+    /// Open an existing certificate authority
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let ca = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        if !ca.cert_path().exists() || !ca.key_path().exists() {
+            return Err(AuthError::Other(format!("No certificate authority in {}", ca.dir.display())));
+        }
+        Ok(ca)
+    }
+
+    /// Create a certificate authority with a new P-256 key
+    ///
+    /// Fails if `dir` already holds one, so clients' certificates are not
+    /// invalidated by accident.
+    pub fn create<P: AsRef<Path>>(dir: P, name: &str, days: u32) -> Result<Self> {
+        let ca = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        if ca.key_path().exists() {
+            return Err(AuthError::Other(format!("{} already exists", ca.key_path().display())));
+        }
+        fs::create_dir_all(&ca.dir)?;
+        fs::set_permissions(&ca.dir, fs::Permissions::from_mode(0o700))?;
+
+        let subject = format!("/CN={}", name);
+        let days = days.to_string();
+        openssl(&[
+            "req", "-x509", "-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:P-256", "-nodes",
+            "-keyout", &ca.key_path().to_string_lossy(),
+            "-out", &ca.cert_path().to_string_lossy(),
+            "-days", &days,
+            "-subj", &subject,
+            "-addext", "basicConstraints=critical,CA:TRUE,pathlen:0",
+            "-addext", "keyUsage=critical,keyCertSign,cRLSign",
+        ])?;
+        fs::set_permissions(ca.key_path(), fs::Permissions::from_mode(0o600))?;
+
+        info!("Created client certificate authority in {}", ca.dir.display());
+        Ok(ca)
+    }
+
+    /// Certificate for the daemon's TLS stack to verify clients against
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join(CA_CERT_FILE)
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.dir.join(CA_KEY_FILE)
+    }
+
+    /// Issue a client certificate for `name`, writing `<name>.crt` and `<name>.key` to `out_dir`
+    pub fn issue<P: AsRef<Path>>(&self, name: &str, days: u32, out_dir: P) -> Result<IssuedCert> {
+        validate_name(name)?;
+        let out_dir = out_dir.as_ref();
+        fs::create_dir_all(out_dir)?;
+        let cert_path = out_dir.join(format!("{}.crt", name));
+        let key_path = out_dir.join(format!("{}.key", name));
+
+        let work = tempfile::Builder::new().prefix("rast-cert-").tempdir()?;
+        let csr_path = work.path().join("client.csr");
+        let ext_path = work.path().join("client.ext");
+        fs::write(&ext_path, CLIENT_EXTENSIONS)?;
+
+        let subject = format!("/CN={}", name);
+        openssl(&[
+            "req", "-new", "-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:P-256", "-nodes",
+            "-keyout", &key_path.to_string_lossy(),
+            "-out", &csr_path.to_string_lossy(),
+            "-subj", &subject,
+        ])?;
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600))?;
+        openssl(&[
+            "x509", "-req",
+            "-in", &csr_path.to_string_lossy(),
+            "-CA", &self.cert_path().to_string_lossy(),
+            "-CAkey", &self.key_path().to_string_lossy(),
+            "-CAserial", &self.dir.join(CA_SERIAL_FILE).to_string_lossy(),
+            "-CAcreateserial",
+            "-days", &days.to_string(),
+            "-extfile", &ext_path.to_string_lossy(),
+            "-out", &cert_path.to_string_lossy(),
+        ])?;
+
+        let fingerprint = format_fingerprint(&Sha256::digest(pem_to_der(&fs::read_to_string(&cert_path)?)?));
+        info!("Issued client certificate for '{}' ({})", name, fingerprint);
+        Ok(IssuedCert {
+            cert_path,
+            key_path,
+            fingerprint,
+            expires_at: chrono::Utc::now().timestamp() + i64::from(days) * 24 * 60 * 60,
+        })
+    }
+}
+
+/// SHA-256 fingerprint of a PEM or DER certificate, as colon-separated hex
+pub fn fingerprint(cert: &[u8]) -> Result<String> {
+    let der = match std::str::from_utf8(cert) {
+        Ok(pem) if pem.contains("-----BEGIN CERTIFICATE-----") => pem_to_der(pem)?,
+        _ => cert.to_vec(),
+    };
+    Ok(format_fingerprint(&Sha256::digest(der)))
+}
+
+/// The DER bytes of the first certificate in a PEM file
+fn pem_to_der(pem: &str) -> Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END"))
+        .collect();
+    if body.is_empty() {
+        return Err(AuthError::Other("No certificate in PEM data".to_string()));
+    }
+    base64::decode(body).map_err(|e| AuthError::Other(format!("Invalid PEM certificate: {}", e)))
+}
+
+fn format_fingerprint(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// Parse a SHA-256 fingerprint, with or without colons and in either case
+fn parse_fingerprint(text: &str) -> Result<Vec<u8>> {
+    let hex: String = text.trim().trim_start_matches("sha256:").chars().filter(|c| *c != ':').collect();
+    let invalid = || AuthError::Other(format!("Invalid SHA-256 fingerprint '{}'", text));
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+/// Client names become certificate subjects and file names
+fn validate_name(name: &str) -> Result<()> {
+    if crate::fs::is_plain_name(name) {
+        Ok(())
+    } else {
+        Err(AuthError::Other(format!("Invalid client name '{}'", name)))
+    }
+}
+
+fn openssl(args: &[&str]) -> Result<()> {
+    let output = Command::new("openssl").args(args).output()?;
+    if !output.status.success() {
+        return Err(AuthError::Other(format!(
+            "openssl {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let cert = b"not really DER, but any bytes have a fingerprint";
+        let mut config = MtlsConfig::default();
+        let fp = fingerprint(cert).unwrap();
+        config.pin("laptop", &fp.to_lowercase().replace(':', ""), vec!["backup:list".to_string()], None).unwrap();
+        assert_eq!(config.pinned["laptop"].fingerprint, fp);
+
+        let verifier = ClientCertVerifier::new(&config);
+        let identity = verifier.authorize(Some(cert), true, 0).unwrap().unwrap();
+        assert_eq!(identity.name, "laptop");
+        assert!(identity.require_scope("backup:list").is_ok());
+        assert!(identity.require_scope("backup:delete").is_err());
+
+        assert!(matches!(verifier.authorize(Some(b"other"), true, 0), Err(AuthError::UntrustedCertificate(_))));
+        assert!(matches!(verifier.authorize(None, true, 0), Err(AuthError::CertificateRequired)));
+        assert_eq!(verifier.authorize(None, false, 0).unwrap(), None);
+
+        config.pin("laptop", &fp, Vec::new(), Some(100)).unwrap();
+        let verifier = ClientCertVerifier::new(&config);
+        assert!(verifier.authorize(Some(cert), true, 100).is_err());
+        assert!(config.pin("../laptop", &fp, Vec::new(), None).is_err());
+        assert!(config.pin("laptop", "12:34", Vec::new(), None).is_err());
+    }
+
+    #[test]
+    fn test_issue_certificate() {
+        if Command::new("openssl").arg("version").output().is_err() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let ca = CertificateAuthority::create(dir.path().join("ca"), "test CA", 30).unwrap();
+        assert!(CertificateAuthority::create(dir.path().join("ca"), "test CA", 30).is_err());
+
+        let issued = ca.issue("laptop", 7, dir.path().join("clients")).unwrap();
+        let pem = fs::read(&issued.cert_path).unwrap();
+        assert_eq!(fingerprint(&pem).unwrap(), issued.fingerprint);
+        assert_eq!(fs::metadata(&issued.key_path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
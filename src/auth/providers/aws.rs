@@ -0,0 +1,285 @@
+//! AWS credential resolution
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Deserialize;
+
+use super::{env, home_dir, metadata_request, parse_time, CredentialSource, ProviderError, Result};
+
+/// EC2 instance metadata service
+const IMDS_URL: &str = "http://169.254.169.254/latest";
+
+/// Lifetime requested for IMDSv2 session tokens
+const IMDS_TOKEN_TTL_SECS: u32 = 21600;
+
+/// Options for resolving AWS credentials
+#[derive(Debug, Clone)]
+pub struct AwsOptions {
+    /// Access key given explicitly
+    pub access_key_id: Option<String>,
+    /// Secret key given explicitly
+    pub secret_access_key: Option<String>,
+    /// Profile in the shared credentials file; `AWS_PROFILE` or `default` if unset
+    pub profile: Option<String>,
+    /// Whether to ask the EC2 instance metadata service
+    pub instance_metadata: bool,
+}
+
+impl Default for AwsOptions {
+    fn default() -> Self {
+        Self {
+            access_key_id: None,
+            secret_access_key: None,
+            profile: None,
+            instance_metadata: true,
+        }
+    }
+}
+
+impl AwsOptions {
+    /// Use these keys instead of looking for credentials
+    pub fn with_static_keys<S: Into<String>>(mut self, access_key_id: S, secret_access_key: S) -> Self {
+        self.access_key_id = Some(access_key_id.into());
+        self.secret_access_key = Some(secret_access_key.into());
+        self
+    }
+
+    /// Use a profile of the shared credentials file
+    pub fn with_profile<S: Into<String>>(mut self, profile: S) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Do not ask the instance metadata service
+    pub fn without_instance_metadata(mut self) -> Self {
+        self.instance_metadata = false;
+        self
+    }
+}
+
+/// AWS access keys
+#[derive(Clone)]
+pub struct AwsCredentials {
+    /// Access key ID
+    pub access_key_id: String,
+    /// Secret access key
+    pub secret_access_key: String,
+    /// Session token of temporary credentials
+    pub session_token: Option<String>,
+    /// When temporary credentials expire
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Where the credentials were found
+    pub source: CredentialSource,
+}
+
+impl AwsCredentials {
+    /// Check whether the credentials have expired
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("expires_at", &self.expires_at)
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Temporary credentials as written by the AWS CLI and the metadata service
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TemporaryCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(alias = "Token")]
+    session_token: Option<String>,
+    expiration: Option<String>,
+}
+
+impl TemporaryCredentials {
+    fn into_credentials(self, source: CredentialSource) -> AwsCredentials {
+        AwsCredentials {
+            access_key_id: self.access_key_id,
+            secret_access_key: self.secret_access_key,
+            session_token: self.session_token,
+            expires_at: self.expiration.as_deref().and_then(parse_time),
+            source,
+        }
+    }
+}
+
+/// Entry of the AWS CLI credential cache
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CacheEntry {
+    credentials: TemporaryCredentials,
+}
+
+/// Find AWS credentials; see the [module documentation](super) for the order
+pub fn resolve_aws(options: &AwsOptions) -> Result<AwsCredentials> {
+    if let (Some(id), Some(secret)) = (&options.access_key_id, &options.secret_access_key) {
+        return Ok(static_credentials(id.clone(), secret.clone(), None, CredentialSource::Explicit));
+    }
+    if let (Some(id), Some(secret)) = (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+        return Ok(static_credentials(id, secret, env("AWS_SESSION_TOKEN"), CredentialSource::Environment));
+    }
+
+    let profile = options
+        .profile
+        .clone()
+        .or_else(|| env("AWS_PROFILE"))
+        .unwrap_or_else(|| "default".to_string());
+    let credentials_file = env("AWS_SHARED_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".aws/credentials")));
+    if let Some(content) = credentials_file.and_then(|path| fs::read_to_string(path).ok()) {
+        if let Some(credentials) = parse_credentials_file(&content, &profile) {
+            return Ok(credentials);
+        }
+    }
+
+    if let Some(cache) = home_dir().map(|home| home.join(".aws/cli/cache")) {
+        if let Some(credentials) = newest_cached(&cache) {
+            return Ok(credentials);
+        }
+    }
+
+    if options.instance_metadata {
+        if let Some(credentials) = instance_metadata() {
+            return Ok(credentials);
+        }
+    }
+
+    Err(ProviderError::NoCredentials {
+        provider: "AWS",
+        tried: "configuration, environment, credentials file, SSO cache, instance metadata".to_string(),
+    })
+}
+
+fn static_credentials(
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    source: CredentialSource,
+) -> AwsCredentials {
+    AwsCredentials {
+        access_key_id,
+        secret_access_key,
+        session_token,
+        expires_at: None,
+        source,
+    }
+}
+
+/// Keys of `profile` in an INI-style shared credentials file
+fn parse_credentials_file(content: &str, profile: &str) -> Option<AwsCredentials> {
+    let mut in_profile = false;
+    let (mut id, mut secret, mut token) = (None, None, None);
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_profile = section.trim() == profile;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if in_profile {
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => id = value,
+                "aws_secret_access_key" => secret = value,
+                "aws_session_token" => token = value,
+                _ => {}
+            }
+        }
+    }
+    Some(static_credentials(id?, secret?, token, CredentialSource::File))
+}
+
+/// The unexpired credentials cached most recently by the AWS CLI
+///
+/// The CLI caches the role credentials of SSO and assumed-role profiles
+/// here after a login.
+fn newest_cached(cache: &Path) -> Option<AwsCredentials> {
+    let mut entries: Vec<_> = fs::read_dir(cache)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    entries.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    entries.into_iter().find_map(|(_, path)| {
+        let entry: CacheEntry = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+        let credentials = entry.credentials.into_credentials(CredentialSource::SsoCache);
+        if credentials.is_expired() {
+            debug!("Skipping expired cached credentials {}", path.display());
+            return None;
+        }
+        Some(credentials)
+    })
+}
+
+/// Role credentials from the EC2 instance metadata service, using IMDSv2
+fn instance_metadata() -> Option<AwsCredentials> {
+    let ttl = format!("X-aws-ec2-metadata-token-ttl-seconds: {}", IMDS_TOKEN_TTL_SECS);
+    let token = metadata_request("PUT", &format!("{}/api/token", IMDS_URL), &[&ttl])?;
+    let token_header = format!("X-aws-ec2-metadata-token: {}", token.trim());
+    let roles_url = format!("{}/meta-data/iam/security-credentials/", IMDS_URL);
+    let roles = metadata_request("GET", &roles_url, &[&token_header])?;
+    let role = roles.lines().next()?.trim();
+    let body = metadata_request("GET", &format!("{}{}", roles_url, role), &[&token_header])?;
+    match serde_json::from_str::<TemporaryCredentials>(&body) {
+        Ok(credentials) => Some(credentials.into_credentials(CredentialSource::InstanceMetadata)),
+        Err(e) => {
+            debug!("Unexpected instance metadata credentials: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials_file() {
+        let content = "\
+[default]
+aws_access_key_id = AKIADEFAULT
+aws_secret_access_key = default-secret
+
+[backup]
+aws_access_key_id=AKIABACKUP
+aws_secret_access_key=backup-secret
+aws_session_token=token
+";
+        let credentials = parse_credentials_file(content, "backup").unwrap();
+        assert_eq!(credentials.access_key_id, "AKIABACKUP");
+        assert_eq!(credentials.session_token.as_deref(), Some("token"));
+        assert_eq!(parse_credentials_file(content, "default").unwrap().secret_access_key, "default-secret");
+        assert!(parse_credentials_file(content, "missing").is_none());
+    }
+
+    #[test]
+    fn test_temporary_credentials() {
+        let imds = r#"{"Code":"Success","Type":"AWS-HMAC","AccessKeyId":"ASIA1","SecretAccessKey":"s","Token":"t","Expiration":"2030-01-01T00:00:00Z"}"#;
+        let credentials: TemporaryCredentials = serde_json::from_str(imds).unwrap();
+        let credentials = credentials.into_credentials(CredentialSource::InstanceMetadata);
+        assert_eq!(credentials.session_token.as_deref(), Some("t"));
+        assert!(!credentials.is_expired());
+
+        let cache = r#"{"ProviderType":"sso","Credentials":{"AccessKeyId":"ASIA2","SecretAccessKey":"s","SessionToken":"t","Expiration":"2000-01-01T00:00:00Z"}}"#;
+        let entry: CacheEntry = serde_json::from_str(cache).unwrap();
+        assert!(entry.credentials.into_credentials(CredentialSource::SsoCache).is_expired());
+    }
+}
@@ -0,0 +1,207 @@
+//! Azure credential resolution
+
+use std::fmt;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+use serde::Deserialize;
+
+use super::{env, metadata_request, CredentialSource, ProviderError, Result};
+
+/// Managed identity token endpoint of the Azure instance metadata service
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token?api-version=2018-02-01";
+
+/// Resource that storage tokens are requested for
+const STORAGE_RESOURCE: &str = "https%3A%2F%2Fstorage.azure.com%2F";
+
+/// Options for resolving Azure credentials
+#[derive(Debug, Clone)]
+pub struct AzureOptions {
+    /// Storage account key given explicitly
+    pub account_key: Option<String>,
+    /// Client ID of a user-assigned managed identity
+    pub managed_identity: Option<String>,
+    /// Whether to ask the instance metadata service for a managed identity token
+    pub instance_metadata: bool,
+}
+
+impl Default for AzureOptions {
+    fn default() -> Self {
+        Self {
+            account_key: None,
+            managed_identity: None,
+            instance_metadata: true,
+        }
+    }
+}
+
+impl AzureOptions {
+    /// Use this storage account key instead of looking for credentials
+    pub fn with_account_key<S: Into<String>>(mut self, key: S) -> Self {
+        self.account_key = Some(key.into());
+        self
+    }
+
+    /// Use a user-assigned managed identity instead of the system-assigned one
+    pub fn with_managed_identity<S: Into<String>>(mut self, client_id: S) -> Self {
+        self.managed_identity = Some(client_id.into());
+        self
+    }
+
+    /// Do not ask the instance metadata service
+    pub fn without_instance_metadata(mut self) -> Self {
+        self.instance_metadata = false;
+        self
+    }
+}
+
+/// Azure Storage credentials
+#[derive(Clone)]
+pub enum AzureCredentials {
+    /// Storage account key
+    AccountKey {
+        /// The base64 account key
+        key: String,
+        /// Where the key was found
+        source: CredentialSource,
+    },
+    /// Shared access signature
+    SasToken {
+        /// The query string granting access
+        token: String,
+    },
+    /// Service principal with a client secret
+    ClientSecret {
+        /// Directory (tenant) ID
+        tenant_id: String,
+        /// Application (client) ID
+        client_id: String,
+        /// Client secret
+        client_secret: String,
+    },
+    /// OAuth access token of a managed identity
+    AccessToken {
+        /// The bearer token
+        token: String,
+        /// When the token expires
+        expires_at: DateTime<Utc>,
+    },
+}
+
+impl AzureCredentials {
+    /// Where the credentials were found
+    pub fn source(&self) -> CredentialSource {
+        match self {
+            AzureCredentials::AccountKey { source, .. } => *source,
+            AzureCredentials::SasToken { .. } | AzureCredentials::ClientSecret { .. } => CredentialSource::Environment,
+            AzureCredentials::AccessToken { .. } => CredentialSource::InstanceMetadata,
+        }
+    }
+
+    /// When the credentials expire, if they are temporary
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            AzureCredentials::AccessToken { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for AzureCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AzureCredentials::AccountKey { source, .. } => {
+                f.debug_struct("AccountKey").field("source", source).finish_non_exhaustive()
+            }
+            AzureCredentials::SasToken { .. } => f.debug_struct("SasToken").finish_non_exhaustive(),
+            AzureCredentials::ClientSecret { tenant_id, client_id, .. } => f
+                .debug_struct("ClientSecret")
+                .field("tenant_id", tenant_id)
+                .field("client_id", client_id)
+                .finish_non_exhaustive(),
+            AzureCredentials::AccessToken { expires_at, .. } => f
+                .debug_struct("AccessToken")
+                .field("expires_at", expires_at)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Answer of the managed identity token endpoint
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// UNIX timestamp, sent as a string
+    expires_on: String,
+}
+
+impl TokenResponse {
+    fn into_credentials(self) -> Option<AzureCredentials> {
+        let expires_on = self.expires_on.parse().ok()?;
+        Some(AzureCredentials::AccessToken {
+            token: self.access_token,
+            expires_at: Utc.timestamp_opt(expires_on, 0).single()?,
+        })
+    }
+}
+
+/// Find Azure Storage credentials; see the [module documentation](super) for the order
+pub fn resolve_azure(options: &AzureOptions) -> Result<AzureCredentials> {
+    if let Some(key) = &options.account_key {
+        return Ok(AzureCredentials::AccountKey {
+            key: key.clone(),
+            source: CredentialSource::Explicit,
+        });
+    }
+    if let Some(key) = env("AZURE_STORAGE_ACCOUNT_KEY").or_else(|| env("AZURE_STORAGE_KEY")) {
+        return Ok(AzureCredentials::AccountKey {
+            key,
+            source: CredentialSource::Environment,
+        });
+    }
+    if let Some(token) = env("AZURE_STORAGE_SAS_TOKEN") {
+        return Ok(AzureCredentials::SasToken { token });
+    }
+    if let (Some(tenant_id), Some(client_id), Some(client_secret)) =
+        (env("AZURE_TENANT_ID"), env("AZURE_CLIENT_ID"), env("AZURE_CLIENT_SECRET"))
+    {
+        return Ok(AzureCredentials::ClientSecret {
+            tenant_id,
+            client_id,
+            client_secret,
+        });
+    }
+
+    if options.instance_metadata {
+        let mut url = format!("{}&resource={}", IMDS_TOKEN_URL, STORAGE_RESOURCE);
+        if let Some(client_id) = &options.managed_identity {
+            url.push_str(&format!("&client_id={}", client_id));
+        }
+        if let Some(body) = metadata_request("GET", &url, &["Metadata: true"]) {
+            match serde_json::from_str::<TokenResponse>(&body).map(TokenResponse::into_credentials) {
+                Ok(Some(credentials)) => return Ok(credentials),
+                Ok(None) => debug!("Managed identity token with an invalid expiry"),
+                Err(e) => debug!("Unexpected managed identity token: {}", e),
+            }
+        }
+    }
+
+    Err(ProviderError::NoCredentials {
+        provider: "Azure",
+        tried: "configuration, AZURE_STORAGE_* variables, service principal variables, managed identity".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_managed_identity_token() {
+        let body = r#"{"access_token":"eyJ0eXAi","expires_on":"1893456000","resource":"https://storage.azure.com/","token_type":"Bearer"}"#;
+        let response: TokenResponse = serde_json::from_str(body).unwrap();
+        let credentials = response.into_credentials().unwrap();
+        assert_eq!(credentials.source(), CredentialSource::InstanceMetadata);
+        assert_eq!(credentials.expires_at().unwrap().timestamp(), 1893456000);
+    }
+}
@@ -0,0 +1,120 @@
+//! Cloud provider credentials
+//!
+//! Storage backends should not need long-lived access keys in their
+//! configuration. The resolvers here look for credentials the way the
+//! providers' own tools do, trying each source in turn:
+//!
+//! | Provider | Sources, in order |
+//! |----------|-------------------|
+//! | AWS      | explicit keys, `AWS_*` variables, shared credentials file, CLI/SSO credential cache, EC2 instance metadata (IMDSv2) |
+//! | GCP      | explicit key file, `GOOGLE_APPLICATION_CREDENTIALS`, gcloud application default credentials, GCE metadata server |
+//! | Azure    | explicit key, `AZURE_STORAGE_*` variables, service principal variables, managed identity (IMDS) |
+//!
+//! Credentials from metadata services and caches are temporary; check
+//! [`expires_at`](AwsCredentials::expires_at) and resolve again when they
+//! run out. Resolution may block on the network for a few seconds, so call
+//! it from `spawn_blocking` in async code.
+
+mod aws;
+mod azure;
+mod gcp;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use thiserror::Error;
+
+pub use aws::{resolve_aws, AwsCredentials, AwsOptions};
+pub use azure::{resolve_azure, AzureCredentials, AzureOptions};
+pub use gcp::{resolve_gcp, GcpCredentials, GcpOptions};
+
+/// Seconds to wait for a metadata service, which only answers on cloud instances
+const METADATA_TIMEOUT_SECS: &str = "2";
+
+/// Error type for credential resolution
+#[derive(Error, Debug)]
+pub enum ProviderError {
+    /// No source had credentials
+    #[error("No {provider} credentials found (tried {tried})")]
+    NoCredentials {
+        /// Provider credentials were looked for
+        provider: &'static str,
+        /// Sources that were tried
+        tried: String,
+    },
+
+    /// Credentials that exist but cannot be used
+    #[error("Invalid {0} credentials: {1}")]
+    Invalid(&'static str, String),
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Result type for credential resolution
+pub type Result<T> = std::result::Result<T, ProviderError>;
+
+/// Where credentials were found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialSource {
+    /// Given explicitly, for example in `backup.toml`
+    Explicit,
+    /// Environment variables
+    Environment,
+    /// A credentials or key file
+    File,
+    /// A cache written by the provider's command-line tools after an SSO login
+    SsoCache,
+    /// The instance metadata service of a cloud VM
+    InstanceMetadata,
+}
+
+impl fmt::Display for CredentialSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CredentialSource::Explicit => "configuration",
+            CredentialSource::Environment => "environment",
+            CredentialSource::File => "credentials file",
+            CredentialSource::SsoCache => "SSO cache",
+            CredentialSource::InstanceMetadata => "instance metadata",
+        })
+    }
+}
+
+/// Non-empty value of an environment variable
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// The user's home directory
+fn home_dir() -> Option<PathBuf> {
+    env("HOME").map(PathBuf::from)
+}
+
+/// Parse an RFC 3339 timestamp as used by all three providers
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text).ok().map(|time| time.with_timezone(&Utc))
+}
+
+/// Request a metadata service URL, returning `None` if it does not answer
+///
+/// Metadata services are link-local, so proxies are bypassed.
+fn metadata_request(method: &str, url: &str, headers: &[&str]) -> Option<String> {
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--fail", "--noproxy", "*", "--max-time", METADATA_TIMEOUT_SECS])
+        .args(["--request", method]);
+    for header in headers {
+        command.args(["--header", header]);
+    }
+    let output = command.arg(url).output().ok()?;
+    if !output.status.success() {
+        debug!("No answer from {}", url);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,318 @@
+//! Encryption of configuration files with a machine key
+//!
+//! A sealed file holds AES-256-GCM ciphertext under a key that never leaves
+//! the machine, so a copied `keys.toml` is useless on its own. The machine
+//! key is kept in one of two ways:
+//!
+//! - a key file readable only by its owner, [`MACHINE_KEY_PATH`]
+//! - a key sealed to the TPM2 chip with `systemd-creds`, [`SEALED_MACHINE_KEY_PATH`],
+//!   which cannot be unsealed on another machine even with root access to a
+//!   disk image
+//!
+//! Sealed files start with a header naming the kind of machine key, so
+//! readers can decrypt them without any configuration. The key is created
+//! the first time a file is sealed.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use log::info;
+use rand_core::RngCore;
+use thiserror::Error;
+
+use crate::fs::{atomic_write_with_options, WriteOptions};
+
+/// Machine key file
+pub const MACHINE_KEY_PATH: &str = "/etc/rast/auth/machine.key";
+
+/// Machine key sealed to the TPM2
+pub const SEALED_MACHINE_KEY_PATH: &str = "/etc/rast/auth/machine.key.cred";
+
+/// First bytes of a sealed file
+const MAGIC: &[u8; 8] = b"RASTSEAL";
+
+/// Version of the sealed file format
+const VERSION: u8 = 1;
+
+/// Length of the header: magic, version and key kind
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// Size of the nonce in bytes (96 bits for AES-GCM)
+const NONCE_SIZE: usize = 12;
+
+/// Size of the machine key in bytes
+const KEY_SIZE: usize = 32;
+
+/// Credential name bound into the TPM2-sealed key
+const CREDENTIAL_NAME: &str = "rast-machine-key";
+
+/// Error type for sealing
+#[derive(Error, Debug)]
+pub enum SealError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Key file with unsafe permissions or contents
+    #[error("Unusable machine key {path}: {reason}")]
+    InsecureKey {
+        /// The key file
+        path: PathBuf,
+        /// What is wrong with it
+        reason: String,
+    },
+
+    /// `systemd-creds` failed
+    #[error("TPM2 error: {0}")]
+    Tpm(String),
+
+    /// Not a sealed file, or one of an unknown version
+    #[error("Invalid sealed file: {0}")]
+    Format(String),
+
+    /// Decryption failed: wrong machine key or tampered file
+    #[error("Decryption failed; the file was sealed with another machine key or has been modified")]
+    Decrypt,
+}
+
+/// Result type for sealing
+pub type Result<T> = std::result::Result<T, SealError>;
+
+/// How the machine key is protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealKind {
+    /// A key file readable only by its owner
+    KeyFile,
+    /// A key sealed to the TPM2 with `systemd-creds`
+    Tpm2,
+}
+
+impl SealKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            SealKind::KeyFile => 0,
+            SealKind::Tpm2 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(SealKind::KeyFile),
+            1 => Ok(SealKind::Tpm2),
+            other => Err(SealError::Format(format!("unknown key kind {}", other))),
+        }
+    }
+}
+
+/// The key that seals files on this machine
+#[derive(Debug, Clone)]
+pub struct MachineKey {
+    kind: SealKind,
+    path: PathBuf,
+}
+
+impl MachineKey {
+    /// The system's machine key of the given kind
+    pub fn system(kind: SealKind) -> Self {
+        match kind {
+            SealKind::KeyFile => Self::key_file(MACHINE_KEY_PATH),
+            SealKind::Tpm2 => Self::tpm2(SEALED_MACHINE_KEY_PATH),
+        }
+    }
+
+    /// A machine key kept in a key file
+    pub fn key_file<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            kind: SealKind::KeyFile,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// A machine key sealed to the TPM2, stored as a credential at `path`
+    pub fn tpm2<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            kind: SealKind::Tpm2,
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// How the key is protected
+    pub fn kind(&self) -> SealKind {
+        self.kind
+    }
+
+    /// Where the key is stored
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Encrypt `plaintext`, creating the key if it does not exist yet
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let key = if self.path.exists() { self.load()? } else { self.create()? };
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| SealError::Decrypt)?;
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| SealError::Decrypt)?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.push(self.kind.to_byte());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt a file sealed with this key
+    pub fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let kind = sealed_kind(sealed)?;
+        if kind != self.kind {
+            return Err(SealError::Format(format!("sealed with a {:?} key, not {:?}", kind, self.kind)));
+        }
+        let body = &sealed[HEADER_LEN..];
+        if body.len() < NONCE_SIZE {
+            return Err(SealError::Format("truncated".to_string()));
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+
+        let key = self.load()?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| SealError::Decrypt)?;
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SealError::Decrypt)
+    }
+
+    fn load(&self) -> Result<Vec<u8>> {
+        let key = match self.kind {
+            SealKind::KeyFile => {
+                let mode = fs::metadata(&self.path)?.permissions().mode();
+                if mode & 0o077 != 0 {
+                    return Err(self.insecure(format!("mode {:o} gives access to other users", mode & 0o777)));
+                }
+                fs::read(&self.path)?
+            }
+            SealKind::Tpm2 => systemd_creds(
+                &["decrypt", &format!("--name={}", CREDENTIAL_NAME), &self.path.to_string_lossy(), "-"],
+                None,
+            )?,
+        };
+        if key.len() != KEY_SIZE {
+            return Err(self.insecure(format!("{} bytes instead of {}", key.len(), KEY_SIZE)));
+        }
+        Ok(key)
+    }
+
+    fn create(&self) -> Result<Vec<u8>> {
+        let mut key = vec![0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let stored = match self.kind {
+            SealKind::KeyFile => key.clone(),
+            SealKind::Tpm2 => systemd_creds(
+                &["encrypt", "--with-key=tpm2", &format!("--name={}", CREDENTIAL_NAME), "-", "-"],
+                Some(&key),
+            )?,
+        };
+        let options = WriteOptions::default().with_mode(0o600);
+        atomic_write_with_options(&self.path, stored, &options).map_err(io::Error::from)?;
+        info!("Created machine key {}", self.path.display());
+        Ok(key)
+    }
+
+    fn insecure(&self, reason: String) -> SealError {
+        SealError::InsecureKey {
+            path: self.path.clone(),
+            reason,
+        }
+    }
+}
+
+/// Check whether `data` is a sealed file
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// The kind of machine key a sealed file needs
+pub fn sealed_kind(sealed: &[u8]) -> Result<SealKind> {
+    if !is_sealed(sealed) || sealed.len() < HEADER_LEN {
+        return Err(SealError::Format("missing header".to_string()));
+    }
+    if sealed[MAGIC.len()] != VERSION {
+        return Err(SealError::Format(format!("unsupported version {}", sealed[MAGIC.len()])));
+    }
+    SealKind::from_byte(sealed[MAGIC.len() + 1])
+}
+
+/// Decrypt a sealed file with the system's machine key, or return other data unchanged
+pub fn unseal_if_sealed(data: Vec<u8>) -> Result<Vec<u8>> {
+    if !is_sealed(&data) {
+        return Ok(data);
+    }
+    MachineKey::system(sealed_kind(&data)?).unseal(&data)
+}
+
+/// Run `systemd-creds`, feeding `input` on stdin, and return its output
+fn systemd_creds(args: &[&str], input: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut child = Command::new("systemd-creds")
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| SealError::Tpm(format!("cannot run systemd-creds: {}", e)))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(SealError::Tpm(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_seal_roundtrip() {
+        let dir = tempdir().unwrap();
+        let key = MachineKey::key_file(dir.path().join("machine.key"));
+
+        let sealed = key.seal(b"[keys.backup]\nprimary = \"secret\"\n").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(sealed_kind(&sealed).unwrap(), SealKind::KeyFile);
+        assert_eq!(fs::metadata(key.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(key.unseal(&sealed).unwrap(), b"[keys.backup]\nprimary = \"secret\"\n");
+
+        // Another machine's key cannot open the file
+        let other = MachineKey::key_file(dir.path().join("other.key"));
+        other.seal(b"").unwrap();
+        assert!(matches!(other.unseal(&sealed), Err(SealError::Decrypt)));
+
+        // Plain files pass through
+        assert_eq!(unseal_if_sealed(b"plain".to_vec()).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn test_reject_readable_key() {
+        let dir = tempdir().unwrap();
+        let key = MachineKey::key_file(dir.path().join("machine.key"));
+        let sealed = key.seal(b"data").unwrap();
+
+        fs::set_permissions(key.path(), fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(key.unseal(&sealed), Err(SealError::InsecureKey { .. })));
+    }
+}
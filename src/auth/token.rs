@@ -0,0 +1,347 @@
+//! Short-lived tokens for the rastOS daemon API
+//!
+//! Clients exchange an API key for a token once, then send only the token
+//! to the local daemon. Tokens are JSON Web Tokens signed with Ed25519
+//! (`alg: EdDSA`), carry the service and the scopes they were granted, and
+//! expire after a few minutes, so a leaked token is worth much less than a
+//! leaked key. The daemon needs only the public key to check them.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{ApiKeyConfig, ApiKeyManager, AuthError, Result};
+use crate::secrets::{SecretError, SecretStore};
+
+/// Lifetime of tokens unless configured otherwise
+const DEFAULT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Longest lifetime a token may be given
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Clock difference tolerated when checking expiry
+const DEFAULT_LEEWAY: Duration = Duration::from_secs(30);
+
+/// Issuer name of tokens for the local daemon
+const DEFAULT_ISSUER: &str = "rastos";
+
+/// Scope granting every other scope
+const ALL_SCOPES: &str = "*";
+
+/// JOSE header of every token
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+/// Claims carried by a token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Issuer of the token
+    pub iss: String,
+    /// Service whose API key the token was exchanged for
+    pub sub: String,
+    /// Issue time (UNIX timestamp)
+    pub iat: i64,
+    /// Expiry time (UNIX timestamp)
+    pub exp: i64,
+    /// Unique token ID, for audit logs
+    pub jti: String,
+    /// Operations the token allows, such as `backup:create`
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl Claims {
+    /// Check whether the token grants `scope`
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == ALL_SCOPES)
+    }
+
+    /// Fail unless the token grants `scope`
+    pub fn require_scope(&self, scope: &str) -> Result<()> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientScope(scope.to_string()))
+        }
+    }
+}
+
+/// A signed token and when it expires
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// The encoded token to send in `Authorization: Bearer` headers
+    pub token: String,
+    /// Expiry time (UNIX timestamp)
+    pub expires_at: i64,
+}
+
+/// Exchanges API keys for signed tokens
+pub struct TokenIssuer {
+    signing_key: SigningKey,
+    issuer: String,
+    ttl: Duration,
+    /// Scopes each service may request
+    scopes: HashMap<String, Vec<String>>,
+}
+
+impl TokenIssuer {
+    /// Create an issuer signing with `signing_key`
+    pub fn new(signing_key: SigningKey) -> Self {
+        Self {
+            signing_key,
+            issuer: DEFAULT_ISSUER.to_string(),
+            ttl: DEFAULT_TTL,
+            scopes: HashMap::new(),
+        }
+    }
+
+    /// Create an issuer with a new random signing key
+    ///
+    /// Tokens it issues become unverifiable once the issuer is gone.
+    pub fn generate() -> Self {
+        Self::new(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Load the signing key from a secret store, creating it on first use
+    pub fn load_or_generate(store: &SecretStore, name: &str) -> Result<Self> {
+        let secret = match store.get(name) {
+            Ok(secret) => secret,
+            Err(SecretError::NotFound(_)) => {
+                let issuer = Self::generate();
+                store.set(name, issuer.signing_key.as_bytes()).map_err(secret_error)?;
+                return Ok(issuer);
+            }
+            Err(e) => return Err(secret_error(e)),
+        };
+        let bytes: [u8; 32] = secret
+            .try_into()
+            .map_err(|_| AuthError::Other(format!("Signing key '{}' has the wrong length", name)))?;
+        Ok(Self::new(SigningKey::from_bytes(&bytes)))
+    }
+
+    /// Set the issuer name written into tokens
+    pub fn with_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Set the token lifetime, at most one day
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl.min(MAX_TTL);
+        self
+    }
+
+    /// Allow tokens for `service` to carry `scopes`
+    pub fn with_service_scopes<S: Into<String>>(mut self, service: S, scopes: &[&str]) -> Self {
+        self.scopes
+            .insert(service.into(), scopes.iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    /// Allow the scopes listed for each service in the API key configuration
+    pub fn with_config_scopes(mut self, config: &ApiKeyConfig) -> Self {
+        for (service, keys) in &config.keys {
+            self.scopes.insert(service.clone(), keys.scopes.clone());
+        }
+        self
+    }
+
+    /// Verifier for the tokens this issuer signs
+    pub fn verifier(&self) -> TokenVerifier {
+        TokenVerifier::new(self.signing_key.verifying_key()).with_issuer(self.issuer.clone())
+    }
+
+    /// Exchange a valid API key for `service` for a token carrying `scopes`
+    ///
+    /// Fails if the key is not valid for the service, or if a scope was not
+    /// allowed for the service with [`with_service_scopes`](Self::with_service_scopes).
+    pub fn issue(&self, manager: &ApiKeyManager, api_key: &str, service: &str, scopes: &[&str]) -> Result<Token> {
+        manager.validate_key(api_key, service)?;
+        let allowed = self.scopes.get(service);
+        for scope in scopes {
+            let granted = allowed.is_some_and(|allowed| allowed.iter().any(|s| s == scope || s == ALL_SCOPES));
+            if !granted {
+                return Err(AuthError::InsufficientScope(scope.to_string()));
+            }
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            iss: self.issuer.clone(),
+            sub: service.to_string(),
+            iat: now,
+            exp: now + self.ttl.as_secs() as i64,
+            jti: random_id(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        };
+        Ok(Token {
+            token: self.sign(&claims)?,
+            expires_at: claims.exp,
+        })
+    }
+
+    fn sign(&self, claims: &Claims) -> Result<String> {
+        let header = Header {
+            alg: "EdDSA".to_string(),
+            typ: "JWT".to_string(),
+        };
+        let signing_input = format!("{}.{}", encode_json(&header)?, encode_json(claims)?);
+        let signature = self.signing_key.sign(signing_input.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(signature.to_bytes(), base64::URL_SAFE_NO_PAD)
+        ))
+    }
+}
+
+/// Checks tokens signed by a [`TokenIssuer`]
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    verifying_key: VerifyingKey,
+    issuer: String,
+    leeway: Duration,
+}
+
+impl TokenVerifier {
+    /// Create a verifier for tokens signed by the key matching `verifying_key`
+    pub fn new(verifying_key: VerifyingKey) -> Self {
+        Self {
+            verifying_key,
+            issuer: DEFAULT_ISSUER.to_string(),
+            leeway: DEFAULT_LEEWAY,
+        }
+    }
+
+    /// Only accept tokens from this issuer
+    pub fn with_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Set the clock difference tolerated when checking expiry
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Check a token's signature, issuer and expiry, returning its claims
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let invalid = |reason: &str| AuthError::InvalidToken(reason.to_string());
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("not a JSON Web Token"));
+        };
+
+        // Only EdDSA is accepted, whatever the header claims
+        let header: Header = decode_json(header)?;
+        if header.alg != "EdDSA" {
+            return Err(invalid("unsupported signature algorithm"));
+        }
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| invalid("malformed signature"))?;
+        let signing_input = &token[..token.rfind('.').expect("three parts")];
+        self.verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .map_err(|_| invalid("bad signature"))?;
+
+        let claims: Claims = decode_json(claims)?;
+        if claims.iss != self.issuer {
+            return Err(invalid("unexpected issuer"));
+        }
+        if chrono::Utc::now().timestamp() > claims.exp + self.leeway.as_secs() as i64 {
+            return Err(AuthError::ExpiredToken);
+        }
+        Ok(claims)
+    }
+}
+
+fn encode_json<T: Serialize>(value: &T) -> Result<String> {
+    let json = serde_json::to_vec(value).map_err(|e| AuthError::Other(e.to_string()))?;
+    Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| AuthError::InvalidToken("malformed token".to_string()))
+}
+
+fn random_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn secret_error(e: SecretError) -> AuthError {
+    AuthError::Other(format!("Cannot access the token signing key: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ApiKey;
+
+    fn manager() -> ApiKeyManager {
+        let manager = ApiKeyManager::new();
+        manager
+            .add_key(ApiKey {
+                key: "backup-key".to_string(),
+                service: "backup".to_string(),
+                description: None,
+                expires_at: None,
+            })
+            .unwrap();
+        manager
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let issuer = TokenIssuer::generate().with_service_scopes("backup", &["backup:create", "backup:list"]);
+        let token = issuer.issue(&manager(), "backup-key", "backup", &["backup:list"]).unwrap();
+
+        let claims = issuer.verifier().verify(&token.token).unwrap();
+        assert_eq!(claims.sub, "backup");
+        assert_eq!(claims.exp, token.expires_at);
+        assert!(claims.require_scope("backup:list").is_ok());
+        assert!(matches!(claims.require_scope("backup:create"), Err(AuthError::InsufficientScope(_))));
+
+        // Another issuer's key does not verify the token
+        assert!(matches!(
+            TokenIssuer::generate().verifier().verify(&token.token),
+            Err(AuthError::InvalidToken(_))
+        ));
+        let mut tampered = token.token.clone();
+        tampered.insert_str(tampered.find('.').unwrap() + 1, "e30");
+        assert!(issuer.verifier().verify(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_issue_rejects() {
+        let issuer = TokenIssuer::generate().with_service_scopes("backup", &["backup:list"]);
+        assert!(matches!(
+            issuer.issue(&manager(), "wrong-key", "backup", &[]),
+            Err(AuthError::InvalidApiKey)
+        ));
+        assert!(matches!(
+            issuer.issue(&manager(), "backup-key", "backup", &["system:reboot"]),
+            Err(AuthError::InsufficientScope(_))
+        ));
+
+        let expired = issuer.with_ttl(Duration::ZERO);
+        let token = expired.issue(&manager(), "backup-key", "backup", &[]).unwrap();
+        let verifier = expired.verifier().with_leeway(Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(matches!(verifier.verify(&token.token), Err(AuthError::ExpiredToken)));
+    }
+}
@@ -1,32 +1,37 @@
 //! BTRFS snapshot management for rastOS backups
 
 use std::{
-    ffi::OsStr,
     path::{Path, PathBuf},
-    process::Command,
-    time::SystemTime,
+    process::{Command, Stdio},
 };
 
-use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// Result type for BTRFS operations
+pub type Result<T> = std::result::Result<T, BtrfsError>;
+
 /// Error type for BTRFS operations
 #[derive(Error, Debug)]
 pub enum BtrfsError {
+    /// The `btrfs` command failed
     #[error("BTRFS command failed: {0}")]
     CommandFailed(String),
     
+    /// The path cannot name a subvolume
     #[error("Invalid subvolume path: {0}")]
     InvalidSubvolume(String),
     
+    /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     
+    /// The path is not a subvolume
     #[error("Path is not a BTRFS subvolume: {0:?}")]
     NotASubvolume(PathBuf),
     
+    /// The snapshot destination already exists
     #[error("Snapshot already exists: {0:?}")]
     SnapshotExists(PathBuf),
 }
@@ -55,26 +60,16 @@ impl Subvolume {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
         
-        // Run btrfs subvolume create
-        let output = Command::new("btrfs")
-            .args(["subvolume", "create", path.as_os_str().to_str().unwrap()])
-            .output()?;
-            
-        if !output.status.success() {
-            return Err(BtrfsError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
-        }
+        crate::sys::btrfs::create_subvolume(path)?;
         
         // Get subvolume info
         Self::from_path(path)
     }
     
     /// Create a read-only snapshot of an existing subvolume
-    pub fn create_snapshot<P: AsRef<Path>>(
-        source: P,
-        dest: P,
+    pub fn create_snapshot<S: AsRef<Path>, D: AsRef<Path>>(
+        source: S,
+        dest: D,
         read_only: bool,
     ) -> Result<Self> {
         let source = source.as_ref();
@@ -82,12 +77,12 @@ impl Subvolume {
         
         // Check if source is a subvolume
         if !Self::is_subvolume(source)? {
-            return Err(BtrfsError::NotASubvolume(source.to_path_buf()).into());
+            return Err(BtrfsError::NotASubvolume(source.to_path_buf()));
         }
         
         // Check if destination exists
         if dest.exists() {
-            return Err(BtrfsError::SnapshotExists(dest.to_path_buf()).into());
+            return Err(BtrfsError::SnapshotExists(dest.to_path_buf()));
         }
         
         // Create parent directories if they don't exist
@@ -97,29 +92,7 @@ impl Subvolume {
             }
         }
         
-        // Build the btrfs subvolume snapshot command
-        let mut cmd = Command::new("btrfs");
-        cmd.arg("subvolume");
-        
-        if read_only {
-            cmd.arg("snapshot");
-        } else {
-            cmd.arg("snapshot");
-        }
-        
-        cmd.arg("-r");
-        cmd.arg(source.as_os_str());
-        cmd.arg(dest.as_os_str());
-        
-        // Execute the command
-        let output = cmd.output()?;
-        
-        if !output.status.success() {
-            return Err(BtrfsError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
-        }
+        crate::sys::btrfs::snapshot(source, dest, read_only)?;
         
         // Get the created snapshot info
         Self::from_path(dest)
@@ -129,50 +102,20 @@ impl Subvolume {
     pub fn delete<P: AsRef<Path>>(path: P) -> Result<()> {
         let path = path.as_ref();
         
-        // Run btrfs subvolume delete
-        let output = Command::new("btrfs")
-            .args(["subvolume", "delete", path.as_os_str().to_str().unwrap()])
-            .output()?;
-            
-        if !output.status.success() {
-            return Err(BtrfsError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
-        }
-        
+        crate::sys::btrfs::delete_subvolume(path, false)?;
         Ok(())
     }
     
     /// List all subvolumes under a given path
     pub fn list_subvolumes<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
         let path = path.as_ref();
-        let output = Command::new("btrfs")
-            .args(["subvolume", "list", "-p", path.to_str().unwrap()])
-            .output()?;
-            
-        if !output.status.success() {
-            return Err(BtrfsError::CommandFailed(
-                String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
-        }
-        
-        let output_str = String::from_utf8_lossy(&output.stdout);
         let mut subvolumes = Vec::new();
-        
-        for line in output_str.lines() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 10 {
-                // Format: ID gen top level path parent_uuid received_uuid uuid path
-                if let (Some(id), Some(parent_id), Some(path_str)) = (parts[1].parse::<u64>().ok(), parts[3].parse::<u64>().ok(), parts.get(9)) {
-                    if id != 5 { // Skip the root subvolume (ID 5)
-                        let path = PathBuf::from(path_str.trim_start_matches("./"));
-                        if let Ok(subvol) = Self::from_path(&path) {
-                            subvolumes.push(subvol);
-                        }
-                    }
-                }
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(parent) = pending.pop() {
+            for nested in crate::sys::btrfs::nested_subvolumes(&parent)? {
+                let nested = parent.join(nested);
+                subvolumes.push(Self::from_path(&nested)?);
+                pending.push(nested);
             }
         }
         
@@ -181,58 +124,29 @@ impl Subvolume {
     
     /// Check if a path is a BTRFS subvolume
     pub fn is_subvolume<P: AsRef<Path>>(path: P) -> Result<bool> {
-        let path = path.as_ref();
-        let output = Command::new("btrfs")
-            .args(["subvolume", "show", path.to_str().unwrap()])
-            .output()?;
-            
-        Ok(output.status.success())
+        match crate::sys::btrfs::is_subvolume(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            result => Ok(result?),
+        }
     }
     
     /// Get subvolume information from a path
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
         
-        // Get subvolume info
-        let output = Command::new("btrfs")
-            .args(["subvolume", "show", path.to_str().unwrap()])
-            .output()?;
-            
-        if !output.status.success() {
-            return Err(BtrfsError::NotASubvolume(path.to_path_buf()).into());
+        if !Self::is_subvolume(path)? {
+            return Err(BtrfsError::NotASubvolume(path.to_path_buf()));
         }
+        let read_only = crate::sys::btrfs::is_read_only(path)?;
         
-        // Parse the output to get subvolume properties
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        let mut read_only = false;
+        // Subvolume roots are created with the subvolume
+        let stat = crate::sys::stat::lstat(path)?;
+        let created_at: DateTime<Utc> = stat.created.unwrap_or(stat.modified).into();
         
-        for line in output_str.lines() {
-            if line.contains("Flags:") && line.contains("readonly") {
-                read_only = true;
-                break;
-            }
-        }
-        
-        // Get file metadata for size and creation time
-        let metadata = std::fs::metadata(path)?;
-        let created_at = metadata.created()?;
-        let created_at: DateTime<Utc> = created_at.into();
-        
-        // Get size using du (more accurate for subvolumes)
-        let du_output = Command::new("du")
-            .args(["-bs", path.to_str().unwrap()])
-            .output()?;
-            
-        let size = if du_output.status.success() {
-            let output_str = String::from_utf8_lossy(&du_output.stdout);
-            output_str
-                .split_whitespace()
-                .next()
-                .and_then(|s| s.parse::<u64>().ok())
-                .unwrap_or(0)
-        } else {
-            0
-        };
+        // Apparent size of everything in the subvolume, as `du -bs` reports it
+        let size = crate::fs::disk_usage(path)
+            .map(|usage| usage.apparent)
+            .unwrap_or(0);
         
         Ok(Self {
             path: path.to_path_buf(),
@@ -248,8 +162,8 @@ impl Subvolume {
         Self::create_snapshot(&self.path, dest, true)
     }
     
-    /// Send this subvolume to a file or stream
-    pub fn send<P: AsRef<Path>>(&self, output: Option<P>) -> Result<()> {
+    /// The `btrfs send` command writing this subvolume to its stdout
+    pub fn send_command(&self) -> Command {
         let mut cmd = Command::new("btrfs");
         cmd.arg("send");
         
@@ -259,15 +173,30 @@ impl Subvolume {
         }
         
         cmd.arg(&self.path);
+        cmd
+    }
+    
+    /// The `btrfs receive` command creating `dest` from its stdin
+    ///
+    /// Creates the parent directory of `dest`, which receives into it.
+    pub fn receive_command<P: AsRef<Path>>(dest: P) -> Result<Command> {
+        let dest = dest.as_ref();
+        let parent = dest.parent().unwrap_or_else(|| Path::new("/"));
+        if !parent.exists() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut cmd = Command::new("btrfs");
+        cmd.arg("receive").arg(parent);
+        Ok(cmd)
+    }
+    
+    /// Send this subvolume to a file or stream
+    pub fn send<P: AsRef<Path>>(&self, output: Option<P>) -> Result<()> {
+        let mut cmd = self.send_command();
         
         // Redirect output if specified
         if let Some(output_path) = output {
-            use std::fs::File;
-            use std::os::unix::io::FromRawFd;
-            
-            let file = File::create(output_path)?;
-            let stdout = unsafe { std::process::Stdio::from_raw_fd(file.into_raw_fd()) };
-            cmd.stdout(stdout);
+            cmd.stdout(Stdio::from(std::fs::File::create(output_path)?));
         }
         
         let output = cmd.output()?;
@@ -275,38 +204,25 @@ impl Subvolume {
         if !output.status.success() {
             return Err(BtrfsError::CommandFailed(
                 String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
+            ));
         }
         
         Ok(())
     }
     
     /// Receive a subvolume from a file or stream
-    pub fn receive<P: AsRef<Path>>(input: P, dest: P) -> Result<Self> {
+    pub fn receive<I: AsRef<Path>, D: AsRef<Path>>(input: I, dest: D) -> Result<Self> {
         let dest = dest.as_ref();
-        
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = dest.parent() {
-            if !parent.exists() {
-                std::fs::create_dir_all(parent)?;
-            }
-        }
-        
         let input_file = std::fs::File::open(input)?;
-        let input_fd = unsafe { std::os::unix::io::AsRawFd::as_raw_fd(&input_file) };
         
-        let output = Command::new("btrfs")
-            .arg("receive")
-            .arg(dest.parent().unwrap_or_else(|| Path::new("/")))
-            .stdin(unsafe { std::process::Stdio::from_raw_fd(input_fd) })
+        let output = Self::receive_command(dest)?
+            .stdin(Stdio::from(input_file))
             .output()?;
             
         if !output.status.success() {
             return Err(BtrfsError::CommandFailed(
                 String::from_utf8_lossy(&output.stderr).into_owned(),
-            )
-            .into());
+            ));
         }
         
         Self::from_path(dest)
@@ -1,14 +1,18 @@
 //! CLI interface for the backup system
 
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::backup::{config::BackupConfig, BackupManager, Result};
+use crate::backup::{config::BackupConfig, BackupError, BackupManager, BackupState};
+use crate::dry_run::{Action, DryRun};
+use crate::error::{Context, Result};
+use crate::output::{ExitCode, Output, OutputArgs};
 
 /// Backup management commands
 #[derive(Debug, Parser)]
 #[command(name = "rast-backup", about = "Manage rastOS backups")]
 pub struct BackupCli {
+    /// Backup command to run
     #[command(subcommand)]
     pub command: BackupCommand,
 
@@ -19,6 +23,14 @@ pub struct BackupCli {
     /// Enable debug output
     #[arg(short, long)]
     pub debug: bool,
+
+    /// Show what would change instead of changing it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Output format
+    #[command(flatten)]
+    pub output: OutputArgs,
 }
 
 /// Backup subcommands
@@ -98,48 +110,98 @@ pub enum BackupCommand {
     },
 }
 
+impl BackupCommand {
+    /// Whether the command creates, changes or deletes anything
+    pub fn changes_backups(&self) -> bool {
+        !matches!(
+            self,
+            BackupCommand::List { .. } | BackupCommand::Verify { .. } | BackupCommand::Status { .. }
+        )
+    }
+}
+
 impl BackupCli {
     /// Create a new backup manager from the CLI configuration
     pub async fn create_manager(&self) -> Result<BackupManager> {
         // Load configuration
         let config = if self.config.exists() {
-            let config_data = tokio::fs::read_to_string(&self.config).await?;
-            toml::from_str(&config_data)?
+            let config_data = tokio::fs::read_to_string(&self.config)
+                .await
+                .with_context(|| format!("reading {}", self.config.display()))?;
+            toml::from_str(&config_data)
+                .map_err(|e| BackupError::Config(e.to_string()))
+                .with_context(|| format!("parsing {}", self.config.display()))?
         } else {
-            return Err(anyhow::anyhow!(
+            return Err(BackupError::Config(format!(
                 "Config file not found: {}",
                 self.config.display()
-            ));
+            ))
+            .into());
         };
 
-        BackupManager::new(config).await
+        Ok(BackupManager::new(config).await?)
     }
 
-    /// Execute the backup command
-    pub async fn execute(self) -> Result<()> {
-        let manager = self.create_manager().await?;
+    /// Execute the backup command, returning the process exit code
+    pub async fn execute(self) -> Result<ExitCode> {
+        let output = self.output.output();
+        let dry_run = DryRun::enabled(self.dry_run);
+        let manager = self
+            .create_manager()
+            .await?
+            .with_progress(output.progress())
+            .with_dry_run(dry_run.clone());
+        if dry_run.is_active() && self.command.changes_backups() {
+            self.plan(manager, &dry_run).await?;
+            output.plan(&dry_run.plan())?;
+            return Ok(ExitCode::Success);
+        }
 
-        match self.command {
+        match &self.command {
             BackupCommand::Create {
                 subvolume,
                 incremental,
                 description,
-            } => self.handle_create(manager, &subvolume, incremental, description).await,
+            } => {
+                self.handle_create(manager, subvolume, *incremental, description.clone(), output)
+                    .await?
+            }
             BackupCommand::List { subvolume, verbose } => {
-                self.handle_list(manager, subvolume, verbose).await
+                self.handle_list(manager, subvolume.clone(), *verbose, output).await?
             }
             BackupCommand::Restore {
                 backup_id,
                 target,
                 force,
-            } => self.handle_restore(manager, &backup_id, target, force).await,
-            BackupCommand::Verify { backup_id } => self.handle_verify(manager, &backup_id).await,
+            } => self.handle_restore(manager, backup_id, target.clone(), *force, output).await?,
+            BackupCommand::Verify { backup_id } => return self.handle_verify(manager, backup_id, output).await,
             BackupCommand::Remove { backup_id, force } => {
-                self.handle_remove(manager, &backup_id, force).await
+                self.handle_remove(manager, backup_id, *force, output).await?
+            }
+            BackupCommand::Status { verbose } => self.handle_status(manager, *verbose, output).await?,
+            BackupCommand::Init { storage, output: path } => {
+                self.handle_init(storage.clone(), path.clone(), output).await?
             }
-            BackupCommand::Status { verbose } => self.handle_status(manager, verbose).await,
-            BackupCommand::Init { storage, output } => self.handle_init(storage, output).await,
         }
+        Ok(ExitCode::Success)
+    }
+
+    /// Record what a command changing backups would do
+    async fn plan(&self, manager: BackupManager, dry_run: &DryRun) -> Result<()> {
+        match &self.command {
+            BackupCommand::Create { subvolume, .. } => {
+                dry_run.skip(Action::create(format!("backup of {}", subvolume)));
+            }
+            BackupCommand::Restore { backup_id, target, .. } => {
+                manager.restore_backup(backup_id, target.as_ref()).await?;
+            }
+            BackupCommand::Remove { backup_id, .. } => manager.delete_backup(backup_id).await?,
+            BackupCommand::Init { output: path, .. } => {
+                dry_run.skip(Action::write(path.display()));
+            }
+            BackupCommand::List { .. } | BackupCommand::Verify { .. } | BackupCommand::Status { .. } => {}
+        }
+        Ok(())
     }
 
     async fn handle_create(
@@ -148,18 +210,37 @@ impl BackupCli {
         subvolume: &str,
         incremental: bool,
         description: Option<String>,
+        output: Output,
     ) -> Result<()> {
-        println!("Creating backup of {}{}...", 
+        output.status(format!("Creating backup of {}{}...", 
             subvolume, 
             if incremental { " (incremental)" } else { "" }
-        );
+        ));
         
-        if let Some(desc) = description {
-            println!("Description: {}", desc);
+        if let Some(desc) = &description {
+            output.status(format!("Description: {}", desc));
         }
 
-        let backup_id = manager.create_backup(subvolume).await?;
-        println!("Backup created successfully: {}", backup_id);
+        // Incremental backups build on the newest complete backup of the same subvolume
+        let parent = if incremental {
+            let parent = manager
+                .list_backups()
+                .await?
+                .into_iter()
+                .filter(|backup| backup.subvolume_path == Path::new(subvolume) && backup.state == BackupState::Complete)
+                .max_by_key(|backup| backup.created_at);
+            if parent.is_none() {
+                output.status("No earlier backup of this subvolume, creating a full backup");
+            }
+            parent
+        } else {
+            None
+        };
+
+        let backup = manager
+            .create_backup(subvolume, None, description.as_deref(), parent.is_some(), parent.as_ref())
+            .await?;
+        output.print(&backup, || println!("Backup created successfully: {}", backup.id))?;
         Ok(())
     }
 
@@ -168,24 +249,36 @@ impl BackupCli {
         manager: BackupManager,
         subvolume: Option<String>,
         verbose: bool,
+        output: Output,
     ) -> Result<()> {
-        println!("Listing backups...");
-        let backups = manager.list_backups().await?;
+        let backups: Vec<_> = manager
+            .list_backups()
+            .await?
+            .into_iter()
+            .filter(|backup| {
+                subvolume
+                    .as_ref()
+                    .is_none_or(|subvol| backup.subvolume_path.to_string_lossy().contains(subvol.as_str()))
+            })
+            .collect();
         
-        for backup in backups {
-            if let Some(subvol) = &subvolume {
-                if !backup.contains(subvol) {
-                    continue;
-                }
-            }
-            
+        let mut headers = vec!["ID", "SUBVOLUME", "SIZE", "CREATED", "STATE"];
+        if verbose {
+            headers.push("DESCRIPTION");
+        }
+        output.table(&backups, &headers, |backup| {
+            let mut row = vec![
+                backup.id.clone(),
+                backup.subvolume_path.display().to_string(),
+                humansize::format_size(backup.size, humansize::BINARY),
+                backup.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                backup.state.to_string(),
+            ];
             if verbose {
-                // TODO: Show detailed backup info
-                println!("- {} (size: 123MB, date: 2023-01-01 12:00:00)", backup);
-            } else {
-                println!("- {}", backup);
+                row.push(backup.description.clone().unwrap_or_default());
             }
-        }
+            row
+        })?;
         
         Ok(())
     }
@@ -196,29 +289,31 @@ impl BackupCli {
         backup_id: &str,
         target: Option<PathBuf>,
         force: bool,
+        output: Output,
     ) -> Result<()> {
         if !force {
             // TODO: Add confirmation prompt
-            println!("Are you sure you want to restore backup {}? (y/N)", backup_id);
+            output.status(format!("Are you sure you want to restore backup {}? (y/N)", backup_id));
             // For now, just proceed
         }
 
-        println!("Restoring backup {}...", backup_id);
+        output.status(format!("Restoring backup {}...", backup_id));
         manager.restore_backup(backup_id, target).await?;
-        println!("Backup restored successfully");
+        output.status("Backup restored successfully");
         Ok(())
     }
 
-    async fn handle_verify(&self, manager: BackupManager, backup_id: &str) -> Result<()> {
-        println!("Verifying backup {}...", backup_id);
+    /// Verify a backup; exits with [`ExitCode::Critical`] when it does not verify
+    async fn handle_verify(&self, manager: BackupManager, backup_id: &str, output: Output) -> Result<ExitCode> {
+        output.status(format!("Verifying backup {}...", backup_id));
         let is_valid = manager.verify_backup(backup_id).await?;
         
         if is_valid {
-            println!("✓ Backup is valid");
-            Ok(())
+            output.status("✓ Backup is valid");
+            Ok(ExitCode::Success)
         } else {
-            println!("✗ Backup verification failed");
-            std::process::exit(1);
+            output.status("✗ Backup verification failed");
+            Ok(ExitCode::Critical)
         }
     }
 
@@ -227,68 +322,73 @@ impl BackupCli {
         manager: BackupManager,
         backup_id: &str,
         force: bool,
+        output: Output,
     ) -> Result<()> {
         if !force {
             // TODO: Add confirmation prompt
-            println!("Are you sure you want to delete backup {}? (y/N)", backup_id);
+            output.status(format!("Are you sure you want to delete backup {}? (y/N)", backup_id));
             // For now, just proceed
         }
 
-        println!("Removing backup {}...", backup_id);
-        // TODO: Implement backup removal
-        println!("Backup removed successfully");
+        output.status(format!("Removing backup {}...", backup_id));
+        manager.delete_backup(backup_id).await?;
+        output.status("Backup removed successfully");
         Ok(())
     }
 
-    async fn handle_status(&self, manager: BackupManager, verbose: bool) -> Result<()> {
-        println!("Backup status:");
+    async fn handle_status(&self, _manager: BackupManager, verbose: bool, output: Output) -> Result<()> {
+        output.status("Backup status:");
         // TODO: Implement status check
-        println!("- Storage: OK");
-        println!("- Last backup: 2023-01-01 12:00:00");
-        println!("- Backups: 10 (2.5 GB)");
+        output.status("- Storage: OK");
+        output.status("- Last backup: 2023-01-01 12:00:00");
+        output.status("- Backups: 10 (2.5 GB)");
         
         if verbose {
-            println!("\nDetailed status:");
-            println!("- Storage provider: S3 (my-bucket)");
-            println!("- Encryption: Enabled (AES-256-GCM)");
-            println!("- Last successful backup: 2023-01-01 12:00:00");
-            println!("- Next scheduled backup: 2023-01-02 02:00:00");
+            output.status("\nDetailed status:");
+            output.status("- Storage provider: S3 (my-bucket)");
+            output.status("- Encryption: Enabled (AES-256-GCM)");
+            output.status("- Last successful backup: 2023-01-01 12:00:00");
+            output.status("- Next scheduled backup: 2023-01-02 02:00:00");
         }
         
         Ok(())
     }
 
-    async fn handle_init(&self, storage: String, output: PathBuf) -> Result<()> {
-        println!("Initializing backup configuration...");
+    async fn handle_init(&self, storage: String, path: PathBuf, output: Output) -> Result<()> {
+        output.status("Initializing backup configuration...");
         
         // Create default config based on storage type
         let config = match storage.to_lowercase().as_str() {
             "s3" => {
-                println!("Configuring S3 storage");
+                output.status("Configuring S3 storage");
                 // TODO: Interactive configuration
                 BackupConfig::default()
             }
             "local" => {
-                println!("Configuring local storage");
+                output.status("Configuring local storage");
                 BackupConfig::default()
             }
             _ => {
-                return Err(anyhow::anyhow!("Unsupported storage type: {}", storage));
+                return Err(BackupError::InvalidArgument(format!("Unsupported storage type: {}", storage)).into());
             }
         };
         
         // Create parent directory if it doesn't exist
-        if let Some(parent) = output.parent() {
+        if let Some(parent) = path.parent() {
             if !parent.exists() {
                 tokio::fs::create_dir_all(parent).await?;
             }
         }
         
         // Write config file
-        let config_str = toml::to_string_pretty(&config)?;
-        tokio::fs::write(&output, config_str).await?;
+        let config_str = toml::to_string_pretty(&config).map_err(|e| BackupError::Config(e.to_string()))?;
+        let dest = path.clone();
+        tokio::task::spawn_blocking(move || crate::fs::atomic_write(dest, config_str))
+            .await
+            .map_err(BackupError::from)?
+            .with_context(|| format!("writing {}", path.display()))?;
         
-        println!("Configuration written to: {}", output.display());
+        output.status(format!("Configuration written to: {}", path.display()));
         Ok(())
     }
 }
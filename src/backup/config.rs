@@ -3,6 +3,8 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::secrets::BackendKind;
+
 /// Backup configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackupConfig {
@@ -20,6 +22,24 @@ pub struct BackupConfig {
     /// Performance settings
     #[serde(default)]
     pub performance: PerformanceSettings,
+    
+    /// Retries of failed storage requests
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    
+    /// Caching of backup metadata read from storage
+    #[serde(default)]
+    pub cache: CacheConfig,
+    
+    /// Keep backups under `machines/<machine identity>/` so several
+    /// machines can share a bucket; turn off to reach backups stored
+    /// without a namespace
+    #[serde(default = "default_machine_namespace")]
+    pub machine_namespace: bool,
+}
+
+fn default_machine_namespace() -> bool {
+    true
 }
 
 /// Storage provider configuration
@@ -43,11 +63,17 @@ pub enum StorageConfig {
         /// Endpoint URL (for non-AWS S3)
         endpoint: Option<String>,
         
-        /// Access key
-        access_key_id: String,
+        /// Access key; found like the AWS CLI does if unset
+        #[serde(default)]
+        access_key_id: Option<String>,
         
         /// Secret access key
-        secret_access_key: String,
+        #[serde(default)]
+        secret_access_key: Option<String>,
+        
+        /// Profile of the shared AWS credentials file
+        #[serde(default)]
+        profile: Option<String>,
     },
     
     // Add other storage providers as needed
@@ -62,6 +88,14 @@ pub struct EncryptionConfig {
     /// Path to encryption key
     pub key_path: Option<PathBuf>,
     
+    /// Name of the encryption key in the secret store, used instead of `key_path`
+    #[serde(default)]
+    pub key_secret: Option<String>,
+    
+    /// Secret store holding `key_secret`
+    #[serde(default)]
+    pub secret_backend: BackendKind,
+    
     /// Encryption algorithm
     pub algorithm: String,
 }
@@ -101,6 +135,59 @@ pub struct PerformanceSettings {
     pub max_bandwidth: Option<u64>,
 }
 
+/// Retries of storage requests that failed in a way that may pass
+///
+/// Delays grow exponentially from `initial_backoff_ms` up to
+/// `max_backoff_ms`, and each is a random time up to that bound, so
+/// clients failing together do not retry together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts of a request, including the first; 1 turns retries off
+    pub attempts: u32,
+    
+    /// Upper bound of the delay before the first retry, in milliseconds
+    pub initial_backoff_ms: u64,
+    
+    /// Upper bound of any delay, in milliseconds
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+/// Caching of backup metadata and listings
+///
+/// Listing backups reads the metadata of every backup; with a cache,
+/// repeated listings within `ttl_secs` do not reach the storage. Writes
+/// and deletions through the same backend update the cache, changes by
+/// other machines show after the TTL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// How long listings and metadata are reused, in seconds; 0 turns the cache off
+    pub ttl_secs: u64,
+    
+    /// Most objects kept
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 30,
+            max_entries: 1024,
+        }
+    }
+}
+
 impl Default for BackupConfig {
     fn default() -> Self {
         Self {
@@ -116,6 +203,9 @@ impl Default for BackupConfig {
                 compression_level: 3,
                 max_bandwidth: None,
             },
+            retry: Default::default(),
+            cache: Default::default(),
+            machine_namespace: default_machine_namespace(),
         }
     }
 }
@@ -1,30 +1,53 @@
 //! Encryption module for secure backup storage
+//!
+//! Data is encrypted in frames of up to [`CHUNK_SIZE`] bytes, each stored
+//! as its nonce, the ciphertext and the authentication tag. Frames are
+//! encrypted in place, and the streaming functions write each one with a
+//! single vectored write, so encrypting a stream never copies it.
 
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
-    Aes256Gcm, Nonce,
+    aead::{Aead, AeadCore, AeadInPlace, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce, Tag,
 };
-use anyhow::{anyhow, Result};
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand_core::RngCore;
+use std::io::IoSlice;
 use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::config::EncryptionConfig;
+use super::stream::{write_all_vectored, BufferPool, CHUNK_SIZE};
+use super::{BackupError, Result};
+use crate::fs::{atomic_write_with_options, shred, WriteOptions};
+use crate::secrets::SecretStore;
 
 /// Size of the nonce in bytes (96 bits for AES-GCM)
 const NONCE_SIZE: usize = 12;
 
+/// Size of the authentication tag in bytes
+const TAG_SIZE: usize = 16;
+
+/// Size of a full encrypted frame
+const FRAME_SIZE: usize = NONCE_SIZE + CHUNK_SIZE + TAG_SIZE;
+
+/// Buffers holding a whole encrypted frame while it is decrypted
+static FRAME_POOL: BufferPool = BufferPool::new(FRAME_SIZE, 4);
+
+/// Random overwrites of a key file before it is deleted
+const KEY_SHRED_PASSES: usize = 3;
+
 /// Encrypts data using AES-256-GCM
 pub fn encrypt_data(data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     // Generate a random nonce
-    let nonce = Nonce::from_slice(&{
-        let mut nonce = [0u8; NONCE_SIZE];
-        OsRng.fill_bytes(&mut nonce);
-        nonce
-    });
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
 
     // Create cipher instance
-    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| anyhow!(e))?;
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| BackupError::Encryption(e.to_string()))?;
 
     // Encrypt the data
-    let mut ciphertext = cipher.encrypt(nonce, data).map_err(|e| anyhow!(e))?;
+    let mut ciphertext = cipher.encrypt(nonce, data).map_err(|e| BackupError::Encryption(e.to_string()))?;
 
     // Prepend the nonce to the ciphertext
     let mut result = nonce.to_vec();
@@ -36,7 +59,7 @@ pub fn encrypt_data(data: &[u8], key: &[u8]) -> Result<Vec<u8>> {
 /// Decrypts data using AES-256-GCM
 pub fn decrypt_data(encrypted: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     if encrypted.len() < NONCE_SIZE {
-        return Err(anyhow!("Encrypted data too short"));
+        return Err(BackupError::Encryption("Encrypted data too short".to_string()));
     }
 
     // Split nonce and ciphertext
@@ -44,12 +67,12 @@ pub fn decrypt_data(encrypted: &[u8], key: &[u8]) -> Result<Vec<u8>> {
     let nonce = Nonce::from_slice(nonce_bytes);
 
     // Create cipher instance
-    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| anyhow!(e))?;
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| BackupError::Encryption(e.to_string()))?;
 
     // Decrypt the data
     let plaintext = cipher
         .decrypt(nonce, ciphertext)
-        .map_err(|e| anyhow!(e))?;
+        .map_err(|e| BackupError::Encryption(e.to_string()))?;
 
     Ok(plaintext)
 }
@@ -101,17 +124,133 @@ impl AesGcmEncryption {
     /// Load key from file
     pub async fn load_key(path: &Path) -> Result<Self> {
         let key = tokio::fs::read(path).await?;
+        Self::from_bytes(&key)
+    }
+
+    /// Load key from a secret store
+    pub async fn load_secret(store: &SecretStore, name: &str) -> Result<Self> {
+        let (store, name) = (store.clone(), name.to_string());
+        let key = tokio::task::spawn_blocking(move || store.get(&name)).await??;
+        Self::from_bytes(&key)
+    }
+
+    /// Load the key named by the configuration, preferring the secret store
+    pub async fn from_config(config: &EncryptionConfig) -> Result<Self> {
+        match (&config.key_secret, &config.key_path) {
+            (Some(name), _) => {
+                let store = SecretStore::open(config.secret_backend)?;
+                Self::load_secret(&store, name).await
+            }
+            (None, Some(path)) => Self::load_key(path).await,
+            (None, None) => Err(BackupError::Encryption("No encryption key configured".to_string())),
+        }
+    }
+
+    fn from_bytes(key: &[u8]) -> Result<Self> {
         if key.len() != 32 {
-            return Err(anyhow!("Invalid key length"));
+            return Err(BackupError::Encryption("Invalid key length".to_string()));
         }
         let mut key_array = [0u8; 32];
-        key_array.copy_from_slice(&key);
+        key_array.copy_from_slice(key);
         Ok(Self { key: key_array })
     }
 
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+
+    /// Encrypt `reader` to `writer` frame by frame, returning the bytes read
+    ///
+    /// Writes the same frames as [`EncryptionProvider::encrypt`] would for
+    /// the whole stream, holding one frame in memory.
+    pub async fn encrypt_stream<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let cipher = self.cipher();
+        let mut buf = BufferPool::shared().get();
+        let mut total = 0;
+        loop {
+            buf.clear();
+            let n = buf.fill(reader).await?;
+            // An empty stream still gets a frame, so it decrypts like empty data
+            if n == 0 && total > 0 {
+                break;
+            }
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let tag = cipher
+                .encrypt_in_place_detached(&nonce, b"", &mut buf[..])
+                .map_err(|e| BackupError::Encryption(e.to_string()))?;
+            let mut frame = [IoSlice::new(&nonce), IoSlice::new(&buf), IoSlice::new(&tag)];
+            write_all_vectored(writer, &mut frame).await?;
+            total += n as u64;
+            if n < CHUNK_SIZE {
+                break;
+            }
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    /// Decrypt frames from `reader` to `writer`, returning the bytes written
+    pub async fn decrypt_stream<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let cipher = self.cipher();
+        let mut buf = FRAME_POOL.get();
+        let mut total = 0;
+        let mut frames = 0;
+        loop {
+            buf.clear();
+            let n = buf.fill(reader).await?;
+            if n == 0 && frames > 0 {
+                break;
+            }
+            if n < NONCE_SIZE + TAG_SIZE {
+                return Err(BackupError::Encryption("Encrypted stream truncated".to_string()));
+            }
+            let (nonce, rest) = buf.split_at_mut(NONCE_SIZE);
+            let (plaintext, tag) = rest.split_at_mut(n - NONCE_SIZE - TAG_SIZE);
+            cipher
+                .decrypt_in_place_detached(Nonce::from_slice(nonce), b"", plaintext, Tag::from_slice(tag))
+                .map_err(|e| BackupError::Encryption(e.to_string()))?;
+            writer.write_all(plaintext).await?;
+            total += plaintext.len() as u64;
+            frames += 1;
+            if n < FRAME_SIZE {
+                break;
+            }
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+
     /// Save key to file
     pub async fn save_key(&self, path: &Path) -> Result<()> {
-        tokio::fs::write(path, &self.key).await?;
+        let (path, key) = (path.to_path_buf(), self.key);
+        let options = WriteOptions::default().with_mode(0o600);
+        tokio::task::spawn_blocking(move || atomic_write_with_options(path, key, &options)).await??;
+        Ok(())
+    }
+
+    /// Save key to a secret store
+    pub async fn save_secret(&self, store: &SecretStore, name: &str) -> Result<()> {
+        let (store, name, key) = (store.clone(), name.to_string(), self.key);
+        tokio::task::spawn_blocking(move || store.set(&name, &key)).await??;
+        Ok(())
+    }
+
+    /// Overwrite and delete a key file
+    ///
+    /// Backups encrypted with the key can no longer be restored. On
+    /// copy-on-write filesystems older copies of the key may survive; see
+    /// [`crate::fs::shred`].
+    pub async fn destroy_key(path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || shred(path, KEY_SHRED_PASSES)).await??;
         Ok(())
     }
 }
@@ -119,44 +258,38 @@ impl AesGcmEncryption {
 #[async_trait::async_trait]
 impl EncryptionProvider for AesGcmEncryption {
     async fn encrypt(&self, data: Bytes) -> Result<Bytes> {
-        // For small data, use a single buffer to avoid allocations
-        if data.len() < 1024 {
-            return Ok(Bytes::from(encrypt_data(&data, &self.key)?));
-        }
-
-        // For larger data, process in chunks
-        let mut encrypted = BytesMut::new();
-        let chunk_size = 64 * 1024; // 64KB chunks
-        let mut pos = 0;
-
-        while pos < data.len() {
-            let end = std::cmp::min(pos + chunk_size, data.len());
-            let chunk = &data[pos..end];
-            encrypted.extend_from_slice(&encrypt_data(chunk, &self.key)?);
-            pos = end;
+        // One allocation for all frames, each encrypted where it lands
+        let cipher = self.cipher();
+        let frames = data.len().div_ceil(CHUNK_SIZE).max(1);
+        let mut encrypted = BytesMut::with_capacity(data.len() + frames * (NONCE_SIZE + TAG_SIZE));
+        for chunk in data.chunks(CHUNK_SIZE).chain(data.is_empty().then_some(&[][..])) {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            encrypted.put_slice(&nonce);
+            let start = encrypted.len();
+            encrypted.put_slice(chunk);
+            let tag = cipher
+                .encrypt_in_place_detached(&nonce, b"", &mut encrypted[start..])
+                .map_err(|e| BackupError::Encryption(e.to_string()))?;
+            encrypted.put_slice(&tag);
         }
-
         Ok(encrypted.freeze())
     }
 
     async fn decrypt(&self, data: Bytes) -> Result<Bytes> {
-        // For small data, use a single buffer to avoid allocations
-        if data.len() < 1024 + NONCE_SIZE {
-            return Ok(Bytes::from(decrypt_data(&data, &self.key)?));
+        let cipher = self.cipher();
+        let mut decrypted = BytesMut::with_capacity(data.len());
+        for frame in data.chunks(FRAME_SIZE) {
+            if frame.len() < NONCE_SIZE + TAG_SIZE {
+                return Err(BackupError::Encryption("Encrypted data too short".to_string()));
+            }
+            let (nonce, rest) = frame.split_at(NONCE_SIZE);
+            let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+            let start = decrypted.len();
+            decrypted.put_slice(ciphertext);
+            cipher
+                .decrypt_in_place_detached(Nonce::from_slice(nonce), b"", &mut decrypted[start..], Tag::from_slice(tag))
+                .map_err(|e| BackupError::Encryption(e.to_string()))?;
         }
-
-        // For larger data, process in chunks
-        let mut decrypted = BytesMut::new();
-        let chunk_size = 64 * 1024 + NONCE_SIZE; // Account for nonce in each chunk
-        let mut pos = 0;
-
-        while pos < data.len() {
-            let end = std::cmp::min(pos + chunk_size, data.len());
-            let chunk = &data[pos..end];
-            decrypted.extend_from_slice(&decrypt_data(chunk, &self.key)?);
-            pos = end;
-        }
-
         Ok(decrypted.freeze())
     }
 }
@@ -164,7 +297,6 @@ impl EncryptionProvider for AesGcmEncryption {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hex_literal::hex;
 
     #[test]
     fn test_encryption_decryption() {
@@ -194,4 +326,38 @@ mod tests {
         let decrypted = provider.decrypt(encrypted).await.unwrap();
         assert_eq!(decrypted, data);
     }
+
+    #[tokio::test]
+    async fn test_stream_matches_frames() {
+        let provider = AesGcmEncryption::new(AesGcmEncryption::generate_key());
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let mut encrypted = Vec::new();
+        let read = provider.encrypt_stream(&mut &data[..], &mut encrypted).await.unwrap();
+        assert_eq!(read, data.len() as u64);
+        assert_eq!(encrypted.len(), data.len() + 3 * (NONCE_SIZE + TAG_SIZE));
+
+        // Streamed frames decrypt in one piece, and the other way round
+        let decrypted = provider.decrypt(Bytes::from(encrypted)).await.unwrap();
+        assert_eq!(decrypted, data);
+        let encrypted = provider.encrypt(Bytes::from(data.clone())).await.unwrap();
+        let mut decrypted = Vec::new();
+        provider.decrypt_stream(&mut &encrypted[..], &mut decrypted).await.unwrap();
+        assert_eq!(decrypted, data);
+
+        let mut truncated = Vec::new();
+        assert!(provider.decrypt_stream(&mut &encrypted[..FRAME_SIZE + 8], &mut truncated).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_in_secret_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SecretStore::new(crate::secrets::FileBackend::new(dir.path().join("secrets")));
+        let provider = AesGcmEncryption::new(AesGcmEncryption::generate_key());
+        provider.save_secret(&store, "backup-key").await.unwrap();
+
+        let loaded = AesGcmEncryption::load_secret(&store, "backup-key").await.unwrap();
+        assert_eq!(loaded.key, provider.key);
+        assert!(AesGcmEncryption::load_secret(&store, "missing").await.is_err());
+    }
 }
@@ -6,19 +6,33 @@
 #![warn(rust_2018_idioms)]
 #![forbid(unsafe_code)]
 
-use std::path::PathBuf;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
 use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 
-//! Backup management for rastOS
+use crate::dry_run::{Action, DryRun};
+use crate::lifecycle::{InvalidTransition, Lifecycle};
+use crate::progress::{Progress, Silent, Task};
+use crate::system::Identity;
 
 pub mod btrfs;
+#[cfg(feature = "cli")]
 pub mod cli;
 pub mod config;
 pub mod encryption;
-pub mod providers;
 pub mod snapshot;
 pub mod storage;
-pub mod tests;
+pub mod stream;
+#[cfg(test)]
+mod tests;
+
+pub use crate::snapshot::{DEFAULT_SNAPSHOT_DIR, LAST_BACKUP_STAMP};
 
 /// Result type for backup operations
 pub type Result<T> = std::result::Result<T, BackupError>;
@@ -38,6 +52,10 @@ pub enum BackupError {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     
+    /// Backup metadata that could not be read or written
+    #[error("Invalid backup metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+    
     /// Encryption error
     #[error("Encryption error: {0}")]
     Encryption(String),
@@ -49,6 +67,110 @@ pub enum BackupError {
     /// Invalid argument
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
+    
+    /// Cloud credentials error
+    #[error("Credentials error: {0}")]
+    Credentials(#[from] crate::auth::providers::ProviderError),
+    
+    /// Btrfs subvolume error
+    #[error("Btrfs error: {0}")]
+    Btrfs(#[from] btrfs::BtrfsError),
+    
+    /// Filesystem error
+    #[error("{0}")]
+    Fs(#[from] crate::fs::FsError),
+    
+    /// Secret store error
+    #[error("Secret store error: {0}")]
+    Secret(#[from] crate::secrets::SecretError),
+    
+    /// A blocking task panicked or was cancelled
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    
+    /// An operation the backup's state does not allow
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidTransition),
+}
+
+/// Where a backup is in its life
+///
+/// The metadata of a backup is written before its stream is uploaded, so
+/// backups that are still uploading, or whose upload failed, are listed
+/// but cannot be restored or built upon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupState {
+    /// The stream is being uploaded
+    Uploading,
+    /// The stream was uploaded completely
+    #[default]
+    Complete,
+    /// The upload failed
+    Failed,
+}
+
+impl std::fmt::Display for BackupState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BackupState::Uploading => "uploading",
+            BackupState::Complete => "complete",
+            BackupState::Failed => "failed",
+        })
+    }
+}
+
+/// Operations on a backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupOp {
+    /// Record that the upload completed
+    Finish,
+    /// Record that the upload failed
+    Fail,
+    /// Restore the backup
+    Restore,
+    /// Base an incremental backup on it
+    Extend,
+    /// Delete the backup, which also discards interrupted uploads
+    Delete,
+}
+
+impl std::fmt::Display for BackupOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BackupOp::Finish => "finish",
+            BackupOp::Fail => "fail",
+            BackupOp::Restore => "restore",
+            BackupOp::Extend => "extend",
+            BackupOp::Delete => "delete",
+        })
+    }
+}
+
+impl Lifecycle for BackupState {
+    type Op = BackupOp;
+
+    const KIND: &'static str = "backup";
+
+    const STATES: &'static [Self] = &[BackupState::Uploading, BackupState::Complete, BackupState::Failed];
+
+    const OPS: &'static [BackupOp] = &[
+        BackupOp::Finish,
+        BackupOp::Fail,
+        BackupOp::Restore,
+        BackupOp::Extend,
+        BackupOp::Delete,
+    ];
+
+    fn next(self, op: BackupOp) -> Option<Self> {
+        match (self, op) {
+            (BackupState::Uploading, BackupOp::Finish) => Some(BackupState::Complete),
+            (BackupState::Uploading, BackupOp::Fail) => Some(BackupState::Failed),
+            (BackupState::Complete, BackupOp::Restore | BackupOp::Extend) => Some(self),
+            (_, BackupOp::Delete) => Some(self),
+            _ => None,
+        }
+    }
 }
 
 /// Represents a backup in the system
@@ -89,6 +211,14 @@ pub struct Backup {
     
     /// IDs of child backups (for incremental backups)
     pub child_ids: Vec<String>,
+    
+    /// Where the backup is in its life; metadata without it is complete
+    #[serde(default)]
+    pub state: BackupState,
+    
+    /// Whether the stream was encrypted with the configured key
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
 /// Manages backup operations
@@ -102,41 +232,87 @@ pub struct BackupManager {
     /// Snapshot manager for BTRFS snapshots
     snapshot_manager: snapshot::SnapshotManager,
     
-    /// Directory for temporary files
-    temp_dir: PathBuf,
+    /// Key backup streams are encrypted with, if encryption is enabled
+    encryption: Option<encryption::AesGcmEncryption>,
+    
+    /// Storage prefix of this machine's backups
+    prefix: String,
+    
+    /// Where backups and restores report their progress
+    progress: Arc<dyn Progress>,
+    
+    /// Records restores and deletions instead of performing them
+    dry_run: DryRun,
 }
 
 impl BackupManager {
     /// Create a new BackupManager with the given configuration
     pub async fn new(config: config::BackupConfig) -> Result<Self> {
         // Create storage backend
-        let storage = storage::StorageBackendFactory::create(&config.storage).await?;
+        let storage = storage::create_backend(&config).await?;
         
         // Create snapshot manager
-        let snapshot_dir = config
-            .storage
-            .local
-            .as_ref()
-            .map(|c| c.path.clone())
-            .unwrap_or_else(|| "/var/lib/rast/backups/snapshots".into());
+        let snapshot_dir = match &config.storage {
+            config::StorageConfig::Local { path } => path.clone(),
+            _ => DEFAULT_SNAPSHOT_DIR.into(),
+        };
             
         let snapshot_manager = snapshot::SnapshotManager::new(snapshot_dir);
         
-        // Create temp directory
-        let temp_dir = std::env::temp_dir()
-            .join("rast-backup")
-            .join(Uuid::new_v4().to_string());
-            
-        tokio::fs::create_dir_all(&temp_dir).await?;
+        let encryption = if config.encryption.enabled {
+            Some(encryption::AesGcmEncryption::from_config(&config.encryption).await?)
+        } else {
+            None
+        };
+        
+        // Separate the backups of machines sharing the storage
+        let prefix = if config.machine_namespace {
+            let identity = Identity::new()
+                .machine_identity()
+                .map_err(|e| BackupError::Config(e.to_string()))?;
+            format!("machines/{}/backups", identity.id)
+        } else {
+            "backups".to_string()
+        };
         
         Ok(Self {
             config,
             storage,
             snapshot_manager,
-            temp_dir,
+            encryption,
+            prefix,
+            progress: Arc::new(Silent),
+            dry_run: DryRun::off(),
         })
     }
     
+    /// Report the progress of backups and restores to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+    
+    /// Record restores and deletions in `dry_run` instead of performing them
+    pub fn with_dry_run(mut self, dry_run: DryRun) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+    
+    /// Storage path of a backup's stream
+    fn backup_path(&self, backup_id: &str) -> String {
+        format!("{}/{}/{}.btrfs", self.prefix, &backup_id[..2], backup_id)
+    }
+    
+    /// Storage path of a backup's metadata
+    fn metadata_path(&self, backup_id: &str) -> String {
+        format!("{}/{}/{}/metadata.json", self.prefix, &backup_id[..2], backup_id)
+    }
+    
+    /// Get the backup configuration
+    pub fn config(&self) -> &config::BackupConfig {
+        &self.config
+    }
+    
     /// Get the storage backend
     pub fn storage(&self) -> &dyn storage::StorageBackend {
         self.storage.as_ref()
@@ -157,15 +333,21 @@ impl BackupManager {
         parent_backup: Option<&Backup>,
     ) -> Result<Backup> {
         let subvolume = subvolume.as_ref();
+        let task = Task::start(&*self.progress, format!("Backing up {}", subvolume.display()), Some(3));
+        
+        if let Some(parent) = parent_backup {
+            parent.state.check(BackupOp::Extend)?;
+        }
         
         // Create a snapshot first
+        task.message("Snapshotting");
         let snapshot = if let Some(parent) = parent_backup {
             // For incremental backups, we need the parent snapshot
             let parent_snapshot = self
                 .snapshot_manager
                 .find_snapshot(&parent.id)
                 .await?
-                .ok_or_else(|| anyhow::anyhow!("Parent snapshot not found"))?;
+                .ok_or_else(|| BackupError::Snapshot("Parent snapshot not found".to_string()))?;
                 
             self.snapshot_manager
                 .create_incremental_snapshot(subvolume, &parent_snapshot, description)
@@ -177,46 +359,56 @@ impl BackupManager {
                 .await?
         };
         
-        // Create a temporary file for the backup
-        let backup_file = self.temp_dir.join(format!("{}.btrfs", Uuid::new_v4()));
-        
-        // Send the snapshot to a file
-        snapshot.send(&backup_file).await?;
-        
-        // Upload the backup file to storage
         let backup_id = Uuid::new_v4().to_string();
-        let backup_path = format!("backups/{}/{}.btrfs", &backup_id[..2], &backup_id);
-        
-        self.storage
-            .upload_file(&backup_file, &backup_path)
-            .await?;
-        
-        // Get file size
-        let size = tokio::fs::metadata(&backup_file).await?.len();
+        let backup_path = self.backup_path(&backup_id);
         
         // Create backup metadata
         let now = Utc::now();
-        let backup = Backup {
+        let mut backup = Backup {
             id: backup_id,
             name: name.unwrap_or_else(|| "Unnamed Backup").to_string(),
             description: description.map(|s| s.to_string()),
             subvolume_path: subvolume.to_path_buf(),
             snapshot_path: Some(snapshot.path.clone()),
-            size,
+            size: 0,
             created_at: now,
             updated_at: now,
-            metadata: snapshot.metadata,
+            metadata: snapshot.metadata.clone(),
             is_incremental: incremental,
             parent_id: parent_backup.map(|b| b.id.clone()),
             child_ids: Vec::new(),
+            state: BackupState::Uploading,
+            encrypted: self.encryption.is_some(),
         };
         
+        // Announce the backup first, so a partial upload is never taken for a complete one
+        self.save_backup_metadata(&backup).await?;
+        
+        // Stream the snapshot straight into storage
+        task.advance("Sending the snapshot");
+        match self.upload_snapshot(&snapshot, &backup_path).await {
+            Ok(size) => backup.size = size,
+            Err(e) => {
+                backup.state.apply(BackupOp::Fail)?;
+                if let Err(e) = self.save_backup_metadata(&backup).await {
+                    log::warn!("Failed to mark backup {} as failed: {}", backup.id, e);
+                }
+                return Err(e);
+            }
+        }
+        backup.state.apply(BackupOp::Finish)?;
+        backup.updated_at = Utc::now();
+        
         // Save backup metadata
+        task.advance("Saving metadata");
         self.save_backup_metadata(&backup).await?;
         
-        // Clean up temporary files
-        tokio::fs::remove_file(backup_file).await.ok();
+        // Record the success for health checks
+        if let Err(e) = record_last_backup(now).await {
+            log::warn!("Failed to record backup time in {}: {}", LAST_BACKUP_STAMP, e);
+        }
         
+        task.finish(format!("Backup {} created", backup.id));
         Ok(backup)
     }
     
@@ -228,39 +420,73 @@ impl BackupManager {
     ) -> Result<()> {
         // Get backup metadata
         let backup = self.get_backup(backup_id).await?;
+        backup.state.check(BackupOp::Restore)?;
         
         // Determine target path
         let target_path = match target {
             Some(path) => path.as_ref().to_path_buf(),
             None => backup.subvolume_path.clone(),
         };
+        let action = Action::write(format!("subvolume {}", target_path.display()))
+            .with_detail(format!("restored from backup {}", backup_id));
+        if self.dry_run.skip(action) {
+            return Ok(());
+        }
         
-        // Download the backup file
-        let backup_path = format!("backups/{}/{}.btrfs", &backup_id[..2], backup_id);
-        let temp_file = self.temp_dir.join(format!("restore-{}.btrfs", backup_id));
-        
-        self.storage
-            .download_file(&backup_path, &temp_file)
-            .await?;
+        let key = match (&self.encryption, backup.encrypted) {
+            (None, true) => {
+                return Err(BackupError::Encryption(format!(
+                    "Backup {} is encrypted, but no encryption key is configured",
+                    backup_id
+                )));
+            }
+            (key, encrypted) => key.as_ref().filter(|_| encrypted),
+        };
         
-        // Restore the snapshot
-        btrfs::Subvolume::receive(&temp_file, &target_path).await?;
+        // Stream the download straight into `btrfs receive`
+        let backup_path = self.backup_path(backup_id);
+        let task = Task::start(&*self.progress, format!("Restoring {}", backup_id), Some(1));
+        task.message("Receiving the snapshot");
+        let mut reader = self.storage.get_stream(Path::new(&backup_path)).await?;
+        let mut receive = tokio::process::Command::from(btrfs::Subvolume::receive_command(&target_path)?)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = receive
+            .stdin
+            .take()
+            .ok_or_else(|| BackupError::Snapshot("btrfs receive has no stdin".to_string()))?;
+        let streamed = match key {
+            Some(key) => key.decrypt_stream(&mut reader, &mut stdin).await,
+            None => stream::copy(&mut reader, &mut stdin).await.map_err(Into::into),
+        };
+        drop(stdin);
         
-        // Clean up
-        tokio::fs::remove_file(temp_file).await.ok();
+        // A failing receive closes its stdin; its own error tells more
+        let output = receive.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(btrfs::BtrfsError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ).into());
+        }
+        streamed?;
         
+        task.finish(format!("Restored to {}", target_path.display()));
         Ok(())
     }
     
     /// List all backups
     pub async fn list_backups(&self) -> Result<Vec<Backup>> {
-        // List all metadata files in the backup storage
+        // List all metadata files in the backup storage; both are cached
         let mut backups = Vec::new();
         
-        for entry in self.storage.list("backups/").await? {
-            if entry.ends_with("/metadata.json") {
-                if let Ok(metadata) = self.storage.read_to_string(&entry).await {
-                    if let Ok(backup) = serde_json::from_str::<Backup>(&metadata) {
+        let prefix = format!("{}/", self.prefix);
+        for entry in self.storage.list(Some(Path::new(&prefix))).await? {
+            if entry.filename() == Some("metadata.json") {
+                if let Ok(metadata) = self.storage.get(Path::new(entry.as_ref())).await {
+                    if let Ok(backup) = serde_json::from_slice::<Backup>(&metadata) {
                         backups.push(backup);
                     }
                 }
@@ -275,16 +501,16 @@ impl BackupManager {
     
     /// Get a specific backup by ID
     pub async fn get_backup(&self, backup_id: &str) -> Result<Backup> {
-        let metadata_path = format!("backups/{}/{}/metadata.json", &backup_id[..2], backup_id);
-        let metadata = self.storage.read_to_string(&metadata_path).await?;
-        serde_json::from_str(&metadata).map_err(Into::into)
+        let metadata_path = self.metadata_path(backup_id);
+        let metadata = self.storage.get(Path::new(&metadata_path)).await?;
+        serde_json::from_slice(&metadata).map_err(Into::into)
     }
     
     /// Verify a backup's integrity
     pub async fn verify_backup(&self, backup_id: &str) -> Result<bool> {
-        // For now, just check if the backup exists and has valid metadata
+        // For now, just check if the backup exists, has valid metadata and was uploaded completely
         match self.get_backup(backup_id).await {
-            Ok(_) => Ok(true),
+            Ok(backup) => Ok(backup.state == BackupState::Complete),
             Err(_) => Ok(false),
         }
     }
@@ -293,54 +519,94 @@ impl BackupManager {
     pub async fn delete_backup(&self, backup_id: &str) -> Result<()> {
         // Get backup metadata first
         let backup = self.get_backup(backup_id).await?;
+        backup.state.check(BackupOp::Delete)?;
+        if self.dry_run.skip(Action::delete(format!("backup {}", backup_id))) {
+            return Ok(());
+        }
         
         // Delete the backup file
-        let backup_path = format!("backups/{}/{}.btrfs", &backup_id[..2], backup_id);
-        self.storage.delete(&backup_path).await?;
+        let backup_path = self.backup_path(backup_id);
+        self.storage.delete(Path::new(&backup_path)).await?;
         
         // Delete the metadata
-        let metadata_path = format!("backups/{}/{}/metadata.json", &backup_id[..2], backup_id);
-        self.storage.delete(&metadata_path).await?;
+        let metadata_path = self.metadata_path(backup_id);
+        self.storage.delete(Path::new(&metadata_path)).await?;
         
         // Delete the snapshot if it exists
         if let Some(snapshot_path) = backup.snapshot_path {
-            if let Ok(snapshot) = btrfs::Subvolume::from_path(&snapshot_path).await {
-                snapshot.delete().await.ok();
+            if btrfs::Subvolume::from_path(&snapshot_path).is_ok() {
+                btrfs::Subvolume::delete(&snapshot_path).ok();
             }
         }
         
         Ok(())
     }
     
+    /// Stream `btrfs send` of `snapshot` to `path`, returning the stream's size
+    ///
+    /// The stream is encrypted on the way if encryption is enabled. Neither
+    /// the stream nor its encrypted form is held in memory or on disk.
+    async fn upload_snapshot(&self, snapshot: &snapshot::Snapshot, path: &str) -> Result<u64> {
+        let mut send = tokio::process::Command::from(snapshot.send_command()?)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdout = send
+            .stdout
+            .take()
+            .ok_or_else(|| BackupError::Snapshot("btrfs send has no stdout".to_string()))?;
+        
+        let uploaded = match &self.encryption {
+            Some(key) => {
+                let (mut writer, reader) = tokio::io::duplex(stream::CHUNK_SIZE);
+                let encrypt = async {
+                    let size = key.encrypt_stream(&mut stdout, &mut writer).await?;
+                    writer.shutdown().await?;
+                    Ok::<_, BackupError>(size)
+                };
+                tokio::try_join!(encrypt, self.storage.put_stream(Path::new(path), Box::new(reader)))
+                    .map(|(size, _)| size)
+            }
+            None => self.storage.put_stream(Path::new(path), Box::new(stdout)).await,
+        };
+        
+        // A stream cut short by a failing send must not count as a backup
+        let output = send.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(btrfs::BtrfsError::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ).into());
+        }
+        uploaded
+    }
+    
     /// Save backup metadata to storage
     async fn save_backup_metadata(&self, backup: &Backup) -> Result<()> {
         let metadata = serde_json::to_string_pretty(backup)?;
-        let metadata_path = format!(
-            "backups/{}/{}/metadata.json",
-            &backup.id[..2],
-            backup.id
-        );
+        let metadata_path = self.metadata_path(&backup.id);
         
         self.storage
-            .write(&metadata_path, metadata.into_bytes())
+            .put(Path::new(&metadata_path), metadata.into_bytes().into())
             .await?;
             
         Ok(())
     }
 }
 
+/// Write the time of a successful backup to [`LAST_BACKUP_STAMP`]
+async fn record_last_backup(at: chrono::DateTime<Utc>) -> std::io::Result<()> {
+    let stamp = std::path::Path::new(LAST_BACKUP_STAMP);
+    if let Some(parent) = stamp.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(stamp, format!("{}\n", at.to_rfc3339())).await
+}
+
+/// A backup step that can be run on its own
 #[async_trait]
 pub trait BackupOperation {
+    /// Run the step
     async fn execute(&self) -> Result<()>;
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
-
-    #[tokio::test]
-    async fn test_backup_manager() {
-        // TODO: Add comprehensive tests
-    }
-}
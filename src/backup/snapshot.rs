@@ -3,15 +3,14 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    time::SystemTime,
 };
 
-use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
-use crate::backup::btrfs::{self, BtrfsError, Subvolume};
+use crate::backup::btrfs;
+use crate::backup::{BackupError, Result};
 
 /// Represents a snapshot in the backup system
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,9 +42,9 @@ pub struct Snapshot {
 
 impl Snapshot {
     /// Create a new snapshot of a subvolume
-    pub async fn create<P: AsRef<Path>>(
-        subvolume: P,
-        snapshot_dir: P,
+    pub async fn create<S: AsRef<Path>, D: AsRef<Path>>(
+        subvolume: S,
+        snapshot_dir: D,
         read_only: bool,
         parent: Option<&Snapshot>,
         metadata: Option<HashMap<String, String>>,
@@ -78,12 +77,10 @@ impl Snapshot {
                 &snapshot_path,
                 read_only,
             )
-            .await
-            .context("Failed to create incremental snapshot")?
+            .map_err(|e| BackupError::Snapshot(format!("Failed to create incremental snapshot: {}", e)))?
         } else {
-            btrfs::Subvolume::create_snapshot(subvolume, &snapshot_path, read_only)
-                .await
-                .context("Failed to create full snapshot")?
+            btrfs::Subvolume::create_snapshot(subvolume, snapshot_path.as_path(), read_only)
+                .map_err(|e| BackupError::Snapshot(format!("Failed to create full snapshot: {}", e)))?
         };
         
         // Create the snapshot metadata
@@ -104,32 +101,38 @@ impl Snapshot {
     /// Delete this snapshot
     pub async fn delete(&self) -> Result<()> {
         if self.path.exists() {
-            btrfs::Subvolume::delete(&self.path).await?;
+            btrfs::Subvolume::delete(&self.path)?;
         }
         Ok(())
     }
     
     /// Send this snapshot to a file or stream
     pub async fn send<P: AsRef<Path>>(&self, output: P) -> Result<()> {
-        let subvol = btrfs::Subvolume::from_path(&self.path).await?;
-        subvol.send(Some(output)).await?;
+        let subvol = btrfs::Subvolume::from_path(&self.path)?;
+        subvol.send(Some(output))?;
         Ok(())
     }
     
+    /// The `btrfs send` command streaming this snapshot to its stdout
+    pub fn send_command(&self) -> Result<std::process::Command> {
+        let subvol = btrfs::Subvolume::from_path(&self.path)?;
+        Ok(subvol.send_command())
+    }
+    
     /// Restore this snapshot to a target path
     pub async fn restore<P: AsRef<Path>>(&self, target: P) -> Result<()> {
         // If target exists, it must be a subvolume
         if target.as_ref().exists() {
-            if !btrfs::Subvolume::is_subvolume(&target).await? {
-                return Err(anyhow::anyhow!("Target exists and is not a subvolume"));
+            if !btrfs::Subvolume::is_subvolume(&target)? {
+                return Err(BackupError::Snapshot("Target exists and is not a subvolume".to_string()));
             }
             
             // Delete the target subvolume
-            btrfs::Subvolume::delete(&target).await?;
+            btrfs::Subvolume::delete(&target)?;
         }
         
         // Create a new snapshot from our snapshot
-        let restored = btrfs::Subvolume::create_snapshot(&self.path, &target, false).await?;
+        let restored = btrfs::Subvolume::create_snapshot(self.path.as_path(), target.as_ref(), false)?;
         
         // If the original was read-only, make the restored one read-write
         if self.read_only && restored.read_only {
@@ -226,7 +229,7 @@ impl SnapshotManager {
         let mut snapshots = Vec::new();
         
         // List all subvolumes in the snapshot directory
-        let subvols = btrfs::Subvolume::list_subvolumes(&self.snapshot_dir).await?;
+        let subvols = btrfs::Subvolume::list_subvolumes(&self.snapshot_dir)?;
         
         for subvol in subvols {
             // Try to load metadata from .snapinfo file if it exists
@@ -275,7 +278,7 @@ impl SnapshotManager {
             snapshot.delete().await?;
             Ok(())
         } else {
-            Err(anyhow::anyhow!("Snapshot not found: {}", id))
+            Err(BackupError::Snapshot(format!("Snapshot not found: {}", id)))
         }
     }
 }
@@ -329,7 +332,7 @@ mod tests {
             .unwrap();
             
         assert!(incremental.path.exists());
-        assert_eq!(incremental.parent_id, Some(snapshot.id));
+        assert_eq!(incremental.parent_id.as_ref(), Some(&snapshot.id));
         
         // Cleanup
         manager.delete_snapshot(&snapshot.id).await.unwrap();
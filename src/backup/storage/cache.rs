@@ -0,0 +1,177 @@
+//! Caching of listings and metadata in front of a storage backend
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::*;
+use crate::backup::config::CacheConfig;
+
+/// A cached value and when it was read
+#[derive(Debug)]
+struct Entry<T> {
+    value: T,
+    read_at: Instant,
+}
+
+/// Entries of one kind, dropped after the TTL
+#[derive(Debug)]
+struct Entries<K, T> {
+    ttl: Duration,
+    max: usize,
+    map: Mutex<HashMap<K, Entry<T>>>,
+}
+
+impl<K: Clone + Eq + Hash, T: Clone> Entries<K, T> {
+    fn new(ttl: Duration, max: usize) -> Self {
+        Self {
+            ttl,
+            max,
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<T> {
+        let map = self.map.lock().unwrap_or_else(|e| e.into_inner());
+        map.get(key)
+            .filter(|entry| entry.read_at.elapsed() < self.ttl)
+            .map(|entry| entry.value.clone())
+    }
+
+    fn insert(&self, key: K, value: T) {
+        let mut map = self.map.lock().unwrap_or_else(|e| e.into_inner());
+        if map.len() >= self.max {
+            map.retain(|_, entry| entry.read_at.elapsed() < self.ttl);
+        }
+        if map.len() >= self.max {
+            let oldest = map.iter().min_by_key(|(_, entry)| entry.read_at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                map.remove(&oldest);
+            }
+        }
+        map.insert(key, Entry { value, read_at: Instant::now() });
+    }
+
+    fn remove_where(&self, stale: impl Fn(&K) -> bool) {
+        self.map.lock().unwrap_or_else(|e| e.into_inner()).retain(|key, _| !stale(key));
+    }
+}
+
+/// A storage backend reusing listings and metadata objects for a while
+///
+/// Only `.json` objects are cached — backup metadata and manifests — never
+/// backup streams. Writes and deletions through the cache drop what they
+/// change, including the listings of every prefix the object is under.
+#[derive(Debug)]
+pub struct CachedStorage {
+    inner: Box<dyn StorageBackend>,
+    lists: Entries<Option<String>, Vec<ObjectPath>>,
+    objects: Entries<String, Bytes>,
+}
+
+impl CachedStorage {
+    /// Cache what `inner` lists and reads as `config` says
+    pub fn new(inner: Box<dyn StorageBackend>, config: &CacheConfig) -> Self {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        Self {
+            inner,
+            lists: Entries::new(ttl, config.max_entries),
+            objects: Entries::new(ttl, config.max_entries),
+        }
+    }
+
+    /// Forget `path` and every listing that may contain it
+    fn invalidate(&self, path: &Path) {
+        let key = key(path);
+        self.objects.remove_where(|cached| *cached == key);
+        self.lists.remove_where(|prefix| prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix.as_str())));
+    }
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn cacheable(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+#[async_trait]
+impl StorageBackend for CachedStorage {
+    async fn put(&self, path: &Path, data: Bytes) -> Result<()> {
+        let result = self.inner.put(path, data).await;
+        self.invalidate(path);
+        result
+    }
+
+    async fn get(&self, path: &Path) -> Result<Bytes> {
+        if !cacheable(path) {
+            return self.inner.get(path).await;
+        }
+        if let Some(data) = self.objects.get(&key(path)) {
+            return Ok(data);
+        }
+        let data = self.inner.get(path).await?;
+        self.objects.insert(key(path), data.clone());
+        Ok(data)
+    }
+
+    async fn put_stream(&self, path: &Path, reader: Reader) -> Result<u64> {
+        let result = self.inner.put_stream(path, reader).await;
+        self.invalidate(path);
+        result
+    }
+
+    async fn get_stream(&self, path: &Path) -> Result<Reader> {
+        self.inner.get_stream(path).await
+    }
+
+    async fn list(&self, prefix: Option<&Path>) -> Result<Vec<ObjectPath>> {
+        let prefix_key = prefix.map(key);
+        if let Some(paths) = self.lists.get(&prefix_key) {
+            return Ok(paths);
+        }
+        let paths = self.inner.list(prefix).await?;
+        self.lists.insert(prefix_key, paths.clone());
+        Ok(paths)
+    }
+
+    async fn delete(&self, path: &Path) -> Result<()> {
+        let result = self.inner.delete(path).await;
+        self.invalidate(path);
+        result
+    }
+
+    async fn exists(&self, path: &Path) -> bool {
+        (cacheable(path) && self.objects.get(&key(path)).is_some()) || self.inner.exists(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_until_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = LocalStorage::new(dir.path()).await.unwrap();
+        let cache = CachedStorage::new(Box::new(local), &CacheConfig::default());
+        let metadata = Path::new("backups/ab/abcd/metadata.json");
+        cache.put(metadata, Bytes::from_static(b"{}")).await.unwrap();
+        assert_eq!(cache.list(Some(Path::new("backups"))).await.unwrap().len(), 1);
+        assert_eq!(cache.get(metadata).await.unwrap(), "{}");
+
+        // Changes behind the cache's back only show after the TTL
+        std::fs::write(dir.path().join(metadata), "{\"changed\":1}").unwrap();
+        std::fs::write(dir.path().join("backups/ab/abcd/other.json"), "{}").unwrap();
+        assert_eq!(cache.get(metadata).await.unwrap(), "{}");
+        assert_eq!(cache.list(Some(Path::new("backups"))).await.unwrap().len(), 1);
+
+        // Writes through the cache show at once
+        cache.put(Path::new("backups/ef/efgh/metadata.json"), Bytes::from_static(b"{}")).await.unwrap();
+        assert_eq!(cache.list(Some(Path::new("backups"))).await.unwrap().len(), 3);
+        cache.delete(metadata).await.unwrap();
+        assert!(cache.get(metadata).await.is_err());
+    }
+}
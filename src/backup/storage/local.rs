@@ -1,9 +1,12 @@
 //! Local filesystem storage backend
 
 use super::*;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::backup::stream::BufferPool;
 
 /// Local filesystem storage backend
 #[derive(Debug)]
@@ -46,8 +49,11 @@ impl StorageBackend for LocalStorage {
             }
         }
         
-        let mut file = fs::File::create(&full_path).await?;
-        file.write_all(&data).await?;
+        // Images and databases are mostly zeros; keep them sparse on disk
+        tokio::task::spawn_blocking(move || crate::fs::write_sparse(full_path, &data))
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(std::io::Error::from)?;
         
         Ok(())
     }
@@ -58,6 +64,39 @@ impl StorageBackend for LocalStorage {
         Ok(bytes::Bytes::from(data))
     }
     
+    async fn put_stream(&self, path: &Path, mut reader: Reader) -> Result<u64> {
+        let full_path = self.resolve_path(path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        
+        // Chunks of zeros are skipped, leaving holes like `put` does
+        let mut file = fs::File::create(&full_path).await?;
+        let mut buf = BufferPool::shared().get();
+        let mut size = 0u64;
+        loop {
+            buf.clear();
+            let n = buf.fill(&mut reader).await?;
+            if n == 0 {
+                break;
+            }
+            if buf.iter().all(|b| *b == 0) {
+                file.seek(SeekFrom::Current(n as i64)).await?;
+            } else {
+                file.write_all(&buf).await?;
+            }
+            size += n as u64;
+        }
+        file.set_len(size).await?;
+        file.flush().await?;
+        Ok(size)
+    }
+    
+    async fn get_stream(&self, path: &Path) -> Result<Reader> {
+        let file = fs::File::open(self.resolve_path(path)).await?;
+        Ok(Box::new(file))
+    }
+    
     async fn list(&self, prefix: Option<&Path>) -> Result<Vec<object_store::path::Path>> {
         let base = if let Some(prefix) = prefix {
             self.resolve_path(prefix)
@@ -67,12 +106,23 @@ impl StorageBackend for LocalStorage {
         
         let mut paths = Vec::new();
         
-        let mut read_dir = fs::read_dir(base).await?;
-        while let Some(entry) = read_dir.next_entry().await? {
-            if entry.file_type().await?.is_file() {
-                if let Some(rel_path) = entry.path().strip_prefix(&self.base_path).ok() {
-                    if let Some(path_str) = rel_path.to_str() {
-                        paths.push(object_store::path::Path::from(path_str));
+        // Everything below the prefix, like object stores list keys
+        let mut dirs = vec![base];
+        while let Some(dir) = dirs.pop() {
+            let mut read_dir = match fs::read_dir(&dir).await {
+                Ok(read_dir) => read_dir,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = read_dir.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() {
+                    if let Ok(rel_path) = entry.path().strip_prefix(&self.base_path) {
+                        if let Some(path_str) = rel_path.to_str() {
+                            paths.push(object_store::path::Path::from(path_str));
+                        }
                     }
                 }
             }
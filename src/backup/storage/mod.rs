@@ -4,10 +4,17 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use std::path::Path;
 use object_store::path::Path as ObjectPath;
+use tokio::io::AsyncReadExt;
 
+#[cfg(feature = "backup-s3")]
+use crate::auth::providers::{resolve_aws, AwsOptions};
+use crate::backup::stream::Reader;
 use crate::backup::{BackupError, Result};
 
+mod cache;
 mod local;
+pub mod retry;
+#[cfg(feature = "backup-s3")]
 mod s3;
 
 /// Trait for storage backends
@@ -19,6 +26,27 @@ pub trait StorageBackend: Send + Sync + std::fmt::Debug {
     /// Download data from the storage backend
     async fn get(&self, path: &Path) -> Result<Bytes>;
     
+    /// Upload everything `reader` yields, returning the bytes uploaded
+    ///
+    /// Backends should override this to upload in parts; by default the
+    /// whole stream is collected and [`StorageBackend::put`] is called.
+    async fn put_stream(&self, path: &Path, mut reader: Reader) -> Result<u64> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        let size = data.len() as u64;
+        self.put(path, Bytes::from(data)).await?;
+        Ok(size)
+    }
+    
+    /// Download an object as a stream
+    ///
+    /// Backends should override this to download incrementally; by default
+    /// the whole object is fetched with [`StorageBackend::get`].
+    async fn get_stream(&self, path: &Path) -> Result<Reader> {
+        let data = self.get(path).await?;
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+    
     /// List objects with the given prefix
     async fn list(&self, prefix: Option<&Path>) -> Result<Vec<ObjectPath>>;
     
@@ -30,29 +58,63 @@ pub trait StorageBackend: Send + Sync + std::fmt::Debug {
 }
 
 /// Create a storage backend from the given configuration
+///
+/// Listings and metadata are cached as [`super::config::CacheConfig`] says.
 pub async fn create_backend(config: &super::config::BackupConfig) -> Result<Box<dyn StorageBackend>> {
+    let backend = open_backend(config).await?;
+    if config.cache.ttl_secs == 0 {
+        return Ok(backend);
+    }
+    Ok(Box::new(CachedStorage::new(backend, &config.cache)))
+}
+
+async fn open_backend(config: &super::config::BackupConfig) -> Result<Box<dyn StorageBackend>> {
     match &config.storage {
         super::config::StorageConfig::Local { path } => {
             Ok(Box::new(local::LocalStorage::new(path).await?))
         }
+        #[cfg(feature = "backup-s3")]
         super::config::StorageConfig::S3 { 
             bucket, 
             region, 
             endpoint, 
             access_key_id, 
-            secret_access_key 
+            secret_access_key,
+            profile,
         } => {
-            Ok(Box::new(s3::S3Storage::new(
+            let mut options = AwsOptions::default();
+            if let (Some(id), Some(secret)) = (access_key_id, secret_access_key) {
+                options = options.with_static_keys(id.clone(), secret.clone());
+            }
+            if let Some(profile) = profile {
+                options = options.with_profile(profile.clone());
+            }
+            // Custom endpoints are not on EC2, so the metadata service would only time out
+            if endpoint.is_some() {
+                options = options.without_instance_metadata();
+            }
+            let credentials = tokio::task::spawn_blocking(move || resolve_aws(&options))
+                .await
+                .map_err(|e| BackupError::Config(e.to_string()))??;
+            
+            Ok(Box::new(s3::S3Storage::connect(
                 bucket,
                 region,
                 endpoint.as_deref(),
-                access_key_id,
-                secret_access_key,
-            ).await?))
+                &credentials,
+            ).await?
+                .with_part_size(config.performance.chunk_size)
+                .with_retry(config.retry.clone())))
         }
+        #[cfg(not(feature = "backup-s3"))]
+        super::config::StorageConfig::S3 { .. } => Err(BackupError::Config(
+            "S3 storage needs the backup-s3 feature".to_string(),
+        )),
     }
 }
 
 // Re-export implementations
+pub use cache::CachedStorage;
 pub use local::LocalStorage;
+#[cfg(feature = "backup-s3")]
 pub use s3::S3Storage;
@@ -0,0 +1,110 @@
+//! Retries of storage requests with jittered exponential backoff
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use rand_core::{OsRng, RngCore};
+
+use crate::backup::config::RetryPolicy;
+use crate::backup::{BackupError, Result};
+
+impl RetryPolicy {
+    /// Delay before retry number `retry`, counting from 0
+    ///
+    /// A random duration up to the exponential bound ("full jitter").
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let bound = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << retry.min(32))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(OsRng.next_u64() % bound.saturating_add(1))
+    }
+}
+
+/// Whether a failed request may succeed when sent again
+///
+/// Missing objects stay missing; other storage errors are mostly
+/// throttling, server errors and dropped connections.
+pub fn is_transient(error: &BackupError) -> bool {
+    match error {
+        BackupError::Storage(object_store::Error::NotFound { .. }) => false,
+        BackupError::Storage(_) => true,
+        BackupError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Run `request` until it succeeds, fails for good or runs out of attempts
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, what: &str, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(e) if attempt < policy.attempts && is_transient(&e) => {
+                let delay = policy.backoff(attempt - 1);
+                log::debug!("{} failed, attempt {} of {}, retrying in {:?}: {}", what, attempt, policy.attempts, delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_backoff_bounds() {
+        let policy = RetryPolicy {
+            attempts: 8,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+        };
+        for retry in 0..40 {
+            let bound = (100u64 << retry.min(4)).min(1_000);
+            assert!(policy.backoff(retry) <= Duration::from_millis(bound));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_only() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+        };
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = retry(&policy, "request", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(std::io::Error::from(ErrorKind::ConnectionReset).into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        calls.store(0, Ordering::SeqCst);
+        let result: Result<()> = retry(&policy, "request", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(BackupError::InvalidArgument("bad".to_string()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
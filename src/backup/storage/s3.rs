@@ -1,49 +1,105 @@
 //! S3-compatible storage backend
 
 use super::*;
-use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::{
-    config::{Credentials, SharedAsyncRead, SharedAsyncSeek, SharedAsyncWrite},
-    operation::{
-        create_bucket::CreateBucketOutput, delete_object::DeleteObjectOutput,
-        get_object::GetObjectOutput, list_objects_v2::ListObjectsV2Output,
-        put_object::PutObjectOutput,
-    },
-    primitives::{ByteStream, SdkBody},
-    types::{BucketLocationConstraint, CreateBucketConfiguration},
+    config::{retry::RetryConfig, Credentials, Region},
+    error::SdkError,
+    operation::{get_object::GetObjectError, head_bucket::HeadBucketError},
+    primitives::ByteStream,
+    types::{BucketLocationConstraint, CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration},
     Client,
 };
-use aws_smithy_http::byte_stream::ByteStream as SmithyByteStream;
+use super::retry::retry;
+use crate::auth::providers::AwsCredentials;
+use crate::backup::config::RetryPolicy;
+use crate::backup::stream::{BufferPool, PooledBuffer};
+use std::collections::HashMap;
 use std::path::Path;
-use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Smallest part S3 accepts in a multipart upload, except for the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Part size unless configured otherwise
+const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Backends created so far, so creating one again reuses its client
+static CLIENTS: LazyLock<Mutex<HashMap<ClientKey, S3Storage>>> = LazyLock::new(Default::default);
+
+/// What backends sharing a client have in common
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    bucket: String,
+    region: String,
+    endpoint: Option<String>,
+    access_key_id: String,
+    session_token: Option<String>,
+}
 
 /// S3 storage backend
 #[derive(Debug, Clone)]
 pub struct S3Storage {
     client: Client,
     bucket: String,
+    /// Buffers of one part each, so streams are uploaded with flat memory
+    parts: Arc<BufferPool>,
+    /// Retries of failed requests
+    retry: RetryPolicy,
 }
 
 impl S3Storage {
+    /// An S3 storage backend sharing the client of earlier ones
+    ///
+    /// Backends of the same bucket with the same credentials share a
+    /// client, which keeps its connections open between requests, and the
+    /// bucket is only checked for the first one. New credentials get a new
+    /// client.
+    pub async fn connect(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        credentials: &AwsCredentials,
+    ) -> Result<Self> {
+        let key = ClientKey {
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            endpoint: endpoint.map(str::to_string),
+            access_key_id: credentials.access_key_id.clone(),
+            session_token: credentials.session_token.clone(),
+        };
+        if let Some(storage) = CLIENTS.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            return Ok(storage.clone());
+        }
+        
+        let storage = Self::new(bucket, region, endpoint, credentials).await?;
+        let mut clients = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+        // Clients of replaced credentials are not used again
+        clients.retain(|cached, _| (&cached.bucket, &cached.region, &cached.endpoint) != (&key.bucket, &key.region, &key.endpoint));
+        clients.insert(key, storage.clone());
+        Ok(storage)
+    }
+    
     /// Create a new S3 storage backend
+    ///
+    /// Temporary credentials are not refreshed; create the backend again
+    /// once they expire.
     pub async fn new(
         bucket: &str,
         region: &str,
         endpoint: Option<&str>,
-        access_key_id: &str,
-        secret_access_key: &str,
+        credentials: &AwsCredentials,
     ) -> Result<Self> {
-        let region_provider = RegionProviderChain::first_try(region.parse().map(Some)?);
-        
         let mut s3_config = aws_sdk_s3::config::Builder::new()
-            .region(region_provider)
+            .region(Region::new(region.to_string()))
             .credentials_provider(Credentials::new(
-                access_key_id,
-                secret_access_key,
-                None,
-                None,
+                &credentials.access_key_id,
+                &credentials.secret_access_key,
+                credentials.session_token.clone(),
+                credentials.expires_at.map(std::time::SystemTime::from),
                 "rastos-backup",
-            ));
+            ))
+            // Requests are retried with the configured policy instead
+            .retry_config(RetryConfig::disabled());
 
         // Use custom endpoint if provided (for MinIO, etc.)
         if let Some(endpoint) = endpoint {
@@ -54,18 +110,32 @@ impl S3Storage {
 
         // Ensure the bucket exists
         if let Err(e) = client.head_bucket().bucket(bucket).send().await {
-            if e.is_no_such_bucket() {
+            if e.as_service_error().is_some_and(HeadBucketError::is_not_found) {
                 Self::create_bucket(&client, bucket, region).await?;
             } else {
-                return Err(BackupError::Storage(e.into()));
+                return Err(storage_error(e));
             }
         }
 
         Ok(Self {
             client,
             bucket: bucket.to_string(),
+            parts: Arc::new(BufferPool::new(DEFAULT_PART_SIZE, 1)),
+            retry: RetryPolicy::default(),
         })
     }
+    
+    /// Retry failed requests as `policy` says
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+    
+    /// Upload streams in parts of `size` bytes, at least the 5 MiB S3 requires
+    pub fn with_part_size(mut self, size: usize) -> Self {
+        self.parts = Arc::new(BufferPool::new(size.max(MIN_PART_SIZE), 1));
+        self
+    }
 
     async fn create_bucket(client: &Client, bucket: &str, region: &str) -> Result<()> {
         let constraint = BucketLocationConstraint::from(region);
@@ -79,7 +149,7 @@ impl S3Storage {
             .create_bucket_configuration(cfg)
             .send()
             .await
-            .map_err(|e| BackupError::Storage(e.into()))?;
+            .map_err(storage_error)?;
 
         Ok(())
     }
@@ -88,43 +158,166 @@ impl S3Storage {
         // Convert path to forward slashes for S3
         path.to_string_lossy().replace('\\', "/")
     }
+    
+    /// Upload the parts of a multipart upload, starting with the one in `buf`
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        buf: &mut PooledBuffer<'_>,
+        reader: &mut Reader,
+    ) -> Result<(Vec<CompletedPart>, u64)> {
+        let mut parts = Vec::new();
+        let mut size = 0u64;
+        while !buf.is_empty() {
+            let part_number = parts.len() as i32 + 1;
+            // The part is handed to the SDK without copying and dropped once sent
+            let part = buf.split().freeze();
+            size += part.len() as u64;
+            let output = retry(&self.retry, "Uploading a part", || async {
+                self.client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(part.clone()))
+                    .send()
+                    .await
+                    .map_err(storage_error)
+            })
+            .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(output.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+            buf.reserve(self.parts.buffer_size());
+            buf.fill(reader).await?;
+        }
+        Ok((parts, size))
+    }
 }
 
 #[async_trait]
 impl StorageBackend for S3Storage {
     async fn put(&self, path: &Path, data: bytes::Bytes) -> Result<()> {
         let key = self.normalize_path(path);
-        let body = ByteStream::from(data);
 
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .body(body)
-            .send()
-            .await
-            .map_err(|e| BackupError::Storage(e.into()))?;
+        retry(&self.retry, "Uploading", || async {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .body(ByteStream::from(data.clone()))
+                .send()
+                .await
+                .map_err(storage_error)
+        })
+        .await?;
 
         Ok(())
     }
 
+    async fn put_stream(&self, path: &Path, mut reader: Reader) -> Result<u64> {
+        let mut buf = self.parts.get();
+        let first = buf.fill(&mut reader).await?;
+        if first < self.parts.buffer_size() {
+            // Fits in a single request
+            self.put(path, buf.split().freeze()).await?;
+            return Ok(first as u64);
+        }
+        
+        let key = self.normalize_path(path);
+        let upload = retry(&self.retry, "Starting an upload", || async {
+            self.client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(storage_error)
+        })
+        .await?;
+        let upload_id = upload.upload_id().ok_or_else(|| {
+            BackupError::Storage(object_store::Error::Generic {
+                store: "S3",
+                source: "multipart upload without an ID".into(),
+            })
+        })?;
+        
+        let result = match self.upload_parts(&key, upload_id, &mut buf, &mut reader).await {
+            Ok((parts, size)) => {
+                let upload = CompletedMultipartUpload::builder().set_parts(Some(parts)).build();
+                retry(&self.retry, "Completing an upload", || async {
+                    self.client
+                        .complete_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(&key)
+                        .upload_id(upload_id)
+                        .multipart_upload(upload.clone())
+                        .send()
+                        .await
+                        .map_err(storage_error)
+                })
+                .await
+                .map(|_| size)
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            // Parts of abandoned uploads are billed until aborted
+            if let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                log::warn!("Failed to abort the upload of {}: {}", key, e);
+            }
+        }
+        result
+    }
+    
+    async fn get_stream(&self, path: &Path) -> Result<Reader> {
+        let key = self.normalize_path(path);
+        let response = retry(&self.retry, "Downloading", || async {
+            self.client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(|e| get_error(&key, e))
+        })
+        .await?;
+        Ok(Box::new(Box::pin(response.body.into_async_read())))
+    }
+    
     async fn get(&self, path: &Path) -> Result<bytes::Bytes> {
         let key = self.normalize_path(path);
 
-        let response = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(&key)
-            .send()
-            .await
-            .map_err(|e| BackupError::Storage(e.into()))?;
-
-        let data = response
-            .body
-            .collect()
-            .await
-            .map_err(|e| BackupError::Storage(e.into()))?;
+        // A body cut short is fetched again like a failed request
+        let data = retry(&self.retry, "Downloading", || async {
+            let response = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(|e| get_error(&key, e))?;
+            response
+                .body
+                .collect()
+                .await
+                .map_err(storage_error)
+        })
+        .await?;
 
         Ok(data.into_bytes())
     }
@@ -135,41 +328,47 @@ impl StorageBackend for S3Storage {
     ) -> Result<Vec<object_store::path::Path>> {
         let prefix = prefix.map(|p| self.normalize_path(p));
 
-        let mut response = self
-            .client
-            .list_objects_v2()
-            .bucket(&self.bucket)
-            .set_prefix(prefix.clone())
-            .into_paginator()
-            .send();
+        retry(&self.retry, "Listing", || async {
+            let mut response = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .set_prefix(prefix.clone())
+                .into_paginator()
+                .send();
 
-        let mut paths = Vec::new();
+            let mut paths = Vec::new();
 
-        while let Some(result) = response.next().await {
-            let output = result.map_err(|e| BackupError::Storage(e.into()))?;
+            while let Some(result) = response.next().await {
+                let output = result.map_err(storage_error)?;
 
-            for object in output.contents() {
-                if let Some(key) = object.key() {
-                    if let Ok(path) = object_store::path::Path::parse(key) {
-                        paths.push(path);
+                for object in output.contents() {
+                    if let Some(key) = object.key() {
+                        if let Ok(path) = object_store::path::Path::parse(key) {
+                            paths.push(path);
+                        }
                     }
                 }
             }
-        }
 
-        Ok(paths)
+            Ok::<_, BackupError>(paths)
+        })
+        .await
     }
 
     async fn delete(&self, path: &Path) -> Result<()> {
         let key = self.normalize_path(path);
 
-        self.client
-            .delete_object()
-            .bucket(&self.bucket)
-            .key(key)
-            .send()
-            .await
-            .map_err(|e| BackupError::Storage(e.into()))?;
+        retry(&self.retry, "Deleting", || async {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(&key)
+                .send()
+                .await
+                .map_err(storage_error)
+        })
+        .await?;
 
         Ok(())
     }
@@ -186,3 +385,30 @@ impl StorageBackend for S3Storage {
             .is_ok()
     }
 }
+
+/// Missing objects are not found rather than failed, so they are not retried
+fn get_error<R>(key: &str, e: SdkError<GetObjectError, R>) -> BackupError
+where
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    let missing = e.as_service_error().is_some_and(GetObjectError::is_no_such_key);
+    if missing {
+        BackupError::Storage(object_store::Error::NotFound {
+            path: key.to_string(),
+            source: Box::new(e),
+        })
+    } else {
+        storage_error(e)
+    }
+}
+
+/// A failed S3 request as a storage error
+fn storage_error<E>(e: E) -> BackupError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    BackupError::Storage(object_store::Error::Generic {
+        store: "S3",
+        source: Box::new(e),
+    })
+}
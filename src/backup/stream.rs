@@ -0,0 +1,219 @@
+//! Streaming I/O for backup data
+//!
+//! Backup streams can be as large as the subvolumes they come from, so no
+//! part of the data path holds a whole stream: `btrfs send` is piped
+//! through encryption straight into the storage backend, and downloads
+//! are piped into `btrfs receive` the same way. Data moves in
+//! [`CHUNK_SIZE`] buffers taken from a [`BufferPool`], so memory stays flat
+//! however large a backup is, and buffers are reused instead of being
+//! allocated for every chunk.
+
+use std::io::{self, IoSlice};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Size of the buffers data is moved in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Buffers kept by the shared pool between uses
+const SHARED_IDLE: usize = 16;
+
+/// Buffers of [`CHUNK_SIZE`] shared by the whole data path
+static SHARED: BufferPool = BufferPool::new(CHUNK_SIZE, SHARED_IDLE);
+
+/// A stream of backup data
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Reusable buffers of one size
+#[derive(Debug)]
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    /// A pool of `size` byte buffers keeping at most `max_idle` between uses
+    pub const fn new(size: usize, max_idle: usize) -> Self {
+        Self {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The pool of [`CHUNK_SIZE`] buffers
+    pub fn shared() -> &'static Self {
+        &SHARED
+    }
+
+    /// Size of the pool's buffers
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    /// An empty buffer with room for [`BufferPool::buffer_size`] bytes
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let mut buf = idle.unwrap_or_else(|| BytesMut::with_capacity(self.size));
+        // Reclaims the whole allocation once split-off parts are dropped
+        buf.reserve(self.size);
+        PooledBuffer { pool: self, buf }
+    }
+}
+
+/// A buffer that goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: BytesMut,
+}
+
+impl PooledBuffer<'_> {
+    /// Fill the buffer up to the pool's buffer size from `reader`
+    ///
+    /// Short reads from pipes are retried, so only the end of the stream
+    /// leaves the buffer less than full. Returns the bytes read.
+    pub async fn fill<R: AsyncRead + Unpin + ?Sized>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let start = self.buf.len();
+        while self.buf.len() < self.pool.size {
+            let want = self.pool.size - self.buf.len();
+            if reader.read_buf(&mut (&mut self.buf).limit(want)).await? == 0 {
+                break;
+            }
+        }
+        Ok(self.buf.len() - start)
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.pool.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+/// Write every byte of `slices`, in as few system calls as the writer allows
+pub async fn write_all_vectored<W: AsyncWrite + Unpin + ?Sized>(
+    writer: &mut W,
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        let written = writer.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(())
+}
+
+/// Copy `reader` to `writer` through a pooled buffer, returning the bytes copied
+pub async fn copy<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = BufferPool::shared().get();
+    let mut total = 0;
+    loop {
+        buf.clear();
+        let n = buf.fill(reader).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf).await?;
+        total += n as u64;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Counts the bytes read through it
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    /// Count the bytes read from `inner`
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Bytes read so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.count += (buf.filled().len() - before) as u64;
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool_reuses_buffers() {
+        let pool = BufferPool::new(16, 1);
+        let ptr = {
+            let mut buf = pool.get();
+            let mut input: &[u8] = b"0123456789abcdefXYZ";
+            assert_eq!(buf.fill(&mut input).await.unwrap(), 16);
+            assert_eq!(&buf[..], b"0123456789abcdef");
+            buf.as_ptr()
+        };
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+
+    #[tokio::test]
+    async fn test_copy_counts_and_writes_vectored() {
+        let data = vec![7u8; CHUNK_SIZE * 2 + 5];
+        let mut reader = CountingReader::new(&data[..]);
+        let mut out = Vec::new();
+        assert_eq!(copy(&mut reader, &mut out).await.unwrap(), data.len() as u64);
+        assert_eq!(reader.count(), data.len() as u64);
+        assert_eq!(out, data);
+
+        let mut out = Vec::new();
+        let mut slices = [IoSlice::new(b"head"), IoSlice::new(b""), IoSlice::new(b"tail")];
+        write_all_vectored(&mut out, &mut slices).await.unwrap();
+        assert_eq!(out, b"headtail");
+    }
+}
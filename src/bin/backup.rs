@@ -2,10 +2,8 @@
 //! 
 //! Command-line interface for managing rastOS backups.
 
-use anyhow::Result;
 use clap::Parser;
 use rastos::backup::cli::BackupCli;
-use std::process;
 
 #[tokio::main]
 async fn main() {
@@ -16,8 +14,9 @@ async fn main() {
     let cli = BackupCli::parse();
 
     // Execute the command
-    if let Err(e) = cli.execute().await {
-        eprintln!("Error: {}", e);
-        process::exit(1);
+    let output = cli.output.output();
+    match cli.execute().await {
+        Ok(code) => code.exit(),
+        Err(e) => output.error(&e).exit(),
     }
 }
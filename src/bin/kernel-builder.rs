@@ -7,9 +7,11 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use log::LevelFilter;
-use rastos::kernel::{KernelBuilder, KernelProfile};
+use rastos::kernel::{BisectTest, Bisector, BootEntryConfig, BootTest, Bootloader, CompilerCache, DistributedConfig, InitramfsConfig, InitramfsGenerator, InitramfsHook, KernelBuilder, KernelProfile, LtoMode, ModuleSet, PackageFormat, PackageOptions, PatchSeries, RustSupport, SigningConfig, Toolchain};
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
 use std::path::PathBuf;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -34,6 +36,106 @@ struct Cli {
     #[arg(short, long)]
     config: Option<PathBuf>,
 
+    /// Distribute compilation over these distcc hosts
+    #[arg(long, value_delimiter = ',', conflicts_with = "icecream")]
+    distcc: Vec<String>,
+
+    /// Distribute compilation through the local icecream daemon
+    #[arg(long)]
+    icecream: bool,
+
+    /// Compile jobs sent to each distcc/icecream host
+    #[arg(long, default_value_t = 8)]
+    jobs_per_host: usize,
+
+    /// Fail instead of building locally when distribution is unavailable
+    #[arg(long)]
+    no_local_fallback: bool,
+
+    /// Compiler toolchain
+    #[arg(long, value_enum, default_value_t = Toolchain::Gcc)]
+    toolchain: Toolchain,
+
+    /// Link-time optimization (LLVM toolchain only)
+    #[arg(long, value_enum, default_value_t = LtoMode::None)]
+    lto: LtoMode,
+
+    /// Rust support in the kernel (auto enables it for LLVM builds when available)
+    #[arg(long, value_enum, default_value_t = RustSupport::Auto)]
+    rust: RustSupport,
+
+    /// Enable the kernel address sanitizer (development builds)
+    #[arg(long)]
+    kasan: bool,
+
+    /// Compiler cache used to speed up rebuilds
+    #[arg(long, value_enum, default_value_t = CompilerCache::Auto)]
+    compiler_cache: CompilerCache,
+
+    /// Generate an initramfs with this tool after installing
+    #[arg(long, value_enum)]
+    initramfs: Option<InitramfsGenerator>,
+
+    /// Initramfs hooks to enable (default: btrfs, snapshot-boot)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    initramfs_hooks: Vec<InitramfsHook>,
+
+    /// Patch series (TOML) applied before configuring
+    #[arg(long)]
+    patches: Option<PathBuf>,
+
+    /// Skip patches that do not apply instead of failing
+    #[arg(long)]
+    skip_broken: bool,
+
+    /// Trim the config to the modules loaded on this machine
+    #[arg(long)]
+    localmodconfig: bool,
+
+    /// Additional module lists (e.g. from other boot profiles) kept by --localmodconfig
+    #[arg(long = "modules-from")]
+    modules_from: Vec<PathBuf>,
+
+    /// Create a boot entry for the new kernel with this bootloader
+    #[arg(long, value_enum)]
+    bootloader: Option<Bootloader>,
+
+    /// Boot partition receiving kernels and entries
+    #[arg(long, default_value = "/boot")]
+    boot_dir: PathBuf,
+
+    /// Kernel command line for the entry (default: the running kernel's)
+    #[arg(long)]
+    cmdline: Option<String>,
+
+    /// Number of kernels to keep on the boot partition
+    #[arg(long, default_value_t = 3)]
+    keep_kernels: usize,
+
+    /// Sign kernel modules with the managed key (always on for production)
+    #[arg(long)]
+    sign_modules: bool,
+
+    /// Sign the kernel image for Secure Boot
+    #[arg(long)]
+    secure_boot: bool,
+
+    /// Queue the signing certificate for MOK enrollment
+    #[arg(long)]
+    enroll_mok: bool,
+
+    /// Directory holding the signing keys
+    #[arg(long)]
+    key_dir: Option<PathBuf>,
+
+    /// Abort the build after this many minutes
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Redo every build stage even if nothing changed
+    #[arg(long)]
+    force: bool,
+
     /// Enable debug output
     #[arg(short, long)]
     debug: bool,
@@ -49,6 +151,69 @@ enum Commands {
     Build,
     /// Clean build directory
     Clean,
+    /// Reverse patches applied by a previous build
+    Unpatch,
+    /// Package the last build for installation through the package manager
+    Package {
+        /// Package formats to produce
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "pacman")]
+        format: Vec<PackageFormat>,
+
+        /// Package name
+        #[arg(long, default_value = "linux-rastos")]
+        name: String,
+
+        /// Package release
+        #[arg(long, default_value_t = 1)]
+        pkgrel: u32,
+
+        /// Skip the headers package
+        #[arg(long)]
+        no_headers: bool,
+    },
+    /// Check the configured kernel against rastOS and profile requirements
+    Check,
+    /// Show warnings and errors from the last build
+    Log,
+    /// Save the loaded modules to a list, merging with its current contents
+    CollectModules {
+        /// Module list to update
+        #[arg(default_value = "modules.list")]
+        output: PathBuf,
+    },
+    /// Find the commit that introduced a regression with git bisect
+    Bisect {
+        /// Last known good revision
+        good: String,
+
+        /// First known bad revision
+        #[arg(default_value = "HEAD")]
+        bad: String,
+
+        /// Boot each step in QEMU instead of only building it
+        #[arg(long)]
+        qemu: bool,
+
+        /// Initramfs for the QEMU boot test
+        #[arg(long)]
+        initrd: Option<PathBuf>,
+
+        /// Console output marking a successful QEMU boot
+        #[arg(long)]
+        success_pattern: Option<String>,
+
+        /// Seconds allowed for a QEMU boot
+        #[arg(long, default_value_t = 120)]
+        boot_timeout: u64,
+
+        /// Shell command judging each step (exit 0 good, 125 skip, else bad)
+        #[arg(long, conflicts_with = "qemu")]
+        test_command: Option<String>,
+
+        /// Mark commits that fail to build as bad instead of skipping them
+        #[arg(long)]
+        build_failures_bad: bool,
+    },
 }
 
 #[tokio::main]
@@ -72,12 +237,91 @@ async fn main() -> Result<()> {
     // Create kernel builder
     let mut builder = KernelBuilder::new(&cli.source)
         .with_jobs(cli.jobs)
-        .with_profile(cli.profile);
+        .with_profile(cli.profile)
+        .with_kasan(cli.kasan)
+        .with_compiler_cache(cli.compiler_cache)
+        .with_toolchain(cli.toolchain)
+        .with_lto(cli.lto)
+        .with_rust(cli.rust)
+        .with_force(cli.force);
+
+    let distributed = if cli.icecream {
+        Some(DistributedConfig::icecream())
+    } else if !cli.distcc.is_empty() {
+        Some(DistributedConfig::distcc(cli.distcc.clone()))
+    } else {
+        None
+    };
+    if let Some(distributed) = distributed {
+        builder = builder.with_distributed(
+            distributed
+                .with_jobs_per_host(cli.jobs_per_host)
+                .with_local_jobs(cli.jobs)
+                .with_fallback_local(!cli.no_local_fallback),
+        );
+    }
+
+    // Ctrl-C stops make's process group and leaves the build resumable
+    let token = CancellationToken::new();
+    {
+        let token = token.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                token.cancel();
+            }
+        });
+    }
+    builder = builder.with_cancellation(token);
+
+    if let Some(minutes) = cli.timeout {
+        builder = builder.with_timeout(Duration::from_secs(minutes * 60));
+    }
+
+    let mut signing = SigningConfig::default()
+        .with_module_signing(cli.sign_modules)
+        .with_secure_boot(cli.secure_boot)
+        .with_mok_enrollment(cli.enroll_mok);
+    if let Some(key_dir) = &cli.key_dir {
+        signing = signing.with_key_dir(key_dir);
+    }
+    builder = builder.with_signing(signing);
+
+    if cli.localmodconfig || !cli.modules_from.is_empty() {
+        let mut modules = ModuleSet::loaded()?;
+        for path in &cli.modules_from {
+            modules.merge(&ModuleSet::from_file(path)?);
+        }
+        builder = builder.with_local_modules(modules);
+    }
+
+    if let Some(bootloader) = cli.bootloader {
+        let mut entries = BootEntryConfig::default()
+            .with_bootloader(bootloader)
+            .with_boot_dir(&cli.boot_dir)
+            .with_keep(cli.keep_kernels);
+        if let Some(cmdline) = &cli.cmdline {
+            entries = entries.with_cmdline(cmdline);
+        }
+        builder = builder.with_boot_entries(entries);
+    }
+
+    if let Some(patches) = &cli.patches {
+        let series = PatchSeries::from_file(patches)?.with_skip_broken(cli.skip_broken);
+        builder = builder.with_patches(series);
+    }
 
     if let Some(config_path) = cli.config {
         builder = builder.with_config(config_path);
     }
 
+    if let Some(generator) = cli.initramfs {
+        let mut initramfs = InitramfsConfig::default().with_generator(generator);
+        if !cli.initramfs_hooks.is_empty() {
+            initramfs.hooks = cli.initramfs_hooks.clone();
+        }
+        builder = builder.with_initramfs(initramfs);
+    }
+
     match cli.command {
         Commands::Build => {
             println!("Building kernel from source: {}", cli.source.display());
@@ -92,6 +336,82 @@ async fn main() -> Result<()> {
             std::fs::remove_dir_all(cli.source.join("build"))?;
             println!("✓ Build directory cleaned");
         }
+        Commands::Package { format, name, pkgrel, no_headers } => {
+            let options = PackageOptions::default()
+                .with_name(name)
+                .with_pkgrel(pkgrel)
+                .with_formats(format)
+                .with_headers(!no_headers);
+            for package in builder.package(&options)? {
+                println!("✓ {}", package.display());
+            }
+        }
+        Commands::Check => {
+            builder.validate_config()?;
+            println!("✓ Kernel config satisfies {:?} profile requirements", cli.profile);
+        }
+        Commands::Log => {
+            let log = builder.build_log()?;
+            for diagnostic in log.errors.iter().chain(&log.warnings) {
+                match (&diagnostic.file, diagnostic.line) {
+                    (Some(file), Some(line)) => println!("{:?} {}:{}: {}", diagnostic.level, file, line, diagnostic.message),
+                    _ => println!("{:?} {}", diagnostic.level, diagnostic.message),
+                }
+            }
+            println!(
+                "{} units compiled, {} warnings, {} errors ({})",
+                log.compiled,
+                log.warnings.len(),
+                log.errors.len(),
+                if log.success { "succeeded" } else { "failed" }
+            );
+        }
+        Commands::CollectModules { output } => {
+            let merged = ModuleSet::loaded()?.save(&output)?;
+            println!("✓ {} modules recorded in {}", merged.len(), output.display());
+        }
+        Commands::Bisect {
+            good,
+            bad,
+            qemu,
+            initrd,
+            success_pattern,
+            boot_timeout,
+            test_command,
+            build_failures_bad,
+        } => {
+            let test = if let Some(command) = test_command {
+                BisectTest::Command(command)
+            } else if qemu {
+                let mut boot = BootTest::default().with_timeout(Duration::from_secs(boot_timeout));
+                if let Some(initrd) = initrd {
+                    boot = boot.with_initrd(initrd);
+                }
+                if let Some(pattern) = success_pattern {
+                    boot = boot.with_success_pattern(pattern);
+                }
+                BisectTest::Boot(boot)
+            } else {
+                BisectTest::Build
+            };
+
+            let outcome = Bisector::new(builder, good, bad)
+                .with_test(test)
+                .with_skip_build_failures(!build_failures_bad)
+                .run()?;
+            for step in &outcome.steps {
+                println!("{} {}", step.verdict.as_str(), step.commit);
+            }
+            match &outcome.first_bad {
+                Some(commit) => println!("✓ First bad commit: {}", commit),
+                None => println!("✗ Could not isolate the first bad commit; see the bisect log below"),
+            }
+            println!("\n{}", outcome.log);
+        }
+        Commands::Unpatch => {
+            let reversed = rastos::kernel::patches::reverse(&cli.source)?;
+            println!("✓ Reversed {} patches", reversed.len());
+        }
     }
 
     Ok(())
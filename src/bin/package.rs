@@ -0,0 +1,20 @@
+//! rastOS Package Utility
+//!
+//! Command-line interface for managing rastOS packages.
+
+use clap::Parser;
+use rastos::package::cli::PackageCli;
+
+fn main() {
+    // Initialize logging
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // Parse command line arguments
+    let cli = PackageCli::parse();
+
+    // Execute the command
+    let output = cli.output.output();
+    if let Err(e) = cli.execute() {
+        output.error(&e).exit();
+    }
+}
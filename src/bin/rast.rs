@@ -0,0 +1,21 @@
+//! rastOS System Utility
+//!
+//! Command-line interface for managing a rastOS system.
+
+use clap::Parser;
+use rastos::system::cli::RastCli;
+
+fn main() {
+    // Initialize logging
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    // Parse command line arguments
+    let cli = RastCli::parse();
+
+    // Execute the command
+    let output = cli.output.output();
+    match cli.execute() {
+        Ok(code) => code.exit(),
+        Err(e) => output.error(&*e).exit(),
+    }
+}
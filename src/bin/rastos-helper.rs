@@ -0,0 +1,61 @@
+//! rastOS privileged helper
+//!
+//! Performs the few operations the other tools need root for. Run as a
+//! service it serves `/run/rastos/helper.sock`, preferably socket-activated;
+//! installed setuid root it is run with `--stdio` by the tool that needs it
+//! and serves only that process. The configuration is always
+//! `/etc/rast/helper.toml`.
+
+use std::env;
+use std::path::Path;
+
+use log::LevelFilter;
+use rastos::helper::{server, Helper, HelperConfig, Peer, HELPER_SOCKET};
+use rastos::output::ExitCode;
+use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
+
+fn main() {
+    // Standard output may be the protocol, so log to standard error only
+    TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Stderr, ColorChoice::Never)
+        .expect("no other logger is set");
+
+    let stdio = match env::args().nth(1).as_deref() {
+        None => false,
+        Some("--stdio") => true,
+        Some(arg) => {
+            eprintln!("Usage: rastos-helper [--stdio]; unexpected argument '{}'", arg);
+            ExitCode::Usage.exit();
+        }
+    };
+    // A setuid caller controls the environment; commands run by the
+    // helper must not find their programs or libraries through it
+    for (name, _) in env::vars_os() {
+        if !stdio && (name == "LISTEN_PID" || name == "LISTEN_FDS") {
+            continue;
+        }
+        // SAFETY: no other thread has been started yet
+        unsafe { env::remove_var(name) };
+    }
+    // SAFETY: as above
+    unsafe { env::set_var("PATH", "/usr/bin:/usr/sbin:/bin:/sbin") };
+    let parent = Peer::parent();
+    if stdio {
+        // Become root entirely, so programs the helper runs keep no trace of the caller
+        if let Err(e) = nix::unistd::setuid(nix::unistd::Uid::from_raw(0)) {
+            eprintln!("Error: rastos-helper --stdio must be setuid root: {}", e);
+            ExitCode::Failure.exit();
+        }
+    }
+
+    let result = HelperConfig::load().map(Helper::new).and_then(|helper| {
+        if stdio {
+            helper.serve_stdio(&parent)
+        } else {
+            helper.serve(server::listener(Path::new(HELPER_SOCKET))?)
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        ExitCode::from_error(&e).exit();
+    }
+}
@@ -0,0 +1,22 @@
+//! rastOS Management Utility
+//!
+//! Command-line interface covering every rastOS subsystem.
+
+use clap::Parser;
+use rastos::cli::RastosCli;
+
+#[tokio::main]
+async fn main() {
+    // Parse command line arguments
+    let cli = RastosCli::parse();
+
+    // Initialize logging at the level selected by --verbose
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(cli.log_level().as_str())).init();
+
+    // Execute the command
+    let output = cli.output.output();
+    match cli.execute().await {
+        Ok(code) => code.exit(),
+        Err(e) => output.error(&*e).exit(),
+    }
+}
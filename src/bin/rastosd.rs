@@ -0,0 +1,23 @@
+//! rastOS Management Daemon
+//!
+//! Serves the rastosd API. Takes the configuration file as its only
+//! argument, `/etc/rast/rastosd.toml` by default.
+
+use rastos::daemon::{Daemon, DaemonConfig, CONFIG_PATH};
+use rastos::output::ExitCode;
+
+#[tokio::main]
+async fn main() {
+    // Initialize logging
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let path = std::env::args().nth(1).unwrap_or_else(|| CONFIG_PATH.to_string());
+    let result = match DaemonConfig::load(&path).and_then(Daemon::new) {
+        Ok(daemon) => daemon.serve().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        ExitCode::from_error(&e).exit();
+    }
+}
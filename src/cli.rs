@@ -0,0 +1,685 @@
+//! The `rastos` command covering every subsystem
+//!
+//! Each subsystem's commands are the ones of its own tool (`rast-backup`,
+//! `rast-package`, `rast`), so the same subcommands work in both places.
+//! The global flags go before the subsystem:
+//!
+//! ```text
+//! rastos --json system doctor
+//! rastos -v --config /srv/backup.toml backup list
+//! ```
+//!
+//! `--format` (or `--json`) selects the [output format](crate::output) of
+//! every command, `--verbose` raises the log level and `--config` replaces
+//! the configuration file of the subsystem: the backup configuration, or the
+//! machine manifest and network configuration for `system`.
+//!
+//! `--dry-run` shows what a command would change instead of changing it;
+//! commands whose subsystem cannot plan its changes refuse to run with it.
+//!
+//! `--host` runs the command on another machine through the
+//! [remote](crate::remote) transports instead:
+//!
+//! ```text
+//! rastos --host nas.lan backup create @home
+//! ```
+
+use std::error::Error;
+use std::ffi::OsString;
+#[cfg(any(feature = "daemon", feature = "installer"))]
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+
+use crate::auth::cli::handle_api_key_command;
+use crate::auth::ApiKeyCommand;
+use crate::dry_run::{Action, DryRun};
+#[cfg(feature = "backup")]
+use crate::backup::cli::{BackupCli, BackupCommand};
+#[cfg(feature = "installer")]
+use crate::installer::{InstallProfile, Installer};
+use crate::jobs::{JobState, JobStore};
+use crate::kernel::BootEntryConfig;
+#[cfg(feature = "kernel")]
+use crate::kernel::{KernelBuilder, KernelProfile};
+#[cfg(feature = "containers")]
+use crate::oci::{Container, ContainerBuilder, LinuxBuilder, ProcessBuilder};
+#[cfg(feature = "containers")]
+use crate::output::OutputFormat;
+use crate::output::{ExitCode, Output, OutputArgs};
+use crate::package::cli::{PackageCli, PackageCommand};
+use crate::remote::ssh::SshClient;
+use crate::remote::{HostConfig, RemoteConfig, RemoteError, Transport};
+use crate::snapshot::transaction::SnapshotSet;
+use crate::snapshot::DEFAULT_SNAPSHOT_DIR;
+use crate::system::cli::{RastCli, RastCommand};
+#[cfg(feature = "containers")]
+use crate::system::mac::Mac;
+use crate::transaction::Executor;
+
+/// rastOS management
+#[derive(Debug, Parser)]
+#[command(name = "rastos", version, about = "Manage a rastOS system")]
+pub struct RastosCli {
+    /// Command to run
+    #[command(subcommand)]
+    pub command: RastosCommand,
+
+    /// Output format
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    /// More log output; repeat for debug output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Configuration file of the subsystem
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Run the command on another machine: a host of /etc/rast/remote.toml,
+    /// `ssh://[user@]host[:port]` or `grpc://host[:port]`
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Show what would change instead of changing it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+/// Subsystems
+#[derive(Debug, Subcommand)]
+pub enum RastosCommand {
+    /// Create, list and delete Btrfs snapshots
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+
+    /// Manage backups
+    #[cfg(feature = "backup")]
+    #[command(subcommand)]
+    Backup(BackupCommand),
+
+    /// Create and inspect container bundles
+    #[cfg(feature = "containers")]
+    #[command(subcommand)]
+    Container(ContainerCommand),
+
+    /// Manage packages
+    Package {
+        /// System root to operate on
+        #[arg(short, long, default_value = "/")]
+        root: PathBuf,
+
+        /// Package command to run
+        #[command(subcommand)]
+        command: PackageCommand,
+    },
+
+    /// Build kernels and manage their boot entries
+    #[command(subcommand)]
+    Kernel(KernelCommand),
+
+    /// Install rastOS
+    #[cfg(feature = "installer")]
+    #[command(subcommand)]
+    Install(InstallCommand),
+
+    /// Manage API keys and client certificates
+    #[command(subcommand)]
+    Auth(ApiKeyCommand),
+
+    /// Follow and cancel the daemon's background jobs
+    Jobs {
+        /// System root whose job store to use
+        #[arg(short, long, default_value = "/")]
+        root: PathBuf,
+
+        /// Job command to run
+        #[command(subcommand)]
+        command: JobCommand,
+    },
+
+    /// Manage the running system
+    System {
+        /// System root to operate on
+        #[arg(short, long, default_value = "/")]
+        root: PathBuf,
+
+        /// System command to run
+        #[command(subcommand)]
+        command: RastCommand,
+    },
+}
+
+/// Snapshot subcommands
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    /// Snapshot a subvolume
+    Create {
+        /// Subvolume to snapshot
+        source: PathBuf,
+
+        /// Path of the new snapshot
+        dest: PathBuf,
+
+        /// Make the snapshot writable
+        #[arg(long)]
+        writable: bool,
+    },
+
+    /// List the subvolumes below a directory
+    List {
+        /// Directory to list
+        #[arg(default_value = DEFAULT_SNAPSHOT_DIR)]
+        path: PathBuf,
+    },
+
+    /// Delete a snapshot
+    Delete {
+        /// Snapshot to delete
+        path: PathBuf,
+    },
+}
+
+/// Container subcommands
+#[cfg(feature = "containers")]
+#[derive(Debug, Subcommand)]
+pub enum ContainerCommand {
+    /// Write the OCI runtime spec of a new container bundle
+    Create {
+        /// Container ID
+        id: String,
+
+        /// Bundle directory; `config.json` is written into it
+        bundle: PathBuf,
+
+        /// Root filesystem, relative to the bundle
+        #[arg(long, default_value = "rootfs")]
+        rootfs: PathBuf,
+
+        /// Confine the container with this shipped SELinux or AppArmor profile
+        #[arg(long)]
+        mac_profile: Option<String>,
+
+        /// Command to run
+        #[arg(last = true, default_value = "/bin/sh")]
+        args: Vec<String>,
+    },
+
+    /// Show the runtime spec of a bundle
+    Inspect {
+        /// Bundle directory
+        bundle: PathBuf,
+    },
+
+    /// Delete a container bundle
+    Delete {
+        /// Bundle directory
+        bundle: PathBuf,
+    },
+}
+
+/// Kernel subcommands; `rast-kernel-builder` has every build option
+#[derive(Debug, Subcommand)]
+pub enum KernelCommand {
+    /// Configure and build a kernel
+    #[cfg(feature = "kernel")]
+    Build {
+        /// Kernel source directory
+        source: PathBuf,
+
+        /// Build profile
+        #[arg(short, long, value_enum, default_value_t = KernelProfile::ContainerHost)]
+        profile: KernelProfile,
+
+        /// Number of parallel jobs
+        #[arg(short, long, default_value_t = num_cpus::get())]
+        jobs: usize,
+    },
+
+    /// Check a configured kernel against the profile requirements
+    #[cfg(feature = "kernel")]
+    Check {
+        /// Kernel source directory
+        source: PathBuf,
+
+        /// Build profile
+        #[arg(short, long, value_enum, default_value_t = KernelProfile::ContainerHost)]
+        profile: KernelProfile,
+    },
+
+    /// List the kernels installed on the boot partition
+    List {
+        /// Boot partition
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+    },
+}
+
+/// Installer subcommands
+#[cfg(feature = "installer")]
+#[derive(Debug, Subcommand)]
+pub enum InstallCommand {
+    /// Check whether this machine can be installed on
+    Preflight {
+        /// Install profile
+        profile: PathBuf,
+    },
+
+    /// Install from a profile
+    Run {
+        /// Install profile
+        profile: PathBuf,
+
+        /// Where the new system is mounted
+        #[arg(long, default_value = "/mnt")]
+        target: PathBuf,
+
+        /// Continue an interrupted installation
+        #[arg(long)]
+        resume: bool,
+    },
+}
+
+/// Background job subcommands
+#[derive(Debug, Subcommand)]
+pub enum JobCommand {
+    /// List jobs, oldest first
+    List {
+        /// Only jobs in this state
+        #[arg(long, value_enum)]
+        state: Option<JobState>,
+    },
+
+    /// Cancel a pending job, or ask a running one to stop
+    Cancel {
+        /// Job ID
+        id: u64,
+    },
+
+    /// Print the log of a job
+    Logs {
+        /// Job ID
+        id: u64,
+    },
+}
+
+impl RastosCli {
+    /// Log level selected by `--verbose`
+    pub fn log_level(&self) -> LevelFilter {
+        match self.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Execute the command, returning the process exit code
+    pub async fn execute(self) -> Result<ExitCode, Box<dyn Error>> {
+        let Self { command, output: output_args, verbose, config, host, dry_run } = self;
+        let output = output_args.output();
+        if let Some(host) = host {
+            return handle_remote(&host, command, dry_run, output).await;
+        }
+        if let Some(subsystem) = without_dry_run(&command).filter(|_| dry_run) {
+            return Err(format!("the {} commands do not support --dry-run", subsystem).into());
+        }
+        let plan = DryRun::enabled(dry_run);
+        match command {
+            RastosCommand::Snapshot(command) => handle_snapshot(command, output, &plan).map(|()| ExitCode::Success),
+            #[cfg(feature = "backup")]
+            RastosCommand::Backup(command) => {
+                let cli = BackupCli {
+                    command,
+                    config: config.unwrap_or_else(|| PathBuf::from("/etc/rast/backup.toml")),
+                    debug: verbose > 0,
+                    dry_run,
+                    output: output_args,
+                };
+                Ok(cli.execute().await?)
+            }
+            #[cfg(feature = "containers")]
+            RastosCommand::Container(command) => handle_container(command, output, &plan).map(|()| ExitCode::Success),
+            RastosCommand::Package { root, command } => {
+                PackageCli { command, root, verbose: verbose > 0, dry_run, output: output_args }.execute()?;
+                Ok(ExitCode::Success)
+            }
+            RastosCommand::Kernel(command) => handle_kernel(command, output).await.map(|()| ExitCode::Success),
+            #[cfg(feature = "installer")]
+            RastosCommand::Install(command) => handle_install(command, output, &plan),
+            RastosCommand::Auth(command) => {
+                handle_api_key_command(command, output).await.map(|()| ExitCode::Success)
+            }
+            RastosCommand::Jobs { root, command } => {
+                handle_jobs(&JobStore::with_root(&root), command, output).map(|()| ExitCode::Success)
+            }
+            RastosCommand::System { root, mut command } => {
+                if let Some(config) = &config {
+                    command.use_config(config);
+                }
+                RastCli { command, root, output: output_args }.execute()
+            }
+        }
+    }
+}
+
+/// Subsystem whose commands cannot plan their changes in a dry run
+fn without_dry_run(command: &RastosCommand) -> Option<&'static str> {
+    match command {
+        RastosCommand::Kernel(_) => Some("kernel"),
+        RastosCommand::Auth(_) => Some("auth"),
+        RastosCommand::Jobs { .. } => Some("jobs"),
+        RastosCommand::System { .. } => Some("system"),
+        _ => None,
+    }
+}
+
+fn handle_snapshot(command: SnapshotCommand, output: Output, dry_run: &DryRun) -> Result<(), Box<dyn Error>> {
+    match command {
+        SnapshotCommand::Create { source, dest, writable } => {
+            let mut snapshot = SnapshotSet::new().writable(writable).with_snapshot(&source, &dest);
+            Executor::new().with_dry_run(dry_run.clone()).run(&mut snapshot)?;
+            if dry_run.is_active() {
+                return Ok(output.plan(&dry_run.plan())?);
+            }
+            output.status(format!("Created {}", dest.display()));
+        }
+        SnapshotCommand::List { path } => {
+            let subvolumes = crate::fs::list_subvolumes(&path)?;
+            output.print(&subvolumes, || {
+                for subvolume in &subvolumes {
+                    println!("{}", subvolume.display());
+                }
+            })?;
+        }
+        SnapshotCommand::Delete { path } => {
+            if dry_run.skip(Action::delete(format!("subvolume {}", path.display()))) {
+                return Ok(output.plan(&dry_run.plan())?);
+            }
+            crate::fs::delete_subvolume(&path)?;
+            output.status(format!("Deleted {}", path.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Run the command on `host`, returning the remote exit code
+///
+/// Over ssh the remote `rastos` gets the whole command line, `--dry-run`
+/// included; the daemon cannot do dry runs.
+async fn handle_remote(host: &str, command: RastosCommand, dry_run: bool, output: Output) -> Result<ExitCode, Box<dyn Error>> {
+    let host = RemoteConfig::load()?.host(host)?;
+    match host.transport {
+        Transport::Ssh => {
+            let args = remote_args(std::env::args_os().skip(1));
+            Ok(ExitCode::from_code(SshClient::new(host).run(args)?))
+        }
+        Transport::Grpc if dry_run => Err(unsupported("the daemon does not do dry runs").into()),
+        Transport::Grpc => handle_grpc(&host, command, output).await,
+    }
+}
+
+/// The command line without `--host`, to be run by the remote `rastos`
+fn remote_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.into_iter();
+    let mut remote = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--host" {
+            args.next();
+            break;
+        }
+        if arg.to_string_lossy().starts_with("--host=") {
+            break;
+        }
+        remote.push(arg);
+    }
+    remote.extend(args);
+    remote
+}
+
+/// Run a snapshot or backup command through the daemon of `host`
+#[cfg(feature = "daemon")]
+async fn handle_grpc(host: &HostConfig, command: RastosCommand, output: Output) -> Result<ExitCode, Box<dyn Error>> {
+    use crate::remote::grpc::GrpcClient;
+
+    let client = GrpcClient::connect(host).await?;
+    let snapshot_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{} does not name a snapshot", path.display()))
+    };
+    match command {
+        RastosCommand::Snapshot(SnapshotCommand::Create { source, dest, writable }) => {
+            let snapshot = client.create_snapshot(&source, &snapshot_name(&dest)?, writable).await?;
+            output.status(format!("Created {} on {}", snapshot.path.display(), host.address));
+        }
+        RastosCommand::Snapshot(SnapshotCommand::List { path }) => {
+            if path != Path::new(DEFAULT_SNAPSHOT_DIR) {
+                return Err(unsupported("the daemon only lists its own snapshot directory").into());
+            }
+            let snapshots = client.snapshots().await?;
+            output.print(&snapshots, || {
+                for snapshot in &snapshots {
+                    println!("{}", snapshot.path.display());
+                }
+            })?;
+        }
+        RastosCommand::Snapshot(SnapshotCommand::Delete { path }) => {
+            client.delete_snapshot(&snapshot_name(&path)?).await?;
+            output.status(format!("Deleted {} on {}", path.display(), host.address));
+        }
+        RastosCommand::Backup(BackupCommand::Create { subvolume, description, .. }) => {
+            let backup = client.create_backup(&subvolume, description).await?;
+            output.print(&backup, || println!("Backup created successfully: {}", backup.id))?;
+        }
+        RastosCommand::Backup(BackupCommand::List { subvolume, verbose }) => {
+            let backups: Vec<_> = client
+                .backups()
+                .await?
+                .into_iter()
+                .filter(|backup| {
+                    subvolume
+                        .as_ref()
+                        .is_none_or(|subvol| backup.subvolume.to_string_lossy().contains(subvol.as_str()))
+                })
+                .collect();
+            let mut headers = vec!["ID", "SUBVOLUME", "SIZE", "CREATED"];
+            if verbose {
+                headers.push("DESCRIPTION");
+            }
+            output.table(&backups, &headers, |backup| {
+                let mut row = vec![
+                    backup.id.clone(),
+                    backup.subvolume.display().to_string(),
+                    humansize::format_size(backup.size, humansize::BINARY),
+                    chrono::DateTime::parse_from_rfc3339(&backup.created_at)
+                        .map_or_else(|_| backup.created_at.clone(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                ];
+                if verbose {
+                    row.push(backup.description.clone().unwrap_or_default());
+                }
+                row
+            })?;
+        }
+        RastosCommand::Backup(BackupCommand::Restore { backup_id, target, .. }) => {
+            let target = match target {
+                Some(target) => target,
+                None => client.backup(&backup_id).await?.subvolume,
+            };
+            output.status(format!("Restoring backup {} on {}...", backup_id, host.address));
+            client.restore_backup(&backup_id, &target).await?;
+            output.status("Backup restored successfully");
+        }
+        RastosCommand::Backup(BackupCommand::Verify { backup_id }) => {
+            output.status(format!("Verifying backup {} on {}...", backup_id, host.address));
+            if !client.verify_backup(&backup_id).await? {
+                output.status("✗ Backup verification failed");
+                return Ok(ExitCode::Critical);
+            }
+            output.status("✓ Backup is valid");
+        }
+        RastosCommand::Backup(BackupCommand::Remove { backup_id, .. }) => {
+            client.delete_backup(&backup_id).await?;
+            output.status(format!("Removed backup {} on {}", backup_id, host.address));
+        }
+        _ => return Err(unsupported("only snapshot and backup commands run over gRPC").into()),
+    }
+    Ok(ExitCode::Success)
+}
+
+#[cfg(not(feature = "daemon"))]
+async fn handle_grpc(_host: &HostConfig, _command: RastosCommand, _output: Output) -> Result<ExitCode, Box<dyn Error>> {
+    Err(unsupported("this rastos was built without gRPC support").into())
+}
+
+fn unsupported(reason: &str) -> RemoteError {
+    RemoteError::Unsupported(format!("{}; use an ssh host for this command", reason))
+}
+
+#[cfg(feature = "containers")]
+fn handle_container(command: ContainerCommand, output: Output, dry_run: &DryRun) -> Result<(), Box<dyn Error>> {
+    match command {
+        ContainerCommand::Create { id, bundle, rootfs, mac_profile, args } => {
+            let mut builder = ContainerBuilder::new(&id)
+                .root(&rootfs)
+                .process(ProcessBuilder::default().cwd("/").args(args))
+                .linux(LinuxBuilder::default());
+            match Mac::new().container_label(mac_profile.as_deref())? {
+                Some(label) => builder = builder.mac_label(label),
+                None if mac_profile.is_some() => return Err("neither SELinux nor AppArmor is active".into()),
+                None => {}
+            }
+            let spec = builder.build()?;
+            if dry_run.skip(Action::write(bundle.join("config.json").display())) {
+                return Ok(output.plan(&dry_run.plan())?);
+            }
+            std::fs::create_dir_all(&bundle)?;
+            spec.save(bundle.join("config.json"))?;
+            output.status(format!("Wrote {}", bundle.join("config.json").display()));
+        }
+        ContainerCommand::Inspect { bundle } => {
+            let container = Container::new(&bundle.to_string_lossy(), &bundle)?;
+            // The runtime spec is a JSON document, so that is its text form too
+            let output = if output.is_text() { Output::new(OutputFormat::Json) } else { output };
+            output.print(container.spec(), || {})?;
+        }
+        ContainerCommand::Delete { bundle } => {
+            // Only bundles are deleted, never an arbitrary directory
+            Container::new(&bundle.to_string_lossy(), &bundle)?;
+            if dry_run.skip(Action::delete(format!("container bundle {}", bundle.display()))) {
+                return Ok(output.plan(&dry_run.plan())?);
+            }
+            std::fs::remove_dir_all(&bundle)?;
+            output.status(format!("Deleted {}", bundle.display()));
+        }
+    }
+    Ok(())
+}
+
+async fn handle_kernel(command: KernelCommand, output: Output) -> Result<(), Box<dyn Error>> {
+    match command {
+        #[cfg(feature = "kernel")]
+        KernelCommand::Build { source, profile, jobs } => {
+            KernelBuilder::new(&source)
+                .with_profile(profile)
+                .with_jobs(jobs)
+                .with_progress(output.progress())
+                .build()
+                .await?;
+            output.status(format!("Built the kernel in {}", source.display()));
+        }
+        #[cfg(feature = "kernel")]
+        KernelCommand::Check { source, profile } => {
+            KernelBuilder::new(&source).with_profile(profile).validate_config()?;
+            output.status(format!("Kernel config satisfies the {:?} profile", profile));
+        }
+        KernelCommand::List { boot_dir } => {
+            let releases = BootEntryConfig::default().with_boot_dir(&boot_dir).installed()?;
+            output.print(&releases, || {
+                for release in &releases {
+                    println!("{}", release);
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_jobs(store: &JobStore, command: JobCommand, output: Output) -> Result<(), Box<dyn Error>> {
+    match command {
+        JobCommand::List { state } => {
+            let jobs: Vec<_> = store
+                .list()?
+                .into_iter()
+                .filter(|job| state.is_none_or(|state| job.state == state))
+                .collect();
+            output.table(&jobs, &["ID", "JOB", "STATE", "PRIORITY", "CREATED"], |job| {
+                vec![
+                    job.id.to_string(),
+                    job.spec.to_string(),
+                    job.state.to_string(),
+                    job.priority.to_string(),
+                    job.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                ]
+            })?;
+        }
+        JobCommand::Cancel { id } => {
+            let job = store.cancel(id)?;
+            if job.state == JobState::Cancelled {
+                output.status(format!("Cancelled job {}", id));
+            } else {
+                output.status(format!("Asked job {} to stop", id));
+            }
+        }
+        JobCommand::Logs { id } => {
+            let log = store.read_log(id)?;
+            output.print(&log, || print!("{}", log))?;
+        }
+    }
+    Ok(())
+}
+
+/// Handle the installer commands; failed preflight checks exit with [`ExitCode::Critical`]
+#[cfg(feature = "installer")]
+fn handle_install(command: InstallCommand, output: Output, dry_run: &DryRun) -> Result<ExitCode, Box<dyn Error>> {
+    match command {
+        InstallCommand::Preflight { profile } => {
+            let report = installer(&profile, Path::new("/mnt"))?.preflight();
+            output.print(&report, || {
+                for check in &report.checks {
+                    println!("{}", check);
+                }
+            })?;
+            if !report.passed() {
+                output.status("Preflight checks failed");
+                return Ok(ExitCode::Critical);
+            }
+        }
+        InstallCommand::Run { profile, target, resume } => {
+            let installer = installer(&profile, &target)?
+                .with_progress(output.progress())
+                .with_dry_run(dry_run.clone());
+            if resume {
+                installer.resume()?;
+            } else {
+                installer.run()?;
+            }
+            if dry_run.is_active() {
+                output.plan(&dry_run.plan())?;
+                return Ok(ExitCode::Success);
+            }
+            output.status(format!("rastOS is installed in {}", target.display()));
+        }
+    }
+    Ok(ExitCode::Success)
+}
+
+#[cfg(feature = "installer")]
+fn installer(profile: &Path, target: &Path) -> Result<Installer, Box<dyn Error>> {
+    Ok(Installer::new()
+        .with_profile(InstallProfile::from_file(profile)?)
+        .with_target(target))
+}
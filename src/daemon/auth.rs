@@ -0,0 +1,163 @@
+//! Authentication of daemon callers
+//!
+//! Only `Auth.ExchangeToken` accepts an API key. Every other call carries a
+//! token from it in an `authorization: Bearer <token>` header, and each
+//! method checks the scope it needs against the token's claims.
+//!
+//! On the mutual TLS listener, a client certificate pinned in the `[mtls]`
+//! table of the key configuration stands in for a token and carries the
+//! scopes of its pin.
+
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::auth::{
+    ApiKeyConfig, ApiKeyManager, AuthError, Claims, ClientCertVerifier, MtlsConfig, Token, TokenIssuer, TokenVerifier,
+};
+
+use super::Result;
+
+/// Secret holding the key tokens are signed with
+const SIGNING_KEY_SECRET: &str = "rastosd-token-key";
+
+/// Scopes checked by the daemon API
+pub mod scopes {
+    /// List and inspect containers
+    pub const CONTAINER_READ: &str = "container:read";
+    /// Create, start, stop and delete containers
+    pub const CONTAINER_WRITE: &str = "container:write";
+    /// List snapshots
+    pub const SNAPSHOT_READ: &str = "snapshot:read";
+    /// Create and delete snapshots
+    pub const SNAPSHOT_WRITE: &str = "snapshot:write";
+    /// List, inspect and verify backups
+    pub const BACKUP_READ: &str = "backup:read";
+    /// Create, restore and delete backups
+    pub const BACKUP_WRITE: &str = "backup:write";
+    /// Show the package history
+    pub const PACKAGE_READ: &str = "package:read";
+    /// Install packages and undo transactions
+    pub const PACKAGE_WRITE: &str = "package:write";
+    /// Build kernels
+    pub const KERNEL_WRITE: &str = "kernel:write";
+    /// List background jobs and read their logs
+    pub const JOB_READ: &str = "job:read";
+    /// Show the deployments and pending updates
+    pub const UPDATE_READ: &str = "update:read";
+}
+
+/// Issues and checks the tokens of daemon callers
+pub struct Authenticator {
+    keys: ApiKeyManager,
+    issuer: TokenIssuer,
+    verifier: TokenVerifier,
+    certs: ClientCertVerifier,
+}
+
+impl Authenticator {
+    /// Accept the keys in `keys`, issuing tokens with `issuer`
+    pub fn new(keys: ApiKeyManager, issuer: TokenIssuer) -> Self {
+        let verifier = issuer.verifier();
+        Self {
+            keys,
+            issuer,
+            verifier,
+            certs: ClientCertVerifier::new(&MtlsConfig::default()),
+        }
+    }
+
+    /// Also accept the client certificates pinned in `config`
+    pub fn with_client_certs(mut self, config: &MtlsConfig) -> Self {
+        self.certs = ClientCertVerifier::new(config);
+        self
+    }
+
+    /// Accept the keys of an API key configuration
+    ///
+    /// Tokens are signed with a key kept in the configuration's secret store,
+    /// so they stay valid across restarts, and may carry the scopes listed
+    /// for each service.
+    pub fn from_config(config: &ApiKeyConfig, ttl: Duration) -> Result<Self> {
+        let keys = ApiKeyManager::new();
+        config.add_to_manager(&keys)?;
+        let issuer = TokenIssuer::load_or_generate(&config.secret_store()?, SIGNING_KEY_SECRET)?
+            .with_ttl(ttl)
+            .with_config_scopes(config);
+        Ok(Self::new(keys, issuer).with_client_certs(&config.mtls))
+    }
+
+    /// Exchange an API key for a token carrying `scopes`
+    pub fn exchange(&self, api_key: &str, service: &str, scopes: &[&str]) -> std::result::Result<Token, AuthError> {
+        self.issuer.issue(&self.keys, api_key, service, scopes)
+    }
+
+    /// Check the value of an `authorization` header
+    pub fn authenticate(&self, authorization: Option<&str>) -> std::result::Result<Claims, AuthError> {
+        let header = authorization.ok_or_else(|| AuthError::InvalidToken("missing bearer token".to_string()))?;
+        let token = header
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| AuthError::InvalidToken("expected a bearer token".to_string()))?;
+        self.verifier.verify(token.trim())
+    }
+
+    /// Authenticate a connection by its client certificate, or by its token
+    /// if it presented none
+    ///
+    /// `peer_cert` is the DER certificate the TLS handshake verified;
+    /// `remote` connections without one are refused if the configuration
+    /// requires certificates for remote clients.
+    pub fn authenticate_client(
+        &self,
+        peer_cert: Option<&[u8]>,
+        remote: bool,
+        authorization: Option<&str>,
+    ) -> std::result::Result<Claims, AuthError> {
+        let now = Utc::now().timestamp();
+        match self.certs.authorize(peer_cert, remote, now)? {
+            Some(identity) => Ok(Claims {
+                iss: "rastosd".to_string(),
+                sub: identity.name,
+                iat: now,
+                exp: now,
+                jti: identity.fingerprint,
+                scopes: identity.scopes,
+            }),
+            None => self.authenticate(authorization),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ApiKey;
+
+    #[test]
+    fn test_authenticate() {
+        let keys = ApiKeyManager::new();
+        keys.add_key(ApiKey {
+            key: "gui-key".to_string(),
+            service: "gui".to_string(),
+            description: None,
+            expires_at: None,
+        })
+        .unwrap();
+        let issuer = TokenIssuer::generate().with_service_scopes("gui", &[scopes::CONTAINER_READ]);
+        let auth = Authenticator::new(keys, issuer);
+
+        assert!(matches!(
+            auth.exchange("gui-key", "gui", &[scopes::PACKAGE_WRITE]),
+            Err(AuthError::InsufficientScope(_))
+        ));
+        assert!(auth.exchange("wrong-key", "gui", &[scopes::CONTAINER_READ]).is_err());
+
+        let token = auth.exchange("gui-key", "gui", &[scopes::CONTAINER_READ]).unwrap();
+        let claims = auth.authenticate(Some(&format!("Bearer {}", token.token))).unwrap();
+        assert!(claims.require_scope(scopes::CONTAINER_READ).is_ok());
+        assert!(claims.require_scope(scopes::CONTAINER_WRITE).is_err());
+
+        assert!(auth.authenticate(None).is_err());
+        assert!(auth.authenticate(Some(&token.token)).is_err());
+    }
+}
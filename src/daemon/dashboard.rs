@@ -0,0 +1,58 @@
+//! Web dashboard of the REST listener
+//!
+//! The pages in `dashboard/` are compiled into the daemon and served at
+//! `/ui/`. They hold no data themselves: the dashboard exchanges an API key
+//! for a token at `/v1/auth/token` and reads everything it shows from the
+//! REST API with that token, so a key sees only what its scopes allow.
+
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use rust_embed::RustEmbed;
+
+/// Files of the dashboard
+#[derive(RustEmbed)]
+#[folder = "dashboard/"]
+struct Assets;
+
+/// Only the dashboard's own files may run, and it may not be framed
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; frame-ancestors 'none'";
+
+/// Routes of the dashboard
+pub(super) fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/ui", get(|| async { Redirect::permanent("/ui/") }))
+        .route("/ui/", get(|| async { asset("index.html") }))
+        .route("/ui/*path", get(|Path(path): Path<String>| async move { asset(&path) }))
+}
+
+fn asset(path: &str) -> Response {
+    match Assets::get(path) {
+        Some(file) => (
+            [
+                (header::CONTENT_TYPE, file.metadata.mimetype().to_string()),
+                (header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY.to_string()),
+                (header::CACHE_CONTROL, "no-cache".to_string()),
+            ],
+            file.data,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assets() {
+        for path in ["index.html", "app.js", "style.css"] {
+            assert!(Assets::get(path).is_some(), "{} is not embedded", path);
+        }
+        assert_eq!(asset("index.html").headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(asset("missing.js").status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,291 @@
+//! D-Bus interface for desktop integration
+//!
+//! rastosd owns `org.rastos.Manager1` on the system bus so desktop applets
+//! can show backup and update status and trigger snapshots without a token:
+//! access is controlled by the bus policy shipped in
+//! `dist/dbus/org.rastos.Manager1.conf`, which lets everyone read
+//! properties and members of `wheel` call methods.
+//!
+//! | Object                          | Interface                        |
+//! |---------------------------------|----------------------------------|
+//! | `/org/rastos/Manager1/Snapshots` | `org.rastos.Manager1.Snapshots` |
+//! | `/org/rastos/Manager1/Backups`   | `org.rastos.Manager1.Backups`   |
+//! | `/org/rastos/Manager1/Updates`   | `org.rastos.Manager1.Updates`   |
+//!
+//! Every change made through the service is announced with
+//! `PropertiesChanged`.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use zbus::object_server::InterfaceRef;
+use zbus::{connection, fdo, interface, Connection, ObjectServer, SignalContext};
+
+use crate::backup::LAST_BACKUP_STAMP;
+use crate::system::update::{UpdateConfig, UpdateOptions, UpdateStatus, Updater};
+
+use super::ops::Operations;
+use super::{DaemonError, Result};
+
+/// Well-known name of the service
+pub const BUS_NAME: &str = "org.rastos.Manager1";
+
+/// Object path of the snapshots object
+pub const SNAPSHOTS_PATH: &str = "/org/rastos/Manager1/Snapshots";
+
+/// Object path of the backups object
+pub const BACKUPS_PATH: &str = "/org/rastos/Manager1/Backups";
+
+/// Object path of the updates object
+pub const UPDATES_PATH: &str = "/org/rastos/Manager1/Updates";
+
+/// Publish the service on the system bus
+///
+/// The service stays up for as long as the returned connection lives.
+pub(super) async fn serve(ops: Arc<Operations>, update: UpdateConfig) -> Result<Connection> {
+    let connection = connection::Builder::system()?
+        .name(BUS_NAME)?
+        .serve_at(SNAPSHOTS_PATH, Snapshots { ops: Arc::clone(&ops) })?
+        .serve_at(
+            BACKUPS_PATH,
+            Backups {
+                ops,
+                state: Mutex::new(BackupState::default()),
+            },
+        )?
+        .serve_at(UPDATES_PATH, Updates { updater: Updater::new(update) })?
+        .build()
+        .await?;
+    info!("Serving {} on the system bus", BUS_NAME);
+    Ok(connection)
+}
+
+impl From<DaemonError> for fdo::Error {
+    fn from(e: DaemonError) -> Self {
+        match e {
+            DaemonError::NotFound(_) => fdo::Error::FileNotFound(e.to_string()),
+            DaemonError::Invalid(_) => fdo::Error::InvalidArgs(e.to_string()),
+            DaemonError::Unavailable(_) => fdo::Error::NotSupported(e.to_string()),
+            _ => fdo::Error::Failed(e.to_string()),
+        }
+    }
+}
+
+/// `org.rastos.Manager1.Snapshots`
+struct Snapshots {
+    ops: Arc<Operations>,
+}
+
+#[interface(name = "org.rastos.Manager1.Snapshots")]
+impl Snapshots {
+    /// Snapshot `source` under `name`, returning the snapshot's path
+    async fn create_snapshot(
+        &self,
+        source: &str,
+        name: &str,
+        writable: bool,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<String> {
+        let snapshot = self.ops.create_snapshot(Path::new(source), name, writable)?;
+        self.snapshots_changed(&ctxt).await?;
+        Ok(snapshot.path.to_string_lossy().into_owned())
+    }
+
+    /// Delete the snapshot `name`
+    async fn delete_snapshot(&self, name: &str, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        self.ops.delete_snapshot(name)?;
+        self.snapshots_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    /// Names of the snapshots
+    #[zbus(property)]
+    async fn snapshots(&self) -> fdo::Result<Vec<String>> {
+        Ok(self.ops.snapshots()?.into_iter().map(|s| s.name).collect())
+    }
+}
+
+/// Progress of the backup started over D-Bus
+#[derive(Debug, Default)]
+struct BackupState {
+    running: bool,
+    last_error: String,
+}
+
+/// `org.rastos.Manager1.Backups`
+struct Backups {
+    ops: Arc<Operations>,
+    state: Mutex<BackupState>,
+}
+
+#[interface(name = "org.rastos.Manager1.Backups")]
+impl Backups {
+    /// Start backing up `subvolume`; `name` may be empty
+    ///
+    /// Returns at once. `Running` turns false and `LastBackup` or
+    /// `LastError` change when the backup is done.
+    async fn create_backup(
+        &self,
+        subvolume: String,
+        name: String,
+        #[zbus(object_server)] server: &ObjectServer,
+    ) -> fdo::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.running {
+                return Err(fdo::Error::Failed("a backup is already running".to_string()));
+            }
+            state.running = true;
+        }
+        let iface: InterfaceRef<Backups> = server.interface(BACKUPS_PATH).await?;
+        self.running_changed(iface.signal_context()).await?;
+
+        let ops = Arc::clone(&self.ops);
+        tokio::spawn(async move {
+            let name = (!name.is_empty()).then_some(name);
+            let result = ops.create_backup(Path::new(&subvolume), name.as_deref(), None, None).await;
+            let backups = iface.get().await;
+            {
+                let mut state = backups.state.lock().unwrap();
+                state.running = false;
+                state.last_error = match result {
+                    Ok(_) => String::new(),
+                    Err(e) => e.to_string(),
+                };
+            }
+            let ctxt = iface.signal_context();
+            let announced = async {
+                backups.running_changed(ctxt).await?;
+                backups.last_backup_changed(ctxt).await?;
+                backups.last_error_changed(ctxt).await
+            };
+            if let Err(e) = announced.await {
+                warn!("Failed to announce the backup result: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Backups as (ID, name, subvolume, creation time, size in bytes)
+    async fn list_backups(&self) -> fdo::Result<Vec<(String, String, String, String, u64)>> {
+        Ok(self
+            .ops
+            .backups()
+            .await?
+            .into_iter()
+            .map(|b| {
+                let subvolume = b.subvolume_path.to_string_lossy().into_owned();
+                (b.id, b.name, subvolume, b.created_at.to_rfc3339(), b.size)
+            })
+            .collect())
+    }
+
+    /// Time of the last successful backup in RFC 3339, empty if there was none
+    #[zbus(property)]
+    async fn last_backup(&self) -> String {
+        fs::read_to_string(LAST_BACKUP_STAMP)
+            .map(|stamp| stamp.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Whether a backup started over D-Bus is running
+    #[zbus(property)]
+    async fn running(&self) -> bool {
+        self.state.lock().unwrap().running
+    }
+
+    /// Why the last backup started over D-Bus failed, empty if it did not
+    #[zbus(property)]
+    async fn last_error(&self) -> String {
+        self.state.lock().unwrap().last_error.clone()
+    }
+}
+
+/// `org.rastos.Manager1.Updates`
+struct Updates {
+    updater: Updater,
+}
+
+impl Updates {
+    async fn status(&self) -> fdo::Result<UpdateStatus> {
+        let updater = self.updater.clone();
+        blocking(move || updater.status()).await
+    }
+
+    async fn announce(&self, ctxt: &SignalContext<'_>) -> zbus::Result<()> {
+        self.default_entry_changed(ctxt).await?;
+        self.deployments_changed(ctxt).await?;
+        self.update_pending_changed(ctxt).await
+    }
+}
+
+#[interface(name = "org.rastos.Manager1.Updates")]
+impl Updates {
+    /// Upgrade into a new deployment that boots next, returning its ID
+    async fn stage(&self, offline: bool, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<String> {
+        let updater = self.updater.clone();
+        let deployment = blocking(move || updater.stage(&UpdateOptions::default().offline(offline))).await?;
+        self.announce(&ctxt).await?;
+        Ok(deployment.id)
+    }
+
+    /// Make the booted deployment the default, returning its ID
+    async fn commit(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<String> {
+        let updater = self.updater.clone();
+        let deployment = blocking(move || updater.commit()).await?;
+        self.announce(&ctxt).await?;
+        Ok(deployment.id)
+    }
+
+    /// Boot the previous root by default, returning its boot entry
+    async fn rollback(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<String> {
+        let updater = self.updater.clone();
+        let entry = blocking(move || updater.rollback()).await?;
+        self.announce(&ctxt).await?;
+        Ok(entry)
+    }
+
+    /// Subvolume the running system was booted from
+    #[zbus(property)]
+    async fn booted(&self) -> fdo::Result<String> {
+        Ok(self.status().await?.booted)
+    }
+
+    /// Default boot entry, empty if unknown
+    #[zbus(property)]
+    async fn default_entry(&self) -> fdo::Result<String> {
+        Ok(self.status().await?.default_entry.unwrap_or_default())
+    }
+
+    /// Deployments as (ID, subvolume, kernel release, creation time), oldest first
+    #[zbus(property)]
+    async fn deployments(&self) -> fdo::Result<Vec<(String, String, String, String)>> {
+        Ok(self
+            .status()
+            .await?
+            .deployments
+            .into_iter()
+            .map(|d| (d.id, d.subvolume, d.kernel, d.created_at.to_rfc3339()))
+            .collect())
+    }
+
+    /// Whether a staged deployment waits for a reboot
+    #[zbus(property)]
+    async fn update_pending(&self) -> fdo::Result<bool> {
+        Ok(self.status().await?.pending())
+    }
+}
+
+/// Run an update step on the blocking thread pool
+async fn blocking<T, F>(f: F) -> std::result::Result<T, fdo::Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> crate::system::update::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| fdo::Error::Failed(e.to_string()))?
+        .map_err(|e| fdo::Error::Failed(e.to_string()))
+}
@@ -0,0 +1,358 @@
+//! gRPC transport of the daemon API
+//!
+//! The services generated from `proto/rastos/v1/daemon.proto`, served on a
+//! unix socket and, for remote management, on a TCP listener with mutual
+//! TLS. All services but `Auth` sit behind an interceptor that checks the
+//! client certificate or bearer token and leaves its [`Claims`] in the
+//! request extensions for the per-method scope check.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::server::Router;
+use tonic::transport::{Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+use crate::auth::{AuthError, Claims};
+use crate::backup::{Backup, BackupError};
+use crate::jobs::JobError;
+use crate::oci::ContainerError;
+use crate::package::TransactionRecord;
+
+use super::auth::{scopes, Authenticator};
+use super::ops::{ContainerInfo, Operations, SnapshotInfo};
+use super::{DaemonError, Result};
+
+#[allow(missing_docs, clippy::derive_partial_eq_without_eq)]
+pub(crate) mod pb {
+    tonic::include_proto!("rastos.v1");
+}
+
+use pb::auth_server::AuthServer;
+use pb::backups_server::BackupsServer;
+use pb::containers_server::ContainersServer;
+use pb::packages_server::PackagesServer;
+use pb::snapshots_server::SnapshotsServer;
+
+/// Serve the API on `listener` until `shutdown` resolves
+pub(super) async fn serve<F>(
+    listener: UnixListener,
+    ops: Arc<Operations>,
+    auth: Arc<Authenticator>,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    services(Server::builder(), ops, auth, false)
+        .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Serve the API to remote clients on `addr` until `shutdown` resolves
+///
+/// `tls` must require client certificates; pinned certificates are
+/// accepted in place of a token.
+pub(super) async fn serve_tls<F>(
+    addr: SocketAddr,
+    tls: ServerTlsConfig,
+    ops: Arc<Operations>,
+    auth: Arc<Authenticator>,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    services(Server::builder().tls_config(tls)?, ops, auth, true)
+        .serve_with_shutdown(addr, shutdown)
+        .await?;
+    Ok(())
+}
+
+// tonic fixes the interceptor's error type to `Status`
+#[allow(clippy::result_large_err)]
+fn services(mut server: Server, ops: Arc<Operations>, auth: Arc<Authenticator>, remote: bool) -> Router {
+    let api = Api { ops };
+    let authenticate = {
+        let auth = Arc::clone(&auth);
+        move |mut request: Request<()>| {
+            let certs = request.peer_certs();
+            let peer_cert = certs.as_deref().and_then(|certs| certs.first()).map(|cert| cert.as_ref());
+            let header = request.metadata().get("authorization").and_then(|v| v.to_str().ok());
+            let claims = auth
+                .authenticate_client(peer_cert, remote, header)
+                .map_err(|e| Status::unauthenticated(e.to_string()))?;
+            request.extensions_mut().insert(claims);
+            Ok(request)
+        }
+    };
+
+    server
+        .add_service(AuthServer::new(AuthApi { auth }))
+        .add_service(ContainersServer::with_interceptor(api.clone(), authenticate.clone()))
+        .add_service(SnapshotsServer::with_interceptor(api.clone(), authenticate.clone()))
+        .add_service(BackupsServer::with_interceptor(api.clone(), authenticate.clone()))
+        .add_service(PackagesServer::with_interceptor(api, authenticate))
+}
+
+impl From<DaemonError> for Status {
+    fn from(e: DaemonError) -> Self {
+        let message = e.to_string();
+        match e {
+            DaemonError::Auth(AuthError::InsufficientScope(_)) => Status::permission_denied(message),
+            DaemonError::Auth(_) => Status::unauthenticated(message),
+            DaemonError::NotFound(_)
+            | DaemonError::Container(ContainerError::NotFound(_))
+            | DaemonError::Job(JobError::NotFound(_)) => Status::not_found(message),
+            DaemonError::Job(JobError::Ended { .. })
+            | DaemonError::Container(ContainerError::InvalidTransition(_))
+            | DaemonError::Backup(BackupError::InvalidTransition(_)) => Status::failed_precondition(message),
+            DaemonError::Container(ContainerError::AlreadyExists(_)) => Status::already_exists(message),
+            DaemonError::Invalid(_) => Status::invalid_argument(message),
+            DaemonError::Unavailable(_) => Status::failed_precondition(message),
+            _ => Status::internal(message),
+        }
+    }
+}
+
+/// Fail unless the request's token grants `scope`
+fn authorize<T>(request: &Request<T>, scope: &str) -> Result<()> {
+    let claims = request
+        .extensions()
+        .get::<Claims>()
+        .ok_or_else(|| AuthError::InvalidToken("missing bearer token".into()))?;
+    Ok(claims.require_scope(scope)?)
+}
+
+/// Token exchange
+struct AuthApi {
+    auth: Arc<Authenticator>,
+}
+
+#[tonic::async_trait]
+impl pb::auth_server::Auth for AuthApi {
+    async fn exchange_token(
+        &self,
+        request: Request<pb::ExchangeTokenRequest>,
+    ) -> std::result::Result<Response<pb::ExchangeTokenResponse>, Status> {
+        let request = request.into_inner();
+        let scopes: Vec<&str> = request.scopes.iter().map(String::as_str).collect();
+        let token = self
+            .auth
+            .exchange(&request.api_key, &request.service, &scopes)
+            .map_err(DaemonError::from)?;
+        info!("Issued a token for {} with scopes {:?}", request.service, scopes);
+        Ok(Response::new(pb::ExchangeTokenResponse {
+            token: token.token,
+            expires_at: token.expires_at,
+        }))
+    }
+}
+
+/// Containers, snapshots, backups and packages
+#[derive(Clone)]
+struct Api {
+    ops: Arc<Operations>,
+}
+
+type ApiResult<T> = std::result::Result<Response<T>, Status>;
+
+#[tonic::async_trait]
+impl pb::containers_server::Containers for Api {
+    async fn create(&self, request: Request<pb::CreateContainerRequest>) -> ApiResult<pb::ContainerInfo> {
+        authorize(&request, scopes::CONTAINER_WRITE)?;
+        let request = request.into_inner();
+        let rootfs = if request.rootfs.is_empty() { "rootfs" } else { &request.rootfs };
+        let info = self.ops.create_container(
+            &request.id,
+            Path::new(rootfs),
+            request.args,
+            request.mac_profile.as_deref(),
+        )?;
+        Ok(Response::new(info.into()))
+    }
+
+    async fn start(&self, request: Request<pb::ContainerRequest>) -> ApiResult<pb::ContainerInfo> {
+        authorize(&request, scopes::CONTAINER_WRITE)?;
+        Ok(Response::new(self.ops.start_container(&request.get_ref().id)?.into()))
+    }
+
+    async fn stop(&self, request: Request<pb::ContainerRequest>) -> ApiResult<pb::ContainerInfo> {
+        authorize(&request, scopes::CONTAINER_WRITE)?;
+        Ok(Response::new(self.ops.stop_container(&request.get_ref().id)?.into()))
+    }
+
+    async fn delete(&self, request: Request<pb::ContainerRequest>) -> ApiResult<pb::Empty> {
+        authorize(&request, scopes::CONTAINER_WRITE)?;
+        self.ops.delete_container(&request.get_ref().id)?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn get(&self, request: Request<pb::ContainerRequest>) -> ApiResult<pb::ContainerInfo> {
+        authorize(&request, scopes::CONTAINER_READ)?;
+        Ok(Response::new(self.ops.container(&request.get_ref().id)?.into()))
+    }
+
+    async fn list(&self, request: Request<pb::Empty>) -> ApiResult<pb::ContainerList> {
+        authorize(&request, scopes::CONTAINER_READ)?;
+        Ok(Response::new(pb::ContainerList {
+            containers: self.ops.containers().into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl pb::snapshots_server::Snapshots for Api {
+    async fn create(&self, request: Request<pb::CreateSnapshotRequest>) -> ApiResult<pb::SnapshotInfo> {
+        authorize(&request, scopes::SNAPSHOT_WRITE)?;
+        let request = request.into_inner();
+        let info = self
+            .ops
+            .create_snapshot(Path::new(&request.source), &request.name, request.writable)?;
+        Ok(Response::new(info.into()))
+    }
+
+    async fn delete(&self, request: Request<pb::SnapshotRequest>) -> ApiResult<pb::Empty> {
+        authorize(&request, scopes::SNAPSHOT_WRITE)?;
+        self.ops.delete_snapshot(&request.get_ref().name)?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn list(&self, request: Request<pb::Empty>) -> ApiResult<pb::SnapshotList> {
+        authorize(&request, scopes::SNAPSHOT_READ)?;
+        Ok(Response::new(pb::SnapshotList {
+            snapshots: self.ops.snapshots()?.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl pb::backups_server::Backups for Api {
+    async fn create(&self, request: Request<pb::CreateBackupRequest>) -> ApiResult<pb::BackupInfo> {
+        authorize(&request, scopes::BACKUP_WRITE)?;
+        let request = request.into_inner();
+        let backup = self
+            .ops
+            .create_backup(
+                Path::new(&request.subvolume),
+                request.name.as_deref(),
+                request.description.as_deref(),
+                request.parent_id.as_deref(),
+            )
+            .await?;
+        Ok(Response::new(backup.into()))
+    }
+
+    async fn restore(&self, request: Request<pb::RestoreBackupRequest>) -> ApiResult<pb::Empty> {
+        authorize(&request, scopes::BACKUP_WRITE)?;
+        let request = request.into_inner();
+        self.ops.restore_backup(&request.id, Path::new(&request.target)).await?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn delete(&self, request: Request<pb::BackupRequest>) -> ApiResult<pb::Empty> {
+        authorize(&request, scopes::BACKUP_WRITE)?;
+        self.ops.delete_backup(&request.get_ref().id).await?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn verify(&self, request: Request<pb::BackupRequest>) -> ApiResult<pb::VerifyBackupResponse> {
+        authorize(&request, scopes::BACKUP_READ)?;
+        let valid = self.ops.verify_backup(&request.get_ref().id).await?;
+        Ok(Response::new(pb::VerifyBackupResponse { valid }))
+    }
+
+    async fn get(&self, request: Request<pb::BackupRequest>) -> ApiResult<pb::BackupInfo> {
+        authorize(&request, scopes::BACKUP_READ)?;
+        Ok(Response::new(self.ops.backup(&request.get_ref().id).await?.into()))
+    }
+
+    async fn list(&self, request: Request<pb::Empty>) -> ApiResult<pb::BackupList> {
+        authorize(&request, scopes::BACKUP_READ)?;
+        Ok(Response::new(pb::BackupList {
+            backups: self.ops.backups().await?.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl pb::packages_server::Packages for Api {
+    async fn install(&self, request: Request<pb::InstallPackagesRequest>) -> ApiResult<pb::Empty> {
+        authorize(&request, scopes::PACKAGE_WRITE)?;
+        self.ops.install_packages(request.into_inner().packages).await?;
+        Ok(Response::new(pb::Empty {}))
+    }
+
+    async fn undo(&self, request: Request<pb::UndoRequest>) -> ApiResult<pb::Transaction> {
+        authorize(&request, scopes::PACKAGE_WRITE)?;
+        Ok(Response::new(self.ops.undo_transaction(&request.get_ref().id).await?.into()))
+    }
+
+    async fn history(&self, request: Request<pb::Empty>) -> ApiResult<pb::TransactionList> {
+        authorize(&request, scopes::PACKAGE_READ)?;
+        Ok(Response::new(pb::TransactionList {
+            transactions: self.ops.package_history().await?.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+impl From<ContainerInfo> for pb::ContainerInfo {
+    fn from(info: ContainerInfo) -> Self {
+        Self {
+            id: info.id,
+            bundle: info.bundle.to_string_lossy().into_owned(),
+            state: info.state.to_string(),
+        }
+    }
+}
+
+impl From<SnapshotInfo> for pb::SnapshotInfo {
+    fn from(info: SnapshotInfo) -> Self {
+        Self {
+            name: info.name,
+            path: info.path.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl From<Backup> for pb::BackupInfo {
+    fn from(backup: Backup) -> Self {
+        Self {
+            id: backup.id,
+            name: backup.name,
+            description: backup.description,
+            subvolume: backup.subvolume_path.to_string_lossy().into_owned(),
+            size: backup.size,
+            created_at: backup.created_at.to_rfc3339(),
+            incremental: backup.is_incremental,
+            parent_id: backup.parent_id,
+        }
+    }
+}
+
+impl From<TransactionRecord> for pb::Transaction {
+    fn from(record: TransactionRecord) -> Self {
+        Self {
+            id: record.id.to_string(),
+            timestamp: record.timestamp.to_rfc3339(),
+            action: record.action,
+            changes: record
+                .changes
+                .into_iter()
+                .map(|change| pb::PackageChange {
+                    name: change.name,
+                    from: change.from,
+                    to: change.to,
+                })
+                .collect(),
+            snapshots: record.snapshots,
+            reverts: record.reverts.map(|id| id.to_string()),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! rastosd, the rastOS management daemon
+//!
+//! Containers, snapshots, backups and packages are managed through a gRPC
+//! API on a unix socket, so graphical tools and remote agents don't need
+//! root or their own copy of the privileged logic. Callers exchange an API
+//! key for a short-lived token with `Auth.ExchangeToken` and send it as a
+//! bearer token with every other call; each method requires one scope, such
+//! as `container:write` (see [`scopes`]).
+//!
+//! When `remote_listen` is configured, the gRPC API is also served over TCP
+//! with mutual TLS, for `rastos --host` on other machines (see
+//! [`crate::remote`]); clients present a certificate from the machine's
+//! client certificate authority that is pinned in the key configuration.
+//!
+//! When `http_listen` is configured, the same operations are also served as
+//! a versioned REST API with an OpenAPI document (see [`openapi`]) for web
+//! dashboards and scripts. Built with the `dashboard` feature, the same
+//! listener serves a web dashboard at `/ui/`, which signs in with an API key
+//! and shows the machine through the REST API. Built with the `dbus`
+//! feature, the daemon also publishes `org.rastos.Manager1` on the system
+//! bus for desktop applets.
+//!
+//! Backups, image pulls and kernel builds can also be submitted as
+//! background [jobs](crate::jobs), which the daemon schedules and runs.
+//!
+//! The operations themselves are in [`Operations`], which knows nothing of
+//! the transport. The gRPC API is defined in `proto/rastos/v1/daemon.proto`.
+
+mod auth;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub(crate) mod grpc;
+mod ops;
+mod rest;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+use crate::auth::{ApiKeyConfig, AuthError, CertificateAuthority};
+use crate::backup::{BackupError, DEFAULT_SNAPSHOT_DIR};
+use crate::jobs::{JobError, JobRunner, JobsConfig};
+use crate::oci::ContainerError;
+use crate::package::PackageError;
+use crate::system::mac::MacError;
+use crate::system::update::{UpdateConfig, UpdateError};
+
+pub use auth::{scopes, Authenticator};
+pub use ops::{ContainerInfo, Operations, SnapshotInfo};
+pub use rest::openapi;
+
+/// Configuration file of the daemon
+pub const CONFIG_PATH: &str = "/etc/rast/rastosd.toml";
+
+/// Socket the API is served on unless configured otherwise
+pub const DEFAULT_SOCKET: &str = "/run/rastos/rastosd.sock";
+
+/// Error type for the daemon
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Configuration file that could not be parsed
+    #[error("Invalid configuration: {0}")]
+    Config(#[from] toml::de::Error),
+
+    /// Authentication or authorization failure
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+
+    /// API key configuration error
+    #[error("API key configuration error: {0}")]
+    AuthConfig(#[from] crate::auth::ConfigError),
+
+    /// Container error
+    #[error("{0}")]
+    Container(#[from] ContainerError),
+
+    /// Snapshot error
+    #[error("Snapshot error: {0}")]
+    Snapshot(#[from] crate::fs::BtrfsError),
+
+    /// Transaction journal error
+    #[error("{0}")]
+    Transaction(#[from] crate::transaction::TransactionError),
+
+    /// Backup error
+    #[error("Backup error: {0}")]
+    Backup(#[from] BackupError),
+
+    /// Package error
+    #[error("Package error: {0}")]
+    Package(#[from] PackageError),
+
+    /// SELinux or AppArmor error
+    #[error("{0}")]
+    Mac(#[from] MacError),
+
+    /// Background job error
+    #[error("{0}")]
+    Job(#[from] JobError),
+
+    /// System update error
+    #[error("Update error: {0}")]
+    Update(#[from] UpdateError),
+
+    /// Unknown container, snapshot or backup
+    #[error("{0} not found")]
+    NotFound(String),
+
+    /// Bad request
+    #[error("{0}")]
+    Invalid(String),
+
+    /// Operation the daemon is not configured for
+    #[error("{0}")]
+    Unavailable(String),
+
+    /// The server failed
+    #[error("Transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+
+    /// The D-Bus service failed
+    #[cfg(feature = "dbus")]
+    #[error("D-Bus error: {0}")]
+    DBus(#[from] zbus::Error),
+}
+
+/// Result type for the daemon
+pub type Result<T> = std::result::Result<T, DaemonError>;
+
+/// Daemon configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Unix socket the API is served on
+    pub socket: PathBuf,
+
+    /// Permissions of the socket
+    pub socket_mode: u32,
+
+    /// Address the REST API is served on; not served if unset
+    ///
+    /// The REST API is plain HTTP: listen on a loopback address, or behind
+    /// a TLS-terminating proxy.
+    pub http_listen: Option<SocketAddr>,
+
+    /// Address the gRPC API is served on with mutual TLS; not served if unset
+    pub remote_listen: Option<SocketAddr>,
+
+    /// Server certificate of the mutual TLS listener (PEM)
+    pub tls_cert: PathBuf,
+
+    /// Private key of the server certificate (PEM)
+    pub tls_key: PathBuf,
+
+    /// Where container bundles are kept
+    pub containers_dir: PathBuf,
+
+    /// Where snapshots are created
+    pub snapshot_dir: PathBuf,
+
+    /// Backup configuration; backup calls fail while it does not exist
+    pub backup_config: PathBuf,
+
+    /// System root whose packages are managed
+    pub package_root: PathBuf,
+
+    /// API key configuration; the layered default configuration if unset
+    pub keys: Option<PathBuf>,
+
+    /// Lifetime of issued tokens, in seconds
+    pub token_ttl: u64,
+
+    /// Publish the D-Bus service; needs the `dbus` feature
+    pub dbus: bool,
+
+    /// Updates started over D-Bus and the update status of the APIs
+    pub update: UpdateConfig,
+
+    /// Serve the web dashboard at `/ui/` of `http_listen`; needs the
+    /// `dashboard` feature
+    pub dashboard: bool,
+
+    /// Job store and concurrency limits of background jobs
+    pub jobs: JobsConfig,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from(DEFAULT_SOCKET),
+            socket_mode: 0o660,
+            http_listen: None,
+            remote_listen: None,
+            tls_cert: PathBuf::from("/etc/rast/auth/server.crt"),
+            tls_key: PathBuf::from("/etc/rast/auth/server.key"),
+            containers_dir: PathBuf::from("/var/lib/rast/containers"),
+            snapshot_dir: PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+            backup_config: PathBuf::from("/etc/rast/backup.toml"),
+            package_root: PathBuf::from("/"),
+            keys: None,
+            token_ttl: 15 * 60,
+            dbus: true,
+            update: UpdateConfig::default(),
+            dashboard: true,
+            jobs: JobsConfig::default(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Load the configuration from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Load the configuration from `path`, or the defaults if it does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        match Self::from_file(&path) {
+            Err(DaemonError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+}
+
+/// The management daemon
+pub struct Daemon {
+    config: DaemonConfig,
+    ops: Arc<Operations>,
+    auth: Arc<Authenticator>,
+    /// Directory of the certificate authority remote clients' certificates come from
+    ca_dir: PathBuf,
+}
+
+impl Daemon {
+    /// Set up the daemon, loading API keys and existing containers
+    pub fn new(config: DaemonConfig) -> Result<Self> {
+        let keys = match &config.keys {
+            Some(path) => ApiKeyConfig::from_file(path)?,
+            None => ApiKeyConfig::load()?,
+        };
+        let auth = Authenticator::from_config(&keys, Duration::from_secs(config.token_ttl))?;
+        let ops = Operations::new(&config)?;
+        Ok(Self {
+            config,
+            ops: Arc::new(ops),
+            auth: Arc::new(auth),
+            ca_dir: keys.mtls.ca_dir.clone(),
+        })
+    }
+
+    /// The operations behind the API
+    pub fn operations(&self) -> Arc<Operations> {
+        Arc::clone(&self.ops)
+    }
+
+    /// Whether the REST listener serves the dashboard
+    fn dashboard(&self) -> bool {
+        cfg!(feature = "dashboard") && self.config.dashboard
+    }
+
+    /// Serve the APIs until SIGINT or SIGTERM
+    pub async fn serve(self) -> Result<()> {
+        let listener = bind(&self.config.socket, self.config.socket_mode)?;
+        info!("Listening on {}", self.config.socket.display());
+        let http = match self.config.http_listen {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                info!("Serving the REST API on http://{}", addr);
+                if self.dashboard() {
+                    info!("Serving the dashboard on http://{}/ui/", addr);
+                }
+                Some(listener)
+            }
+            None => None,
+        };
+
+        let remote = match self.config.remote_listen {
+            Some(addr) => {
+                info!("Serving the gRPC API with mutual TLS on {}", addr);
+                Some((addr, self.server_tls()?))
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "dbus")]
+        let _bus = if self.config.dbus {
+            Some(dbus::serve(Arc::clone(&self.ops), self.config.update.clone()).await?)
+        } else {
+            None
+        };
+
+        let stop = CancellationToken::new();
+        tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                shutdown().await;
+                stop.cancel();
+            }
+        });
+        let grpc = grpc::serve(listener, Arc::clone(&self.ops), Arc::clone(&self.auth), stop.clone().cancelled_owned());
+        let jobs = async {
+            let runner: Arc<dyn JobRunner> = self.ops.clone();
+            self.ops.scheduler().run(runner, stop.clone()).await.map_err(DaemonError::from)
+        };
+        let rest = async {
+            match http {
+                Some(listener) => {
+                    let (ops, auth) = (Arc::clone(&self.ops), Arc::clone(&self.auth));
+                    rest::serve(listener, ops, auth, self.dashboard(), stop.clone().cancelled_owned()).await
+                }
+                None => Ok(()),
+            }
+        };
+        let remote = async {
+            match remote {
+                Some((addr, tls)) => {
+                    let (ops, auth) = (Arc::clone(&self.ops), Arc::clone(&self.auth));
+                    grpc::serve_tls(addr, tls, ops, auth, stop.clone().cancelled_owned()).await
+                }
+                None => Ok(()),
+            }
+        };
+        let result = tokio::try_join!(grpc, remote, jobs, rest).map(|_| ());
+        stop.cancel();
+        if let Err(e) = fs::remove_file(&self.config.socket) {
+            warn!("Failed to remove {}: {}", self.config.socket.display(), e);
+        }
+        result
+    }
+
+    /// TLS settings of the remote listener, requiring client certificates
+    fn server_tls(&self) -> Result<ServerTlsConfig> {
+        let identity = Identity::from_pem(fs::read(&self.config.tls_cert)?, fs::read(&self.config.tls_key)?);
+        let client_ca = fs::read(CertificateAuthority::open(&self.ca_dir)?.cert_path())?;
+        Ok(ServerTlsConfig::new()
+            .identity(identity)
+            .client_ca_root(Certificate::from_pem(client_ca)))
+    }
+}
+
+/// Bind the socket, replacing one left behind by an earlier run
+fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(DaemonError::Invalid(format!("{} exists and is not a socket", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown() {
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            warn!("Cannot handle SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+    info!("Shutting down");
+}
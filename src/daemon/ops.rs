@@ -0,0 +1,523 @@
+//! Operations served by the daemon
+//!
+//! Everything here is independent of the API transport: the gRPC and REST
+//! services check the caller's scope and then call one of these methods. Blocking
+//! package transactions run on the blocking thread pool, one at a time.
+//! Background jobs are run by [`Operations`] as their [`JobRunner`].
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::sync::{broadcast, OnceCell};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::backup::config::BackupConfig;
+use crate::backup::{Backup, BackupManager};
+use crate::jobs::{Job, JobContext, JobRunner, JobSpec, Priority, Scheduler};
+use crate::kernel::KernelBuilder;
+use crate::oci::OciImage;
+use crate::oci::{Container, ContainerBuilder, ContainerOp, LinuxBuilder, ProcessBuilder};
+use crate::package::{PackageError, PackageList, PackageManager, PackageSpec, TransactionRecord};
+use crate::progress::{EventForwarder, Progress, ProgressEvent};
+use crate::snapshot::transaction::SnapshotSet;
+use crate::system::mac::Mac;
+use crate::system::update::{UpdateStatus, Updater};
+use crate::transaction::{BoxError, Executor};
+
+use super::{DaemonConfig, DaemonError, Result};
+
+/// A container known to the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ContainerInfo {
+    /// Container ID
+    pub id: String,
+    /// Bundle directory
+    #[schema(value_type = String)]
+    pub bundle: PathBuf,
+    /// `created`, `running`, `stopped`, `paused` or `error`
+    #[schema(value_type = String)]
+    pub state: &'static str,
+}
+
+/// A snapshot in the daemon's snapshot directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SnapshotInfo {
+    /// Name of the snapshot
+    pub name: String,
+    /// Path of the snapshot
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+}
+
+/// Privileged operations behind the daemon API
+pub struct Operations {
+    containers_dir: PathBuf,
+    snapshot_dir: PathBuf,
+    backup_config: PathBuf,
+    package_root: PathBuf,
+    containers: Mutex<BTreeMap<String, Container>>,
+    backups: OnceCell<BackupManager>,
+    /// Progress of running operations, forwarded to API clients
+    events: EventForwarder,
+    /// Background jobs
+    jobs: Arc<Scheduler>,
+    /// Held while a package transaction runs
+    packages: tokio::sync::Mutex<()>,
+    updater: Updater,
+}
+
+impl Operations {
+    /// Set up the operations of `config`, loading the bundles in its containers directory
+    pub fn new(config: &DaemonConfig) -> Result<Self> {
+        let events = EventForwarder::default();
+        let jobs = Scheduler::new(config.jobs.clone()).with_progress(Arc::new(events.clone()));
+        let ops = Self {
+            containers_dir: config.containers_dir.clone(),
+            snapshot_dir: config.snapshot_dir.clone(),
+            backup_config: config.backup_config.clone(),
+            package_root: config.package_root.clone(),
+            containers: Mutex::new(BTreeMap::new()),
+            backups: OnceCell::new(),
+            events,
+            jobs: Arc::new(jobs),
+            packages: tokio::sync::Mutex::new(()),
+            updater: Updater::new(config.update.clone()),
+        };
+        ops.load_containers()?;
+        Ok(ops)
+    }
+
+    /// Receive the progress of operations started from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.events.subscribe()
+    }
+
+    fn load_containers(&self) -> Result<()> {
+        let entries = match fs::read_dir(&self.containers_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut containers = self.containers.lock().unwrap();
+        for entry in entries {
+            let bundle = entry?.path();
+            if !bundle.join("config.json").is_file() {
+                continue;
+            }
+            let id = bundle.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            match Container::new(&id, &bundle) {
+                Ok(container) => {
+                    containers.insert(id, container);
+                }
+                Err(e) => warn!("Skipping the bundle {}: {}", bundle.display(), e),
+            }
+        }
+        info!("Loaded {} container(s)", containers.len());
+        Ok(())
+    }
+
+    /// Write the bundle of a new container
+    ///
+    /// `rootfs` is relative to the bundle; `args` default to `/bin/sh`.
+    pub fn create_container(
+        &self,
+        id: &str,
+        rootfs: &Path,
+        args: Vec<String>,
+        mac_profile: Option<&str>,
+    ) -> Result<ContainerInfo> {
+        validate_name("container", id)?;
+        let mut containers = self.containers.lock().unwrap();
+        if containers.contains_key(id) {
+            return Err(DaemonError::Invalid(format!("container {} already exists", id)));
+        }
+
+        let args = if args.is_empty() { vec!["/bin/sh".to_string()] } else { args };
+        let mut builder = ContainerBuilder::new(id)
+            .root(rootfs)
+            .process(ProcessBuilder::default().cwd("/").args(args))
+            .linux(LinuxBuilder::default());
+        match Mac::new().container_label(mac_profile)? {
+            Some(label) => builder = builder.mac_label(label),
+            None if mac_profile.is_some() => {
+                return Err(DaemonError::Unavailable("neither SELinux nor AppArmor is active".to_string()))
+            }
+            None => {}
+        }
+        let spec = builder.build()?;
+
+        let bundle = self.containers_dir.join(id);
+        fs::create_dir_all(&bundle)?;
+        spec.save(bundle.join("config.json")).map_err(crate::oci::ContainerError::from)?;
+        let container = Container::new(id, &bundle)?;
+        info!("Created container {}", id);
+        let info = container_info(id, &bundle, &container);
+        containers.insert(id.to_string(), container);
+        Ok(info)
+    }
+
+    /// Start a container
+    pub fn start_container(&self, id: &str) -> Result<ContainerInfo> {
+        self.with_container(id, |container| Ok(container.start()?))
+    }
+
+    /// Stop a container
+    pub fn stop_container(&self, id: &str) -> Result<ContainerInfo> {
+        self.with_container(id, |container| Ok(container.stop()?))
+    }
+
+    /// Delete a stopped container and its bundle
+    pub fn delete_container(&self, id: &str) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+        let container = containers
+            .get(id)
+            .ok_or_else(|| DaemonError::NotFound(format!("container {}", id)))?;
+        container.check(ContainerOp::Delete)?;
+        fs::remove_dir_all(self.containers_dir.join(id))?;
+        containers.remove(id);
+        info!("Deleted container {}", id);
+        Ok(())
+    }
+
+    /// A container
+    pub fn container(&self, id: &str) -> Result<ContainerInfo> {
+        self.with_container(id, |_| Ok(()))
+    }
+
+    /// All containers
+    pub fn containers(&self) -> Vec<ContainerInfo> {
+        let containers = self.containers.lock().unwrap();
+        containers
+            .iter()
+            .map(|(id, container)| container_info(id, &self.containers_dir.join(id), container))
+            .collect()
+    }
+
+    fn with_container<F>(&self, id: &str, f: F) -> Result<ContainerInfo>
+    where
+        F: FnOnce(&mut Container) -> Result<()>,
+    {
+        let mut containers = self.containers.lock().unwrap();
+        let container = containers
+            .get_mut(id)
+            .ok_or_else(|| DaemonError::NotFound(format!("container {}", id)))?;
+        f(container)?;
+        Ok(container_info(id, &self.containers_dir.join(id), container))
+    }
+
+    /// Snapshot `source` into the snapshot directory
+    pub fn create_snapshot(&self, source: &Path, name: &str, writable: bool) -> Result<SnapshotInfo> {
+        validate_name("snapshot", name)?;
+        let path = self.snapshot_dir.join(name);
+        if path.exists() {
+            return Err(DaemonError::Invalid(format!("snapshot {} already exists", name)));
+        }
+        fs::create_dir_all(&self.snapshot_dir)?;
+        let mut snapshot = SnapshotSet::new().writable(writable).with_snapshot(source, &path);
+        if let Err(e) = Executor::new().run(&mut snapshot) {
+            return Err(e.downcast::<crate::fs::BtrfsError>()?.into());
+        }
+        info!("Snapshotted {} to {}", source.display(), path.display());
+        Ok(SnapshotInfo {
+            name: name.to_string(),
+            path,
+        })
+    }
+
+    /// Delete a snapshot from the snapshot directory
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        validate_name("snapshot", name)?;
+        let path = self.snapshot_dir.join(name);
+        if !crate::fs::is_subvolume(&path) {
+            return Err(DaemonError::NotFound(format!("snapshot {}", name)));
+        }
+        crate::fs::delete_subvolume(&path)?;
+        info!("Deleted snapshot {}", name);
+        Ok(())
+    }
+
+    /// Snapshots in the snapshot directory
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        if !self.snapshot_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots: Vec<_> = crate::fs::list_subvolumes(&self.snapshot_dir)?
+            .into_iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_string_lossy().into_owned();
+                Some(SnapshotInfo {
+                    path: self.snapshot_dir.join(&name),
+                    name,
+                })
+            })
+            .collect();
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(snapshots)
+    }
+
+    /// The backup manager, set up on first use
+    async fn backup_manager(&self) -> Result<&BackupManager> {
+        self.backups
+            .get_or_try_init(|| self.load_backup_manager(Arc::new(self.events.clone())))
+            .await
+    }
+
+    /// Set up a backup manager reporting to `progress`
+    async fn load_backup_manager(&self, progress: Arc<dyn Progress>) -> Result<BackupManager> {
+        let content = match tokio::fs::read_to_string(&self.backup_config).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(DaemonError::Unavailable(format!(
+                    "backups are not configured: {} does not exist",
+                    self.backup_config.display()
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let config: BackupConfig = toml::from_str(&content)?;
+        Ok(BackupManager::new(config).await?.with_progress(progress))
+    }
+
+    /// Back up a subvolume, incrementally on top of `parent` if given
+    pub async fn create_backup(
+        &self,
+        subvolume: &Path,
+        name: Option<&str>,
+        description: Option<&str>,
+        parent: Option<&str>,
+    ) -> Result<Backup> {
+        let manager = self.backup_manager().await?;
+        let parent = match parent {
+            Some(id) => Some(manager.get_backup(id).await?),
+            None => None,
+        };
+        let backup = manager
+            .create_backup(subvolume, name, description, parent.is_some(), parent.as_ref())
+            .await?;
+        info!("Created backup {}", backup.id);
+        Ok(backup)
+    }
+
+    /// Restore a backup to `target`
+    pub async fn restore_backup(&self, id: &str, target: &Path) -> Result<()> {
+        self.backup_manager().await?.restore_backup(id, Some(target)).await?;
+        info!("Restored backup {} to {}", id, target.display());
+        Ok(())
+    }
+
+    /// Delete a backup
+    pub async fn delete_backup(&self, id: &str) -> Result<()> {
+        self.backup_manager().await?.delete_backup(id).await?;
+        info!("Deleted backup {}", id);
+        Ok(())
+    }
+
+    /// Check a backup's integrity
+    pub async fn verify_backup(&self, id: &str) -> Result<bool> {
+        Ok(self.backup_manager().await?.verify_backup(id).await?)
+    }
+
+    /// A backup
+    pub async fn backup(&self, id: &str) -> Result<Backup> {
+        Ok(self.backup_manager().await?.get_backup(id).await?)
+    }
+
+    /// All backups
+    pub async fn backups(&self) -> Result<Vec<Backup>> {
+        Ok(self.backup_manager().await?.list_backups().await?)
+    }
+
+    /// Run a package transaction on the blocking thread pool
+    async fn with_packages<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(PackageManager) -> std::result::Result<T, PackageError> + Send + 'static,
+    {
+        let _transaction = self.packages.lock().await;
+        let root = self.package_root.to_string_lossy().into_owned();
+        let result = tokio::task::spawn_blocking(move || f(PackageManager::new(&root)))
+            .await
+            .map_err(io::Error::other)?;
+        Ok(result?)
+    }
+
+    /// Install packages
+    pub async fn install_packages(&self, names: Vec<String>) -> Result<()> {
+        if names.is_empty() {
+            return Err(DaemonError::Invalid("no packages given".to_string()));
+        }
+        let list = PackageList {
+            packages: names.into_iter().map(PackageSpec::new).collect(),
+            ..Default::default()
+        };
+        self.with_packages(move |manager| manager.install_list(&list)).await
+    }
+
+    /// Revert a package transaction
+    pub async fn undo_transaction(&self, id: &str) -> Result<TransactionRecord> {
+        let id = Uuid::parse_str(id).map_err(|e| DaemonError::Invalid(format!("invalid transaction ID: {}", e)))?;
+        self.with_packages(move |manager| manager.undo(&id)).await
+    }
+
+    /// Recorded package transactions
+    pub async fn package_history(&self) -> Result<Vec<TransactionRecord>> {
+        self.with_packages(|manager| manager.history()).await
+    }
+
+    /// Deployments of the system and whether an update waits for a reboot
+    pub async fn update_status(&self) -> Result<UpdateStatus> {
+        let updater = self.updater.clone();
+        let status = tokio::task::spawn_blocking(move || updater.status())
+            .await
+            .map_err(io::Error::other)?;
+        Ok(status?)
+    }
+
+    /// The scheduler of background jobs
+    pub fn scheduler(&self) -> Arc<Scheduler> {
+        Arc::clone(&self.jobs)
+    }
+
+    /// Queue a background job
+    pub fn submit_job(&self, spec: JobSpec, priority: Priority) -> Result<Job> {
+        Ok(self.jobs.submit(spec, priority)?)
+    }
+
+    /// All background jobs, oldest first
+    pub fn jobs(&self) -> Result<Vec<Job>> {
+        Ok(self.jobs.store().list()?)
+    }
+
+    /// A background job
+    pub fn job(&self, id: u64) -> Result<Job> {
+        Ok(self.jobs.store().get(id)?)
+    }
+
+    /// Cancel a background job
+    pub fn cancel_job(&self, id: u64) -> Result<Job> {
+        Ok(self.jobs.cancel(id)?)
+    }
+
+    /// The log of a background job so far
+    pub fn job_log(&self, id: u64) -> Result<String> {
+        Ok(self.jobs.store().read_log(id)?)
+    }
+
+    async fn run_backup_job(
+        &self,
+        subvolume: &Path,
+        name: Option<&str>,
+        description: Option<&str>,
+        parent: Option<&str>,
+        context: &JobContext,
+    ) -> Result<()> {
+        // A manager of its own, so the backup's progress ends up in the job log
+        let manager = self.load_backup_manager(Arc::clone(&context.progress)).await?;
+        let parent = match parent {
+            Some(id) => Some(manager.get_backup(id).await?),
+            None => None,
+        };
+        let backup = tokio::select! {
+            backup = manager.create_backup(subvolume, name, description, parent.is_some(), parent.as_ref()) => backup?,
+            _ = context.cancel.cancelled() => return Err(DaemonError::Invalid("cancelled".to_string())),
+        };
+        info!("Job {} created backup {}", context.id, backup.id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobRunner for Operations {
+    async fn run(&self, spec: &JobSpec, context: &JobContext) -> std::result::Result<(), BoxError> {
+        match spec.clone() {
+            JobSpec::Backup {
+                subvolume,
+                name,
+                description,
+                parent,
+            } => {
+                self.run_backup_job(&subvolume, name.as_deref(), description.as_deref(), parent.as_deref(), context)
+                    .await?
+            }
+            JobSpec::ImagePull { reference, layout } => {
+                // skopeo runs to the end; a cancelled pull is only noticed afterwards
+                let progress = Arc::clone(&context.progress);
+                tokio::task::spawn_blocking(move || OciImage::pull(&reference, &layout, &*progress)).await??;
+                if context.cancel.is_cancelled() {
+                    return Err("cancelled".into());
+                }
+            }
+            JobSpec::KernelBuild { source, profile, jobs } => {
+                let mut builder = KernelBuilder::new(&source)
+                    .with_profile(profile)
+                    .with_cancellation(context.cancel.clone())
+                    .with_progress(Arc::clone(&context.progress));
+                if let Some(jobs) = jobs {
+                    builder = builder.with_jobs(jobs);
+                }
+                // The build blocks on make for its whole length
+                let runtime = tokio::runtime::Handle::current();
+                tokio::task::spawn_blocking(move || runtime.block_on(builder.build())).await??;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn container_info(id: &str, bundle: &Path, container: &Container) -> ContainerInfo {
+    ContainerInfo {
+        id: id.to_string(),
+        bundle: bundle.to_path_buf(),
+        state: container.status().as_str(),
+    }
+}
+
+/// Reject names that are not a single plain path component
+fn validate_name(kind: &str, name: &str) -> Result<()> {
+    if name.len() <= 128 && crate::fs::is_plain_name(name) {
+        Ok(())
+    } else {
+        Err(DaemonError::Invalid(format!("invalid {} name '{}'", kind, name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_container_lifecycle() {
+        let dir = tempdir().unwrap();
+        let config = DaemonConfig {
+            containers_dir: dir.path().join("containers"),
+            ..Default::default()
+        };
+        let ops = Operations::new(&config).unwrap();
+
+        assert!(ops.create_container("../escape", Path::new("rootfs"), Vec::new(), None).is_err());
+        let info = ops.create_container("web", Path::new("rootfs"), Vec::new(), None).unwrap();
+        assert_eq!(info.state, "created");
+        assert!(info.bundle.join("config.json").is_file());
+        assert!(ops.create_container("web", Path::new("rootfs"), Vec::new(), None).is_err());
+
+        assert_eq!(ops.start_container("web").unwrap().state, "running");
+        assert!(ops.delete_container("web").is_err());
+        ops.stop_container("web").unwrap();
+
+        // Bundles are picked up again after a restart
+        let restarted = Operations::new(&config).unwrap();
+        assert_eq!(restarted.containers().len(), 1);
+
+        ops.delete_container("web").unwrap();
+        assert!(ops.containers().is_empty());
+        assert!(matches!(ops.container("web"), Err(DaemonError::NotFound(_))));
+    }
+}
@@ -0,0 +1,876 @@
+//! REST transport of the daemon API
+//!
+//! A versioned HTTP/JSON API for dashboards and scripts, covering snapshots,
+//! backups, containers and the update status. It authenticates like the
+//! gRPC API: `POST /v1/auth/token` exchanges an API key for a token, which
+//! every other route except the OpenAPI document needs in an
+//! `Authorization: Bearer` header. Errors are returned as
+//! `{"error": "..."}`.
+//!
+//! Long-running operations can be queued under `/v1/jobs` instead, and
+//! `GET /v1/events` streams the progress of running operations, such as
+//! backups, as server-sent events carrying one JSON progress event each.
+//!
+//! The OpenAPI document is generated from the handlers and served at
+//! `/v1/openapi.json`.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::auth::{AuthError, Claims};
+use crate::backup::{Backup, BackupError};
+use crate::jobs::{Job, JobError, JobKind, JobSpec, Priority};
+use crate::oci::ContainerError;
+use crate::system::update::UpdateStatus;
+
+use super::auth::{scopes, Authenticator};
+use super::ops::{ContainerInfo, Operations, SnapshotInfo};
+use super::{DaemonError, Result};
+
+/// Serve the API on `listener` until `shutdown` resolves
+pub(super) async fn serve<F>(
+    listener: TcpListener,
+    ops: Arc<Operations>,
+    auth: Arc<Authenticator>,
+    dashboard: bool,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let router = router(ops, auth);
+    #[cfg(feature = "dashboard")]
+    let router = if dashboard { router.merge(super::dashboard::router()) } else { router };
+    #[cfg(not(feature = "dashboard"))]
+    let _ = dashboard;
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// The OpenAPI document of the REST API
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+#[derive(Clone)]
+struct AppState {
+    ops: Arc<Operations>,
+    auth: Arc<Authenticator>,
+}
+
+fn router(ops: Arc<Operations>, auth: Arc<Authenticator>) -> Router {
+    let state = AppState { ops, auth };
+    let protected = Router::new()
+        .route("/v1/snapshots", get(list_snapshots).post(create_snapshot))
+        .route("/v1/snapshots/:name", axum::routing::delete(delete_snapshot))
+        .route("/v1/backups", get(list_backups).post(create_backup))
+        .route("/v1/backups/:id", get(get_backup).delete(delete_backup))
+        .route("/v1/backups/:id/restore", post(restore_backup))
+        .route("/v1/backups/:id/verify", post(verify_backup))
+        .route("/v1/containers", get(list_containers).post(create_container))
+        .route("/v1/containers/:id", get(get_container).delete(delete_container))
+        .route("/v1/containers/:id/start", post(start_container))
+        .route("/v1/containers/:id/stop", post(stop_container))
+        .route("/v1/jobs", get(list_jobs).post(submit_job))
+        .route("/v1/jobs/:id", get(get_job))
+        .route("/v1/jobs/:id/cancel", post(cancel_job))
+        .route("/v1/jobs/:id/log", get(job_log))
+        .route("/v1/updates", get(update_status))
+        .route("/v1/events", get(events))
+        .route_layer(middleware::from_fn_with_state(state.clone(), bearer));
+    Router::new()
+        .route("/v1/auth/token", post(exchange_token))
+        .route("/v1/openapi.json", get(|| async { Json(openapi()) }))
+        .merge(protected)
+        .with_state(state)
+}
+
+/// Check the bearer token, leaving its claims for the handlers
+async fn bearer(State(state): State<AppState>, mut request: Request, next: Next) -> std::result::Result<Response, DaemonError> {
+    let header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let claims = state.auth.authenticate(header)?;
+    request.extensions_mut().insert(claims);
+    Ok(next.run(request).await)
+}
+
+fn require(claims: &Claims, scope: &str) -> Result<()> {
+    Ok(claims.require_scope(scope)?)
+}
+
+/// Body of error responses
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for DaemonError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            DaemonError::Auth(AuthError::InsufficientScope(_)) => StatusCode::FORBIDDEN,
+            DaemonError::Auth(_) => StatusCode::UNAUTHORIZED,
+            DaemonError::NotFound(_)
+            | DaemonError::Container(ContainerError::NotFound(_))
+            | DaemonError::Job(JobError::NotFound(_)) => StatusCode::NOT_FOUND,
+            DaemonError::Job(JobError::Ended { .. })
+            | DaemonError::Container(ContainerError::InvalidTransition(_))
+            | DaemonError::Backup(BackupError::InvalidTransition(_)) => StatusCode::CONFLICT,
+            DaemonError::Container(ContainerError::AlreadyExists(_)) => StatusCode::CONFLICT,
+            DaemonError::Invalid(_) => StatusCode::BAD_REQUEST,
+            DaemonError::Unavailable(_) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(ErrorBody { error: self.to_string() })).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, DaemonError>;
+
+/// Token exchange request
+#[derive(Debug, Deserialize, ToSchema)]
+struct TokenRequest {
+    /// API key of the service
+    api_key: String,
+    /// Service the key belongs to
+    service: String,
+    /// Scopes the token should carry, such as `container:read`
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+/// An issued token
+#[derive(Debug, Serialize, ToSchema)]
+struct TokenResponse {
+    token: String,
+    /// UNIX timestamp
+    expires_at: i64,
+}
+
+/// Exchange an API key for a short-lived token
+#[utoipa::path(
+    post,
+    path = "/v1/auth/token",
+    tag = "auth",
+    request_body = TokenRequest,
+    responses(
+        (status = 200, body = TokenResponse),
+        (status = 401, body = ErrorBody),
+        (status = 403, description = "A scope is not allowed for the service", body = ErrorBody)
+    )
+)]
+async fn exchange_token(State(state): State<AppState>, Json(request): Json<TokenRequest>) -> ApiResult<TokenResponse> {
+    let scopes: Vec<&str> = request.scopes.iter().map(String::as_str).collect();
+    let token = state.auth.exchange(&request.api_key, &request.service, &scopes)?;
+    info!("Issued a token for {} with scopes {:?}", request.service, scopes);
+    Ok(Json(TokenResponse {
+        token: token.token,
+        expires_at: token.expires_at,
+    }))
+}
+
+/// Snapshot request
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateSnapshot {
+    /// Subvolume to snapshot
+    #[schema(value_type = String)]
+    source: PathBuf,
+    /// Name of the snapshot in the snapshot directory
+    name: String,
+    #[serde(default)]
+    writable: bool,
+}
+
+/// List snapshots
+#[utoipa::path(
+    get,
+    path = "/v1/snapshots",
+    tag = "snapshots",
+    security(("bearer" = ["snapshot:read"])),
+    responses((status = 200, body = [SnapshotInfo]))
+)]
+async fn list_snapshots(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> ApiResult<Vec<SnapshotInfo>> {
+    require(&claims, scopes::SNAPSHOT_READ)?;
+    Ok(Json(state.ops.snapshots()?))
+}
+
+/// Snapshot a subvolume
+#[utoipa::path(
+    post,
+    path = "/v1/snapshots",
+    tag = "snapshots",
+    security(("bearer" = ["snapshot:write"])),
+    request_body = CreateSnapshot,
+    responses((status = 200, body = SnapshotInfo), (status = 400, body = ErrorBody))
+)]
+async fn create_snapshot(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateSnapshot>,
+) -> ApiResult<SnapshotInfo> {
+    require(&claims, scopes::SNAPSHOT_WRITE)?;
+    Ok(Json(state.ops.create_snapshot(&request.source, &request.name, request.writable)?))
+}
+
+/// Delete a snapshot
+#[utoipa::path(
+    delete,
+    path = "/v1/snapshots/{name}",
+    tag = "snapshots",
+    security(("bearer" = ["snapshot:write"])),
+    params(("name" = String, Path, description = "Snapshot name")),
+    responses((status = 204), (status = 404, body = ErrorBody))
+)]
+async fn delete_snapshot(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(name): Path<String>,
+) -> std::result::Result<StatusCode, DaemonError> {
+    require(&claims, scopes::SNAPSHOT_WRITE)?;
+    state.ops.delete_snapshot(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A backup
+#[derive(Debug, Serialize, ToSchema)]
+struct BackupInfo {
+    id: String,
+    name: String,
+    description: Option<String>,
+    /// Subvolume that was backed up
+    subvolume: String,
+    /// Size in bytes
+    size: u64,
+    /// RFC 3339
+    created_at: String,
+    incremental: bool,
+    parent_id: Option<String>,
+}
+
+impl From<Backup> for BackupInfo {
+    fn from(backup: Backup) -> Self {
+        Self {
+            id: backup.id,
+            name: backup.name,
+            description: backup.description,
+            subvolume: backup.subvolume_path.to_string_lossy().into_owned(),
+            size: backup.size,
+            created_at: backup.created_at.to_rfc3339(),
+            incremental: backup.is_incremental,
+            parent_id: backup.parent_id,
+        }
+    }
+}
+
+/// Backup request
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateBackup {
+    /// Subvolume to back up
+    #[schema(value_type = String)]
+    subvolume: PathBuf,
+    name: Option<String>,
+    description: Option<String>,
+    /// Make an incremental backup on top of this backup
+    parent_id: Option<String>,
+}
+
+/// Restore request
+#[derive(Debug, Deserialize, ToSchema)]
+struct RestoreBackup {
+    /// Where to restore the backup
+    #[schema(value_type = String)]
+    target: PathBuf,
+}
+
+/// Result of a backup verification
+#[derive(Debug, Serialize, ToSchema)]
+struct Verification {
+    valid: bool,
+}
+
+/// List backups
+#[utoipa::path(
+    get,
+    path = "/v1/backups",
+    tag = "backups",
+    security(("bearer" = ["backup:read"])),
+    responses((status = 200, body = [BackupInfo]), (status = 409, description = "Backups are not configured", body = ErrorBody))
+)]
+async fn list_backups(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> ApiResult<Vec<BackupInfo>> {
+    require(&claims, scopes::BACKUP_READ)?;
+    Ok(Json(state.ops.backups().await?.into_iter().map(Into::into).collect()))
+}
+
+/// Back up a subvolume
+#[utoipa::path(
+    post,
+    path = "/v1/backups",
+    tag = "backups",
+    security(("bearer" = ["backup:write"])),
+    request_body = CreateBackup,
+    responses((status = 200, body = BackupInfo), (status = 409, description = "Backups are not configured", body = ErrorBody))
+)]
+async fn create_backup(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateBackup>,
+) -> ApiResult<BackupInfo> {
+    require(&claims, scopes::BACKUP_WRITE)?;
+    let backup = state
+        .ops
+        .create_backup(
+            &request.subvolume,
+            request.name.as_deref(),
+            request.description.as_deref(),
+            request.parent_id.as_deref(),
+        )
+        .await?;
+    Ok(Json(backup.into()))
+}
+
+/// Show a backup
+#[utoipa::path(
+    get,
+    path = "/v1/backups/{id}",
+    tag = "backups",
+    security(("bearer" = ["backup:read"])),
+    params(("id" = String, Path, description = "Backup ID")),
+    responses((status = 200, body = BackupInfo), (status = 500, body = ErrorBody))
+)]
+async fn get_backup(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> ApiResult<BackupInfo> {
+    require(&claims, scopes::BACKUP_READ)?;
+    Ok(Json(state.ops.backup(&id).await?.into()))
+}
+
+/// Delete a backup
+#[utoipa::path(
+    delete,
+    path = "/v1/backups/{id}",
+    tag = "backups",
+    security(("bearer" = ["backup:write"])),
+    params(("id" = String, Path, description = "Backup ID")),
+    responses((status = 204), (status = 500, body = ErrorBody))
+)]
+async fn delete_backup(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> std::result::Result<StatusCode, DaemonError> {
+    require(&claims, scopes::BACKUP_WRITE)?;
+    state.ops.delete_backup(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restore a backup
+#[utoipa::path(
+    post,
+    path = "/v1/backups/{id}/restore",
+    tag = "backups",
+    security(("bearer" = ["backup:write"])),
+    params(("id" = String, Path, description = "Backup ID")),
+    request_body = RestoreBackup,
+    responses((status = 204), (status = 500, body = ErrorBody))
+)]
+async fn restore_backup(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+    Json(request): Json<RestoreBackup>,
+) -> std::result::Result<StatusCode, DaemonError> {
+    require(&claims, scopes::BACKUP_WRITE)?;
+    state.ops.restore_backup(&id, &request.target).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Check a backup's integrity
+#[utoipa::path(
+    post,
+    path = "/v1/backups/{id}/verify",
+    tag = "backups",
+    security(("bearer" = ["backup:read"])),
+    params(("id" = String, Path, description = "Backup ID")),
+    responses((status = 200, body = Verification), (status = 500, body = ErrorBody))
+)]
+async fn verify_backup(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> ApiResult<Verification> {
+    require(&claims, scopes::BACKUP_READ)?;
+    Ok(Json(Verification {
+        valid: state.ops.verify_backup(&id).await?,
+    }))
+}
+
+/// Container request
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateContainer {
+    id: String,
+    /// Root filesystem, relative to the bundle
+    #[serde(default = "default_rootfs")]
+    #[schema(value_type = String, default = "rootfs")]
+    rootfs: PathBuf,
+    /// Command to run; `/bin/sh` if empty
+    #[serde(default)]
+    args: Vec<String>,
+    /// Shipped SELinux or AppArmor profile to confine the container with
+    mac_profile: Option<String>,
+}
+
+fn default_rootfs() -> PathBuf {
+    PathBuf::from("rootfs")
+}
+
+/// List containers
+#[utoipa::path(
+    get,
+    path = "/v1/containers",
+    tag = "containers",
+    security(("bearer" = ["container:read"])),
+    responses((status = 200, body = [ContainerInfo]))
+)]
+async fn list_containers(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> ApiResult<Vec<ContainerInfo>> {
+    require(&claims, scopes::CONTAINER_READ)?;
+    Ok(Json(state.ops.containers()))
+}
+
+/// Create a container bundle
+#[utoipa::path(
+    post,
+    path = "/v1/containers",
+    tag = "containers",
+    security(("bearer" = ["container:write"])),
+    request_body = CreateContainer,
+    responses((status = 200, body = ContainerInfo), (status = 400, body = ErrorBody))
+)]
+async fn create_container(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateContainer>,
+) -> ApiResult<ContainerInfo> {
+    require(&claims, scopes::CONTAINER_WRITE)?;
+    let info = state.ops.create_container(
+        &request.id,
+        &request.rootfs,
+        request.args,
+        request.mac_profile.as_deref(),
+    )?;
+    Ok(Json(info))
+}
+
+/// Show a container
+#[utoipa::path(
+    get,
+    path = "/v1/containers/{id}",
+    tag = "containers",
+    security(("bearer" = ["container:read"])),
+    params(("id" = String, Path, description = "Container ID")),
+    responses((status = 200, body = ContainerInfo), (status = 404, body = ErrorBody))
+)]
+async fn get_container(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> ApiResult<ContainerInfo> {
+    require(&claims, scopes::CONTAINER_READ)?;
+    Ok(Json(state.ops.container(&id)?))
+}
+
+/// Delete a stopped container
+#[utoipa::path(
+    delete,
+    path = "/v1/containers/{id}",
+    tag = "containers",
+    security(("bearer" = ["container:write"])),
+    params(("id" = String, Path, description = "Container ID")),
+    responses((status = 204), (status = 400, description = "The container is running", body = ErrorBody), (status = 404, body = ErrorBody))
+)]
+async fn delete_container(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> std::result::Result<StatusCode, DaemonError> {
+    require(&claims, scopes::CONTAINER_WRITE)?;
+    state.ops.delete_container(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start a container
+#[utoipa::path(
+    post,
+    path = "/v1/containers/{id}/start",
+    tag = "containers",
+    security(("bearer" = ["container:write"])),
+    params(("id" = String, Path, description = "Container ID")),
+    responses((status = 200, body = ContainerInfo), (status = 404, body = ErrorBody))
+)]
+async fn start_container(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> ApiResult<ContainerInfo> {
+    require(&claims, scopes::CONTAINER_WRITE)?;
+    Ok(Json(state.ops.start_container(&id)?))
+}
+
+/// Stop a container
+#[utoipa::path(
+    post,
+    path = "/v1/containers/{id}/stop",
+    tag = "containers",
+    security(("bearer" = ["container:write"])),
+    params(("id" = String, Path, description = "Container ID")),
+    responses((status = 200, body = ContainerInfo), (status = 404, body = ErrorBody))
+)]
+async fn stop_container(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<String>,
+) -> ApiResult<ContainerInfo> {
+    require(&claims, scopes::CONTAINER_WRITE)?;
+    Ok(Json(state.ops.stop_container(&id)?))
+}
+
+/// A background job
+#[derive(Debug, Serialize, ToSchema)]
+struct JobInfo {
+    id: u64,
+    /// `backup`, `image-pull` or `kernel-build`
+    kind: String,
+    /// What the job does
+    description: String,
+    /// `low`, `normal` or `high`
+    priority: String,
+    /// `pending`, `running`, `completed`, `failed` or `cancelled`
+    state: String,
+    /// RFC 3339
+    created_at: String,
+    /// RFC 3339
+    started_at: Option<String>,
+    /// RFC 3339
+    finished_at: Option<String>,
+    error: Option<String>,
+}
+
+impl From<Job> for JobInfo {
+    fn from(job: Job) -> Self {
+        Self {
+            id: job.id,
+            kind: job.spec.kind().to_string(),
+            description: job.spec.to_string(),
+            priority: job.priority.to_string(),
+            state: job.state.to_string(),
+            created_at: job.created_at.to_rfc3339(),
+            started_at: job.started_at.map(|t| t.to_rfc3339()),
+            finished_at: job.finished_at.map(|t| t.to_rfc3339()),
+            error: job.error,
+        }
+    }
+}
+
+/// Job request
+#[derive(Debug, Deserialize, ToSchema)]
+struct SubmitJob {
+    /// What to run, such as `{"kind": "backup", "subvolume": "/home"}`,
+    /// `{"kind": "image-pull", "reference": "...", "layout": "..."}` or
+    /// `{"kind": "kernel-build", "source": "...", "profile": "Production"}`
+    #[schema(value_type = Object)]
+    spec: JobSpec,
+    /// `low`, `normal` or `high`
+    #[serde(default)]
+    #[schema(value_type = String)]
+    priority: Priority,
+}
+
+/// Scope needed to submit or cancel a job of `kind`
+fn job_scope(kind: JobKind) -> &'static str {
+    match kind {
+        JobKind::Backup => scopes::BACKUP_WRITE,
+        JobKind::ImagePull => scopes::CONTAINER_WRITE,
+        JobKind::KernelBuild => scopes::KERNEL_WRITE,
+    }
+}
+
+/// List background jobs
+#[utoipa::path(
+    get,
+    path = "/v1/jobs",
+    tag = "jobs",
+    security(("bearer" = ["job:read"])),
+    responses((status = 200, body = [JobInfo]))
+)]
+async fn list_jobs(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> ApiResult<Vec<JobInfo>> {
+    require(&claims, scopes::JOB_READ)?;
+    Ok(Json(state.ops.jobs()?.into_iter().map(Into::into).collect()))
+}
+
+/// Queue a background job
+///
+/// Needs the write scope of the job's kind: `backup:write`,
+/// `container:write` for image pulls or `kernel:write`.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs",
+    tag = "jobs",
+    security(("bearer" = ["backup:write", "container:write", "kernel:write"])),
+    request_body = SubmitJob,
+    responses((status = 200, body = JobInfo), (status = 403, body = ErrorBody))
+)]
+async fn submit_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<SubmitJob>,
+) -> ApiResult<JobInfo> {
+    require(&claims, job_scope(request.spec.kind()))?;
+    Ok(Json(state.ops.submit_job(request.spec, request.priority)?.into()))
+}
+
+/// Show a background job
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{id}",
+    tag = "jobs",
+    security(("bearer" = ["job:read"])),
+    params(("id" = u64, Path, description = "Job ID")),
+    responses((status = 200, body = JobInfo), (status = 404, body = ErrorBody))
+)]
+async fn get_job(State(state): State<AppState>, Extension(claims): Extension<Claims>, Path(id): Path<u64>) -> ApiResult<JobInfo> {
+    require(&claims, scopes::JOB_READ)?;
+    Ok(Json(state.ops.job(id)?.into()))
+}
+
+/// Cancel a background job
+///
+/// Pending jobs are cancelled at once; running jobs are asked to stop.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs/{id}/cancel",
+    tag = "jobs",
+    security(("bearer" = ["backup:write", "container:write", "kernel:write"])),
+    params(("id" = u64, Path, description = "Job ID")),
+    responses(
+        (status = 200, body = JobInfo),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "The job already ended", body = ErrorBody)
+    )
+)]
+async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> ApiResult<JobInfo> {
+    require(&claims, job_scope(state.ops.job(id)?.spec.kind()))?;
+    Ok(Json(state.ops.cancel_job(id)?.into()))
+}
+
+/// Read the log of a background job
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{id}/log",
+    tag = "jobs",
+    security(("bearer" = ["job:read"])),
+    params(("id" = u64, Path, description = "Job ID")),
+    responses((status = 200, description = "The log so far", content_type = "text/plain", body = String), (status = 404, body = ErrorBody))
+)]
+async fn job_log(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<u64>,
+) -> std::result::Result<String, DaemonError> {
+    require(&claims, scopes::JOB_READ)?;
+    state.ops.job_log(id)
+}
+
+/// A root filesystem produced by an update
+#[derive(Debug, Serialize, ToSchema)]
+struct DeploymentInfo {
+    id: String,
+    /// Subvolume relative to the Btrfs top level
+    subvolume: String,
+    /// Subvolume the deployment was created from
+    parent: String,
+    /// Kernel release booted by the deployment
+    kernel: String,
+    /// RFC 3339
+    created_at: String,
+}
+
+/// Deployments of the system
+#[derive(Debug, Serialize, ToSchema)]
+struct UpdateInfo {
+    /// Subvolume the running system was booted from
+    booted: String,
+    /// Default boot entry
+    default_entry: Option<String>,
+    /// Deployments, oldest first
+    deployments: Vec<DeploymentInfo>,
+    /// Whether a staged deployment waits for a reboot
+    pending: bool,
+}
+
+impl From<UpdateStatus> for UpdateInfo {
+    fn from(status: UpdateStatus) -> Self {
+        Self {
+            pending: status.pending(),
+            booted: status.booted,
+            default_entry: status.default_entry,
+            deployments: status
+                .deployments
+                .into_iter()
+                .map(|d| DeploymentInfo {
+                    id: d.id,
+                    subvolume: d.subvolume,
+                    parent: d.parent,
+                    kernel: d.kernel,
+                    created_at: d.created_at.to_rfc3339(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Show the deployments and whether an update waits for a reboot
+#[utoipa::path(
+    get,
+    path = "/v1/updates",
+    tag = "updates",
+    security(("bearer" = ["update:read"])),
+    responses((status = 200, body = UpdateInfo))
+)]
+async fn update_status(State(state): State<AppState>, Extension(claims): Extension<Claims>) -> ApiResult<UpdateInfo> {
+    require(&claims, scopes::UPDATE_READ)?;
+    Ok(Json(state.ops.update_status().await?.into()))
+}
+
+/// Stream the progress of running operations
+///
+/// Events a client is too slow to receive are skipped.
+#[utoipa::path(
+    get,
+    path = "/v1/events",
+    tag = "events",
+    security(("bearer" = ["backup:read"])),
+    responses((
+        status = 200,
+        description = "Server-sent events, each a JSON progress event",
+        content_type = "text/event-stream",
+        body = String
+    ))
+)]
+async fn events(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> std::result::Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>, DaemonError> {
+    require(&claims, scopes::BACKUP_READ)?;
+    let stream = BroadcastStream::new(state.ops.subscribe())
+        .filter_map(|event| Event::default().json_data(event.ok()?).ok())
+        .map(Ok);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rastosd", description = "rastOS management API"),
+    paths(
+        exchange_token,
+        list_snapshots,
+        create_snapshot,
+        delete_snapshot,
+        list_backups,
+        create_backup,
+        get_backup,
+        delete_backup,
+        restore_backup,
+        verify_backup,
+        list_containers,
+        create_container,
+        get_container,
+        delete_container,
+        start_container,
+        stop_container,
+        list_jobs,
+        submit_job,
+        get_job,
+        cancel_job,
+        job_log,
+        update_status,
+        events,
+    ),
+    components(schemas(
+        ErrorBody,
+        TokenRequest,
+        TokenResponse,
+        SnapshotInfo,
+        CreateSnapshot,
+        BackupInfo,
+        CreateBackup,
+        RestoreBackup,
+        Verification,
+        ContainerInfo,
+        CreateContainer,
+        JobInfo,
+        SubmitJob,
+        DeploymentInfo,
+        UpdateInfo,
+    )),
+    modifiers(&BearerAuth)
+)]
+struct ApiDoc;
+
+/// Declares the bearer token security scheme
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openapi() {
+        let doc = serde_json::to_value(openapi()).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+        for path in ["/v1/auth/token", "/v1/snapshots", "/v1/backups/{id}/restore", "/v1/containers/{id}/stop", "/v1/updates"] {
+            assert!(paths.contains_key(path), "{} is not documented", path);
+        }
+        assert_eq!(
+            doc["paths"]["/v1/containers"]["post"]["security"][0]["bearer"][0],
+            scopes::CONTAINER_WRITE
+        );
+        assert!(doc["paths"]["/v1/auth/token"]["post"]["security"].is_null());
+        assert_eq!(doc["components"]["securitySchemes"]["bearer"]["scheme"], "bearer");
+    }
+}
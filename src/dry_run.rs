@@ -0,0 +1,211 @@
+//! Dry runs of destructive operations
+//!
+//! Operations that destroy or replace data take a [`DryRun`] through a
+//! `with_dry_run` builder method. While it is active they record what they
+//! would do as [`Action`]s in its [`Plan`] and skip doing it; everything
+//! leading up to the change, such as validation and planning, still runs,
+//! so a dry run fails where the real run would fail before changing
+//! anything.
+//!
+//! The context is shared: clones record into the same plan, so one
+//! `DryRun` handed to every component of a command collects the whole plan.
+//!
+//! ```
+//! use rastos::dry_run::{Action, DryRun};
+//!
+//! let dry_run = DryRun::new();
+//! if !dry_run.skip(Action::delete("subvolume /.snapshots/old")) {
+//!     // delete it
+//! }
+//! assert_eq!(dry_run.plan().to_string(), "delete subvolume /.snapshots/old\n");
+//! ```
+
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use serde::Serialize;
+
+/// What an action does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActionKind {
+    /// Create something new
+    Create,
+    /// Write or overwrite data
+    Write,
+    /// Delete something
+    Delete,
+    /// Run a command or a step of a transaction
+    Run,
+}
+
+impl Display for ActionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ActionKind::Create => "create",
+            ActionKind::Write => "write",
+            ActionKind::Delete => "delete",
+            ActionKind::Run => "run",
+        })
+    }
+}
+
+/// An operation a dry run skipped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Action {
+    /// What the action does
+    pub kind: ActionKind,
+    /// What it works on, such as `subvolume /.snapshots/old`
+    pub target: String,
+    /// More about it, such as the partition table to be written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Action {
+    /// An action of `kind` on `target`
+    pub fn new(kind: ActionKind, target: impl Display) -> Self {
+        Self {
+            kind,
+            target: target.to_string(),
+            detail: None,
+        }
+    }
+
+    /// Creating `target`
+    pub fn create(target: impl Display) -> Self {
+        Self::new(ActionKind::Create, target)
+    }
+
+    /// Writing `target`
+    pub fn write(target: impl Display) -> Self {
+        Self::new(ActionKind::Write, target)
+    }
+
+    /// Deleting `target`
+    pub fn delete(target: impl Display) -> Self {
+        Self::new(ActionKind::Delete, target)
+    }
+
+    /// Running `target`
+    pub fn run(target: impl Display) -> Self {
+        Self::new(ActionKind::Run, target)
+    }
+
+    /// Add a detail
+    pub fn with_detail(mut self, detail: impl Display) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.target)?;
+        if let Some(detail) = &self.detail {
+            for line in detail.lines() {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The actions a dry run skipped, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Plan {
+    /// Skipped actions
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    /// Whether nothing would have changed
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for action in &self.actions {
+            writeln!(f, "{}", action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether destructive operations run or are only recorded
+///
+/// The default performs them.
+#[derive(Debug, Clone, Default)]
+pub struct DryRun {
+    plan: Option<Arc<Mutex<Plan>>>,
+}
+
+impl DryRun {
+    /// A dry run recording into an empty plan
+    pub fn new() -> Self {
+        Self {
+            plan: Some(Arc::default()),
+        }
+    }
+
+    /// Perform operations instead of recording them
+    pub fn off() -> Self {
+        Self::default()
+    }
+
+    /// A dry run if `enabled`, otherwise performing operations
+    pub fn enabled(enabled: bool) -> Self {
+        if enabled {
+            Self::new()
+        } else {
+            Self::off()
+        }
+    }
+
+    /// Whether operations are recorded instead of performed
+    pub fn is_active(&self) -> bool {
+        self.plan.is_some()
+    }
+
+    /// Record `action` in a dry run, returning whether to skip it
+    pub fn skip(&self, action: Action) -> bool {
+        let Some(plan) = &self.plan else {
+            return false;
+        };
+        info!("Dry run: would {}", action);
+        plan.lock().unwrap().actions.push(action);
+        true
+    }
+
+    /// The actions recorded so far
+    pub fn plan(&self) -> Plan {
+        self.plan
+            .as_ref()
+            .map(|plan| plan.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_plan() {
+        let dry_run = DryRun::new();
+        let clone = dry_run.clone();
+        assert!(clone.skip(Action::delete("backup 42")));
+        assert!(dry_run.skip(Action::run("pacman -Rns vim").with_detail("removes 1 package")));
+        assert_eq!(
+            dry_run.plan().to_string(),
+            "delete backup 42\nrun pacman -Rns vim\n    removes 1 package\n"
+        );
+
+        let off = DryRun::off();
+        assert!(!off.skip(Action::delete("backup 42")));
+        assert!(off.plan().is_empty());
+    }
+}
@@ -0,0 +1,455 @@
+//! Crate-wide error type
+//!
+//! Each subsystem keeps its own error type; [`RastosError`] wraps all of
+//! them for code that spans subsystems, such as the command-line tools.
+//! Every error has an [`ErrorCode`] naming the subsystem it came from. The
+//! codes are stable: scripts and API clients may match on them, and a code
+//! is never reused for another subsystem.
+//!
+//! [`Context`] adds a description of what was being done to an error,
+//! keeping the original error as its source:
+//!
+//! ```no_run
+//! use rastos::error::Context;
+//!
+//! fn read_manifest() -> rastos::Result<String> {
+//!     std::fs::read_to_string("/etc/rast/machine.toml").context("reading the machine manifest")
+//! }
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::auth::{AuthError, ConfigError as AuthConfigError};
+#[cfg(feature = "backup")]
+use crate::backup::BackupError;
+#[cfg(feature = "daemon")]
+use crate::daemon::DaemonError;
+use crate::fs::{BtrfsError, FsError};
+use crate::helper::HelperError;
+use crate::installer::InstallerError;
+use crate::jobs::JobError;
+use crate::kernel::KernelError;
+#[cfg(feature = "containers")]
+use crate::oci::ContainerError;
+use crate::output::{ExitCode, OutputError};
+use crate::package::PackageError;
+use crate::remote::RemoteError;
+use crate::secrets::SecretError;
+use crate::snapshot::SnapshotTreeError;
+use crate::system::identity::IdentityError;
+use crate::system::mac::MacError;
+use crate::system::network::NetworkError;
+use crate::system::power::PowerError;
+use crate::system::reset::ResetError;
+use crate::system::update::UpdateError;
+use crate::transaction::TransactionError;
+
+/// Result type using [`RastosError`]
+pub type Result<T> = std::result::Result<T, RastosError>;
+
+/// Stable code of an error
+///
+/// Serialized as its name; [`ErrorCode::number`] is the numeric form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// An error no subsystem claims
+    Other = 1,
+    /// I/O error outside any subsystem
+    Io = 2,
+    /// Printing command output failed
+    Output = 3,
+    /// API keys, tokens and certificates
+    Auth = 10,
+    /// Secret store
+    Secret = 11,
+    /// Privileged helper
+    Helper = 12,
+    /// Filesystem helpers
+    Fs = 20,
+    /// Btrfs subvolumes
+    Btrfs = 21,
+    /// Snapshot trees
+    Snapshot = 22,
+    /// Transaction journal
+    Transaction = 23,
+    /// Backups
+    Backup = 30,
+    /// Containers
+    Container = 40,
+    /// Packages
+    Package = 50,
+    /// Kernel builds and boot entries
+    Kernel = 60,
+    /// Installer
+    Installer = 70,
+    /// System updates
+    Update = 80,
+    /// Factory reset
+    Reset = 81,
+    /// Machine identity
+    Identity = 82,
+    /// Network configuration
+    Network = 83,
+    /// SELinux and AppArmor
+    Mac = 84,
+    /// Power management
+    Power = 85,
+    /// Background jobs
+    Jobs = 90,
+    /// Remote hosts
+    Remote = 100,
+    /// The daemon
+    Daemon = 110,
+}
+
+impl ErrorCode {
+    /// Numeric form of the code
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    /// Name of the code, as it is serialized
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Other => "other",
+            ErrorCode::Io => "io",
+            ErrorCode::Output => "output",
+            ErrorCode::Auth => "auth",
+            ErrorCode::Secret => "secret",
+            ErrorCode::Helper => "helper",
+            ErrorCode::Fs => "fs",
+            ErrorCode::Btrfs => "btrfs",
+            ErrorCode::Snapshot => "snapshot",
+            ErrorCode::Transaction => "transaction",
+            ErrorCode::Backup => "backup",
+            ErrorCode::Container => "container",
+            ErrorCode::Package => "package",
+            ErrorCode::Kernel => "kernel",
+            ErrorCode::Installer => "installer",
+            ErrorCode::Update => "update",
+            ErrorCode::Reset => "reset",
+            ErrorCode::Identity => "identity",
+            ErrorCode::Network => "network",
+            ErrorCode::Mac => "mac",
+            ErrorCode::Power => "power",
+            ErrorCode::Jobs => "jobs",
+            ErrorCode::Remote => "remote",
+            ErrorCode::Daemon => "daemon",
+        }
+    }
+
+    /// Code of any error
+    ///
+    /// Recognizes [`RastosError`] and the error types of the subsystems, so
+    /// errors that were boxed on the way up keep their code.
+    pub fn of(err: &(dyn Error + 'static)) -> Self {
+        macro_rules! subsystems {
+            ($($ty:ty => $code:ident),* $(,)?) => {
+                $(if err.is::<$ty>() {
+                    return ErrorCode::$code;
+                })*
+            };
+        }
+        if let Some(err) = err.downcast_ref::<RastosError>() {
+            return err.code();
+        }
+        subsystems! {
+            AuthError => Auth,
+            AuthConfigError => Auth,
+            SecretError => Secret,
+            HelperError => Helper,
+            FsError => Fs,
+            BtrfsError => Btrfs,
+            SnapshotTreeError => Snapshot,
+            TransactionError => Transaction,
+            PackageError => Package,
+            KernelError => Kernel,
+            InstallerError => Installer,
+            UpdateError => Update,
+            ResetError => Reset,
+            IdentityError => Identity,
+            NetworkError => Network,
+            MacError => Mac,
+            PowerError => Power,
+            JobError => Jobs,
+            RemoteError => Remote,
+            OutputError => Output,
+            io::Error => Io,
+        }
+        #[cfg(feature = "backup")]
+        subsystems! { BackupError => Backup }
+        #[cfg(feature = "containers")]
+        subsystems! { ContainerError => Container }
+        #[cfg(feature = "daemon")]
+        subsystems! { DaemonError => Daemon }
+        ErrorCode::Other
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error of any rastOS subsystem
+#[derive(Error, Debug)]
+pub enum RastosError {
+    /// I/O error outside any subsystem
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Printing command output failed
+    #[error(transparent)]
+    Output(#[from] OutputError),
+
+    /// Authentication or authorization failure
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+
+    /// API key configuration error
+    #[error(transparent)]
+    AuthConfig(#[from] AuthConfigError),
+
+    /// Secret store error
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+
+    /// Privileged helper error
+    #[error(transparent)]
+    Helper(#[from] HelperError),
+
+    /// Filesystem error
+    #[error(transparent)]
+    Fs(#[from] FsError),
+
+    /// Btrfs error
+    #[error(transparent)]
+    Btrfs(#[from] BtrfsError),
+
+    /// Snapshot tree error
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotTreeError),
+
+    /// Transaction journal error
+    #[error(transparent)]
+    Transaction(#[from] TransactionError),
+
+    /// Backup error
+    #[cfg(feature = "backup")]
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+
+    /// Container error
+    #[cfg(feature = "containers")]
+    #[error(transparent)]
+    Container(#[from] ContainerError),
+
+    /// Package error
+    #[error(transparent)]
+    Package(#[from] PackageError),
+
+    /// Kernel error
+    #[error(transparent)]
+    Kernel(#[from] KernelError),
+
+    /// Installer error
+    #[error(transparent)]
+    Installer(#[from] InstallerError),
+
+    /// System update error
+    #[error(transparent)]
+    Update(#[from] UpdateError),
+
+    /// Factory reset error
+    #[error(transparent)]
+    Reset(#[from] ResetError),
+
+    /// Machine identity error
+    #[error(transparent)]
+    Identity(#[from] IdentityError),
+
+    /// Network configuration error
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+
+    /// SELinux or AppArmor error
+    #[error(transparent)]
+    Mac(#[from] MacError),
+
+    /// Power management error
+    #[error(transparent)]
+    Power(#[from] PowerError),
+
+    /// Background job error
+    #[error(transparent)]
+    Jobs(#[from] JobError),
+
+    /// Remote host error
+    #[error(transparent)]
+    Remote(#[from] RemoteError),
+
+    /// Daemon error
+    #[cfg(feature = "daemon")]
+    #[error(transparent)]
+    Daemon(#[from] DaemonError),
+
+    /// An error described by what was being done when it happened
+    #[error("{message}")]
+    Context {
+        /// What was being done
+        message: String,
+        /// The error itself
+        #[source]
+        source: Box<RastosError>,
+    },
+
+    /// An error that is only a message
+    #[error("{0}")]
+    Message(String),
+}
+
+impl RastosError {
+    /// An error that is only a message
+    pub fn msg(message: impl Into<String>) -> Self {
+        RastosError::Message(message.into())
+    }
+
+    /// Stable code of the error; errors with context have the code of the
+    /// error they wrap
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RastosError::Io(_) => ErrorCode::Io,
+            RastosError::Output(_) => ErrorCode::Output,
+            RastosError::Auth(_) | RastosError::AuthConfig(_) => ErrorCode::Auth,
+            RastosError::Secret(_) => ErrorCode::Secret,
+            RastosError::Helper(_) => ErrorCode::Helper,
+            RastosError::Fs(_) => ErrorCode::Fs,
+            RastosError::Btrfs(_) => ErrorCode::Btrfs,
+            RastosError::Snapshot(_) => ErrorCode::Snapshot,
+            RastosError::Transaction(_) => ErrorCode::Transaction,
+            #[cfg(feature = "backup")]
+            RastosError::Backup(_) => ErrorCode::Backup,
+            #[cfg(feature = "containers")]
+            RastosError::Container(_) => ErrorCode::Container,
+            RastosError::Package(_) => ErrorCode::Package,
+            RastosError::Kernel(_) => ErrorCode::Kernel,
+            RastosError::Installer(_) => ErrorCode::Installer,
+            RastosError::Update(_) => ErrorCode::Update,
+            RastosError::Reset(_) => ErrorCode::Reset,
+            RastosError::Identity(_) => ErrorCode::Identity,
+            RastosError::Network(_) => ErrorCode::Network,
+            RastosError::Mac(_) => ErrorCode::Mac,
+            RastosError::Power(_) => ErrorCode::Power,
+            RastosError::Jobs(_) => ErrorCode::Jobs,
+            RastosError::Remote(_) => ErrorCode::Remote,
+            #[cfg(feature = "daemon")]
+            RastosError::Daemon(_) => ErrorCode::Daemon,
+            RastosError::Context { source, .. } => source.code(),
+            RastosError::Message(_) => ErrorCode::Other,
+        }
+    }
+
+    /// Exit status of a command that failed with this error
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from_error(self)
+    }
+}
+
+/// Adds a description of what was being done to errors
+pub trait Context<T> {
+    /// Describe the error with `message`
+    fn context(self, message: impl Into<String>) -> Result<T>;
+
+    /// Describe the error with the message `f` returns, computed only on
+    /// failure
+    fn with_context<M: Into<String>>(self, f: impl FnOnce() -> M) -> Result<T>;
+}
+
+impl<T, E: Into<RastosError>> Context<T> for std::result::Result<T, E> {
+    fn context(self, message: impl Into<String>) -> Result<T> {
+        self.with_context(|| message)
+    }
+
+    fn with_context<M: Into<String>>(self, f: impl FnOnce() -> M) -> Result<T> {
+        self.map_err(|err| RastosError::Context {
+            message: f().into(),
+            source: Box::new(err.into()),
+        })
+    }
+}
+
+/// Serializable description of an error, for the machine output formats
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    /// Stable code of the error
+    pub code: ErrorCode,
+    /// Numeric form of `code`
+    pub number: u16,
+    /// The error message
+    pub message: String,
+    /// Messages of the errors that caused it, outermost first
+    pub causes: Vec<String>,
+}
+
+impl ErrorReport {
+    /// Report of any error
+    pub fn new(err: &(dyn Error + 'static)) -> Self {
+        let code = ErrorCode::of(err);
+        Self {
+            code,
+            number: code.number(),
+            message: err.to_string(),
+            causes: std::iter::successors(err.source(), |e| (*e).source())
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+}
+
+impl Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for cause in &self.causes {
+            write!(f, "\n  caused by: {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_chain() {
+        let err = std::fs::read("/nonexistent/rastos")
+            .context("reading the manifest")
+            .context("loading the machine configuration")
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Io);
+        assert_eq!(err.exit_code(), ExitCode::Failure);
+
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.code, ErrorCode::Io);
+        assert_eq!(report.number, 2);
+        assert_eq!(report.message, "loading the machine configuration");
+        assert_eq!(report.causes[0], "reading the manifest");
+        assert!(report.causes[1].starts_with("I/O error:"));
+    }
+
+    #[test]
+    fn test_code_of_boxed_error() {
+        let err: Box<dyn Error> = Box::new(RemoteError::InvalidHost("nas..lan".to_string()));
+        assert_eq!(ErrorCode::of(&*err), ErrorCode::Remote);
+        assert_eq!(ErrorCode::of(&*Box::<dyn Error>::from("oops")), ErrorCode::Other);
+        assert_eq!(serde_json::to_value(ErrorCode::Remote).unwrap(), "remote");
+    }
+}
@@ -0,0 +1,444 @@
+//! POSIX access control lists for rastOS
+//!
+//! This module reads, modifies and applies POSIX ACLs. The kernel stores them
+//! in the `system.posix_acl_access` and `system.posix_acl_default` extended
+//! attributes; the access ACL extends a file's permission bits, while the
+//! default ACL of a directory is inherited by everything created in it.
+//!
+//! ACLs are written in the short text form of setfacl(1), with numeric user
+//! and group IDs: `user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::---`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::acl::{self, AclTag};
+//!
+//! fn main() -> Result<(), rastos::fs::FsError> {
+//!     // Give user 1000 read access to a file
+//!     let mut entries = acl::get_acl("shared.txt")?;
+//!     entries.set(AclTag::User(1000), acl::READ);
+//!     acl::set_acl("shared.txt", &entries.with_mask())?;
+//!
+//!     // Make new files in a directory group-writable
+//!     let default = "user::rwx,group::rwx,other::r-x".parse()?;
+//!     acl::set_default_acl("shared", &default)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{xattr, FsError, Result};
+
+/// Extended attribute holding the access ACL
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// Extended attribute holding the default ACL of a directory
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Read permission
+pub const READ: u8 = 0o4;
+
+/// Write permission
+pub const WRITE: u8 = 0o2;
+
+/// Execute or search permission
+pub const EXECUTE: u8 = 0o1;
+
+/// Version of the extended attribute encoding
+const ACL_XATTR_VERSION: u32 = 2;
+
+/// ID stored for entries without a qualifier
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Who an ACL entry applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AclTag {
+    /// The file's owner
+    UserObj,
+    /// A named user
+    User(u32),
+    /// The file's group
+    GroupObj,
+    /// A named group
+    Group(u32),
+    /// Upper bound for named entries and the owning group
+    Mask,
+    /// Everyone else
+    Other,
+}
+
+impl AclTag {
+    fn code(self) -> u16 {
+        match self {
+            Self::UserObj => 0x01,
+            Self::User(_) => 0x02,
+            Self::GroupObj => 0x04,
+            Self::Group(_) => 0x08,
+            Self::Mask => 0x10,
+            Self::Other => 0x20,
+        }
+    }
+
+    fn from_code(code: u16, id: u32) -> Result<Self> {
+        match code {
+            0x01 => Ok(Self::UserObj),
+            0x02 => Ok(Self::User(id)),
+            0x04 => Ok(Self::GroupObj),
+            0x08 => Ok(Self::Group(id)),
+            0x10 => Ok(Self::Mask),
+            0x20 => Ok(Self::Other),
+            _ => Err(FsError::invalid_acl(format!("unknown entry tag {:#x}", code))),
+        }
+    }
+
+    fn id(self) -> u32 {
+        match self {
+            Self::User(id) | Self::Group(id) => id,
+            _ => ACL_UNDEFINED_ID,
+        }
+    }
+
+    /// Check whether the entry names a specific user or group
+    pub fn is_named(self) -> bool {
+        matches!(self, Self::User(_) | Self::Group(_))
+    }
+}
+
+/// One ACL entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AclEntry {
+    /// Who the entry applies to
+    pub tag: AclTag,
+    /// Combination of [`READ`], [`WRITE`] and [`EXECUTE`]
+    pub perm: u8,
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, qualifier) = match self.tag {
+            AclTag::UserObj => ("user", String::new()),
+            AclTag::User(id) => ("user", id.to_string()),
+            AclTag::GroupObj => ("group", String::new()),
+            AclTag::Group(id) => ("group", id.to_string()),
+            AclTag::Mask => ("mask", String::new()),
+            AclTag::Other => ("other", String::new()),
+        };
+        let bit = |flag: u8, c: char| if self.perm & flag != 0 { c } else { '-' };
+        write!(f, "{}:{}:{}{}{}", kind, qualifier, bit(READ, 'r'), bit(WRITE, 'w'), bit(EXECUTE, 'x'))
+    }
+}
+
+impl FromStr for AclEntry {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || FsError::invalid_acl(format!("malformed entry '{}'", s));
+        let mut fields = s.trim().splitn(3, ':');
+        let (kind, qualifier, perms) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(qualifier), Some(perms)) => (kind, qualifier, perms),
+            // `other` and `mask` may omit the empty qualifier
+            (Some(kind), Some(perms), None) => (kind, "", perms),
+            _ => return Err(invalid()),
+        };
+        let id = || qualifier.parse::<u32>().map_err(|_| invalid());
+        let tag = match (kind, qualifier.is_empty()) {
+            ("user" | "u", true) => AclTag::UserObj,
+            ("user" | "u", false) => AclTag::User(id()?),
+            ("group" | "g", true) => AclTag::GroupObj,
+            ("group" | "g", false) => AclTag::Group(id()?),
+            ("mask" | "m", true) => AclTag::Mask,
+            ("other" | "o", true) => AclTag::Other,
+            _ => return Err(invalid()),
+        };
+        let mut perm = 0;
+        for c in perms.chars() {
+            perm |= match c {
+                'r' => READ,
+                'w' => WRITE,
+                'x' => EXECUTE,
+                '-' => 0,
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(Self { tag, perm })
+    }
+}
+
+/// A POSIX access control list
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// Create the minimal ACL equivalent to permission bits `mode`
+    pub fn from_mode(mode: u32) -> Self {
+        let bits = |shift: u32| ((mode >> shift) & 0o7) as u8;
+        Self::default()
+            .with_entry(AclTag::UserObj, bits(6))
+            .with_entry(AclTag::GroupObj, bits(3))
+            .with_entry(AclTag::Other, bits(0))
+    }
+
+    /// Add or replace an entry
+    pub fn with_entry(mut self, tag: AclTag, perm: u8) -> Self {
+        self.set(tag, perm);
+        self
+    }
+
+    /// Recompute the mask from the entries it limits
+    ///
+    /// Minimal ACLs need no mask and are returned unchanged.
+    pub fn with_mask(mut self) -> Self {
+        let limited = self
+            .entries
+            .iter()
+            .filter(|e| e.tag.is_named() || e.tag == AclTag::GroupObj)
+            .fold(0, |mask, e| mask | e.perm);
+        if self.entries.iter().any(|e| e.tag.is_named()) {
+            self.set(AclTag::Mask, limited);
+        }
+        self
+    }
+
+    /// Entries in canonical order
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    /// Get the permissions of an entry
+    pub fn get(&self, tag: AclTag) -> Option<u8> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// Add or replace an entry
+    pub fn set(&mut self, tag: AclTag, perm: u8) {
+        let perm = perm & (READ | WRITE | EXECUTE);
+        match self.entries.binary_search_by_key(&tag, |e| e.tag) {
+            Ok(index) => self.entries[index].perm = perm,
+            Err(index) => self.entries.insert(index, AclEntry { tag, perm }),
+        }
+    }
+
+    /// Remove an entry, returning whether it existed
+    pub fn remove(&mut self, tag: AclTag) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.tag != tag);
+        self.entries.len() != before
+    }
+
+    /// Check whether the ACL only mirrors the permission bits
+    pub fn is_minimal(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|e| matches!(e.tag, AclTag::UserObj | AclTag::GroupObj | AclTag::Other))
+    }
+
+    /// Permission bits the kernel derives from the ACL
+    ///
+    /// With a mask, the group bits show the mask rather than the owning group.
+    pub fn mode(&self) -> u32 {
+        let perm = |tag| u32::from(self.get(tag).unwrap_or(0));
+        let group = self.get(AclTag::Mask).map_or(perm(AclTag::GroupObj), u32::from);
+        perm(AclTag::UserObj) << 6 | group << 3 | perm(AclTag::Other)
+    }
+
+    /// Check the ACL is one the kernel accepts
+    pub fn validate(&self) -> Result<()> {
+        for required in [AclTag::UserObj, AclTag::GroupObj, AclTag::Other] {
+            if self.get(required).is_none() {
+                return Err(FsError::invalid_acl(format!("missing {} entry", AclEntry { tag: required, perm: 0 })));
+            }
+        }
+        if !self.is_minimal() && self.get(AclTag::Mask).is_none() {
+            return Err(FsError::invalid_acl("named entries require a mask entry"));
+        }
+        Ok(())
+    }
+
+    /// Decode the extended attribute representation
+    pub fn from_bytes(value: &[u8]) -> Result<Self> {
+        let header = value
+            .get(..4)
+            .map(|h| u32::from_le_bytes([h[0], h[1], h[2], h[3]]));
+        if header != Some(ACL_XATTR_VERSION) || !(value.len() - 4).is_multiple_of(8) {
+            return Err(FsError::invalid_acl(format!("unsupported encoding of {} bytes", value.len())));
+        }
+        let mut acl = Self::default();
+        for entry in value[4..].chunks_exact(8) {
+            let code = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            acl.set(AclTag::from_code(code, id)?, perm as u8);
+        }
+        Ok(acl)
+    }
+
+    /// Encode as an extended attribute value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            value.extend(entry.tag.code().to_le_bytes());
+            value.extend(u16::from(entry.perm).to_le_bytes());
+            value.extend(entry.tag.id().to_le_bytes());
+        }
+        value
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, entry) in self.entries.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Acl {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut acl = Self::default();
+        for entry in s.split([',', '\n']).map(str::trim).filter(|e| !e.is_empty() && !e.starts_with('#')) {
+            let entry: AclEntry = entry.parse()?;
+            acl.set(entry.tag, entry.perm);
+        }
+        Ok(acl)
+    }
+}
+
+/// Get the access ACL of a file
+///
+/// Files without an extended ACL, or on filesystems without ACL support,
+/// get the minimal ACL matching their permission bits.
+pub fn get_acl<P: AsRef<Path>>(path: P) -> Result<Acl> {
+    let path = path.as_ref();
+    match xattr::get(path, ACCESS_XATTR) {
+        Ok(Some(value)) => Acl::from_bytes(&value),
+        Ok(None) | Err(FsError::NotSupported(_)) => Ok(Acl::from_mode(fs::symlink_metadata(path)?.mode())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Apply an access ACL to a file
+///
+/// A minimal ACL is applied as plain permission bits, which also works on
+/// filesystems without ACL support.
+pub fn set_acl<P: AsRef<Path>>(path: P, acl: &Acl) -> Result<()> {
+    let path = path.as_ref();
+    acl.validate()?;
+    if acl.is_minimal() {
+        // Keep setuid, setgid and sticky bits, which ACLs do not carry
+        let special = fs::metadata(path)?.mode() & 0o7000;
+        fs::set_permissions(path, fs::Permissions::from_mode(special | acl.mode()))?;
+        return match xattr::remove(path, ACCESS_XATTR) {
+            Err(FsError::NotSupported(_)) => Ok(()),
+            result => result.map(drop),
+        };
+    }
+    xattr::set(path, ACCESS_XATTR, acl.to_bytes())
+}
+
+/// Get the default ACL of a directory, `None` if it has none
+pub fn get_default_acl<P: AsRef<Path>>(path: P) -> Result<Option<Acl>> {
+    match xattr::get(path, DEFAULT_XATTR) {
+        Ok(value) => value.map(|v| Acl::from_bytes(&v)).transpose(),
+        Err(FsError::NotSupported(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Set the default ACL of a directory
+pub fn set_default_acl<P: AsRef<Path>>(path: P, acl: &Acl) -> Result<()> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Err(FsError::invalid_path("Only directories have default ACLs"));
+    }
+    acl.validate()?;
+    xattr::set(path, DEFAULT_XATTR, acl.to_bytes())
+}
+
+/// Remove the default ACL of a directory, returning whether it had one
+pub fn remove_default_acl<P: AsRef<Path>>(path: P) -> Result<bool> {
+    match xattr::remove(path, DEFAULT_XATTR) {
+        Err(FsError::NotSupported(_)) => Ok(false),
+        result => result,
+    }
+}
+
+/// Copy the access ACL, and for directories the default ACL, of `from` to `to`
+///
+/// Copying to a filesystem without ACL support only fails if extended
+/// entries would be lost.
+pub fn copy_acl<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+    set_acl(to, &get_acl(from)?)?;
+    if to.is_dir() {
+        match get_default_acl(from)? {
+            Some(default) => set_default_acl(to, &default)?,
+            None => {
+                remove_default_acl(to)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_acl_text_and_binary() -> Result<()> {
+        let acl: Acl = "user::rwx,u:1000:r-x,group::r--,other::---".parse()?;
+        assert!(acl.validate().is_err());
+        let acl = acl.with_mask();
+        assert_eq!(acl.to_string(), "user::rwx,user:1000:r-x,group::r--,mask::r-x,other::---");
+        assert_eq!(acl.mode(), 0o750);
+        assert!(!acl.is_minimal());
+        assert_eq!(Acl::from_bytes(&acl.to_bytes())?, acl);
+        assert_eq!(acl.to_bytes().len(), 4 + 5 * 8);
+
+        assert_eq!(Acl::from_mode(0o100644).to_string(), "user::rw-,group::r--,other::r--");
+        assert!("user:alice:rwx".parse::<AclEntry>().is_err());
+        assert!("other::rwz".parse::<AclEntry>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_acl() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("test.txt");
+        fs::write(&file_path, "test")?;
+
+        set_acl(&file_path, &Acl::from_mode(0o640))?;
+        assert_eq!(fs::metadata(&file_path)?.mode() & 0o777, 0o640);
+        assert!(get_acl(&file_path)?.is_minimal());
+
+        let extended = Acl::from_mode(0o640).with_entry(AclTag::User(65534), READ).with_mask();
+        match set_acl(&file_path, &extended) {
+            // tmpfs without CONFIG_TMPFS_POSIX_ACL
+            Err(FsError::NotSupported(_)) => return Ok(()),
+            result => result?,
+        }
+        assert_eq!(get_acl(&file_path)?, extended);
+
+        let default = Acl::from_mode(0o770);
+        set_default_acl(dir.path(), &default)?;
+        assert_eq!(get_default_acl(dir.path())?, Some(default));
+        assert!(set_default_acl(&file_path, &Acl::from_mode(0o770)).is_err());
+        assert!(remove_default_acl(dir.path())?);
+        Ok(())
+    }
+}
@@ -1,41 +1,70 @@
 //! Btrfs subvolume and snapshot operations
 //!
 //! This module provides functions for working with Btrfs subvolumes and snapshots
-//! using the `btrfsutil-rs` crate.
+//! using the `btrfsutil` crate, for managing quota groups (qgroups) with
+//! the `btrfs` tool, which libbtrfsutil does not cover, and for tuning the
+//! compression and copy-on-write behaviour of individual files and subvolumes.
 
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
 use thiserror::Error;
-use btrfsutil_rs::{BtrfsUtil, SubvolumeInfo, BtrfsUtilError};
+use btrfsutil::qgroup::QgroupInherit;
+use btrfsutil::subvolume::{
+    DeleteFlags, SnapshotFlags, Subvolume, SubvolumeInfo, SubvolumeIterator, SubvolumeIteratorFlags,
+};
+use btrfsutil::BtrfsUtilError;
 
-use crate::fs::FsError;
+use crate::fs::{xattr, FsError};
+
+/// Extended attribute backing the `compression` property
+pub const COMPRESSION_XATTR: &str = "btrfs.compression";
+
+/// `_IOR('f', 1, long)`: read inode flags
+const FS_IOC_GETFLAGS: u64 = 0x8008_6601;
+
+/// `_IOW('f', 2, long)`: write inode flags
+const FS_IOC_SETFLAGS: u64 = 0x4008_6602;
+
+/// Inode flag disabling copy-on-write, and with it checksums and compression
+const FS_NOCOW_FL: libc::c_int = 0x0080_0000;
 
 /// Errors that can occur during Btrfs operations
 #[derive(Debug, Error)]
 pub enum BtrfsError {
+    /// A Btrfs operation that failed
     #[error("Btrfs operation failed: {0}")]
     OperationFailed(String),
     
+    /// A path that cannot name a subvolume
     #[error("Invalid subvolume path: {0}")]
     InvalidPath(String),
     
+    /// libbtrfsutil error
     #[error("Btrfs error: {0}")]
     BtrfsUtil(#[from] BtrfsUtilError),
     
+    /// No subvolume at the path
     #[error("Subvolume not found: {0}")]
     SubvolumeNotFound(PathBuf),
     
+    /// A path that is not UTF-8
     #[error("Path is not a valid UTF-8 string")]
     InvalidUtf8,
     
+    /// I/O error
     #[error(transparent)]
     Io(#[from] std::io::Error),
     
+    /// A subvolume ID that does not exist
     #[error("Invalid subvolume ID: {0}")]
     InvalidSubvolumeId(u64),
     
-    #[error(transparent)]
-    Btrfs(#[from] BtrfsCrateError),
-    
+    /// Filesystem error
     #[error(transparent)]
     Fs(#[from] FsError),
 }
@@ -45,7 +74,7 @@ pub type Result<T> = std::result::Result<T, BtrfsError>;
 
 /// Check if a path is a Btrfs subvolume
 pub fn is_subvolume<P: AsRef<Path>>(path: P) -> bool {
-    BtrfsUtil::is_subvolume(path).unwrap_or(false)
+    Subvolume::is_subvolume(path.as_ref()).is_ok()
 }
 
 /// Create a new Btrfs subvolume
@@ -57,7 +86,7 @@ pub fn create_subvolume<P: AsRef<Path>>(path: P) -> Result<()> {
         std::fs::create_dir_all(parent).map_err(BtrfsError::Io)?;
     }
     
-    BtrfsUtil::create_subvolume(path, false, None)
+    Subvolume::create(path, None::<QgroupInherit>)
         .map_err(|e| BtrfsError::OperationFailed(format!(
             "Failed to create subvolume at {}: {}", 
             path.display(), 
@@ -81,7 +110,9 @@ pub fn create_snapshot<S: AsRef<Path>, D: AsRef<Path>>(
         std::fs::create_dir_all(parent).map_err(BtrfsError::Io)?;
     }
     
-    BtrfsUtil::create_snapshot(source, dest, read_only, None)
+    let flags = if read_only { SnapshotFlags::READ_ONLY } else { SnapshotFlags::empty() };
+    Subvolume::get(source)
+        .and_then(|subvolume| subvolume.snapshot(dest, flags, None::<QgroupInherit>))
         .map_err(|e| BtrfsError::OperationFailed(format!(
             "Failed to create snapshot from {} to {}: {}", 
             source.display(), 
@@ -100,7 +131,8 @@ pub fn delete_subvolume<P: AsRef<Path>>(path: P) -> Result<()> {
         return Err(BtrfsError::SubvolumeNotFound(path.to_path_buf()));
     }
     
-    BtrfsUtil::delete_subvolume(path, None)
+    Subvolume::get(path)
+        .and_then(|subvolume| subvolume.delete(DeleteFlags::empty()))
         .map_err(|e| BtrfsError::OperationFailed(format!(
             "Failed to delete subvolume at {}: {}", 
             path.display(), 
@@ -110,18 +142,20 @@ pub fn delete_subvolume<P: AsRef<Path>>(path: P) -> Result<()> {
 
 /// List all subvolumes under a given path
 pub fn list_subvolumes<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
-    let subvols = BtrfsUtil::list_subvolumes(path, true, None)
-        .map_err(|e| BtrfsError::OperationFailed(e.to_string()))?;
-    
-    Ok(subvols.into_iter()
-        .map(|(_, path)| PathBuf::from(path))
-        .collect())
+    SubvolumeIterator::new(path.as_ref(), None::<SubvolumeIteratorFlags>)
+        .and_then(|subvols| {
+            subvols
+                .map(|subvol| subvol.map(|subvol| subvol.path().to_path_buf()))
+                .collect()
+        })
+        .map_err(|e| BtrfsError::OperationFailed(e.to_string()))
 }
 
 /// Get information about a subvolume
 pub fn get_subvolume_info<P: AsRef<Path>>(path: P) -> Result<SubvolumeInfo> {
     let path = path.as_ref();
-    BtrfsUtil::subvolume_info(path, None)
+    Subvolume::get(path)
+        .and_then(|subvolume| subvolume.info())
         .map_err(|e| BtrfsError::OperationFailed(format!(
             "Failed to get subvolume info for {}: {}", 
             path.display(), 
@@ -137,7 +171,8 @@ pub fn set_subvolume_readonly<P: AsRef<Path>>(path: P, read_only: bool) -> Resul
         return Err(BtrfsError::SubvolumeNotFound(path.to_path_buf()));
     }
     
-    BtrfsUtil::set_subvolume_read_only(path, read_only, None)
+    Subvolume::get(path)
+        .and_then(|subvolume| subvolume.set_ro(read_only))
         .map_err(|e| BtrfsError::OperationFailed(format!(
             "Failed to set read-only flag for {}: {}", 
             path.display(), 
@@ -145,6 +180,363 @@ pub fn set_subvolume_readonly<P: AsRef<Path>>(path: P, read_only: bool) -> Resul
         )))
 }
 
+/// Compression algorithm of a file or subvolume, as in `btrfs property set`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Never compress, even if the filesystem is mounted with `compress`
+    None,
+    /// zlib, with an optional level from 1 to 9
+    Zlib(Option<u8>),
+    /// LZO, fast with a low ratio
+    Lzo,
+    /// zstd, with an optional level from 1 to 15
+    Zstd(Option<u8>),
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::Lzo => f.write_str("lzo"),
+            Self::Zlib(None) => f.write_str("zlib"),
+            Self::Zlib(Some(level)) => write!(f, "zlib:{}", level),
+            Self::Zstd(None) => f.write_str("zstd"),
+            Self::Zstd(Some(level)) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BtrfsError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BtrfsError::OperationFailed(format!("Invalid compression: {}", s));
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        match (algorithm, level) {
+            // Older kernels report "no" for disabled compression
+            ("none" | "no", None) => Ok(Self::None),
+            ("lzo", None) => Ok(Self::Lzo),
+            ("zlib", Some(1..=9) | None) => Ok(Self::Zlib(level)),
+            ("zstd", Some(1..=15) | None) => Ok(Self::Zstd(level)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Set the compression property of a file, directory or subvolume
+///
+/// Only data written afterwards is affected; new files in a directory inherit
+/// the property. Existing data can be recompressed with
+/// `btrfs filesystem defragment -c`.
+pub fn set_compression<P: AsRef<Path>>(path: P, compression: Compression) -> Result<()> {
+    xattr::set(path, COMPRESSION_XATTR, compression.to_string())?;
+    Ok(())
+}
+
+/// Compression property of a file, directory or subvolume
+///
+/// Returns `None` if it follows the mount options.
+pub fn get_compression<P: AsRef<Path>>(path: P) -> Result<Option<Compression>> {
+    match xattr::get_string(path, COMPRESSION_XATTR)? {
+        Some(value) if !value.is_empty() => value.parse().map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Remove the compression property, so the mount options apply again
+pub fn clear_compression<P: AsRef<Path>>(path: P) -> Result<()> {
+    xattr::remove(path, COMPRESSION_XATTR)?;
+    Ok(())
+}
+
+/// Turn copy-on-write off or on for a file or directory
+///
+/// Btrfs only changes the attribute of empty files; set it on a directory to
+/// have files created in it inherit it. Disabling copy-on-write also
+/// disables checksums and compression, which suits databases, VM images
+/// and swapfiles.
+pub fn set_nocow<P: AsRef<Path>>(path: P, nocow: bool) -> Result<()> {
+    let path = path.as_ref();
+    let file = open_for_flags(path)?;
+    let mut flags = get_flags(&file, path)?;
+    if nocow == (flags & FS_NOCOW_FL != 0) {
+        return Ok(());
+    }
+    if nocow {
+        flags |= FS_NOCOW_FL;
+    } else {
+        flags &= !FS_NOCOW_FL;
+    }
+    // SAFETY: FS_IOC_SETFLAGS reads an int, which `flags` is, and it
+    // outlives the call
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags as *const libc::c_int) } == -1 {
+        return Err(flags_error(io::Error::last_os_error(), path));
+    }
+    Ok(())
+}
+
+/// Check whether copy-on-write is disabled for a file or directory
+pub fn is_nocow<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let file = open_for_flags(path)?;
+    Ok(get_flags(&file, path)? & FS_NOCOW_FL != 0)
+}
+
+fn open_for_flags(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path).into(),
+        _ => BtrfsError::Io(e),
+    })
+}
+
+fn get_flags(file: &File, path: &Path) -> Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    // SAFETY: FS_IOC_GETFLAGS writes an int, which `flags` is
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags as *mut libc::c_int) } == -1 {
+        return Err(flags_error(io::Error::last_os_error(), path));
+    }
+    Ok(flags)
+}
+
+fn flags_error(e: io::Error, path: &Path) -> BtrfsError {
+    match e.raw_os_error() {
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) => {
+            FsError::not_supported(format!("inode flags on {}", path.display())).into()
+        }
+        _ => BtrfsError::Io(e),
+    }
+}
+
+/// Identifier of a quota group, written `level/id`
+///
+/// Level 0 qgroups track a single subvolume and share its ID; higher levels
+/// group other qgroups so that, for example, all snapshots of a subvolume can
+/// be limited together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QgroupId {
+    /// Level in the qgroup hierarchy
+    pub level: u16,
+    /// ID within the level
+    pub id: u64,
+}
+
+impl QgroupId {
+    /// Create a qgroup ID
+    pub fn new(level: u16, id: u64) -> Self {
+        Self { level, id }
+    }
+    
+    /// The level 0 qgroup of the subvolume with the given ID
+    pub fn subvolume(id: u64) -> Self {
+        Self::new(0, id)
+    }
+}
+
+impl fmt::Display for QgroupId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.level, self.id)
+    }
+}
+
+impl FromStr for QgroupId {
+    type Err = BtrfsError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BtrfsError::OperationFailed(format!("Invalid qgroup ID: {}", s));
+        let (level, id) = s.split_once('/').ok_or_else(invalid)?;
+        Ok(Self {
+            level: level.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Space limits of a quota group
+///
+/// `None` removes the corresponding limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QgroupLimit {
+    /// Maximum bytes referenced, including extents shared with other qgroups
+    pub max_referenced: Option<u64>,
+    /// Maximum bytes held exclusively
+    pub max_exclusive: Option<u64>,
+}
+
+/// Space accounted to a quota group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QgroupUsage {
+    /// The qgroup
+    pub id: QgroupId,
+    /// Bytes referenced, including extents shared with other qgroups
+    pub referenced: u64,
+    /// Bytes only this qgroup references, which deleting it would free
+    pub exclusive: u64,
+    /// Configured limits
+    pub limit: QgroupLimit,
+    /// Higher-level qgroups this one is assigned to
+    pub parents: Vec<QgroupId>,
+}
+
+/// Enable quota accounting on the filesystem containing `path`
+///
+/// Existing data is accounted by a rescan that runs in the background;
+/// see [`rescan_quota`].
+pub fn enable_quota<P: AsRef<Path>>(path: P) -> Result<()> {
+    run_btrfs(&["quota", "enable"], path.as_ref()).map(drop)
+}
+
+/// Disable quota accounting on the filesystem containing `path`
+pub fn disable_quota<P: AsRef<Path>>(path: P) -> Result<()> {
+    run_btrfs(&["quota", "disable"], path.as_ref()).map(drop)
+}
+
+/// Recount the usage of all qgroups and wait until it is done
+pub fn rescan_quota<P: AsRef<Path>>(path: P) -> Result<()> {
+    run_btrfs(&["quota", "rescan", "-w"], path.as_ref()).map(drop)
+}
+
+/// Create a quota group on the filesystem containing `path`
+pub fn create_qgroup<P: AsRef<Path>>(path: P, qgroup: QgroupId) -> Result<()> {
+    run_btrfs(&["qgroup", "create", &qgroup.to_string()], path.as_ref()).map(drop)
+}
+
+/// Destroy a quota group on the filesystem containing `path`
+pub fn destroy_qgroup<P: AsRef<Path>>(path: P, qgroup: QgroupId) -> Result<()> {
+    run_btrfs(&["qgroup", "destroy", &qgroup.to_string()], path.as_ref()).map(drop)
+}
+
+/// Assign `child` to the higher-level qgroup `parent`
+///
+/// Btrfs marks the accounting inconsistent after an assignment, so a rescan
+/// is run before returning.
+pub fn assign_qgroup<P: AsRef<Path>>(path: P, child: QgroupId, parent: QgroupId) -> Result<()> {
+    if child.level >= parent.level {
+        return Err(BtrfsError::OperationFailed(format!(
+            "Cannot assign qgroup {} to {}: parent must have a higher level", 
+            child, 
+            parent
+        )));
+    }
+    run_btrfs(&["qgroup", "assign", "--rescan", &child.to_string(), &parent.to_string()], path.as_ref())
+        .map(drop)
+}
+
+/// Remove `child` from the higher-level qgroup `parent`
+pub fn unassign_qgroup<P: AsRef<Path>>(path: P, child: QgroupId, parent: QgroupId) -> Result<()> {
+    run_btrfs(&["qgroup", "remove", "--rescan", &child.to_string(), &parent.to_string()], path.as_ref())
+        .map(drop)
+}
+
+/// Set or clear the limits of a quota group
+pub fn set_qgroup_limit<P: AsRef<Path>>(path: P, qgroup: QgroupId, limit: QgroupLimit) -> Result<()> {
+    let path = path.as_ref();
+    let qgroup = qgroup.to_string();
+    let size = |max: Option<u64>| max.map_or_else(|| "none".to_string(), |bytes| bytes.to_string());
+    run_btrfs(&["qgroup", "limit", &size(limit.max_referenced), &qgroup], path)?;
+    run_btrfs(&["qgroup", "limit", "-e", &size(limit.max_exclusive), &qgroup], path)?;
+    Ok(())
+}
+
+/// Usage and limits of every quota group on the filesystem containing `path`
+pub fn qgroup_usage<P: AsRef<Path>>(path: P) -> Result<Vec<QgroupUsage>> {
+    let output = run_btrfs(&["qgroup", "show", "--raw", "-r", "-e", "-p"], path.as_ref())?;
+    parse_qgroup_show(&output)
+}
+
+/// Usage and limits of the level 0 qgroup of the subvolume containing `path`
+pub fn subvolume_usage<P: AsRef<Path>>(path: P) -> Result<QgroupUsage> {
+    let path = path.as_ref();
+    let output = run_btrfs(&["qgroup", "show", "--raw", "-r", "-e", "-p", "-f"], path)?;
+    parse_qgroup_show(&output)?
+        .into_iter()
+        .find(|usage| usage.id.level == 0)
+        .ok_or_else(|| BtrfsError::OperationFailed(format!(
+            "No qgroup found for {}; are quotas enabled?", 
+            path.display()
+        )))
+}
+
+/// Run `btrfs <args> <path>` and return its standard output
+fn run_btrfs(args: &[&str], path: &Path) -> Result<String> {
+    let output = Command::new("btrfs")
+        .args(args)
+        .arg(path)
+        .output()?;
+    
+    if !output.status.success() {
+        return Err(BtrfsError::OperationFailed(format!(
+            "btrfs {} {} failed: {}", 
+            args.join(" "), 
+            path.display(), 
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the table printed by `btrfs qgroup show --raw -r -e -p`
+///
+/// Columns are found by their headers, since newer btrfs-progs add a path
+/// column.
+fn parse_qgroup_show(output: &str) -> Result<Vec<QgroupUsage>> {
+    let mut lines = output.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split_whitespace().collect(),
+        None => return Ok(Vec::new()),
+    };
+    let column = |name: &str| {
+        header.iter().position(|h| *h == name).ok_or_else(|| BtrfsError::OperationFailed(format!(
+            "Missing column '{}' in qgroup listing", 
+            name
+        )))
+    };
+    let (id_col, rfer_col, excl_col) = (column("qgroupid")?, column("rfer")?, column("excl")?);
+    let (max_rfer_col, max_excl_col, parent_col) = (column("max_rfer")?, column("max_excl")?, column("parent")?);
+    
+    let mut usages = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Separator line under the header
+        if fields.first().is_some_and(|f| f.starts_with('-')) {
+            continue;
+        }
+        let field = |index: usize| fields.get(index).copied().ok_or_else(|| BtrfsError::OperationFailed(format!(
+            "Truncated qgroup listing line: {}", 
+            line
+        )));
+        let bytes = |index: usize| -> Result<u64> {
+            field(index)?.parse().map_err(|_| BtrfsError::OperationFailed(format!(
+                "Invalid size in qgroup listing line: {}", 
+                line
+            )))
+        };
+        let limit = |index: usize| -> Result<Option<u64>> {
+            match field(index)? {
+                "none" => Ok(None),
+                _ => bytes(index).map(Some),
+            }
+        };
+        let parents = match field(parent_col)? {
+            "-" | "---" => Vec::new(),
+            list => list.split(',').map(str::parse).collect::<Result<_>>()?,
+        };
+        usages.push(QgroupUsage {
+            id: field(id_col)?.parse()?,
+            referenced: bytes(rfer_col)?,
+            exclusive: bytes(excl_col)?,
+            limit: QgroupLimit {
+                max_referenced: limit(max_rfer_col)?,
+                max_exclusive: limit(max_excl_col)?,
+            },
+            parents,
+        });
+    }
+    Ok(usages)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -195,17 +587,50 @@ mod tests {
         set_subvolume_readonly(&subvol_path, true).unwrap();
         
         // Verify it's read-only
-        let info = get_subvolume_info(&subvol_path).unwrap();
-        assert!(info.readonly);
+        assert!(crate::sys::btrfs::is_read_only(&subvol_path).unwrap());
         
         // Set back to read-write
         set_subvolume_readonly(&subvol_path, false).unwrap();
         
         // Verify it's read-write
-        let info = get_subvolume_info(&subvol_path).unwrap();
-        assert!(!info.readonly);
+        assert!(!crate::sys::btrfs::is_read_only(&subvol_path).unwrap());
         
         // Clean up
         delete_subvolume(&subvol_path).unwrap();
     }
+    
+    #[test]
+    fn test_compression_property() {
+        for value in ["none", "lzo", "zlib:9", "zstd", "zstd:3"] {
+            assert_eq!(value.parse::<Compression>().unwrap().to_string(), value);
+        }
+        assert_eq!("no".parse::<Compression>().unwrap(), Compression::None);
+        assert!("zstd:22".parse::<Compression>().is_err());
+        assert!("lzo:1".parse::<Compression>().is_err());
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+    
+    #[test]
+    fn test_parse_qgroup_show() {
+        let output = "\
+Qgroupid    Referenced    Exclusive   Max referenced   Max exclusive   Parent   Path
+";
+        assert!(parse_qgroup_show(output).is_err());
+        
+        let output = "\
+qgroupid         rfer         excl     max_rfer     max_excl parent     path 
+--------         ----         ----     --------     -------- ------     ---- 
+0/5             16384        16384         none         none ---        <toplevel>
+0/257       104857600     65536000   1073741824         none 1/100      @snapshots/1
+1/100       104857600    104857600         none   2147483648 ---        <0 member qgroups>
+";
+        let usages = parse_qgroup_show(output).unwrap();
+        assert_eq!(usages.len(), 3);
+        assert_eq!(usages[1].id, QgroupId::subvolume(257));
+        assert_eq!(usages[1].exclusive, 65536000);
+        assert_eq!(usages[1].limit.max_referenced, Some(1073741824));
+        assert_eq!(usages[1].parents, vec![QgroupId::new(1, 100)]);
+        assert_eq!(usages[2].limit.max_exclusive, Some(2147483648));
+        assert!(usages[0].parents.is_empty());
+    }
 }
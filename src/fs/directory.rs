@@ -42,14 +42,16 @@
 //! # Directory Traversal
 //!
 //! The `list_dir` function returns a vector of `PathBuf`s for each entry in the directory.
-//! For more advanced directory traversal, consider using the `walkdir` crate which provides
-//! recursive directory iteration with more control over the traversal process.
+//! For recursive traversal with depth limits, glob filters and parallelism, use
+//! [`walk`](super::walk()).
 
 
-use std::fs::{self, ReadDir};
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
 use std::path::{Path, PathBuf};
 
-use super::{FsError, Result};
+use super::file_ops::RemoveOptions;
+use super::{trash, FsError, Result};
 
 /// Directory operations trait
 pub trait DirectoryOps {
@@ -161,6 +163,28 @@ pub fn create_dir_all<P: AsRef<Path>>(path: P) -> Result<()> {
     StdDirectory::create_all(path).map(|_| ())
 }
 
+/// Create a directory only its owner may use, or check an existing one
+///
+/// Missing parents are created as usual. An existing directory must be owned
+/// by the effective user and closed to everybody else, so that no other user
+/// can plant or swap files in it.
+pub fn create_private_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::Uid::effective().as_raw() || meta.mode() & 0o077 != 0 {
+        return Err(FsError::permission_denied(path));
+    }
+    Ok(())
+}
+
 /// Remove an empty directory
 pub fn remove_dir<P: AsRef<Path>>(path: P) -> Result<()> {
     let dir = StdDirectory::open(path)?;
@@ -169,7 +193,15 @@ pub fn remove_dir<P: AsRef<Path>>(path: P) -> Result<()> {
 
 /// Remove a directory and all its contents
 pub fn remove_dir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+    remove_dir_all_with_options(path, &RemoveOptions::default())
+}
+
+/// Remove a directory and all its contents, or move it to the trash
+pub fn remove_dir_all_with_options<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
     let dir = StdDirectory::open(path)?;
+    if options.trash {
+        return trash::trash(&dir.path).map(drop);
+    }
     dir.remove_all()
 }
 
@@ -193,7 +225,7 @@ mod tests {
         let file_path = dir_path.join("test.txt");
         File::create(&file_path)?;
         
-        let mut entries = list_dir(&dir_path)?;
+        let entries = list_dir(&dir_path)?;
         assert_eq!(entries.len(), 1);
         assert_eq!(entries[0], file_path);
         
@@ -216,4 +248,20 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_create_private_dir() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let private = dir.path().join("cache/private");
+        create_private_dir(&private)?;
+        assert_eq!(fs::metadata(&private)?.mode() & 0o777, 0o700);
+        create_private_dir(&private)?;
+
+        // Directories others can write are refused
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o777))?;
+        assert!(matches!(create_private_dir(&private), Err(FsError::PermissionDenied(_))));
+        Ok(())
+    }
 }
@@ -58,6 +58,21 @@ pub enum FsError {
     #[error("Directory not empty: {0}")]
     DirectoryNotEmpty(PathBuf),
 
+    /// Malformed or incomplete access control list
+    #[error("Invalid ACL: {0}")]
+    InvalidAcl(String),
+
+    /// A workspace or tree uses more space than allowed
+    #[error("{path} uses {used} bytes, over its limit of {limit} bytes")]
+    SizeLimitExceeded {
+        /// Directory over its limit
+        path: PathBuf,
+        /// Bytes used on disk
+        used: u64,
+        /// Allowed bytes
+        limit: u64,
+    },
+
     /// Other errors
     #[error(transparent)]
     Other(#[from] anyhow::Error),
@@ -93,6 +108,11 @@ impl FsError {
     pub fn directory_not_empty<P: Into<PathBuf>>(path: P) -> Self {
         Self::DirectoryNotEmpty(path.into())
     }
+
+    /// Create a new invalid ACL error
+    pub fn invalid_acl<S: Into<String>>(msg: S) -> Self {
+        Self::InvalidAcl(msg.into())
+    }
 }
 
 impl From<FsError> for std::io::Error {
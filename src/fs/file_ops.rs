@@ -10,6 +10,8 @@
 //! - **Cross-Platform**: Consistent behavior across different operating systems
 //! - **Detailed Errors**: Rich error information including paths and operation context
 //! - **Automatic Cleanup**: Uses RAII patterns to ensure resources are properly cleaned up
+//! - **Reflinks**: Copies share data extents with their source on Btrfs and XFS
+//! - **Access Control**: Copies can optionally preserve POSIX ACLs and extended attributes
 //!
 //! # Examples
 //!
@@ -43,20 +45,176 @@
 //!
 //! # Atomicity
 //!
-//! - `write`: Atomic on most platforms when the target file doesn't exist
+//! - `write`: Atomic; the contents go to a temporary file in the target's directory
+//!   which is synced and renamed over the target, so readers see the old or the new
+//!   contents but never a mix
 //! - `move_file`: Atomic on the same filesystem, falls back to copy+delete across filesystems
-//! - `copy_file`: Not guaranteed to be atomic
+//! - `copy_file`: Not guaranteed to be atomic; reflinked where the filesystem allows
 //! - `delete_file`: Atomic on all platforms
 
 use std::fs;
+use std::os::unix::fs::{fchown, lchown, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write};
 
-use super::{FsError, Result};
-use super::metadata::metadata;
+use log::debug;
+
+use super::{acl, trash, xattr, FsError, Result};
+use super::reflink::{copy_file_with_mode, ReflinkMode};
+
+/// How much of a write must reach the disk before it returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Leave flushing to the kernel; a crash may lose the write, but never tears it
+    None,
+    /// Sync the file contents before renaming it into place
+    Data,
+    /// Also sync the directory, so the rename itself survives a crash
+    #[default]
+    Full,
+}
+
+/// Options for atomic writes
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// What must be on disk when the write returns
+    pub durability: Durability,
+    /// Permission bits; an existing file's are kept by default, new files get `0o644`
+    pub mode: Option<u32>,
+}
+
+impl WriteOptions {
+    /// Set the durability
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Set the permission bits of the written file
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+}
+
+/// Options for copying files and directories
+///
+/// Permission bits are always copied.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// Whether copies keep the source's owner and group, which needs root
+    /// to give files to other users
+    pub preserve_ownership: bool,
+    /// Whether copies keep the source's access and modification times
+    pub preserve_timestamps: bool,
+    /// Whether to copy POSIX ACLs, including default ACLs of directories
+    pub preserve_acls: bool,
+    /// Whether to copy extended attributes such as capabilities and SELinux labels
+    pub preserve_xattrs: bool,
+    /// Whether copies share data extents with their source, as on Btrfs
+    pub reflink: ReflinkMode,
+}
+
+impl CopyOptions {
+    /// Preserve everything that can be preserved, like `cp -a`
+    pub fn archive() -> Self {
+        Self::default()
+            .with_ownership()
+            .with_timestamps()
+            .with_xattrs()
+            .with_acls()
+    }
+
+    /// Keep the owner and group of the source
+    pub fn with_ownership(mut self) -> Self {
+        self.preserve_ownership = true;
+        self
+    }
+
+    /// Keep the access and modification times of the source
+    pub fn with_timestamps(mut self) -> Self {
+        self.preserve_timestamps = true;
+        self
+    }
+
+    /// Copy POSIX ACLs along with the contents
+    pub fn with_acls(mut self) -> Self {
+        self.preserve_acls = true;
+        self
+    }
+
+    /// Copy extended attributes along with the contents
+    pub fn with_xattrs(mut self) -> Self {
+        self.preserve_xattrs = true;
+        self
+    }
+
+    /// Set whether copies are reflinked
+    pub fn with_reflink(mut self, reflink: ReflinkMode) -> Self {
+        self.reflink = reflink;
+        self
+    }
+
+    /// Copy the attributes selected by the options from `from` to `to`
+    fn apply(&self, from: &Path, to: &Path) -> Result<()> {
+        let source = fs::metadata(from)?;
+        // Ownership first: changing it drops capabilities and the
+        // set-user-ID and set-group-ID bits
+        if self.preserve_ownership {
+            match lchown(to, Some(source.uid()), Some(source.gid())) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    debug!("Cannot keep the owner of {}: {}", to.display(), e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        fs::set_permissions(to, source.permissions())?;
+        // Extended attributes next: they include the raw ACLs, which
+        // copy_acl then applies with proper fallbacks
+        if self.preserve_xattrs {
+            xattr::copy_all(from, to)?;
+        }
+        if self.preserve_acls {
+            acl::copy_acl(from, to)?;
+        }
+        // Last, since every other change touches the times
+        if self.preserve_timestamps {
+            let times = fs::FileTimes::new()
+                .set_accessed(source.accessed()?)
+                .set_modified(source.modified()?);
+            fs::File::open(to)?.set_times(times)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options for deleting files and directories
+#[derive(Debug, Clone, Default)]
+pub struct RemoveOptions {
+    /// Whether to move the entry to the trash instead of deleting it
+    pub trash: bool,
+}
+
+impl RemoveOptions {
+    /// Move to the trash instead of deleting, see [`trash`](super::trash::trash())
+    pub fn with_trash(mut self) -> Self {
+        self.trash = true;
+        self
+    }
+}
 
 /// Copy a file from source to destination
 pub fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
+    copy_file_with_options(from, to, &CopyOptions::default())
+}
+
+/// Copy a file from source to destination with custom options
+pub fn copy_file_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    options: &CopyOptions,
+) -> Result<u64> {
     let from = from.as_ref();
     let to = to.as_ref();
     
@@ -87,18 +245,31 @@ pub fn copy_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64>
     }
     
     // Perform the copy
-    fs::copy(from, &dest_path)
-        .map_err(|e| {
-            if e.kind() == io::ErrorKind::PermissionDenied {
+    let copied = copy_file_with_mode(from, &dest_path, options.reflink)
+        .map_err(|e| match e {
+            FsError::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => {
                 FsError::permission_denied(&dest_path)
-            } else {
-                e.into()
             }
-        })
+            e => e,
+        })?;
+    options.apply(from, &dest_path)?;
+    Ok(copied)
 }
 
 /// Move a file or directory from source to destination
+///
+/// When the move has to copy across filesystems, the copies keep the
+/// source's permissions, ownership and timestamps.
 pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    move_file_with_options(from, to, &CopyOptions::default().with_ownership().with_timestamps())
+}
+
+/// Move a file or directory, choosing what a copy across filesystems preserves
+pub fn move_file_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    options: &CopyOptions,
+) -> Result<()> {
     let from = from.as_ref();
     let to = to.as_ref();
     
@@ -115,7 +286,7 @@ pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
             Ok(_) => return Ok(()),
             Err(_) => {
                 // If rename fails (cross-device move), fall back to copy + delete
-                copy_dir_all(from, to)?;
+                copy_dir_all_with_options(from, to, options)?;
                 fs::remove_dir_all(from)?;
             }
         }
@@ -125,7 +296,7 @@ pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
             Ok(_) => return Ok(()),
             Err(_) => {
                 // If rename fails (cross-device move), fall back to copy + delete
-                copy_file(from, to)?;
+                copy_file_with_options(from, to, options)?;
                 fs::remove_file(from)?;
             }
         }
@@ -136,6 +307,11 @@ pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
 
 /// Delete a file
 pub fn delete_file<P: AsRef<Path>>(path: P) -> Result<()> {
+    delete_file_with_options(path, &RemoveOptions::default())
+}
+
+/// Delete a file, or move it to the trash
+pub fn delete_file_with_options<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
     let path = path.as_ref();
     
     if !path.exists() {
@@ -146,11 +322,23 @@ pub fn delete_file<P: AsRef<Path>>(path: P) -> Result<()> {
         return Err(FsError::invalid_path("Path is a directory, use remove_dir instead"));
     }
     
+    if options.trash {
+        return trash::trash(path).map(drop);
+    }
     fs::remove_file(path).map_err(Into::into)
 }
 
 /// Copy a directory and all its contents recursively
-fn copy_dir_all<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+pub fn copy_dir_all<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    copy_dir_all_with_options(from, to, &CopyOptions::default())
+}
+
+/// Copy a directory and all its contents recursively with custom options
+pub fn copy_dir_all_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+    options: &CopyOptions,
+) -> Result<()> {
     let from = from.as_ref();
     let to = to.as_ref();
     
@@ -164,12 +352,16 @@ fn copy_dir_all<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
         let target = to.join(entry.file_name());
         
         if file_type.is_dir() {
-            copy_dir_all(entry.path(), target)?;
+            copy_dir_all_with_options(entry.path(), target, options)?;
         } else {
-            fs::copy(entry.path(), target)?;
+            copy_file_with_mode(&entry.path(), &target, options.reflink)?;
+            options.apply(&entry.path(), &target)?;
         }
     }
     
+    // Applied last so a default ACL does not leak into the copied entries
+    options.apply(from, to)?;
+    
     Ok(())
 }
 
@@ -182,17 +374,84 @@ pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
 }
 
 /// Write a string to a file, creating it if it doesn't exist
+///
+/// The write is atomic and durable, see [`atomic_write`].
 pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    atomic_write(path, contents)
+}
+
+/// Atomically replace the contents of a file
+///
+/// Equivalent to [`atomic_write_with_options`] with the default options:
+/// fully durable, keeping the permissions of an existing file.
+pub fn atomic_write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    atomic_write_with_options(path, contents, &WriteOptions::default())
+}
+
+/// Atomically replace the contents of a file with custom options
+///
+/// The contents are written to a temporary file in the same directory, which
+/// is renamed over the target once complete, so the target never holds a
+/// partial write. An existing file keeps its owner and permissions; if the
+/// target is a symbolic link, the file it points to is replaced.
+pub fn atomic_write_with_options<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+    options: &WriteOptions,
+) -> Result<()> {
     let path = path.as_ref();
     
+    // Replace the link's target rather than the link
+    let path = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let existing = fs::metadata(&path).ok();
+    if existing.as_ref().is_some_and(|meta| meta.is_dir()) {
+        return Err(FsError::invalid_path("Path is a directory"));
+    }
+    
     // Create parent directories if they don't exist
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if !parent.exists() {
+        fs::create_dir_all(&parent)?;
+    }
+    
+    // Hidden, and in the same directory so the rename cannot cross filesystems
+    let file_name = path.file_name()
+        .ok_or_else(|| FsError::invalid_path("Invalid target filename"))?;
+    let mut temp = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name.to_string_lossy()))
+        .suffix(".tmp")
+        .tempfile_in(&parent)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => FsError::permission_denied(&parent),
+            _ => e.into(),
+        })?;
+    temp.write_all(contents.as_ref())?;
+    
+    let mode = options.mode
+        .or_else(|| existing.as_ref().map(|meta| meta.mode() & 0o7777))
+        .unwrap_or(0o644);
+    temp.as_file().set_permissions(fs::Permissions::from_mode(mode))?;
+    if let Some(meta) = &existing {
+        if let Err(e) = fchown(temp.as_file(), Some(meta.uid()), Some(meta.gid())) {
+            // Only root may give files away; the writer owns the new file then
+            debug!("Cannot keep the owner of {}: {}", path.display(), e);
         }
     }
     
-    fs::write(path, contents).map_err(Into::into)
+    if options.durability != Durability::None {
+        temp.as_file().sync_all()?;
+    }
+    temp.persist(&path).map_err(|e| FsError::from(e.error))?;
+    if options.durability == Durability::Full {
+        fs::File::open(&parent)?.sync_all()?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -200,6 +459,7 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
     use std::fs;
+    use std::os::unix::fs::PermissionsExt;
 
     #[test]
     fn test_file_operations() -> Result<()> {
@@ -230,6 +490,35 @@ mod tests {
         Ok(())
     }
     
+    #[test]
+    fn test_atomic_write() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("config/settings.toml");
+        
+        // New files get default permissions and missing parents
+        atomic_write(&path, "a = 1\n")?;
+        assert_eq!(read_to_string(&path)?, "a = 1\n");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o644);
+        
+        // Existing files keep theirs
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        atomic_write_with_options(&path, "a = 2\n", &WriteOptions::default().with_durability(Durability::Data))?;
+        assert_eq!(read_to_string(&path)?, "a = 2\n");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        
+        // Links are followed, not replaced
+        let link = dir.path().join("link.toml");
+        std::os::unix::fs::symlink(&path, &link)?;
+        write(&link, "a = 3\n")?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(read_to_string(&path)?, "a = 3\n");
+        
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path().join("config"))?.count(), 1);
+        assert!(write(dir.path(), "x").is_err());
+        Ok(())
+    }
+    
     #[test]
     fn test_directory_operations() -> Result<()> {
         let dir = tempdir()?;
@@ -249,4 +538,49 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_copy_preserving_acls() -> Result<()> {
+        let dir = tempdir()?;
+        let src_dir = dir.path().join("src");
+        let dest_dir = dir.path().join("dest");
+        fs::create_dir(&src_dir)?;
+        write(src_dir.join("test.txt"), "test")?;
+
+        let file_acl = acl::Acl::from_mode(0o640)
+            .with_entry(acl::AclTag::User(65534), acl::READ)
+            .with_mask();
+        match acl::set_acl(src_dir.join("test.txt"), &file_acl) {
+            // tmpfs without CONFIG_TMPFS_POSIX_ACL
+            Err(FsError::NotSupported(_)) => return Ok(()),
+            result => result?,
+        }
+        acl::set_default_acl(&src_dir, &acl::Acl::from_mode(0o750))?;
+
+        copy_dir_all_with_options(&src_dir, &dest_dir, &CopyOptions::default().with_acls())?;
+        assert_eq!(acl::get_acl(dest_dir.join("test.txt"))?, file_acl);
+        assert_eq!(acl::get_default_acl(&dest_dir)?, Some(acl::Acl::from_mode(0o750)));
+
+        // Without the option only the permission bits come along
+        copy_file(src_dir.join("test.txt"), dir.path().join("plain.txt"))?;
+        assert!(acl::get_acl(dir.path().join("plain.txt"))?.is_minimal());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_preserving_times() -> Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("unit.service"), "[Unit]\n")?;
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        fs::File::open(src.join("unit.service"))?.set_modified(old)?;
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o750))?;
+
+        let options = CopyOptions::default().with_ownership().with_timestamps();
+        copy_dir_all_with_options(&src, dir.path().join("dest"), &options)?;
+        assert_eq!(fs::metadata(dir.path().join("dest/unit.service"))?.modified()?, old);
+        assert_eq!(fs::metadata(dir.path().join("dest"))?.permissions().mode() & 0o777, 0o750);
+        Ok(())
+    }
 }
@@ -0,0 +1,293 @@
+//! File hashing and tree manifests for rastOS
+//!
+//! This module hashes files with SHA-256 or BLAKE3 in fixed-size chunks, so
+//! memory use does not depend on file size, and records whole directory
+//! trees in a [`Manifest`] that can later be checked with [`verify_manifest`]
+//! to find files that went missing, changed or appeared.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::hash::{verify_manifest, HashAlgorithm, Manifest};
+//!
+//! fn main() -> Result<(), rastos::fs::FsError> {
+//!     let manifest = Manifest::generate("/srv/backup/latest", HashAlgorithm::Blake3)?;
+//!     // ... later ...
+//!     let report = verify_manifest("/srv/backup/latest", &manifest)?;
+//!     for path in &report.changed {
+//!         println!("Changed: {}", path.display());
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::walk::{walk, WalkOptions};
+use super::{FsError, Result};
+
+/// Bytes read per chunk while hashing
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hash algorithm for files and manifests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// SHA-256, as used by pacman and OCI
+    #[default]
+    Sha256,
+    /// BLAKE3, several times faster on large trees
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(FsError::not_supported(format!("hash algorithm '{}'", s))),
+        }
+    }
+}
+
+/// Hex-encoded digest of everything `reader` yields
+pub fn hash_reader<R: Read>(mut reader: R, algorithm: HashAlgorithm) -> Result<String> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut sha256 = Sha256::new();
+    let mut blake3 = blake3::Hasher::new();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match algorithm {
+            HashAlgorithm::Sha256 => sha256.update(&buf[..n]),
+            HashAlgorithm::Blake3 => {
+                blake3.update(&buf[..n]);
+            }
+        }
+    }
+    Ok(match algorithm {
+        HashAlgorithm::Sha256 => sha256.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+        HashAlgorithm::Blake3 => blake3.finalize().to_hex().to_string(),
+    })
+}
+
+/// Hex-encoded digest of a file's contents
+pub fn hash_file<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> Result<String> {
+    let path = path.as_ref();
+    let file = fs::File::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => FsError::not_found(path),
+        std::io::ErrorKind::PermissionDenied => FsError::permission_denied(path),
+        _ => e.into(),
+    })?;
+    hash_reader(file, algorithm)
+}
+
+/// Hex-encoded SHA-256 of a file's contents
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    hash_file(path, HashAlgorithm::Sha256)
+}
+
+/// Hex-encoded BLAKE3 of a file's contents
+pub fn blake3_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    hash_file(path, HashAlgorithm::Blake3)
+}
+
+/// What a manifest records for one path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ManifestEntry {
+    /// Regular file
+    File {
+        /// Size in bytes
+        size: u64,
+        /// Hex-encoded digest of the contents
+        digest: String,
+    },
+    /// Directory
+    Dir,
+    /// Symbolic link, which is recorded but not followed
+    Symlink {
+        /// Link target
+        target: PathBuf,
+    },
+}
+
+impl ManifestEntry {
+    /// Record the entry at `path`
+    fn read(path: &Path, algorithm: HashAlgorithm) -> Result<Self> {
+        let meta = fs::symlink_metadata(path)?;
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            Ok(Self::Symlink {
+                target: fs::read_link(path)?,
+            })
+        } else if file_type.is_dir() {
+            Ok(Self::Dir)
+        } else if file_type.is_file() {
+            Ok(Self::File {
+                size: meta.len(),
+                digest: hash_file(path, algorithm)?,
+            })
+        } else {
+            Err(FsError::not_supported(format!("manifest entry for special file {}", path.display())))
+        }
+    }
+}
+
+/// Digests of every file in a directory tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Algorithm of the file digests
+    pub algorithm: HashAlgorithm,
+    /// Entries by path relative to the tree's root
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Record the tree below `root`
+    ///
+    /// Symbolic links are recorded with their target and not followed;
+    /// sockets, FIFOs and device nodes are skipped.
+    pub fn generate<P: AsRef<Path>>(root: P, algorithm: HashAlgorithm) -> Result<Self> {
+        let root = root.as_ref();
+        if !root.is_dir() {
+            return Err(FsError::not_found(root));
+        }
+        let mut entries = BTreeMap::new();
+        for entry in walk(root, WalkOptions::default())? {
+            let entry = entry?;
+            let file_type = entry.file_type;
+            if !(file_type.is_file() || file_type.is_dir() || file_type.is_symlink()) {
+                continue;
+            }
+            entries.insert(entry.relative, ManifestEntry::read(&entry.path, algorithm)?);
+        }
+        Ok(Self { algorithm, entries })
+    }
+
+    /// Total size of the recorded files
+    pub fn total_size(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|entry| match entry {
+                ManifestEntry::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// Differences between a tree and its manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestReport {
+    /// Recorded paths that no longer exist
+    pub missing: Vec<PathBuf>,
+    /// Paths whose type, size, contents or link target changed
+    pub changed: Vec<PathBuf>,
+    /// Paths that were not recorded
+    pub added: Vec<PathBuf>,
+}
+
+impl ManifestReport {
+    /// Check whether the tree matches the manifest exactly
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Compare the tree below `root` with a manifest
+pub fn verify_manifest<P: AsRef<Path>>(root: P, manifest: &Manifest) -> Result<ManifestReport> {
+    let root = root.as_ref();
+    let mut report = ManifestReport::default();
+    for (relative, expected) in &manifest.entries {
+        let path = root.join(relative);
+        if fs::symlink_metadata(&path).is_err() {
+            report.missing.push(relative.clone());
+            continue;
+        }
+        // Compare sizes first so changed files are usually caught without hashing
+        let size_changed = matches!(expected, ManifestEntry::File { size, .. }
+            if fs::symlink_metadata(&path)?.len() != *size);
+        if size_changed || ManifestEntry::read(&path, manifest.algorithm)? != *expected {
+            report.changed.push(relative.clone());
+        }
+    }
+
+    for entry in walk(root, WalkOptions::default())? {
+        let entry = entry?;
+        if !manifest.entries.contains_key(&entry.relative) {
+            report.added.push(entry.relative);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash_reader() -> Result<()> {
+        assert_eq!(
+            hash_reader(&b"abc"[..], HashAlgorithm::Sha256)?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hash_reader(&b""[..], HashAlgorithm::Blake3)?,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!("BLAKE3".parse::<HashAlgorithm>()?, HashAlgorithm::Blake3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_verification() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("etc"))?;
+        fs::write(dir.path().join("etc/hostname"), "rastos\n")?;
+        fs::write(dir.path().join("etc/motd"), "hello\n")?;
+        std::os::unix::fs::symlink("hostname", dir.path().join("etc/name"))?;
+
+        let manifest = Manifest::generate(dir.path(), HashAlgorithm::Sha256)?;
+        assert_eq!(manifest.entries.len(), 4);
+        assert_eq!(manifest.total_size(), 13);
+        assert!(verify_manifest(dir.path(), &manifest)?.is_intact());
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+
+        // Same size, different contents
+        fs::write(dir.path().join("etc/hostname"), "rastOS\n")?;
+        fs::remove_file(dir.path().join("etc/motd"))?;
+        fs::write(dir.path().join("etc/issue"), "")?;
+        let report = verify_manifest(dir.path(), &manifest)?;
+        assert_eq!(report.changed, vec![PathBuf::from("etc/hostname")]);
+        assert_eq!(report.missing, vec![PathBuf::from("etc/motd")]);
+        assert_eq!(report.added, vec![PathBuf::from("etc/issue")]);
+        Ok(())
+    }
+}
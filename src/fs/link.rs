@@ -0,0 +1,202 @@
+//! Symbolic links, hard links and confined path resolution for rastOS
+//!
+//! Besides thin wrappers around link creation and reading, this module
+//! provides [`canonicalize_within`], which resolves a path the way a process
+//! chrooted into `root` would see it. Absolute symlink targets are taken
+//! relative to `root`, and any path that would climb above `root` is
+//! rejected. Use it whenever a path inside an installed system, container
+//! image or backup is about to be written to or deleted, so that a malicious
+//! or stale link cannot redirect the operation to the host.
+
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::{FsError, Result};
+
+/// Symbolic links followed before resolution is considered a loop, as in Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// Create a symbolic link at `link` pointing to `target`
+///
+/// `target` is stored as given; relative targets are resolved against the
+/// link's directory when the link is followed.
+pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(target: P, link: Q) -> Result<()> {
+    let link = link.as_ref();
+    std::os::unix::fs::symlink(target, link).map_err(|e| link_error(e, link))
+}
+
+/// Create a hard link at `link` to the existing file `original`
+pub fn hardlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> Result<()> {
+    let original = original.as_ref();
+    let link = link.as_ref();
+    if fs::symlink_metadata(original).is_err() {
+        return Err(FsError::not_found(original));
+    }
+    fs::hard_link(original, link).map_err(|e| match e.raw_os_error() {
+        Some(libc::EXDEV) => FsError::not_supported(format!(
+            "hard link from {} to {} across filesystems",
+            link.display(),
+            original.display()
+        )),
+        _ => link_error(e, link),
+    })
+}
+
+/// Read the target of a symbolic link
+pub fn read_link<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path.as_ref();
+    fs::read_link(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        io::ErrorKind::InvalidInput => FsError::invalid_path(format!("{} is not a symbolic link", path.display())),
+        _ => e.into(),
+    })
+}
+
+/// Resolve `path` as seen from inside `root`, without escaping it
+///
+/// `path` may be absolute or relative; either way it is taken relative to
+/// `root`. Symbolic links are followed, with absolute targets resolved
+/// against `root` rather than the host's `/`. Components that do not exist
+/// yet are kept as they are, so the result can name a file about to be
+/// created. The returned path starts with the canonical form of `root`.
+///
+/// Fails with [`FsError::InvalidPath`] if a `..` component or a link target
+/// would leave `root`, or if links loop.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rastos::fs::canonicalize_within;
+///
+/// // /mnt/target/etc/localtime -> /usr/share/zoneinfo/UTC
+/// let zone = canonicalize_within("/mnt/target", "/etc/localtime").unwrap();
+/// assert_eq!(zone, std::path::Path::new("/mnt/target/usr/share/zoneinfo/UTC"));
+/// ```
+pub fn canonicalize_within<P: AsRef<Path>, Q: AsRef<Path>>(root: P, path: Q) -> Result<PathBuf> {
+    let root = root.as_ref();
+    let path = path.as_ref();
+    let root = root.canonicalize().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(root),
+        _ => e.into(),
+    })?;
+    let escape = || FsError::invalid_path(format!("{} escapes {}", path.display(), root.display()));
+
+    let mut pending: VecDeque<OsString> = VecDeque::new();
+    push_components(&mut pending, path, &escape)?;
+    let mut resolved = root.clone();
+    let mut depth = 0usize;
+    let mut links = 0;
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            if depth == 0 {
+                return Err(escape());
+            }
+            resolved.pop();
+            depth -= 1;
+            continue;
+        }
+        resolved.push(&name);
+        match fs::symlink_metadata(&resolved) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(FsError::invalid_path(format!(
+                        "Too many levels of symbolic links resolving {}",
+                        path.display()
+                    )));
+                }
+                let target = fs::read_link(&resolved)?;
+                resolved.pop();
+                if target.is_absolute() {
+                    resolved = root.clone();
+                    depth = 0;
+                }
+                // The target's components are resolved before the rest of the path
+                let mut target_components = VecDeque::new();
+                push_components(&mut target_components, &target, &escape)?;
+                while let Some(component) = target_components.pop_back() {
+                    pending.push_front(component);
+                }
+            }
+            Ok(_) => depth += 1,
+            // The remaining components are created later, if at all
+            Err(e) if e.kind() == io::ErrorKind::NotFound => depth += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Append the normal and `..` components of `path` to `components`
+fn push_components(components: &mut VecDeque<OsString>, path: &Path, escape: &dyn Fn() -> FsError) -> Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push_back(name.to_os_string()),
+            Component::ParentDir => components.push_back(OsString::from("..")),
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) => return Err(escape()),
+        }
+    }
+    Ok(())
+}
+
+fn link_error(e: io::Error, link: &Path) -> FsError {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => FsError::already_exists(link),
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(link),
+        io::ErrorKind::NotFound => FsError::not_found(link.parent().unwrap_or(link)),
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_links() -> Result<()> {
+        let dir = tempdir()?;
+        let original = dir.path().join("os-release");
+        fs::write(&original, "ID=rastos\n")?;
+
+        symlink("os-release", dir.path().join("release"))?;
+        assert_eq!(read_link(dir.path().join("release"))?, Path::new("os-release"));
+        assert!(matches!(symlink("other", dir.path().join("release")), Err(FsError::AlreadyExists(_))));
+        assert!(matches!(read_link(&original), Err(FsError::InvalidPath(_))));
+
+        hardlink(&original, dir.path().join("os-release.bak"))?;
+        assert_eq!(fs::read_to_string(dir.path().join("os-release.bak"))?, "ID=rastos\n");
+        assert!(matches!(hardlink(dir.path().join("missing"), dir.path().join("x")), Err(FsError::NotFound(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonicalize_within() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().canonicalize()?;
+        fs::create_dir_all(root.join("usr/share/zoneinfo"))?;
+        fs::create_dir(root.join("etc"))?;
+        fs::write(root.join("usr/share/zoneinfo/UTC"), "")?;
+        symlink("/usr/share/zoneinfo/UTC", root.join("etc/localtime"))?;
+        symlink("../usr", root.join("etc/usr"))?;
+        symlink("/", root.join("etc/host"))?;
+        symlink("../../../../../../etc", root.join("etc/escape"))?;
+        symlink("loop", root.join("etc/loop"))?;
+
+        assert_eq!(canonicalize_within(&root, "/etc/localtime")?, root.join("usr/share/zoneinfo/UTC"));
+        assert_eq!(canonicalize_within(&root, "etc/usr/share")?, root.join("usr/share"));
+        // An absolute target stays inside the root
+        assert_eq!(canonicalize_within(&root, "/etc/host/etc")?, root.join("etc"));
+        // Missing components are kept
+        assert_eq!(canonicalize_within(&root, "/etc/new/../hostname")?, root.join("etc/hostname"));
+
+        assert!(matches!(canonicalize_within(&root, "/../etc"), Err(FsError::InvalidPath(_))));
+        assert!(matches!(canonicalize_within(&root, "/etc/escape/passwd"), Err(FsError::InvalidPath(_))));
+        assert!(matches!(canonicalize_within(&root, "/etc/loop"), Err(FsError::InvalidPath(_))));
+        Ok(())
+    }
+}
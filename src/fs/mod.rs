@@ -8,7 +8,21 @@
 //!
 //! - **File Operations**: Create, read, write, copy, move, and delete files
 //! - **Directory Operations**: Create, list, and remove directories
+//! - **Disk Usage**: Apparent, allocated and Btrfs-shared sizes of directory trees
+//! - **Walking**: Recursive traversal with depth limits, glob filters, link policy and parallelism
+//! - **Links**: Create and read symbolic and hard links, and resolve paths confined to a root
 //! - **Metadata**: Get and set file metadata (permissions, timestamps, etc.)
+//! - **Ownership**: chmod/chown, recursively or by name, and umask-aware creation
+//! - **Extended Attributes**: Read and write xattrs such as capabilities and SELinux labels
+//! - **Access Control Lists**: Read, modify and apply POSIX ACLs, optionally preserved on copy
+//! - **Btrfs**: Subvolumes, snapshots, qgroup quotas, and per-file compression and NOCOW
+//! - **Reflinks**: Copy-on-write copies on Btrfs, falling back to byte copies elsewhere
+//! - **Sparse Files**: Copies keep holes; helpers detect sparseness and punch holes
+//! - **Trash**: freedesktop.org trash cans, as an opt-in alternative to permanent deletion
+//! - **Workspaces**: Per-task scratch directories with size limits and cleanup after crashes
+//! - **Secure Deletion**: Overwrite files before deleting them and wipe free space
+//! - **Hashing**: Streaming SHA-256/BLAKE3 digests and verifiable directory manifests
+//! - **Watching**: Async stream of debounced, filtered change events for files and directory trees
 //! - **Error Handling**: Comprehensive error types with detailed error messages
 //! - **Cross-platform**: Works consistently across different operating systems
 //!
@@ -51,26 +65,61 @@
 //! about what went wrong. The error type includes the operation that failed, the path involved,
 //! and the underlying system error if any.
 
+pub mod acl;
 mod btrfs;
 mod directory;
 mod error;
-mod file;
 mod file_ops;
+pub mod hash;
+mod link;
 mod metadata;
+mod permissions;
+mod reflink;
+mod shred;
+mod sparse;
+pub mod trash;
+mod usage;
 mod utils;
+mod walk;
+pub mod watch;
+mod workspace;
+pub mod xattr;
 
 pub use error::FsError;
-pub use file::FileOps;
-pub use file_ops::{copy_file, move_file, delete_file, read_to_string, write};
-pub use metadata::Metadata;
+pub use file_ops::{
+    copy_file, copy_file_with_options, copy_dir_all, copy_dir_all_with_options,
+    move_file, move_file_with_options, delete_file, delete_file_with_options, read_to_string,
+    write, atomic_write, atomic_write_with_options,
+    CopyOptions, Durability, RemoveOptions, WriteOptions,
+};
+pub use link::{canonicalize_within, hardlink, read_link, symlink};
+pub use metadata::{exists, is_dir, is_file, metadata, metadata_with_xattrs, Metadata};
+pub use permissions::{
+    chmod, chmod_recursive, chown, chown_by_name, chown_recursive, create_dir_with_options,
+    create_file_with_options, group_id, user_id, CreateOptions,
+};
+pub use usage::{disk_usage, shared_bytes, DiskUsage};
+pub use walk::{walk, SymlinkPolicy, Walk, WalkEntry, WalkOptions};
+pub use workspace::{cleanup_stale, Workspace, WorkspaceOptions};
+pub use reflink::{clone_range, copy_file_reflink, reflink, ReflinkMode};
+pub use shred::{shred, wipe_free_space};
+pub use sparse::{copy_sparse, data_ranges, is_sparse, punch_hole, write_sparse};
 pub use btrfs::{
     create_subvolume, create_snapshot, delete_subvolume, list_subvolumes,
-    set_subvolume_readonly, is_subvolume, BtrfsError
+    set_subvolume_readonly, is_subvolume, get_subvolume_info, BtrfsError,
+    enable_quota, disable_quota, rescan_quota, create_qgroup, destroy_qgroup,
+    assign_qgroup, unassign_qgroup, set_qgroup_limit, qgroup_usage, subvolume_usage,
+    QgroupId, QgroupLimit, QgroupUsage,
+    set_compression, get_compression, clear_compression, set_nocow, is_nocow, Compression,
+};
+pub use directory::{
+    DirectoryOps, list_dir, create_dir, create_dir_all, create_private_dir, remove_dir, remove_dir_all,
+    remove_dir_all_with_options,
 };
-pub use directory::{DirectoryOps, list_dir, create_dir, create_dir_all, remove_dir, remove_dir_all};
+pub use trash::trash;
 pub use utils::{
     glob, glob_with_options, GlobOptions,
-    temp_file, temp_dir, create_temp_file,
+    temp_file, temp_dir, create_temp_file, is_plain_name,
 };
 
 /// Type alias for the standard result type with our error type
@@ -0,0 +1,211 @@
+//! Ownership and permission management for rastOS
+//!
+//! This module changes the mode and ownership of files, one at a time or
+//! for whole trees, and creates files and directories with a chosen mode
+//! either filtered through the process umask, as `open(2)` does, or exactly
+//! as given.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{fchown, lchown, DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use super::walk::{walk, WalkOptions};
+use super::{FsError, Result};
+
+/// Options for creating files and directories
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Permission bits requested at creation; `0o666` for files and `0o777`
+    /// for directories by default, as in `open(2)` and `mkdir(2)`
+    pub mode: Option<u32>,
+    /// Whether to set `mode` exactly instead of letting the umask clear bits
+    pub ignore_umask: bool,
+    /// Owner to give the new entry
+    pub uid: Option<u32>,
+    /// Group to give the new entry
+    pub gid: Option<u32>,
+}
+
+impl CreateOptions {
+    /// Set the requested permission bits
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Apply the mode exactly, regardless of the umask
+    pub fn with_exact_mode(mut self) -> Self {
+        self.ignore_umask = true;
+        self
+    }
+
+    /// Set the owner and group of the new entry
+    pub fn with_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+}
+
+/// Change the permission bits of a file or directory
+///
+/// Symbolic links are followed; their own mode is meaningless on Linux.
+pub fn chmod<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+    let path = path.as_ref();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| path_error(e, path))
+}
+
+/// Change the permission bits of a tree, with separate modes for directories and files
+///
+/// Symbolic links are skipped, so a link inside the tree cannot redirect
+/// the change to a file outside it.
+pub fn chmod_recursive<P: AsRef<Path>>(path: P, dir_mode: u32, file_mode: u32) -> Result<()> {
+    let options = WalkOptions {
+        min_depth: 0,
+        ..WalkOptions::default()
+    };
+    for entry in walk(path, options)? {
+        let entry = entry?;
+        if entry.file_type.is_dir() {
+            chmod(&entry.path, dir_mode)?;
+        } else if !entry.file_type.is_symlink() {
+            chmod(&entry.path, file_mode)?;
+        }
+    }
+    Ok(())
+}
+
+/// Change the owner and group of a file or directory
+///
+/// `None` leaves the owner or group as it is. Symbolic links are changed
+/// themselves, not the file they point to.
+pub fn chown<P: AsRef<Path>>(path: P, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let path = path.as_ref();
+    lchown(path, uid, gid).map_err(|e| path_error(e, path))
+}
+
+/// Change the owner and group of a file or directory by name
+pub fn chown_by_name<P: AsRef<Path>>(path: P, user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let uid = user.map(user_id).transpose()?;
+    let gid = group.map(group_id).transpose()?;
+    chown(path, uid, gid)
+}
+
+/// Change the owner and group of a tree, including symbolic links themselves
+pub fn chown_recursive<P: AsRef<Path>>(path: P, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let options = WalkOptions {
+        min_depth: 0,
+        ..WalkOptions::default()
+    };
+    for entry in walk(path, options)? {
+        chown(entry?.path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// User ID of a user name from the system's user database
+pub fn user_id(name: &str) -> Result<u32> {
+    users::get_user_by_name(name)
+        .map(|user| user.uid())
+        .ok_or_else(|| FsError::invalid_path(format!("Unknown user '{}'", name)))
+}
+
+/// Group ID of a group name from the system's group database
+pub fn group_id(name: &str) -> Result<u32> {
+    users::get_group_by_name(name)
+        .map(|group| group.gid())
+        .ok_or_else(|| FsError::invalid_path(format!("Unknown group '{}'", name)))
+}
+
+/// Create a new file, failing if it exists
+pub fn create_file_with_options<P: AsRef<Path>>(path: P, options: &CreateOptions) -> Result<File> {
+    let path = path.as_ref();
+    let mode = options.mode.unwrap_or(0o666);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .map_err(|e| path_error(e, path))?;
+    if options.uid.is_some() || options.gid.is_some() {
+        fchown(&file, options.uid, options.gid).map_err(|e| path_error(e, path))?;
+    }
+    // Also after the owner change, which clears the set-user-ID and set-group-ID bits
+    if options.ignore_umask {
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    Ok(file)
+}
+
+/// Create a new directory, failing if it exists
+pub fn create_dir_with_options<P: AsRef<Path>>(path: P, options: &CreateOptions) -> Result<()> {
+    let path = path.as_ref();
+    let mode = options.mode.unwrap_or(0o777);
+    DirBuilder::new().mode(mode).create(path).map_err(|e| path_error(e, path))?;
+    if options.uid.is_some() || options.gid.is_some() {
+        chown(path, options.uid, options.gid)?;
+    }
+    if options.ignore_umask {
+        chmod(path, mode)?;
+    }
+    Ok(())
+}
+
+fn path_error(e: io::Error, path: &Path) -> FsError {
+    match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(path),
+        io::ErrorKind::AlreadyExists => FsError::already_exists(path),
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    #[test]
+    fn test_chmod_recursive() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("srv/www"))?;
+        fs::write(dir.path().join("srv/www/index.html"), "")?;
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("srv/passwd"))?;
+
+        chmod_recursive(dir.path().join("srv"), 0o750, 0o640)?;
+        assert_eq!(mode(&dir.path().join("srv")), 0o750);
+        assert_eq!(mode(&dir.path().join("srv/www")), 0o750);
+        assert_eq!(mode(&dir.path().join("srv/www/index.html")), 0o640);
+
+        // Giving files to ourselves always works
+        let uid = fs::metadata(dir.path())?.uid();
+        chown_recursive(dir.path().join("srv"), Some(uid), None)?;
+        assert!(matches!(chmod(dir.path().join("missing"), 0o600), Err(FsError::NotFound(_))));
+        assert!(user_id("no-such-user-rastos").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_with_options() -> Result<()> {
+        let dir = tempdir()?;
+        let key = dir.path().join("key");
+        create_file_with_options(&key, &CreateOptions::default().with_mode(0o666).with_exact_mode())?;
+        assert_eq!(mode(&key), 0o666);
+        assert!(matches!(
+            create_file_with_options(&key, &CreateOptions::default()),
+            Err(FsError::AlreadyExists(_))
+        ));
+
+        let private = dir.path().join("private");
+        create_dir_with_options(&private, &CreateOptions::default().with_mode(0o700))?;
+        assert_eq!(mode(&private) & 0o700, 0o700);
+        assert_eq!(mode(&private) & 0o077, 0);
+        Ok(())
+    }
+}
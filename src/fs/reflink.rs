@@ -0,0 +1,161 @@
+//! Reflink (copy-on-write) copies for rastOS
+//!
+//! On filesystems with shared extents, such as Btrfs and XFS, a reflink
+//! copy makes the destination point at the source's data instead of
+//! duplicating it: copying a multi-gigabyte image takes milliseconds and no
+//! space until either file is modified. Where reflinks are unavailable,
+//! for example across filesystems, the copy falls back to copying bytes,
+//! skipping the holes of sparse files.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use log::debug;
+
+use super::sparse::copy_sparse;
+use super::{FsError, Result};
+
+/// `_IOW(0x94, 9, int)`: share all extents of a file
+const FICLONE: u64 = 0x4004_9409;
+
+/// `_IOW(0x94, 13, struct file_clone_range)`: share a range of extents
+const FICLONERANGE: u64 = 0x4020_940d;
+
+/// Argument of [`FICLONERANGE`], `struct file_clone_range` in `linux/fs.h`
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+/// Whether copies share extents with their source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReflinkMode {
+    /// Reflink where the filesystem supports it, copy bytes otherwise
+    #[default]
+    Auto,
+    /// Fail unless the copy can be reflinked
+    Always,
+    /// Always copy bytes
+    Never,
+}
+
+/// Make `to` share all of `from`'s extents, without falling back
+///
+/// Fails with [`FsError::NotSupported`] if the filesystem cannot reflink
+/// the pair, such as when they are on different filesystems.
+pub fn reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
+    copy_file_with_mode(from.as_ref(), to.as_ref(), ReflinkMode::Always)
+}
+
+/// Copy a file, sharing extents with the source where possible
+///
+/// Falls back to a byte copy when reflinks are unavailable. The destination
+/// is created or truncated and gets the source's permissions. Returns the
+/// number of bytes copied.
+pub fn copy_file_reflink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
+    copy_file_with_mode(from.as_ref(), to.as_ref(), ReflinkMode::Auto)
+}
+
+/// Make `len` bytes of `to` at `dest_offset` share the extents of `from` at `src_offset`
+///
+/// Offsets and length must be multiples of the filesystem block size, except
+/// that the range may end at the end of the source file. A length of 0 clones
+/// everything from `src_offset` to the end of the source.
+pub fn clone_range(from: &File, to: &File, src_offset: u64, len: u64, dest_offset: u64) -> Result<()> {
+    let range = FileCloneRange {
+        src_fd: i64::from(from.as_raw_fd()),
+        src_offset,
+        src_length: len,
+        dest_offset,
+    };
+    // SAFETY: FICLONERANGE reads a `struct file_clone_range`, which `range`
+    // matches in layout, and it outlives the call
+    let ret = unsafe { libc::ioctl(to.as_raw_fd(), FICLONERANGE as _, &range as *const FileCloneRange) };
+    if ret == -1 {
+        return Err(clone_error(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Copy `from` to `to` with the given reflink mode
+pub(crate) fn copy_file_with_mode(from: &Path, to: &Path, mode: ReflinkMode) -> Result<u64> {
+    let source = File::open(from).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(from),
+        _ => e.into(),
+    })?;
+    let meta = source.metadata()?;
+    if !meta.is_file() {
+        return Err(FsError::invalid_path("Source is not a file"));
+    }
+    let dest = File::create(to).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(to),
+        _ => e.into(),
+    })?;
+
+    let cloned = match mode {
+        ReflinkMode::Never => false,
+        // SAFETY: FICLONE takes the source descriptor by value; both files
+        // stay open for the duration of the call
+        _ => match unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } {
+            -1 => match clone_error(io::Error::last_os_error()) {
+                FsError::NotSupported(reason) if mode == ReflinkMode::Auto => {
+                    debug!("Copying {} without reflink: {}", from.display(), reason);
+                    false
+                }
+                e => return Err(e),
+            },
+            _ => true,
+        },
+    };
+    if !cloned {
+        // A failed clone leaves the destination untouched and empty
+        copy_sparse(&source, &dest)?;
+    }
+    dest.set_permissions(meta.permissions())?;
+    Ok(meta.len())
+}
+
+/// Tell "cannot reflink here" apart from real I/O errors
+fn clone_error(e: io::Error) -> FsError {
+    match e.raw_os_error() {
+        // No reflink support, different filesystems, unaligned range, or not an ioctl target
+        Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) | Some(libc::ENOTTY) => {
+            FsError::not_supported(format!("reflink: {}", e))
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_file_reflink() -> Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("image.raw");
+        let dest = dir.path().join("image-copy.raw");
+        fs::write(&src, vec![0x5a; 256 * 1024])?;
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600))?;
+
+        assert_eq!(copy_file_reflink(&src, &dest)?, 256 * 1024);
+        assert_eq!(fs::read(&dest)?, fs::read(&src)?);
+        assert_eq!(fs::metadata(&dest)?.permissions().mode() & 0o777, 0o600);
+
+        // Strict reflinks only work on filesystems with shared extents
+        match reflink(&src, dir.path().join("strict.raw")) {
+            Ok(len) => assert_eq!(len, 256 * 1024),
+            Err(e) => assert!(matches!(e, FsError::NotSupported(_)), "{}", e),
+        }
+        assert!(matches!(copy_file_reflink(dir.path().join("missing"), &dest), Err(FsError::NotFound(_))));
+        Ok(())
+    }
+}
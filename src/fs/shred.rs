@@ -0,0 +1,171 @@
+//! Secure deletion for rastOS
+//!
+//! [`shred`] overwrites a file in place before unlinking it, and
+//! [`wipe_free_space`] fills a filesystem's free space so that the blocks of
+//! files deleted earlier are overwritten.
+//!
+//! # Limitations
+//!
+//! Overwriting in place only reaches the old data if the filesystem writes
+//! to the same blocks, which is not the case for:
+//!
+//! - Copy-on-write filesystems such as Btrfs and ZFS, which write new data
+//!   to new extents and leave the old ones until they are reused. Snapshots
+//!   and reflinked copies keep the old extents alive indefinitely.
+//! - Filesystems with data journaling (`data=journal` on ext4), which may
+//!   hold copies of the data in the journal.
+//! - SSDs and other flash storage, whose wear leveling remaps writes.
+//!
+//! On such storage, combine [`shred`] with [`wipe_free_space`], and prefer
+//! keeping secrets on encrypted volumes so that destroying the key, or the
+//! LUKS header with `cryptsetup erase`, is enough.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use super::{FsError, Result};
+
+/// Bytes written per call while overwriting
+const WIPE_CHUNK: usize = 1024 * 1024;
+
+/// Overwrite a file `passes` times with random data, then with zeros, and delete it
+///
+/// Each pass is flushed to disk before the next one starts. Before the
+/// file is unlinked it is truncated and renamed so that its size and name
+/// are not left in the directory. See the [module documentation](self)
+/// for the storage this cannot protect against.
+pub fn shred<P: AsRef<Path>>(path: P, passes: usize) -> Result<()> {
+    let path = path.as_ref();
+    let meta = fs::symlink_metadata(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        _ => e.into(),
+    })?;
+    if !meta.is_file() {
+        return Err(FsError::invalid_path(format!("{} is not a regular file", path.display())));
+    }
+
+    let file = OpenOptions::new().write(true).open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(path),
+        _ => e.into(),
+    })?;
+    let size = meta.len();
+    let mut random = File::open("/dev/urandom")?;
+    let mut buf = vec![0u8; WIPE_CHUNK];
+    for pass in 0..=passes {
+        let zeros = pass == passes;
+        if zeros {
+            buf.fill(0);
+        }
+        let mut offset = 0;
+        while offset < size {
+            let len = usize::try_from(size - offset).map_or(buf.len(), |n| n.min(buf.len()));
+            if !zeros {
+                random.read_exact(&mut buf[..len])?;
+            }
+            file.write_all_at(&buf[..len], offset)?;
+            offset += len as u64;
+        }
+        file.sync_data()?;
+    }
+    file.set_len(0)?;
+    file.sync_all()?;
+    drop(file);
+
+    let hidden = obscure_name(path);
+    let removed = match fs::rename(path, &hidden) {
+        Ok(()) => &hidden,
+        Err(e) => {
+            debug!("Cannot rename {} before deleting it: {}", path.display(), e);
+            path
+        }
+    };
+    fs::remove_file(removed)?;
+    if let Some(dir) = removed.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Overwrite the free space of the filesystem mounted at `mountpoint`
+///
+/// Writes random data to a hidden file until the filesystem is full, flushes
+/// it and deletes it again. Random data is used because compressing
+/// filesystems would store zeros in almost no space. Space reserved for root
+/// is only covered when running as root. Returns the number of bytes written.
+///
+/// The filesystem is completely full for a moment, so other writers may
+/// fail while this runs.
+pub fn wipe_free_space<P: AsRef<Path>>(mountpoint: P) -> Result<u64> {
+    let mountpoint = mountpoint.as_ref();
+    if !mountpoint.is_dir() {
+        return Err(FsError::not_found(mountpoint));
+    }
+    let mut filler = tempfile::Builder::new()
+        .prefix(".rastos-wipe.")
+        .tempfile_in(mountpoint)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => FsError::permission_denied(mountpoint),
+            _ => e.into(),
+        })?;
+
+    // One random chunk, rewritten over and over, is as incompressible as fresh data
+    let mut buf = vec![0u8; WIPE_CHUNK];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    let mut written = 0u64;
+    let result = loop {
+        match io::Write::write(filler.as_file_mut(), &buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => written += n as u64,
+            Err(e) if e.raw_os_error() == Some(libc::ENOSPC) || e.raw_os_error() == Some(libc::EDQUOT) => {
+                break Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    // Delayed allocation can fail only when the data is flushed
+    if let Err(e) = filler.as_file().sync_all() {
+        if e.raw_os_error() != Some(libc::ENOSPC) {
+            warn!("Flushing {} failed: {}", filler.path().display(), e);
+        }
+    }
+    filler.close()?;
+    result?;
+    debug!("Overwrote {} bytes of free space on {}", written, mountpoint.display());
+    Ok(written)
+}
+
+/// Random name in the same directory as `path`
+fn obscure_name(path: &Path) -> PathBuf {
+    let mut bytes = [0u8; 8];
+    let name = match File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut bytes)) {
+        Ok(()) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        Err(_) => "0".repeat(16),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_shred() -> Result<()> {
+        let dir = tempdir()?;
+        let key = dir.path().join("backup.key");
+        fs::write(&key, vec![0x42; 3 * WIPE_CHUNK / 2])?;
+
+        shred(&key, 2)?;
+        assert!(!key.exists());
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+
+        assert!(matches!(shred(&key, 1), Err(FsError::NotFound(_))));
+        assert!(matches!(shred(dir.path(), 1), Err(FsError::InvalidPath(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! Sparse file handling for rastOS
+//!
+//! Sparse files, like VM images and database files, contain holes: ranges
+//! that read as zeros but take no disk space. A naive copy reads the zeros
+//! and writes them out, so the copy takes the file's full apparent size.
+//! This module finds the data ranges with `SEEK_DATA`/`SEEK_HOLE` and copies
+//! only those, and can punch new holes into existing files.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use super::{FsError, Result};
+
+/// Block size used to detect runs of zeros in in-memory data
+const ZERO_BLOCK: usize = 4096;
+
+/// Bytes copied per read while copying a data range
+const COPY_CHUNK: usize = 1024 * 1024;
+
+/// Check whether a file takes less space on disk than its size
+pub fn is_sparse<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let meta = std::fs::metadata(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        _ => e.into(),
+    })?;
+    // st_blocks counts 512-byte units regardless of the filesystem block size
+    Ok(meta.is_file() && meta.blocks() * 512 < meta.len())
+}
+
+/// Deallocate `len` bytes at `offset`, which then read as zeros
+///
+/// The file size does not change. Filesystems free whole blocks only;
+/// partial blocks at either end are zeroed instead.
+pub fn punch_hole<P: AsRef<Path>>(path: P, offset: u64, len: u64) -> Result<()> {
+    let path = path.as_ref();
+    let file = OpenOptions::new().write(true).open(path)?;
+    let (offset, len) = match (i64::try_from(offset), i64::try_from(len)) {
+        (Ok(offset), Ok(len)) => (offset, len),
+        _ => return Err(FsError::invalid_path("Hole offset or length out of range")),
+    };
+    // SAFETY: fallocate only operates on the open descriptor
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            len,
+        )
+    };
+    if ret == -1 {
+        let e = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => FsError::not_supported(format!("punching holes in {}", path.display())),
+            _ => e.into(),
+        });
+    }
+    Ok(())
+}
+
+/// Byte ranges of a file that hold data, in order
+///
+/// On filesystems without hole reporting the whole file is one range.
+pub fn data_ranges(file: &File) -> Result<Vec<Range<u64>>> {
+    let size = file.metadata()?.len();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = match seek(file, offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // Only a hole remains
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(std::iter::once(offset..size).collect()),
+            Err(e) => return Err(e.into()),
+        };
+        let end = seek(file, start, libc::SEEK_HOLE)?.min(size);
+        ranges.push(start..end);
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+/// Copy `source` into `dest`, leaving holes where the source has them
+///
+/// `dest` should be empty. Returns the source's size, which `dest` is
+/// truncated or extended to.
+pub fn copy_sparse(source: &File, dest: &File) -> Result<u64> {
+    let size = source.metadata()?.len();
+    let mut buf = vec![0u8; COPY_CHUNK];
+    for range in data_ranges(source)? {
+        let mut offset = range.start;
+        while offset < range.end {
+            let want = usize::try_from(range.end - offset).map_or(buf.len(), |n| n.min(buf.len()));
+            let n = source.read_at(&mut buf[..want], offset)?;
+            if n == 0 {
+                // Truncated while copying
+                break;
+            }
+            dest.write_all_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+    }
+    // Trailing holes are only created by setting the size
+    dest.set_len(size)?;
+    Ok(size)
+}
+
+/// Write `contents` to a new or truncated file, skipping blocks of zeros
+///
+/// Useful for data that arrives in memory, such as downloaded images.
+pub fn write_sparse<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    let file = File::create(path.as_ref())?;
+    let mut offset = 0u64;
+    for block in contents.chunks(ZERO_BLOCK) {
+        if block.iter().any(|b| *b != 0) {
+            file.write_all_at(block, offset)?;
+        }
+        offset += block.len() as u64;
+    }
+    file.set_len(contents.len() as u64)?;
+    Ok(())
+}
+
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    let offset = i64::try_from(offset).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: lseek only operates on the open descriptor
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let src_path = dir.path().join("disk.img");
+        let dest_path = dir.path().join("disk-copy.img");
+
+        // 64 MiB with data only at the start and in the middle
+        let src = File::create(&src_path)?;
+        src.set_len(64 << 20)?;
+        src.write_all_at(b"boot", 0)?;
+        src.write_all_at(b"data", 32 << 20)?;
+        src.sync_all()?;
+        assert!(is_sparse(&src_path)?);
+
+        let src = File::open(&src_path)?;
+        let dest = File::create(&dest_path)?;
+        assert_eq!(copy_sparse(&src, &dest)?, 64 << 20);
+        let mut buf = [0u8; 4];
+        dest.read_exact_at(&mut buf, 32 << 20)?;
+        assert_eq!(&buf, b"data");
+        assert_eq!(dest.metadata()?.len(), 64 << 20);
+        assert!(is_sparse(&dest_path)?);
+
+        write_sparse(dir.path().join("zeros.img"), &vec![0u8; 1 << 20])?;
+        assert!(is_sparse(dir.path().join("zeros.img"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_punch_hole() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("db.sqlite");
+        std::fs::write(&path, vec![0xff; 1 << 20])?;
+        match punch_hole(&path, 0, 512 << 10) {
+            Err(FsError::NotSupported(_)) => return Ok(()),
+            result => result?,
+        }
+        let content = std::fs::read(&path)?;
+        assert_eq!(content.len(), 1 << 20);
+        assert!(content[..512 << 10].iter().all(|b| *b == 0));
+        assert_eq!(content[512 << 10], 0xff);
+        assert!(is_sparse(&path)?);
+        Ok(())
+    }
+}
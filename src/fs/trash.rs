@@ -0,0 +1,442 @@
+//! Trash can support for rastOS
+//!
+//! This module implements the [freedesktop.org Trash specification], so
+//! files trashed here show up in, and can be restored from, the trash of
+//! desktop file managers, and the other way around.
+//!
+//! Files on the filesystem of the home directory go to the home trash,
+//! `$XDG_DATA_HOME/Trash`. Files on other filesystems go to a trash at the
+//! top of their own filesystem, `.Trash/$uid` if the administrator prepared
+//! a shared `.Trash` directory, or `.Trash-$uid` otherwise, so trashing never
+//! has to copy data.
+//!
+//! [freedesktop.org Trash specification]: https://specifications.freedesktop.org/trash-spec/trashspec-latest.html
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::trash;
+//!
+//! fn main() -> Result<(), rastos::fs::FsError> {
+//!     let item = trash::trash("/home/user/notes.txt")?;
+//!     // ... changed our mind ...
+//!     trash::restore(&item)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime};
+use log::debug;
+
+use super::{FsError, Result};
+
+/// Extension of the files describing trashed items
+const INFO_EXTENSION: &str = "trashinfo";
+
+/// Format of `DeletionDate`, in local time
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Names tried for an item before giving up
+const MAX_NAME_ATTEMPTS: u32 = 10_000;
+
+/// A file or directory in a trash can
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashItem {
+    /// Name of the item inside the trash
+    pub name: String,
+    /// Where the item was when it was trashed
+    pub original_path: PathBuf,
+    /// When the item was trashed, in local time
+    pub deleted_at: NaiveDateTime,
+    trash_dir: PathBuf,
+}
+
+/// A trash directory with its `files` and `info` subdirectories
+#[derive(Debug, Clone)]
+pub struct Trash {
+    dir: PathBuf,
+    /// Top directory of the filesystem for per-filesystem trash cans, which
+    /// record original paths relative to it
+    topdir: Option<PathBuf>,
+}
+
+impl Trash {
+    /// Use `dir` as a trash can that records absolute paths, like the home trash
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            topdir: None,
+        }
+    }
+
+    /// The current user's home trash, `$XDG_DATA_HOME/Trash`
+    pub fn home() -> Result<Self> {
+        let data_home = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+            .ok_or_else(|| FsError::not_supported("trash without a home directory"))?;
+        Ok(Self::new(data_home.join("Trash")))
+    }
+
+    /// The trash can that `path` is moved to by [`trash`]
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = absolute(path.as_ref())?;
+        let device = fs::symlink_metadata(&path).map_err(|e| not_found_error(e, &path))?.dev();
+        let home = Self::home()?;
+        if nearest_existing(&home.dir).is_some_and(|dir| dir.dev() == device) {
+            return Ok(home);
+        }
+
+        let topdir = mount_point(&path, device);
+        let uid = users::get_current_uid();
+        let shared = topdir.join(".Trash");
+        // The shared directory must be sticky and not a link, or other users could redirect it
+        let dir = match fs::symlink_metadata(&shared) {
+            Ok(meta) if meta.is_dir() && meta.permissions().mode() & 0o1000 != 0 => shared.join(uid.to_string()),
+            _ => topdir.join(format!(".Trash-{}", uid)),
+        };
+        Ok(Self {
+            dir,
+            topdir: Some(topdir),
+        })
+    }
+
+    /// Directory of this trash can
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Move `path` into this trash can
+    ///
+    /// Fails with [`FsError::NotSupported`] if the trash can is on another
+    /// filesystem, since trashing must not copy.
+    pub fn put<P: AsRef<Path>>(&self, path: P) -> Result<TrashItem> {
+        let path = absolute(path.as_ref())?;
+        fs::symlink_metadata(&path).map_err(|e| not_found_error(e, &path))?;
+        if self.dir.starts_with(&path) {
+            return Err(FsError::invalid_path(format!("Cannot trash {}, which holds the trash", path.display())));
+        }
+        let (files, info) = self.create_dirs()?;
+
+        let recorded = match &self.topdir {
+            Some(topdir) => path.strip_prefix(topdir).unwrap_or(&path),
+            None => &path,
+        };
+        let deleted_at = Local::now().naive_local();
+        let content = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode_path(recorded),
+            deleted_at.format(DATE_FORMAT)
+        );
+
+        // Creating the info file exclusively reserves the name
+        let base = path.file_name().unwrap_or(OsStr::new("item")).to_string_lossy().into_owned();
+        for attempt in 1..=MAX_NAME_ATTEMPTS {
+            let name = match attempt {
+                1 => base.clone(),
+                n => format!("{}.{}", base, n),
+            };
+            let info_path = info.join(format!("{}.{}", name, INFO_EXTENSION));
+            let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let moved = info_file
+                .write_all(content.as_bytes())
+                .and_then(|()| info_file.sync_all())
+                .and_then(|()| fs::rename(&path, files.join(&name)));
+            if let Err(e) = moved {
+                let _ = fs::remove_file(&info_path);
+                return Err(match e.raw_os_error() {
+                    Some(libc::EXDEV) => FsError::not_supported(format!(
+                        "trashing {} into {} on another filesystem",
+                        path.display(),
+                        self.dir.display()
+                    )),
+                    _ => e.into(),
+                });
+            }
+            debug!("Trashed {} as {}", path.display(), name);
+            return Ok(TrashItem {
+                name,
+                original_path: path,
+                deleted_at,
+                trash_dir: self.dir.clone(),
+            });
+        }
+        Err(FsError::already_exists(files.join(base)))
+    }
+
+    /// Items in this trash can, oldest first
+    ///
+    /// Info files without a matching item and malformed info files are skipped.
+    pub fn list(&self) -> Result<Vec<TrashItem>> {
+        let info = self.dir.join("info");
+        let entries = match fs::read_dir(&info) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut items = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(INFO_EXTENSION)) {
+                continue;
+            }
+            let Some(name) = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            if fs::symlink_metadata(self.dir.join("files").join(&name)).is_err() {
+                continue;
+            }
+            match self.parse_info(&fs::read_to_string(&path)?) {
+                Some((original_path, deleted_at)) => items.push(TrashItem {
+                    name,
+                    original_path,
+                    deleted_at,
+                    trash_dir: self.dir.clone(),
+                }),
+                None => debug!("Skipping malformed {}", path.display()),
+            }
+        }
+        items.sort_by(|a, b| a.deleted_at.cmp(&b.deleted_at).then_with(|| a.name.cmp(&b.name)));
+        Ok(items)
+    }
+
+    /// Permanently delete everything in this trash can
+    pub fn empty(&self) -> Result<()> {
+        for sub in ["files", "info"] {
+            let dir = self.dir.join(sub);
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                remove_entry(&entry?.path())?;
+            }
+        }
+        // Cache of directory sizes kept by some file managers
+        match fs::remove_file(self.dir.join("directorysizes")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_dirs(&self) -> Result<(PathBuf, PathBuf)> {
+        let files = self.dir.join("files");
+        let info = self.dir.join("info");
+        let mut builder = DirBuilder::new();
+        builder.recursive(true).mode(0o700);
+        builder.create(&files)?;
+        builder.create(&info)?;
+        Ok((files, info))
+    }
+
+    fn parse_info(&self, content: &str) -> Option<(PathBuf, NaiveDateTime)> {
+        let mut lines = content.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next()? != "[Trash Info]" {
+            return None;
+        }
+        let (mut path, mut date) = (None, None);
+        for line in lines {
+            if line.starts_with('[') {
+                break;
+            }
+            match line.split_once('=') {
+                Some(("Path", value)) => path = Some(decode_path(value)?),
+                Some(("DeletionDate", value)) => date = NaiveDateTime::parse_from_str(value, DATE_FORMAT).ok(),
+                _ => {}
+            }
+        }
+        let path = match (&self.topdir, path?) {
+            (Some(topdir), path) if path.is_relative() => topdir.join(path),
+            (_, path) => path,
+        };
+        Some((path, date?))
+    }
+}
+
+impl TrashItem {
+    fn files_path(&self) -> PathBuf {
+        self.trash_dir.join("files").join(&self.name)
+    }
+
+    fn info_path(&self) -> PathBuf {
+        self.trash_dir.join("info").join(format!("{}.{}", self.name, INFO_EXTENSION))
+    }
+}
+
+/// Move `path` to the trash can of its filesystem
+pub fn trash<P: AsRef<Path>>(path: P) -> Result<TrashItem> {
+    let path = path.as_ref();
+    Trash::for_path(path)?.put(path)
+}
+
+/// Put a trashed item back where it was
+///
+/// Missing parent directories are recreated. Fails with
+/// [`FsError::AlreadyExists`] if something took its place in the meantime.
+pub fn restore(item: &TrashItem) -> Result<PathBuf> {
+    let source = item.files_path();
+    fs::symlink_metadata(&source).map_err(|e| not_found_error(e, &source))?;
+    if fs::symlink_metadata(&item.original_path).is_ok() {
+        return Err(FsError::already_exists(&item.original_path));
+    }
+    if let Some(parent) = item.original_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&source, &item.original_path)?;
+    fs::remove_file(item.info_path())?;
+    Ok(item.original_path.clone())
+}
+
+/// Permanently delete one trashed item
+pub fn purge(item: &TrashItem) -> Result<()> {
+    remove_entry(&item.files_path())?;
+    fs::remove_file(item.info_path())?;
+    Ok(())
+}
+
+/// Items in the current user's home trash, oldest first
+pub fn list() -> Result<Vec<TrashItem>> {
+    Trash::home()?.list()
+}
+
+/// Permanently delete everything in the current user's home trash
+pub fn empty() -> Result<()> {
+    Trash::home()?.empty()
+}
+
+/// Make `path` absolute and drop `.` and `..`, without following a final link
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let file_name = match path.components().next_back() {
+        Some(Component::Normal(name)) => name.to_os_string(),
+        _ => return Err(FsError::invalid_path(format!("Cannot trash {}", path.display()))),
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let parent = parent.canonicalize().map_err(|e| not_found_error(e, &parent))?;
+    Ok(parent.join(file_name))
+}
+
+/// Metadata of `path` or of its nearest existing ancestor
+fn nearest_existing(path: &Path) -> Option<fs::Metadata> {
+    path.ancestors().find_map(|dir| fs::metadata(dir).ok())
+}
+
+/// Topmost ancestor of `path` on the same device
+fn mount_point(path: &Path, device: u64) -> PathBuf {
+    let mut top = path;
+    while let Some(parent) = top.parent() {
+        match fs::metadata(parent) {
+            Ok(meta) if meta.dev() == device => top = parent,
+            _ => break,
+        }
+    }
+    top.to_path_buf()
+}
+
+fn remove_entry(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path)?,
+        _ => fs::remove_file(path)?,
+    }
+    Ok(())
+}
+
+fn not_found_error(e: io::Error, path: &Path) -> FsError {
+    match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        _ => e.into(),
+    }
+}
+
+/// Percent-encode a path as the specification requires, keeping `/`
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode_path(value: &str) -> Option<PathBuf> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&decoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_trash_and_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let can = Trash::new(dir.path().join("Trash"));
+        let notes = dir.path().join("my notes.txt");
+        fs::write(&notes, "remember")?;
+
+        let item = can.put(&notes)?;
+        assert!(!notes.exists());
+        assert_eq!(item.name, "my notes.txt");
+        let info = fs::read_to_string(item.info_path())?;
+        assert!(info.contains("my%20notes.txt"), "{}", info);
+
+        // A second file of the same name gets another name in the trash
+        fs::write(&notes, "again")?;
+        let second = can.put(&notes)?;
+        assert_eq!(second.name, "my notes.txt.2");
+
+        let items = can.list()?;
+        assert_eq!(items.len(), 2);
+        let original = absolute(&notes)?;
+        assert!(items.iter().all(|i| i.original_path == original));
+
+        assert_eq!(restore(&item)?, item.original_path);
+        assert_eq!(fs::read_to_string(&notes)?, "remember");
+        assert!(matches!(restore(&second), Err(FsError::AlreadyExists(_))));
+
+        can.empty()?;
+        assert!(can.list()?.is_empty());
+        assert!(matches!(can.put(dir.path().join("missing")), Err(FsError::NotFound(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_encoding() {
+        let path = Path::new("/home/user/Ünïcode & spaces%.txt");
+        let encoded = encode_path(path);
+        assert_eq!(encoded, "/home/user/%C3%9Cn%C3%AFcode%20%26%20spaces%25.txt");
+        assert_eq!(decode_path(&encoded).as_deref(), Some(path));
+        assert_eq!(decode_path("/bad%2"), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Disk usage of directory trees for rastOS
+//!
+//! [`disk_usage`] adds up the apparent and allocated sizes of everything
+//! below a path, like `du -s`, while traversing directories in parallel.
+//! Hard-linked files are counted once. On Btrfs it also asks the filesystem
+//! which extents are shared with other files or snapshots, so the bytes a
+//! subvolume holds exclusively can be told apart from the bytes that
+//! deleting it would not free.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use log::debug;
+
+use super::walk::{walk, WalkEntry, WalkOptions};
+use super::{FsError, Result};
+
+/// `_IOWR('f', 11, struct fiemap)`: map the extents of a file
+const FS_IOC_FIEMAP: u64 = 0xc020_660b;
+
+/// Extent is also referenced by other files or snapshots
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// Last extent of the file
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+
+/// Extents requested per `FS_IOC_FIEMAP` call
+const FIEMAP_BATCH: usize = 64;
+
+/// `struct fiemap_extent` in `linux/fiemap.h`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct fiemap` in `linux/fiemap.h`, with room for [`FIEMAP_BATCH`] extents
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// Sizes of a directory tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Sum of the sizes reported by `stat`, as `du -b` counts them
+    pub apparent: u64,
+    /// Bytes allocated on disk, as `du -B1` counts them
+    pub on_disk: u64,
+    /// Bytes in extents shared with other files or snapshots
+    ///
+    /// Only counted on Btrfs; elsewhere this is 0.
+    pub shared: u64,
+    /// Number of files, links and special files
+    pub files: u64,
+    /// Number of directories, including the root
+    pub dirs: u64,
+    /// Entries that could not be read and were left out
+    pub unreadable: u64,
+}
+
+impl DiskUsage {
+    /// Bytes on disk that belong to this tree alone
+    ///
+    /// This is roughly what deleting the tree would free.
+    pub fn exclusive(&self) -> u64 {
+        self.on_disk.saturating_sub(self.shared)
+    }
+}
+
+/// Compute the disk usage of `path` and everything below it
+///
+/// Symbolic links are counted but not followed. Entries that cannot be
+/// read are counted in [`DiskUsage::unreadable`] instead of failing the
+/// whole calculation, as `du` does.
+pub fn disk_usage<P: AsRef<Path>>(path: P) -> Result<DiskUsage> {
+    let path = path.as_ref();
+    let count_shared = is_btrfs(path)?;
+    let options = WalkOptions {
+        min_depth: 0,
+        ..WalkOptions::default()
+    }
+    .with_parallel();
+
+    let mut usage = DiskUsage::default();
+    let mut linked = HashSet::new();
+    for entry in walk(path, options)? {
+        let stat = |entry: WalkEntry| -> Result<_> {
+            let meta = fs::symlink_metadata(&entry.path)?;
+            Ok((entry, meta))
+        };
+        let (entry, meta) = match entry.and_then(stat) {
+            Ok(found) => found,
+            Err(e) => {
+                debug!("Leaving entry out of disk usage: {}", e);
+                usage.unreadable += 1;
+                continue;
+            }
+        };
+        if meta.is_dir() {
+            usage.dirs += 1;
+        } else {
+            usage.files += 1;
+        }
+        // Every name of a hard-linked file refers to the same data
+        if !meta.is_dir() && meta.nlink() > 1 && !linked.insert((meta.dev(), meta.ino())) {
+            continue;
+        }
+        usage.apparent += meta.len();
+        // st_blocks counts 512-byte units regardless of the filesystem block size
+        usage.on_disk += meta.blocks() * 512;
+        if count_shared && meta.is_file() {
+            match shared_bytes(&entry.path) {
+                Ok(shared) => usage.shared += shared,
+                Err(e) => debug!("Cannot map extents of {}: {}", entry.path.display(), e),
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Bytes of a file that are in extents shared with other files
+pub fn shared_bytes<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(path),
+        _ => e.into(),
+    })?;
+    let mut map = Box::new(Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: 0,
+        mapped_extents: 0,
+        extent_count: FIEMAP_BATCH as u32,
+        reserved: 0,
+        extents: [FiemapExtent::default(); FIEMAP_BATCH],
+    });
+
+    let mut shared = 0;
+    loop {
+        map.length = u64::MAX - map.start;
+        map.mapped_extents = 0;
+        // SAFETY: FS_IOC_FIEMAP reads and writes a `struct fiemap` followed
+        // by `extent_count` extents, which `map` matches in layout
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut *map as *mut Fiemap) };
+        if ret == -1 {
+            let e = io::Error::last_os_error();
+            return Err(match e.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => {
+                    FsError::not_supported(format!("mapping extents of {}", path.display()))
+                }
+                _ => e.into(),
+            });
+        }
+
+        let extents = &map.extents[..map.mapped_extents as usize];
+        shared += extents
+            .iter()
+            .filter(|extent| extent.flags & FIEMAP_EXTENT_SHARED != 0)
+            .map(|extent| extent.length)
+            .sum::<u64>();
+        match extents.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => map.start = last.logical + last.length,
+            _ => break,
+        }
+    }
+    Ok(shared)
+}
+
+/// Check whether `path` is on a Btrfs filesystem
+fn is_btrfs(path: &Path) -> Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| FsError::invalid_path(format!("{} contains a NUL byte", path.display())))?;
+    // SAFETY: statfs only writes to the zeroed struct it is given
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } == -1 {
+        let e = io::Error::last_os_error();
+        return Err(match e.kind() {
+            io::ErrorKind::NotFound => FsError::not_found(path),
+            _ => e.into(),
+        });
+    }
+    Ok(stat.f_type == libc::BTRFS_SUPER_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_disk_usage() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("var"))?;
+        fs::write(dir.path().join("var/log"), vec![b'x'; 10_000])?;
+        fs::write(dir.path().join("motd"), "hello\n")?;
+        fs::hard_link(dir.path().join("motd"), dir.path().join("issue"))?;
+        std::os::unix::fs::symlink("motd", dir.path().join("link"))?;
+
+        let usage = disk_usage(dir.path())?;
+        assert_eq!(usage.dirs, 2);
+        assert_eq!(usage.files, 4);
+        assert_eq!(usage.unreadable, 0);
+        let dir_sizes = fs::metadata(dir.path())?.len() + fs::metadata(dir.path().join("var"))?.len();
+        // The hard link is counted once, the symlink by its target's length
+        assert_eq!(usage.apparent, dir_sizes + 10_000 + 6 + 4);
+        assert!(usage.on_disk >= 10_000);
+        assert!(usage.exclusive() <= usage.on_disk);
+
+        assert!(matches!(disk_usage(dir.path().join("missing")), Err(FsError::NotFound(_))));
+        Ok(())
+    }
+}
@@ -3,13 +3,10 @@
 //! This module provides additional utilities for working with the file system,
 //! including temporary files/directories and glob pattern matching.
 
-use std::ffi::OsString;
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::PathBuf;
 
 use glob::{glob_with, MatchOptions};
-use tempfile::{NamedTempFile, TempDir};
+use tempfile::{Builder, NamedTempFile, TempDir};
 
 use crate::fs::{FsError, Result};
 
@@ -48,12 +45,12 @@ impl Default for GlobOptions {
 /// }
 /// ```
 pub fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
-    glob_with(pattern, &MatchOptions::new())
+    glob_with(pattern, MatchOptions::new())
         .map_err(|e| FsError::invalid_path(e.to_string()))
         .and_then(|entries| {
             entries
                 .collect::<std::result::Result<Vec<_>, _>>()
-                .map_err(|e| FsError::io(e, "Failed to read directory entry"))
+                .map_err(|e| e.into_error().into())
         })
 }
 
@@ -87,18 +84,20 @@ pub fn glob_with_options(pattern: &str, options: GlobOptions) -> Result<Vec<Path
         }
     };
 
-    glob_with(&pattern, &match_options)
+    glob_with(&pattern, match_options)
         .map_err(|e| FsError::invalid_path(e.to_string()))
         .and_then(|entries| {
             entries
                 .collect::<std::result::Result<Vec<_>, _>>()
-                .map_err(|e| FsError::io(e, "Failed to read directory entry"))
+                .map_err(|e| e.into_error().into())
         })
 }
 
-/// Create a new temporary file
+/// Create a new temporary file in the system temporary directory
 ///
-/// The file will be automatically deleted when the returned `NamedTempFile` is dropped.
+/// The file name starts with `prefix` and ends with `suffix`, with random
+/// characters in between. The file will be automatically deleted when the
+/// returned `NamedTempFile` is dropped.
 ///
 /// # Examples
 ///
@@ -113,24 +112,14 @@ pub fn glob_with_options(pattern: &str, options: GlobOptions) -> Result<Vec<Path
 /// // File is automatically deleted when `file` is dropped
 /// ```
 pub fn temp_file(prefix: &str, suffix: &str) -> Result<NamedTempFile> {
-    let temp_dir = std::env::temp_dir();
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos();
-    
-    let mut file_name = OsString::new();
-    file_name.push(prefix);
-    file_name.push(timestamp.to_string());
-    file_name.push(suffix);
-    
-    let path = temp_dir.join(file_name);
-    
-    NamedTempFile::new_in(temp_dir)
-        .map_err(|e| FsError::io(e, "Failed to create temporary file"))
+    Ok(Builder::new().prefix(prefix).suffix(suffix).tempfile()?)
 }
 
-/// Create a new temporary directory
+/// Create a new temporary directory in the system temporary directory
+///
+/// The directory name starts with `prefix`, followed by random characters.
+/// For scratch space that is cleaned up after crashes and can be size
+/// limited, use a [`Workspace`](crate::fs::Workspace) instead.
 ///
 /// The directory will be automatically deleted when the returned `TempDir` is dropped.
 ///
@@ -147,22 +136,13 @@ pub fn temp_file(prefix: &str, suffix: &str) -> Result<NamedTempFile> {
 /// // Directory and all its contents are automatically deleted when `dir` is dropped
 /// ```
 pub fn temp_dir(prefix: &str) -> Result<TempDir> {
-    let temp_dir = std::env::temp_dir();
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos();
-    
-    let dir_name = format!("{}temp_{}", prefix, timestamp);
-    let path = temp_dir.join(dir_name);
-    
-    TempDir::new_in(temp_dir, &path)
-        .map_err(|e| FsError::io(e, "Failed to create temporary directory"))
+    Ok(Builder::new().prefix(prefix).tempdir()?)
 }
 
 /// Create a temporary file with the given content
 ///
-/// Returns the path to the created file. The file will be automatically deleted when the program exits.
+/// Returns the path to the created file. The file is kept after the program
+/// exits; the caller is responsible for deleting it.
 ///
 /// # Examples
 ///
@@ -174,16 +154,21 @@ pub fn temp_dir(prefix: &str) -> Result<TempDir> {
 /// ```
 pub fn create_temp_file(prefix: &str, suffix: &str, content: &[u8]) -> Result<PathBuf> {
     let mut temp_file = temp_file(prefix, suffix)?;
-    std::io::Write::write_all(&mut temp_file, content)
-        .map_err(|e| FsError::io(e, "Failed to write to temporary file"))?;
-    
-    let path = temp_file.path().to_owned();
-    // Convert to a persistent file that will be deleted when the program exits
-    temp_file.keep().map_err(|e| FsError::io(e.error, "Failed to persist temporary file"))?;
-    
+    std::io::Write::write_all(&mut temp_file, content)?;
+    let (_, path) = temp_file.keep().map_err(|e| e.error)?;
     Ok(path)
 }
 
+/// Whether `name` is safe to use as a single file name
+///
+/// Plain names are made of ASCII letters, digits, `.`, `_` and `-` and do
+/// not start with a dot, so they can neither leave a directory nor hide in it.
+pub fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -225,6 +210,8 @@ mod tests {
     fn test_temp_file() -> Result<()> {
         let mut file = temp_file("test_", ".tmp")?;
         let path = file.path().to_owned();
+        let name = path.file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with("test_") && name.ends_with(".tmp"));
         
         // Write to the temp file
         file.write_all(b"test content")?;
@@ -243,6 +230,7 @@ mod tests {
     #[test]
     fn test_temp_dir() -> Result<()> {
         let dir = temp_dir("test_dir")?;
+        assert!(dir.path().file_name().unwrap().to_string_lossy().starts_with("test_dir"));
         let file_path = dir.path().join("test.txt");
         
         // Create a file in the temp directory
@@ -256,4 +244,12 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_is_plain_name() {
+        assert!(is_plain_name("web-1.example_org"));
+        for name in ["", ".hidden", "..", "a/b", "tab\tname", "naïve"] {
+            assert!(!is_plain_name(name), "{:?}", name);
+        }
+    }
 }
@@ -0,0 +1,407 @@
+//! Recursive directory walking for rastOS
+//!
+//! This module walks directory trees with depth limits, glob filters and an
+//! explicit policy for symbolic links. Sequential walks visit entries in
+//! file name order; parallel walks read directories on the rayon thread pool
+//! and yield entries in no particular order, which pays off on large trees
+//! such as kernel sources or restored backups.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::{walk, WalkOptions};
+//!
+//! fn main() -> Result<(), rastos::fs::FsError> {
+//!     let options = WalkOptions::default()
+//!         .with_include("**/*.ko")
+//!         .with_exclude(".git");
+//!     for entry in walk("/usr/lib/modules", options)? {
+//!         println!("Found: {}", entry?.path.display());
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::fs::{self, FileType, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+
+use glob::{MatchOptions, Pattern};
+use walkdir::WalkDir;
+
+use super::{FsError, Result};
+
+/// Entries buffered between parallel walkers and the consumer
+const PARALLEL_BUFFER: usize = 1024;
+
+/// What to do with symbolic links
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Report links as entries without following them
+    #[default]
+    Report,
+    /// Follow links, reporting their targets; loops are detected
+    Follow,
+    /// Leave links out
+    Skip,
+}
+
+/// Options for walking a directory tree
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Depth of the shallowest entries reported; the root itself is depth 0
+    pub min_depth: usize,
+    /// Depth of the deepest entries reported and descended into
+    pub max_depth: Option<usize>,
+    /// Glob patterns, relative to the root, of entries to report; all if empty
+    ///
+    /// Directories that do not match are still descended into.
+    pub include: Vec<String>,
+    /// Glob patterns of entries to leave out; excluded directories are not descended into
+    pub exclude: Vec<String>,
+    /// What to do with symbolic links
+    pub symlinks: SymlinkPolicy,
+    /// Read directories in parallel, yielding entries in no particular order
+    pub parallel: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            min_depth: 1,
+            max_depth: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            symlinks: SymlinkPolicy::default(),
+            parallel: false,
+        }
+    }
+}
+
+impl WalkOptions {
+    /// Do not report or descend below `depth`
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Only report entries matching `pattern`
+    pub fn with_include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Leave out entries matching `pattern`
+    pub fn with_exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Set the symbolic link policy
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Walk in parallel
+    pub fn with_parallel(mut self) -> Self {
+        self.parallel = true;
+        self
+    }
+}
+
+/// An entry found while walking
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// Full path
+    pub path: PathBuf,
+    /// Path relative to the walked root
+    pub relative: PathBuf,
+    /// Depth below the root, which is depth 0
+    pub depth: usize,
+    /// Type of the entry, of the link target when links are followed
+    pub file_type: FileType,
+    follow: bool,
+}
+
+impl WalkEntry {
+    /// Get the entry's metadata, following links only if the walk does
+    pub fn metadata(&self) -> Result<Metadata> {
+        let meta = if self.follow { fs::metadata(&self.path) } else { fs::symlink_metadata(&self.path) };
+        meta.map_err(Into::into)
+    }
+}
+
+/// Compiled include and exclude patterns, matched against paths relative to a root
+#[derive(Debug)]
+pub(crate) struct PathFilter {
+    root: PathBuf,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(root: &Path, include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| Pattern::new(p).map_err(|e| FsError::invalid_path(format!("{}: {}", p, e))))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            root: root.to_path_buf(),
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    fn hit(patterns: &[Pattern], relative: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        patterns.iter().any(|p| p.matches_path_with(relative, options))
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    /// Whether `path` is excluded
+    pub(crate) fn excludes(&self, path: &Path) -> bool {
+        Self::hit(&self.exclude, self.relative(path))
+    }
+
+    /// Whether `path` matches the include patterns, ignoring exclusion
+    pub(crate) fn includes(&self, path: &Path) -> bool {
+        self.include.is_empty() || Self::hit(&self.include, self.relative(path))
+    }
+
+    /// Whether `path` is included and not excluded
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        self.includes(path) && !self.excludes(path)
+    }
+}
+
+/// Iterator over the entries of a directory tree
+///
+/// Created by [`walk`].
+pub struct Walk {
+    inner: WalkInner,
+}
+
+enum WalkInner {
+    Sequential(Box<dyn Iterator<Item = Result<WalkEntry>> + Send>),
+    Parallel(Receiver<Result<WalkEntry>>),
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            WalkInner::Sequential(entries) => entries.next(),
+            WalkInner::Parallel(entries) => entries.recv().ok(),
+        }
+    }
+}
+
+/// Walk the directory tree below `path`
+pub fn walk<P: AsRef<Path>>(path: P, options: WalkOptions) -> Result<Walk> {
+    let root = path.as_ref().to_path_buf();
+    if fs::symlink_metadata(&root).is_err() {
+        return Err(FsError::not_found(root));
+    }
+    let filter = PathFilter::new(&root, &options.include, &options.exclude)?;
+    let inner = if options.parallel {
+        WalkInner::Parallel(walk_parallel(root, options, filter))
+    } else {
+        WalkInner::Sequential(Box::new(walk_sequential(root, options, filter)))
+    };
+    Ok(Walk { inner })
+}
+
+fn walk_sequential(
+    root: PathBuf,
+    options: WalkOptions,
+    filter: PathFilter,
+) -> impl Iterator<Item = Result<WalkEntry>> + Send {
+    let follow = options.symlinks == SymlinkPolicy::Follow;
+    let skip_links = options.symlinks == SymlinkPolicy::Skip;
+    let mut walker = WalkDir::new(&root)
+        .min_depth(options.min_depth)
+        .follow_links(follow)
+        .sort_by_file_name();
+    if let Some(depth) = options.max_depth {
+        walker = walker.max_depth(depth);
+    }
+    let filter = Arc::new(filter);
+    let excluded = Arc::clone(&filter);
+    walker
+        .into_iter()
+        .filter_entry(move |e| e.depth() == 0 || !excluded.excludes(e.path()))
+        .filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(FsError::Other(e.into()))),
+            };
+            if (skip_links && entry.path_is_symlink()) || !filter.includes(entry.path()) {
+                return None;
+            }
+            Some(Ok(WalkEntry {
+                relative: entry.path().strip_prefix(&root).unwrap_or(entry.path()).to_path_buf(),
+                path: entry.path().to_path_buf(),
+                depth: entry.depth(),
+                file_type: entry.file_type(),
+                follow,
+            }))
+        })
+}
+
+fn walk_parallel(root: PathBuf, options: WalkOptions, filter: PathFilter) -> Receiver<Result<WalkEntry>> {
+    let (tx, rx) = mpsc::sync_channel(PARALLEL_BUFFER);
+    std::thread::spawn(move || {
+        let walker = ParallelWalker {
+            root: root.clone(),
+            options,
+            filter,
+        };
+        rayon::scope(|scope| walker.visit(scope, root, Vec::new(), &tx));
+    });
+    rx
+}
+
+struct ParallelWalker {
+    root: PathBuf,
+    options: WalkOptions,
+    filter: PathFilter,
+}
+
+impl ParallelWalker {
+    /// Report `path` and spawn visits of its children
+    ///
+    /// `ancestors` holds the device and inode of every directory above
+    /// `path`, so followed links cannot loop.
+    fn visit<'s>(
+        &'s self,
+        scope: &rayon::Scope<'s>,
+        path: PathBuf,
+        ancestors: Vec<(u64, u64)>,
+        tx: &SyncSender<Result<WalkEntry>>,
+    ) {
+        let depth = ancestors.len();
+        let follow = self.options.symlinks == SymlinkPolicy::Follow;
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) if depth > 0 && meta.file_type().is_symlink() && self.options.symlinks == SymlinkPolicy::Skip => {
+                return;
+            }
+            Ok(meta) if follow && meta.file_type().is_symlink() => fs::metadata(&path),
+            result => result,
+        };
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(e) => {
+                let _ = tx.send(Err(e.into()));
+                return;
+            }
+        };
+
+        let entry = WalkEntry {
+            relative: path.strip_prefix(&self.root).unwrap_or(&path).to_path_buf(),
+            path: path.clone(),
+            depth,
+            file_type: meta.file_type(),
+            follow,
+        };
+        if depth >= self.options.min_depth && self.filter.matches(&path) && tx.send(Ok(entry)).is_err() {
+            // The consumer went away
+            return;
+        }
+
+        if !meta.is_dir() || self.options.max_depth.is_some_and(|max| depth >= max) {
+            return;
+        }
+        let id = (meta.dev(), meta.ino());
+        if ancestors.contains(&id) {
+            let _ = tx.send(Err(FsError::invalid_path(format!("Symbolic link loop at {}", path.display()))));
+            return;
+        }
+        let children = match fs::read_dir(&path) {
+            Ok(children) => children,
+            Err(e) => {
+                let _ = tx.send(Err(e.into()));
+                return;
+            }
+        };
+        for child in children {
+            match child {
+                Ok(child) if !self.filter.excludes(&child.path()) => {
+                    let tx = tx.clone();
+                    let mut ancestors = ancestors.clone();
+                    ancestors.push(id);
+                    scope.spawn(move |scope| self.visit(scope, child.path(), ancestors, &tx));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let _ = tx.send(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn tree() -> Result<tempfile::TempDir> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("kernel/drivers/net"))?;
+        fs::create_dir_all(dir.path().join(".git/objects"))?;
+        fs::write(dir.path().join("kernel/drivers/net/e1000.ko"), "")?;
+        fs::write(dir.path().join("kernel/drivers/net/Makefile"), "")?;
+        fs::write(dir.path().join("kernel/fs.ko"), "")?;
+        fs::write(dir.path().join(".git/objects/ab.ko"), "")?;
+        std::os::unix::fs::symlink("kernel", dir.path().join("current"))?;
+        Ok(dir)
+    }
+
+    fn relative_paths(walk: Walk) -> Result<Vec<PathBuf>> {
+        let mut paths = walk.map(|e| e.map(|e| e.relative)).collect::<Result<Vec<_>>>()?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_walk_filters() -> Result<()> {
+        let dir = tree()?;
+        let options = WalkOptions::default().with_include("**/*.ko").with_exclude(".git");
+        let expected = vec![PathBuf::from("kernel/drivers/net/e1000.ko"), PathBuf::from("kernel/fs.ko")];
+        assert_eq!(relative_paths(walk(dir.path(), options.clone())?)?, expected);
+        assert_eq!(relative_paths(walk(dir.path(), options.with_parallel())?)?, expected);
+
+        let shallow = WalkOptions::default().with_max_depth(1).with_exclude(".git");
+        assert_eq!(
+            relative_paths(walk(dir.path(), shallow.clone())?)?,
+            vec![PathBuf::from("current"), PathBuf::from("kernel")]
+        );
+        let no_links = shallow.with_symlinks(SymlinkPolicy::Skip);
+        assert_eq!(relative_paths(walk(dir.path(), no_links.with_parallel())?)?, vec![PathBuf::from("kernel")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_follow_links() -> Result<()> {
+        let dir = tree()?;
+        let options = WalkOptions::default()
+            .with_include("current/**/*.ko")
+            .with_symlinks(SymlinkPolicy::Follow);
+        let expected = vec![PathBuf::from("current/drivers/net/e1000.ko"), PathBuf::from("current/fs.ko")];
+        assert_eq!(relative_paths(walk(dir.path(), options.clone())?)?, expected);
+        assert!(walk(dir.path().join("missing"), options).is_err());
+        Ok(())
+    }
+}
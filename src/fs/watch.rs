@@ -0,0 +1,309 @@
+//! File and directory watching for rastOS
+//!
+//! This module turns inotify notifications into an async stream of
+//! [`WatchEvent`]s. Bursts of notifications for the same path, such as the
+//! create/modify/close sequence of an editor saving a file, are debounced
+//! into a single event, and glob filters restrict which paths are reported.
+//!
+//! The stream needs a running Tokio runtime; it stops watching when dropped.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::watch::{watch, WatchOptions};
+//! use tokio_stream::StreamExt;
+//!
+//! # async fn example() -> Result<(), rastos::fs::FsError> {
+//! let options = WatchOptions::default().with_include("**/*.toml");
+//! let mut events = watch("/etc/rastos", options)?;
+//! while let Some(event) = events.next().await {
+//!     println!("{:?}: {}", event.kind, event.path.display());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::warn;
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tokio_stream::Stream;
+
+use super::walk::PathFilter;
+use super::{FsError, Result};
+
+/// What happened to a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchEventKind {
+    /// The path was created or moved into the watched tree
+    Created,
+    /// The contents or metadata of the path changed
+    Modified,
+    /// The path was removed or moved out of the watched tree
+    Removed,
+    /// Notifications were lost; the watched tree should be rescanned
+    Rescan,
+}
+
+/// A debounced change to a watched path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// Changed path; the watched root for [`WatchEventKind::Rescan`]
+    pub path: PathBuf,
+    /// What happened to it
+    pub kind: WatchEventKind,
+}
+
+/// Options for watching a path
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Whether to watch subdirectories, including ones created later
+    pub recursive: bool,
+    /// Quiet period after the last notification for a path before it is reported
+    pub debounce: Duration,
+    /// Glob patterns, relative to the watched path, of paths to report; all if empty
+    pub include: Vec<String>,
+    /// Glob patterns of paths never to report
+    pub exclude: Vec<String>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            recursive: true,
+            debounce: Duration::from_millis(200),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl WatchOptions {
+    /// Only report paths matching `pattern`
+    pub fn with_include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Never report paths matching `pattern`
+    pub fn with_exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+}
+
+/// Async stream of debounced filesystem events
+///
+/// Created by [`watch`]. Dropping it stops the watch.
+pub struct WatchStream {
+    events: mpsc::UnboundedReceiver<WatchEvent>,
+    debouncer: JoinHandle<()>,
+    // Kept alive for as long as the stream is
+    _watcher: RecommendedWatcher,
+}
+
+impl Stream for WatchStream {
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for WatchStream {
+    fn drop(&mut self) {
+        self.debouncer.abort();
+    }
+}
+
+/// Watch a file or directory for changes
+///
+/// Must be called from within a Tokio runtime.
+pub fn watch<P: AsRef<Path>>(path: P, options: WatchOptions) -> Result<WatchStream> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Err(FsError::not_found(path));
+    }
+    let root = path.canonicalize()?;
+    let filter = PathFilter::new(&root, &options.include, &options.exclude)?;
+
+    let (raw_tx, raw_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // The receiver is gone once the stream is dropped
+        let _ = raw_tx.send(event);
+    })
+    .map_err(|e| FsError::Other(e.into()))?;
+    let mode = if options.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    watcher.watch(&root, mode).map_err(|e| FsError::Other(e.into()))?;
+
+    let (tx, events) = mpsc::unbounded_channel();
+    let debouncer = tokio::spawn(debounce(raw_rx, tx, root, filter, options.debounce));
+    Ok(WatchStream {
+        events,
+        debouncer,
+        _watcher: watcher,
+    })
+}
+
+/// Map a notification onto per-path events
+fn classify(event: notify::Event) -> Vec<(PathBuf, WatchEventKind)> {
+    let kind = match event.kind {
+        EventKind::Create(_) => WatchEventKind::Created,
+        EventKind::Remove(_) => WatchEventKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => WatchEventKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => WatchEventKind::Created,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            // Paths are [from, to]
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| (from, WatchEventKind::Removed))
+                .into_iter()
+                .chain(paths.next().map(|to| (to, WatchEventKind::Created)))
+                .collect();
+        }
+        EventKind::Modify(_) => WatchEventKind::Modified,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (path, kind)).collect()
+}
+
+/// Fold a new event for a path into the pending one
+///
+/// Returns `None` when the two cancel out, like a temporary file created
+/// and removed within the debounce period.
+fn merge(pending: Option<WatchEventKind>, next: WatchEventKind) -> Option<WatchEventKind> {
+    use WatchEventKind::*;
+    match (pending, next) {
+        (None, next) => Some(next),
+        (Some(Created), Modified) => Some(Created),
+        (Some(Created), Removed) => None,
+        (Some(Removed), Created) => Some(Modified),
+        (Some(_), next) => Some(next),
+    }
+}
+
+async fn debounce(
+    mut raw: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    events: mpsc::UnboundedSender<WatchEvent>,
+    root: PathBuf,
+    filter: PathFilter,
+    period: Duration,
+) {
+    let mut pending: HashMap<PathBuf, (WatchEventKind, Instant)> = HashMap::new();
+    loop {
+        let deadline = pending.values().map(|(_, at)| *at + period).min();
+        let received = tokio::select! {
+            received = raw.recv() => received,
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+                let due: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, (_, at))| *at + period <= now)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in due {
+                    if let Some((kind, _)) = pending.remove(&path) {
+                        if events.send(WatchEvent { path, kind }).is_err() {
+                            return;
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        let event = match received {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => {
+                warn!("Error watching {}: {}", root.display(), e);
+                continue;
+            }
+            None => return,
+        };
+        if event.need_rescan() {
+            pending.clear();
+            let rescan = WatchEvent {
+                path: root.clone(),
+                kind: WatchEventKind::Rescan,
+            };
+            if events.send(rescan).is_err() {
+                return;
+            }
+            continue;
+        }
+
+        let now = Instant::now();
+        for (path, kind) in classify(event) {
+            if !filter.matches(&path) {
+                continue;
+            }
+            match merge(pending.get(&path).map(|(kind, _)| *kind), kind) {
+                Some(kind) => {
+                    pending.insert(path, (kind, now));
+                }
+                None => {
+                    pending.remove(&path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_merge_and_filter() -> Result<()> {
+        use WatchEventKind::*;
+        assert_eq!(merge(merge(None, Created), Modified), Some(Created));
+        assert_eq!(merge(Some(Created), Removed), None);
+        assert_eq!(merge(Some(Removed), Created), Some(Modified));
+        assert_eq!(merge(Some(Modified), Removed), Some(Removed));
+
+        let options = WatchOptions::default()
+            .with_include("**/*.toml")
+            .with_exclude("cache/**");
+        let filter = PathFilter::new(Path::new("/etc/rastos"), &options.include, &options.exclude)?;
+        assert!(filter.matches(Path::new("/etc/rastos/profiles/desktop.toml")));
+        assert!(!filter.matches(Path::new("/etc/rastos/profiles/desktop.toml.swp")));
+        assert!(!filter.matches(Path::new("/etc/rastos/cache/state.toml")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_directory() -> Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("nested"))?;
+        let options = WatchOptions {
+            debounce: Duration::from_millis(50),
+            ..WatchOptions::default()
+        }
+        .with_exclude("*.tmp");
+        let mut events = watch(dir.path(), options)?;
+
+        std::fs::write(dir.path().join("ignored.tmp"), "x")?;
+        std::fs::write(dir.path().join("nested/config.toml"), "a = 1")?;
+        std::fs::write(dir.path().join("nested/config.toml"), "a = 2")?;
+
+        let event = timeout(Duration::from_secs(5), events.next())
+            .await
+            .map_err(|_| FsError::not_supported("no watch event within 5s"))?
+            .expect("watch stream ended");
+        assert_eq!(event.path, dir.path().canonicalize()?.join("nested/config.toml"));
+        assert_eq!(event.kind, WatchEventKind::Created);
+        Ok(())
+    }
+}
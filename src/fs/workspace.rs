@@ -0,0 +1,239 @@
+//! Managed scratch space for rastOS tasks
+//!
+//! A [`Workspace`] is a private directory for one task, such as staging a
+//! kernel package or holding a backup stream before upload. It lives under
+//! a configurable base directory, can be given a size limit, and is deleted
+//! when dropped. Each workspace records the PID of its owner next to it, so
+//! workspaces left behind by a crashed process are removed the next time a
+//! workspace is created under the same base.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use tempfile::TempDir;
+
+use super::directory::create_private_dir;
+use super::usage::disk_usage;
+use super::{FsError, Result};
+
+/// Suffix of the files recording the owner of a workspace
+const OWNER_SUFFIX: &str = ".owner";
+
+/// Options for creating workspaces
+#[derive(Debug, Clone)]
+pub struct WorkspaceOptions {
+    /// Directory the workspaces are created in
+    ///
+    /// It must belong to the current user and be closed to everyone else;
+    /// the default is a per-user directory in the system's temporary directory.
+    pub base: PathBuf,
+    /// Most bytes a workspace may use on disk
+    pub size_limit: Option<u64>,
+}
+
+impl Default for WorkspaceOptions {
+    fn default() -> Self {
+        Self {
+            base: std::env::temp_dir().join(format!("rastos-{}", nix::unistd::Uid::effective())),
+            size_limit: None,
+        }
+    }
+}
+
+impl WorkspaceOptions {
+    /// Create workspaces in `base`
+    pub fn with_base<P: AsRef<Path>>(mut self, base: P) -> Self {
+        self.base = base.as_ref().to_path_buf();
+        self
+    }
+
+    /// Limit how much disk space a workspace may use
+    pub fn with_size_limit(mut self, bytes: u64) -> Self {
+        self.size_limit = Some(bytes);
+        self
+    }
+}
+
+/// Scratch directory for one task, deleted when dropped
+#[derive(Debug)]
+pub struct Workspace {
+    dir: Option<TempDir>,
+    owner: PathBuf,
+    size_limit: Option<u64>,
+}
+
+impl Workspace {
+    /// Create a workspace for `task` under the base directory of `options`
+    ///
+    /// Workspaces under the same base whose owner process has exited are
+    /// cleaned up first. A base another user could write to is refused.
+    pub fn new(task: &str, options: &WorkspaceOptions) -> Result<Self> {
+        create_private_dir(&options.base).map_err(|e| match e {
+            FsError::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => FsError::permission_denied(&options.base),
+            e => e,
+        })?;
+        match cleanup_stale(&options.base) {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} stale workspaces from {}", removed, options.base.display()),
+            Err(e) => warn!("Cannot clean up stale workspaces in {}: {}", options.base.display(), e),
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{}.", task))
+            .tempdir_in(&options.base)?;
+        let owner = owner_path(dir.path());
+        fs::write(&owner, format!("{}\n", std::process::id()))?;
+        Ok(Self {
+            dir: Some(dir),
+            owner,
+            size_limit: options.size_limit,
+        })
+    }
+
+    /// Directory of the workspace
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().map(TempDir::path).expect("workspace directory exists until drop")
+    }
+
+    /// Path of `relative` inside the workspace
+    pub fn join<P: AsRef<Path>>(&self, relative: P) -> PathBuf {
+        self.path().join(relative)
+    }
+
+    /// Bytes the workspace uses on disk
+    pub fn usage(&self) -> Result<u64> {
+        Ok(disk_usage(self.path())?.on_disk)
+    }
+
+    /// Check the workspace against its size limit, returning its usage
+    ///
+    /// Long-running tasks call this between steps; the limit is not
+    /// enforced while files are being written.
+    pub fn check_size(&self) -> Result<u64> {
+        let used = self.usage()?;
+        match self.size_limit {
+            Some(limit) if used > limit => Err(FsError::SizeLimitExceeded {
+                path: self.path().to_path_buf(),
+                used,
+                limit,
+            }),
+            _ => Ok(used),
+        }
+    }
+
+    /// Keep the directory instead of deleting it, for example to debug a failed task
+    pub fn keep(mut self) -> PathBuf {
+        let _ = fs::remove_file(&self.owner);
+        self.dir.take().map(TempDir::keep).expect("workspace directory exists until drop")
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        // The directory goes first, so a crash in between leaves an owner
+        // file that the next cleanup handles
+        if let Some(dir) = self.dir.take() {
+            let path = dir.path().to_path_buf();
+            if let Err(e) = dir.close() {
+                warn!("Cannot remove workspace {}: {}", path.display(), e);
+                return;
+            }
+        }
+        let _ = fs::remove_file(&self.owner);
+    }
+}
+
+/// Remove workspaces under `base` whose owner process no longer runs
+///
+/// Returns the number of workspaces removed.
+pub fn cleanup_stale<P: AsRef<Path>>(base: P) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(base.as_ref())? {
+        let owner = entry?.path();
+        let Some(name) = owner.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some(dir_name) = name.strip_suffix(OWNER_SUFFIX) else {
+            continue;
+        };
+        let pid = fs::read_to_string(&owner).ok().and_then(|pid| pid.trim().parse::<libc::pid_t>().ok());
+        if pid.is_some_and(process_alive) {
+            continue;
+        }
+        let dir = owner.with_file_name(dir_name);
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::remove_file(&owner)?;
+        debug!("Removed stale workspace {}", dir.display());
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+fn owner_path(dir: &Path) -> PathBuf {
+    let mut path = OsString::from(dir.as_os_str());
+    path.push(OWNER_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Check whether a process exists; a reused PID counts as alive
+fn process_alive(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks that the process exists and may be signalled
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_workspace_lifecycle() -> Result<()> {
+        let base = tempdir()?;
+        let options = WorkspaceOptions::default().with_base(base.path()).with_size_limit(64 * 1024);
+
+        let workspace = Workspace::new("kernel-staging", &options)?;
+        let path = workspace.path().to_path_buf();
+        assert!(path.file_name().unwrap().to_string_lossy().starts_with("kernel-staging."));
+        assert!(owner_path(&path).exists());
+
+        fs::write(workspace.join("small"), vec![1u8; 1024])?;
+        workspace.check_size()?;
+        fs::write(workspace.join("large"), vec![1u8; 256 * 1024])?;
+        assert!(matches!(workspace.check_size(), Err(FsError::SizeLimitExceeded { .. })));
+
+        drop(workspace);
+        assert!(!path.exists());
+        assert!(!owner_path(&path).exists());
+
+        // Bases others can write to are refused
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(base.path(), fs::Permissions::from_mode(0o1777))?;
+        assert!(matches!(Workspace::new("shared", &options), Err(FsError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_stale() -> Result<()> {
+        let base = tempdir()?;
+        // Left behind by a process that no longer exists
+        fs::create_dir(base.path().join("backup.crashed"))?;
+        fs::write(base.path().join("backup.crashed.owner"), format!("{}\n", libc::pid_t::MAX))?;
+
+        let workspace = Workspace::new("backup", &WorkspaceOptions::default().with_base(base.path()))?;
+        assert!(!base.path().join("backup.crashed").exists());
+        assert!(!base.path().join("backup.crashed.owner").exists());
+        // Our own workspace is not stale
+        assert_eq!(cleanup_stale(base.path())?, 0);
+
+        let kept = workspace.keep();
+        assert!(kept.exists());
+        Ok(())
+    }
+}
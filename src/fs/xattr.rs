@@ -0,0 +1,298 @@
+//! Extended attribute operations for rastOS
+//!
+//! This module reads and writes extended attributes (xattrs), the name/value
+//! pairs the kernel stores next to a file's regular metadata. They carry file
+//! capabilities, SELinux labels and overlayfs bookkeeping, all of which are
+//! lost when a file is copied without them.
+//!
+//! Operations act on the path itself and do not follow symbolic links, so a
+//! link's own attributes are read and written rather than its target's.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::fs::xattr;
+//!
+//! fn main() -> Result<(), rastos::fs::FsError> {
+//!     xattr::set("example.txt", "user.origin", "rastos")?;
+//!     assert_eq!(xattr::get_string("example.txt", "user.origin")?.as_deref(), Some("rastos"));
+//!
+//!     for name in xattr::list("/usr/bin/ping")? {
+//!         println!("Found: {}", name);
+//!     }
+//!
+//!     if let Some(caps) = xattr::capabilities("/usr/bin/ping")? {
+//!         println!("Permitted capabilities: {:#x}", caps.permitted);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use log::debug;
+
+use super::{FsError, Result};
+
+/// File capabilities set with setcap(8)
+pub const CAPABILITY: &str = "security.capability";
+
+/// SELinux security context
+pub const SELINUX: &str = "security.selinux";
+
+/// Prefix of the attributes overlayfs keeps on its upper and work directories
+pub const OVERLAY_PREFIX: &str = "trusted.overlay.";
+
+/// `magic_etc` revision mask in `security.capability`
+const VFS_CAP_REVISION_MASK: u32 = 0xFF00_0000;
+
+/// Capabilities are raised into the effective set on exec
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+
+/// Decoded `security.capability` value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileCapabilities {
+    /// Permitted set, one bit per capability number
+    pub permitted: u64,
+    /// Inheritable set
+    pub inheritable: u64,
+    /// Whether the permitted set is raised into the effective set on exec
+    pub effective: bool,
+    /// Root user ID of the owning user namespace (revision 3 only)
+    pub rootid: Option<u32>,
+}
+
+impl FileCapabilities {
+    /// Decode a `security.capability` value
+    pub fn from_bytes(value: &[u8]) -> Result<Self> {
+        let words: Vec<u32> = value
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let invalid = || FsError::not_supported(format!("malformed {} value of {} bytes", CAPABILITY, value.len()));
+        let magic = *words.first().ok_or_else(invalid)?;
+        let effective = magic & VFS_CAP_FLAGS_EFFECTIVE != 0;
+
+        match (magic & VFS_CAP_REVISION_MASK, words.len(), value.len() % 4) {
+            (VFS_CAP_REVISION_1, 3, 0) => Ok(Self {
+                permitted: u64::from(words[1]),
+                inheritable: u64::from(words[2]),
+                effective,
+                rootid: None,
+            }),
+            (VFS_CAP_REVISION_2, 5, 0) | (VFS_CAP_REVISION_3, 6, 0) => Ok(Self {
+                permitted: u64::from(words[1]) | u64::from(words[3]) << 32,
+                inheritable: u64::from(words[2]) | u64::from(words[4]) << 32,
+                effective,
+                rootid: words.get(5).copied(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Encode as a revision 2 value, or revision 3 when a root ID is set
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let revision = if self.rootid.is_some() { VFS_CAP_REVISION_3 } else { VFS_CAP_REVISION_2 };
+        let flags = if self.effective { VFS_CAP_FLAGS_EFFECTIVE } else { 0 };
+        let mut words = vec![
+            revision | flags,
+            self.permitted as u32,
+            self.inheritable as u32,
+            (self.permitted >> 32) as u32,
+            (self.inheritable >> 32) as u32,
+        ];
+        words.extend(self.rootid);
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    /// Check whether capability number `cap` is permitted
+    pub fn has_permitted(&self, cap: u32) -> bool {
+        cap < 64 && self.permitted & (1 << cap) != 0
+    }
+}
+
+/// Get the value of an extended attribute, `None` if it is not set
+pub fn get<P: AsRef<Path>>(path: P, name: &str) -> Result<Option<Vec<u8>>> {
+    let path = path.as_ref();
+    ::xattr::get(path, name).map_err(|e| map_error(path, e))
+}
+
+/// Get the value of an extended attribute as a string
+///
+/// A trailing NUL byte, as stored with SELinux labels, is dropped.
+pub fn get_string<P: AsRef<Path>>(path: P, name: &str) -> Result<Option<String>> {
+    let path = path.as_ref();
+    match get(path, name)? {
+        Some(mut value) => {
+            if value.last() == Some(&0) {
+                value.pop();
+            }
+            String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| FsError::invalid_path(format!("{} of {} is not valid UTF-8", name, path.display())))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Set an extended attribute, replacing any previous value
+pub fn set<P: AsRef<Path>, V: AsRef<[u8]>>(path: P, name: &str, value: V) -> Result<()> {
+    let path = path.as_ref();
+    ::xattr::set(path, name, value.as_ref()).map_err(|e| map_error(path, e))
+}
+
+/// Remove an extended attribute
+///
+/// Returns whether the attribute was set.
+pub fn remove<P: AsRef<Path>>(path: P, name: &str) -> Result<bool> {
+    let path = path.as_ref();
+    match ::xattr::remove(path, name) {
+        Ok(()) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(false),
+        Err(e) => Err(map_error(path, e)),
+    }
+}
+
+/// List the names of the extended attributes of a file
+///
+/// Attributes in namespaces the caller may not read, such as `trusted.*`
+/// for unprivileged users, are not listed.
+pub fn list<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let names = ::xattr::list(path).map_err(|e| map_error(path, e))?;
+    Ok(names.map(|name| name.to_string_lossy().into_owned()).collect())
+}
+
+/// Get every extended attribute of a file
+pub fn get_all<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+    let path = path.as_ref();
+    let mut attributes = BTreeMap::new();
+    for name in list(path)? {
+        // The attribute may have been removed since it was listed
+        if let Some(value) = get(path, &name)? {
+            attributes.insert(name, value);
+        }
+    }
+    Ok(attributes)
+}
+
+/// Get every extended attribute of a file, following symbolic links
+pub(crate) fn get_all_deref(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut attributes = BTreeMap::new();
+    for name in ::xattr::list_deref(path).map_err(|e| map_error(path, e))? {
+        if let Some(value) = ::xattr::get_deref(path, &name).map_err(|e| map_error(path, e))? {
+            attributes.insert(name.to_string_lossy().into_owned(), value);
+        }
+    }
+    Ok(attributes)
+}
+
+/// Copy every extended attribute of `from` to `to`
+///
+/// Attributes the destination rejects as unsupported, for example
+/// `security.selinux` on a filesystem without labels, are skipped.
+pub fn copy_all<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let to = to.as_ref();
+    for (name, value) in get_all(from)? {
+        match set(to, &name, &value) {
+            Err(FsError::NotSupported(_)) => debug!("Skipping {} on {}", name, to.display()),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Get the file capabilities of an executable
+pub fn capabilities<P: AsRef<Path>>(path: P) -> Result<Option<FileCapabilities>> {
+    get(path, CAPABILITY)?
+        .map(|value| FileCapabilities::from_bytes(&value))
+        .transpose()
+}
+
+/// Set the file capabilities of an executable
+pub fn set_capabilities<P: AsRef<Path>>(path: P, caps: &FileCapabilities) -> Result<()> {
+    set(path, CAPABILITY, caps.to_bytes())
+}
+
+/// Get the SELinux label of a file
+pub fn selinux_label<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    get_string(path, SELINUX)
+}
+
+/// Get the overlayfs attributes of a file, without the `trusted.overlay.` prefix
+pub fn overlay_attributes<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+    Ok(get_all(path)?
+        .into_iter()
+        .filter_map(|(name, value)| name.strip_prefix(OVERLAY_PREFIX).map(|n| (n.to_string(), value)))
+        .collect())
+}
+
+/// Translate xattr errno values into [`FsError`]s
+fn map_error(path: &Path, e: io::Error) -> FsError {
+    match e.kind() {
+        io::ErrorKind::NotFound => FsError::not_found(path),
+        io::ErrorKind::PermissionDenied => FsError::permission_denied(path),
+        _ if e.raw_os_error() == Some(libc::ENOTSUP) => {
+            FsError::not_supported(format!("extended attributes on {}", path.display()))
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_xattr_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let file_path = dir.path().join("test.txt");
+        File::create(&file_path)?;
+
+        match set(&file_path, "user.rastos.test", "value") {
+            // tmpfs before Linux 6.6 has no user xattrs
+            Err(FsError::NotSupported(_)) => return Ok(()),
+            result => result?,
+        }
+        assert_eq!(get(&file_path, "user.rastos.test")?, Some(b"value".to_vec()));
+        assert_eq!(get_string(&file_path, "user.rastos.missing")?, None);
+        assert!(list(&file_path)?.contains(&"user.rastos.test".to_string()));
+
+        let copy_path = dir.path().join("copy.txt");
+        File::create(&copy_path)?;
+        copy_all(&file_path, &copy_path)?;
+        assert_eq!(get_string(&copy_path, "user.rastos.test")?.as_deref(), Some("value"));
+
+        assert!(remove(&file_path, "user.rastos.test")?);
+        assert!(!remove(&file_path, "user.rastos.test")?);
+        assert_eq!(get(&file_path, "user.rastos.test")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_capabilities() {
+        // setcap cap_net_raw=ep, as on /usr/bin/ping
+        let value = [1, 0, 0, 2, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let caps = FileCapabilities::from_bytes(&value).unwrap();
+        assert!(caps.effective);
+        assert!(caps.has_permitted(13));
+        assert!(!caps.has_permitted(12));
+        assert_eq!(caps.rootid, None);
+        assert_eq!(caps.to_bytes(), value);
+
+        let namespaced = FileCapabilities {
+            rootid: Some(100000),
+            ..caps
+        };
+        assert_eq!(FileCapabilities::from_bytes(&namespaced.to_bytes()).unwrap(), namespaced);
+        assert!(FileCapabilities::from_bytes(&value[..7]).is_err());
+    }
+}
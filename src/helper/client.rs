@@ -0,0 +1,85 @@
+//! Sending requests to the privileged helper
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+
+use log::debug;
+
+use super::{HelperError, Request, Response, Result, HELPER_PATH, HELPER_SOCKET};
+
+/// Connection to the privileged helper
+pub struct Client {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+    /// The setuid helper, when it was run directly
+    child: Option<Child>,
+}
+
+impl Client {
+    /// Connect to the helper service, or run the setuid helper without one
+    pub fn connect() -> Result<Self> {
+        match UnixStream::connect(HELPER_SOCKET) {
+            Ok(stream) => {
+                let reader = stream.try_clone()?;
+                return Ok(Self {
+                    reader: BufReader::new(Box::new(reader)),
+                    writer: Box::new(stream),
+                    child: None,
+                });
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => {
+                debug!("Helper service unavailable: {}", e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        // Running a helper that is not setuid would only fail the same way
+        match fs::metadata(HELPER_PATH) {
+            Ok(meta) if meta.permissions().mode() & libc::S_ISUID != 0 => {}
+            Ok(_) => return Err(HelperError::Unavailable),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(HelperError::Unavailable),
+            Err(e) => return Err(e.into()),
+        }
+        let mut child = Command::new(HELPER_PATH)
+            .arg("--stdio")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            unreachable!("both pipes were requested");
+        };
+        Ok(Self {
+            reader: BufReader::new(Box::new(stdout)),
+            writer: Box::new(stdin),
+            child: Some(child),
+        })
+    }
+
+    /// Have the helper perform `request`
+    pub fn call(&mut self, request: &Request) -> Result<()> {
+        debug!("Asking the helper to {}", request);
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the helper closed the connection").into());
+        }
+        serde_json::from_str::<Response>(&line)?.into_result()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // Closing its standard input ends the helper
+            self.writer = Box::new(io::sink());
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Privilege separation
+//!
+//! The few operations rastOS needs root for — Btrfs subvolume ioctls,
+//! mounting and writing the ID maps of a user namespace — are carried out
+//! by `rastos-helper`, a small process that does nothing else. The CLI and
+//! rastosd run unprivileged and send it [`Request`]s; the operations in
+//! [`crate::sys`] retry through the helper whenever the kernel refuses them
+//! with `EPERM`, so callers need no changes.
+//!
+//! The helper is reached in one of two ways:
+//!
+//! - **socket**: a system service listening on [`HELPER_SOCKET`],
+//!   preferably socket-activated with `dist/systemd/rastos-helper.socket`.
+//!   The peer is identified by `SO_PEERCRED`.
+//! - **setuid**: without the service, [`HELPER_PATH`] is run with `--stdio`
+//!   if it is setuid root, speaking the same protocol over its standard
+//!   input and output. The peer is its parent process.
+//!
+//! Either way every request is authorized for the peer: root and the
+//! [`HelperConfig::trusted_users`] may do everything, everyone else needs
+//! the request's polkit action (`dist/polkit/org.rastos.helper.policy`).
+//! Paths are then checked against [`HelperConfig`]: subvolumes are only
+//! created or deleted below [`HelperConfig::subvolume_roots`] and block
+//! devices only mounted below [`HelperConfig::mount_roots`], without
+//! setuid binaries and device files.
+//!
+//! The protocol is one JSON [`Request`] per line, each answered by one JSON
+//! [`Response`] line, for as long as the connection stays open:
+//!
+//! ```text
+//! {"op":"delete-subvolume","path":"/.snapshots/42","recursive":false}
+//! {"status":"done"}
+//! ```
+
+pub mod client;
+pub mod server;
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::snapshot::DEFAULT_SNAPSHOT_DIR;
+
+pub use client::Client;
+pub use server::{Helper, Peer};
+
+/// Socket of the helper service
+pub const HELPER_SOCKET: &str = "/run/rastos/helper.sock";
+
+/// The helper executable, run directly when it is setuid root
+pub const HELPER_PATH: &str = "/usr/lib/rastos/rastos-helper";
+
+/// Configuration of the helper
+pub const HELPER_CONFIG: &str = "/etc/rast/helper.toml";
+
+/// Error type for the privileged helper
+#[derive(Error, Debug)]
+pub enum HelperError {
+    /// I/O error, such as a connection that broke
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Helper configuration that could not be parsed
+    #[error("Invalid helper configuration: {0}")]
+    Config(#[from] toml::de::Error),
+
+    /// A message that is not valid for the protocol
+    #[error("Malformed helper message: {0}")]
+    Protocol(#[from] serde_json::Error),
+
+    /// Neither the helper service nor a setuid helper is installed
+    #[error("The privileged helper is not installed")]
+    Unavailable,
+
+    /// The peer may not perform the request
+    #[error("Not authorized to {0}")]
+    NotAuthorized(String),
+
+    /// The request breaks the helper's policy, such as a path outside its roots
+    #[error("Refused: {0}")]
+    Denied(String),
+
+    /// The operation was allowed but failed
+    #[error("{message}")]
+    Failed {
+        /// Description of the failure
+        message: String,
+        /// `errno` of the failure, if it was a system call
+        errno: Option<i32>,
+    },
+}
+
+/// Result type for the privileged helper
+pub type Result<T> = std::result::Result<T, HelperError>;
+
+impl From<HelperError> for io::Error {
+    fn from(err: HelperError) -> Self {
+        match err {
+            HelperError::Io(e) => e,
+            HelperError::Failed { errno: Some(errno), message } => {
+                io::Error::new(io::Error::from_raw_os_error(errno).kind(), message)
+            }
+            e @ (HelperError::NotAuthorized(_) | HelperError::Denied(_)) => {
+                io::Error::new(io::ErrorKind::PermissionDenied, e)
+            }
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// A range of IDs mapped into a user namespace, as in `/proc/<pid>/uid_map`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    /// First ID inside the namespace
+    pub inside: u32,
+    /// First ID outside it
+    pub outside: u32,
+    /// Number of IDs
+    pub count: u32,
+}
+
+/// An operation the helper performs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Request {
+    /// Create an empty subvolume
+    CreateSubvolume {
+        /// Path of the new subvolume
+        path: PathBuf,
+    },
+    /// Snapshot a subvolume
+    Snapshot {
+        /// Subvolume to snapshot
+        source: PathBuf,
+        /// Path of the snapshot
+        dest: PathBuf,
+        /// Whether the snapshot is read-only
+        read_only: bool,
+    },
+    /// Delete a subvolume
+    DeleteSubvolume {
+        /// Subvolume to delete
+        path: PathBuf,
+        /// Whether to delete subvolumes nested in it first
+        recursive: bool,
+    },
+    /// Mount a block device, creating the mount point if missing
+    Mount {
+        /// Block device
+        source: PathBuf,
+        /// Mount point
+        target: PathBuf,
+        /// Filesystem type, such as `btrfs`
+        fstype: String,
+        /// Filesystem options, such as `subvolid=5`
+        options: Option<String>,
+    },
+    /// Unmount a filesystem
+    Unmount {
+        /// Mount point
+        target: PathBuf,
+    },
+    /// Write the ID maps of a process's user namespace
+    MapIds {
+        /// Process in the namespace, owned by the peer
+        pid: u32,
+        /// User ID ranges
+        uid_map: Vec<IdMap>,
+        /// Group ID ranges
+        gid_map: Vec<IdMap>,
+    },
+}
+
+impl Request {
+    /// Polkit action that authorizes the request
+    pub fn action(&self) -> &'static str {
+        match self {
+            Request::CreateSubvolume { .. } | Request::Snapshot { .. } | Request::DeleteSubvolume { .. } => {
+                "org.rastos.helper.subvolumes"
+            }
+            Request::Mount { .. } | Request::Unmount { .. } => "org.rastos.helper.mount",
+            Request::MapIds { .. } => "org.rastos.helper.id-maps",
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::CreateSubvolume { path } => write!(f, "create subvolume {}", path.display()),
+            Request::Snapshot { source, dest, .. } => {
+                write!(f, "snapshot {} to {}", source.display(), dest.display())
+            }
+            Request::DeleteSubvolume { path, .. } => write!(f, "delete subvolume {}", path.display()),
+            Request::Mount { source, target, .. } => {
+                write!(f, "mount {} on {}", source.display(), target.display())
+            }
+            Request::Unmount { target } => write!(f, "unmount {}", target.display()),
+            Request::MapIds { pid, .. } => write!(f, "map IDs of process {}", pid),
+        }
+    }
+}
+
+/// The helper's answer to a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum Response {
+    /// The operation succeeded
+    Done,
+    /// The peer may not perform the request
+    NotAuthorized {
+        /// What was refused
+        message: String,
+    },
+    /// The request breaks the helper's policy
+    Denied {
+        /// Why it was refused
+        message: String,
+    },
+    /// The operation failed
+    Failed {
+        /// Description of the failure
+        message: String,
+        /// `errno` of the failure, if it was a system call
+        errno: Option<i32>,
+    },
+}
+
+impl Response {
+    /// The response as the result of the request
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            Response::Done => Ok(()),
+            Response::NotAuthorized { message } => Err(HelperError::NotAuthorized(message)),
+            Response::Denied { message } => Err(HelperError::Denied(message)),
+            Response::Failed { message, errno } => Err(HelperError::Failed { message, errno }),
+        }
+    }
+}
+
+/// What the helper lets unprivileged peers touch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HelperConfig {
+    /// Directories below which subvolumes may be created and deleted
+    pub subvolume_roots: Vec<PathBuf>,
+    /// Directories below which block devices may be mounted
+    pub mount_roots: Vec<PathBuf>,
+    /// Filesystem types that may be mounted
+    pub filesystems: Vec<String>,
+    /// Users authorized for every request without polkit, such as the
+    /// account rastosd runs as
+    pub trusted_users: Vec<String>,
+}
+
+impl Default for HelperConfig {
+    fn default() -> Self {
+        Self {
+            subvolume_roots: vec![
+                PathBuf::from("/.snapshots"),
+                PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+                PathBuf::from("/run/rastos-update/toplevel"),
+                PathBuf::from("/run/rastos-reset/toplevel"),
+            ],
+            mount_roots: vec![PathBuf::from("/run/rastos-update"), PathBuf::from("/run/rastos-reset")],
+            filesystems: vec!["btrfs".to_string()],
+            trusted_users: Vec::new(),
+        }
+    }
+}
+
+impl HelperConfig {
+    /// Load the configuration from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Load [`HELPER_CONFIG`], or the defaults if it does not exist
+    pub fn load() -> Result<Self> {
+        match Self::from_file(HELPER_CONFIG) {
+            Err(HelperError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request = Request::DeleteSubvolume {
+            path: PathBuf::from("/.snapshots/42"),
+            recursive: false,
+        };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"op":"delete-subvolume","path":"/.snapshots/42","recursive":false}"#);
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+        assert_eq!(request.action(), "org.rastos.helper.subvolumes");
+
+        let response: Response = serde_json::from_str(r#"{"status":"failed","message":"busy","errno":16}"#).unwrap();
+        let err = io::Error::from(response.into_result().unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+    }
+}
@@ -0,0 +1,411 @@
+//! The privileged side of the helper
+//!
+//! Runs as root and performs nothing but [`Request`]s, each one in three
+//! steps that are logged: authorize the peer, check the request against
+//! the [`HelperConfig`], then call the same [`crate::sys`] function an
+//! unprivileged caller tried first.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+
+use log::{info, warn};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+use super::{HelperConfig, IdMap, Request, Response, Result};
+use crate::sys;
+
+/// pkcheck, which asks polkit whether a process is authorized
+const PKCHECK: &str = "/usr/bin/pkcheck";
+
+/// The process a request comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    /// Process ID
+    pub pid: u32,
+    /// Real user ID
+    pub uid: u32,
+    /// Real group ID
+    pub gid: u32,
+}
+
+impl Peer {
+    /// The process connected to `stream`, as the kernel reports it
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let cred = getsockopt(stream.as_raw_fd(), PeerCredentials).map_err(io::Error::from)?;
+        Ok(Self {
+            pid: cred.pid() as u32,
+            uid: cred.uid(),
+            gid: cred.gid(),
+        })
+    }
+
+    /// The process that ran this one, for the setuid helper
+    ///
+    /// Its IDs are this process's real IDs, so this must be called before
+    /// the helper changes them.
+    pub fn parent() -> Self {
+        Self {
+            pid: std::os::unix::process::parent_id(),
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+        }
+    }
+}
+
+/// The socket to serve: the one systemd passed, or `path` bound anew
+pub fn listener(path: &Path) -> Result<UnixListener> {
+    // sd_listen_fds(3): passed sockets start at descriptor 3
+    let activated = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(process::id())
+        && env::var("LISTEN_FDS").as_deref() == Ok("1");
+    if activated {
+        // SAFETY: systemd passed descriptor 3 to this process for it to own
+        return Ok(unsafe { UnixListener::from_raw_fd(3) });
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            let message = format!("{} exists and is not a socket", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    // Anyone may connect; every request is authorized on its own
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+/// Performs requests for authorized peers
+#[derive(Debug, Clone)]
+pub struct Helper {
+    config: HelperConfig,
+}
+
+impl Helper {
+    /// Create a helper enforcing `config`
+    pub fn new(config: HelperConfig) -> Self {
+        Self { config }
+    }
+
+    /// Serve every connection to `listener`, each on its own thread
+    ///
+    /// A request waiting for the user to authenticate holds up no other
+    /// connection.
+    pub fn serve(&self, listener: UnixListener) -> Result<()> {
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    if let Err(e) = self.serve_stream(stream) {
+                        warn!("Helper connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    /// Serve `peer`, the parent process, over standard input and output
+    pub fn serve_stdio(&self, peer: &Peer) -> Result<()> {
+        self.handle(peer, io::stdin().lock(), io::stdout().lock())
+    }
+
+    fn serve_stream(&self, stream: UnixStream) -> Result<()> {
+        let peer = Peer::of(&stream)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.handle(&peer, reader, stream)
+    }
+
+    /// Answer every request `reader` carries until it is closed
+    pub fn handle<R: BufRead, W: Write>(&self, peer: &Peer, reader: R, mut writer: W) -> Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => self.respond(peer, &request),
+                Err(e) => Response::Denied {
+                    message: format!("malformed request: {}", e),
+                },
+            };
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Authorize, check and perform `request` for `peer`
+    pub fn respond(&self, peer: &Peer, request: &Request) -> Response {
+        match self.authorized(peer, request) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Refused to {} for uid {} (pid {}): not authorized", request, peer.uid, peer.pid);
+                return Response::NotAuthorized {
+                    message: request.to_string(),
+                };
+            }
+            Err(e) => {
+                warn!("Failed to authorize uid {} to {}: {}", peer.uid, request, e);
+                return Response::Failed {
+                    message: format!("authorization failed: {}", e),
+                    errno: None,
+                };
+            }
+        }
+        if let Err(message) = self.check(peer, request) {
+            warn!("Refused to {} for uid {} (pid {}): {}", request, peer.uid, peer.pid, message);
+            return Response::Denied { message };
+        }
+        info!("Performing '{}' for uid {} (pid {})", request, peer.uid, peer.pid);
+        match perform(peer, request) {
+            Ok(()) => Response::Done,
+            Err(e) => Response::Failed {
+                message: format!("Failed to {}: {}", request, e),
+                errno: e.raw_os_error(),
+            },
+        }
+    }
+
+    /// Whether `peer` may perform `request`
+    fn authorized(&self, peer: &Peer, request: &Request) -> io::Result<bool> {
+        if peer.uid == 0 {
+            return Ok(true);
+        }
+        let trusted = self
+            .config
+            .trusted_users
+            .iter()
+            .filter_map(users::get_user_by_name)
+            .any(|user| user.uid() == peer.uid);
+        if trusted {
+            return Ok(true);
+        }
+        polkit_allows(peer, request.action())
+    }
+
+    /// Check `request` against the configuration
+    pub fn check(&self, peer: &Peer, request: &Request) -> std::result::Result<(), String> {
+        let config = &self.config;
+        match request {
+            Request::CreateSubvolume { path } => within(path, &config.subvolume_roots),
+            Request::Snapshot { source, dest, .. } => {
+                if !source.is_absolute() || !sys::btrfs::is_subvolume(source).unwrap_or(false) {
+                    return Err(format!("{} is not a subvolume", source.display()));
+                }
+                within(dest, &config.subvolume_roots)
+            }
+            Request::DeleteSubvolume { path, .. } => {
+                within(path, &config.subvolume_roots)?;
+                if !sys::btrfs::is_subvolume(path).unwrap_or(false) {
+                    return Err(format!("{} is not a subvolume", path.display()));
+                }
+                Ok(())
+            }
+            Request::Mount { source, target, fstype, options } => {
+                if !config.filesystems.contains(fstype) {
+                    return Err(format!("mounting {} filesystems is not allowed", fstype));
+                }
+                if !fs::metadata(source).is_ok_and(|meta| meta.file_type().is_block_device()) {
+                    return Err(format!("{} is not a block device", source.display()));
+                }
+                let plain = |c: char| c.is_ascii_alphanumeric() || "=,._/@:-".contains(c);
+                if options.as_deref().is_some_and(|options| !options.chars().all(plain)) {
+                    return Err("the mount options contain unexpected characters".to_string());
+                }
+                within(target, &config.mount_roots)
+            }
+            Request::Unmount { target } => within(target, &config.mount_roots),
+            Request::MapIds { pid, uid_map, gid_map } => {
+                if process_owner(*pid).ok() != Some(peer.uid) {
+                    return Err(format!("process {} does not belong to uid {}", pid, peer.uid));
+                }
+                let subuids = delegated_to(peer, "/etc/subuid");
+                let subgids = delegated_to(peer, "/etc/subgid");
+                if let Some(map) = uid_map.iter().find(|map| !map_allowed(map, peer.uid, &subuids)) {
+                    return Err(format!("uid {} is not delegated to uid {}", map.outside, peer.uid));
+                }
+                if let Some(map) = gid_map.iter().find(|map| !map_allowed(map, peer.gid, &subgids)) {
+                    return Err(format!("gid {} is not delegated to uid {}", map.outside, peer.uid));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Carry out a checked request
+fn perform(peer: &Peer, request: &Request) -> io::Result<()> {
+    match request {
+        Request::CreateSubvolume { path } => sys::btrfs::create_subvolume(path),
+        Request::Snapshot { source, dest, read_only } => sys::btrfs::snapshot(source, dest, *read_only),
+        Request::DeleteSubvolume { path, recursive } => sys::btrfs::delete_subvolume(path, *recursive),
+        Request::Mount { source, target, fstype, options } => {
+            sys::mount::mount(source, target, fstype, options.as_deref())
+        }
+        Request::Unmount { target } => sys::mount::unmount(target),
+        Request::MapIds { pid, uid_map, gid_map } => {
+            // As newgidmap does since CVE-2018-7169: a group mapped without
+            // delegation must not be droppable with setgroups(2)
+            let subgids = delegated_to(peer, "/etc/subgid");
+            let setgroups = gid_map.iter().all(|map| map_delegated(map, &subgids));
+            sys::userns::write_id_maps_privileged(*pid, uid_map, gid_map, setgroups)
+        }
+    }
+}
+
+/// Check that `path` lies below one of `roots` and cannot be redirected
+///
+/// Every existing directory from the root down must be a real directory
+/// owned by root and writable by nobody else, so no other user can swap in
+/// a symbolic link between the check and the operation.
+fn within(path: &Path, roots: &[PathBuf]) -> std::result::Result<(), String> {
+    let plain = path.components().all(|c| matches!(c, Component::RootDir | Component::Normal(_)));
+    if !path.is_absolute() || !plain {
+        return Err(format!("{} is not a plain absolute path", path.display()));
+    }
+    let Some(root) = roots.iter().find(|root| path.starts_with(root) && path != root.as_path()) else {
+        return Err(format!("{} is outside the directories the helper manages", path.display()));
+    };
+    let unsafe_dir = |dir: &Path| format!("{} is not a directory only root can change", dir.display());
+    // The configured root itself may be a symbolic link
+    let dirs = path.ancestors().skip(1).take_while(|dir| dir.starts_with(root));
+    for dir in dirs {
+        let meta = if dir == root.as_path() { fs::metadata(dir) } else { fs::symlink_metadata(dir) };
+        match meta {
+            Ok(meta) if meta.is_dir() && meta.uid() == 0 && meta.mode() & 0o022 == 0 => {}
+            Ok(_) => return Err(unsafe_dir(dir)),
+            // Missing directories are created by the helper, as root
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", dir.display(), e)),
+        }
+    }
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        return Err(format!("{} is a symbolic link", path.display()));
+    }
+    Ok(())
+}
+
+/// Real user ID of process `pid`
+fn process_owner(pid: u32) -> io::Result<u32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next())
+        .and_then(|uid| uid.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no Uid line for process {}", pid)))
+}
+
+/// Ranges `file` (`/etc/subuid` or `/etc/subgid`) delegates to `peer`
+fn delegated_to(peer: &Peer, file: &str) -> Vec<(u32, u32)> {
+    let name = users::get_user_by_uid(peer.uid).map(|user| user.name().to_string_lossy().into_owned());
+    subordinate_ids(&fs::read_to_string(file).unwrap_or_default(), &name.unwrap_or_default(), peer.uid)
+}
+
+/// ID ranges an `/etc/subuid` or `/etc/subgid` file grants to user `name` or `id`
+fn subordinate_ids(content: &str, name: &str, id: u32) -> Vec<(u32, u32)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().split(':');
+            let (owner, start, count) = (fields.next()?, fields.next()?, fields.next()?);
+            if owner != name && owner.parse() != Ok(id) {
+                return None;
+            }
+            Some((start.parse().ok()?, count.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Whether `map` only maps the peer's own ID or IDs delegated to it
+fn map_allowed(map: &IdMap, own: u32, delegated: &[(u32, u32)]) -> bool {
+    (map.outside == own && map.count == 1) || map_delegated(map, delegated)
+}
+
+/// Whether `map` lies within the ranges delegated to the peer
+fn map_delegated(map: &IdMap, delegated: &[(u32, u32)]) -> bool {
+    let end = u64::from(map.outside) + u64::from(map.count);
+    map.count > 0
+        && delegated
+            .iter()
+            .any(|&(start, count)| map.outside >= start && end <= u64::from(start) + u64::from(count))
+}
+
+/// Whether polkit authorizes `peer` for `action`, letting the user
+/// authenticate if the policy asks for it
+fn polkit_allows(peer: &Peer, action: &str) -> io::Result<bool> {
+    // The start time keeps a recycled PID from inheriting the authorization
+    let stat = fs::read_to_string(format!("/proc/{}/stat", peer.pid))?;
+    let start = start_time(&stat)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unreadable stat of process {}", peer.pid)))?;
+    let status = Command::new(PKCHECK)
+        .env_clear()
+        .args(["--action-id", action, "--allow-user-interaction", "--process"])
+        .arg(format!("{},{},{}", peer.pid, start, peer.uid))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// Start time, field 22 of `/proc/<pid>/stat`
+///
+/// The command name in field 2 may itself contain spaces and parentheses,
+/// so fields are counted from the last closing parenthesis.
+fn start_time(stat: &str) -> Option<u64> {
+    stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_within_roots() {
+        let roots = [PathBuf::from("/.snapshots")];
+        assert!(within(Path::new("/.snapshots/../etc"), &roots).is_err());
+        assert!(within(Path::new("/var/lib/other"), &roots).is_err());
+        assert!(within(Path::new("/.snapshots"), &roots).is_err());
+        assert!(within(Path::new("relative/42"), &roots).is_err());
+    }
+
+    #[test]
+    fn test_id_delegation() {
+        let subuid = "alice:100000:65536\n1001:200000:65536\nbob:300000:65536\n";
+        let delegated = subordinate_ids(subuid, "alice", 1000);
+        assert_eq!(delegated, vec![(100000, 65536)]);
+        assert_eq!(subordinate_ids(subuid, "carol", 1001), vec![(200000, 65536)]);
+
+        let map = |outside, count| IdMap { inside: 0, outside, count };
+        assert!(map_allowed(&map(1000, 1), 1000, &delegated));
+        assert!(map_allowed(&map(100000, 65536), 1000, &delegated));
+        assert!(!map_allowed(&map(100000, 65537), 1000, &delegated));
+        assert!(!map_allowed(&map(0, 1), 1000, &delegated));
+        assert!(!map_allowed(&map(1000, 2), 1000, &delegated));
+        // The peer's own group is allowed, but not delegated
+        assert!(!map_delegated(&map(1000, 1), &delegated));
+        assert!(map_delegated(&map(100000, 10), &delegated));
+
+        let stat = "1234 (a (b) c) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 98765 1000 50";
+        assert_eq!(start_time(stat), Some(98765));
+    }
+}
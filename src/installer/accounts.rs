@@ -0,0 +1,366 @@
+//! User accounts and sudo
+//!
+//! Creates the initial users inside the installed system with `useradd
+//! --root`, hashes their passwords with the system `crypt(3)` (SHA-512) and
+//! sets them with `chpasswd -e` over stdin so hashes never show up in a
+//! command line, writes a validated sudoers drop-in and optionally locks the
+//! root account.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::Read;
+use std::os::raw::c_char;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::encryption::{Passphrase, run_with_input};
+use super::error::InstallerError;
+use super::partition::run;
+use super::report::CommandLog;
+
+/// Sudoers drop-in written by the installer
+pub const SUDOERS_DROP_IN: &str = "etc/sudoers.d/10-rastos";
+
+/// Characters allowed in crypt salts
+const SALT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[link(name = "crypt")]
+unsafe extern "C" {
+    fn crypt(key: *const c_char, salt: *const c_char) -> *mut c_char;
+}
+
+/// `crypt(3)` returns a static buffer
+static CRYPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// sudo access for members of `wheel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SudoPolicy {
+    /// Allowed after entering their password
+    #[default]
+    Password,
+    /// Allowed without a password
+    NoPassword,
+    /// No sudo rule is written
+    Disabled,
+}
+
+/// An account to create
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserConfig {
+    /// Login name
+    pub name: String,
+
+    /// Full name (GECOS)
+    #[serde(default)]
+    pub full_name: Option<String>,
+
+    /// Plain password, hashed at install time
+    #[serde(default, skip_serializing)]
+    pub password: Option<Passphrase>,
+
+    /// Pre-hashed password in crypt(3) format
+    #[serde(default)]
+    pub password_hash: Option<String>,
+
+    /// Supplementary groups
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>,
+
+    /// Login shell
+    #[serde(default = "default_shell")]
+    pub shell: String,
+}
+
+fn default_groups() -> Vec<String> {
+    vec!["wheel".to_string()]
+}
+
+fn default_shell() -> String {
+    "/bin/bash".to_string()
+}
+
+impl UserConfig {
+    /// A user in `wheel` with the default shell
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            full_name: None,
+            password: None,
+            password_hash: None,
+            groups: default_groups(),
+            shell: default_shell(),
+        }
+    }
+
+    /// Set the password, hashed at install time
+    pub fn with_password(mut self, password: Passphrase) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Set supplementary groups
+    pub fn with_groups<I, S>(mut self, groups: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.groups = groups.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the login shell
+    pub fn with_shell<S: Into<String>>(mut self, shell: S) -> Self {
+        self.shell = shell.into();
+        self
+    }
+
+    /// Check the login name
+    pub fn validate(&self) -> Result<(), InstallerError> {
+        if !is_valid_username(&self.name) {
+            return Err(InstallerError::InvalidProfile(format!("invalid user name '{}'", self.name)));
+        }
+        if self.name == "root" {
+            return Err(InstallerError::InvalidProfile("root cannot be created as a user".to_string()));
+        }
+        if let Some(hash) = &self.password_hash {
+            if hash.contains([':', '\n']) {
+                return Err(InstallerError::InvalidProfile(format!("invalid password hash for '{}'", self.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Password hash for `/etc/shadow`, if a password is set
+    pub(crate) fn shadow_hash(&self) -> Result<Option<String>, InstallerError> {
+        match (&self.password_hash, &self.password) {
+            (Some(hash), _) => Ok(Some(hash.clone())),
+            (None, Some(password)) => hash_password(password.expose()).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Accounts of the installed system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+    /// Users to create
+    #[serde(rename = "user")]
+    pub users: Vec<UserConfig>,
+
+    /// sudo access for `wheel`
+    pub sudo: SudoPolicy,
+
+    /// Additional sudoers lines
+    pub sudo_rules: Vec<String>,
+
+    /// Lock the root password
+    pub lock_root: bool,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            users: Vec::new(),
+            sudo: SudoPolicy::default(),
+            sudo_rules: Vec::new(),
+            lock_root: true,
+        }
+    }
+}
+
+impl AccountsConfig {
+    /// Add a user
+    pub fn with_user(mut self, user: UserConfig) -> Self {
+        self.users.push(user);
+        self
+    }
+
+    /// Set the sudo policy
+    pub fn with_sudo(mut self, sudo: SudoPolicy) -> Self {
+        self.sudo = sudo;
+        self
+    }
+
+    /// Lock or keep the root password
+    pub fn with_lock_root(mut self, lock: bool) -> Self {
+        self.lock_root = lock;
+        self
+    }
+
+    /// Check users and that locking root leaves an administrator
+    pub fn validate(&self) -> Result<(), InstallerError> {
+        for user in &self.users {
+            user.validate()?;
+        }
+        let admin = self.sudo != SudoPolicy::Disabled
+            && self.users.iter().any(|u| u.groups.iter().any(|g| g == "wheel"));
+        if self.lock_root && !admin && self.sudo_rules.is_empty() {
+            return Err(InstallerError::InvalidProfile(
+                "locking root requires a user with sudo access".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// sudoers drop-in content
+    pub fn sudoers(&self) -> String {
+        let mut content = String::from("# Generated by the rastOS installer\n");
+        match self.sudo {
+            SudoPolicy::Password => content.push_str("%wheel ALL=(ALL:ALL) ALL\n"),
+            SudoPolicy::NoPassword => content.push_str("%wheel ALL=(ALL:ALL) NOPASSWD: ALL\n"),
+            SudoPolicy::Disabled => {}
+        }
+        for rule in &self.sudo_rules {
+            content.push_str(rule);
+            content.push('\n');
+        }
+        content
+    }
+
+    /// Create the accounts in the system installed at `root`
+    pub fn apply(&self, root: &Path, log: &CommandLog) -> Result<(), InstallerError> {
+        self.validate()?;
+
+        for user in &self.users {
+            info!("Creating user {}", user.name);
+            let mut command = Command::new("useradd");
+            command.arg("--root").arg(root).args(["--create-home", "--shell", &user.shell]);
+            if !user.groups.is_empty() {
+                command.args(["--groups", &user.groups.join(",")]);
+            }
+            if let Some(full_name) = &user.full_name {
+                command.args(["--comment", full_name]);
+            }
+            run(command.arg(&user.name), log)?;
+            if let Some(hash) = user.shadow_hash()? {
+                let input = format!("{}:{}\n", user.name, hash);
+                run_with_input(
+                    Command::new("chpasswd").arg("--root").arg(root).arg("--encrypted"),
+                    Some(input.as_bytes()),
+                    log,
+                )?;
+            }
+        }
+
+        self.write_sudoers(root, log)?;
+
+        if self.lock_root {
+            info!("Locking the root account");
+            run(Command::new("usermod").arg("--root").arg(root).args(["--lock", "root"]), log)?;
+        }
+        Ok(())
+    }
+
+    /// Write and check the sudoers drop-in
+    fn write_sudoers(&self, root: &Path, log: &CommandLog) -> Result<(), InstallerError> {
+        let path = root.join(SUDOERS_DROP_IN);
+        if self.sudo == SudoPolicy::Disabled && self.sudo_rules.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, self.sudoers())?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o440))?;
+        if let Err(e) = run(Command::new("visudo").arg("--check").arg("--file").arg(&path), log) {
+            fs::remove_file(&path)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Hash a password with SHA-512 crypt
+pub fn hash_password(password: &str) -> Result<String, InstallerError> {
+    let mut random = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let salt: String = random
+        .iter()
+        .map(|b| SALT_ALPHABET[*b as usize % SALT_ALPHABET.len()] as char)
+        .collect();
+    crypt_with_salt(password, &format!("$6${}", salt))
+}
+
+/// Call `crypt(3)` with an explicit salt setting
+fn crypt_with_salt(password: &str, setting: &str) -> Result<String, InstallerError> {
+    let key = CString::new(password)
+        .map_err(|_| InstallerError::InvalidProfile("password contains a NUL byte".to_string()))?;
+    let setting = CString::new(setting).expect("salt has no NUL bytes");
+
+    let _guard = CRYPT_LOCK.lock().unwrap();
+    // SAFETY: both arguments are valid NUL-terminated strings; the returned
+    // static buffer is copied while the lock is held.
+    let hash = unsafe {
+        let result = crypt(key.as_ptr(), setting.as_ptr());
+        if result.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(result).to_string_lossy().into_owned())
+        }
+    };
+    match hash {
+        // libxcrypt signals failure with a hash starting with '*'
+        Some(hash) if !hash.starts_with('*') => Ok(hash),
+        _ => Err(InstallerError::Unsupported("crypt(3) does not support SHA-512 hashes".to_string())),
+    }
+}
+
+/// Whether `name` is a portable login name
+fn is_valid_username(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_first = chars.next().is_some_and(|c| c.is_ascii_lowercase() || c == '_');
+    valid_first
+        && name.len() <= 32
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with("$6$"));
+        let salt = hash.rsplit_once('$').unwrap().0;
+        assert_eq!(crypt_with_salt("hunter2", salt).unwrap(), hash);
+        assert_ne!(crypt_with_salt("hunter3", salt).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_validate_accounts() {
+        assert!(is_valid_username("alice"));
+        assert!(is_valid_username("_build-01"));
+        assert!(!is_valid_username("Alice"));
+        assert!(!is_valid_username("1alice"));
+        assert!(UserConfig::new("root").validate().is_err());
+
+        // Locking root without an administrator would lock everyone out
+        assert!(AccountsConfig::default().validate().is_err());
+        let accounts = AccountsConfig::default().with_user(UserConfig::new("alice"));
+        assert!(accounts.validate().is_ok());
+        assert!(accounts.clone().with_sudo(SudoPolicy::Disabled).validate().is_err());
+        assert!(accounts.with_sudo(SudoPolicy::Disabled).with_lock_root(false).validate().is_ok());
+    }
+
+    #[test]
+    fn test_sudoers() {
+        let mut accounts = AccountsConfig::default().with_sudo(SudoPolicy::NoPassword);
+        accounts.sudo_rules.push("alice ALL=(root) /usr/bin/rast".to_string());
+        assert_eq!(
+            accounts.sudoers(),
+            "# Generated by the rastOS installer\n%wheel ALL=(ALL:ALL) NOPASSWD: ALL\nalice ALL=(root) /usr/bin/rast\n"
+        );
+    }
+}
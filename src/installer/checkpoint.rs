@@ -0,0 +1,262 @@
+//! Resumable installations
+//!
+//! Once the filesystems exist, the installer records every completed phase
+//! in a checkpoint file at the top level of the new Btrfs filesystem,
+//! outside the `@` subvolume that becomes the installed system. After each
+//! phase from bootstrap on, a read-only snapshot of `@` is taken first, so
+//! a phase interrupted half-way is retried on exactly the tree it started
+//! from rather than on a partially modified one.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::error::InstallerError;
+use super::profile::InstallProfile;
+use super::progress::InstallPhase;
+
+/// Checkpoint file at the top level of the root filesystem
+pub const CHECKPOINT_FILE: &str = ".rastos-install.json";
+
+/// Prefix of the snapshots of `@` taken after each phase
+const SNAPSHOT_PREFIX: &str = "@install-checkpoint-";
+
+/// Name `@` is moved to while it is replaced by a snapshot
+const INTERRUPTED_SUBVOLUME: &str = "@interrupted";
+
+/// Bumped when the checkpoint format changes incompatibly
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// Progress of an installation, persisted between runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Format version
+    pub version: u32,
+
+    /// SHA-256 of the install profile the installation was started with
+    pub profile_digest: String,
+
+    /// Target disk
+    pub device: PathBuf,
+
+    /// UUID of the root Btrfs filesystem
+    pub filesystem_uuid: String,
+
+    /// Kernel parameters for resuming from hibernation, once configured
+    pub resume: Option<String>,
+
+    /// Snapshot of `@` matching the last completed phase
+    pub snapshot: Option<String>,
+
+    /// Completed phases, in order
+    pub completed: Vec<InstallPhase>,
+}
+
+impl Checkpoint {
+    /// Start tracking an installation whose filesystems were just created
+    pub fn new(profile: &InstallProfile, device: &Path, filesystem_uuid: &str) -> Result<Self, InstallerError> {
+        Ok(Self {
+            version: CHECKPOINT_VERSION,
+            profile_digest: profile_digest(profile)?,
+            device: device.to_path_buf(),
+            filesystem_uuid: filesystem_uuid.to_string(),
+            resume: None,
+            snapshot: None,
+            completed: Vec::new(),
+        })
+    }
+
+    /// Whether `phase` already completed
+    pub fn is_completed(&self, phase: InstallPhase) -> bool {
+        self.completed.contains(&phase)
+    }
+
+    /// Record `phase` as completed
+    pub fn complete(&mut self, phase: InstallPhase) {
+        if !self.is_completed(phase) {
+            self.completed.push(phase);
+        }
+    }
+
+    /// Load the checkpoint stored at the top level mounted at `toplevel`
+    pub fn load(toplevel: &Path) -> Result<Option<Self>, InstallerError> {
+        let path = toplevel.join(CHECKPOINT_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| InstallerError::Resume(format!("{} is corrupt: {}", path.display(), e)))
+    }
+
+    /// Persist the checkpoint, replacing the previous one atomically
+    pub fn save(&self, toplevel: &Path) -> Result<(), InstallerError> {
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|e| InstallerError::Resume(format!("cannot serialize checkpoint: {}", e)))?;
+        crate::fs::atomic_write(toplevel.join(CHECKPOINT_FILE), content).map_err(std::io::Error::from)?;
+        debug!("Checkpoint saved after {:?}", self.completed.last());
+        Ok(())
+    }
+
+    /// Check that the checkpoint belongs to this profile and filesystem
+    pub fn verify(&self, profile: &InstallProfile, device: &Path, filesystem_uuid: &str) -> Result<(), InstallerError> {
+        if self.version != CHECKPOINT_VERSION {
+            return Err(InstallerError::Resume(format!(
+                "checkpoint format {} is not supported",
+                self.version
+            )));
+        }
+        if self.profile_digest != profile_digest(profile)? {
+            return Err(InstallerError::Resume(
+                "the install profile changed since the installation started".to_string(),
+            ));
+        }
+        if self.device != device {
+            return Err(InstallerError::Resume(format!(
+                "the installation was started on {}, not {}",
+                self.device.display(),
+                device.display()
+            )));
+        }
+        if self.filesystem_uuid != filesystem_uuid {
+            return Err(InstallerError::Resume(format!(
+                "root filesystem {} was replaced by {}",
+                self.filesystem_uuid, filesystem_uuid
+            )));
+        }
+        let in_order = InstallPhase::ALL
+            .iter()
+            .filter(|phase| self.is_completed(**phase))
+            .eq(self.completed.iter());
+        let contiguous = InstallPhase::ALL
+            .iter()
+            .take(self.completed.len())
+            .all(|phase| self.is_completed(*phase));
+        if !in_order || !contiguous {
+            return Err(InstallerError::Resume(format!(
+                "completed phases {:?} are inconsistent",
+                self.completed
+            )));
+        }
+        if self.is_completed(InstallPhase::Bootstrap) && self.snapshot.is_none() {
+            return Err(InstallerError::Resume("no snapshot of the installed system was recorded".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Snapshot `subvolume` after `phase` and record it
+    ///
+    /// The previous snapshot is only deleted once the checkpoint pointing
+    /// at the new one is on disk.
+    pub fn snapshot(&mut self, toplevel: &Path, subvolume: &str, phase: InstallPhase) -> Result<(), InstallerError> {
+        let name = format!("{}{}", SNAPSHOT_PREFIX, phase);
+        let path = toplevel.join(&name);
+        if path.exists() {
+            delete_subvolume(&path)?;
+        }
+        crate::sys::btrfs::snapshot(toplevel.join(subvolume), &path, true)?;
+        let previous = self.snapshot.replace(name);
+        self.complete(phase);
+        self.save(toplevel)?;
+        if let Some(previous) = previous.filter(|p| Some(p) != self.snapshot.as_ref()) {
+            delete_subvolume(&toplevel.join(previous))?;
+        }
+        Ok(())
+    }
+
+    /// Replace `subvolume` with a writable copy of the recorded snapshot
+    ///
+    /// Subvolumes nested in the interrupted tree, such as the swapfile's,
+    /// are not part of a snapshot and are moved over to the restored one.
+    pub fn restore(&self, toplevel: &Path, subvolume: &str) -> Result<(), InstallerError> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        info!("Restoring {} from {}", subvolume, snapshot);
+        let current = toplevel.join(subvolume);
+        let interrupted = toplevel.join(INTERRUPTED_SUBVOLUME);
+        if interrupted.exists() {
+            delete_subvolume(&interrupted)?;
+        }
+        if current.exists() {
+            fs::rename(&current, &interrupted)?;
+        }
+        crate::sys::btrfs::snapshot(toplevel.join(snapshot), &current, false)?;
+
+        if interrupted.exists() {
+            for nested in crate::sys::btrfs::nested_subvolumes(&interrupted)? {
+                let destination = current.join(&nested);
+                // The snapshot holds an empty directory where the subvolume was
+                if destination.is_dir() {
+                    fs::remove_dir(&destination)?;
+                }
+                fs::rename(interrupted.join(&nested), &destination)?;
+            }
+            delete_subvolume(&interrupted)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the checkpoint and every snapshot once the installation finished
+    pub fn finish(toplevel: &Path) -> Result<(), InstallerError> {
+        for entry in fs::read_dir(toplevel)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(SNAPSHOT_PREFIX) {
+                delete_subvolume(&entry.path())?;
+            }
+        }
+        match fs::remove_file(toplevel.join(CHECKPOINT_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// SHA-256 of the serialized profile; secrets are not serialized
+pub fn profile_digest(profile: &InstallProfile) -> Result<String, InstallerError> {
+    let serialized = serde_json::to_vec(profile)
+        .map_err(|e| InstallerError::InvalidProfile(format!("cannot serialize profile: {}", e)))?;
+    Ok(format!("{:x}", Sha256::digest(&serialized)))
+}
+
+fn delete_subvolume(path: &Path) -> Result<(), InstallerError> {
+    Ok(crate::sys::btrfs::delete_subvolume(path, true)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checkpoint_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let profile = InstallProfile::default();
+        assert_eq!(Checkpoint::load(dir.path())?, None);
+
+        let mut checkpoint = Checkpoint::new(&profile, Path::new("/dev/sda"), "5d1f3c2a")?;
+        for phase in [InstallPhase::Preflight, InstallPhase::Partition, InstallPhase::Format] {
+            checkpoint.complete(phase);
+        }
+        checkpoint.save(dir.path())?;
+        let loaded = Checkpoint::load(dir.path())?.unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert!(loaded.verify(&profile, Path::new("/dev/sda"), "5d1f3c2a").is_ok());
+        assert!(loaded.verify(&profile, Path::new("/dev/sdb"), "5d1f3c2a").is_err());
+        assert!(loaded.verify(&profile, Path::new("/dev/sda"), "00000000").is_err());
+
+        let mut changed = profile.clone();
+        changed.dual_boot = true;
+        assert!(loaded.verify(&changed, Path::new("/dev/sda"), "5d1f3c2a").is_err());
+
+        let mut skipped = loaded.clone();
+        skipped.completed.remove(1);
+        assert!(skipped.verify(&profile, Path::new("/dev/sda"), "5d1f3c2a").is_err());
+        Ok(())
+    }
+}
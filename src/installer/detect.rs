@@ -0,0 +1,440 @@
+//! Existing operating system detection
+//!
+//! Finds other systems before the installer touches a disk: UEFI boot
+//! entries from `efibootmgr`, loaders on every EFI system partition and
+//! Linux roots identified by their `os-release`. Detected systems can be
+//! chain-loaded from the rastOS boot menu when dual boot is enabled.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use super::partition::run;
+use super::report::CommandLog;
+use crate::kernel::Bootloader;
+
+/// GPT type GUID of EFI system partitions
+const ESP_TYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+
+/// Filesystems probed for Linux installations
+const LINUX_FILESYSTEMS: &[&str] = &["ext4", "btrfs", "xfs", "f2fs"];
+
+/// GRUB script adding chain-load entries
+pub const GRUB_CHAINLOAD_SCRIPT: &str = "etc/grub.d/35_rastos_chainload";
+
+/// Kind of a detected system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsKind {
+    /// Microsoft Windows
+    Windows,
+    /// A Linux distribution
+    Linux,
+    /// Any other EFI loader
+    Other,
+}
+
+/// An operating system found on the machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedOs {
+    /// Human readable name
+    pub name: String,
+    /// Kind of system
+    pub kind: OsKind,
+    /// Partition the system or its loader lives on
+    pub device: PathBuf,
+    /// Filesystem UUID of that partition
+    pub uuid: Option<String>,
+    /// EFI loader path on the ESP, for chain-loading
+    pub loader: Option<String>,
+}
+
+impl DetectedOs {
+    /// Whether the system lives on `disk`, or on one of its partitions
+    ///
+    /// Only a partition number, with the `p` separator of NVMe and MMC
+    /// names, may follow the disk name, so `/dev/sdaa1` is not on `/dev/sda`.
+    pub fn is_on(&self, disk: &Path) -> bool {
+        let (device, disk) = (self.device.to_string_lossy(), disk.to_string_lossy());
+        let Some(suffix) = device.strip_prefix(disk.as_ref()) else {
+            return false;
+        };
+        let number = suffix.strip_prefix('p').unwrap_or(suffix);
+        suffix.is_empty() || (!number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+    }
+
+    fn slug(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+            .collect::<String>()
+            .split('-')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+/// A UEFI boot entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfiEntry {
+    /// Boot number (`0001`)
+    pub number: String,
+    /// Entry label
+    pub label: String,
+    /// Whether the entry is active
+    pub active: bool,
+}
+
+/// Everything found on the machine
+#[derive(Debug, Clone, Default)]
+pub struct Detection {
+    /// Firmware boot entries
+    pub efi_entries: Vec<EfiEntry>,
+    /// Installed systems
+    pub systems: Vec<DetectedOs>,
+}
+
+impl Detection {
+    /// Probe firmware entries and all partitions
+    ///
+    /// Partitions that are not mounted are mounted read-only in a temporary
+    /// directory while they are inspected.
+    pub fn probe(log: &CommandLog) -> Result<Self, InstallerError> {
+        let efi_entries = match Command::new("efibootmgr").output() {
+            Ok(output) if output.status.success() => parse_efibootmgr(&String::from_utf8_lossy(&output.stdout)),
+            _ => {
+                debug!("efibootmgr unavailable, skipping firmware entries");
+                Vec::new()
+            }
+        };
+
+        let mut systems = Vec::new();
+        for partition in list_partitions()? {
+            let is_esp = partition.parttype.as_deref() == Some(ESP_TYPE);
+            if !is_esp && !LINUX_FILESYSTEMS.contains(&partition.fstype.as_str()) {
+                continue;
+            }
+            let found = with_mounted(&partition, log, |root| {
+                if is_esp {
+                    scan_esp(root, &partition.name, partition.uuid.as_deref())
+                } else {
+                    probe_linux_root(root, &partition.name, partition.uuid.as_deref())
+                        .into_iter()
+                        .collect()
+                }
+            });
+            match found {
+                Ok(found) => systems.extend(found),
+                Err(e) => warn!("Could not inspect {}: {}", partition.name.display(), e),
+            }
+        }
+        Ok(Self { efi_entries, systems })
+    }
+
+    /// Systems that would be destroyed by repartitioning `disk`
+    pub fn overwritten(&self, disk: &Path) -> Vec<&DetectedOs> {
+        self.systems.iter().filter(|os| os.is_on(disk)).collect()
+    }
+
+    /// Log a warning for every system on `disk`, returning whether there were any
+    pub fn warn_overwrite(&self, disk: &Path) -> bool {
+        let doomed = self.overwritten(disk);
+        for os in &doomed {
+            warn!("{} on {} will be erased", os.name, os.device.display());
+        }
+        !doomed.is_empty()
+    }
+}
+
+/// Loaders found on an ESP mounted at `esp`
+pub fn scan_esp(esp: &Path, device: &Path, uuid: Option<&str>) -> Vec<DetectedOs> {
+    let Ok(vendors) = fs::read_dir(esp.join("EFI")) else {
+        return Vec::new();
+    };
+
+    let mut systems = Vec::new();
+    let mut vendors: Vec<_> = vendors.filter_map(Result::ok).collect();
+    vendors.sort_by_key(|e| e.file_name());
+    for vendor in vendors {
+        let vendor = vendor.file_name().to_string_lossy().into_owned();
+        let dir = esp.join("EFI").join(&vendor);
+        // Fallback loaders and rastOS's own loaders are not other systems
+        if matches!(vendor.to_ascii_lowercase().as_str(), "boot" | "rastos" | "systemd" | "linux") {
+            continue;
+        }
+
+        let (kind, name, candidates): (OsKind, String, &[&str]) = if vendor.eq_ignore_ascii_case("microsoft") {
+            (OsKind::Windows, "Windows Boot Manager".to_string(), &["Boot/bootmgfw.efi"])
+        } else {
+            (
+                OsKind::Linux,
+                capitalize(&vendor),
+                &["shimx64.efi", "grubx64.efi", "grubaa64.efi", "shimaa64.efi"],
+            )
+        };
+        if let Some(loader) = candidates.iter().find(|c| dir.join(c).is_file()) {
+            systems.push(DetectedOs {
+                name,
+                kind,
+                device: device.to_path_buf(),
+                uuid: uuid.map(str::to_string),
+                loader: Some(format!("/EFI/{}/{}", vendor, loader)),
+            });
+        }
+    }
+    systems
+}
+
+/// Linux installation rooted at `root`, identified by its os-release
+pub fn probe_linux_root(root: &Path, device: &Path, uuid: Option<&str>) -> Option<DetectedOs> {
+    let content = ["etc/os-release", "usr/lib/os-release"]
+        .iter()
+        .find_map(|path| fs::read_to_string(root.join(path)).ok())?;
+    Some(DetectedOs {
+        name: parse_os_release(&content)?,
+        kind: OsKind::Linux,
+        device: device.to_path_buf(),
+        uuid: uuid.map(str::to_string),
+        loader: None,
+    })
+}
+
+/// `PRETTY_NAME`, falling back to `NAME`
+pub fn parse_os_release(content: &str) -> Option<String> {
+    let value = |key: &str| {
+        content.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_string())
+        })
+    };
+    value("PRETTY_NAME").or_else(|| value("NAME")).filter(|name| !name.is_empty())
+}
+
+/// Parse `efibootmgr` output
+pub fn parse_efibootmgr(output: &str) -> Vec<EfiEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Boot")?;
+            let number = rest.get(..4)?;
+            if !number.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let rest = &rest[4..];
+            let active = rest.starts_with('*');
+            let label = rest.trim_start_matches('*').split('\t').next()?.trim();
+            Some(EfiEntry {
+                number: number.to_string(),
+                label: label.to_string(),
+                active,
+            })
+        })
+        .collect()
+}
+
+/// Boot menu entries chain-loading detected systems
+///
+/// systemd-boot can only start loaders on its own ESP, so systems on other
+/// partitions are skipped there (they stay reachable through the firmware
+/// boot menu). GRUB searches for the loader's partition by UUID.
+pub fn write_chainload_entries(
+    bootloader: Bootloader,
+    root: &Path,
+    esp_uuid: Option<&str>,
+    systems: &[DetectedOs],
+) -> Result<usize, InstallerError> {
+    let loaders: Vec<_> = systems.iter().filter(|os| os.loader.is_some()).collect();
+    match bootloader {
+        Bootloader::SystemdBoot => {
+            let entries = root.join("boot/loader/entries");
+            let mut written = 0;
+            for os in loaders {
+                if os.uuid.is_none() || os.uuid.as_deref() != esp_uuid {
+                    info!("{} is on another ESP, use the firmware boot menu to start it", os.name);
+                    continue;
+                }
+                fs::create_dir_all(&entries)?;
+                let entry = format!("title {}\nefi {}\n", os.name, os.loader.as_deref().unwrap_or_default());
+                fs::write(entries.join(format!("chainload-{}.conf", os.slug())), entry)?;
+                written += 1;
+            }
+            Ok(written)
+        }
+        Bootloader::Grub => {
+            let path = root.join(GRUB_CHAINLOAD_SCRIPT);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, grub_chainload_script(&loaders))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            Ok(loaders.len())
+        }
+    }
+}
+
+fn grub_chainload_script(systems: &[&DetectedOs]) -> String {
+    let mut script = String::from("#!/bin/sh\n# Generated by the rastOS installer\nexec tail -n +4 $0\n");
+    for os in systems {
+        let Some(uuid) = &os.uuid else {
+            continue;
+        };
+        script.push_str(&format!(
+            "menuentry '{}' {{\n    insmod part_gpt\n    insmod fat\n    search --no-floppy --fs-uuid --set=root {}\n    chainloader {}\n}}\n",
+            os.name.replace('\'', ""),
+            uuid,
+            os.loader.as_deref().unwrap_or_default()
+        ));
+    }
+    script
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// A partition as reported by lsblk
+struct ProbedPartition {
+    name: PathBuf,
+    fstype: String,
+    parttype: Option<String>,
+    uuid: Option<String>,
+    mountpoint: Option<PathBuf>,
+}
+
+fn list_partitions() -> Result<Vec<ProbedPartition>, InstallerError> {
+    let output = Command::new("lsblk")
+        .args(["--noheadings", "--pairs", "--paths", "--output", "NAME,TYPE,FSTYPE,PARTTYPE,UUID,MOUNTPOINT"])
+        .output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("lsblk", &output));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let field = |key: &str| {
+                let start = line.find(&format!("{}=\"", key))? + key.len() + 2;
+                let end = line[start..].find('"')? + start;
+                Some(line[start..end].to_string()).filter(|v| !v.is_empty())
+            };
+            if field("TYPE").as_deref() != Some("part") {
+                return None;
+            }
+            Some(ProbedPartition {
+                name: PathBuf::from(field("NAME")?),
+                fstype: field("FSTYPE")?,
+                parttype: field("PARTTYPE").map(|t| t.to_ascii_lowercase()),
+                uuid: field("UUID"),
+                mountpoint: field("MOUNTPOINT").map(PathBuf::from),
+            })
+        })
+        .collect())
+}
+
+/// Run `f` on the partition's mounted filesystem, mounting it read-only if needed
+fn with_mounted<T>(
+    partition: &ProbedPartition,
+    log: &CommandLog,
+    f: impl FnOnce(&Path) -> T,
+) -> Result<T, InstallerError> {
+    if let Some(mountpoint) = &partition.mountpoint {
+        return Ok(f(mountpoint));
+    }
+
+    let dir = tempfile::tempdir()?;
+    run(
+        Command::new("mount")
+            .args(["-o", "ro", "-t", &partition.fstype])
+            .arg(&partition.name)
+            .arg(dir.path()),
+        log,
+    )?;
+    let result = f(dir.path());
+    run(Command::new("umount").arg(dir.path()), log)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_efibootmgr() {
+        let entries = parse_efibootmgr(
+            "BootCurrent: 0001\nTimeout: 1 seconds\nBootOrder: 0001,0000\n\
+             Boot0000* Windows Boot Manager\tHD(1,GPT,...)/File(\\EFI\\Microsoft\\Boot\\bootmgfw.efi)\n\
+             Boot0001  Fedora\tHD(1,GPT,...)/File(\\EFI\\fedora\\shimx64.efi)\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].label, "Windows Boot Manager");
+        assert!(entries[0].active);
+        assert_eq!(entries[1].number, "0001");
+        assert!(!entries[1].active);
+    }
+
+    #[test]
+    fn test_scan_esp() -> Result<(), Box<dyn std::error::Error>> {
+        let esp = tempdir()?;
+        fs::create_dir_all(esp.path().join("EFI/Microsoft/Boot"))?;
+        fs::write(esp.path().join("EFI/Microsoft/Boot/bootmgfw.efi"), "")?;
+        fs::create_dir_all(esp.path().join("EFI/fedora"))?;
+        fs::write(esp.path().join("EFI/fedora/shimx64.efi"), "")?;
+        fs::create_dir_all(esp.path().join("EFI/BOOT"))?;
+        fs::write(esp.path().join("EFI/BOOT/BOOTX64.EFI"), "")?;
+
+        let systems = scan_esp(esp.path(), Path::new("/dev/nvme0n1p1"), Some("ABCD-1234"));
+        assert_eq!(systems.len(), 2);
+        assert_eq!(systems[0].kind, OsKind::Windows);
+        assert!(systems[0].is_on(Path::new("/dev/nvme0n1")));
+        assert!(!systems[0].is_on(Path::new("/dev/sda")));
+        assert!(!systems[0].is_on(Path::new("/dev/nvme0")));
+        assert_eq!(systems[1].name, "Fedora");
+        assert_eq!(systems[1].loader.as_deref(), Some("/EFI/fedora/shimx64.efi"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chainload_entries() -> Result<(), Box<dyn std::error::Error>> {
+        let root = tempdir()?;
+        let windows = DetectedOs {
+            name: "Windows Boot Manager".to_string(),
+            kind: OsKind::Windows,
+            device: PathBuf::from("/dev/nvme0n1p1"),
+            uuid: Some("ABCD-1234".to_string()),
+            loader: Some("/EFI/Microsoft/Boot/bootmgfw.efi".to_string()),
+        };
+
+        let written = write_chainload_entries(Bootloader::SystemdBoot, root.path(), Some("ABCD-1234"), std::slice::from_ref(&windows))?;
+        assert_eq!(written, 1);
+        let entry = fs::read_to_string(root.path().join("boot/loader/entries/chainload-windows-boot-manager.conf"))?;
+        assert_eq!(entry, "title Windows Boot Manager\nefi /EFI/Microsoft/Boot/bootmgfw.efi\n");
+        assert_eq!(write_chainload_entries(Bootloader::SystemdBoot, root.path(), Some("0000-0000"), std::slice::from_ref(&windows))?, 0);
+
+        write_chainload_entries(Bootloader::Grub, root.path(), None, &[windows])?;
+        let script = fs::read_to_string(root.path().join(GRUB_CHAINLOAD_SCRIPT))?;
+        assert!(script.contains("search --no-floppy --fs-uuid --set=root ABCD-1234"));
+        assert!(script.contains("chainloader /EFI/Microsoft/Boot/bootmgfw.efi"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        assert_eq!(
+            parse_os_release("NAME=\"Ubuntu\"\nPRETTY_NAME=\"Ubuntu 24.04 LTS\"\n"),
+            Some("Ubuntu 24.04 LTS".to_string())
+        );
+        assert_eq!(parse_os_release("NAME=Arch Linux\n"), Some("Arch Linux".to_string()));
+        assert_eq!(parse_os_release("ID=foo\n"), None);
+    }
+}
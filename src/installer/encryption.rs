@@ -0,0 +1,409 @@
+//! LUKS2 root encryption
+//!
+//! Formats the root partition as a LUKS2 container with the argon2id KDF,
+//! enrolls the passphrase, an optional keyfile and optional TPM2
+//! auto-unlock, and produces the crypttab entry, kernel command line and
+//! initramfs hooks needed to unlock it at boot.
+
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use super::fstab::{Crypttab, CrypttabEntry};
+use super::partition::run;
+use super::report::CommandLog;
+use crate::kernel::{InitramfsConfig, InitramfsGenerator, InitramfsHook};
+
+/// Size of generated keyfiles
+const KEYFILE_BYTES: usize = 4096;
+
+/// Secret passphrase, never printed
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    /// Wrap a passphrase
+    pub fn new<S: Into<String>>(passphrase: S) -> Self {
+        Self(passphrase.into())
+    }
+
+    /// The passphrase itself
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase(***)")
+    }
+}
+
+/// Root encryption settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// Device-mapper name of the opened container
+    pub mapper_name: String,
+
+    /// Passphrase enrolled in the first key slot
+    #[serde(skip_serializing)]
+    pub passphrase: Option<Passphrase>,
+
+    /// Keyfile enrolled in an additional slot, generated if missing
+    pub keyfile: Option<PathBuf>,
+
+    /// Enroll the TPM2 for automatic unlocking
+    pub tpm2: bool,
+
+    /// PCRs the TPM2 key is bound to
+    pub tpm2_pcrs: Vec<u32>,
+
+    /// Cipher
+    pub cipher: String,
+
+    /// Key size in bits
+    pub key_size: u32,
+
+    /// Pass discards through to the disk
+    pub discard: bool,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            mapper_name: "cryptroot".to_string(),
+            passphrase: None,
+            keyfile: None,
+            tpm2: false,
+            tpm2_pcrs: vec![7],
+            cipher: "aes-xts-plain64".to_string(),
+            key_size: 512,
+            discard: true,
+        }
+    }
+}
+
+impl EncryptionConfig {
+    /// Enroll a passphrase
+    pub fn with_passphrase(mut self, passphrase: Passphrase) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Enroll a keyfile
+    pub fn with_keyfile<P: AsRef<Path>>(mut self, keyfile: P) -> Self {
+        self.keyfile = Some(keyfile.as_ref().to_path_buf());
+        self
+    }
+
+    /// Enroll the TPM2 bound to the given PCRs
+    pub fn with_tpm2(mut self, pcrs: Vec<u32>) -> Self {
+        self.tpm2 = true;
+        self.tpm2_pcrs = pcrs;
+        self
+    }
+
+    /// Set the device-mapper name
+    pub fn with_mapper_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.mapper_name = name.into();
+        self
+    }
+
+    /// Check that a key can be enrolled and the initramfs can unlock the root
+    pub fn validate(&self, generator: InitramfsGenerator) -> Result<(), InstallerError> {
+        if self.passphrase.is_none() && self.keyfile.is_none() {
+            return Err(InstallerError::InvalidLayout(
+                "encryption needs a passphrase or a keyfile".to_string(),
+            ));
+        }
+        if self.tpm2 && generator == InitramfsGenerator::Mkinitcpio {
+            return Err(InstallerError::Unsupported(
+                "TPM2 auto-unlock needs dracut; the mkinitcpio encrypt hook cannot use the TPM".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add the unlock hooks to an initramfs configuration
+    pub fn initramfs_config(&self, config: InitramfsConfig) -> InitramfsConfig {
+        let config = config.with_hook(InitramfsHook::Luks);
+        if self.tpm2 {
+            config.with_hook(InitramfsHook::Tpm2)
+        } else {
+            config
+        }
+    }
+
+    /// Format `device` as LUKS2 and enroll all configured keys
+    pub fn format(&self, device: &Path, log: &CommandLog) -> Result<LuksVolume, InstallerError> {
+        info!("Formatting {} as LUKS2", device.display());
+        let key_size = self.key_size.to_string();
+        let mut command = Command::new("cryptsetup");
+        command
+            .args(["luksFormat", "--batch-mode", "--type", "luks2", "--pbkdf", "argon2id"])
+            .args(["--cipher", &self.cipher, "--key-size", &key_size]);
+        let input = self.unlock_key(&mut command)?;
+        run_with_input(command.arg(device), input, log)?;
+
+        if let (Some(passphrase), Some(keyfile)) = (&self.passphrase, &self.keyfile) {
+            ensure_keyfile(keyfile)?;
+            run_with_input(
+                Command::new("cryptsetup")
+                    .args(["luksAddKey", "--batch-mode", "--key-file", "-"])
+                    .arg(device)
+                    .arg(keyfile),
+                Some(passphrase.expose().as_bytes()),
+                log,
+            )?;
+        }
+
+        if self.tpm2 {
+            info!("Enrolling TPM2 for {}", device.display());
+            let pcrs = self.tpm2_pcrs.iter().map(u32::to_string).collect::<Vec<_>>().join("+");
+            let mut command = Command::new("systemd-cryptenroll");
+            command.arg("--tpm2-device=auto").arg(format!("--tpm2-pcrs={}", pcrs));
+            match (&self.passphrase, &self.keyfile) {
+                (Some(passphrase), _) => command.env("PASSWORD", passphrase.expose()),
+                (None, Some(keyfile)) => command.arg(format!("--unlock-key-file={}", keyfile.display())),
+                (None, None) => unreachable!("validated: a key is configured"),
+            };
+            run(command.arg(device), log)?;
+        }
+
+        Ok(LuksVolume {
+            device: device.to_path_buf(),
+            uuid: luks_uuid(device)?,
+            name: self.mapper_name.clone(),
+        })
+    }
+
+    /// Point `command` at the first configured key, returning stdin input
+    fn unlock_key<'a>(&'a self, command: &mut Command) -> Result<Option<&'a [u8]>, InstallerError> {
+        match (&self.passphrase, &self.keyfile) {
+            (Some(passphrase), _) => {
+                command.args(["--key-file", "-"]);
+                Ok(Some(passphrase.expose().as_bytes()))
+            }
+            (None, Some(keyfile)) => {
+                ensure_keyfile(keyfile)?;
+                command.arg("--key-file").arg(keyfile);
+                Ok(None)
+            }
+            (None, None) => Err(InstallerError::InvalidLayout(
+                "encryption needs a passphrase or a keyfile".to_string(),
+            )),
+        }
+    }
+}
+
+/// A formatted LUKS container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuksVolume {
+    /// Underlying partition
+    pub device: PathBuf,
+    /// LUKS header UUID
+    pub uuid: String,
+    /// Device-mapper name
+    pub name: String,
+}
+
+impl LuksVolume {
+    /// An existing container on `device`, mapped as `name` once opened
+    pub fn existing<S: Into<String>>(device: &Path, name: S) -> Result<Self, InstallerError> {
+        Ok(Self {
+            device: device.to_path_buf(),
+            uuid: luks_uuid(device)?,
+            name: name.into(),
+        })
+    }
+
+    /// Device node of the opened container
+    pub fn mapper_path(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(&self.name)
+    }
+
+    /// Open the container with the configured key
+    pub fn open(&self, config: &EncryptionConfig, log: &CommandLog) -> Result<PathBuf, InstallerError> {
+        let mut command = Command::new("cryptsetup");
+        command.arg("open");
+        let input = config.unlock_key(&mut command)?;
+        run_with_input(command.arg(&self.device).arg(&self.name), input, log)?;
+        Ok(self.mapper_path())
+    }
+
+    /// Close the container
+    pub fn close(&self, log: &CommandLog) -> Result<(), InstallerError> {
+        run(Command::new("cryptsetup").arg("close").arg(&self.name), log)
+    }
+
+    /// `/etc/crypttab` entry for the container
+    pub fn crypttab_entry(&self, config: &EncryptionConfig) -> CrypttabEntry {
+        let mut options = vec!["luks".to_string()];
+        if config.discard {
+            options.push("discard".to_string());
+        }
+        if config.tpm2 {
+            options.push("tpm2-device=auto".to_string());
+        }
+        CrypttabEntry {
+            name: self.name.clone(),
+            device: format!("UUID={}", self.uuid),
+            keyfile: None,
+            options,
+        }
+    }
+
+    /// Add the container to `etc/crypttab` below `root`
+    pub fn write_crypttab(&self, root: &Path, config: &EncryptionConfig) -> Result<(), InstallerError> {
+        let path = root.join("etc/crypttab");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut crypttab: Crypttab = match fs::read_to_string(&path) {
+            Ok(content) => content.parse()?,
+            Err(_) => Crypttab::default(),
+        };
+        crypttab.insert(self.crypttab_entry(config));
+        fs::write(&path, crypttab.to_string())?;
+        Ok(())
+    }
+
+    /// Kernel parameters unlocking the container as root
+    pub fn kernel_cmdline(&self, config: &EncryptionConfig, generator: InitramfsGenerator) -> String {
+        let root = format!("root={}", self.mapper_path().display());
+        match generator {
+            InitramfsGenerator::Mkinitcpio => {
+                let discard = if config.discard { ":allow-discards" } else { "" };
+                format!("cryptdevice=UUID={}:{}{} {}", self.uuid, self.name, discard, root)
+            }
+            InitramfsGenerator::Dracut => {
+                let mut options = Vec::new();
+                if config.discard {
+                    options.push("discard");
+                }
+                if config.tpm2 {
+                    options.push("tpm2-device=auto");
+                }
+                let mut cmdline = format!("rd.luks.uuid={0} rd.luks.name={0}={1}", self.uuid, self.name);
+                if !options.is_empty() {
+                    cmdline.push_str(&format!(" rd.luks.options={}={}", self.uuid, options.join(",")));
+                }
+                format!("{} {}", cmdline, root)
+            }
+        }
+    }
+}
+
+/// Create a random keyfile readable only by root, unless it exists
+fn ensure_keyfile(path: &Path) -> Result<(), InstallerError> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut key = vec![0u8; KEYFILE_BYTES];
+    fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+    // Never readable by anyone else, not even between creation and writing
+    let mut file = match fs::OpenOptions::new().write(true).create_new(true).mode(0o400).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    file.write_all(&key)?;
+    file.sync_all()?;
+    debug!("Generated keyfile {}", path.display());
+    Ok(())
+}
+
+/// UUID of a LUKS header
+fn luks_uuid(device: &Path) -> Result<String, InstallerError> {
+    let output = Command::new("cryptsetup").arg("luksUUID").arg(device).output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("cryptsetup luksUUID", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Run a command, feeding a secret on stdin
+pub(super) fn run_with_input(
+    command: &mut Command,
+    input: Option<&[u8]>,
+    log: &CommandLog,
+) -> Result<(), InstallerError> {
+    let Some(input) = input else {
+        return run(command, log);
+    };
+    debug!("Running: {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().expect("piped stdin").write_all(input)?;
+    let output = child.wait_with_output()?;
+    log.record(command, &output);
+    if !output.status.success() {
+        return Err(InstallerError::command_error(command.get_program().to_string_lossy(), &output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn volume() -> LuksVolume {
+        LuksVolume {
+            device: PathBuf::from("/dev/nvme0n1p3"),
+            uuid: "0b7a5a5e-1f3c-4b0e-9d1a-6c2f8e4d3a21".to_string(),
+            name: "cryptroot".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_kernel_cmdline() {
+        let config = EncryptionConfig::default();
+        assert_eq!(
+            volume().kernel_cmdline(&config, InitramfsGenerator::Mkinitcpio),
+            "cryptdevice=UUID=0b7a5a5e-1f3c-4b0e-9d1a-6c2f8e4d3a21:cryptroot:allow-discards root=/dev/mapper/cryptroot"
+        );
+        let dracut = volume().kernel_cmdline(&config.with_tpm2(vec![7]), InitramfsGenerator::Dracut);
+        assert!(dracut.contains("rd.luks.name=0b7a5a5e-1f3c-4b0e-9d1a-6c2f8e4d3a21=cryptroot"));
+        assert!(dracut.contains("=discard,tpm2-device=auto"));
+    }
+
+    #[test]
+    fn test_write_crypttab() -> Result<(), Box<dyn std::error::Error>> {
+        let root = tempdir()?;
+        fs::create_dir_all(root.path().join("etc"))?;
+        fs::write(root.path().join("etc/crypttab"), "home UUID=1234 none luks\ncryptroot UUID=old none luks\n")?;
+
+        volume().write_crypttab(root.path(), &EncryptionConfig::default())?;
+        let crypttab = fs::read_to_string(root.path().join("etc/crypttab"))?;
+        assert_eq!(
+            crypttab,
+            "home UUID=1234 none luks\ncryptroot UUID=0b7a5a5e-1f3c-4b0e-9d1a-6c2f8e4d3a21 none luks,discard\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(EncryptionConfig::default().validate(InitramfsGenerator::Dracut).is_err());
+        let config = EncryptionConfig::default().with_passphrase(Passphrase::new("correct horse"));
+        assert!(config.validate(InitramfsGenerator::Mkinitcpio).is_ok());
+        assert!(config.clone().with_tpm2(vec![7]).validate(InitramfsGenerator::Mkinitcpio).is_err());
+        assert!(!format!("{:?}", config).contains("correct horse"));
+    }
+}
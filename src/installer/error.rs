@@ -0,0 +1,88 @@
+//! Error types for system installation
+
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+/// Errors that can occur during installation
+#[derive(Error, Debug)]
+pub enum InstallerError {
+    /// I/O error during file operations
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Command execution failed
+    #[error("Command '{command}' failed with code {code}: {message}")]
+    CommandError {
+        /// The full command string that was executed
+        command: String,
+
+        /// The exit status code returned by the command
+        code: i32,
+
+        /// The error output (stderr) from the command
+        message: String,
+    },
+
+    /// Disk layout description is invalid or does not fit the disk
+    #[error("Invalid disk layout: {0}")]
+    InvalidLayout(String),
+
+    /// Install profile is invalid
+    #[error("Invalid install profile: {0}")]
+    InvalidProfile(String),
+
+    /// Unsupported combination of install options
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+
+    /// Initramfs or boot setup failed
+    #[error(transparent)]
+    Kernel(#[from] crate::kernel::KernelError),
+
+    /// Pulling or unpacking the system image failed
+    #[cfg(feature = "containers")]
+    #[error(transparent)]
+    Image(#[from] crate::oci::ContainerError),
+
+    /// fstab or crypttab line could not be parsed
+    #[error("Parse error: {0}")]
+    Parse(String),
+
+    /// The phase journal could not be written or a phase not rolled back
+    #[error(transparent)]
+    Transaction(#[from] crate::transaction::TransactionError),
+
+    /// An interrupted installation cannot be resumed
+    #[error("Cannot resume installation: {0}")]
+    Resume(String),
+
+    /// Preflight checks failed
+    #[error("Preflight checks failed: {}", .0.join("; "))]
+    Preflight(Vec<String>),
+
+    /// Disk has mounted partitions or active swap
+    #[error("Disk {0} is in use")]
+    DiskInUse(PathBuf),
+
+    /// Disk holds data and wiping was not allowed
+    #[error("Disk {} contains existing data ({}); allow wiping to overwrite it", device.display(), signatures.join(", "))]
+    WipeProtected {
+        /// Disk that would be overwritten
+        device: PathBuf,
+
+        /// Partition tables and filesystems found on it
+        signatures: Vec<String>,
+    },
+}
+
+impl InstallerError {
+    /// Create a new command error
+    pub fn command_error<S: Into<String>>(command: S, output: &std::process::Output) -> Self {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Self::CommandError {
+            command: command.into(),
+            code: output.status.code().unwrap_or(-1),
+            message,
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! fstab and crypttab generation
+//!
+//! Builds `/etc/fstab` and `/etc/crypttab` for the filesystems the
+//! installer created, referring to every device by UUID. Btrfs subvolumes
+//! get explicit `subvol=` options so a snapshot rollback only has to swap
+//! what the subvolume name points at. Both tables parse back into the
+//! same types, so existing files can be edited without losing entries.
+
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use super::error::InstallerError;
+
+/// Mount options applied to every Btrfs subvolume
+pub const BTRFS_OPTIONS: [&str; 3] = ["noatime", "compress=zstd:1", "space_cache=v2"];
+
+/// Mount options of the EFI system partition; keeps the ESP unreadable for users
+pub const ESP_OPTIONS: [&str; 3] = ["umask=0077", "fmask=0077", "dmask=0077"];
+
+/// One `/etc/fstab` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    /// Device (`UUID=...`) or swapfile path
+    pub spec: String,
+    /// Mount point, `none` for swap
+    pub file: String,
+    /// Filesystem type
+    pub vfstype: String,
+    /// Mount options
+    pub options: Vec<String>,
+    /// dump(8) frequency
+    pub freq: u8,
+    /// fsck order; 0 skips the check
+    pub passno: u8,
+}
+
+impl FstabEntry {
+    /// Btrfs `subvolume` of filesystem `uuid` mounted at `mountpoint`
+    pub fn btrfs(uuid: &str, mountpoint: &str, subvolume: &str) -> Self {
+        let mut options: Vec<String> = BTRFS_OPTIONS.iter().map(|o| o.to_string()).collect();
+        options.push(format!("subvol=/{}", subvolume.trim_start_matches('/')));
+        Self {
+            spec: format!("UUID={}", uuid),
+            file: mountpoint.to_string(),
+            vfstype: "btrfs".to_string(),
+            options,
+            freq: 0,
+            // Btrfs checks itself; fsck.btrfs is a no-op
+            passno: 0,
+        }
+    }
+
+    /// EFI system partition `uuid` mounted at `mountpoint`
+    pub fn esp(uuid: &str, mountpoint: &str) -> Self {
+        Self {
+            spec: format!("UUID={}", uuid),
+            file: mountpoint.to_string(),
+            vfstype: "vfat".to_string(),
+            options: ESP_OPTIONS.iter().map(|o| o.to_string()).collect(),
+            freq: 0,
+            passno: 2,
+        }
+    }
+
+    /// Swap area: `UUID=...` of a partition or the path of a swapfile
+    pub fn swap<S: Into<String>>(spec: S) -> Self {
+        Self {
+            spec: spec.into(),
+            file: "none".to_string(),
+            vfstype: "swap".to_string(),
+            options: vec!["defaults".to_string()],
+            freq: 0,
+            passno: 0,
+        }
+    }
+}
+
+impl fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{} {}",
+            escape(&self.spec),
+            escape(&self.file),
+            self.vfstype,
+            self.options.join(","),
+            self.freq,
+            self.passno
+        )
+    }
+}
+
+impl FromStr for FstabEntry {
+    type Err = InstallerError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |index: usize| -> Result<u8, InstallerError> {
+            fields
+                .get(index)
+                .map_or(Ok(0), |n| n.parse())
+                .map_err(|_| InstallerError::Parse(format!("invalid fstab number in '{}'", line)))
+        };
+        match fields.as_slice() {
+            [spec, file, vfstype, rest @ ..] if rest.len() <= 3 => Ok(Self {
+                spec: unescape(spec),
+                file: unescape(file),
+                vfstype: vfstype.to_string(),
+                options: rest
+                    .first()
+                    .unwrap_or(&"defaults")
+                    .split(',')
+                    .map(str::to_string)
+                    .collect(),
+                freq: number(4)?,
+                passno: number(5)?,
+            }),
+            _ => Err(InstallerError::Parse(format!("malformed fstab line '{}'", line))),
+        }
+    }
+}
+
+/// Contents of `/etc/fstab`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fstab {
+    /// Entries in mount order
+    pub entries: Vec<FstabEntry>,
+}
+
+impl Fstab {
+    /// Append an entry
+    pub fn with_entry(mut self, entry: FstabEntry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// Entry mounted at `mountpoint`
+    pub fn get(&self, mountpoint: &str) -> Option<&FstabEntry> {
+        self.entries.iter().find(|e| e.file == mountpoint)
+    }
+}
+
+impl fmt::Display for Fstab {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Generated by rastOS\n# <spec>\t<file>\t<type>\t<options>\t<dump> <pass>")?;
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Fstab {
+    type Err = InstallerError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let entries = table_lines(content).map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// One `/etc/crypttab` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrypttabEntry {
+    /// Device-mapper name
+    pub name: String,
+    /// Encrypted device (`UUID=...`)
+    pub device: String,
+    /// Key file, `None` to ask for a passphrase
+    pub keyfile: Option<String>,
+    /// Unlock options
+    pub options: Vec<String>,
+}
+
+impl fmt::Display for CrypttabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.name,
+            escape(&self.device),
+            self.keyfile.as_deref().map_or("none".to_string(), escape),
+            self.options.join(",")
+        )
+    }
+}
+
+impl FromStr for CrypttabEntry {
+    type Err = InstallerError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [name, device, rest @ ..] if rest.len() <= 2 => Ok(Self {
+                name: name.to_string(),
+                device: unescape(device),
+                keyfile: rest.first().filter(|k| !matches!(**k, "none" | "-")).map(|k| unescape(k)),
+                options: rest
+                    .get(1)
+                    .map(|o| o.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+            }),
+            _ => Err(InstallerError::Parse(format!("malformed crypttab line '{}'", line))),
+        }
+    }
+}
+
+/// Contents of `/etc/crypttab`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Crypttab {
+    /// Entries in unlock order
+    pub entries: Vec<CrypttabEntry>,
+}
+
+impl Crypttab {
+    /// Add an entry, replacing one with the same name
+    pub fn insert(&mut self, entry: CrypttabEntry) {
+        match self.entries.iter_mut().find(|e| e.name == entry.name) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+}
+
+impl fmt::Display for Crypttab {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Crypttab {
+    type Err = InstallerError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let entries = table_lines(content).map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// Non-empty, non-comment lines
+fn table_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Octal-escape the characters that would split a field
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            ' ' => escaped.push_str("\\040"),
+            '\t' => escaped.push_str("\\011"),
+            '\n' => escaped.push_str("\\012"),
+            '\\' => escaped.push_str("\\134"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let code = rest.get(index + 1..index + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Filesystem UUID of whatever is mounted at `path`
+pub(super) fn mount_uuid(path: &Path) -> Result<String, InstallerError> {
+    let output = Command::new("findmnt").args(["--noheadings", "--output", "UUID"]).arg(path).output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("findmnt", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_UUID: &str = "5d1f3c2a-7b7e-4c55-9a0e-2f4b8c6d1e3f";
+
+    #[test]
+    fn test_fstab_round_trip() {
+        let fstab = Fstab::default()
+            .with_entry(FstabEntry::btrfs(ROOT_UUID, "/", "@"))
+            .with_entry(FstabEntry::esp("3A1B-2C4D", "/boot"))
+            .with_entry(FstabEntry::swap("/swap/swapfile"))
+            .with_entry(FstabEntry::btrfs(ROOT_UUID, "/srv/my data", "@data"));
+        let rendered = fstab.to_string();
+        assert!(rendered.contains(&format!(
+            "UUID={}\t/\tbtrfs\tnoatime,compress=zstd:1,space_cache=v2,subvol=/@\t0 0\n",
+            ROOT_UUID
+        )));
+        assert!(rendered.contains("/srv/my\\040data"));
+        assert_eq!(rendered.parse::<Fstab>().unwrap(), fstab);
+        assert_eq!(fstab.get("/boot").unwrap().passno, 2);
+
+        let short: FstabEntry = "tmpfs /tmp tmpfs".parse().unwrap();
+        assert_eq!(short.options, vec!["defaults"]);
+        assert!("/dev/sda1".parse::<FstabEntry>().is_err());
+    }
+
+    #[test]
+    fn test_crypttab_round_trip() {
+        let content = "# encrypted devices\nhome UUID=1234 /etc/keys/home.key luks\ncryptroot UUID=old none luks\n";
+        let mut crypttab: Crypttab = content.parse().unwrap();
+        assert_eq!(crypttab.entries[0].keyfile.as_deref(), Some("/etc/keys/home.key"));
+        assert_eq!(crypttab.entries[1].keyfile, None);
+
+        crypttab.insert(CrypttabEntry {
+            name: "cryptroot".to_string(),
+            device: "UUID=new".to_string(),
+            keyfile: None,
+            options: vec!["luks".to_string(), "discard".to_string()],
+        });
+        let rendered = crypttab.to_string();
+        assert_eq!(
+            rendered,
+            "home UUID=1234 /etc/keys/home.key luks\ncryptroot UUID=new none luks,discard\n"
+        );
+        assert_eq!(rendered.parse::<Crypttab>().unwrap(), crypttab);
+    }
+}
@@ -0,0 +1,891 @@
+//! Installation driver
+//!
+//! Runs an [`InstallProfile`] against a target mount point phase by phase,
+//! emitting [`InstallEvent`]s for every step. Without a disk layout the
+//! partition and format phases are skipped and the system is installed
+//! into whatever is already mounted at the target. The base system comes
+//! from pacstrap, or from an OCI image when the profile names one;
+//! pacstrap uses the fastest mirrors, or the media's local repository
+//! when offline.
+//! Installs to a disk layout are checkpointed after every phase and can
+//! be picked up again with [`Installer::resume`]. Phases from bootstrap on
+//! run as journaled transactions: a failed phase is rolled back to the
+//! checkpoint it started from.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use tokio::sync::broadcast;
+
+use super::checkpoint::Checkpoint;
+use super::detect::{write_chainload_entries, DetectedOs, Detection};
+use super::encryption::{EncryptionConfig, LuksVolume};
+use super::error::InstallerError;
+use super::fstab::{mount_uuid, Fstab, FstabEntry};
+use super::mirrors::PackageSource;
+use super::partition::{run, PartitionPlan, PartitionRole};
+use super::preflight::PreflightReport;
+use super::profile::InstallProfile;
+use super::progress::{InstallEvent, InstallPhase, PhaseReporter, EVENT_CAPACITY};
+use super::report::{write_report, CommandLog, InstallLog};
+use super::{FACTORY_SUBVOLUME, ROOT_SUBVOLUME};
+use crate::dry_run::{Action, DryRun};
+use crate::kernel::{Bootloader, InitramfsConfig, InitramfsGenerator, InitramfsHook};
+use crate::oci::OciImage;
+use crate::package::mirrors::render_mirrorlist;
+use crate::progress::{Progress, Silent};
+use crate::sys::btrfs;
+use crate::sys::stat::is_mount_point;
+use crate::transaction::{Executor, Step, Transaction};
+
+/// Boot entry written for the installed system (systemd-boot)
+const ENTRY_NAME: &str = "rastos.conf";
+
+/// Fallback entry mounting a multi-device root with a missing member (systemd-boot)
+const DEGRADED_ENTRY_NAME: &str = "rastos-degraded.conf";
+
+/// OCI layout used while installing from an image, relative to the target
+const IMAGE_LAYOUT: &str = ".rastos-image";
+
+/// Mount point of the root filesystem's top level, which holds the checkpoint
+const TOPLEVEL_MOUNT: &str = "/run/rastos-installer/toplevel";
+
+/// Journal of the phases at the top level, next to the checkpoint
+const JOURNAL_DIR: &str = ".rastos-journal";
+
+/// Reason given for phases skipped when resuming
+const COMPLETED_BEFORE: &str = "completed before the installation was interrupted";
+
+/// Handles system installation process
+#[derive(Debug)]
+pub struct Installer {
+    profile: InstallProfile,
+    target: PathBuf,
+    report_dir: PathBuf,
+    events: broadcast::Sender<InstallEvent>,
+    log: Arc<Mutex<InstallLog>>,
+    commands: CommandLog,
+    progress: Arc<dyn Progress>,
+    dry_run: DryRun,
+}
+
+impl Default for Installer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Installer {
+    /// Create a new installer instance
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            profile: InstallProfile::default(),
+            target: PathBuf::from("/mnt"),
+            report_dir: PathBuf::from("/var/log/rastos-installer"),
+            events,
+            log: Arc::new(Mutex::new(InstallLog::default())),
+            commands: CommandLog::default(),
+            progress: Arc::new(Silent),
+            dry_run: DryRun::off(),
+        }
+    }
+
+    /// Install according to `profile`
+    pub fn with_profile(mut self, profile: InstallProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Mount point of the new system
+    pub fn with_target<P: AsRef<Path>>(mut self, target: P) -> Self {
+        self.target = target.as_ref().to_path_buf();
+        self
+    }
+
+    /// Directory receiving the report bundle when the installation fails
+    pub fn with_report_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.report_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Also report the installation's events to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Only check the machine and plan the disk, recording the phases that
+    /// would run in `dry_run`
+    pub fn with_dry_run(mut self, dry_run: DryRun) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Events and commands of the installation so far
+    pub fn log(&self) -> InstallLog {
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        log.collect_commands(&self.commands);
+        log.clone()
+    }
+
+    /// Receive the installation's events
+    ///
+    /// Subscribe before calling [`run`](Self::run); events sent while no
+    /// one is subscribed are dropped.
+    pub fn subscribe(&self) -> broadcast::Receiver<InstallEvent> {
+        self.events.subscribe()
+    }
+
+    /// Check the machine against the profile without changing anything
+    pub fn preflight(&self) -> PreflightReport {
+        self.profile.preflight.run(&self.profile)
+    }
+
+    /// Run all phases
+    pub fn run(&self) -> Result<(), InstallerError> {
+        let result = self.install(false);
+        self.report(result)
+    }
+
+    /// Resume an interrupted installation after its last completed phase
+    ///
+    /// The disk must still hold the filesystems the interrupted run created
+    /// and the profile must be unchanged; both are checked before anything
+    /// is modified.
+    pub fn resume(&self) -> Result<(), InstallerError> {
+        let result = self.install(true);
+        self.report(result)
+    }
+
+    /// Write a report bundle if the installation failed
+    fn report(&self, result: Result<(), InstallerError>) -> Result<(), InstallerError> {
+        let Err(error) = &result else {
+            return result;
+        };
+        if self.dry_run.is_active() {
+            return result;
+        }
+        match write_report(&self.report_dir, &self.log(), &self.profile, error) {
+            Ok(path) => self.emit(InstallEvent::ReportWritten { path }),
+            Err(e) => self.emit(InstallEvent::Warning {
+                message: format!("Could not write the failure report: {}", e),
+            }),
+        }
+        result
+    }
+
+    fn install(&self, resuming: bool) -> Result<(), InstallerError> {
+        self.profile.validate()?;
+
+        let source = self.phase(InstallPhase::Preflight, |r| {
+            let report = self.preflight();
+            for check in report.warnings() {
+                r.warn(check.detail.clone());
+            }
+            if !report.passed() {
+                return Err(InstallerError::Preflight(report.failures().map(|c| c.detail.clone()).collect()));
+            }
+            // Settled before the disk is touched so a missing source fails early
+            if self.profile.image.is_some() {
+                return Ok(None);
+            }
+            r.message("Selecting package mirrors");
+            let source = self.profile.mirrors.resolve()?;
+            match &source {
+                PackageSource::Mirrors(mirrors) => r.message(format!("Using {} mirrors, fastest first", mirrors.len())),
+                PackageSource::Local { path, .. } => r.message(format!("Installing from {}", path.display())),
+            }
+            Ok(Some(source))
+        })?;
+
+        let detection = Detection::probe(&self.commands).unwrap_or_else(|e| {
+            warn!("Could not detect existing systems: {}", e);
+            Detection::default()
+        });
+        let plan = self.profile.disk.as_ref().map(|disk| disk.plan()).transpose()?;
+        if let Some(plan) = plan.as_ref().filter(|_| !resuming) {
+            for os in plan.disks().flat_map(|disk| detection.overwritten(disk)) {
+                self.emit(InstallEvent::Warning {
+                    message: format!("{} on {} will be erased", os.name, os.device.display()),
+                });
+            }
+        }
+
+        if self.dry_run.is_active() {
+            self.plan_phases(plan.as_ref(), resuming);
+            return Ok(());
+        }
+
+        let toplevel = Path::new(TOPLEVEL_MOUNT);
+        let (luks, mut checkpoint) = match (&plan, resuming) {
+            (Some(plan), false) => {
+                self.phase(InstallPhase::Partition, |r| {
+                    r.message(format!("Writing partition table\n{}", plan));
+                    plan.write_table(&self.commands)
+                })?;
+                let luks = self.phase(InstallPhase::Format, |r| {
+                    r.message("Creating filesystems");
+                    plan.format(&self.commands)
+                })?;
+                let uuid = self.mount_toplevel(plan, luks.as_ref())?;
+                let mut checkpoint = Checkpoint::new(&self.profile, &plan.device, &uuid)?;
+                for phase in [InstallPhase::Preflight, InstallPhase::Partition, InstallPhase::Format] {
+                    checkpoint.complete(phase);
+                }
+                checkpoint.save(toplevel)?;
+                (luks, Some(checkpoint))
+            }
+            (Some(plan), true) => {
+                let (luks, checkpoint) = self.reopen(plan)?;
+                for phase in [InstallPhase::Partition, InstallPhase::Format] {
+                    self.emit(InstallEvent::PhaseSkipped {
+                        phase,
+                        reason: COMPLETED_BEFORE.to_string(),
+                    });
+                }
+                (luks, Some(checkpoint))
+            }
+            (None, false) => {
+                for phase in [InstallPhase::Partition, InstallPhase::Format] {
+                    self.emit(InstallEvent::PhaseSkipped {
+                        phase,
+                        reason: format!("no disk layout, installing into {}", self.target.display()),
+                    });
+                }
+                (None, None)
+            }
+            (None, true) => {
+                return Err(InstallerError::Resume(
+                    "installations without a disk layout are not checkpointed".to_string(),
+                ))
+            }
+        };
+
+        self.resumable(
+            &mut checkpoint,
+            InstallPhase::Bootstrap,
+            |r| self.bootstrap(r, plan.as_ref(), luks.as_ref(), source.as_ref()),
+            |_, _| {},
+        )?;
+        let resume = match self.resumable(
+            &mut checkpoint,
+            InstallPhase::Configure,
+            |r| self.configure(r, plan.as_ref(), luks.as_ref()),
+            |checkpoint, resume| checkpoint.resume = resume.clone(),
+        )? {
+            Some(resume) => resume,
+            None => checkpoint.as_ref().and_then(|c| c.resume.clone()),
+        };
+
+        let others: Vec<DetectedOs> = detection
+            .systems
+            .iter()
+            .filter(|os| !plan.as_ref().is_some_and(|plan| plan.disks().any(|disk| os.is_on(disk))))
+            .cloned()
+            .collect();
+        self.resumable(
+            &mut checkpoint,
+            InstallPhase::Bootloader,
+            |r| self.install_bootloader(r, luks.as_ref(), resume.as_deref(), &others),
+            |_, _| {},
+        )?;
+
+        if self.profile.provision.is_empty() {
+            self.emit(InstallEvent::PhaseSkipped {
+                phase: InstallPhase::PostInstall,
+                reason: "no hooks or first-boot tasks".to_string(),
+            });
+        } else {
+            self.resumable(
+                &mut checkpoint,
+                InstallPhase::PostInstall,
+                |r| {
+                    let provision = &self.profile.provision;
+                    provision.run_hooks(&self.target, &self.commands, |hook| {
+                        r.message(format!("Running hook {}", hook.name))
+                    })?;
+                    r.message(format!("Installing {} first-boot tasks", provision.first_boot.len()));
+                    provision.write_first_boot(&self.target)
+                },
+                |_, _| {},
+            )?;
+        }
+
+        if checkpoint.is_some() {
+            Checkpoint::finish(toplevel)?;
+            // Taken before the first boot, so it holds no machine-specific state yet
+            if !toplevel.join(FACTORY_SUBVOLUME).exists() {
+                btrfs::snapshot(toplevel.join(ROOT_SUBVOLUME), toplevel.join(FACTORY_SUBVOLUME), true)?;
+            }
+            run(Command::new("umount").arg(toplevel), &self.commands)?;
+        }
+        self.emit(InstallEvent::Finished);
+        Ok(())
+    }
+
+    /// Record the phases after the preflight checks in the dry run
+    fn plan_phases(&self, plan: Option<&PartitionPlan>, resuming: bool) {
+        if let Some(plan) = plan.filter(|_| !resuming) {
+            let device = plan.device.display();
+            self.dry_run.skip(Action::write(format!("partition table of {}", device)).with_detail(plan));
+            self.dry_run.skip(Action::create(format!("filesystems on {}", device)));
+        }
+        for phase in [InstallPhase::Bootstrap, InstallPhase::Configure, InstallPhase::Bootloader, InstallPhase::PostInstall] {
+            if phase == InstallPhase::PostInstall && self.profile.provision.is_empty() {
+                continue;
+            }
+            self.dry_run.skip(Action::run(format!("{} phase into {}", phase, self.target.display())));
+        }
+    }
+
+    /// Run `phase` unless the checkpoint shows it completed
+    ///
+    /// `record` stores the phase's result in the checkpoint, which is saved
+    /// together with a snapshot of the installed system. A failed phase is
+    /// rolled back to the checkpoint's snapshot.
+    fn resumable<T>(
+        &self,
+        checkpoint: &mut Option<Checkpoint>,
+        phase: InstallPhase,
+        f: impl FnOnce(&PhaseReporter) -> Result<T, InstallerError>,
+        record: impl FnOnce(&mut Checkpoint, &T),
+    ) -> Result<Option<T>, InstallerError> {
+        if checkpoint.as_ref().is_some_and(|c| c.is_completed(phase)) {
+            self.emit(InstallEvent::PhaseSkipped {
+                phase,
+                reason: COMPLETED_BEFORE.to_string(),
+            });
+            return Ok(None);
+        }
+        let executor = match checkpoint {
+            Some(_) => Executor::with_dir(Path::new(TOPLEVEL_MOUNT).join(JOURNAL_DIR)),
+            None => Executor::with_root(&self.target),
+        };
+        let value = {
+            let mut transaction = PhaseTransaction {
+                installer: self,
+                phase,
+                checkpoint: checkpoint.as_ref(),
+                f: Some(f),
+                value: None,
+            };
+            if let Err(e) = executor.run(&mut transaction) {
+                return Err(e.downcast::<InstallerError>()?);
+            }
+            transaction.value.take()
+        };
+        let Some(value) = value else {
+            unreachable!("a committed phase has run");
+        };
+        if let Some(checkpoint) = checkpoint {
+            record(checkpoint, &value);
+            checkpoint.snapshot(Path::new(TOPLEVEL_MOUNT), ROOT_SUBVOLUME, phase)?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Open and mount what an interrupted run created and load its checkpoint
+    fn reopen(&self, plan: &PartitionPlan) -> Result<(Option<LuksVolume>, Checkpoint), InstallerError> {
+        let luks = match self.encryption() {
+            Some(encryption) => {
+                let Some(root) = plan.partition(PartitionRole::Root) else {
+                    unreachable!("validated: the layout has a root partition");
+                };
+                let volume = LuksVolume::existing(&root.path, encryption.mapper_name.clone())?;
+                volume.open(encryption, &self.commands)?;
+                Some(volume)
+            }
+            None => None,
+        };
+        let uuid = self.mount_toplevel(plan, luks.as_ref())?;
+        let toplevel = Path::new(TOPLEVEL_MOUNT);
+        let checkpoint = Checkpoint::load(toplevel)?.ok_or_else(|| {
+            InstallerError::Resume(format!("no interrupted installation found on {}", plan.device.display()))
+        })?;
+        checkpoint.verify(&self.profile, &plan.device, &uuid)?;
+
+        if checkpoint.is_completed(InstallPhase::Bootstrap) {
+            checkpoint.restore(toplevel, ROOT_SUBVOLUME)?;
+            self.mount_target(plan, luks.as_ref())?;
+        }
+        Ok((luks, checkpoint))
+    }
+
+    /// Mount the root filesystem's top level, returning its UUID
+    fn mount_toplevel(&self, plan: &PartitionPlan, luks: Option<&LuksVolume>) -> Result<String, InstallerError> {
+        let toplevel = Path::new(TOPLEVEL_MOUNT);
+        fs::create_dir_all(toplevel)?;
+        run(
+            Command::new("mount")
+                .args(["-o", "subvolid=5"])
+                .arg(root_device(plan, luks))
+                .arg(toplevel),
+            &self.commands,
+        )?;
+        mount_uuid(toplevel)
+    }
+
+    /// Mount the `@` subvolume at the target and the ESP below it
+    fn mount_target(&self, plan: &PartitionPlan, luks: Option<&LuksVolume>) -> Result<(), InstallerError> {
+        fs::create_dir_all(&self.target)?;
+        run(
+            Command::new("mount")
+                .args(["-o", &format!("subvol={}", ROOT_SUBVOLUME)])
+                .arg(root_device(plan, luks))
+                .arg(&self.target),
+            &self.commands,
+        )?;
+        if let Some(esp) = plan.partition(PartitionRole::Esp) {
+            let boot = self.target.join("boot");
+            fs::create_dir_all(&boot)?;
+            run(Command::new("mount").arg(&esp.path).arg(&boot), &self.commands)?;
+        }
+        Ok(())
+    }
+
+    /// Run one phase, reporting its start and its outcome
+    fn phase<T>(
+        &self,
+        phase: InstallPhase,
+        f: impl FnOnce(&PhaseReporter) -> Result<T, InstallerError>,
+    ) -> Result<T, InstallerError> {
+        self.emit(InstallEvent::PhaseStarted { phase });
+        let reporter = PhaseReporter::new(phase, self.events.clone(), Arc::clone(&self.log), Arc::clone(&self.progress));
+        match f(&reporter) {
+            Ok(value) => {
+                self.emit(InstallEvent::PhaseFinished { phase });
+                Ok(value)
+            }
+            Err(e) => {
+                self.emit(InstallEvent::Failed {
+                    phase,
+                    error: e.to_string(),
+                });
+                Err(e)
+            }
+        }
+    }
+
+    fn emit(&self, event: InstallEvent) {
+        self.progress.report(event.to_progress());
+        self.log.lock().unwrap_or_else(|e| e.into_inner()).event(event.clone());
+        // Nobody listening is not an error
+        let _ = self.events.send(event);
+    }
+
+    /// Mount the new filesystems and install the base packages
+    fn bootstrap(
+        &self,
+        r: &PhaseReporter,
+        plan: Option<&PartitionPlan>,
+        luks: Option<&LuksVolume>,
+        source: Option<&PackageSource>,
+    ) -> Result<(), InstallerError> {
+        if let Some(plan) = plan {
+            // The system lives in a subvolume so it can be snapshotted as a whole
+            let subvolume = Path::new(TOPLEVEL_MOUNT).join(ROOT_SUBVOLUME);
+            if subvolume.exists() {
+                r.message("Discarding the interrupted bootstrap");
+                btrfs::delete_subvolume(&subvolume, true)?;
+            }
+            btrfs::create_subvolume(&subvolume)?;
+            r.message(format!("Mounting the new system at {}", self.target.display()));
+            self.mount_target(plan, luks)?;
+        }
+
+        if let Some(reference) = &self.profile.image {
+            return self.unpack_image(r, reference);
+        }
+        let Some(source) = source else {
+            return Err(InstallerError::Unsupported("no package source was selected".to_string()));
+        };
+
+        let mut packages = self.profile.packages.clone();
+        let extra = match self.profile.initramfs {
+            InitramfsGenerator::Mkinitcpio => "mkinitcpio",
+            InitramfsGenerator::Dracut => "dracut",
+        };
+        if !packages.iter().any(|p| p == extra) {
+            packages.push(extra.to_string());
+        }
+        if self.profile.bootloader == Bootloader::Grub {
+            packages.extend(["grub".to_string(), "efibootmgr".to_string()]);
+        }
+        packages.extend(self.profile.swap.packages().iter().map(|p| p.to_string()));
+
+        let staging = tempfile::tempdir()?;
+        let staged_mirrorlist = staging.path().join("mirrorlist");
+        let pacman_conf = staging.path().join("pacman.conf");
+        if let PackageSource::Mirrors(mirrors) = source {
+            fs::write(&staged_mirrorlist, render_mirrorlist(mirrors))?;
+        }
+        fs::write(&pacman_conf, source.pacman_conf(&staged_mirrorlist))?;
+
+        r.message(format!("Installing {} packages", packages.len()));
+        // -M: the live system's mirrorlist is replaced below
+        run(
+            Command::new("pacstrap")
+                .arg("-C")
+                .arg(&pacman_conf)
+                .args(["-M", "-K"])
+                .arg(&self.target)
+                .args(&packages),
+            &self.commands,
+        )?;
+
+        // Offline installs get the unranked candidates for later updates
+        let servers = match source {
+            PackageSource::Mirrors(mirrors) => mirrors.clone(),
+            PackageSource::Local { .. } => self.profile.mirrors.candidates(),
+        };
+        let target_mirrorlist = self.target.join("etc/pacman.d/mirrorlist");
+        if let Some(parent) = target_mirrorlist.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(target_mirrorlist, render_mirrorlist(&servers))?;
+        Ok(())
+    }
+
+    /// Unpack an OCI image instead of installing packages
+    fn unpack_image(&self, r: &PhaseReporter, reference: &str) -> Result<(), InstallerError> {
+        // Keep the layout on the target disk rather than in live-system RAM
+        let layout = self.target.join(IMAGE_LAYOUT);
+        r.progress(0, format!("Pulling {}", reference));
+        let image = OciImage::pull(reference, &layout, r.subtasks())?;
+        r.progress(50, "Unpacking image layers");
+        let result = image.unpack(&self.target, r.subtasks());
+        if let Err(e) = fs::remove_dir_all(&layout) {
+            warn!("Could not remove image layout {}: {}", layout.display(), e);
+        }
+        result?;
+        r.progress(100, "Image unpacked");
+        Ok(())
+    }
+
+    /// Write fstab, swap, locale, accounts and boot-time unlocking
+    ///
+    /// Returns the kernel parameters for resuming from hibernation.
+    fn configure(
+        &self,
+        r: &PhaseReporter,
+        plan: Option<&PartitionPlan>,
+        luks: Option<&LuksVolume>,
+    ) -> Result<Option<String>, InstallerError> {
+        r.progress(0, "Configuring swap");
+        let swap_partition = plan.and_then(|plan| plan.partition(PartitionRole::Swap)).map(|p| p.path.as_path());
+        let swap = self.profile.swap.apply(&self.target, swap_partition, &self.commands)?;
+
+        r.progress(10, "Generating fstab");
+        let fstab = match plan {
+            Some(plan) => {
+                let mut fstab = Fstab::default().with_entry(FstabEntry::btrfs(
+                    &mount_uuid(&self.target)?,
+                    "/",
+                    ROOT_SUBVOLUME,
+                ));
+                if plan.partition(PartitionRole::Esp).is_some() {
+                    fstab = fstab.with_entry(FstabEntry::esp(&mount_uuid(&self.target.join("boot"))?, "/boot"));
+                }
+                fstab.entries.extend(swap.fstab.clone());
+                fstab.to_string()
+            }
+            // Not our filesystems: describe whatever is mounted
+            None => {
+                let output = Command::new("genfstab").arg("-U").arg(&self.target).output()?;
+                if !output.status.success() {
+                    return Err(InstallerError::command_error("genfstab", &output));
+                }
+                let mut fstab = String::from_utf8_lossy(&output.stdout).into_owned();
+                if let Some(entry) = &swap.fstab {
+                    fstab.push_str(&format!("{}\n", entry));
+                }
+                fstab
+            }
+        };
+        fs::write(self.target.join("etc/fstab"), fstab)?;
+
+        r.progress(25, "Configuring locale and timezone");
+        self.profile.locale.apply(&self.target, &self.commands)?;
+
+        r.progress(50, "Creating accounts");
+        self.profile.accounts.apply(&self.target, &self.commands)?;
+
+        let hook_dir = match self.profile.initramfs {
+            InitramfsGenerator::Mkinitcpio => "etc/initcpio",
+            InitramfsGenerator::Dracut => "usr/lib/dracut/modules.d",
+        };
+        let mut initramfs = InitramfsConfig::default()
+            .with_generator(self.profile.initramfs)
+            .with_hook_dir(self.target.join(hook_dir));
+        if let (Some(volume), Some(encryption)) = (luks, self.encryption()) {
+            r.progress(75, "Configuring encrypted root unlocking");
+            volume.write_crypttab(&self.target, encryption)?;
+            initramfs = encryption.initramfs_config(initramfs);
+        }
+        if swap.resume.is_some() {
+            initramfs = initramfs.with_hook(InitramfsHook::Resume);
+        }
+
+        if luks.is_some() || swap.resume.is_some() {
+            r.progress(85, "Regenerating the initramfs");
+            initramfs.install_hooks()?;
+            match self.profile.initramfs {
+                InitramfsGenerator::Mkinitcpio => {
+                    fs::write(self.target.join("etc/mkinitcpio.conf"), initramfs.mkinitcpio_conf())?;
+                    run(Command::new("arch-chroot").arg(&self.target).args(["mkinitcpio", "-P"]), &self.commands)?;
+                }
+                InitramfsGenerator::Dracut => {
+                    let conf = format!("add_dracutmodules+=\" {} \"\n", initramfs.dracut_modules().join(" "));
+                    let dir = self.target.join("etc/dracut.conf.d");
+                    fs::create_dir_all(&dir)?;
+                    fs::write(dir.join("10-rastos.conf"), conf)?;
+                    run(
+                        Command::new("arch-chroot").arg(&self.target).args(["dracut", "--force", "--regenerate-all"]),
+                        &self.commands,
+                    )?;
+                }
+            }
+        }
+        r.progress(100, "System configured");
+        Ok(swap.resume)
+    }
+
+    /// Install the bootloader with an entry for the new system
+    ///
+    /// With a Btrfs RAID the ESP only exists on the first disk; member disks
+    /// are whole-disk filesystem devices and cannot boot on their own. The
+    /// extra entry mounting the root degraded is only written for
+    /// systemd-boot; GRUB users add `rootflags=degraded` from the menu.
+    fn install_bootloader(
+        &self,
+        r: &PhaseReporter,
+        luks: Option<&LuksVolume>,
+        resume: Option<&str>,
+        others: &[DetectedOs],
+    ) -> Result<(), InstallerError> {
+        let root = match (luks, self.encryption()) {
+            (Some(volume), Some(encryption)) => {
+                format!("{} rw", volume.kernel_cmdline(encryption, self.profile.initramfs))
+            }
+            _ => format!("root=UUID={} rw", mount_uuid(&self.target)?),
+        };
+        let mut rootflags = Vec::new();
+        if let Some(subvolume) = mount_subvolume(&self.target)? {
+            rootflags.push(format!("subvol={}", subvolume));
+        }
+        let with_flags = |rootflags: &[String]| {
+            let mut cmdline = root.clone();
+            if !rootflags.is_empty() {
+                cmdline.push_str(&format!(" rootflags={}", rootflags.join(",")));
+            }
+            if let Some(resume) = resume {
+                cmdline.push_str(&format!(" {}", resume));
+            }
+            cmdline
+        };
+        let cmdline = with_flags(&rootflags);
+        let raid = self.profile.disk.as_ref().and_then(|disk| disk.raid);
+        if let Some(raid) = raid {
+            r.warn(format!(
+                "The root filesystem is Btrfs {}; with a failed disk it only mounts with rootflags=degraded, \
+                 and only the first disk holds the ESP",
+                raid.profile()
+            ));
+        }
+        let boot = self.target.join("boot");
+
+        if self.profile.dual_boot && !others.is_empty() {
+            let esp_uuid = mount_uuid(&boot).ok();
+            let written = write_chainload_entries(self.profile.bootloader, &self.target, esp_uuid.as_deref(), others)?;
+            r.message(format!("Added {} entries for other systems", written));
+        }
+
+        match self.profile.bootloader {
+            Bootloader::SystemdBoot => {
+                r.message("Installing systemd-boot");
+                run(
+                    Command::new("bootctl").arg(format!("--esp-path={}", boot.display())).arg("install"),
+                    &self.commands,
+                )?;
+                let entries = boot.join("loader/entries");
+                fs::create_dir_all(&entries)?;
+                fs::write(
+                    entries.join(ENTRY_NAME),
+                    format!(
+                        "title rastOS\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions {}\n",
+                        cmdline
+                    ),
+                )?;
+                if raid.is_some() {
+                    rootflags.push("degraded".to_string());
+                    fs::write(
+                        entries.join(DEGRADED_ENTRY_NAME),
+                        format!(
+                            "title rastOS (degraded RAID)\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions {}\n",
+                            with_flags(&rootflags)
+                        ),
+                    )?;
+                }
+                fs::write(boot.join("loader/loader.conf"), format!("default {}\ntimeout 3\n", ENTRY_NAME))?;
+            }
+            Bootloader::Grub => {
+                r.message("Installing GRUB");
+                if raid.is_some() {
+                    r.warn("GRUB gets no degraded RAID entry; add rootflags=degraded to the kernel line when a disk fails");
+                }
+                let default = self.target.join("etc/default/grub");
+                let content = fs::read_to_string(&default).unwrap_or_default();
+                let mut content: String = content
+                    .lines()
+                    .filter(|line| !line.starts_with("GRUB_CMDLINE_LINUX="))
+                    .map(|line| format!("{}\n", line))
+                    .collect();
+                content.push_str(&format!("GRUB_CMDLINE_LINUX=\"{}\"\n", cmdline));
+                fs::write(&default, content)?;
+                run(
+                    Command::new("arch-chroot").arg(&self.target).args([
+                        "grub-install",
+                        "--target=x86_64-efi",
+                        "--efi-directory=/boot",
+                        "--bootloader-id=rastOS",
+                    ]),
+                    &self.commands,
+                )?;
+                run(
+                    Command::new("arch-chroot")
+                        .arg(&self.target)
+                        .args(["grub-mkconfig", "-o", "/boot/grub/grub.cfg"]),
+                    &self.commands,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encryption(&self) -> Option<&EncryptionConfig> {
+        self.profile.disk.as_ref().and_then(|disk| disk.encryption.as_ref())
+    }
+}
+
+/// A resumable phase as a transaction of one step
+///
+/// Rolling back unmounts the target and replaces `@` with the snapshot
+/// the checkpoint recorded before the phase; without a checkpoint there is
+/// nothing to return to and the journal only records the failure.
+struct PhaseTransaction<'a, T, F> {
+    installer: &'a Installer,
+    phase: InstallPhase,
+    checkpoint: Option<&'a Checkpoint>,
+    f: Option<F>,
+    value: Option<T>,
+}
+
+impl<T, F> Transaction for PhaseTransaction<'_, T, F>
+where
+    F: FnOnce(&PhaseReporter) -> Result<T, InstallerError>,
+{
+    type Error = InstallerError;
+
+    fn kind(&self) -> &str {
+        "install-phase"
+    }
+
+    fn plan(&mut self) -> Result<Vec<Step>, InstallerError> {
+        let mut step = Step::new(self.phase.to_string());
+        if let Some(snapshot) = self.checkpoint.and_then(|c| c.snapshot.as_ref()) {
+            step.set("snapshot", snapshot);
+        }
+        Ok(vec![step])
+    }
+
+    fn execute(&mut self, _step: &mut Step) -> Result<(), InstallerError> {
+        let Some(f) = self.f.take() else {
+            unreachable!("a phase has a single step");
+        };
+        self.value = Some(self.installer.phase(self.phase, f)?);
+        Ok(())
+    }
+
+    fn rollback(&mut self, _step: &Step) -> Result<(), InstallerError> {
+        let Some(checkpoint) = self.checkpoint else {
+            return Ok(());
+        };
+        // `@` is replaced underneath the target, so nothing may stay mounted there
+        let target = &self.installer.target;
+        if target.exists() && is_mount_point(target)? {
+            run(Command::new("umount").arg("-R").arg(target), &self.installer.commands)?;
+        }
+        checkpoint.restore(Path::new(TOPLEVEL_MOUNT), ROOT_SUBVOLUME)
+    }
+}
+
+/// Device holding the root filesystem: the opened LUKS container or the partition
+fn root_device(plan: &PartitionPlan, luks: Option<&LuksVolume>) -> PathBuf {
+    match (luks, plan.partition(PartitionRole::Root)) {
+        (Some(volume), _) => volume.mapper_path(),
+        (None, Some(root)) => root.path.clone(),
+        (None, None) => unreachable!("validated: the layout has a root partition"),
+    }
+}
+
+/// Btrfs subvolume mounted at `path`, unless it is the top level
+fn mount_subvolume(path: &Path) -> Result<Option<String>, InstallerError> {
+    let output = Command::new("findmnt")
+        .args(["--noheadings", "--output", "FSTYPE,FSROOT"])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("findmnt", &output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some("btrfs"), Some(root)) if root != "/" => Ok(Some(root.trim_start_matches('/').to_string())),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_events() {
+        let installer = Installer::new();
+        let mut events = installer.subscribe();
+
+        installer
+            .phase(InstallPhase::Configure, |r| {
+                r.progress(50, "halfway");
+                Ok(())
+            })
+            .unwrap();
+        let failed: Result<(), _> = installer.phase(InstallPhase::Bootloader, |_| {
+            Err(InstallerError::Unsupported("no ESP".to_string()))
+        });
+        assert!(failed.is_err());
+
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(
+            received,
+            vec![
+                InstallEvent::PhaseStarted { phase: InstallPhase::Configure },
+                InstallEvent::Progress {
+                    phase: InstallPhase::Configure,
+                    message: "halfway".to_string(),
+                    percent: Some(50),
+                },
+                InstallEvent::PhaseFinished { phase: InstallPhase::Configure },
+                InstallEvent::PhaseStarted { phase: InstallPhase::Bootloader },
+                InstallEvent::Failed {
+                    phase: InstallPhase::Bootloader,
+                    error: "Unsupported operation: no ESP".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Locale, timezone and console configuration
+//!
+//! Writes `/etc/locale.gen`, `/etc/locale.conf`, `/etc/localtime` and
+//! `/etc/vconsole.conf` in the installed system and runs `locale-gen`
+//! there. Every value is checked against the zoneinfo, locale and keymap
+//! data shipped in the target before anything is written.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path};
+use std::process::Command;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::error::InstallerError;
+use super::partition::run;
+use super::report::CommandLog;
+
+const ZONEINFO: &str = "usr/share/zoneinfo";
+const SUPPORTED_LOCALES: &str = "usr/share/i18n/SUPPORTED";
+const KEYMAPS: &str = "usr/share/kbd/keymaps";
+const CONSOLE_FONTS: &str = "usr/share/kbd/consolefonts";
+
+/// Locale, timezone and console settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocaleConfig {
+    /// Locales to generate
+    pub locales: Vec<String>,
+
+    /// `LANG`; defaults to the first generated locale
+    pub lang: Option<String>,
+
+    /// Timezone name below `/usr/share/zoneinfo`
+    pub timezone: String,
+
+    /// Console keymap
+    pub keymap: String,
+
+    /// Console font
+    pub console_font: Option<String>,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        Self {
+            locales: vec!["en_US.UTF-8".to_string()],
+            lang: None,
+            timezone: "UTC".to_string(),
+            keymap: "us".to_string(),
+            console_font: None,
+        }
+    }
+}
+
+impl LocaleConfig {
+    /// Set the locales to generate
+    pub fn with_locales<I, S>(mut self, locales: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.locales = locales.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the timezone
+    pub fn with_timezone<S: Into<String>>(mut self, timezone: S) -> Self {
+        self.timezone = timezone.into();
+        self
+    }
+
+    /// Set the console keymap
+    pub fn with_keymap<S: Into<String>>(mut self, keymap: S) -> Self {
+        self.keymap = keymap.into();
+        self
+    }
+
+    /// Effective `LANG`
+    pub fn lang(&self) -> Option<&str> {
+        self.lang.as_deref().or(self.locales.first().map(String::as_str))
+    }
+
+    /// Check the settings against the data installed at `root`
+    ///
+    /// Returns the `locale.gen` lines (`<locale> <charset>`) to enable.
+    pub fn validate(&self, root: &Path) -> Result<Vec<String>, InstallerError> {
+        let Some(lang) = self.lang() else {
+            return Err(InstallerError::InvalidProfile("no locale configured".to_string()));
+        };
+        if !self.locales.iter().any(|l| l == lang) {
+            return Err(InstallerError::InvalidProfile(format!("LANG {} is not a generated locale", lang)));
+        }
+
+        let supported = fs::read_to_string(root.join(SUPPORTED_LOCALES))?;
+        let mut entries = Vec::new();
+        for locale in &self.locales {
+            let entry = supported
+                .lines()
+                .find(|line| line.split_whitespace().next() == Some(locale.as_str()))
+                .ok_or_else(|| InstallerError::InvalidProfile(format!("unknown locale {}", locale)))?;
+            entries.push(entry.trim().to_string());
+        }
+
+        if !is_relative_name(&self.timezone) || !root.join(ZONEINFO).join(&self.timezone).is_file() {
+            return Err(InstallerError::InvalidProfile(format!("unknown timezone {}", self.timezone)));
+        }
+        if !has_data_file(&root.join(KEYMAPS), &self.keymap, ".map") {
+            return Err(InstallerError::InvalidProfile(format!("unknown keymap {}", self.keymap)));
+        }
+        if let Some(font) = &self.console_font {
+            if !has_data_file(&root.join(CONSOLE_FONTS), font, "") {
+                return Err(InstallerError::InvalidProfile(format!("unknown console font {}", font)));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Write the configuration files below `root`
+    pub fn write_files(&self, root: &Path) -> Result<(), InstallerError> {
+        let entries = self.validate(root)?;
+        let etc = root.join("etc");
+        fs::create_dir_all(&etc)?;
+
+        let existing = fs::read_to_string(etc.join("locale.gen")).unwrap_or_default();
+        fs::write(etc.join("locale.gen"), locale_gen(&existing, &entries))?;
+        fs::write(etc.join("locale.conf"), format!("LANG={}\n", self.lang().unwrap_or_default()))?;
+
+        let localtime = etc.join("localtime");
+        if localtime.symlink_metadata().is_ok() {
+            fs::remove_file(&localtime)?;
+        }
+        symlink(Path::new("..").join(ZONEINFO).join(&self.timezone), &localtime)?;
+
+        let mut vconsole = format!("KEYMAP={}\n", self.keymap);
+        if let Some(font) = &self.console_font {
+            vconsole.push_str(&format!("FONT={}\n", font));
+        }
+        fs::write(etc.join("vconsole.conf"), vconsole)?;
+        Ok(())
+    }
+
+    /// Configure the system installed at `root` and generate its locales
+    pub fn apply(&self, root: &Path, log: &CommandLog) -> Result<(), InstallerError> {
+        self.write_files(root)?;
+        info!("Generating locales: {}", self.locales.join(", "));
+        run(Command::new("chroot").arg(root).arg("locale-gen"), log)
+    }
+}
+
+/// Enable `entries` in an existing `locale.gen`, appending missing ones
+fn locale_gen(existing: &str, entries: &[String]) -> String {
+    let mut enabled = Vec::new();
+    let mut content = String::new();
+    for line in existing.lines() {
+        let uncommented = line.trim_start_matches('#').trim();
+        if entries.iter().any(|e| e == uncommented) {
+            content.push_str(uncommented);
+            enabled.push(uncommented);
+        } else {
+            content.push_str(line);
+        }
+        content.push('\n');
+    }
+    for entry in entries {
+        if !enabled.contains(&entry.as_str()) {
+            content.push_str(entry);
+            content.push('\n');
+        }
+    }
+    content
+}
+
+/// Whether `name` stays below the directory it is joined to
+fn is_relative_name(name: &str) -> bool {
+    !name.is_empty() && Path::new(name).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Whether a kbd data file named `name` (optionally compressed) exists below `dir`
+fn has_data_file(dir: &Path, name: &str, extension: &str) -> bool {
+    if !is_relative_name(name) {
+        return false;
+    }
+    let stem = format!("{}{}", name, extension);
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .any(|e| {
+            let file = e.file_name().to_string_lossy();
+            file == stem || file.strip_prefix(stem.as_str()).is_some_and(|rest| rest.starts_with('.'))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn fake_root() -> tempfile::TempDir {
+        let root = tempdir().unwrap();
+        let path = root.path();
+        fs::create_dir_all(path.join("usr/share/zoneinfo/Europe")).unwrap();
+        fs::write(path.join("usr/share/zoneinfo/Europe/Berlin"), "TZif").unwrap();
+        fs::create_dir_all(path.join("usr/share/i18n")).unwrap();
+        fs::write(
+            path.join(SUPPORTED_LOCALES),
+            "de_DE.UTF-8 UTF-8\nde_DE ISO-8859-1\nen_US.UTF-8 UTF-8\n",
+        )
+        .unwrap();
+        fs::create_dir_all(path.join("usr/share/kbd/keymaps/i386/qwertz")).unwrap();
+        fs::write(path.join("usr/share/kbd/keymaps/i386/qwertz/de-latin1.map.gz"), "").unwrap();
+        fs::create_dir_all(path.join("etc")).unwrap();
+        fs::write(path.join("etc/locale.gen"), "#de_DE.UTF-8 UTF-8\n#en_US.UTF-8 UTF-8\n").unwrap();
+        root
+    }
+
+    #[test]
+    fn test_write_files() -> Result<(), Box<dyn std::error::Error>> {
+        let root = fake_root();
+        let config = LocaleConfig::default()
+            .with_locales(["de_DE.UTF-8", "en_US.UTF-8"])
+            .with_timezone("Europe/Berlin")
+            .with_keymap("de-latin1");
+        config.write_files(root.path())?;
+
+        let etc = root.path().join("etc");
+        assert_eq!(fs::read_to_string(etc.join("locale.gen"))?, "de_DE.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n");
+        assert_eq!(fs::read_to_string(etc.join("locale.conf"))?, "LANG=de_DE.UTF-8\n");
+        assert_eq!(fs::read_link(etc.join("localtime"))?, Path::new("../usr/share/zoneinfo/Europe/Berlin"));
+        assert_eq!(fs::read_to_string(etc.join("vconsole.conf"))?, "KEYMAP=de-latin1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_against_target() {
+        let root = fake_root();
+        let valid = LocaleConfig::default().with_timezone("Europe/Berlin").with_keymap("de-latin1");
+        assert!(valid.validate(root.path()).is_ok());
+        assert!(valid.clone().with_timezone("Mars/Olympus").validate(root.path()).is_err());
+        assert!(valid.clone().with_timezone("../../etc/passwd").validate(root.path()).is_err());
+        assert!(valid.clone().with_keymap("dvorak").validate(root.path()).is_err());
+        assert!(valid.with_locales(["xx_XX.UTF-8"]).validate(root.path()).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+//! Package mirrors and offline installation
+//!
+//! Before packages are installed, candidate mirrors are ranked by download
+//! speed and the fastest are written to the new system's mirrorlist. When
+//! no mirror is reachable, or when asked to, packages come from a local
+//! repository shipped on the installation media instead.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use crate::package::mirrors::{measure_mirrors, parse_mirrorlist, sort_by_rank, Mirror, MirrorOptions};
+
+/// Mirrorlist of the live system, used when the profile names no servers
+const HOST_MIRRORLIST: &str = "/etc/pacman.d/mirrorlist";
+
+/// Repositories installed from mirrors
+const REPOSITORIES: [&str; 2] = ["core", "extra"];
+
+/// Where packages are installed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceMode {
+    /// Mirrors if any is reachable, the local repository otherwise
+    #[default]
+    Auto,
+    /// Mirrors only
+    Online,
+    /// The local repository only
+    Offline,
+}
+
+/// Mirror ranking and local repository settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Where packages come from
+    pub mode: SourceMode,
+
+    /// Candidate servers (`https://host/archlinux/$repo/os/$arch`); the live
+    /// system's mirrorlist when empty
+    pub servers: Vec<String>,
+
+    /// Rank candidates by download speed
+    pub rank: bool,
+
+    /// Mirrors kept after ranking
+    pub keep: usize,
+
+    /// Per-mirror download timeout while ranking
+    pub timeout_secs: u64,
+
+    /// Local repository directory on the installation media
+    pub local_repo: PathBuf,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            mode: SourceMode::Auto,
+            servers: Vec::new(),
+            rank: true,
+            keep: 5,
+            timeout_secs: 5,
+            local_repo: PathBuf::from("/opt/rastos/repo"),
+        }
+    }
+}
+
+/// Resolved package source
+#[derive(Debug, Clone, PartialEq)]
+pub enum PackageSource {
+    /// Mirrors, fastest first
+    Mirrors(Vec<Mirror>),
+    /// Local repository
+    Local {
+        /// Repository name, from its database file
+        name: String,
+        /// Repository directory
+        path: PathBuf,
+    },
+}
+
+impl MirrorConfig {
+    /// Install from the given mode
+    pub fn with_mode(mut self, mode: SourceMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Use these candidate servers
+    pub fn with_servers<I, S>(mut self, servers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Name of the local repository, if the media carries one
+    pub fn local_repository(&self) -> Option<String> {
+        fs::read_dir(&self.local_repo)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().to_str().and_then(|n| n.strip_suffix(".db")).map(str::to_string))
+            .next()
+    }
+
+    /// Candidate servers: the configured ones or those enabled in the live system's mirrorlist
+    pub fn candidates(&self) -> Vec<Mirror> {
+        self.servers(false)
+    }
+
+    /// Servers worth ranking, including those commented out in the live system's mirrorlist
+    pub fn ranking_candidates(&self) -> Vec<Mirror> {
+        self.servers(true)
+    }
+
+    fn servers(&self, disabled: bool) -> Vec<Mirror> {
+        if !self.servers.is_empty() {
+            return self.servers.iter().map(Mirror::new).collect();
+        }
+        let content = fs::read_to_string(HOST_MIRRORLIST).unwrap_or_default();
+        if disabled {
+            parse_mirrorlist(&content)
+        } else {
+            parse_mirrorlist(&enabled_entries(&content))
+        }
+    }
+
+    /// Decide where packages come from
+    pub fn resolve(&self) -> Result<PackageSource, InstallerError> {
+        let local = || {
+            self.local_repository()
+                .map(|name| PackageSource::Local {
+                    name,
+                    path: self.local_repo.clone(),
+                })
+                .ok_or_else(|| {
+                    InstallerError::Unsupported(format!(
+                        "no local package repository in {}",
+                        self.local_repo.display()
+                    ))
+                })
+        };
+        if self.mode == SourceMode::Offline {
+            return local();
+        }
+
+        let mirrors = if self.rank {
+            self.rank(self.ranking_candidates())
+        } else {
+            self.candidates().into_iter().take(self.keep).collect()
+        };
+        match (mirrors.is_empty(), self.mode) {
+            (false, _) => Ok(PackageSource::Mirrors(mirrors)),
+            (true, SourceMode::Auto) => {
+                info!("No mirror is reachable, installing from local media");
+                local()
+            }
+            (true, _) => Err(InstallerError::Unsupported("no package mirror is reachable".to_string())),
+        }
+    }
+
+    /// Reachable candidates, fastest first, at most [`keep`](Self::keep)
+    pub fn rank(&self, mut candidates: Vec<Mirror>) -> Vec<Mirror> {
+        let options = MirrorOptions::default()
+            .with_timeout(Duration::from_secs(self.timeout_secs))
+            .with_max_mirrors(self.keep);
+        measure_mirrors(&mut candidates, &options);
+        sort_by_rank(&mut candidates);
+        candidates.truncate(options.max_mirrors);
+        candidates
+    }
+}
+
+impl PackageSource {
+    /// pacman configuration installing from this source
+    ///
+    /// `mirrorlist` is the file the mirror servers are written to.
+    pub fn pacman_conf(&self, mirrorlist: &Path) -> String {
+        let mut conf = String::from(
+            "# Generated by the rastOS installer\n\
+             [options]\n\
+             Architecture = auto\n\
+             SigLevel = Required DatabaseOptional\n\
+             LocalFileSigLevel = Optional\n\
+             ParallelDownloads = 5\n",
+        );
+        match self {
+            Self::Mirrors(_) => {
+                for repo in REPOSITORIES {
+                    conf.push_str(&format!("\n[{}]\nInclude = {}\n", repo, mirrorlist.display()));
+                }
+            }
+            Self::Local { name, path } => {
+                conf.push_str(&format!("\n[{}]\nServer = file://{}\n", name, path.display()));
+            }
+        }
+        conf
+    }
+}
+
+/// Lines of a mirrorlist that are not commented out
+fn enabled_entries(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_enabled_entries() {
+        let content = "## Germany\n#Server = https://a.example/archlinux/$repo/os/$arch\n\
+                       Server=https://b.example/$repo/os/$arch\n# comment\n";
+        let all: Vec<String> = parse_mirrorlist(content).into_iter().map(|m| m.url).collect();
+        assert_eq!(
+            all,
+            vec!["https://a.example/archlinux/$repo/os/$arch", "https://b.example/$repo/os/$arch"]
+        );
+        let enabled = parse_mirrorlist(&enabled_entries(content));
+        assert_eq!(enabled.len(), 1);
+        assert_eq!(enabled[0].url, "https://b.example/$repo/os/$arch");
+    }
+
+    #[test]
+    fn test_offline_source() -> Result<(), Box<dyn std::error::Error>> {
+        let repo = tempdir()?;
+        let config = MirrorConfig {
+            local_repo: repo.path().to_path_buf(),
+            ..MirrorConfig::default()
+        }
+        .with_mode(SourceMode::Offline);
+        assert!(config.resolve().is_err());
+
+        fs::write(repo.path().join("rastos-offline.db"), "")?;
+        let source = config.resolve()?;
+        let conf = source.pacman_conf(Path::new("/tmp/mirrorlist"));
+        assert!(conf.contains(&format!("[rastos-offline]\nServer = file://{}\n", repo.path().display())));
+        assert!(!conf.contains("[core]"));
+
+        let online = PackageSource::Mirrors(Vec::new()).pacman_conf(Path::new("/tmp/mirrorlist"));
+        assert!(online.contains("[extra]\nInclude = /tmp/mirrorlist\n"));
+        Ok(())
+    }
+}
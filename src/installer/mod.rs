@@ -1,18 +1,53 @@
 //! System installation module for rastOS
+//!
+//! The install profile and the pieces it describes (disk layout, accounts,
+//! encryption, ...) are always built, as the machine configuration reuses
+//! them; the installation engine, [`Installer`], needs the `installer`
+//! feature.
 
-/// Handles system installation process
-pub struct Installer {
-    // Implementation will be added later
-}
+pub mod accounts;
+pub mod checkpoint;
+pub mod detect;
+pub mod encryption;
+mod error;
+pub mod fstab;
+#[cfg(feature = "installer")]
+mod install;
+pub mod locale;
+pub mod mirrors;
+pub mod partition;
+pub mod preflight;
+pub mod profile;
+pub mod progress;
+pub mod provision;
+pub mod report;
+pub mod swap;
 
-impl Installer {
-    /// Create a new installer instance
-    pub fn new() -> Self {
-        Self {}
-    }
-}
+pub use accounts::{AccountsConfig, SudoPolicy, UserConfig};
+pub use checkpoint::Checkpoint;
+pub use detect::{DetectedOs, Detection, OsKind};
+pub use encryption::{EncryptionConfig, LuksVolume, Passphrase};
+pub use error::InstallerError;
+pub use fstab::{Crypttab, CrypttabEntry, Fstab, FstabEntry};
+#[cfg(feature = "installer")]
+pub use install::Installer;
+pub use locale::LocaleConfig;
+pub use mirrors::{MirrorConfig, PackageSource, SourceMode};
+pub use partition::{BtrfsRaid, DiskLayout, PartitionPlan, PartitionRole, PartitionSpec, PlannedPartition};
+pub use preflight::{BootMode, CheckKind, CheckStatus, Preflight, PreflightCheck, PreflightReport};
+pub use profile::InstallProfile;
+pub use progress::{InstallEvent, InstallPhase, PhaseReporter};
+pub use provision::{ProvisionConfig, ProvisionScript};
+pub use report::{CommandLog, CommandRecord, InstallLog, LogEntry};
+pub use swap::{SwapConfig, SwapKind, SwapSetup};
+
+/// Btrfs subvolume holding the installed system
+pub const ROOT_SUBVOLUME: &str = "@";
+
+/// Read-only snapshot of the freshly installed system, restored by a factory reset
+pub const FACTORY_SUBVOLUME: &str = "@factory";
 
-#[cfg(test)]
+#[cfg(all(test, feature = "installer"))]
 mod tests {
     use super::*;
 
@@ -0,0 +1,692 @@
+//! Disk partitioning
+//!
+//! Turns a declarative [`DiskLayout`] into a GPT [`PartitionPlan`] with
+//! aligned partition boundaries. The plan can be previewed before it is
+//! applied; applying refuses to touch disks that are in use, and disks that
+//! already carry a partition table or filesystem unless wiping was allowed.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::encryption::{EncryptionConfig, LuksVolume};
+use super::error::InstallerError;
+use super::report::CommandLog;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Size of the GPT partition entry array
+const GPT_ENTRIES_BYTES: u64 = 16384;
+
+/// Smallest ESP that can hold FAT32 on 4K-sector disks
+pub const MIN_ESP_MIB: u64 = 260;
+
+/// Purpose of a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionRole {
+    /// EFI system partition (FAT32)
+    Esp,
+    /// Swap space
+    Swap,
+    /// Btrfs root filesystem
+    Root,
+}
+
+impl PartitionRole {
+    /// sgdisk type code
+    pub fn type_code(&self) -> &'static str {
+        match self {
+            Self::Esp => "ef00",
+            Self::Swap => "8200",
+            Self::Root => "8304",
+        }
+    }
+
+    /// Filesystem created on the partition
+    pub fn filesystem(&self) -> &'static str {
+        match self {
+            Self::Esp => "vfat",
+            Self::Swap => "swap",
+            Self::Root => "btrfs",
+        }
+    }
+}
+
+/// One partition of a layout
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartitionSpec {
+    /// Purpose of the partition
+    pub role: PartitionRole,
+
+    /// GPT partition name and filesystem label
+    pub label: String,
+
+    /// Size in MiB; `None` takes the rest of the disk (last partition only)
+    #[serde(default)]
+    pub size_mib: Option<u64>,
+}
+
+/// Btrfs profile spreading the root filesystem over several devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BtrfsRaid {
+    /// Two copies of everything on different devices
+    Raid1,
+    /// Mirrored stripes
+    Raid10,
+}
+
+impl BtrfsRaid {
+    /// Profile name for `mkfs.btrfs --data`/`--metadata`
+    pub fn profile(&self) -> &'static str {
+        match self {
+            Self::Raid1 => "raid1",
+            Self::Raid10 => "raid10",
+        }
+    }
+
+    /// Devices the profile needs at least
+    pub fn min_devices(&self) -> usize {
+        match self {
+            Self::Raid1 => 2,
+            Self::Raid10 => 4,
+        }
+    }
+}
+
+/// Declarative description of a disk's partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskLayout {
+    /// Target disk
+    pub device: PathBuf,
+
+    /// Partitions in disk order
+    #[serde(rename = "partition")]
+    pub partitions: Vec<PartitionSpec>,
+
+    /// Partition boundary alignment in MiB
+    #[serde(default = "default_alignment")]
+    pub alignment_mib: u64,
+
+    /// Allow overwriting existing partition tables and filesystems
+    #[serde(default)]
+    pub wipe: bool,
+
+    /// Encrypt the root partition with LUKS2
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+
+    /// Combine the root partition with `members` into a Btrfs RAID
+    #[serde(default)]
+    pub raid: Option<BtrfsRaid>,
+
+    /// Further whole disks added to the root filesystem
+    #[serde(default)]
+    pub members: Vec<PathBuf>,
+}
+
+fn default_alignment() -> u64 {
+    1
+}
+
+impl DiskLayout {
+    /// ESP, optional swap and a Btrfs root filling the rest of the disk
+    pub fn standard<P: AsRef<Path>>(device: P, swap_mib: Option<u64>) -> Self {
+        let mut partitions = vec![PartitionSpec {
+            role: PartitionRole::Esp,
+            label: "ESP".to_string(),
+            size_mib: Some(512),
+        }];
+        if let Some(size) = swap_mib {
+            partitions.push(PartitionSpec {
+                role: PartitionRole::Swap,
+                label: "swap".to_string(),
+                size_mib: Some(size),
+            });
+        }
+        partitions.push(PartitionSpec {
+            role: PartitionRole::Root,
+            label: "rastos".to_string(),
+            size_mib: None,
+        });
+
+        Self {
+            device: device.as_ref().to_path_buf(),
+            partitions,
+            alignment_mib: default_alignment(),
+            wipe: false,
+            encryption: None,
+            raid: None,
+            members: Vec::new(),
+        }
+    }
+
+    /// Load a layout from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, InstallerError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| InstallerError::InvalidLayout(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Set partition alignment
+    pub fn with_alignment(mut self, alignment_mib: u64) -> Self {
+        self.alignment_mib = alignment_mib;
+        self
+    }
+
+    /// Allow or forbid wiping existing data
+    pub fn with_wipe(mut self, wipe: bool) -> Self {
+        self.wipe = wipe;
+        self
+    }
+
+    /// Encrypt the root partition
+    pub fn with_encryption(mut self, encryption: EncryptionConfig) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Spread the root filesystem over the root partition and `members`
+    pub fn with_raid<I, P>(mut self, raid: BtrfsRaid, members: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.raid = Some(raid);
+        self.members = members.into_iter().map(|m| m.as_ref().to_path_buf()).collect();
+        self
+    }
+
+    /// Check the layout for structural errors
+    pub fn validate(&self) -> Result<(), InstallerError> {
+        self.validate_raid()?;
+        let count = |role| self.partitions.iter().filter(|p| p.role == role).count();
+        if count(PartitionRole::Esp) != 1 {
+            return Err(InstallerError::InvalidLayout("exactly one ESP is required".to_string()));
+        }
+        if count(PartitionRole::Root) != 1 {
+            return Err(InstallerError::InvalidLayout("exactly one root partition is required".to_string()));
+        }
+        if count(PartitionRole::Swap) > 1 {
+            return Err(InstallerError::InvalidLayout("at most one swap partition is allowed".to_string()));
+        }
+        if !self.alignment_mib.is_power_of_two() {
+            return Err(InstallerError::InvalidLayout(format!(
+                "alignment of {} MiB is not a power of two",
+                self.alignment_mib
+            )));
+        }
+
+        let last = self.partitions.len() - 1;
+        for (index, partition) in self.partitions.iter().enumerate() {
+            match partition.size_mib {
+                None if index != last => {
+                    return Err(InstallerError::InvalidLayout(format!(
+                        "only the last partition may fill the disk, not {}",
+                        partition.label
+                    )));
+                }
+                Some(0) => {
+                    return Err(InstallerError::InvalidLayout(format!("{} has no size", partition.label)));
+                }
+                Some(size) if partition.role == PartitionRole::Esp && size < MIN_ESP_MIB => {
+                    return Err(InstallerError::InvalidLayout(format!(
+                        "ESP must be at least {} MiB",
+                        MIN_ESP_MIB
+                    )));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_raid(&self) -> Result<(), InstallerError> {
+        let Some(raid) = self.raid else {
+            if !self.members.is_empty() {
+                return Err(InstallerError::InvalidLayout("member disks need a RAID profile".to_string()));
+            }
+            return Ok(());
+        };
+        let devices = self.members.len() + 1;
+        if devices < raid.min_devices() {
+            return Err(InstallerError::InvalidLayout(format!(
+                "{} needs at least {} devices, the layout has {}",
+                raid.profile(),
+                raid.min_devices(),
+                devices
+            )));
+        }
+        for (index, member) in self.members.iter().enumerate() {
+            if *member == self.device || self.members[..index].contains(member) {
+                return Err(InstallerError::InvalidLayout(format!(
+                    "{} is used more than once",
+                    member.display()
+                )));
+            }
+        }
+        if self.encryption.is_some() {
+            return Err(InstallerError::Unsupported(
+                "encrypted multi-device roots would need every member unlocked in the initramfs".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lay the partitions out on a disk of the given geometry
+    pub fn plan_for(&self, disk_bytes: u64, sector_size: u64) -> Result<PartitionPlan, InstallerError> {
+        self.validate()?;
+
+        let sectors = disk_bytes / sector_size;
+        let align = (self.alignment_mib * MIB / sector_size).max(1);
+        let gpt_sectors = GPT_ENTRIES_BYTES / sector_size;
+        // Protective MBR and primary header up front, backup header at the end
+        let first_usable = 2 + gpt_sectors;
+        let last_usable = sectors
+            .checked_sub(2 + gpt_sectors)
+            .ok_or_else(|| InstallerError::InvalidLayout("disk is too small for a GPT".to_string()))?;
+
+        let mut partitions = Vec::new();
+        let mut next = first_usable;
+        for (index, spec) in self.partitions.iter().enumerate() {
+            let start = next.div_ceil(align) * align;
+            let end = match spec.size_mib {
+                Some(size) => start + size * MIB / sector_size - 1,
+                // Keep the end aligned so the partition holds whole alignment units
+                None => ((last_usable + 1) / align * align).saturating_sub(1),
+            };
+            if end > last_usable || end < start {
+                return Err(InstallerError::InvalidLayout(format!(
+                    "{} does not fit on a {} MiB disk",
+                    spec.label,
+                    disk_bytes / MIB
+                )));
+            }
+
+            partitions.push(PlannedPartition {
+                number: index as u32 + 1,
+                role: spec.role,
+                label: spec.label.clone(),
+                start,
+                end,
+                path: partition_path(&self.device, index as u32 + 1),
+            });
+            next = end + 1;
+        }
+
+        Ok(PartitionPlan {
+            device: self.device.clone(),
+            disk_bytes,
+            sector_size,
+            partitions,
+            wipe: self.wipe,
+            encryption: self.encryption.clone(),
+            raid: self.raid,
+            members: self.members.clone(),
+        })
+    }
+
+    /// Probe the disk and plan the layout on it
+    pub fn plan(&self) -> Result<PartitionPlan, InstallerError> {
+        let (disk_bytes, sector_size) = disk_geometry(&self.device)?;
+        self.plan_for(disk_bytes, sector_size)
+    }
+}
+
+/// A partition with its final position on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedPartition {
+    /// GPT partition number
+    pub number: u32,
+    /// Purpose of the partition
+    pub role: PartitionRole,
+    /// Partition name and filesystem label
+    pub label: String,
+    /// First sector
+    pub start: u64,
+    /// Last sector (inclusive)
+    pub end: u64,
+    /// Partition device node
+    pub path: PathBuf,
+}
+
+impl PlannedPartition {
+    /// Size in sectors
+    pub fn sectors(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Partitioning to be written to a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionPlan {
+    /// Target disk
+    pub device: PathBuf,
+    /// Disk size in bytes
+    pub disk_bytes: u64,
+    /// Logical sector size
+    pub sector_size: u64,
+    /// Partitions in disk order
+    pub partitions: Vec<PlannedPartition>,
+    /// Whether existing data may be overwritten
+    pub wipe: bool,
+    /// Root encryption, if any
+    pub encryption: Option<EncryptionConfig>,
+    /// Btrfs RAID profile of the root filesystem, if any
+    pub raid: Option<BtrfsRaid>,
+    /// Whole disks joining the root filesystem
+    pub members: Vec<PathBuf>,
+}
+
+impl fmt::Display for PartitionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} MiB, {}-byte sectors, GPT",
+            self.device.display(),
+            self.disk_bytes / MIB,
+            self.sector_size
+        )?;
+        for p in &self.partitions {
+            writeln!(
+                f,
+                "  {:<16} {:>12} {:>12} {:>10} MiB  {:<6} {}",
+                p.path.display(),
+                p.start,
+                p.end,
+                p.sectors() * self.sector_size / MIB,
+                p.role.filesystem(),
+                p.label
+            )?;
+        }
+        if self.encryption.is_some() {
+            writeln!(f, "  root encrypted with LUKS2")?;
+        }
+        if let Some(raid) = self.raid {
+            let members: Vec<String> = self.members.iter().map(|m| m.display().to_string()).collect();
+            writeln!(f, "  root {} with {}", raid.profile(), members.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl PartitionPlan {
+    /// Partition with the given role
+    pub fn partition(&self, role: PartitionRole) -> Option<&PlannedPartition> {
+        self.partitions.iter().find(|p| p.role == role)
+    }
+
+    /// The partitioned disk followed by the RAID member disks
+    pub fn disks(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.device.as_path()).chain(self.members.iter().map(PathBuf::as_path))
+    }
+
+    /// Write the partition table and create the filesystems
+    ///
+    /// With encryption, the root filesystem is created inside a LUKS2
+    /// container, which is left open and returned.
+    pub fn apply(&self, log: &CommandLog) -> Result<Option<LuksVolume>, InstallerError> {
+        self.write_table(log)?;
+        self.format(log)
+    }
+
+    /// Write the partition table
+    ///
+    /// RAID member disks are only cleared; the root filesystem spans them whole.
+    pub fn write_table(&self, log: &CommandLog) -> Result<(), InstallerError> {
+        let disks: Vec<&Path> = self.disks().collect();
+        // Check every disk before touching any of them
+        let mut wipe = Vec::new();
+        for disk in &disks {
+            if disk_in_use(disk)? {
+                return Err(InstallerError::DiskInUse(disk.to_path_buf()));
+            }
+            let signatures = existing_signatures(disk)?;
+            if !signatures.is_empty() {
+                if !self.wipe {
+                    return Err(InstallerError::WipeProtected {
+                        device: disk.to_path_buf(),
+                        signatures,
+                    });
+                }
+                wipe.push((*disk, signatures));
+            }
+        }
+        for (disk, signatures) in wipe {
+            info!("Wiping {} ({})", disk.display(), signatures.join(", "));
+            run(Command::new("wipefs").arg("--all").arg(disk), log)?;
+        }
+
+        run(Command::new("sgdisk").arg("--zap-all").arg(&self.device), log)?;
+        for p in &self.partitions {
+            let new = format!("{}:{}:{}", p.number, p.start, p.end);
+            let typecode = format!("{}:{}", p.number, p.role.type_code());
+            let name = format!("{}:{}", p.number, p.label);
+            run(
+                Command::new("sgdisk")
+                    .args(["--new", &new, "--typecode", &typecode, "--change-name", &name])
+                    .arg(&self.device),
+                log,
+            )?;
+        }
+        run(Command::new("partprobe").arg(&self.device), log)?;
+        run(Command::new("udevadm").arg("settle"), log)?;
+        Ok(())
+    }
+
+    /// Create the filesystems on the written partitions
+    ///
+    /// Returns the opened LUKS container when the root is encrypted.
+    pub fn format(&self, log: &CommandLog) -> Result<Option<LuksVolume>, InstallerError> {
+        let mut luks = None;
+        for p in &self.partitions {
+            let mut target = p.path.clone();
+            if let (PartitionRole::Root, Some(encryption)) = (p.role, &self.encryption) {
+                let volume = encryption.format(&p.path, log)?;
+                target = volume.open(encryption, log)?;
+                luks = Some(volume);
+            }
+
+            info!("Creating {} on {}", p.role.filesystem(), target.display());
+            let mut command = match p.role {
+                PartitionRole::Esp => {
+                    let mut command = Command::new("mkfs.fat");
+                    command.args(["-F", "32", "-n", &p.label]);
+                    command
+                }
+                PartitionRole::Swap => {
+                    let mut command = Command::new("mkswap");
+                    command.args(["-L", &p.label]);
+                    command
+                }
+                PartitionRole::Root => {
+                    let mut command = Command::new("mkfs.btrfs");
+                    command.args(["-f", "-L", &p.label]);
+                    if let Some(raid) = self.raid {
+                        command.args(["--data", raid.profile(), "--metadata", raid.profile()]);
+                    }
+                    command
+                }
+            };
+            command.arg(&target);
+            if p.role == PartitionRole::Root {
+                command.args(&self.members);
+            }
+            run(&mut command, log)?;
+        }
+        if self.raid.is_some() {
+            // Make the kernel aware of all members before the first mount
+            run(Command::new("btrfs").args(["device", "scan"]), log)?;
+        }
+        Ok(luks)
+    }
+}
+
+/// Device node of partition `number` (`sda1`, `nvme0n1p1`, `mmcblk0p1`)
+pub fn partition_path(device: &Path, number: u32) -> PathBuf {
+    let name = device.to_string_lossy();
+    if name.ends_with(|c: char| c.is_ascii_digit()) {
+        PathBuf::from(format!("{}p{}", name, number))
+    } else {
+        PathBuf::from(format!("{}{}", name, number))
+    }
+}
+
+/// Disk size in bytes and logical sector size
+pub(super) fn disk_geometry(device: &Path) -> Result<(u64, u64), InstallerError> {
+    let output = lsblk(device, &["--bytes", "--nodeps", "--output", "SIZE,LOG-SEC"])?;
+    let mut fields = output.split_whitespace().map(str::parse::<u64>);
+    match (fields.next(), fields.next()) {
+        (Some(Ok(size)), Some(Ok(sector))) if sector > 0 => Ok((size, sector)),
+        _ => Err(InstallerError::InvalidLayout(format!(
+            "could not read the geometry of {}",
+            device.display()
+        ))),
+    }
+}
+
+/// Whether any partition of the disk is mounted or used as swap
+pub(super) fn disk_in_use(device: &Path) -> Result<bool, InstallerError> {
+    let output = lsblk(device, &["--output", "MOUNTPOINTS"])?;
+    Ok(output.lines().any(|line| !line.trim().is_empty()))
+}
+
+/// Partition tables and filesystems found on the disk
+fn existing_signatures(device: &Path) -> Result<Vec<String>, InstallerError> {
+    let output = lsblk(device, &["--output", "NAME,PTTYPE,FSTYPE"])?;
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            let types: Vec<&str> = fields.collect();
+            (!types.is_empty()).then(|| format!("{}: {}", name, types.join("/")))
+        })
+        .collect())
+}
+
+fn lsblk(device: &Path, args: &[&str]) -> Result<String, InstallerError> {
+    let output = Command::new("lsblk")
+        .args(["--noheadings", "--raw"])
+        .args(args)
+        .arg(device)
+        .output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("lsblk", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run a command, recording it in `log`
+pub(super) fn run(command: &mut Command, log: &CommandLog) -> Result<(), InstallerError> {
+    debug!("Running: {:?}", command);
+    let output = command.output()?;
+    log.record(command, &output);
+    if !output.status.success() {
+        return Err(InstallerError::command_error(command.get_program().to_string_lossy(), &output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * MIB;
+
+    #[test]
+    fn test_standard_plan() {
+        let layout = DiskLayout::standard("/dev/nvme0n1", Some(8192));
+        let plan = layout.plan_for(100 * GIB, 512).unwrap();
+
+        assert_eq!(plan.partitions.len(), 3);
+        let esp = plan.partition(PartitionRole::Esp).unwrap();
+        assert_eq!(esp.start, 2048);
+        assert_eq!(esp.sectors(), 512 * MIB / 512);
+        assert_eq!(esp.path, PathBuf::from("/dev/nvme0n1p1"));
+
+        let swap = plan.partition(PartitionRole::Swap).unwrap();
+        assert_eq!(swap.start, esp.end + 1);
+        let root = plan.partition(PartitionRole::Root).unwrap();
+        assert_eq!(root.start % 2048, 0);
+        assert_eq!((root.end + 1) % 2048, 0);
+        assert!(root.end < 100 * GIB / 512 - 33);
+    }
+
+    #[test]
+    fn test_alignment_4k_sectors() {
+        let plan = DiskLayout::standard("/dev/sda", None)
+            .with_alignment(4)
+            .plan_for(20 * GIB, 4096)
+            .unwrap();
+        let align = 4 * MIB / 4096;
+        for partition in &plan.partitions {
+            assert_eq!(partition.start % align, 0);
+        }
+        assert_eq!(plan.partitions[1].path, PathBuf::from("/dev/sda2"));
+    }
+
+    #[test]
+    fn test_invalid_layouts() {
+        let mut layout = DiskLayout::standard("/dev/sda", Some(4096));
+        layout.partitions.swap(1, 2);
+        assert!(layout.validate().is_err());
+
+        let mut layout = DiskLayout::standard("/dev/sda", None);
+        layout.partitions[0].size_mib = Some(100);
+        assert!(layout.validate().is_err());
+
+        assert!(DiskLayout::standard("/dev/sda", None).with_alignment(3).validate().is_err());
+        assert!(DiskLayout::standard("/dev/sda", Some(64 * 1024)).plan_for(32 * GIB, 512).is_err());
+    }
+
+    #[test]
+    fn test_layout_from_toml() {
+        let layout: DiskLayout = toml::from_str(
+            r#"
+            device = "/dev/vda"
+            wipe = true
+
+            [[partition]]
+            role = "esp"
+            label = "ESP"
+            size_mib = 1024
+
+            [[partition]]
+            role = "root"
+            label = "rastos"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(layout.alignment_mib, 1);
+        assert!(layout.wipe);
+        assert!(layout.validate().is_ok());
+    }
+
+    #[test]
+    fn test_raid_layouts() {
+        let layout = DiskLayout::standard("/dev/sda", None).with_raid(BtrfsRaid::Raid1, ["/dev/sdb"]);
+        assert!(layout.validate().is_ok());
+        let plan = layout.plan_for(100 * GIB, 512).unwrap();
+        assert!(plan.to_string().contains("root raid1 with /dev/sdb\n"));
+
+        assert!(DiskLayout::standard("/dev/sda", None)
+            .with_raid(BtrfsRaid::Raid10, ["/dev/sdb", "/dev/sdc"])
+            .validate()
+            .is_err());
+        assert!(DiskLayout::standard("/dev/sda", None)
+            .with_raid(BtrfsRaid::Raid1, ["/dev/sda"])
+            .validate()
+            .is_err());
+
+        let mut orphan = DiskLayout::standard("/dev/sda", None);
+        orphan.members.push(PathBuf::from("/dev/sdb"));
+        assert!(orphan.validate().is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Preflight checks
+//!
+//! Inspects the machine before anything destructive happens: boot mode,
+//! memory, target disk size and busy state, network reachability, and TPM
+//! and Secure Boot status. The result is a typed [`PreflightReport`] that
+//! front ends can show as-is, along with the hardware inventory; failed
+//! checks stop the installation.
+
+use std::fmt;
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::mirrors::SourceMode;
+use super::partition::{disk_geometry, disk_in_use};
+use super::profile::InstallProfile;
+use crate::system::hardware::{HardwareInventory, MemoryInfo};
+
+/// UEFI global variable GUID
+const EFI_GLOBAL_GUID: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Firmware boot mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootMode {
+    /// UEFI firmware
+    Uefi,
+    /// Legacy BIOS
+    Bios,
+}
+
+/// What a check looked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckKind {
+    /// UEFI vs BIOS
+    BootMode,
+    /// Installed memory
+    Memory,
+    /// Target disk size
+    DiskSize,
+    /// Target disk mounted or used as swap
+    DiskBusy,
+    /// Package mirror or registry reachable
+    Network,
+    /// TPM presence
+    Tpm,
+    /// Secure Boot state
+    SecureBoot,
+}
+
+/// Outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    /// Requirement met
+    Pass,
+    /// Installation can continue, but the user should know
+    Warn,
+    /// Installation cannot continue
+    Fail,
+}
+
+/// One check and its outcome
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreflightCheck {
+    /// What was checked
+    pub kind: CheckKind,
+    /// Outcome
+    pub status: CheckStatus,
+    /// Human readable detail
+    pub detail: String,
+}
+
+impl fmt::Display for PreflightCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:?}: {}", self.status, self.kind, self.detail)
+    }
+}
+
+/// Result of all preflight checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreflightReport {
+    /// Firmware boot mode
+    pub boot_mode: BootMode,
+    /// Whether a TPM 2.0 is present
+    pub tpm2: bool,
+    /// Secure Boot state, if the firmware reports it
+    pub secure_boot: Option<bool>,
+    /// The machine's hardware, if it could be read
+    pub hardware: Option<HardwareInventory>,
+    /// Individual checks in the order they ran
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Whether the installation may proceed
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &PreflightCheck> {
+        self.checks.iter().filter(|c| c.status == CheckStatus::Fail)
+    }
+
+    /// Checks that passed with a warning
+    pub fn warnings(&self) -> impl Iterator<Item = &PreflightCheck> {
+        self.checks.iter().filter(|c| c.status == CheckStatus::Warn)
+    }
+
+    fn push<S: Into<String>>(&mut self, kind: CheckKind, status: CheckStatus, detail: S) {
+        self.checks.push(PreflightCheck {
+            kind,
+            status,
+            detail: detail.into(),
+        });
+    }
+}
+
+/// Thresholds and endpoints for the checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preflight {
+    /// Minimum installed memory in MiB
+    pub min_memory_mib: u64,
+    /// Minimum target disk size in GiB
+    pub min_disk_gib: u64,
+    /// `host:port` that must be reachable
+    pub network_endpoint: String,
+    /// Connection timeout for the network check
+    pub network_timeout_secs: u64,
+}
+
+impl Default for Preflight {
+    fn default() -> Self {
+        Self {
+            min_memory_mib: 2048,
+            min_disk_gib: 20,
+            network_endpoint: "geo.mirror.pkgbuild.com:443".to_string(),
+            network_timeout_secs: 5,
+        }
+    }
+}
+
+impl Preflight {
+    /// Run every check for `profile`
+    pub fn run(&self, profile: &InstallProfile) -> PreflightReport {
+        let boot_mode = if Path::new("/sys/firmware/efi").exists() {
+            BootMode::Uefi
+        } else {
+            BootMode::Bios
+        };
+        let tpm2 = fs::read_to_string("/sys/class/tpm/tpm0/tpm_version_major").is_ok_and(|v| v.trim() == "2");
+        let secure_boot = fs::read(Path::new("/sys/firmware/efi/efivars").join(format!("SecureBoot-{}", EFI_GLOBAL_GUID)))
+            .ok()
+            .and_then(|data| secure_boot_enabled(&data));
+
+        let hardware = HardwareInventory::collect().ok();
+        let memory_kib = hardware.as_ref().map(|h| h.memory.total_kib);
+
+        let mut report = PreflightReport {
+            boot_mode,
+            tpm2,
+            secure_boot,
+            hardware,
+            checks: Vec::new(),
+        };
+
+        match boot_mode {
+            BootMode::Uefi => report.push(CheckKind::BootMode, CheckStatus::Pass, "booted in UEFI mode"),
+            BootMode::Bios => report.push(
+                CheckKind::BootMode,
+                CheckStatus::Fail,
+                "booted in legacy BIOS mode; rastOS requires UEFI",
+            ),
+        }
+
+        match memory_kib {
+            Some(kib) => {
+                let mib = kib / 1024;
+                let status = if mib >= self.min_memory_mib { CheckStatus::Pass } else { CheckStatus::Fail };
+                report.push(
+                    CheckKind::Memory,
+                    status,
+                    format!("{} MiB installed, {} MiB required", mib, self.min_memory_mib),
+                );
+            }
+            None => report.push(CheckKind::Memory, CheckStatus::Warn, "could not read /proc/meminfo"),
+        }
+
+        self.check_disk(profile, &mut report);
+        self.check_network(profile, &mut report);
+
+        let wants_tpm = profile
+            .disk
+            .as_ref()
+            .and_then(|d| d.encryption.as_ref())
+            .is_some_and(|e| e.tpm2);
+        match (tpm2, wants_tpm) {
+            (true, _) => report.push(CheckKind::Tpm, CheckStatus::Pass, "TPM 2.0 present"),
+            (false, true) => report.push(
+                CheckKind::Tpm,
+                CheckStatus::Fail,
+                "TPM2 auto-unlock requested but no TPM 2.0 was found",
+            ),
+            (false, false) => report.push(CheckKind::Tpm, CheckStatus::Pass, "no TPM 2.0 (not required)"),
+        }
+
+        match secure_boot {
+            Some(true) => report.push(
+                CheckKind::SecureBoot,
+                CheckStatus::Warn,
+                "Secure Boot is enabled; the installed bootloader must be signed to boot",
+            ),
+            Some(false) => report.push(CheckKind::SecureBoot, CheckStatus::Pass, "Secure Boot is disabled"),
+            None => report.push(CheckKind::SecureBoot, CheckStatus::Pass, "Secure Boot state not reported"),
+        }
+        report
+    }
+
+    fn check_disk(&self, profile: &InstallProfile, report: &mut PreflightReport) {
+        let Some(disk) = &profile.disk else {
+            report.push(
+                CheckKind::DiskSize,
+                CheckStatus::Warn,
+                "no disk layout; installing into the mounted target",
+            );
+            return;
+        };
+        let device = disk.device.display();
+
+        match disk_geometry(&disk.device) {
+            Ok((bytes, _)) => {
+                let gib = bytes / (1024 * 1024 * 1024);
+                let status = if gib >= self.min_disk_gib { CheckStatus::Pass } else { CheckStatus::Fail };
+                report.push(
+                    CheckKind::DiskSize,
+                    status,
+                    format!("{} has {} GiB, {} GiB required", device, gib, self.min_disk_gib),
+                );
+            }
+            Err(e) => report.push(CheckKind::DiskSize, CheckStatus::Fail, format!("{}: {}", device, e)),
+        }
+
+        for disk in std::iter::once(&disk.device).chain(&disk.members) {
+            let device = disk.display();
+            match disk_in_use(disk) {
+                Ok(false) => report.push(CheckKind::DiskBusy, CheckStatus::Pass, format!("{} is not in use", device)),
+                Ok(true) => report.push(
+                    CheckKind::DiskBusy,
+                    CheckStatus::Fail,
+                    format!("{} has mounted partitions or active swap", device),
+                ),
+                Err(e) => report.push(CheckKind::DiskBusy, CheckStatus::Fail, format!("{}: {}", device, e)),
+            }
+        }
+    }
+
+    fn check_network(&self, profile: &InstallProfile, report: &mut PreflightReport) {
+        let offline = profile.image.is_none() && profile.mirrors.local_repository().is_some();
+        if offline && profile.mirrors.mode == SourceMode::Offline {
+            report.push(CheckKind::Network, CheckStatus::Pass, "offline install, no network needed");
+            return;
+        }
+        let timeout = Duration::from_secs(self.network_timeout_secs);
+        let reachable = self
+            .network_endpoint
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok()))
+            .unwrap_or(false);
+        if reachable {
+            report.push(CheckKind::Network, CheckStatus::Pass, format!("{} is reachable", self.network_endpoint));
+        } else if offline && profile.mirrors.mode == SourceMode::Auto {
+            report.push(
+                CheckKind::Network,
+                CheckStatus::Warn,
+                format!("{} is not reachable; installing from local media", self.network_endpoint),
+            );
+        } else {
+            report.push(
+                CheckKind::Network,
+                CheckStatus::Fail,
+                format!("{} is not reachable", self.network_endpoint),
+            );
+        }
+    }
+}
+
+/// `MemTotal` from `/proc/meminfo`, in KiB
+pub(super) fn mem_total_kib(meminfo: &str) -> Option<u64> {
+    MemoryInfo::parse(meminfo).map(|memory| memory.total_kib)
+}
+
+/// Secure Boot state from the `SecureBoot` efivar (4 attribute bytes, then the value)
+fn secure_boot_enabled(data: &[u8]) -> Option<bool> {
+    data.get(4).map(|value| *value == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_firmware_data() {
+        assert_eq!(mem_total_kib("MemTotal:       16318480 kB\nMemFree: 1 kB\n"), Some(16318480));
+        assert_eq!(mem_total_kib("MemFree: 1 kB\n"), None);
+        assert_eq!(secure_boot_enabled(&[0x06, 0, 0, 0, 1]), Some(true));
+        assert_eq!(secure_boot_enabled(&[0x06, 0, 0, 0, 0]), Some(false));
+        assert_eq!(secure_boot_enabled(&[0x06]), None);
+    }
+
+    #[test]
+    fn test_report_outcome() {
+        let mut report = PreflightReport {
+            boot_mode: BootMode::Uefi,
+            tpm2: false,
+            secure_boot: None,
+            hardware: None,
+            checks: Vec::new(),
+        };
+        report.push(CheckKind::BootMode, CheckStatus::Pass, "uefi");
+        report.push(CheckKind::SecureBoot, CheckStatus::Warn, "enabled");
+        assert!(report.passed());
+        assert_eq!(report.warnings().count(), 1);
+
+        report.push(CheckKind::Memory, CheckStatus::Fail, "512 MiB installed");
+        assert!(!report.passed());
+        assert_eq!(report.failures().next().unwrap().kind, CheckKind::Memory);
+    }
+}
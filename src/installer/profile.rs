@@ -0,0 +1,135 @@
+//! Install profiles
+//!
+//! An install profile is a TOML description of everything the installer
+//! needs to know: the disk layout and swap, the accounts to create, the
+//! locale and the packages and bootloader to install. Secrets such as passwords may be given in the profile but are never
+//! written back out.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::accounts::AccountsConfig;
+use super::error::InstallerError;
+use super::locale::LocaleConfig;
+use super::mirrors::MirrorConfig;
+use super::partition::DiskLayout;
+use super::preflight::Preflight;
+use super::provision::ProvisionConfig;
+use super::swap::SwapConfig;
+use crate::kernel::{Bootloader, InitramfsGenerator};
+
+/// Declarative description of an installation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstallProfile {
+    /// Target disk layout
+    pub disk: Option<DiskLayout>,
+
+    /// Users, sudo and root account
+    pub accounts: AccountsConfig,
+
+    /// Locale, timezone and console
+    pub locale: LocaleConfig,
+
+    /// Swap and hibernation
+    pub swap: SwapConfig,
+
+    /// Requirements checked before anything is written
+    pub preflight: Preflight,
+
+    /// Add boot entries chain-loading other detected systems
+    pub dual_boot: bool,
+
+    /// Bootloader to install
+    pub bootloader: Bootloader,
+
+    /// Initramfs generator of the installed system
+    pub initramfs: InitramfsGenerator,
+
+    /// Packages installed into the new system
+    pub packages: Vec<String>,
+
+    /// Package mirrors and offline installation
+    pub mirrors: MirrorConfig,
+
+    /// OCI image to unpack as the root filesystem instead of installing packages
+    pub image: Option<String>,
+
+    /// Post-install hooks and first-boot tasks
+    pub provision: ProvisionConfig,
+}
+
+impl Default for InstallProfile {
+    fn default() -> Self {
+        Self {
+            disk: None,
+            accounts: AccountsConfig::default(),
+            locale: LocaleConfig::default(),
+            swap: SwapConfig::default(),
+            preflight: Preflight::default(),
+            dual_boot: false,
+            bootloader: Bootloader::default(),
+            initramfs: InitramfsGenerator::default(),
+            packages: ["base", "linux", "linux-firmware", "btrfs-progs", "sudo"]
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            mirrors: MirrorConfig::default(),
+            image: None,
+            provision: ProvisionConfig::default(),
+        }
+    }
+}
+
+impl InstallProfile {
+    /// Load a profile from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, InstallerError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| InstallerError::InvalidProfile(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Check every section of the profile
+    pub fn validate(&self) -> Result<(), InstallerError> {
+        self.swap.validate(self.disk.as_ref())?;
+        if let Some(disk) = &self.disk {
+            disk.validate()?;
+            if let Some(encryption) = &disk.encryption {
+                encryption.validate(self.initramfs)?;
+            }
+        }
+        self.provision.validate()?;
+        self.accounts.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile: InstallProfile = toml::from_str(
+            r#"
+            [accounts]
+            sudo = "no-password"
+
+            [[accounts.user]]
+            name = "alice"
+            password = "hunter2"
+            groups = ["wheel", "video"]
+            "#,
+        )
+        .unwrap();
+        assert!(profile.disk.is_none());
+        assert!(profile.accounts.lock_root);
+        assert_eq!(profile.accounts.users[0].groups, vec!["wheel", "video"]);
+        assert!(profile.validate().is_ok());
+
+        let serialized = toml::to_string(&profile).unwrap();
+        assert!(!serialized.contains("hunter2"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Installer phases and progress events
+//!
+//! The installer runs as a fixed sequence of [`InstallPhase`]s and reports
+//! everything it does as [`InstallEvent`]s on a broadcast channel. The TUI,
+//! the log and remote front ends subscribe to the same stream; events are
+//! serializable so they can be forwarded as JSON. Every event is also
+//! reported to the installer's [`Progress`] sink, one task per phase.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::report::InstallLog;
+use crate::progress::{Progress, ProgressEvent};
+
+/// Events buffered for slow subscribers before they start lagging
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Step of the installation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallPhase {
+    /// Check the machine and target disk
+    Preflight,
+    /// Write the partition table
+    Partition,
+    /// Create filesystems and encryption
+    Format,
+    /// Install the base system
+    Bootstrap,
+    /// Configure locale, accounts and boot-time unlocking
+    Configure,
+    /// Install the bootloader and boot entries
+    Bootloader,
+    /// Run post-install hooks and set up first-boot tasks
+    #[serde(rename = "post-install")]
+    PostInstall,
+}
+
+impl InstallPhase {
+    /// All phases in order
+    pub const ALL: [InstallPhase; 7] = [
+        Self::Preflight,
+        Self::Partition,
+        Self::Format,
+        Self::Bootstrap,
+        Self::Configure,
+        Self::Bootloader,
+        Self::PostInstall,
+    ];
+
+    /// 1-based position of the phase
+    pub fn number(&self) -> usize {
+        Self::ALL.iter().position(|p| p == self).unwrap_or_default() + 1
+    }
+}
+
+impl fmt::Display for InstallPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Preflight => "preflight",
+            Self::Partition => "partition",
+            Self::Format => "format",
+            Self::Bootstrap => "bootstrap",
+            Self::Configure => "configure",
+            Self::Bootloader => "bootloader",
+            Self::PostInstall => "post-install",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something that happened during the installation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum InstallEvent {
+    /// A phase started
+    PhaseStarted {
+        /// Phase
+        phase: InstallPhase,
+    },
+    /// A phase has nothing to do
+    PhaseSkipped {
+        /// Phase
+        phase: InstallPhase,
+        /// Why it was skipped
+        reason: String,
+    },
+    /// Progress within a phase
+    Progress {
+        /// Phase
+        phase: InstallPhase,
+        /// What is being done
+        message: String,
+        /// Completion of the phase, if known
+        percent: Option<u8>,
+    },
+    /// Something the user should know about
+    Warning {
+        /// Warning text
+        message: String,
+    },
+    /// A phase finished
+    PhaseFinished {
+        /// Phase
+        phase: InstallPhase,
+    },
+    /// A phase failed; the installation stops
+    Failed {
+        /// Phase
+        phase: InstallPhase,
+        /// Error description
+        error: String,
+    },
+    /// The installation completed
+    Finished,
+    /// A failure report bundle was written
+    ReportWritten {
+        /// Path of the bundle
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for InstallEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = InstallPhase::ALL.len();
+        match self {
+            Self::PhaseStarted { phase } => write!(f, "[{}/{}] {}", phase.number(), total, phase),
+            Self::PhaseSkipped { phase, reason } => write!(f, "[{}/{}] {} skipped: {}", phase.number(), total, phase, reason),
+            Self::Progress { phase, message, percent: Some(percent) } => write!(f, "{}: {} ({}%)", phase, message, percent),
+            Self::Progress { phase, message, percent: None } => write!(f, "{}: {}", phase, message),
+            Self::Warning { message } => write!(f, "warning: {}", message),
+            Self::PhaseFinished { phase } => write!(f, "{} done", phase),
+            Self::Failed { phase, error } => write!(f, "{} failed: {}", phase, error),
+            Self::Finished => write!(f, "installation finished"),
+            Self::ReportWritten { path } => write!(f, "failure report written to {}", path.display()),
+        }
+    }
+}
+
+impl InstallEvent {
+    /// The event as reported to a [`Progress`] sink
+    ///
+    /// Each phase is a task counting percent; events outside of a phase are
+    /// messages of the `install` task.
+    pub fn to_progress(&self) -> ProgressEvent {
+        match self {
+            Self::PhaseStarted { phase } => ProgressEvent::Started {
+                task: phase.to_string(),
+                total: Some(100),
+            },
+            Self::Progress {
+                phase,
+                message,
+                percent: Some(percent),
+            } => ProgressEvent::Advanced {
+                task: phase.to_string(),
+                done: u64::from(*percent),
+                total: Some(100),
+                message: Some(message.clone()),
+            },
+            Self::Progress {
+                phase,
+                message,
+                percent: None,
+            } => ProgressEvent::Message {
+                task: phase.to_string(),
+                message: message.clone(),
+            },
+            Self::PhaseFinished { phase } => ProgressEvent::Finished {
+                task: phase.to_string(),
+                message: None,
+            },
+            Self::Failed { phase, error } => ProgressEvent::Failed {
+                task: phase.to_string(),
+                error: error.clone(),
+            },
+            event => ProgressEvent::Message {
+                task: "install".to_string(),
+                message: event.to_string(),
+            },
+        }
+    }
+}
+
+/// Reports progress for the running phase
+#[derive(Debug, Clone)]
+pub struct PhaseReporter {
+    phase: InstallPhase,
+    events: broadcast::Sender<InstallEvent>,
+    log: Arc<Mutex<InstallLog>>,
+    progress: Arc<dyn Progress>,
+}
+
+impl PhaseReporter {
+    #[cfg(feature = "installer")]
+    pub(super) fn new(
+        phase: InstallPhase,
+        events: broadcast::Sender<InstallEvent>,
+        log: Arc<Mutex<InstallLog>>,
+        progress: Arc<dyn Progress>,
+    ) -> Self {
+        Self {
+            phase,
+            events,
+            log,
+            progress,
+        }
+    }
+
+    /// Phase being reported
+    pub fn phase(&self) -> InstallPhase {
+        self.phase
+    }
+
+    /// Report what is being done
+    pub fn message<S: Into<String>>(&self, message: S) {
+        self.send(message.into(), None);
+    }
+
+    /// Report what is being done and how far the phase is
+    pub fn progress<S: Into<String>>(&self, percent: u8, message: S) {
+        self.send(message.into(), Some(percent.min(100)));
+    }
+
+    /// Sink for the progress of operations run by the phase, such as image pulls
+    pub fn subtasks(&self) -> &dyn Progress {
+        &*self.progress
+    }
+
+    /// Report a warning
+    pub fn warn<S: Into<String>>(&self, message: S) {
+        self.publish(InstallEvent::Warning { message: message.into() });
+    }
+
+    fn send(&self, message: String, percent: Option<u8>) {
+        self.publish(InstallEvent::Progress {
+            phase: self.phase,
+            message,
+            percent,
+        });
+    }
+
+    fn publish(&self, event: InstallEvent) {
+        self.progress.report(event.to_progress());
+        self.log.lock().unwrap_or_else(|e| e.into_inner()).event(event.clone());
+        // Nobody listening is not an error
+        let _ = self.events.send(event);
+    }
+}
+
+/// Write every event to the log until the installer is dropped
+pub async fn log_events(mut events: broadcast::Receiver<InstallEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event @ InstallEvent::Warning { .. }) => warn!("{}", event),
+            Ok(event @ InstallEvent::Failed { .. }) => error!("{}", event),
+            Ok(event) => info!("{}", event),
+            Err(broadcast::error::RecvError::Lagged(missed)) => warn!("Install log skipped {} events", missed),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        let event = InstallEvent::PhaseSkipped {
+            phase: InstallPhase::Format,
+            reason: "no disk layout".to_string(),
+        };
+        assert_eq!(event.to_string(), "[3/7] format skipped: no disk layout");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"phase-skipped","phase":"format","reason":"no disk layout"}"#
+        );
+        assert_eq!(serde_json::from_str::<InstallEvent>(r#"{"event":"finished"}"#).unwrap(), InstallEvent::Finished);
+    }
+}
@@ -0,0 +1,288 @@
+//! Post-install hooks and first-boot provisioning
+//!
+//! Post-install hooks are profile-supplied scripts run in a [`Sandbox`]
+//! rooted at the freshly installed system, which they may change but not
+//! escape. Work that needs the real machine, such as
+//! generating host keys or enrolling a TPM, is deferred to first-boot
+//! tasks instead: they are installed as scripts run in order by a oneshot
+//! systemd unit that disables itself once every task succeeded.
+
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use super::report::CommandLog;
+use crate::sandbox::{Sandbox, SandboxConfig};
+
+/// Unit running the first-boot tasks
+pub const FIRST_BOOT_UNIT: &str = "rastos-first-boot.service";
+
+/// Directory of the first-boot task scripts in the installed system
+const FIRST_BOOT_DIR: &str = "usr/lib/rastos/first-boot";
+
+/// Marker written once every first-boot task succeeded
+const FIRST_BOOT_DONE: &str = "/var/lib/rastos/first-boot.done";
+
+/// Hook scripts are copied here, relative to the target, while they run;
+/// not below `tmp`, which is empty in the sandbox
+const HOOK_DIR: &str = "var/tmp/rastos-hooks";
+
+/// A script run in the installed system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisionScript {
+    /// Name, used for the script file and in progress messages
+    pub name: String,
+
+    /// Shell script, run with `/bin/sh -e`
+    pub script: String,
+}
+
+impl ProvisionScript {
+    /// Create a script
+    pub fn new<N: Into<String>, S: Into<String>>(name: N, script: S) -> Self {
+        Self {
+            name: name.into(),
+            script: script.into(),
+        }
+    }
+
+    /// Generate the SSH host keys on first boot
+    pub fn ssh_host_keys() -> Self {
+        Self::new("ssh-host-keys", "ssh-keygen -A\n")
+    }
+
+    fn contents(&self) -> String {
+        format!("#!/bin/sh -e\n# {}: generated by rastOS\n{}", self.name, self.script)
+    }
+}
+
+/// Profile-defined provisioning of the installed system
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProvisionConfig {
+    /// Scripts run in the installed system at the end of the installation
+    #[serde(rename = "hook")]
+    pub hooks: Vec<ProvisionScript>,
+
+    /// Scripts run on the installed system's first boot
+    pub first_boot: Vec<ProvisionScript>,
+
+    /// Sandbox the post-install hooks run in
+    pub sandbox: SandboxConfig,
+}
+
+impl ProvisionConfig {
+    /// Add a post-install hook
+    pub fn with_hook(mut self, hook: ProvisionScript) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Add a first-boot task
+    pub fn with_first_boot(mut self, task: ProvisionScript) -> Self {
+        self.first_boot.push(task);
+        self
+    }
+
+    /// Whether there is anything to do
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty() && self.first_boot.is_empty()
+    }
+
+    /// Check script names, which become file names
+    pub fn validate(&self) -> Result<(), InstallerError> {
+        for scripts in [&self.hooks, &self.first_boot] {
+            let mut seen = HashSet::new();
+            for script in scripts {
+                let valid = !script.name.is_empty()
+                    && script
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if !valid {
+                    return Err(InstallerError::InvalidProfile(format!(
+                        "invalid provisioning script name '{}'",
+                        script.name
+                    )));
+                }
+                if !seen.insert(&script.name) {
+                    return Err(InstallerError::InvalidProfile(format!(
+                        "duplicate provisioning script '{}'",
+                        script.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the post-install hooks in a sandbox rooted at `root`, in order
+    ///
+    /// The hooks may write anywhere in `root` and are recorded in `log`.
+    /// `progress` is called with each hook before it runs.
+    pub fn run_hooks(
+        &self,
+        root: &Path,
+        log: &CommandLog,
+        mut progress: impl FnMut(&ProvisionScript),
+    ) -> Result<(), InstallerError> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        let sandbox = Sandbox::new(self.sandbox.clone()).with_root(root).with_writable("/");
+        let dir = root.join(HOOK_DIR);
+        fs::create_dir_all(&dir)?;
+        let result = self.hooks.iter().try_for_each(|hook| {
+            progress(hook);
+            info!("Running post-install hook {}", hook.name);
+            write_script(&dir.join(&hook.name), &hook.contents())?;
+            let mut command = Command::new("/bin/sh");
+            command.arg("-e").arg(Path::new("/").join(HOOK_DIR).join(&hook.name));
+            let output = sandbox.output(&mut command)?;
+            log.record(&command, &output);
+            if !output.status.success() {
+                return Err(InstallerError::command_error(format!("hook {}", hook.name), &output));
+            }
+            Ok(())
+        });
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            warn!("Could not remove hook directory {}: {}", dir.display(), e);
+        }
+        result
+    }
+
+    /// Install the first-boot scripts and enable their unit below `root`
+    pub fn write_first_boot(&self, root: &Path) -> Result<(), InstallerError> {
+        if self.first_boot.is_empty() {
+            return Ok(());
+        }
+        let dir = root.join(FIRST_BOOT_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut scripts = Vec::new();
+        for (index, task) in self.first_boot.iter().enumerate() {
+            // Numbered so a directory listing shows the execution order
+            let file = format!("{:02}-{}", (index + 1) * 10, task.name);
+            write_script(&dir.join(&file), &task.contents())?;
+            scripts.push(Path::new("/").join(FIRST_BOOT_DIR).join(file));
+        }
+
+        let units = root.join("etc/systemd/system");
+        let wants = units.join("multi-user.target.wants");
+        fs::create_dir_all(&wants)?;
+        fs::write(units.join(FIRST_BOOT_UNIT), first_boot_unit(&scripts))?;
+        let link = wants.join(FIRST_BOOT_UNIT);
+        if fs::symlink_metadata(&link).is_err() {
+            symlink(Path::new("/etc/systemd/system").join(FIRST_BOOT_UNIT), link)?;
+        }
+        Ok(())
+    }
+}
+
+/// Make the first-boot tasks installed below `root` run again on the next boot
+///
+/// Returns false if no first-boot tasks are installed.
+pub fn rearm_first_boot(root: &Path) -> Result<bool, InstallerError> {
+    let unit = root.join("etc/systemd/system").join(FIRST_BOOT_UNIT);
+    if !unit.exists() {
+        return Ok(false);
+    }
+    match fs::remove_file(root.join(FIRST_BOOT_DONE.trim_start_matches('/'))) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let wants = root.join("etc/systemd/system/multi-user.target.wants");
+    fs::create_dir_all(&wants)?;
+    let link = wants.join(FIRST_BOOT_UNIT);
+    if fs::symlink_metadata(&link).is_err() {
+        symlink(Path::new("/etc/systemd/system").join(FIRST_BOOT_UNIT), link)?;
+    }
+    Ok(true)
+}
+
+/// Oneshot unit running `scripts` in order until one fails
+fn first_boot_unit(scripts: &[impl AsRef<Path>]) -> String {
+    let mut unit = format!(
+        "# Generated by rastOS\n\
+         [Unit]\n\
+         Description=rastOS first-boot provisioning\n\
+         ConditionPathExists=!{done}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         StateDirectory=rastos\n",
+        done = FIRST_BOOT_DONE
+    );
+    for script in scripts {
+        unit.push_str(&format!("ExecStart={}\n", script.as_ref().display()));
+    }
+    unit.push_str(&format!(
+        "ExecStartPost=/usr/bin/touch {}\n\n[Install]\nWantedBy=multi-user.target\n",
+        FIRST_BOOT_DONE
+    ));
+    unit
+}
+
+fn write_script(path: &Path, contents: &str) -> Result<(), InstallerError> {
+    fs::write(path, contents)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_validate_names() {
+        let config = ProvisionConfig::default().with_hook(ProvisionScript::new("enable-sshd", "systemctl enable sshd"));
+        assert!(config.validate().is_ok());
+        assert!(config
+            .clone()
+            .with_hook(ProvisionScript::new("enable-sshd", "true"))
+            .validate()
+            .is_err());
+        assert!(config.with_first_boot(ProvisionScript::new("../etc", "true")).validate().is_err());
+    }
+
+    #[test]
+    fn test_write_first_boot() -> Result<(), Box<dyn std::error::Error>> {
+        let root = tempdir()?;
+        ProvisionConfig::default()
+            .with_first_boot(ProvisionScript::ssh_host_keys())
+            .with_first_boot(ProvisionScript::new("enroll-tpm", "systemd-cryptenroll --tpm2-device=auto /dev/sda2\n"))
+            .write_first_boot(root.path())?;
+
+        let script = root.path().join(FIRST_BOOT_DIR).join("10-ssh-host-keys");
+        assert_eq!(fs::metadata(&script)?.permissions().mode() & 0o777, 0o755);
+        assert!(fs::read_to_string(&script)?.ends_with("ssh-keygen -A\n"));
+
+        let unit = fs::read_to_string(root.path().join("etc/systemd/system").join(FIRST_BOOT_UNIT))?;
+        assert!(unit.contains(
+            "ExecStart=/usr/lib/rastos/first-boot/10-ssh-host-keys\n\
+             ExecStart=/usr/lib/rastos/first-boot/20-enroll-tpm\n"
+        ));
+        assert!(root
+            .path()
+            .join("etc/systemd/system/multi-user.target.wants")
+            .join(FIRST_BOOT_UNIT)
+            .symlink_metadata()
+            .is_ok());
+
+        let done = root.path().join(FIRST_BOOT_DONE.trim_start_matches('/'));
+        fs::create_dir_all(done.parent().unwrap())?;
+        fs::write(&done, "")?;
+        assert!(rearm_first_boot(root.path())?);
+        assert!(!done.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+//! Installation log and failure reports
+//!
+//! Every event the installer emits and every external command it runs is
+//! recorded with a timestamp. When an installation fails, the log, the
+//! profile with its secrets redacted, the error and a description of the
+//! hardware are packed into a gzipped tarball users can attach to a bug
+//! report.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use super::profile::InstallProfile;
+use super::progress::InstallEvent;
+
+/// Bytes of command output kept per stream; the tail is the useful part
+const MAX_OUTPUT: usize = 16 * 1024;
+
+/// Arguments whose value is a secret
+const SECRET_ARGS: [&str; 2] = ["--password", "-p"];
+
+/// Placeholder for redacted values
+const REDACTED: &str = "<redacted>";
+
+/// An external command and its outcome
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    /// When the command finished
+    pub time: DateTime<Utc>,
+    /// Program and arguments, secrets redacted
+    pub command: String,
+    /// Exit code, `None` if killed by a signal
+    pub code: Option<i32>,
+    /// End of the standard output
+    pub stdout: String,
+    /// End of the standard error
+    pub stderr: String,
+}
+
+/// One entry of the installation log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LogEntry {
+    /// Installer event
+    Event {
+        /// When it was emitted
+        time: DateTime<Utc>,
+        /// The event
+        event: InstallEvent,
+    },
+    /// External command
+    Command(CommandRecord),
+}
+
+impl LogEntry {
+    /// When the entry was recorded
+    pub fn time(&self) -> DateTime<Utc> {
+        match self {
+            Self::Event { time, .. } => *time,
+            Self::Command(record) => record.time,
+        }
+    }
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Event { time, event } => write!(f, "{} {}", time.format("%H:%M:%S%.3f"), event),
+            Self::Command(record) => {
+                let status = record.code.map_or("signal".to_string(), |code| code.to_string());
+                write!(f, "{} $ {} -> {}", record.time.format("%H:%M:%S%.3f"), record.command, status)?;
+                for (name, output) in [("stdout", &record.stdout), ("stderr", &record.stderr)] {
+                    for line in output.lines() {
+                        write!(f, "\n    {}| {}", name, line)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Chronological record of an installation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstallLog {
+    /// Entries, oldest first
+    pub entries: Vec<LogEntry>,
+}
+
+impl InstallLog {
+    /// Record an event
+    pub fn event(&mut self, event: InstallEvent) {
+        self.entries.push(LogEntry::Event { time: Utc::now(), event });
+    }
+
+    /// Add the commands recorded in `commands` since the last call, keeping the log in order
+    pub fn collect_commands(&mut self, commands: &CommandLog) {
+        self.entries.extend(commands.take().into_iter().map(LogEntry::Command));
+        self.entries.sort_by_key(LogEntry::time);
+    }
+}
+
+impl fmt::Display for InstallLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// External commands run by one installation
+///
+/// Clones share the records, so the installer keeps one and hands it to
+/// everything that runs commands on its behalf.
+#[derive(Debug, Clone, Default)]
+pub struct CommandLog {
+    records: Arc<Mutex<Vec<CommandRecord>>>,
+}
+
+impl CommandLog {
+    /// Record a finished command
+    pub(super) fn record(&self, command: &Command, output: &Output) {
+        let record = CommandRecord {
+            time: Utc::now(),
+            command: command_line(command),
+            code: output.status.code(),
+            stdout: tail(&output.stdout),
+            stderr: tail(&output.stderr),
+        };
+        self.records.lock().unwrap_or_else(|e| e.into_inner()).push(record);
+    }
+
+    /// Remove and return the recorded commands
+    pub fn take(&self) -> Vec<CommandRecord> {
+        std::mem::take(&mut *self.records.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Write a report bundle for a failed installation into `dir`
+///
+/// Returns the path of the `.tar.gz` bundle.
+pub fn write_report(
+    dir: &Path,
+    log: &InstallLog,
+    profile: &InstallProfile,
+    error: &InstallerError,
+) -> Result<PathBuf, InstallerError> {
+    let name = format!("rastos-install-report-{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let staging = tempfile::tempdir()?;
+    let root = staging.path().join(&name);
+    fs::create_dir_all(&root)?;
+
+    fs::write(root.join("install.log"), log.to_string())?;
+    let json = serde_json::to_string_pretty(log)
+        .map_err(|e| InstallerError::Unsupported(format!("cannot serialize the install log: {}", e)))?;
+    fs::write(root.join("install-log.json"), json)?;
+    let profile = toml::to_string(&redact(profile))
+        .map_err(|e| InstallerError::InvalidProfile(format!("cannot serialize profile: {}", e)))?;
+    fs::write(root.join("profile.toml"), profile)?;
+    fs::write(root.join("error.txt"), format!("{}\n\n{:#?}\n", error, error))?;
+    fs::write(root.join("hardware.txt"), hardware_info())?;
+
+    fs::create_dir_all(dir)?;
+    let bundle = dir.join(format!("{}.tar.gz", name));
+    let output = Command::new("tar")
+        .arg("--create")
+        .arg("--gzip")
+        .arg("--file")
+        .arg(&bundle)
+        .arg("--directory")
+        .arg(staging.path())
+        .arg(&name)
+        .output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("tar", &output));
+    }
+    info!("Wrote installation report {}", bundle.display());
+    Ok(bundle)
+}
+
+/// Copy of the profile safe to share
+///
+/// Plain passwords and passphrases are never serialized; hashes are.
+fn redact(profile: &InstallProfile) -> InstallProfile {
+    let mut profile = profile.clone();
+    for user in &mut profile.accounts.users {
+        if user.password_hash.is_some() {
+            user.password_hash = Some(REDACTED.to_string());
+        }
+    }
+    profile
+}
+
+/// Kernel, firmware, CPU, memory, disks and PCI devices
+fn hardware_info() -> String {
+    let mut info = String::new();
+    let sections: [(&str, &[&str]); 4] = [
+        ("uname", &["uname", "-a"]),
+        ("lsblk", &["lsblk", "--output", "NAME,SIZE,TYPE,FSTYPE,MODEL,TRAN"]),
+        ("lspci", &["lspci", "-nn"]),
+        ("efibootmgr", &["efibootmgr"]),
+    ];
+    for (title, command) in sections {
+        let output = Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+            .unwrap_or_else(|e| format!("unavailable: {}\n", e));
+        info.push_str(&format!("== {} ==\n{}\n", title, output));
+    }
+
+    let boot_mode = if Path::new("/sys/firmware/efi").exists() { "UEFI" } else { "BIOS" };
+    info.push_str(&format!("== firmware ==\n{}\n\n", boot_mode));
+    for (title, file, prefixes) in [
+        ("cpu", "/proc/cpuinfo", &["model name", "flags"][..]),
+        ("memory", "/proc/meminfo", &["MemTotal", "SwapTotal"][..]),
+    ] {
+        let content = fs::read_to_string(file).unwrap_or_default();
+        let mut lines: Vec<&str> = Vec::new();
+        for prefix in prefixes {
+            lines.extend(content.lines().find(|line| line.starts_with(prefix)));
+        }
+        info.push_str(&format!("== {} ==\n{}\n\n", title, lines.join("\n")));
+    }
+    info
+}
+
+/// Program and arguments with secret values replaced
+fn command_line(command: &Command) -> String {
+    let mut words = vec![command.get_program().to_string_lossy().into_owned()];
+    let mut secret = false;
+    for arg in command.get_args() {
+        let arg = arg.to_string_lossy();
+        words.push(if secret { REDACTED.to_string() } else { arg.to_string() });
+        secret = SECRET_ARGS.contains(&arg.as_ref());
+    }
+    words.join(" ")
+}
+
+/// Last [`MAX_OUTPUT`] bytes of a stream
+fn tail(output: &[u8]) -> String {
+    let text = String::from_utf8_lossy(output);
+    if text.len() <= MAX_OUTPUT {
+        return text.into_owned();
+    }
+    let mut start = text.len() - MAX_OUTPUT;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[...]{}", &text[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::installer::{InstallPhase, UserConfig};
+
+    #[test]
+    fn test_command_line_redacts_secrets() {
+        let mut command = Command::new("useradd");
+        command.args(["--root", "/mnt", "--password", "$6$salt$hash", "alice"]);
+        assert_eq!(command_line(&command), "useradd --root /mnt --password <redacted> alice");
+    }
+
+    #[test]
+    fn test_redact_profile() {
+        let mut profile = InstallProfile::default();
+        let mut user = UserConfig::new("alice");
+        user.password_hash = Some("$6$salt$hash".to_string());
+        profile.accounts.users.push(user);
+        let serialized = toml::to_string(&redact(&profile)).unwrap();
+        assert!(!serialized.contains("$6$salt$hash"));
+        assert!(serialized.contains(REDACTED));
+    }
+
+    #[test]
+    fn test_log_format() {
+        let mut log = InstallLog::default();
+        log.event(InstallEvent::PhaseStarted {
+            phase: InstallPhase::Partition,
+        });
+        log.entries.push(LogEntry::Command(CommandRecord {
+            time: Utc::now(),
+            command: "sgdisk --zap-all /dev/sda".to_string(),
+            code: Some(2),
+            stdout: String::new(),
+            stderr: "Problem opening /dev/sda".to_string(),
+        }));
+        let text = log.to_string();
+        assert!(text.contains("[2/7] partition\n"));
+        assert!(text.contains("$ sgdisk --zap-all /dev/sda -> 2\n    stderr| Problem opening /dev/sda\n"));
+        assert_eq!(tail(&[b'x'; MAX_OUTPUT + 10]).len(), MAX_OUTPUT + "[...]".len());
+
+        // Commands are kept per log, never shared between installations
+        let commands = CommandLog::default();
+        let output = Command::new("true").output().unwrap();
+        commands.record(&Command::new("true"), &output);
+        let mut other = InstallLog::default();
+        other.collect_commands(&CommandLog::default());
+        assert!(other.entries.is_empty());
+        other.collect_commands(&commands);
+        assert_eq!(other.entries.len(), 1);
+        assert!(commands.take().is_empty());
+    }
+}
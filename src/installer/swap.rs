@@ -0,0 +1,297 @@
+//! Swap configuration
+//!
+//! The installed system swaps to the layout's swap partition, to a
+//! swapfile in its own Btrfs subvolume, to compressed RAM through
+//! zram-generator, or not at all. Hibernation needs a disk-backed swap
+//! area and adds the matching `resume=` parameters to the kernel command
+//! line.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::error::InstallerError;
+use super::fstab::{mount_uuid, FstabEntry};
+use super::partition::{run, DiskLayout, PartitionRole};
+use super::report::CommandLog;
+
+/// Subvolume holding the swapfile, relative to the installed root
+///
+/// Snapshots of the root stop at nested subvolumes, and Btrfs refuses to
+/// snapshot a subvolume with an active swapfile.
+const SWAP_SUBVOLUME: &str = "swap";
+
+/// Swapfile path inside [`SWAP_SUBVOLUME`]
+const SWAPFILE: &str = "swapfile";
+
+/// Default swapfile size when not hibernating
+const DEFAULT_SWAPFILE_MIB: u64 = 4096;
+
+/// Where the installed system swaps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwapKind {
+    /// No swap
+    None,
+    /// The disk layout's swap partition
+    #[default]
+    Partition,
+    /// A swapfile on the root filesystem
+    File,
+    /// Compressed swap in RAM
+    Zram,
+}
+
+/// Swap settings of the installed system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwapConfig {
+    /// Swap backing
+    pub kind: SwapKind,
+
+    /// Swapfile size; defaults to the installed memory when hibernating
+    pub size_mib: Option<u64>,
+
+    /// zram device size as a percentage of memory
+    pub zram_percent: u8,
+
+    /// zram compression algorithm
+    pub zram_algorithm: String,
+
+    /// Configure resuming from hibernation
+    pub hibernate: bool,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            kind: SwapKind::Partition,
+            size_mib: None,
+            zram_percent: 50,
+            zram_algorithm: "zstd".to_string(),
+            hibernate: false,
+        }
+    }
+}
+
+/// Swap area set up in the installed system
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapSetup {
+    /// `/etc/fstab` entry activating the swap area, if any
+    pub fstab: Option<FstabEntry>,
+
+    /// Kernel parameters for resuming from hibernation
+    pub resume: Option<String>,
+}
+
+impl SwapConfig {
+    /// Use the given swap backing
+    pub fn with_kind(mut self, kind: SwapKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Configure resuming from hibernation
+    pub fn with_hibernate(mut self, hibernate: bool) -> Self {
+        self.hibernate = hibernate;
+        self
+    }
+
+    /// Check the settings against the disk layout
+    pub fn validate(&self, disk: Option<&DiskLayout>) -> Result<(), InstallerError> {
+        let has_partition = disk.is_some_and(|d| d.partitions.iter().any(|p| p.role == PartitionRole::Swap));
+        if has_partition && self.kind != SwapKind::Partition {
+            return Err(InstallerError::InvalidProfile(format!(
+                "the disk layout has a swap partition but swap is set to {:?}",
+                self.kind
+            )));
+        }
+        if self.kind == SwapKind::Zram && !(1..=100).contains(&self.zram_percent) {
+            return Err(InstallerError::InvalidProfile(format!(
+                "zram size must be 1-100% of memory, not {}%",
+                self.zram_percent
+            )));
+        }
+        if self.size_mib == Some(0) {
+            return Err(InstallerError::InvalidProfile("swapfile size must not be 0".to_string()));
+        }
+        if !self.hibernate {
+            return Ok(());
+        }
+        match self.kind {
+            SwapKind::None | SwapKind::Zram => Err(InstallerError::InvalidProfile(format!(
+                "hibernation needs a swap partition or swapfile, not {:?}",
+                self.kind
+            ))),
+            SwapKind::Partition if !has_partition => Err(InstallerError::InvalidProfile(
+                "hibernation to a swap partition needs one in the disk layout".to_string(),
+            )),
+            SwapKind::Partition if disk.is_some_and(|d| d.encryption.is_some()) => Err(InstallerError::Unsupported(
+                "the swap partition is not encrypted, so hibernating would write memory to disk in the clear; \
+                 use a swapfile"
+                    .to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set up swap in the system mounted at `root`
+    ///
+    /// `partition` is the formatted swap partition, if the layout has one.
+    pub fn apply(&self, root: &Path, partition: Option<&Path>, log: &CommandLog) -> Result<SwapSetup, InstallerError> {
+        match (self.kind, partition) {
+            (SwapKind::None, _) | (SwapKind::Partition, None) => Ok(SwapSetup {
+                fstab: None,
+                resume: None,
+            }),
+            (SwapKind::Partition, Some(device)) => {
+                let uuid = device_uuid(device)?;
+                Ok(SwapSetup {
+                    fstab: Some(FstabEntry::swap(format!("UUID={}", uuid))),
+                    resume: self.hibernate.then(|| resume_params(&uuid, None)),
+                })
+            }
+            (SwapKind::File, _) => self.create_swapfile(root, log),
+            (SwapKind::Zram, _) => {
+                let dir = root.join("etc/systemd");
+                fs::create_dir_all(&dir)?;
+                fs::write(dir.join("zram-generator.conf"), self.zram_generator_conf())?;
+                Ok(SwapSetup {
+                    fstab: None,
+                    resume: None,
+                })
+            }
+        }
+    }
+
+    /// Packages the installed system needs for this configuration
+    pub fn packages(&self) -> &'static [&'static str] {
+        match self.kind {
+            SwapKind::Zram => &["zram-generator"],
+            _ => &[],
+        }
+    }
+
+    /// Create a no-CoW swapfile in its own subvolume
+    ///
+    /// A swap subvolume left by an interrupted run, which checkpoint
+    /// restores carry over, is replaced, so configuring again succeeds.
+    fn create_swapfile(&self, root: &Path, log: &CommandLog) -> Result<SwapSetup, InstallerError> {
+        let size_mib = match (self.size_mib, self.hibernate) {
+            (Some(size), _) => size,
+            (None, true) => memory_mib()?,
+            (None, false) => DEFAULT_SWAPFILE_MIB,
+        };
+        let subvolume = root.join(SWAP_SUBVOLUME);
+        let file = subvolume.join(SWAPFILE);
+        info!("Creating {} MiB swapfile {}", size_mib, file.display());
+
+        if subvolume.exists() {
+            info!("Replacing swap subvolume {} from an earlier run", subvolume.display());
+            crate::sys::btrfs::delete_subvolume(&subvolume, true)?;
+        }
+        crate::sys::btrfs::create_subvolume(&subvolume)?;
+        // Copy-on-write must be off before the file has any data
+        fs::write(&file, "")?;
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600))?;
+        crate::fs::set_nocow(&file, true)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        run(Command::new("fallocate").args(["--length", &format!("{}MiB", size_mib)]).arg(&file), log)?;
+        run(Command::new("mkswap").arg(&file), log)?;
+
+        let resume = if self.hibernate {
+            let uuid = mount_uuid(root)?;
+            Some(resume_params(&uuid, Some(resume_offset(&file)?)))
+        } else {
+            None
+        };
+        Ok(SwapSetup {
+            fstab: Some(FstabEntry::swap(format!("/{}/{}", SWAP_SUBVOLUME, SWAPFILE))),
+            resume,
+        })
+    }
+
+    fn zram_generator_conf(&self) -> String {
+        format!(
+            "# Generated by rastOS\n[zram0]\nzram-size = ram * {} / 100\ncompression-algorithm = {}\n",
+            self.zram_percent, self.zram_algorithm
+        )
+    }
+}
+
+/// Kernel parameters resuming from the swap area on filesystem `uuid`
+fn resume_params(uuid: &str, offset: Option<u64>) -> String {
+    match offset {
+        Some(offset) => format!("resume=UUID={} resume_offset={}", uuid, offset),
+        None => format!("resume=UUID={}", uuid),
+    }
+}
+
+/// Physical offset of a Btrfs swapfile in pages, as `resume_offset=` expects
+fn resume_offset(file: &Path) -> Result<u64, InstallerError> {
+    let output = Command::new("btrfs")
+        .args(["inspect-internal", "map-swapfile", "-r"])
+        .arg(file)
+        .output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("btrfs inspect-internal map-swapfile", &output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .parse()
+        .map_err(|_| InstallerError::Unsupported(format!("unexpected swapfile offset '{}'", stdout.trim())))
+}
+
+fn device_uuid(device: &Path) -> Result<String, InstallerError> {
+    let output = Command::new("blkid").args(["-s", "UUID", "-o", "value"]).arg(device).output()?;
+    if !output.status.success() {
+        return Err(InstallerError::command_error("blkid", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn memory_mib() -> Result<u64, InstallerError> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    super::preflight::mem_total_kib(&meminfo)
+        .map(|kib| kib.div_ceil(1024))
+        .ok_or_else(|| InstallerError::Unsupported("could not read the installed memory size".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_swap() {
+        let layout = DiskLayout::standard("/dev/sda", Some(4096));
+        let plain = DiskLayout::standard("/dev/sda", None);
+
+        assert!(SwapConfig::default().with_hibernate(true).validate(Some(&layout)).is_ok());
+        assert!(SwapConfig::default().with_hibernate(true).validate(Some(&plain)).is_err());
+        assert!(SwapConfig::default().with_kind(SwapKind::File).validate(Some(&layout)).is_err());
+        assert!(SwapConfig::default()
+            .with_kind(SwapKind::File)
+            .with_hibernate(true)
+            .validate(Some(&plain))
+            .is_ok());
+        assert!(SwapConfig::default()
+            .with_kind(SwapKind::Zram)
+            .with_hibernate(true)
+            .validate(None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_resume_params() {
+        assert_eq!(resume_params("abcd", None), "resume=UUID=abcd");
+        assert_eq!(resume_params("abcd", Some(533760)), "resume=UUID=abcd resume_offset=533760");
+
+        let zram = SwapConfig::default().with_kind(SwapKind::Zram);
+        assert!(zram.zram_generator_conf().contains("zram-size = ram * 50 / 100\n"));
+    }
+}
@@ -0,0 +1,513 @@
+//! Background jobs
+//!
+//! Backups, image pulls and kernel builds can take hours, so the daemon
+//! runs them as [`Job`]s instead of within an API call. Jobs are kept in a
+//! [`JobStore`], one JSON file and one log per job, so they outlive the
+//! daemon and can be listed, cancelled and followed with `rastos jobs`
+//! without it. The [`Scheduler`] starts pending jobs by priority within
+//! the concurrency limits of [`JobsConfig`].
+//!
+//! A job that was running when the daemon stopped is not run again: it is
+//! marked failed when the scheduler starts.
+
+mod scheduler;
+
+pub use scheduler::{JobContext, JobRunner, Scheduler};
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::fs::WriteOptions;
+use crate::kernel::KernelProfile;
+use crate::progress::{Progress, ProgressEvent};
+
+/// Job directory relative to the system root
+pub const JOBS_DIR: &str = "var/lib/rast/jobs";
+
+/// Error type for jobs
+#[derive(Error, Debug)]
+pub enum JobError {
+    /// I/O error in the job directory
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Job file that cannot be read
+    #[error("Corrupt job file: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// No job with this ID
+    #[error("Job {0} not found")]
+    NotFound(u64),
+
+    /// The job already ended
+    #[error("Job {id} is already {state}")]
+    Ended {
+        /// Job ID
+        id: u64,
+        /// State it ended in
+        state: JobState,
+    },
+}
+
+/// Result type for jobs
+pub type Result<T> = std::result::Result<T, JobError>;
+
+/// Kind of work a job does, which concurrency limits apply to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobKind {
+    /// Back up a subvolume
+    Backup,
+    /// Pull a container image
+    ImagePull,
+    /// Build a kernel
+    KernelBuild,
+}
+
+impl Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobKind::Backup => "backup",
+            JobKind::ImagePull => "image-pull",
+            JobKind::KernelBuild => "kernel-build",
+        })
+    }
+}
+
+/// What a job does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum JobSpec {
+    /// Back up a subvolume, incrementally on top of `parent` if given
+    Backup {
+        /// Subvolume to back up
+        subvolume: PathBuf,
+        /// Name of the backup
+        #[serde(default)]
+        name: Option<String>,
+        /// Description of the backup
+        #[serde(default)]
+        description: Option<String>,
+        /// ID of the parent backup
+        #[serde(default)]
+        parent: Option<String>,
+    },
+    /// Pull an image into an OCI image layout
+    ImagePull {
+        /// Image reference (`registry/name:tag`)
+        reference: String,
+        /// Layout directory
+        layout: PathBuf,
+    },
+    /// Configure, build and install a kernel
+    KernelBuild {
+        /// Kernel source directory
+        source: PathBuf,
+        /// Build profile
+        #[serde(default)]
+        profile: KernelProfile,
+        /// Parallel make jobs; the number of CPUs if unset
+        #[serde(default)]
+        jobs: Option<usize>,
+    },
+}
+
+impl JobSpec {
+    /// Kind of the job
+    pub fn kind(&self) -> JobKind {
+        match self {
+            JobSpec::Backup { .. } => JobKind::Backup,
+            JobSpec::ImagePull { .. } => JobKind::ImagePull,
+            JobSpec::KernelBuild { .. } => JobKind::KernelBuild,
+        }
+    }
+}
+
+impl Display for JobSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobSpec::Backup { subvolume, .. } => write!(f, "backup {}", subvolume.display()),
+            JobSpec::ImagePull { reference, .. } => write!(f, "image-pull {}", reference),
+            JobSpec::KernelBuild { source, .. } => write!(f, "kernel-build {}", source.display()),
+        }
+    }
+}
+
+/// Scheduling priority; higher priorities start first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Start when nothing else is waiting
+    Low,
+    /// The default
+    #[default]
+    Normal,
+    /// Start before other waiting jobs
+    High,
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+/// Where a job is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting to be started
+    Pending,
+    /// Started and not yet ended
+    Running,
+    /// Ended successfully
+    Completed,
+    /// Ended with an error
+    Failed,
+    /// Cancelled before it ended
+    Cancelled,
+}
+
+impl JobState {
+    /// Whether the job has ended
+    pub fn is_final(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// A queued, running or ended job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    /// Job ID, increasing in submission order
+    pub id: u64,
+    /// What the job does
+    pub spec: JobSpec,
+    /// Scheduling priority
+    pub priority: Priority,
+    /// Current state
+    pub state: JobState,
+    /// When the job was submitted
+    pub created_at: DateTime<Utc>,
+    /// When the job was started
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the job ended
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Why the job failed
+    pub error: Option<String>,
+}
+
+impl Job {
+    /// Record that the job ended in `state`
+    pub fn end(&mut self, state: JobState, error: Option<String>) {
+        self.state = state;
+        self.error = error;
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+/// Scheduling of background jobs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Directory of the job store
+    pub dir: PathBuf,
+    /// Jobs running at the same time
+    pub max_running: usize,
+    /// Jobs of one kind running at the same time; unlimited if not listed
+    pub limits: BTreeMap<JobKind, usize>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            dir: Path::new("/").join(JOBS_DIR),
+            max_running: 2,
+            limits: BTreeMap::from([(JobKind::Backup, 1), (JobKind::ImagePull, 2), (JobKind::KernelBuild, 1)]),
+        }
+    }
+}
+
+impl JobsConfig {
+    /// Jobs of `kind` allowed to run at the same time
+    pub fn limit(&self, kind: JobKind) -> usize {
+        self.limits.get(&kind).copied().unwrap_or(self.max_running)
+    }
+}
+
+/// Jobs kept on disk
+///
+/// Each job is `<id>.json` in the store's directory, with its log in
+/// `<id>.log`. Cancelling a running job leaves `<id>.cancel` for the
+/// scheduler, so tools without access to the daemon can cancel jobs too.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    dir: PathBuf,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobStore {
+    /// The job store of the running system
+    pub fn new() -> Self {
+        Self::with_root("/")
+    }
+
+    /// The job store of the system at `root`
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Self::with_dir(root.as_ref().join(JOBS_DIR))
+    }
+
+    /// A job store in `dir`
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Directory holding the jobs
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Queue a new job
+    pub fn submit(&self, spec: JobSpec, priority: Priority) -> Result<Job> {
+        fs::create_dir_all(&self.dir)?;
+        let mut id = self.list()?.last().map_or(1, |job| job.id + 1);
+        // Claim the ID atomically; a concurrent submit may have taken it
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(self.path(id)) {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let job = Job {
+            id,
+            spec,
+            priority,
+            state: JobState::Pending,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+        };
+        self.save(&job)?;
+        Ok(job)
+    }
+
+    /// The job with `id`
+    pub fn get(&self, id: u64) -> Result<Job> {
+        match fs::read(self.path(id)) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(JobError::NotFound(id)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// All jobs, oldest first
+    pub fn list(&self) -> Result<Vec<Job>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut jobs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(id) = job_id(&path) else { continue };
+            match self.get(id) {
+                Ok(job) => jobs.push(job),
+                // Claimed by a submit that has not written it yet
+                Err(JobError::Json(_)) if fs::metadata(&path)?.len() == 0 => {}
+                Err(e) => return Err(e),
+            }
+        }
+        jobs.sort_by_key(|job| job.id);
+        Ok(jobs)
+    }
+
+    /// Pending jobs in the order they should start
+    pub fn pending(&self) -> Result<Vec<Job>> {
+        let mut jobs: Vec<Job> = self.list()?.into_iter().filter(|job| job.state == JobState::Pending).collect();
+        jobs.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
+        Ok(jobs)
+    }
+
+    /// Write the state of `job`
+    pub fn save(&self, job: &Job) -> Result<()> {
+        let content = serde_json::to_vec_pretty(job)?;
+        crate::fs::atomic_write_with_options(self.path(job.id), content, &WriteOptions::default().with_mode(0o600))
+            .map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Cancel a job
+    ///
+    /// A pending job is cancelled at once; a running job is stopped by the
+    /// scheduler, which records it as cancelled when it has stopped.
+    pub fn cancel(&self, id: u64) -> Result<Job> {
+        let mut job = self.get(id)?;
+        match job.state {
+            state if state.is_final() => return Err(JobError::Ended { id, state }),
+            JobState::Pending => {
+                job.end(JobState::Cancelled, None);
+                self.save(&job)?;
+            }
+            _ => {}
+        }
+        File::create(self.cancel_path(id))?;
+        Ok(job)
+    }
+
+    /// Whether cancelling `id` was requested
+    pub fn cancel_requested(&self, id: u64) -> bool {
+        self.cancel_path(id).exists()
+    }
+
+    /// Mark jobs left running by a daemon that stopped as failed
+    pub fn recover(&self) -> Result<Vec<Job>> {
+        let mut interrupted = Vec::new();
+        for mut job in self.list()? {
+            if job.state == JobState::Running {
+                job.end(JobState::Failed, Some("interrupted by a daemon restart".to_string()));
+                self.save(&job)?;
+                interrupted.push(job);
+            }
+        }
+        Ok(interrupted)
+    }
+
+    /// Path of the log of `id`
+    pub fn log_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.log", id))
+    }
+
+    /// The log of `id` so far
+    pub fn read_log(&self, id: u64) -> Result<String> {
+        self.get(id)?;
+        match fs::read_to_string(self.log_path(id)) {
+            Ok(log) => Ok(log),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Open the log of `id` for appending, also reporting to `forward`
+    pub fn log(&self, id: u64, forward: Arc<dyn Progress>) -> Result<JobLog> {
+        let file = OpenOptions::new().create(true).append(true).open(self.log_path(id))?;
+        Ok(JobLog {
+            file: Mutex::new(file),
+            forward,
+        })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn cancel_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.cancel", id))
+    }
+}
+
+/// ID of a job file
+fn job_id(path: &Path) -> Option<u64> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Progress sink writing a job's log
+///
+/// Every event is appended to the log with a timestamp and passed on.
+#[derive(Debug)]
+pub struct JobLog {
+    file: Mutex<File>,
+    forward: Arc<dyn Progress>,
+}
+
+impl Progress for JobLog {
+    fn report(&self, event: ProgressEvent) {
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let _ = writeln!(self.file.lock().unwrap(), "{} {}", now, event);
+        self.forward.report(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn pull(reference: &str) -> JobSpec {
+        JobSpec::ImagePull {
+            reference: reference.to_string(),
+            layout: PathBuf::from("/var/lib/rast/images"),
+        }
+    }
+
+    #[test]
+    fn test_store_queue_order() {
+        let dir = tempdir().unwrap();
+        let store = JobStore::with_dir(dir.path());
+        let first = store.submit(pull("alpine:3"), Priority::Normal).unwrap();
+        let urgent = store.submit(pull("busybox:1"), Priority::High).unwrap();
+        let last = store.submit(pull("debian:12"), Priority::Normal).unwrap();
+        assert_eq!((first.id, urgent.id, last.id), (1, 2, 3));
+
+        let order: Vec<u64> = store.pending().unwrap().iter().map(|job| job.id).collect();
+        assert_eq!(order, [2, 1, 3]);
+
+        let cancelled = store.cancel(1).unwrap();
+        assert_eq!(cancelled.state, JobState::Cancelled);
+        assert!(matches!(store.cancel(1), Err(JobError::Ended { id: 1, .. })));
+        assert_eq!(store.pending().unwrap().len(), 2);
+        assert!(matches!(store.get(7), Err(JobError::NotFound(7))));
+    }
+
+    #[test]
+    fn test_recover_running_jobs() {
+        let dir = tempdir().unwrap();
+        let store = JobStore::with_dir(dir.path());
+        let mut job = store.submit(pull("alpine:3"), Priority::Low).unwrap();
+        job.state = JobState::Running;
+        store.save(&job).unwrap();
+
+        let recovered = store.recover().unwrap();
+        assert_eq!(recovered.len(), 1);
+        let job = store.get(job.id).unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert!(job.finished_at.is_some());
+    }
+}
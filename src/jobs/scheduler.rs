@@ -0,0 +1,240 @@
+//! Running queued jobs
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info, warn};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use super::{Job, JobKind, JobSpec, JobState, JobStore, JobsConfig, Priority, Result};
+use crate::progress::{Progress, Silent};
+use crate::transaction::BoxError;
+
+/// How often the store is checked for cancellations by other processes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a running job gets from the scheduler
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    /// ID of the job
+    pub id: u64,
+    /// Cancelled when the job should stop
+    pub cancel: CancellationToken,
+    /// Writes the job's log
+    pub progress: Arc<dyn Progress>,
+}
+
+/// Does the work of jobs
+#[async_trait]
+pub trait JobRunner: Send + Sync {
+    /// Run `spec` to the end
+    ///
+    /// Runners should stop early and return an error once `context.cancel`
+    /// is cancelled; the job is then recorded as cancelled.
+    async fn run(&self, spec: &JobSpec, context: &JobContext) -> std::result::Result<(), BoxError>;
+}
+
+/// Starts queued jobs within the configured limits
+#[derive(Debug)]
+pub struct Scheduler {
+    config: JobsConfig,
+    store: JobStore,
+    running: Mutex<HashMap<u64, (JobKind, CancellationToken)>>,
+    wake: Notify,
+    progress: Arc<dyn Progress>,
+}
+
+impl Scheduler {
+    /// Create a scheduler for the jobs in `config.dir`
+    pub fn new(config: JobsConfig) -> Self {
+        Self {
+            store: JobStore::with_dir(&config.dir),
+            config,
+            running: Mutex::new(HashMap::new()),
+            wake: Notify::new(),
+            progress: Arc::new(Silent),
+        }
+    }
+
+    /// Also report the progress of every job to `progress`
+    pub fn with_progress(mut self, progress: Arc<dyn Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// The store holding the jobs
+    pub fn store(&self) -> &JobStore {
+        &self.store
+    }
+
+    /// Queue a job
+    pub fn submit(&self, spec: JobSpec, priority: Priority) -> Result<Job> {
+        let job = self.store.submit(spec, priority)?;
+        info!("Queued job {}: {}", job.id, job.spec);
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    /// Cancel a pending job, or ask a running one to stop
+    pub fn cancel(&self, id: u64) -> Result<Job> {
+        let job = self.store.cancel(id)?;
+        if let Some((_, token)) = self.running.lock().unwrap().get(&id) {
+            token.cancel();
+        }
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    /// Run jobs with `runner` until `stop` is cancelled
+    ///
+    /// Jobs still running when `stop` is cancelled are asked to stop.
+    pub async fn run(self: Arc<Self>, runner: Arc<dyn JobRunner>, stop: CancellationToken) -> Result<()> {
+        for job in self.store.recover()? {
+            warn!("Job {} ({}) was interrupted by a restart", job.id, job.spec);
+        }
+
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            self.check_cancellations();
+            if let Err(e) = self.start_pending(&runner) {
+                error!("Cannot start queued jobs: {}", e);
+            }
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                _ = self.wake.notified() => {}
+                _ = poll.tick() => {}
+            }
+        }
+
+        for (_, token) in self.running.lock().unwrap().values() {
+            token.cancel();
+        }
+        Ok(())
+    }
+
+    /// Pass cancellations requested through the store on to running jobs
+    fn check_cancellations(&self) {
+        for (id, (_, token)) in self.running.lock().unwrap().iter() {
+            if self.store.cancel_requested(*id) {
+                token.cancel();
+            }
+        }
+    }
+
+    /// Start pending jobs, highest priority first, while the limits allow
+    fn start_pending(self: &Arc<Self>, runner: &Arc<dyn JobRunner>) -> Result<()> {
+        let mut running = self.running.lock().unwrap();
+        for job in self.store.pending()? {
+            if running.len() >= self.config.max_running {
+                break;
+            }
+            let kind = job.spec.kind();
+            if running.values().filter(|(k, _)| *k == kind).count() >= self.config.limit(kind) {
+                continue;
+            }
+            let token = CancellationToken::new();
+            running.insert(job.id, (kind, token.clone()));
+            tokio::spawn(Arc::clone(self).execute(job, Arc::clone(runner), token));
+        }
+        Ok(())
+    }
+
+    async fn execute(self: Arc<Self>, mut job: Job, runner: Arc<dyn JobRunner>, cancel: CancellationToken) {
+        let id = job.id;
+        if let Err(e) = self.execute_job(&mut job, runner, cancel).await {
+            error!("Cannot record the state of job {}: {}", id, e);
+        }
+        self.running.lock().unwrap().remove(&id);
+        self.wake.notify_one();
+    }
+
+    async fn execute_job(&self, job: &mut Job, runner: Arc<dyn JobRunner>, cancel: CancellationToken) -> Result<()> {
+        // Cancelled while waiting to be started
+        if self.store.cancel_requested(job.id) {
+            job.end(JobState::Cancelled, None);
+            return self.store.save(job);
+        }
+
+        job.state = JobState::Running;
+        job.started_at = Some(Utc::now());
+        self.store.save(job)?;
+        info!("Started job {}: {}", job.id, job.spec);
+
+        let context = JobContext {
+            id: job.id,
+            cancel: cancel.clone(),
+            progress: Arc::new(self.store.log(job.id, Arc::clone(&self.progress))?),
+        };
+        match runner.run(&job.spec, &context).await {
+            Ok(()) => job.end(JobState::Completed, None),
+            Err(_) if cancel.is_cancelled() => job.end(JobState::Cancelled, None),
+            Err(e) => job.end(JobState::Failed, Some(e.to_string())),
+        }
+        info!("Job {} {}", job.id, job.state);
+        self.store.save(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    /// Waits until cancelled
+    struct Blocking;
+
+    #[async_trait]
+    impl JobRunner for Blocking {
+        async fn run(&self, _spec: &JobSpec, context: &JobContext) -> std::result::Result<(), BoxError> {
+            context.cancel.cancelled().await;
+            Err("cancelled".into())
+        }
+    }
+
+    async fn wait_for(store: &JobStore, id: u64, state: JobState) {
+        for _ in 0..100 {
+            if store.get(id).unwrap().state == state {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("job {} never became {}", id, state);
+    }
+
+    #[tokio::test]
+    async fn test_limits_and_cancellation() {
+        let dir = tempdir().unwrap();
+        let config = JobsConfig {
+            dir: dir.path().to_path_buf(),
+            ..JobsConfig::default()
+        };
+        let scheduler = Arc::new(Scheduler::new(config));
+        let backup = |name: &str| JobSpec::Backup {
+            subvolume: PathBuf::from(name),
+            name: None,
+            description: None,
+            parent: None,
+        };
+        let first = scheduler.submit(backup("/home"), Priority::Normal).unwrap();
+        let second = scheduler.submit(backup("/srv"), Priority::Normal).unwrap();
+
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(Arc::clone(&scheduler).run(Arc::new(Blocking), stop.clone()));
+
+        // One backup at a time
+        wait_for(scheduler.store(), first.id, JobState::Running).await;
+        assert_eq!(scheduler.store().get(second.id).unwrap().state, JobState::Pending);
+
+        scheduler.cancel(first.id).unwrap();
+        wait_for(scheduler.store(), first.id, JobState::Cancelled).await;
+        wait_for(scheduler.store(), second.id, JobState::Running).await;
+
+        stop.cancel();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+//! Kernel bisection
+//!
+//! Drives `git bisect` over a kernel checkout: every step is compiled with
+//! the configured [`KernelBuilder`] and then judged by a test — a successful
+//! build, a boot in QEMU, or a user command — until git names the first bad
+//! commit. Commits that fail to build are skipped by default, and the
+//! bisection is always reset afterwards so the checkout is left as found.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use super::build::KernelBuilder;
+use super::error::KernelError;
+use super::patches;
+
+/// Exit code a test command uses to skip a commit, as with `git bisect run`
+pub const SKIP_EXIT_CODE: i32 = 125;
+
+/// Verdict on one bisection step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BisectVerdict {
+    /// The commit works
+    Good,
+    /// The commit shows the regression
+    Bad,
+    /// The commit cannot be tested
+    Skip,
+}
+
+impl BisectVerdict {
+    /// `git bisect` subcommand recording the verdict
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Good => "good",
+            Self::Bad => "bad",
+            Self::Skip => "skip",
+        }
+    }
+
+    /// Verdict for the exit code of a test command
+    pub fn from_exit_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => Self::Good,
+            Some(SKIP_EXIT_CODE) => Self::Skip,
+            _ => Self::Bad,
+        }
+    }
+}
+
+/// Boot test in a QEMU virtual machine
+#[derive(Debug, Clone)]
+pub struct BootTest {
+    /// QEMU system emulator
+    pub qemu: String,
+    /// Guest memory (`-m`)
+    pub memory: String,
+    /// Initramfs to boot with
+    pub initrd: Option<PathBuf>,
+    /// Kernel command line
+    pub cmdline: String,
+    /// Console output that marks a successful boot
+    pub success_pattern: String,
+    /// Time allowed for the success pattern to appear
+    pub timeout: Duration,
+}
+
+impl Default for BootTest {
+    fn default() -> Self {
+        Self {
+            qemu: "qemu-system-x86_64".to_string(),
+            memory: "1G".to_string(),
+            initrd: None,
+            cmdline: "console=ttyS0 panic=-1".to_string(),
+            success_pattern: "Freeing unused kernel image".to_string(),
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl BootTest {
+    /// Boot with an initramfs
+    pub fn with_initrd<P: AsRef<Path>>(mut self, initrd: P) -> Self {
+        self.initrd = Some(initrd.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the kernel command line
+    pub fn with_cmdline<S: Into<String>>(mut self, cmdline: S) -> Self {
+        self.cmdline = cmdline.into();
+        self
+    }
+
+    /// Set the console output that marks a successful boot
+    pub fn with_success_pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.success_pattern = pattern.into();
+        self
+    }
+
+    /// Set the boot timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Boot `image` and watch the serial console
+    ///
+    /// Good if the success pattern appears before a panic, an oops or the
+    /// timeout; bad otherwise.
+    pub fn run(&self, image: &Path) -> Result<BisectVerdict, KernelError> {
+        let mut command = Command::new(&self.qemu);
+        command
+            .arg("-kernel")
+            .arg(image)
+            .args(["-append", &self.cmdline, "-m", &self.memory])
+            .args(["-nographic", "-no-reboot"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        if let Some(initrd) = &self.initrd {
+            command.arg("-initrd").arg(initrd);
+        }
+        if Path::new("/dev/kvm").exists() {
+            command.args(["-enable-kvm", "-cpu", "host"]);
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|_| KernelError::Unsupported(format!("{} is not installed", self.qemu)))?;
+        let (tx, rx) = mpsc::channel();
+        let stdout = child.stdout.take().expect("piped stdout");
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let verdict = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(line) => {
+                    if line.contains(&self.success_pattern) {
+                        break BisectVerdict::Good;
+                    }
+                    if is_crash(&line) {
+                        warn!("Boot failed: {}", line.trim());
+                        break BisectVerdict::Bad;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    warn!("Boot did not finish within {}s", self.timeout.as_secs());
+                    break BisectVerdict::Bad;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    warn!("QEMU exited before the kernel finished booting");
+                    break BisectVerdict::Bad;
+                }
+            }
+        };
+
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = reader.join();
+        Ok(verdict)
+    }
+}
+
+/// Console lines that mean the kernel crashed
+fn is_crash(line: &str) -> bool {
+    ["Kernel panic", "BUG: ", "Oops: ", "general protection fault"]
+        .iter()
+        .any(|marker| line.contains(marker))
+}
+
+/// How each bisection step is judged
+#[derive(Debug, Clone, Default)]
+pub enum BisectTest {
+    /// Good if the kernel builds
+    #[default]
+    Build,
+    /// Good if the kernel boots in QEMU
+    Boot(BootTest),
+    /// Run a shell command with `KERNEL_IMAGE` set; exit 0 is good, 125 skips
+    Command(String),
+}
+
+/// One tested commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BisectStep {
+    /// Commit hash
+    pub commit: String,
+    /// Verdict given to git
+    pub verdict: BisectVerdict,
+}
+
+/// Result of a bisection
+#[derive(Debug, Clone, Default)]
+pub struct BisectOutcome {
+    /// First bad commit, unless skipped commits left it ambiguous
+    pub first_bad: Option<String>,
+    /// Tested commits in order
+    pub steps: Vec<BisectStep>,
+    /// `git bisect log`, replayable with `git bisect replay`
+    pub log: String,
+}
+
+/// State of the bisection after a verdict
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BisectStatus {
+    Continue,
+    Found(String),
+    Inconclusive,
+}
+
+/// Bisects a kernel regression between a good and a bad revision
+#[derive(Debug)]
+pub struct Bisector {
+    builder: KernelBuilder,
+    source_dir: PathBuf,
+    good: String,
+    bad: String,
+    test: BisectTest,
+    skip_build_failures: bool,
+}
+
+impl Bisector {
+    /// Bisect between `good` and `bad` in the builder's source directory
+    pub fn new<G: Into<String>, B: Into<String>>(builder: KernelBuilder, good: G, bad: B) -> Self {
+        Self {
+            source_dir: builder.source_dir().to_path_buf(),
+            builder,
+            good: good.into(),
+            bad: bad.into(),
+            test: BisectTest::default(),
+            skip_build_failures: true,
+        }
+    }
+
+    /// Set how each step is judged
+    pub fn with_test(mut self, test: BisectTest) -> Self {
+        self.test = test;
+        self
+    }
+
+    /// Skip commits that fail to build instead of marking them bad
+    pub fn with_skip_build_failures(mut self, skip: bool) -> Self {
+        self.skip_build_failures = skip;
+        self
+    }
+
+    /// Run the bisection to completion
+    ///
+    /// The bisection is reset afterwards, also on error or interruption.
+    pub fn run(&self) -> Result<BisectOutcome, KernelError> {
+        if !self.source_dir.join(".git").exists() {
+            return Err(KernelError::Unsupported(format!(
+                "{} is not a git checkout",
+                self.source_dir.display()
+            )));
+        }
+
+        // Applied patches would block checking out other commits
+        patches::reverse(&self.source_dir)?;
+        self.git(&["bisect", "start", &self.bad, &self.good])?;
+        let result = self.bisect();
+        if let Err(e) = self.git(&["bisect", "reset"]) {
+            warn!("Could not reset bisection: {}", e);
+        }
+        result
+    }
+
+    fn bisect(&self) -> Result<BisectOutcome, KernelError> {
+        let mut outcome = BisectOutcome::default();
+        loop {
+            let commit = self.git(&["rev-parse", "HEAD"])?.trim().to_string();
+            info!("Testing {} (step {})", commit, outcome.steps.len() + 1);
+
+            let verdict = self.test_commit()?;
+            patches::reverse(&self.source_dir)?;
+            info!("{} is {}", commit, verdict.as_str());
+
+            let output = self.git(&["bisect", verdict.as_str()])?;
+            outcome.steps.push(BisectStep { commit, verdict });
+            match parse_status(&output) {
+                BisectStatus::Continue => {}
+                BisectStatus::Found(commit) => {
+                    outcome.first_bad = Some(commit);
+                    break;
+                }
+                BisectStatus::Inconclusive => {
+                    warn!("Only skipped commits are left, the first bad commit is ambiguous");
+                    break;
+                }
+            }
+        }
+        outcome.log = self.git(&["bisect", "log"])?;
+        Ok(outcome)
+    }
+
+    fn test_commit(&self) -> Result<BisectVerdict, KernelError> {
+        let image = match self.builder.compile_image() {
+            Ok(image) => image,
+            Err(e @ KernelError::Interrupted { .. }) => return Err(e),
+            Err(e) => {
+                warn!("Build failed: {}", e);
+                return Ok(if self.skip_build_failures {
+                    BisectVerdict::Skip
+                } else {
+                    BisectVerdict::Bad
+                });
+            }
+        };
+
+        match &self.test {
+            BisectTest::Build => Ok(BisectVerdict::Good),
+            BisectTest::Boot(boot) => boot.run(&image),
+            BisectTest::Command(command) => {
+                let status = Command::new("sh")
+                    .args(["-c", command])
+                    .current_dir(&self.source_dir)
+                    .env("KERNEL_IMAGE", &image)
+                    .status()?;
+                Ok(BisectVerdict::from_exit_code(status.code()))
+            }
+        }
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String, KernelError> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.source_dir)
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            return Err(KernelError::command_error(format!("git {}", args.join(" ")), &output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Interpret the output of `git bisect good|bad|skip`
+fn parse_status(output: &str) -> BisectStatus {
+    if let Some(line) = output.lines().find(|l| l.ends_with("is the first bad commit")) {
+        return BisectStatus::Found(line.split_whitespace().next().unwrap_or_default().to_string());
+    }
+    if output.contains("only 'skip'ped commits left") {
+        return BisectStatus::Inconclusive;
+    }
+    BisectStatus::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            parse_status("Bisecting: 3 revisions left to test after this (roughly 2 steps)\n[abc123] mm: fix"),
+            BisectStatus::Continue
+        );
+        assert_eq!(
+            parse_status("1f2e3d4c5b6a is the first bad commit\ncommit 1f2e3d4c5b6a\nAuthor: someone"),
+            BisectStatus::Found("1f2e3d4c5b6a".to_string())
+        );
+        assert_eq!(
+            parse_status("There are only 'skip'ped commits left to test.\nThe first bad commit could be any of:"),
+            BisectStatus::Inconclusive
+        );
+    }
+
+    #[test]
+    fn test_verdicts() {
+        assert_eq!(BisectVerdict::from_exit_code(Some(0)), BisectVerdict::Good);
+        assert_eq!(BisectVerdict::from_exit_code(Some(SKIP_EXIT_CODE)), BisectVerdict::Skip);
+        assert_eq!(BisectVerdict::from_exit_code(Some(1)), BisectVerdict::Bad);
+        assert_eq!(BisectVerdict::from_exit_code(None), BisectVerdict::Bad);
+        assert!(is_crash("[    1.234] Kernel panic - not syncing: VFS: Unable to mount root fs"));
+        assert!(!is_crash("[    1.234] Freeing unused kernel image (initmem) memory: 2048K"));
+    }
+}
@@ -0,0 +1,470 @@
+//! Boot entry management
+//!
+//! After a kernel is installed, creates a boot entry for it, keeps entries
+//! for the previous kernels as fallbacks and prunes everything beyond a
+//! configurable count. Only kernels rastOS installed itself, recorded in a
+//! manifest on the boot partition, are ever pruned, and never the running
+//! one. systemd-boot entries are written directly as Boot
+//! Loader Specification files; GRUB menus are regenerated with
+//! `grub-mkconfig`, which picks up `vmlinuz-<release>` images in `/boot`.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+use crate::package::vercmp;
+
+/// Prefix of entry files and images managed by rastOS
+const ENTRY_PREFIX: &str = "rastos-";
+
+/// Manifest of the kernels installed by rastOS, one release per line
+const MANIFEST: &str = "rastos-kernels";
+
+/// Entry booting a snapshot selected with [`BootEntryConfig::snapshot_entry`]
+const SNAPSHOT_ENTRY: &str = "rastos-snapshot.conf";
+
+/// Kernel command line parameters that must not be copied into new entries
+const TRANSIENT_PARAMS: &[&str] = &["BOOT_IMAGE=", "initrd=", "rastos.snapshot="];
+
+/// Supported bootloaders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum Bootloader {
+    /// systemd-boot (Boot Loader Specification entries)
+    #[default]
+    SystemdBoot,
+    /// GRUB (menu regenerated with grub-mkconfig)
+    Grub,
+}
+
+/// Boot entry settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootEntryConfig {
+    /// Bootloader to configure
+    pub bootloader: Bootloader,
+
+    /// Boot partition holding kernels, initramfs images and entries
+    pub boot_dir: PathBuf,
+
+    /// Kernel command line (defaults to the running kernel's)
+    pub cmdline: Option<String>,
+
+    /// Number of kernels to keep, including the new one (at least 2)
+    pub keep: usize,
+
+    /// GRUB configuration file to regenerate
+    pub grub_config: PathBuf,
+}
+
+impl Default for BootEntryConfig {
+    fn default() -> Self {
+        Self {
+            bootloader: Bootloader::default(),
+            boot_dir: PathBuf::from("/boot"),
+            cmdline: None,
+            keep: 3,
+            grub_config: PathBuf::from("/boot/grub/grub.cfg"),
+        }
+    }
+}
+
+impl BootEntryConfig {
+    /// Use the given bootloader
+    pub fn with_bootloader(mut self, bootloader: Bootloader) -> Self {
+        self.bootloader = bootloader;
+        self
+    }
+
+    /// Set the boot partition
+    pub fn with_boot_dir<P: AsRef<Path>>(mut self, boot_dir: P) -> Self {
+        self.boot_dir = boot_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Set the kernel command line
+    pub fn with_cmdline<S: Into<String>>(mut self, cmdline: S) -> Self {
+        self.cmdline = Some(cmdline.into());
+        self
+    }
+
+    /// Keep this many kernels (the previous kernel is always kept as fallback)
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.boot_dir.join("loader/entries")
+    }
+
+    fn cmdline(&self) -> Result<String, KernelError> {
+        match &self.cmdline {
+            Some(cmdline) => Ok(cmdline.clone()),
+            None => Ok(filter_cmdline(&fs::read_to_string("/proc/cmdline")?)),
+        }
+    }
+
+    /// Install a kernel and refresh the boot menu
+    ///
+    /// `image` and `initramfs` are copied to the boot partition if they are
+    /// not already there. Returns the releases that were pruned.
+    pub fn install(&self, release: &str, image: &Path, initramfs: Option<&Path>) -> Result<Vec<String>, KernelError> {
+        fs::create_dir_all(&self.boot_dir)?;
+        copy_if_needed(image, &self.boot_dir.join(image_name(release)))?;
+        if let Some(initramfs) = initramfs {
+            copy_if_needed(initramfs, &self.boot_dir.join(initramfs_name(release)))?;
+        }
+        let mut managed = self.manifest()?;
+        if !managed.iter().any(|r| r == release) {
+            managed.push(release.to_string());
+            self.write_manifest(&managed)?;
+        }
+
+        if self.bootloader == Bootloader::SystemdBoot {
+            let entries = self.entries_dir();
+            fs::create_dir_all(&entries)?;
+            let entry = render_entry(release, &self.cmdline()?, initramfs.is_some());
+            fs::write(entries.join(entry_name(release)), entry)?;
+            info!("Created boot entry for {}", release);
+        }
+
+        let pruned = self.prune()?;
+
+        match self.bootloader {
+            Bootloader::SystemdBoot => self.set_default(release)?,
+            Bootloader::Grub => self.grub_mkconfig()?,
+        }
+        Ok(pruned)
+    }
+
+    /// Kernels installed by rastOS that are still on the boot partition, newest first
+    ///
+    /// Kernels from distribution packages or installed by hand are not
+    /// listed, so they are never pruned.
+    pub fn installed(&self) -> Result<Vec<String>, KernelError> {
+        let mut releases: Vec<String> = self
+            .manifest()?
+            .into_iter()
+            .filter(|release| self.boot_dir.join(image_name(release)).exists())
+            .collect();
+        releases.sort_by(|a, b| compare_releases(b, a));
+        Ok(releases)
+    }
+
+    /// Remove kernels beyond the configured count, oldest first
+    ///
+    /// The running kernel is kept even when it is beyond the count.
+    pub fn prune(&self) -> Result<Vec<String>, KernelError> {
+        let keep = self.keep.max(2);
+        let running = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let pruned: Vec<String> = self
+            .installed()?
+            .into_iter()
+            .skip(keep)
+            .filter(|release| release != running.trim())
+            .collect();
+        if pruned.is_empty() {
+            return Ok(pruned);
+        }
+
+        for release in &pruned {
+            info!("Removing old kernel {}", release);
+            for path in [
+                self.boot_dir.join(image_name(release)),
+                self.boot_dir.join(initramfs_name(release)),
+                self.entries_dir().join(entry_name(release)),
+            ] {
+                if path.exists() {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        let managed: Vec<String> = self.manifest()?.into_iter().filter(|r| !pruned.contains(r)).collect();
+        self.write_manifest(&managed)?;
+        Ok(pruned)
+    }
+
+    /// Releases recorded in the manifest
+    fn manifest(&self) -> Result<Vec<String>, KernelError> {
+        match fs::read_to_string(self.boot_dir.join(MANIFEST)) {
+            Ok(content) => Ok(content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_manifest(&self, releases: &[String]) -> Result<(), KernelError> {
+        let mut content = releases.join("\n");
+        content.push('\n');
+        fs::write(self.boot_dir.join(MANIFEST), content)?;
+        Ok(())
+    }
+
+    /// Make the given kernel the systemd-boot default
+    fn set_default(&self, release: &str) -> Result<(), KernelError> {
+        let loader_conf = self.boot_dir.join("loader/loader.conf");
+        let existing = fs::read_to_string(&loader_conf).unwrap_or_default();
+        let mut lines: Vec<String> = existing
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("default "))
+            .map(str::to_string)
+            .collect();
+        lines.insert(0, format!("default {}", entry_name(release)));
+        fs::write(&loader_conf, lines.join("\n") + "\n")?;
+        Ok(())
+    }
+
+    /// Entry booting the Btrfs subvolume `subvolume` through the snapshot-boot hook
+    ///
+    /// An existing entry for the subvolume, such as an update deployment's,
+    /// is reused. Otherwise the default entry is copied with
+    /// `rastos.snapshot=` added; it boots the default kernel, so the
+    /// snapshot must still have that kernel's modules.
+    pub fn snapshot_entry(&self, subvolume: &str) -> Result<String, KernelError> {
+        if self.bootloader != Bootloader::SystemdBoot {
+            return Err(KernelError::Unsupported("snapshot entries need systemd-boot".to_string()));
+        }
+        let entries = self.entries_dir();
+        let param = format!("rastos.snapshot={}", subvolume);
+        let mut names: Vec<String> = fs::read_dir(&entries)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".conf") && name != SNAPSHOT_ENTRY)
+            .collect();
+        names.sort();
+        for name in &names {
+            let entry = fs::read_to_string(entries.join(name))?;
+            if entry_options(&entry).is_some_and(|options| options.split_whitespace().any(|p| p == param)) {
+                return Ok(name.clone());
+            }
+        }
+
+        let default = self
+            .default_entry()
+            .filter(|name| names.contains(name))
+            .or_else(|| names.iter().find(|name| name.starts_with(ENTRY_PREFIX)).cloned())
+            .ok_or_else(|| KernelError::MissingFile(entries.join("*.conf")))?;
+        let base = fs::read_to_string(entries.join(&default))?;
+        let mut entry = format!("title   rastOS (snapshot {})\n", subvolume);
+        for line in base.lines() {
+            match line.split_once(char::is_whitespace).map(|(key, value)| (key, value.trim())) {
+                Some(("title", _)) => {}
+                Some(("options", options)) => {
+                    entry.push_str(&format!("options {}\n", snapshot_cmdline(options, subvolume)));
+                }
+                _ => entry.push_str(&format!("{}\n", line)),
+            }
+        }
+        if entry_options(&base).is_none() {
+            entry.push_str(&format!("options {}\n", snapshot_cmdline(&self.cmdline()?, subvolume)));
+        }
+        fs::write(entries.join(SNAPSHOT_ENTRY), entry)?;
+        info!("Created boot entry for snapshot {}", subvolume);
+        Ok(SNAPSHOT_ENTRY.to_string())
+    }
+
+    /// Boot `entry` on the next boot only
+    pub fn set_oneshot(&self, entry: &str) -> Result<(), KernelError> {
+        if self.bootloader != Bootloader::SystemdBoot {
+            return Err(KernelError::Unsupported("one-time boot entries need systemd-boot".to_string()));
+        }
+        if !self.entries_dir().join(entry).exists() {
+            return Err(KernelError::MissingFile(self.entries_dir().join(entry)));
+        }
+        let output = Command::new("bootctl").arg("set-oneshot").arg(entry).output()?;
+        if !output.status.success() {
+            return Err(KernelError::command_error("bootctl set-oneshot", &output));
+        }
+        Ok(())
+    }
+
+    /// The `default` of `loader.conf`
+    fn default_entry(&self) -> Option<String> {
+        fs::read_to_string(self.boot_dir.join("loader/loader.conf"))
+            .ok()?
+            .lines()
+            .find_map(|line| line.trim_start().strip_prefix("default ").map(|s| s.trim().to_string()))
+    }
+
+    fn grub_mkconfig(&self) -> Result<(), KernelError> {
+        debug!("Running: grub-mkconfig -o {}", self.grub_config.display());
+        let output = Command::new("grub-mkconfig")
+            .arg("-o")
+            .arg(&self.grub_config)
+            .output()?;
+        if !output.status.success() {
+            return Err(KernelError::command_error("grub-mkconfig", &output));
+        }
+        Ok(())
+    }
+}
+
+/// Kernel image file name on the boot partition
+pub fn image_name(release: &str) -> String {
+    format!("vmlinuz-{}", release)
+}
+
+/// Initramfs file name on the boot partition
+pub fn initramfs_name(release: &str) -> String {
+    format!("initramfs-{}.img", release)
+}
+
+fn entry_name(release: &str) -> String {
+    format!("{}{}.conf", ENTRY_PREFIX, release)
+}
+
+/// Render a Boot Loader Specification entry
+fn render_entry(release: &str, cmdline: &str, initramfs: bool) -> String {
+    let mut entry = format!(
+        "title   rastOS ({})\nversion {}\nlinux   /{}\n",
+        release,
+        release,
+        image_name(release)
+    );
+    if initramfs {
+        entry.push_str(&format!("initrd  /{}\n", initramfs_name(release)));
+    }
+    entry.push_str(&format!("options {}\n", cmdline));
+    entry
+}
+
+/// Drop parameters that describe the current boot rather than the system
+pub(crate) fn filter_cmdline(cmdline: &str) -> String {
+    cmdline
+        .split_whitespace()
+        .filter(|param| !TRANSIENT_PARAMS.iter().any(|prefix| param.starts_with(prefix)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Kernel command line booting `subvolume` through the snapshot-boot hook
+///
+/// The hook puts `subvol=` in front of the root flags, so an existing
+/// `subvol=` would win and is removed.
+pub(crate) fn snapshot_cmdline(cmdline: &str, subvolume: &str) -> String {
+    let mut params: Vec<String> = Vec::new();
+    for param in filter_cmdline(cmdline).split_whitespace() {
+        match param.strip_prefix("rootflags=") {
+            Some(flags) => {
+                let flags: Vec<_> = flags
+                    .split(',')
+                    .filter(|f| !f.starts_with("subvol=") && !f.starts_with("subvolid="))
+                    .collect();
+                if !flags.is_empty() {
+                    params.push(format!("rootflags={}", flags.join(",")));
+                }
+            }
+            None => params.push(param.to_string()),
+        }
+    }
+    params.push(format!("rastos.snapshot={}", subvolume));
+    params.join(" ")
+}
+
+/// The `options` line of a Boot Loader Specification entry
+fn entry_options(entry: &str) -> Option<&str> {
+    entry
+        .lines()
+        .find_map(|line| line.strip_prefix("options").filter(|rest| rest.starts_with(char::is_whitespace)))
+        .map(str::trim)
+}
+
+/// Order kernel releases like package versions (`6.10` after `6.9`)
+fn compare_releases(a: &str, b: &str) -> Ordering {
+    vercmp(&a.replace('-', "."), &b.replace('-', "."))
+}
+
+fn copy_if_needed(from: &Path, to: &Path) -> Result<(), KernelError> {
+    if !from.exists() {
+        return Err(KernelError::MissingFile(from.to_path_buf()));
+    }
+    if from.canonicalize()? != to.canonicalize().unwrap_or_default() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_filter_cmdline() {
+        assert_eq!(
+            filter_cmdline("BOOT_IMAGE=/vmlinuz-linux root=UUID=abc rw rastos.snapshot=@snap/3 quiet\n"),
+            "root=UUID=abc rw quiet"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_entry() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let config = BootEntryConfig::default().with_boot_dir(dir.path());
+        fs::create_dir_all(config.entries_dir())?;
+        fs::write(
+            config.entries_dir().join("rastos.conf"),
+            "title rastOS\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions root=UUID=abc rw rootflags=subvol=@,noatime\n",
+        )?;
+        fs::write(dir.path().join("loader/loader.conf"), "default rastos.conf\n")?;
+
+        assert_eq!(config.snapshot_entry("@snapshots/7")?, "rastos-snapshot.conf");
+        let entry = fs::read_to_string(config.entries_dir().join("rastos-snapshot.conf"))?;
+        assert!(entry.starts_with("title   rastOS (snapshot @snapshots/7)\nlinux /vmlinuz-linux\n"));
+        assert!(entry.ends_with("options root=UUID=abc rw rootflags=noatime rastos.snapshot=@snapshots/7\n"));
+
+        // Entries that already boot the subvolume are reused
+        fs::write(
+            config.entries_dir().join("rastos-deploy-1.conf"),
+            "title rastOS\noptions root=UUID=abc rastos.snapshot=@deployments/1\n",
+        )?;
+        assert_eq!(config.snapshot_entry("@deployments/1")?, "rastos-deploy-1.conf");
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_keep_fallback_and_prune() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let build = dir.path().join("build");
+        fs::create_dir_all(&build)?;
+        let config = BootEntryConfig::default()
+            .with_boot_dir(dir.path().join("esp"))
+            .with_cmdline("root=LABEL=rastos rw")
+            .with_keep(1);
+
+        // A kernel rastOS did not install is never touched
+        fs::create_dir_all(&config.boot_dir)?;
+        fs::write(config.boot_dir.join("vmlinuz-6.1.0-distro"), "distro")?;
+
+        for release in ["6.9.2-rastos", "6.10.1-rastos", "6.10.3-rastos"] {
+            let image = build.join(format!("bzImage-{}", release));
+            let initramfs = build.join(format!("initramfs-{}", release));
+            fs::write(&image, release)?;
+            fs::write(&initramfs, release)?;
+            config.install(release, &image, Some(&initramfs))?;
+        }
+
+        // keep is raised to 2 so the previous kernel stays as fallback
+        assert_eq!(config.installed()?, ["6.10.3-rastos", "6.10.1-rastos"]);
+        let entry = fs::read_to_string(config.entries_dir().join("rastos-6.10.3-rastos.conf"))?;
+        assert!(entry.contains("linux   /vmlinuz-6.10.3-rastos\n"));
+        assert!(entry.contains("initrd  /initramfs-6.10.3-rastos.img\n"));
+        assert!(entry.contains("options root=LABEL=rastos rw\n"));
+        assert!(!config.entries_dir().join("rastos-6.9.2-rastos.conf").exists());
+        assert!(config.boot_dir.join("vmlinuz-6.1.0-distro").exists());
+
+        let loader = fs::read_to_string(config.boot_dir.join("loader/loader.conf"))?;
+        assert_eq!(loader, "default rastos-6.10.3-rastos.conf\n");
+        Ok(())
+    }
+}
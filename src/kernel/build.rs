@@ -1,11 +1,27 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
 
-use indicatif::{ProgressBar, ProgressStyle};
-use log::debug;
+use log::{debug, info, warn};
+use tokio_util::sync::CancellationToken;
 
+use super::bootloader::BootEntryConfig;
+use super::cancel::{BuildControl, BuildProgress};
+use super::ccache::CompilerCache;
+use super::distributed::DistributedConfig;
+use super::incremental::{fingerprint_source, hash_inputs, BuildState};
+use super::initramfs::InitramfsConfig;
+use super::localmod::ModuleSet;
+use super::package::{package_headers, package_kernel, PackageFormat, PackageOptions};
+use super::patches::PatchSeries;
+use super::progress::{run_make, BuildLog};
+use super::config::{merge_kconfig, rastos_missing_symbols, KernelConfig, KASAN_CONFIG};
 use super::error::KernelError;
+use super::signing::{unsigned_modules, SigningConfig};
+use super::toolchain::{toolchain_kconfig, LtoMode, RustSupport, Toolchain};
 use crate::kernel::KernelProfile;
+use crate::progress::{Progress, Task, TerminalProgress};
 
 /// Builder for compiling Linux kernels
 #[derive(Debug)]
@@ -16,6 +32,21 @@ pub struct KernelBuilder {
     config_path: Option<PathBuf>,
     profile: KernelProfile,
     jobs: usize,
+    kasan: bool,
+    compiler_cache: CompilerCache,
+    initramfs: Option<InitramfsConfig>,
+    signing: SigningConfig,
+    patches: Option<PatchSeries>,
+    local_modules: Option<ModuleSet>,
+    boot_entries: Option<BootEntryConfig>,
+    force: bool,
+    toolchain: Toolchain,
+    lto: LtoMode,
+    rust: RustSupport,
+    control: BuildControl,
+    distributed: Option<DistributedConfig>,
+    make_args: Vec<String>,
+    reporter: Arc<dyn Progress>,
 }
 
 impl KernelBuilder {
@@ -32,6 +63,36 @@ impl KernelBuilder {
             config_path: None,
             profile: KernelProfile::default(),
             jobs: num_cpus::get(),
+            kasan: false,
+            compiler_cache: CompilerCache::default(),
+            initramfs: None,
+            signing: SigningConfig::default(),
+            patches: None,
+            local_modules: None,
+            boot_entries: None,
+            force: false,
+            toolchain: Toolchain::default(),
+            lto: LtoMode::default(),
+            rust: RustSupport::default(),
+            control: BuildControl::default(),
+            distributed: None,
+            make_args: Vec::new(),
+            reporter: Arc::new(TerminalProgress::new()),
+        }
+    }
+
+    /// Create a builder from a kernel configuration
+    pub fn from_config(config: &KernelConfig) -> Self {
+        Self {
+            source_dir: config.source_dir.clone(),
+            build_dir: config.build_dir.clone(),
+            install_dir: config.install_dir.clone(),
+            profile: config.profile,
+            jobs: config.jobs,
+            signing: config.signing.clone(),
+            distributed: config.distributed.clone(),
+            make_args: config.make_args.clone(),
+            ..Self::new(&config.source_dir)
         }
     }
 
@@ -53,50 +114,340 @@ impl KernelBuilder {
         self
     }
 
+    /// Enable the kernel address sanitizer (intended for development builds)
+    pub fn with_kasan(mut self, kasan: bool) -> Self {
+        self.kasan = kasan;
+        self
+    }
+
+    /// Set the compiler cache used for compilation
+    pub fn with_compiler_cache(mut self, cache: CompilerCache) -> Self {
+        self.compiler_cache = cache;
+        self
+    }
+
+    /// Generate an initramfs after installing the kernel
+    pub fn with_initramfs(mut self, config: InitramfsConfig) -> Self {
+        self.initramfs = Some(config);
+        self
+    }
+
+    /// Set module signing and Secure Boot options
+    pub fn with_signing(mut self, signing: SigningConfig) -> Self {
+        self.signing = signing;
+        self
+    }
+
+    /// Apply a patch series to the source before configuring
+    pub fn with_patches(mut self, patches: PatchSeries) -> Self {
+        self.patches = Some(patches);
+        self
+    }
+
+    /// Trim the config to the given modules (`make localmodconfig`)
+    pub fn with_local_modules(mut self, modules: ModuleSet) -> Self {
+        self.local_modules = Some(modules);
+        self
+    }
+
+    /// Create a boot entry for the new kernel after installing
+    pub fn with_boot_entries(mut self, config: BootEntryConfig) -> Self {
+        self.boot_entries = Some(config);
+        self
+    }
+
+    /// Select the compiler toolchain
+    pub fn with_toolchain(mut self, toolchain: Toolchain) -> Self {
+        self.toolchain = toolchain;
+        self
+    }
+
+    /// Enable link-time optimization (requires the LLVM toolchain)
+    pub fn with_lto(mut self, lto: LtoMode) -> Self {
+        self.lto = lto;
+        self
+    }
+
+    /// Control Rust support in the kernel
+    pub fn with_rust(mut self, rust: RustSupport) -> Self {
+        self.rust = rust;
+        self
+    }
+
+    /// Distribute compilation over distcc or icecream hosts
+    pub fn with_distributed(mut self, config: DistributedConfig) -> Self {
+        self.distributed = Some(config);
+        self
+    }
+
+    /// Stop the build when `token` is cancelled
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.control = self.control.with_token(token);
+        self
+    }
+
+    /// Stop the build if it runs longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.control = self.control.with_timeout(timeout);
+        self
+    }
+
+    /// Report progress to `reporter` instead of the terminal
+    pub fn with_progress(mut self, reporter: Arc<dyn Progress>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    /// Kernel source directory
+    pub fn source_dir(&self) -> &Path {
+        &self.source_dir
+    }
+
+    /// Stages of the running or last build
+    pub fn progress(&self) -> BuildProgress {
+        self.control.progress()
+    }
+
+    /// Redo every stage even if its inputs are unchanged
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Package the installed kernel
+    ///
+    /// Must run after [`build`](Self::build). Returns the created package
+    /// files; pacman packages can be installed with
+    /// `PackageManager::install_files`.
+    pub fn package(&self, options: &PackageOptions) -> Result<Vec<PathBuf>, KernelError> {
+        let release = self.kernel_release()?;
+        let output_dir = options.output_dir.clone().unwrap_or_else(|| self.build_dir.clone());
+        std::fs::create_dir_all(&output_dir)?;
+
+        let mut packages = Vec::new();
+        for format in &options.formats {
+            match format {
+                PackageFormat::Pacman => {
+                    let module_dir = self.install_dir.join("lib/modules").join(&release);
+                    if !module_dir.exists() {
+                        return Err(KernelError::MissingFile(module_dir));
+                    }
+                    let image = self.build_dir.join(self.image_name()?);
+                    packages.push(package_kernel(options, &release, &image, &module_dir, &output_dir)?);
+                    if options.headers {
+                        packages.push(package_headers(options, &release, &self.source_dir, &self.build_dir, &output_dir)?);
+                    }
+                }
+                PackageFormat::Deb => packages.extend(self.package_deb(options, &output_dir)?),
+            }
+        }
+
+        for package in &packages {
+            info!("Created package {}", package.display());
+        }
+        Ok(packages)
+    }
+
+    /// Build Debian packages with kbuild's `bindeb-pkg` target
+    fn package_deb(&self, options: &PackageOptions, output_dir: &Path) -> Result<Vec<PathBuf>, KernelError> {
+        let release = self.kernel_release()?;
+        let started = std::time::SystemTime::now();
+        self.run_command(
+            "make",
+            &[
+                "-C",
+                self.source_dir.to_str().unwrap(),
+                &self.build_dir_arg(),
+                &format!("-j{}", self.jobs),
+                &format!("KDEB_PKGVERSION={}", options.pkgrel),
+                "bindeb-pkg",
+            ],
+        )?;
+
+        // kbuild writes the packages next to the build directory, which may
+        // hold packages of other builds too: only take this release's, and
+        // the release-less linux-libc-dev only if this run wrote it
+        let parent = self.build_dir.parent().unwrap_or(Path::new("."));
+        let mut packages = Vec::new();
+        for entry in std::fs::read_dir(parent)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let ours = name.contains(&format!("-{}_", release))
+                || name.contains(&format!("-{}-dbg_", release))
+                || (name.starts_with("linux-libc-dev_") && entry.metadata()?.modified()? >= started);
+            if path.extension().is_some_and(|ext| ext == "deb") && ours {
+                let target = output_dir.join(path.file_name().unwrap());
+                std::fs::rename(&path, &target)?;
+                packages.push(target);
+            }
+        }
+        if !options.headers {
+            packages.retain(|p| !p.to_string_lossy().contains("linux-headers"));
+        }
+        Ok(packages)
+    }
+
+    /// Path of the kernel image relative to the build directory (`make image_name`)
+    fn image_name(&self) -> Result<String, KernelError> {
+        let output = Command::new("make")
+            .args(self.toolchain.make_args())
+            .args(["-s", "-C"])
+            .arg(&self.source_dir)
+            .arg(self.build_dir_arg())
+            .arg("image_name")
+            .output()?;
+
+        if !output.status.success() {
+            return Err(KernelError::command_error("make image_name", &output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Structured log of the last compile, including warnings and errors
+    pub fn build_log(&self) -> Result<BuildLog, KernelError> {
+        BuildLog::load(&self.build_dir)
+    }
+
     /// Build the kernel
     pub async fn build(&self) -> Result<(), KernelError> {
-        self.prepare_build_dir()?;
-        self.configure()?;
-        self.compile()?;
+        self.compile_image()?;
+
+        self.control.enter("install")?;
         self.install()?;
+        self.control.finish();
+
+        self.control.enter("sign")?;
+        self.sign()?;
+        self.control.finish();
+
+        self.control.enter("initramfs")?;
+        self.generate_initramfs()?;
+        self.control.finish();
+
+        self.control.enter("boot entries")?;
+        self.update_boot_entries()?;
+        self.control.finish();
         Ok(())
     }
 
-    fn prepare_build_dir(&self) -> Result<(), KernelError> {
+    /// Run the prepare, patch, configure and compile stages only
+    ///
+    /// Nothing is installed and no boot entries are touched. Returns the
+    /// path of the kernel image in the build directory.
+    pub fn compile_image(&self) -> Result<PathBuf, KernelError> {
+        self.control.start();
+        self.toolchain.validate(self.lto)?;
+
+        self.control.enter("prepare")?;
+        let mut state = self.prepare_build_dir()?;
+        self.control.finish();
+
+        self.control.enter("patch")?;
+        let patches = match &self.patches {
+            Some(series) if !series.is_empty() => Some(series.fingerprint()?),
+            _ => None,
+        };
+        if self.force || patches != state.patches {
+            self.apply_patches()?;
+            state.patches = patches;
+            state.save(&self.build_dir)?;
+        } else if patches.is_some() {
+            info!("Patch series unchanged, skipping");
+        }
+        self.control.finish();
+
+        self.control.enter("configure")?;
+        let source = fingerprint_source(&self.source_dir, &[&self.build_dir, &self.install_dir])?;
+        let config_file = self.build_dir.join(".config");
+        let configure = hash_inputs([
+            self.config_input()?,
+            self.local_modules.as_ref().map(ModuleSet::to_lsmod).unwrap_or_default(),
+            source.clone(),
+        ]);
+        if self.force || state.configure.as_ref() != Some(&configure) || !config_file.exists() {
+            self.configure()?;
+            state.configure = Some(configure);
+            state.compile = None;
+            state.save(&self.build_dir)?;
+        } else {
+            info!("Configuration inputs unchanged, skipping configure");
+        }
+        self.control.finish();
+
+        self.control.enter("compile")?;
+        let compile = hash_inputs([std::fs::read_to_string(&config_file)?, source, self.make_args.join(" ")]);
+        if self.force || state.compile.as_ref() != Some(&compile) || !self.build_dir.join("vmlinux").exists() {
+            self.compile()?;
+            state.compile = Some(compile);
+            state.save(&self.build_dir)?;
+        } else {
+            info!("Sources and config unchanged, skipping compile");
+        }
+        self.control.finish();
+
+        Ok(self.build_dir.join(self.image_name()?))
+    }
+
+    /// Create the build directories and load the state of previous builds
+    fn prepare_build_dir(&self) -> Result<BuildState, KernelError> {
         if !self.source_dir.exists() {
             return Err(KernelError::MissingFile(self.source_dir.clone()));
         }
 
+        let mut state = BuildState::load(&self.build_dir);
+        if state.belongs_to_other(&self.source_dir) {
+            warn!(
+                "Build directory {} was used for another source tree, starting over",
+                self.build_dir.display()
+            );
+            std::fs::remove_dir_all(&self.build_dir)?;
+            state = BuildState::default();
+        }
+
         std::fs::create_dir_all(&self.build_dir)?;
         std::fs::create_dir_all(&self.install_dir)?;
 
+        state.source_dir = Some(self.source_dir.clone());
+        Ok(state)
+    }
+
+    fn apply_patches(&self) -> Result<(), KernelError> {
+        let Some(series) = self.patches.as_ref().filter(|s| !s.is_empty()) else {
+            // Drop patches applied by an earlier build with a series
+            let reversed = super::patches::reverse(&self.source_dir)?;
+            if !reversed.is_empty() {
+                info!("Reversed patches no longer in the series: {}", reversed.join(", "));
+            }
+            return Ok(());
+        };
+
+        let task = Task::start(&*self.reporter, "Applying patches", None);
+
+        let report = series.apply(&self.source_dir)?;
+        if !report.skipped.is_empty() {
+            warn!("Skipped patches that did not apply: {}", report.skipped.join(", "));
+        }
+
+        task.finish(format!("Applied {} patches", report.applied.len()));
         Ok(())
     }
 
     fn configure(&self) -> Result<(), KernelError> {
-        let pb = ProgressBar::new_spinner();
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-                .template("{spinner:.blue} {msg}")
-                .unwrap(),
-        );
-        pb.set_message("Configuring kernel...");
-        pb.enable_steady_tick(std::time::Duration::from_millis(100));
-
-        // Copy config if provided, otherwise use default
-        if let Some(config_path) = &self.config_path {
-            std::fs::copy(
-                config_path,
-                self.source_dir.join(".config"),
-            )?;
-        } else {
-            // Generate default config based on profile
-            let config = match self.profile {
-                KernelProfile::ContainerHost => include_str!("../../configs/linux-container.config"),
-                _ => include_str!("../../configs/linux-container.config"), // TODO: Add other profiles
-            };
-            std::fs::write(self.source_dir.join(".config"), config)?;
+        let task = Task::start(&*self.reporter, "Configuring kernel", None);
+
+        // Out-of-tree builds read the config from the build directory
+        let config_file = self.build_dir.join(".config");
+
+        let signing = self.signing();
+        if signing.sign_modules {
+            signing.keys().ensure()?;
+        }
+        std::fs::write(&config_file, self.config_input()?)?;
+
+        if let Some(modules) = &self.local_modules {
+            task.message(format!("Trimming config to {} modules...", modules.len()));
+            self.localmodconfig(modules)?;
         }
 
         // Run olddefconfig to set defaults for new options
@@ -105,45 +456,175 @@ impl KernelBuilder {
             &[
                 "-C",
                 self.source_dir.to_str().unwrap(),
-                "O=build",
+                &self.build_dir_arg(),
                 "olddefconfig",
             ],
         )?;
 
-        pb.finish_with_message("✓ Configuration complete");
+        // Kconfig silently drops options the source tree does not support
+        if let Err(e) = self.validate_config() {
+            task.fail(&e);
+            return Err(e);
+        }
+
+        task.finish("Configuration complete");
+        Ok(())
+    }
+
+    /// Check the final `.config` in the build directory
+    ///
+    /// Every config must provide the rastOS base options; profile baselines
+    /// must also keep their profile-specific options. Custom configs are only
+    /// held to the base requirements.
+    pub fn validate_config(&self) -> Result<(), KernelError> {
+        let config = std::fs::read_to_string(self.build_dir.join(".config"))?;
+        let missing = match self.config_path {
+            Some(_) => rastos_missing_symbols(&config),
+            None => self.profile.missing_symbols(&config),
+        };
+        if !missing.is_empty() {
+            return Err(KernelError::MissingSymbols(missing));
+        }
+        Ok(())
+    }
+
+    /// Config written before olddefconfig: the custom config or profile
+    /// baseline with toolchain options, pointed at the managed signing key
+    /// if modules are signed
+    fn config_input(&self) -> Result<String, KernelError> {
+        let mut config = match &self.config_path {
+            Some(config_path) => std::fs::read_to_string(config_path)?,
+            None => self.profile_config(),
+        };
+
+        let overrides = toolchain_kconfig(self.lto, self.rust_enabled()?);
+        if !overrides.is_empty() {
+            config = merge_kconfig(&config, &overrides);
+        }
+
+        let signing = self.signing();
+        if signing.sign_modules {
+            let keys = signing.keys();
+            config = merge_kconfig(&config, &keys.kconfig(self.profile == KernelProfile::Production));
+        }
+        Ok(config)
+    }
+
+    /// Whether to build Rust support, checking the toolchain can provide it
+    fn rust_enabled(&self) -> Result<bool, KernelError> {
+        match self.rust {
+            RustSupport::Off => Ok(false),
+            RustSupport::Auto => Ok(self.toolchain == Toolchain::Llvm && self.toolchain.rust_available(&self.source_dir)),
+            RustSupport::On if self.toolchain.rust_available(&self.source_dir) => Ok(true),
+            RustSupport::On => Err(KernelError::Unsupported(
+                "Rust support requires the LLVM toolchain and a usable Rust toolchain (make rustavailable)".to_string(),
+            )),
+        }
+    }
+
+    /// Disable modules not in `modules`, accepting defaults for new options
+    fn localmodconfig(&self, modules: &ModuleSet) -> Result<(), KernelError> {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let lsmod = self.build_dir.join("lsmod.txt");
+        std::fs::write(&lsmod, modules.to_lsmod())?;
+
+        let mut child = Command::new("make")
+            .args(self.toolchain.make_args())
+            .arg("-C")
+            .arg(&self.source_dir)
+            .arg(self.build_dir_arg())
+            .arg(format!("LSMOD={}", lsmod.display()))
+            .arg("localmodconfig")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Equivalent of `yes "" |`: answer every prompt with its default
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let feeder = std::thread::spawn(move || while stdin.write_all(b"\n").is_ok() {});
+
+        let output = child.wait_with_output()?;
+        let _ = feeder.join();
+        if !output.status.success() {
+            return Err(KernelError::command_error("make localmodconfig", &output));
+        }
         Ok(())
     }
 
+    /// Baseline config for the selected profile and options
+    fn profile_config(&self) -> String {
+        let config = self.profile.config();
+        if !self.kasan {
+            return config;
+        }
+        if self.profile != KernelProfile::Development {
+            warn!("KASAN enabled for the {:?} profile", self.profile);
+        }
+        merge_kconfig(&config, KASAN_CONFIG)
+    }
+
+    /// Signing settings in effect (Production always signs modules)
+    fn signing(&self) -> SigningConfig {
+        self.signing.clone().for_profile(self.profile)
+    }
+
+    /// `O=` argument pointing make at the build directory
+    fn build_dir_arg(&self) -> String {
+        format!("O={}", self.build_dir.display())
+    }
+
     fn compile(&self) -> Result<(), KernelError> {
-        let pb = ProgressBar::new_spinner();
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .tick_strings(&["⣾", "⣽", "⣻", "⢿", "⡿", "⣟", "⣯", "⣷"])
-                .template("{spinner:.green} {msg}")
-                .unwrap(),
-        );
-        pb.set_message("Compiling kernel...");
-        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        let cache = self.compiler_cache.resolve();
+        cache.zero_stats()?;
+
+        let distributed = match &self.distributed {
+            Some(config) => config.plan(self.toolchain.compiler(), cache)?,
+            None => None,
+        };
+        let jobs = distributed.as_ref().map_or(self.jobs, |plan| plan.jobs);
+        if distributed.is_some() {
+            info!("Distributing compilation over {} job slots", jobs);
+        }
 
         // Build the kernel
-        self.run_command(
-            "make",
-            &[
-                "-C",
-                self.source_dir.to_str().unwrap(),
-                "O=build",
-                &format!("-j{}", self.jobs),
-                "all",
-            ],
-        )?;
+        let mut args = vec![
+            "-C".to_string(),
+            self.source_dir.to_string_lossy().into_owned(),
+            self.build_dir_arg(),
+            format!("-j{}", jobs),
+        ];
+        args.extend(self.toolchain.make_args());
+        args.extend(cache.make_args(self.toolchain.compiler()));
+        let env = match distributed {
+            Some(plan) => {
+                args.extend(plan.make_args);
+                plan.env
+            }
+            None => Vec::new(),
+        };
+        args.extend(self.make_args.iter().cloned());
+        args.push("all".to_string());
+        run_make(&args, &env, &self.source_dir, &self.build_dir, &self.control, &*self.reporter)?;
 
-        pb.finish_with_message("✓ Kernel compiled successfully");
+        match cache.stats() {
+            Ok(Some(stats)) => info!(
+                "{:?}: {} hits, {} misses ({:.1}% hit rate)",
+                cache,
+                stats.hits,
+                stats.misses,
+                stats.hit_rate() * 100.0
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("Could not read compiler cache statistics: {}", e),
+        }
         Ok(())
     }
 
     fn install(&self) -> Result<(), KernelError> {
-        let pb = ProgressBar::new_spinner();
-        pb.set_message("Installing kernel...");
+        let task = Task::start(&*self.reporter, "Installing kernel", None);
 
         // Install kernel modules
         self.run_command(
@@ -151,7 +632,7 @@ impl KernelBuilder {
             &[
                 "-C",
                 self.source_dir.to_str().unwrap(),
-                "O=build",
+                &self.build_dir_arg(),
                 &format!("INSTALL_MOD_PATH={}", self.install_dir.display()),
                 "modules_install",
             ],
@@ -163,23 +644,132 @@ impl KernelBuilder {
             &[
                 "-C",
                 self.source_dir.to_str().unwrap(),
-                "O=build",
+                &self.build_dir_arg(),
                 &format!("INSTALL_PATH={}/boot", self.install_dir.display()),
                 "install",
             ],
         )?;
 
-        pb.finish_with_message("✓ Kernel installed successfully");
+        task.finish("Kernel installed successfully");
+        Ok(())
+    }
+
+    /// Verify module signatures, sign the kernel image and enroll the key
+    fn sign(&self) -> Result<(), KernelError> {
+        let signing = self.signing();
+        if !signing.is_enabled() {
+            return Ok(());
+        }
+
+        let keys = signing.keys();
+        keys.ensure()?;
+
+        if signing.sign_modules {
+            let unsigned = unsigned_modules(&self.install_dir.join("lib/modules"))?;
+            if !unsigned.is_empty() {
+                return Err(KernelError::BuildFailed(format!(
+                    "{} installed modules are unsigned (first: {})",
+                    unsigned.len(),
+                    unsigned[0].display()
+                )));
+            }
+        }
+
+        if signing.secure_boot {
+            let image = self.installed_image(&self.kernel_release()?)?;
+            info!("Signing {} for Secure Boot", image.display());
+            keys.sign_image(&image)?;
+        }
+
+        if signing.enroll_mok {
+            info!("Queueing {} for MOK enrollment", keys.cert_der().display());
+            keys.enroll_mok()?;
+        }
+        Ok(())
+    }
+
+    fn generate_initramfs(&self) -> Result<(), KernelError> {
+        let Some(config) = &self.initramfs else {
+            return Ok(());
+        };
+
+        let task = Task::start(&*self.reporter, "Generating initramfs", None);
+
+        let release = self.kernel_release()?;
+        let output = self
+            .install_dir
+            .join("boot")
+            .join(format!("initramfs-{}.img", release));
+        config.generate(&release, &self.install_dir, &output)?;
+
+        task.finish(format!("Initramfs written to {}", output.display()));
+        Ok(())
+    }
+
+    fn update_boot_entries(&self) -> Result<(), KernelError> {
+        let Some(config) = &self.boot_entries else {
+            return Ok(());
+        };
+
+        let release = self.kernel_release()?;
+        let image = self.installed_image(&release)?;
+        let initramfs = self.installed_initramfs(&release);
+        let pruned = config.install(&release, &image, initramfs.as_deref())?;
+        if !pruned.is_empty() {
+            info!("Pruned old kernels: {}", pruned.join(", "));
+        }
         Ok(())
     }
 
+    /// Kernel image installed by `make install`
+    fn installed_image(&self, release: &str) -> Result<PathBuf, KernelError> {
+        let boot = self.install_dir.join("boot");
+        [format!("vmlinuz-{}", release), "vmlinuz".to_string()]
+            .into_iter()
+            .map(|name| boot.join(name))
+            .find(|path| path.exists())
+            .ok_or_else(|| KernelError::MissingFile(boot.join(format!("vmlinuz-{}", release))))
+    }
+
+    /// Initramfs generated for the release, if any
+    fn installed_initramfs(&self, release: &str) -> Option<PathBuf> {
+        let path = self
+            .install_dir
+            .join("boot")
+            .join(format!("initramfs-{}.img", release));
+        path.exists().then_some(path)
+    }
+
+    /// Release string of the configured kernel (`make kernelrelease`)
+    fn kernel_release(&self) -> Result<String, KernelError> {
+        let output = Command::new("make")
+            .args(self.toolchain.make_args())
+            .args(["-s", "-C"])
+            .arg(&self.source_dir)
+            .arg(self.build_dir_arg())
+            .arg("kernelrelease")
+            .output()?;
+
+        if !output.status.success() {
+            return Err(KernelError::command_error("make kernelrelease", &output));
+        }
+
+        let release = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if release.is_empty() {
+            return Err(KernelError::BuildFailed("Could not determine kernel release".to_string()));
+        }
+        Ok(release)
+    }
+
     fn run_command(&self, program: &str, args: &[&str]) -> Result<(), KernelError> {
         debug!("Running: {} {}", program, args.join(" "));
-        
-        let output = Command::new(program)
-            .args(args)
-            .output()
-            .map_err(|e| KernelError::Io(e))?;
+
+        let mut command = Command::new(program);
+        command.args(args);
+        if program == "make" {
+            command.args(self.toolchain.make_args());
+        }
+        let output = self.control.run(&mut command)?;
 
         if !output.status.success() {
             return Err(KernelError::command_error(program, &output));
@@ -211,4 +801,27 @@ mod tests {
         temp_dir.close()?;
         Ok(())
     }
+
+    #[test]
+    fn test_kasan_config() {
+        let builder = KernelBuilder::new("/usr/src/linux").with_profile(KernelProfile::Development);
+        assert!(builder.profile_config().contains("# CONFIG_KASAN is not set"));
+
+        let builder = builder.with_kasan(true);
+        let config = builder.profile_config();
+        assert!(config.contains("CONFIG_KASAN=y"));
+        assert!(config.contains("CONFIG_LOCKDEP=y"));
+    }
+
+    #[test]
+    fn test_signing_from_config() {
+        let config = KernelConfig::new(PathBuf::from("/usr/src/linux"))
+            .with_profile(KernelProfile::Production)
+            .with_signing(SigningConfig::default().with_secure_boot(true));
+        let builder = KernelBuilder::from_config(&config);
+        let signing = builder.signing();
+        assert!(signing.sign_modules);
+        assert!(signing.secure_boot);
+        assert_eq!(builder.install_dir, PathBuf::from("/boot"));
+    }
 }
@@ -0,0 +1,254 @@
+//! Build cancellation and timeouts
+//!
+//! Long-running build commands are started in their own process group and
+//! polled instead of waited on, so a cancelled token or an expired deadline
+//! can stop the whole `make` tree. Stages only record their state after
+//! finishing, which leaves an interrupted build directory resumable: the
+//! next build redoes the interrupted stage and kbuild picks up where it
+//! stopped.
+
+use std::fmt;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use tokio_util::sync::CancellationToken;
+
+use super::error::KernelError;
+
+/// Poll interval for running commands
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time given to make to exit after SIGTERM before it is killed
+const TERMINATE_GRACE: Duration = Duration::from_secs(10);
+
+/// Why a build stopped early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptReason {
+    /// The cancellation token was cancelled
+    Cancelled,
+    /// The build exceeded its timeout
+    TimedOut,
+}
+
+impl fmt::Display for InterruptReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// Stages of the current build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// Stage currently running
+    pub stage: Option<&'static str>,
+    /// Stages finished so far
+    pub completed: Vec<&'static str>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    progress: BuildProgress,
+    deadline: Option<Instant>,
+}
+
+/// Cancellation, timeout and stage tracking for a build
+#[derive(Debug, Clone, Default)]
+pub struct BuildControl {
+    token: Option<CancellationToken>,
+    timeout: Option<Duration>,
+    state: Arc<Mutex<ControlState>>,
+}
+
+impl BuildControl {
+    /// Stop the build when `token` is cancelled
+    pub fn with_token(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Stop the build after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Start tracking a new build
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.progress = BuildProgress::default();
+        state.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Enter a stage, failing if the build was interrupted
+    pub fn enter(&self, stage: &'static str) -> Result<(), KernelError> {
+        self.state.lock().unwrap().progress.stage = Some(stage);
+        self.check()
+    }
+
+    /// Mark the current stage as finished
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stage) = state.progress.stage.take() {
+            state.progress.completed.push(stage);
+        }
+    }
+
+    /// Stages of the current or last build
+    pub fn progress(&self) -> BuildProgress {
+        self.state.lock().unwrap().progress.clone()
+    }
+
+    /// Why the build should stop, if it should
+    pub fn interrupt_reason(&self) -> Option<InterruptReason> {
+        if self.token.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return Some(InterruptReason::Cancelled);
+        }
+        let deadline = self.state.lock().unwrap().deadline;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(InterruptReason::TimedOut);
+        }
+        None
+    }
+
+    /// Fail if the build was cancelled or timed out
+    pub fn check(&self) -> Result<(), KernelError> {
+        match self.interrupt_reason() {
+            Some(reason) => Err(self.interrupted(reason)),
+            None => Ok(()),
+        }
+    }
+
+    fn interrupted(&self, reason: InterruptReason) -> KernelError {
+        let progress = self.progress();
+        KernelError::Interrupted {
+            reason,
+            stage: progress.stage.unwrap_or("startup").to_string(),
+            completed: progress.completed.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Spawn a command in its own process group
+    pub fn spawn(&self, command: &mut Command) -> Result<Child, KernelError> {
+        self.check()?;
+        Ok(command.process_group(0).spawn()?)
+    }
+
+    /// Check whether a child has exited, terminating it if the build was interrupted
+    pub fn poll(&self, child: &mut Child) -> Result<Option<ExitStatus>, KernelError> {
+        if let Some(reason) = self.interrupt_reason() {
+            warn!("Build {}, stopping process group {}", reason, child.id());
+            terminate(child);
+            return Err(self.interrupted(reason));
+        }
+        Ok(child.try_wait()?)
+    }
+
+    /// Wait for a child, terminating it if the build is interrupted
+    pub fn wait(&self, child: &mut Child) -> Result<ExitStatus, KernelError> {
+        loop {
+            if let Some(status) = self.poll(child)? {
+                return Ok(status);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Run a command to completion, capturing its output
+    pub fn run(&self, command: &mut Command) -> Result<Output, KernelError> {
+        let mut child = self.spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let stdout = collect(child.stdout.take());
+        let stderr = collect(child.stderr.take());
+
+        let status = self.wait(&mut child);
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        Ok(Output {
+            status: status?,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+fn collect<R: Read + Send + 'static>(reader: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut reader) = reader {
+            let _ = reader.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Stop a process group: SIGTERM first, SIGKILL after a grace period
+fn terminate(child: &mut Child) {
+    let group = Pid::from_raw(child.id() as i32);
+    let _ = killpg(group, Signal::SIGTERM);
+
+    let deadline = Instant::now() + TERMINATE_GRACE;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let _ = killpg(group, Signal::SIGKILL);
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_running_command() {
+        let token = CancellationToken::new();
+        let control = BuildControl::default().with_token(token.clone());
+        control.start();
+        control.enter("configure").unwrap();
+        control.finish();
+        control.enter("compile").unwrap();
+
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                token.cancel();
+            })
+        };
+        let started = Instant::now();
+        let err = control.run(Command::new("sleep").arg("30")).unwrap_err();
+        canceller.join().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        match err {
+            KernelError::Interrupted { reason, stage, completed } => {
+                assert_eq!(reason, InterruptReason::Cancelled);
+                assert_eq!(stage, "compile");
+                assert_eq!(completed, vec!["configure".to_string()]);
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let control = BuildControl::default().with_timeout(Duration::ZERO);
+        control.start();
+        assert!(matches!(
+            control.enter("configure"),
+            Err(KernelError::Interrupted { reason: InterruptReason::TimedOut, .. })
+        ));
+        assert!(control.run(&mut Command::new("true")).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Compiler cache support for kernel builds
+//!
+//! Wraps the compiler with ccache or sccache so that rebuilds after small
+//! config changes only recompile what actually changed, and reads the cache
+//! statistics back after the build.
+
+use std::process::Command;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+
+/// Compiler cache used for kernel builds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum CompilerCache {
+    /// Use ccache or sccache if installed
+    #[default]
+    Auto,
+    /// Use ccache
+    Ccache,
+    /// Use sccache
+    Sccache,
+    /// Compile without a cache
+    None,
+}
+
+/// Hit and miss counters of a compiler cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Compilations served from the cache
+    pub hits: u64,
+    /// Compilations that had to run the compiler
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Fraction of cacheable compilations served from the cache
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+impl CompilerCache {
+    /// Resolve `Auto` to an installed cache, if any
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Auto => [Self::Sccache, Self::Ccache]
+                .into_iter()
+                .find(|cache| cache.is_installed())
+                .unwrap_or(Self::None),
+            other => other,
+        }
+    }
+
+    /// Executable name of the cache
+    pub fn program(&self) -> Option<&'static str> {
+        match self {
+            Self::Ccache => Some("ccache"),
+            Self::Sccache => Some("sccache"),
+            Self::Auto | Self::None => None,
+        }
+    }
+
+    /// Whether the cache executable is available
+    pub fn is_installed(&self) -> bool {
+        self.program().is_some_and(|program| {
+            Command::new(program)
+                .arg("--version")
+                .output()
+                .map(|o| o.status.success())
+                .unwrap_or(false)
+        })
+    }
+
+    /// make variables routing compilations through the cache
+    pub fn make_args(&self, compiler: &str) -> Vec<String> {
+        match self.program() {
+            Some(program) => vec![
+                format!("CC={} {}", program, compiler),
+                format!("HOSTCC={} {}", program, compiler),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// Reset the statistics before a build
+    pub fn zero_stats(&self) -> Result<(), KernelError> {
+        if let Some(program) = self.program() {
+            let output = Command::new(program).arg("--zero-stats").output()?;
+            if !output.status.success() {
+                return Err(KernelError::command_error(format!("{} --zero-stats", program), &output));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the statistics accumulated since the last reset
+    pub fn stats(&self) -> Result<Option<CacheStats>, KernelError> {
+        let (program, args): (&str, &[&str]) = match self {
+            Self::Ccache => ("ccache", &["--print-stats"]),
+            Self::Sccache => ("sccache", &["--show-stats", "--stats-format=json"]),
+            Self::Auto | Self::None => return Ok(None),
+        };
+
+        let output = Command::new(program).args(args).output()?;
+        if !output.status.success() {
+            return Err(KernelError::command_error(program, &output));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(match self {
+            Self::Ccache => Some(parse_ccache_stats(&stdout)),
+            _ => parse_sccache_stats(&stdout),
+        })
+    }
+}
+
+/// Parse `ccache --print-stats` output (tab separated key/value lines)
+fn parse_ccache_stats(output: &str) -> CacheStats {
+    let mut stats = CacheStats::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once('\t') else {
+            continue;
+        };
+        let value: u64 = value.trim().parse().unwrap_or(0);
+        match key.trim() {
+            "direct_cache_hit" | "preprocessed_cache_hit" => stats.hits += value,
+            "cache_miss" => stats.misses += value,
+            _ => {}
+        }
+    }
+    stats
+}
+
+/// Parse `sccache --show-stats --stats-format=json` output
+fn parse_sccache_stats(output: &str) -> Option<CacheStats> {
+    let json: serde_json::Value = serde_json::from_str(output).ok()?;
+    let sum = |key: &str| {
+        json["stats"][key]["counts"]
+            .as_object()
+            .map(|counts| counts.values().filter_map(|v| v.as_u64()).sum())
+            .unwrap_or(0)
+    };
+    Some(CacheStats {
+        hits: sum("cache_hits"),
+        misses: sum("cache_misses"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_args() {
+        assert_eq!(
+            CompilerCache::Ccache.make_args("gcc"),
+            vec!["CC=ccache gcc".to_string(), "HOSTCC=ccache gcc".to_string()]
+        );
+        assert!(CompilerCache::None.make_args("gcc").is_empty());
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let ccache = parse_ccache_stats("stats_updated_timestamp\t0\ndirect_cache_hit\t120\npreprocessed_cache_hit\t30\ncache_miss\t50\n");
+        assert_eq!(ccache, CacheStats { hits: 150, misses: 50 });
+        assert_eq!(ccache.hit_rate(), 0.75);
+
+        let sccache = parse_sccache_stats(
+            r#"{"stats": {"cache_hits": {"counts": {"C/C++": 10}}, "cache_misses": {"counts": {"C/C++": 5, "ASM": 5}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(sccache, CacheStats { hits: 10, misses: 10 });
+    }
+}
@@ -1,10 +1,14 @@
 //! Kernel configuration management
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use thiserror::Error;
 use clap::ValueEnum;
 
+use super::distributed::DistributedConfig;
+use super::signing::SigningConfig;
+
 /// Represents a kernel configuration profile
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
 pub enum KernelProfile {
@@ -22,6 +26,226 @@ impl Default for KernelProfile {
     }
 }
 
+/// Container-optimized baseline shared by all profiles
+const CONTAINER_CONFIG: &str = include_str!("../../configs/linux-container.config");
+
+/// Development overrides applied on top of the baseline
+const DEVELOPMENT_CONFIG: &str = include_str!("../../configs/linux-development.config");
+
+/// Production overrides applied on top of the baseline
+const PRODUCTION_CONFIG: &str = include_str!("../../configs/linux-production.config");
+
+/// Kernel address sanitizer overrides, optional for development builds
+pub const KASAN_CONFIG: &str = "CONFIG_KASAN=y\nCONFIG_KASAN_GENERIC=y\nCONFIG_KASAN_INLINE=y\nCONFIG_STACKTRACE=y\n";
+
+/// Options rastOS itself depends on, for every profile and custom config
+///
+/// Each symbol must have one of the listed values.
+const RASTOS_SYMBOLS: &[(&str, &[&str])] = &[
+    // Root filesystem and snapshots
+    ("CONFIG_BTRFS_FS", &["y", "m"]),
+    // Container layers
+    ("CONFIG_OVERLAY_FS", &["y", "m"]),
+    // Namespaces
+    ("CONFIG_NAMESPACES", &["y"]),
+    ("CONFIG_UTS_NS", &["y"]),
+    ("CONFIG_IPC_NS", &["y"]),
+    ("CONFIG_USER_NS", &["y"]),
+    ("CONFIG_PID_NS", &["y"]),
+    ("CONFIG_NET_NS", &["y"]),
+    // cgroups v2 controllers
+    ("CONFIG_CGROUPS", &["y"]),
+    ("CONFIG_CGROUP_SCHED", &["y"]),
+    ("CONFIG_MEMCG", &["y"]),
+    ("CONFIG_CPUSETS", &["y"]),
+    ("CONFIG_CGROUP_BPF", &["y"]),
+    // Syscall filtering
+    ("CONFIG_SECCOMP", &["y"]),
+    ("CONFIG_SECCOMP_FILTER", &["y"]),
+];
+
+/// Symbols every development config must set
+const DEVELOPMENT_SYMBOLS: &[(&str, Option<&str>)] = &[
+    ("CONFIG_DEBUG_KERNEL", Some("y")),
+    ("CONFIG_DEBUG_INFO", Some("y")),
+    ("CONFIG_LOCKDEP", Some("y")),
+    ("CONFIG_PROVE_LOCKING", Some("y")),
+    ("CONFIG_DEBUG_FS", Some("y")),
+    ("CONFIG_FTRACE", Some("y")),
+    ("CONFIG_MODULES", Some("y")),
+];
+
+/// Symbols every production config must set
+const PRODUCTION_SYMBOLS: &[(&str, Option<&str>)] = &[
+    ("CONFIG_MODULES", Some("y")),
+    ("CONFIG_MODULE_SIG", Some("y")),
+    ("CONFIG_MODULE_SIG_FORCE", Some("y")),
+    ("CONFIG_MODULE_SIG_ALL", Some("y")),
+    ("CONFIG_SECURITY_LOCKDOWN_LSM", Some("y")),
+    ("CONFIG_HARDENED_USERCOPY", Some("y")),
+    ("CONFIG_FORTIFY_SOURCE", Some("y")),
+    ("CONFIG_STACKPROTECTOR_STRONG", Some("y")),
+    ("CONFIG_RANDOMIZE_BASE", Some("y")),
+    ("CONFIG_STRICT_KERNEL_RWX", Some("y")),
+    ("CONFIG_DEBUG_FS", None),
+    ("CONFIG_PROC_KCORE", None),
+    ("CONFIG_DEVMEM", None),
+];
+
+/// Symbols every container host config must set
+const CONTAINER_SYMBOLS: &[(&str, Option<&str>)] = &[
+    ("CONFIG_NAMESPACES", Some("y")),
+    ("CONFIG_CGROUPS", Some("y")),
+    ("CONFIG_OVERLAY_FS", Some("y")),
+    ("CONFIG_BTRFS_FS", Some("y")),
+    ("CONFIG_SECCOMP_FILTER", Some("y")),
+];
+
+impl KernelProfile {
+    /// Baseline kernel config for this profile
+    pub fn config(&self) -> String {
+        match self {
+            Self::ContainerHost => CONTAINER_CONFIG.to_string(),
+            Self::Development => merge_kconfig(CONTAINER_CONFIG, DEVELOPMENT_CONFIG),
+            Self::Production => merge_kconfig(CONTAINER_CONFIG, PRODUCTION_CONFIG),
+        }
+    }
+
+    /// Symbols (and values) a config for this profile must contain
+    ///
+    /// `None` means the symbol must be disabled.
+    pub fn required_symbols(&self) -> &'static [(&'static str, Option<&'static str>)] {
+        match self {
+            Self::ContainerHost => CONTAINER_SYMBOLS,
+            Self::Development => DEVELOPMENT_SYMBOLS,
+            Self::Production => PRODUCTION_SYMBOLS,
+        }
+    }
+
+    /// Required symbols that a config does not satisfy
+    ///
+    /// Covers the rastOS base requirements (see [`rastos_missing_symbols`])
+    /// and the symbols specific to this profile.
+    pub fn missing_symbols(&self, config: &str) -> Vec<String> {
+        let values = parse_kconfig(config);
+        let mut missing = rastos_missing(&values);
+        missing.extend(
+            self.required_symbols()
+                .iter()
+                .filter(|(symbol, expected)| {
+                    let actual = values.get(*symbol).cloned().flatten();
+                    actual.as_deref() != *expected
+                })
+                .filter(|(symbol, _)| !missing.iter().any(|m| m.starts_with(&format!("{}=", symbol))))
+                .map(|(symbol, expected)| match expected {
+                    Some(value) => format!("{}={} ({})", symbol, value, describe(&values, symbol)),
+                    None => format!("{} unset ({})", symbol, describe(&values, symbol)),
+                })
+                .collect::<Vec<_>>(),
+        );
+        missing
+    }
+}
+
+/// rastOS base requirements that a config does not satisfy
+///
+/// Applies to every config, including custom ones: a kernel without these
+/// options cannot boot a rastOS root or run its containers.
+pub fn rastos_missing_symbols(config: &str) -> Vec<String> {
+    rastos_missing(&parse_kconfig(config))
+}
+
+fn rastos_missing(values: &KconfigValues) -> Vec<String> {
+    RASTOS_SYMBOLS
+        .iter()
+        .filter(|(symbol, allowed)| {
+            let actual = values.get(*symbol).cloned().flatten();
+            !actual.is_some_and(|value| allowed.contains(&value.as_str()))
+        })
+        .map(|(symbol, allowed)| format!("{}={} ({})", symbol, allowed.join("|"), describe(values, symbol)))
+        .collect()
+}
+
+/// Current state of a symbol, for error messages
+fn describe(values: &KconfigValues, symbol: &str) -> String {
+    match values.get(symbol) {
+        Some(Some(value)) => format!("is {}", value),
+        Some(None) => "is not set".to_string(),
+        None => "missing".to_string(),
+    }
+}
+
+/// Symbol values of a kernel config; `None` means "is not set"
+pub type KconfigValues = BTreeMap<String, Option<String>>;
+
+/// Parse the symbol assignments of a kernel config
+///
+/// Later assignments of a symbol override earlier ones, as in Kconfig.
+pub fn parse_kconfig(text: &str) -> KconfigValues {
+    kconfig_entries(text).into_iter().collect()
+}
+
+/// Apply overrides to a kernel config
+///
+/// Symbols present in `base` are updated in place; new symbols are appended.
+pub fn merge_kconfig(base: &str, overlay: &str) -> String {
+    let overrides = kconfig_entries(overlay);
+    let lookup: KconfigValues = overrides.iter().cloned().collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut out = String::new();
+
+    for line in base.lines() {
+        match kconfig_entry(line) {
+            Some((symbol, _)) if lookup.contains_key(&symbol) => {
+                if seen.insert(symbol.clone()) {
+                    out.push_str(&render_kconfig(&symbol, &lookup[&symbol]));
+                    out.push('\n');
+                }
+            }
+            _ => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+
+    let appended: Vec<&(String, Option<String>)> = overrides.iter().filter(|(s, _)| !seen.contains(s)).collect();
+    if !appended.is_empty() {
+        out.push_str("\n# Profile overrides\n");
+        for (symbol, _) in appended {
+            if seen.insert(symbol.clone()) {
+                out.push_str(&render_kconfig(symbol, &lookup[symbol]));
+                out.push('\n');
+            }
+        }
+    }
+
+    out
+}
+
+fn kconfig_entries(text: &str) -> Vec<(String, Option<String>)> {
+    text.lines().filter_map(kconfig_entry).collect()
+}
+
+fn kconfig_entry(line: &str) -> Option<(String, Option<String>)> {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix("# ") {
+        let symbol = rest.strip_suffix(" is not set")?;
+        return symbol.starts_with("CONFIG_").then(|| (symbol.to_string(), None));
+    }
+    let (symbol, value) = line.split_once('=')?;
+    symbol
+        .starts_with("CONFIG_")
+        .then(|| (symbol.to_string(), Some(value.to_string())))
+}
+
+fn render_kconfig(symbol: &str, value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("{}={}", symbol, value),
+        None => format!("# {} is not set", symbol),
+    }
+}
+
 /// Kernel configuration options
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KernelConfig {
@@ -37,6 +261,12 @@ pub struct KernelConfig {
     pub jobs: usize,
     /// Additional make arguments
     pub make_args: Vec<String>,
+    /// Module signing and Secure Boot settings
+    #[serde(default)]
+    pub signing: SigningConfig,
+    /// Distributed compilation (distcc/icecream)
+    #[serde(default)]
+    pub distributed: Option<DistributedConfig>,
 }
 
 impl Default for KernelConfig {
@@ -52,6 +282,8 @@ impl Default for KernelConfig {
             profile: KernelProfile::default(),
             jobs: num_cpus::get(),
             make_args: Vec::new(),
+            signing: SigningConfig::default(),
+            distributed: None,
         }
     }
 }
@@ -116,6 +348,23 @@ impl KernelConfig {
         self
     }
 
+    /// Set the module signing and Secure Boot settings
+    pub fn with_signing(mut self, signing: SigningConfig) -> Self {
+        self.signing = signing;
+        self
+    }
+
+    /// Distribute compilation over distcc or icecream hosts
+    pub fn with_distributed(mut self, distributed: DistributedConfig) -> Self {
+        self.distributed = Some(distributed);
+        self
+    }
+
+    /// Signing settings in effect for the profile
+    pub fn effective_signing(&self) -> SigningConfig {
+        self.signing.clone().for_profile(self.profile)
+    }
+
     /// Validate the configuration
     pub fn validate(&self) -> Result<(), ConfigError> {
         if !self.source_dir.exists() {
@@ -132,3 +381,62 @@ impl KernelConfig {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_have_required_symbols() {
+        for profile in [KernelProfile::ContainerHost, KernelProfile::Development, KernelProfile::Production] {
+            let config = profile.config();
+            assert!(profile.missing_symbols(&config).is_empty(), "{:?}: {:?}", profile, profile.missing_symbols(&config));
+        }
+    }
+
+    #[test]
+    fn test_profiles_are_distinct() {
+        let dev = parse_kconfig(&KernelProfile::Development.config());
+        let prod = parse_kconfig(&KernelProfile::Production.config());
+
+        assert_eq!(dev["CONFIG_DEBUG_FS"], Some("y".to_string()));
+        assert_eq!(prod["CONFIG_DEBUG_FS"], None);
+        assert_eq!(dev.get("CONFIG_MODULE_SIG_FORCE"), None);
+        assert_eq!(prod["CONFIG_MODULE_SIG_HASH"], Some("\"sha512\"".to_string()));
+        // Development keeps KASAN off unless requested
+        assert_eq!(dev["CONFIG_KASAN"], None);
+        // The baseline disables modules; both profiles re-enable them
+        assert_eq!(parse_kconfig(&KernelProfile::ContainerHost.config())["CONFIG_MODULES"], None);
+    }
+
+    #[test]
+    fn test_merge_kconfig() {
+        let merged = merge_kconfig(
+            "# Base\nCONFIG_A=y\n# CONFIG_B is not set\n",
+            "CONFIG_B=m\n# CONFIG_A is not set\nCONFIG_C=\"x\"\n",
+        );
+        assert_eq!(merged, "# Base\n# CONFIG_A is not set\nCONFIG_B=m\n\n# Profile overrides\nCONFIG_C=\"x\"\n");
+        assert!(KernelProfile::Production.missing_symbols("CONFIG_MODULES=y\n").len() > 1);
+    }
+
+    #[test]
+    fn test_rastos_requirements() {
+        let config = merge_kconfig(
+            &KernelProfile::ContainerHost.config(),
+            "CONFIG_BTRFS_FS=m\n# CONFIG_USER_NS is not set\n",
+        );
+        assert_eq!(rastos_missing_symbols(&config), vec!["CONFIG_USER_NS=y (is not set)".to_string()]);
+
+        let missing = KernelProfile::ContainerHost.missing_symbols("CONFIG_BTRFS_FS=y\n");
+        assert!(missing.contains(&"CONFIG_OVERLAY_FS=y|m (missing)".to_string()));
+        // Reported once even though the container profile also requires it
+        assert_eq!(missing.iter().filter(|m| m.starts_with("CONFIG_SECCOMP_FILTER=")).count(), 1);
+    }
+
+    #[test]
+    fn test_production_enforces_module_signing() {
+        let config = KernelConfig::default();
+        assert!(!config.effective_signing().sign_modules);
+        assert!(config.with_profile(KernelProfile::Production).effective_signing().sign_modules);
+    }
+}
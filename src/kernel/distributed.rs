@@ -0,0 +1,214 @@
+//! Distributed compilation
+//!
+//! Spreads kernel compilation over distcc or icecream hosts. The compiler
+//! is wrapped with the distribution client (or, when a compiler cache is
+//! used, the cache is told to forward misses to it), and the job count is
+//! raised to the capacity of the host list.
+
+use std::process::Command;
+
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::ccache::CompilerCache;
+use super::error::KernelError;
+
+/// Distribution system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum DistributedBackend {
+    /// distcc with an explicit host list
+    #[default]
+    Distcc,
+    /// icecream, scheduled by the local iceccd
+    Icecream,
+}
+
+impl DistributedBackend {
+    /// Client executable
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Distcc => "distcc",
+            Self::Icecream => "icecc",
+        }
+    }
+
+    /// Whether the client is installed
+    pub fn is_installed(&self) -> bool {
+        Command::new(self.program())
+            .arg("--version")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+}
+
+/// Distributed compilation settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DistributedConfig {
+    /// Distribution system
+    pub backend: DistributedBackend,
+
+    /// Remote hosts (`host` or `host:port`; distcc only)
+    pub hosts: Vec<String>,
+
+    /// Compile jobs sent to each host
+    pub jobs_per_host: usize,
+
+    /// Compile jobs kept on this machine
+    pub local_jobs: usize,
+
+    /// Build locally when the client is missing or hosts are unreachable
+    pub fallback_local: bool,
+}
+
+impl Default for DistributedConfig {
+    fn default() -> Self {
+        Self {
+            backend: DistributedBackend::default(),
+            hosts: Vec::new(),
+            jobs_per_host: 8,
+            local_jobs: num_cpus::get(),
+            fallback_local: true,
+        }
+    }
+}
+
+/// How to invoke make for a distributed build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DistributedPlan {
+    /// Extra make variables
+    pub make_args: Vec<String>,
+    /// Environment for make and the compiler
+    pub env: Vec<(String, String)>,
+    /// Parallel jobs to run
+    pub jobs: usize,
+}
+
+impl DistributedConfig {
+    /// distcc with the given hosts
+    pub fn distcc<I, S>(hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            hosts: hosts.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// icecream through the local scheduler
+    pub fn icecream() -> Self {
+        Self {
+            backend: DistributedBackend::Icecream,
+            ..Default::default()
+        }
+    }
+
+    /// Set jobs per remote host
+    pub fn with_jobs_per_host(mut self, jobs: usize) -> Self {
+        self.jobs_per_host = jobs;
+        self
+    }
+
+    /// Set jobs kept local
+    pub fn with_local_jobs(mut self, jobs: usize) -> Self {
+        self.local_jobs = jobs;
+        self
+    }
+
+    /// Allow or forbid falling back to local compilation
+    pub fn with_fallback_local(mut self, fallback: bool) -> Self {
+        self.fallback_local = fallback;
+        self
+    }
+
+    /// `DISTCC_HOSTS` value: remote hosts with job limits, then localhost
+    pub fn distcc_hosts(&self) -> String {
+        let mut hosts: Vec<String> = self
+            .hosts
+            .iter()
+            .map(|host| format!("{}/{}", host, self.jobs_per_host))
+            .collect();
+        if self.local_jobs > 0 {
+            hosts.push(format!("localhost/{}", self.local_jobs));
+        }
+        hosts.join(" ")
+    }
+
+    /// Total compile slots
+    ///
+    /// icecream discovers its hosts through the scheduler, so its capacity
+    /// is estimated from `jobs_per_host` times the configured host count
+    /// (or one remote host if none are listed).
+    pub fn capacity(&self) -> usize {
+        let remotes = match self.backend {
+            DistributedBackend::Distcc => self.hosts.len(),
+            DistributedBackend::Icecream => self.hosts.len().max(1),
+        };
+        (remotes * self.jobs_per_host + self.local_jobs).max(1)
+    }
+
+    /// Plan the make invocation, or `None` to build locally
+    pub fn plan(&self, compiler: &str, cache: CompilerCache) -> Result<Option<DistributedPlan>, KernelError> {
+        if self.backend == DistributedBackend::Distcc && self.hosts.is_empty() {
+            return self.unavailable("no distcc hosts configured");
+        }
+        if !self.backend.is_installed() {
+            return self.unavailable(&format!("{} is not installed", self.backend.program()));
+        }
+
+        let program = self.backend.program();
+        let mut plan = DistributedPlan {
+            jobs: self.capacity(),
+            ..Default::default()
+        };
+
+        match cache {
+            // ccache hands misses to the distribution client
+            CompilerCache::Ccache => plan.env.push(("CCACHE_PREFIX".to_string(), program.to_string())),
+            CompilerCache::Sccache => {
+                return self.unavailable("sccache cannot forward compilations to distcc or icecream");
+            }
+            CompilerCache::Auto | CompilerCache::None => plan.make_args.push(format!("CC={} {}", program, compiler)),
+        }
+
+        if self.backend == DistributedBackend::Distcc {
+            plan.env.push(("DISTCC_HOSTS".to_string(), self.distcc_hosts()));
+            let fallback = if self.fallback_local { "1" } else { "0" };
+            plan.env.push(("DISTCC_FALLBACK".to_string(), fallback.to_string()));
+        }
+        Ok(Some(plan))
+    }
+
+    fn unavailable(&self, reason: &str) -> Result<Option<DistributedPlan>, KernelError> {
+        if self.fallback_local {
+            warn!("Distributed compilation disabled, building locally: {}", reason);
+            Ok(None)
+        } else {
+            Err(KernelError::Unsupported(format!("Distributed compilation unavailable: {}", reason)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distcc_hosts() {
+        let config = DistributedConfig::distcc(["build1", "build2:3632"])
+            .with_jobs_per_host(16)
+            .with_local_jobs(4);
+        assert_eq!(config.distcc_hosts(), "build1/16 build2:3632/16 localhost/4");
+        assert_eq!(config.capacity(), 36);
+    }
+
+    #[test]
+    fn test_unavailable_fallback() {
+        let config = DistributedConfig::distcc(Vec::<String>::new());
+        assert_eq!(config.plan("gcc", CompilerCache::None).unwrap(), None);
+        assert!(config.with_fallback_local(false).plan("gcc", CompilerCache::None).is_err());
+    }
+}
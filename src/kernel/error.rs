@@ -3,6 +3,8 @@
 use std::{io, path::PathBuf};
 use thiserror::Error;
 
+use super::cancel::InterruptReason;
+
 /// Errors that can occur during kernel building
 #[derive(Error, Debug)]
 pub enum KernelError {
@@ -39,6 +41,10 @@ pub enum KernelError {
     #[error("Invalid kernel configuration: {0}")]
     InvalidConfig(String),
 
+    /// Final kernel config lacks required options
+    #[error("Kernel config does not satisfy required options: {}", .0.join(", "))]
+    MissingSymbols(Vec<String>),
+
     /// Build process failed
     #[error("Build failed: {0}")]
     BuildFailed(String),
@@ -46,6 +52,19 @@ pub enum KernelError {
     /// Unsupported operation
     #[error("Unsupported operation: {0}")]
     Unsupported(String),
+
+    /// Build cancelled or timed out
+    #[error("Build {reason} during {stage} (completed stages: {completed:?})")]
+    Interrupted {
+        /// Why the build stopped
+        reason: InterruptReason,
+
+        /// Stage that was running
+        stage: String,
+
+        /// Stages that finished before the interruption
+        completed: Vec<String>,
+    },
 }
 
 impl KernelError {
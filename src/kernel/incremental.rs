@@ -0,0 +1,181 @@
+//! Incremental rebuild detection
+//!
+//! Records fingerprints of each stage's inputs in the build directory so a
+//! repeated `build()` can skip patching, configuring and compiling when
+//! nothing relevant changed. The state also remembers which source tree the
+//! build directory belongs to, so a directory reused for another tree is
+//! wiped instead of producing a mixed build.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use super::error::KernelError;
+
+/// State file, relative to the build directory
+pub const STATE_FILE: &str = ".rastos-build-state.json";
+
+/// Fingerprints of the inputs of the last successful stages
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildState {
+    /// Source tree the build directory belongs to
+    pub source_dir: Option<PathBuf>,
+    /// Patch series applied to the source
+    pub patches: Option<String>,
+    /// Inputs of the configure stage
+    pub configure: Option<String>,
+    /// Inputs of the compile stage
+    pub compile: Option<String>,
+}
+
+impl BuildState {
+    /// Load the state of a build directory (empty if missing or unreadable)
+    pub fn load(build_dir: &Path) -> Self {
+        fs::read_to_string(build_dir.join(STATE_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save the state to a build directory
+    pub fn save(&self, build_dir: &Path) -> Result<(), KernelError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| KernelError::BuildFailed(format!("Could not serialize build state: {}", e)))?;
+        fs::write(build_dir.join(STATE_FILE), json)?;
+        Ok(())
+    }
+
+    /// Whether the build directory was last used for another source tree
+    pub fn belongs_to_other(&self, source_dir: &Path) -> bool {
+        self.source_dir
+            .as_deref()
+            .is_some_and(|recorded| canonical(recorded) != canonical(source_dir))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Hash a sequence of inputs
+pub fn hash_inputs<I, T>(inputs: I) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut hasher = Sha256::new();
+    for input in inputs {
+        let input = input.as_ref();
+        // Length prefix keeps ("ab", "c") and ("a", "bc") apart
+        hasher.update((input.len() as u64).to_le_bytes());
+        hasher.update(input);
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fingerprint the contents of a source tree
+///
+/// Git checkouts are fingerprinted by `HEAD` plus the working tree diff;
+/// other trees by the path, size and modification time of every file.
+/// Directories in `exclude` (e.g. an in-tree build directory) are skipped.
+pub fn fingerprint_source(source_dir: &Path, exclude: &[&Path]) -> Result<String, KernelError> {
+    if source_dir.join(".git").exists() {
+        if let Some(fingerprint) = git_fingerprint(source_dir, exclude) {
+            return Ok(fingerprint);
+        }
+    }
+
+    let exclude: Vec<PathBuf> = exclude.iter().map(|p| canonical(p)).collect();
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(source_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !exclude.contains(&canonical(e.path())))
+    {
+        let entry = entry.map_err(|e| KernelError::Io(e.into()))?;
+        let metadata = entry.metadata().map_err(|e| KernelError::Io(e.into()))?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let relative = entry.path().strip_prefix(source_dir).unwrap_or(entry.path());
+        entries.push(format!("{}\t{}\t{}", relative.display(), metadata.len(), modified));
+    }
+    Ok(hash_inputs(entries))
+}
+
+fn git_fingerprint(source_dir: &Path, exclude: &[&Path]) -> Option<String> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(source_dir)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| o.stdout)
+    };
+
+    // In-tree build and install directories must not count as changes
+    let mut excludes = vec!["--".to_string(), ".".to_string()];
+    for dir in exclude {
+        if let Ok(relative) = dir.strip_prefix(source_dir) {
+            excludes.push(format!(":(exclude){}", relative.display()));
+        }
+    }
+    let excludes: Vec<&str> = excludes.iter().map(String::as_str).collect();
+
+    let head = git(&["rev-parse", "HEAD"])?;
+    let diff = git(&[&["diff", "HEAD", "--binary"], excludes.as_slice()].concat())?;
+    let untracked = git(&[&["ls-files", "--others", "--exclude-standard"], excludes.as_slice()].concat())?;
+    Some(hash_inputs([head, diff, untracked]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_state_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        assert_eq!(BuildState::load(dir.path()), BuildState::default());
+
+        let state = BuildState {
+            source_dir: Some(dir.path().to_path_buf()),
+            configure: Some(hash_inputs(["config"])),
+            ..Default::default()
+        };
+        state.save(dir.path())?;
+        assert_eq!(BuildState::load(dir.path()), state);
+        assert!(!state.belongs_to_other(dir.path()));
+        assert!(state.belongs_to_other(Path::new("/usr/src/other")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_source() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let build = dir.path().join("build");
+        fs::create_dir_all(&build)?;
+        fs::write(dir.path().join("Makefile"), "all:\n")?;
+
+        let before = fingerprint_source(dir.path(), &[&build])?;
+        fs::write(build.join("vmlinux.o"), "object")?;
+        assert_eq!(fingerprint_source(dir.path(), &[&build])?, before);
+
+        fs::write(dir.path().join("Makefile"), "all: vmlinux\n")?;
+        assert_ne!(fingerprint_source(dir.path(), &[&build])?, before);
+        assert_ne!(hash_inputs(["ab", "c"]), hash_inputs(["a", "bc"]));
+        Ok(())
+    }
+}
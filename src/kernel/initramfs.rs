@@ -0,0 +1,349 @@
+//! Initramfs generation
+//!
+//! Produces the initramfs for a freshly installed kernel by driving
+//! mkinitcpio or dracut. Optional hooks add Btrfs and LUKS support and the
+//! rastOS snapshot-boot hook, which boots the subvolume named by the
+//! `rastos.snapshot=` kernel parameter instead of the default root.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+
+/// Name of the snapshot-boot hook (mkinitcpio) and module (dracut)
+pub const SNAPSHOT_HOOK: &str = "rastos-snapshot";
+
+/// Runtime part of the mkinitcpio snapshot-boot hook
+const MKINITCPIO_SNAPSHOT_HOOK: &str = r#"#!/usr/bin/ash
+# Boot the Btrfs subvolume given as rastos.snapshot=<subvolume>
+
+run_hook() {
+    if [ -n "$rastos_snapshot" ]; then
+        rootflags="subvol=${rastos_snapshot}${rootflags:+,$rootflags}"
+        export rootflags
+    fi
+}
+"#;
+
+/// Build-time part of the mkinitcpio snapshot-boot hook
+const MKINITCPIO_SNAPSHOT_INSTALL: &str = r#"#!/bin/bash
+
+build() {
+    add_module btrfs
+    add_runscript
+}
+
+help() {
+    echo "Boot the Btrfs subvolume selected with rastos.snapshot=<subvolume>"
+}
+"#;
+
+/// dracut module setup for snapshot boot
+const DRACUT_SNAPSHOT_SETUP: &str = r#"#!/bin/bash
+
+check() {
+    return 0
+}
+
+depends() {
+    echo btrfs
+}
+
+install() {
+    inst_hook cmdline 95 "$moddir/rastos-snapshot.sh"
+}
+"#;
+
+/// dracut cmdline hook selecting the snapshot subvolume
+const DRACUT_SNAPSHOT_HOOK: &str = r#"#!/bin/sh
+# Boot the Btrfs subvolume given as rastos.snapshot=<subvolume>
+
+snapshot=$(getarg rastos.snapshot=)
+if [ -n "$snapshot" ]; then
+    rflags="subvol=${snapshot}${rflags:+,$rflags}"
+    export rflags
+fi
+"#;
+
+/// Tool used to generate the initramfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum InitramfsGenerator {
+    /// Arch Linux mkinitcpio
+    #[default]
+    Mkinitcpio,
+    /// dracut
+    Dracut,
+}
+
+/// Optional initramfs features
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum InitramfsHook {
+    /// Btrfs root filesystems
+    Btrfs,
+    /// LUKS encrypted root devices
+    Luks,
+    /// Booting into a snapshot selected on the kernel command line
+    SnapshotBoot,
+    /// TPM2 unlocking of LUKS devices (dracut only)
+    Tpm2,
+    /// Resuming from hibernation
+    Resume,
+}
+
+/// Initramfs generation settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitramfsConfig {
+    /// Generator to drive
+    pub generator: InitramfsGenerator,
+
+    /// Enabled hooks
+    pub hooks: Vec<InitramfsHook>,
+
+    /// Directory receiving generator hook files (`/etc/initcpio` or the dracut module directory)
+    pub hook_dir: Option<PathBuf>,
+}
+
+impl Default for InitramfsConfig {
+    fn default() -> Self {
+        Self {
+            generator: InitramfsGenerator::default(),
+            hooks: vec![InitramfsHook::Btrfs, InitramfsHook::SnapshotBoot],
+            hook_dir: None,
+        }
+    }
+}
+
+impl InitramfsConfig {
+    /// Use the given generator
+    pub fn with_generator(mut self, generator: InitramfsGenerator) -> Self {
+        self.generator = generator;
+        self
+    }
+
+    /// Enable a hook
+    pub fn with_hook(mut self, hook: InitramfsHook) -> Self {
+        if !self.hooks.contains(&hook) {
+            self.hooks.push(hook);
+        }
+        self
+    }
+
+    /// Install generator hook files below a custom directory
+    pub fn with_hook_dir<P: AsRef<Path>>(mut self, hook_dir: P) -> Self {
+        self.hook_dir = Some(hook_dir.as_ref().to_path_buf());
+        self
+    }
+
+    fn has(&self, hook: InitramfsHook) -> bool {
+        self.hooks.contains(&hook)
+    }
+
+    fn hook_dir(&self) -> PathBuf {
+        self.hook_dir.clone().unwrap_or_else(|| match self.generator {
+            InitramfsGenerator::Mkinitcpio => PathBuf::from("/etc/initcpio"),
+            InitramfsGenerator::Dracut => PathBuf::from("/usr/lib/dracut/modules.d"),
+        })
+    }
+
+    /// Render the mkinitcpio configuration for the enabled hooks
+    pub fn mkinitcpio_conf(&self) -> String {
+        let mut modules = Vec::new();
+        let mut hooks = vec!["base", "udev", "autodetect", "microcode", "modconf", "kms", "keyboard", "block"];
+        if self.has(InitramfsHook::Luks) {
+            hooks.push("encrypt");
+        }
+        if self.has(InitramfsHook::Btrfs) || self.has(InitramfsHook::SnapshotBoot) {
+            modules.push("btrfs");
+            hooks.push("btrfs");
+        }
+        // The image must be resumed before anything mounts the root
+        if self.has(InitramfsHook::Resume) {
+            hooks.push("resume");
+        }
+        if self.has(InitramfsHook::SnapshotBoot) {
+            hooks.push(SNAPSHOT_HOOK);
+        }
+        hooks.push("filesystems");
+        hooks.push("fsck");
+
+        format!(
+            "# Generated by rastOS\nMODULES=({})\nBINARIES=()\nFILES=()\nHOOKS=({})\nCOMPRESSION=\"zstd\"\n",
+            modules.join(" "),
+            hooks.join(" ")
+        )
+    }
+
+    /// dracut modules to add for the enabled hooks
+    pub fn dracut_modules(&self) -> Vec<&'static str> {
+        let mut modules = Vec::new();
+        if self.has(InitramfsHook::Btrfs) || self.has(InitramfsHook::SnapshotBoot) {
+            modules.push("btrfs");
+        }
+        if self.has(InitramfsHook::Luks) {
+            modules.push("crypt");
+        }
+        if self.has(InitramfsHook::Tpm2) {
+            modules.push("tpm2-tss");
+        }
+        if self.has(InitramfsHook::Resume) {
+            modules.push("resume");
+        }
+        if self.has(InitramfsHook::SnapshotBoot) {
+            modules.push(SNAPSHOT_HOOK);
+        }
+        modules
+    }
+
+    /// Install the snapshot-boot hook files for the selected generator
+    pub fn install_hooks(&self) -> Result<(), KernelError> {
+        if !self.has(InitramfsHook::SnapshotBoot) {
+            return Ok(());
+        }
+
+        let dir = self.hook_dir();
+        let files: [(PathBuf, &str); 2] = match self.generator {
+            InitramfsGenerator::Mkinitcpio => [
+                (dir.join("hooks").join(SNAPSHOT_HOOK), MKINITCPIO_SNAPSHOT_HOOK),
+                (dir.join("install").join(SNAPSHOT_HOOK), MKINITCPIO_SNAPSHOT_INSTALL),
+            ],
+            InitramfsGenerator::Dracut => {
+                let module = dir.join(format!("95{}", SNAPSHOT_HOOK));
+                [
+                    (module.join("module-setup.sh"), DRACUT_SNAPSHOT_SETUP),
+                    (module.join(format!("{}.sh", SNAPSHOT_HOOK)), DRACUT_SNAPSHOT_HOOK),
+                ]
+            }
+        };
+
+        for (path, content) in files {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, content)?;
+            set_executable(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Generate the initramfs for a kernel
+    ///
+    /// `module_root` is the directory the kernel modules were installed to
+    /// (containing `lib/modules/<release>`).
+    pub fn generate(&self, release: &str, module_root: &Path, output: &Path) -> Result<(), KernelError> {
+        self.install_hooks()?;
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Deleted when dropped, once the generator has exited
+        let mut conf = None;
+        let (program, args) = match self.generator {
+            InitramfsGenerator::Mkinitcpio => {
+                let mut file = tempfile::Builder::new().prefix("rastos-mkinitcpio-").suffix(".conf").tempfile()?;
+                file.write_all(self.mkinitcpio_conf().as_bytes())?;
+                file.flush()?;
+                let args = self.mkinitcpio_args(release, module_root, file.path(), output);
+                conf = Some(file);
+                ("mkinitcpio", args)
+            }
+            InitramfsGenerator::Dracut => ("dracut", self.dracut_args(release, module_root, output)),
+        };
+
+        debug!("Running: {} {}", program, args.join(" "));
+        let output = Command::new(program).args(&args).output()?;
+        drop(conf);
+        if !output.status.success() {
+            return Err(KernelError::command_error(program, &output));
+        }
+        Ok(())
+    }
+
+    fn mkinitcpio_args(&self, release: &str, module_root: &Path, conf: &Path, output: &Path) -> Vec<String> {
+        let mut args = vec![
+            "--config".to_string(),
+            conf.display().to_string(),
+            "--kernel".to_string(),
+            release.to_string(),
+            "--moduleroot".to_string(),
+            module_root.display().to_string(),
+            "--generate".to_string(),
+            output.display().to_string(),
+        ];
+        if self.hook_dir.is_some() && self.has(InitramfsHook::SnapshotBoot) {
+            // Custom hook directories replace the defaults, so list both
+            for dir in [self.hook_dir(), PathBuf::from("/usr/lib/initcpio"), PathBuf::from("/etc/initcpio")] {
+                args.push("--hookdir".to_string());
+                args.push(dir.display().to_string());
+            }
+        }
+        args
+    }
+
+    fn dracut_args(&self, release: &str, module_root: &Path, output: &Path) -> Vec<String> {
+        let mut args = vec![
+            "--force".to_string(),
+            "--kver".to_string(),
+            release.to_string(),
+            "--kmoddir".to_string(),
+            module_root.join("lib/modules").join(release).display().to_string(),
+        ];
+        let modules = self.dracut_modules();
+        if !modules.is_empty() {
+            args.push("--add".to_string());
+            args.push(modules.join(" "));
+        }
+        args.push(output.display().to_string());
+        args
+    }
+}
+
+fn set_executable(path: &Path) -> Result<(), KernelError> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_mkinitcpio_hooks() {
+        let config = InitramfsConfig::default().with_hook(InitramfsHook::Luks);
+        let conf = config.mkinitcpio_conf();
+        assert!(conf.contains("MODULES=(btrfs)"));
+        assert!(conf.contains("block encrypt btrfs rastos-snapshot filesystems fsck)"));
+
+        let plain = InitramfsConfig {
+            hooks: Vec::new(),
+            ..Default::default()
+        };
+        assert!(!plain.mkinitcpio_conf().contains("btrfs"));
+    }
+
+    #[test]
+    fn test_dracut_args() {
+        let config = InitramfsConfig::default().with_generator(InitramfsGenerator::Dracut);
+        let args = config.dracut_args("6.6.1-rastos", Path::new("/out"), Path::new("/out/boot/initramfs.img"));
+        assert_eq!(args[4], "/out/lib/modules/6.6.1-rastos");
+        assert!(args.contains(&"btrfs rastos-snapshot".to_string()));
+    }
+
+    #[test]
+    fn test_install_snapshot_hook() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        InitramfsConfig::default().with_hook_dir(dir.path()).install_hooks()?;
+
+        let hook = fs::read_to_string(dir.path().join("hooks").join(SNAPSHOT_HOOK))?;
+        assert!(hook.contains("rastos_snapshot"));
+        assert!(dir.path().join("install").join(SNAPSHOT_HOOK).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Hardware-tailored configs
+//!
+//! Collects the modules loaded on this machine and feeds them to
+//! `make localmodconfig`, which disables every module the hardware does not
+//! need. Module lists can be saved under several boot profiles (e.g. docked
+//! and undocked) and merged, so hardware that is only present sometimes is
+//! kept.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+
+/// Kernel interface listing loaded modules
+pub const PROC_MODULES: &str = "/proc/modules";
+
+/// A set of kernel module names
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleSet {
+    /// Module names
+    pub modules: BTreeSet<String>,
+}
+
+impl ModuleSet {
+    /// Modules currently loaded
+    pub fn loaded() -> Result<Self, KernelError> {
+        Self::from_file(PROC_MODULES)
+    }
+
+    /// Read a module list
+    ///
+    /// Accepts `/proc/modules`, `lsmod` output, or one module name per line.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, KernelError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(KernelError::MissingFile(path.to_path_buf()));
+        }
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parse a module list
+    pub fn parse(content: &str) -> Self {
+        let modules = content
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|name| *name != "Module" && !name.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Self { modules }
+    }
+
+    /// Add the modules of another set
+    pub fn merge(&mut self, other: &ModuleSet) {
+        self.modules.extend(other.modules.iter().cloned());
+    }
+
+    /// Number of modules
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Whether the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Render in `lsmod` format, as read by `make localmodconfig LSMOD=<file>`
+    pub fn to_lsmod(&self) -> String {
+        let mut out = String::from("Module                  Size  Used by\n");
+        for module in &self.modules {
+            out.push_str(&format!("{:<24}0  0\n", module));
+        }
+        out
+    }
+
+    /// Save as a module list, merging with a list already at `path`
+    ///
+    /// Run once per boot profile to accumulate the modules of all of them.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<ModuleSet, KernelError> {
+        let path = path.as_ref();
+        let mut merged = if path.exists() {
+            Self::from_file(path)?
+        } else {
+            Self::default()
+        };
+        merged.merge(self);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = String::new();
+        for module in &merged.modules {
+            out.push_str(module);
+            out.push('\n');
+        }
+        fs::write(path, out)?;
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_module_lists() {
+        let proc = ModuleSet::parse("btrfs 1638400 1 - Live 0x0000000000000000\nxor 24576 1 btrfs, Live 0x0000000000000000\n");
+        let lsmod = ModuleSet::parse("Module                  Size  Used by\nbtrfs                1638400  1\nnvme 53248 2\n");
+        let names = ModuleSet::parse("# docked\nthunderbolt\n\n");
+
+        assert_eq!(proc.modules.iter().collect::<Vec<_>>(), ["btrfs", "xor"]);
+        assert_eq!(lsmod.modules.iter().collect::<Vec<_>>(), ["btrfs", "nvme"]);
+        assert_eq!(names.modules.iter().collect::<Vec<_>>(), ["thunderbolt"]);
+        assert_eq!(ModuleSet::parse(&lsmod.to_lsmod()), lsmod);
+    }
+
+    #[test]
+    fn test_save_merges_profiles() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("modules.list");
+
+        ModuleSet::parse("btrfs\nnvme\n").save(&path)?;
+        let merged = ModuleSet::parse("btrfs\nthunderbolt\n").save(&path)?;
+
+        assert_eq!(merged.len(), 3);
+        assert_eq!(ModuleSet::from_file(&path)?, merged);
+        Ok(())
+    }
+}
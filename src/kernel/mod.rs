@@ -1,11 +1,55 @@
 //! Kernel building and management module
+//!
+//! Boot entries, bootloaders and initramfs generation are always built, as
+//! installing and updating the system needs them; building kernels needs the
+//! `kernel` feature.
 
+#[cfg(feature = "kernel")]
+pub mod bisect;
+pub mod bootloader;
+#[cfg(feature = "kernel")]
 mod build;
+pub mod cancel;
+pub mod ccache;
 pub mod config;
+pub mod distributed;
+#[cfg(feature = "kernel")]
+pub mod incremental;
+pub mod initramfs;
+#[cfg(feature = "kernel")]
+pub mod localmod;
+#[cfg(feature = "kernel")]
+pub mod package;
+#[cfg(feature = "kernel")]
+pub mod patches;
+#[cfg(feature = "kernel")]
+pub mod progress;
+pub mod signing;
+#[cfg(feature = "kernel")]
+pub mod toolchain;
 mod error;
 
+#[cfg(feature = "kernel")]
+pub use bisect::{BisectOutcome, BisectTest, Bisector, BootTest};
+pub use bootloader::{BootEntryConfig, Bootloader};
+#[cfg(feature = "kernel")]
 pub use build::KernelBuilder;
+pub use cancel::{BuildControl, BuildProgress, InterruptReason};
+pub use ccache::{CacheStats, CompilerCache};
 pub use config::{KernelConfig, KernelProfile};
+pub use distributed::{DistributedBackend, DistributedConfig};
+pub use initramfs::{InitramfsConfig, InitramfsGenerator, InitramfsHook};
+#[cfg(feature = "kernel")]
+pub use localmod::ModuleSet;
+#[cfg(feature = "kernel")]
+pub use package::{PackageFormat, PackageOptions};
+#[cfg(feature = "kernel")]
+pub use patches::{KernelPatch, PatchReport, PatchSeries};
+#[cfg(feature = "kernel")]
+pub use progress::{BuildLog, Diagnostic, DiagnosticLevel};
+pub use signing::{SigningConfig, SigningKeys};
+#[cfg(feature = "kernel")]
+pub use toolchain::{LtoMode, RustSupport, Toolchain};
 pub use error::KernelError;
 
 /// Re-export commonly used types
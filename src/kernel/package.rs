@@ -0,0 +1,355 @@
+//! Kernel packaging
+//!
+//! Turns an installed build into a pacman package (and optionally Debian
+//! packages via kbuild's `bindeb-pkg`), so the kernel can be installed with
+//! `PackageManager::install_files` and rolled back like any other package.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+use crate::fs::{walk, WalkOptions, Workspace, WorkspaceOptions};
+
+/// Package format to produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum PackageFormat {
+    /// pacman `.pkg.tar.zst`
+    #[default]
+    Pacman,
+    /// Debian `.deb` (built by kbuild)
+    Deb,
+}
+
+/// Packaging options
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageOptions {
+    /// Package name
+    pub name: String,
+
+    /// Package release, bumped when repackaging the same kernel
+    pub pkgrel: u32,
+
+    /// Formats to produce
+    pub formats: Vec<PackageFormat>,
+
+    /// Also produce a `<name>-headers` package for out-of-tree modules
+    pub headers: bool,
+
+    /// Directory receiving the packages (defaults to the build directory)
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Default for PackageOptions {
+    fn default() -> Self {
+        Self {
+            name: "linux-rastos".to_string(),
+            pkgrel: 1,
+            formats: vec![PackageFormat::Pacman],
+            headers: true,
+            output_dir: None,
+        }
+    }
+}
+
+impl PackageOptions {
+    /// Set the package name
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Set the package release
+    pub fn with_pkgrel(mut self, pkgrel: u32) -> Self {
+        self.pkgrel = pkgrel;
+        self
+    }
+
+    /// Produce the given formats
+    pub fn with_formats(mut self, formats: Vec<PackageFormat>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Enable or disable the headers package
+    pub fn with_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Write packages to a directory
+    pub fn with_output_dir<P: AsRef<Path>>(mut self, output_dir: P) -> Self {
+        self.output_dir = Some(output_dir.as_ref().to_path_buf());
+        self
+    }
+}
+
+/// pacman version for a kernel release (`6.6.1-rastos` -> `6.6.1.rastos`)
+pub fn pkgver(release: &str) -> String {
+    release.replace(['-', ':', '/'], ".")
+}
+
+/// Machine architecture as pacman names it
+fn arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86" => "i686",
+        other => other,
+    }
+}
+
+/// Render a `.PKGINFO` file
+pub fn pkginfo(name: &str, version: &str, description: &str, size: u64, depends: &[&str]) -> String {
+    let builddate = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut info = format!(
+        "# Generated by rastOS kernel builder\npkgname = {}\npkgbase = {}\npkgver = {}\npkgdesc = {}\nurl = https://www.kernel.org/\nbuilddate = {}\npackager = rastOS kernel builder\nsize = {}\narch = {}\nlicense = GPL-2.0-only\n",
+        name,
+        name,
+        version,
+        description,
+        builddate,
+        size,
+        arch()
+    );
+    for depend in depends {
+        info.push_str(&format!("depend = {}\n", depend));
+    }
+    info
+}
+
+/// Render the `.INSTALL` script for a kernel release
+///
+/// Copies the image to `/boot`, refreshes module dependencies and rebuilds
+/// the initramfs; removal cleans both up again.
+pub fn install_script(name: &str, release: &str) -> String {
+    format!(
+        r#"post_install() {{
+    install -Dm644 /usr/lib/modules/{release}/vmlinuz /boot/vmlinuz-{name}
+    depmod {release}
+    if command -v mkinitcpio >/dev/null; then
+        mkinitcpio -k {release} -g /boot/initramfs-{name}.img
+    elif command -v dracut >/dev/null; then
+        dracut --force --kver {release} /boot/initramfs-{name}.img
+    fi
+}}
+
+post_upgrade() {{
+    post_install
+}}
+
+pre_remove() {{
+    rm -f /boot/vmlinuz-{name} /boot/initramfs-{name}.img
+}}
+"#
+    )
+}
+
+/// Total size of the files below a directory
+fn tree_size(dir: &Path) -> u64 {
+    let Ok(entries) = walk(dir, WalkOptions::default()) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type.is_file())
+        .filter_map(|e| fs::symlink_metadata(&e.path).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Archive a staging directory into a `.pkg.tar.zst`
+///
+/// `.PKGINFO` (and `.INSTALL`, if present) must already be in `staging`.
+pub fn create_archive(staging: &Path, output: &Path) -> Result<(), KernelError> {
+    let mut entries = vec![".PKGINFO".to_string()];
+    if staging.join(".INSTALL").exists() {
+        entries.push(".INSTALL".to_string());
+    }
+    for entry in fs::read_dir(staging)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') {
+            entries.push(name);
+        }
+    }
+
+    // pacman verifies installed files against the mtree, as makepkg generates it
+    let output_mtree = Command::new("bsdtar")
+        .current_dir(staging)
+        .args(["-czf", ".MTREE", "--format=mtree"])
+        .arg("--options=!all,use-set,type,uid,gid,mode,time,size,md5,sha256,link")
+        .args(&entries)
+        .output()?;
+    if !output_mtree.status.success() {
+        return Err(KernelError::command_error("bsdtar --format=mtree", &output_mtree));
+    }
+    entries.insert(1, ".MTREE".to_string());
+
+    debug!("Archiving {} into {}", staging.display(), output.display());
+    let output_tar = Command::new("bsdtar")
+        .current_dir(staging)
+        .args(["--zstd", "--uid", "0", "--gid", "0", "-cf"])
+        .arg(output)
+        .args(&entries)
+        .output()?;
+    if !output_tar.status.success() {
+        return Err(KernelError::command_error("bsdtar", &output_tar));
+    }
+    Ok(())
+}
+
+/// Stage and archive the kernel package
+///
+/// `module_dir` is the installed `lib/modules/<release>` directory and
+/// `image` the kernel image built by kbuild.
+pub fn package_kernel(
+    options: &PackageOptions,
+    release: &str,
+    image: &Path,
+    module_dir: &Path,
+    output_dir: &Path,
+) -> Result<PathBuf, KernelError> {
+    let workspace = staging_workspace(output_dir, &options.name)?;
+    let staging = workspace.path();
+    let target = staging.join("usr/lib/modules").join(release);
+    fs::create_dir_all(&target)?;
+
+    copy_tree(module_dir, &target)?;
+    // Symlinks into the build tree are meaningless on the target system
+    for link in ["build", "source"] {
+        let _ = fs::remove_file(target.join(link));
+    }
+    fs::copy(image, target.join("vmlinuz"))?;
+    fs::write(target.join("pkgbase"), format!("{}\n", options.name))?;
+
+    let version = format!("{}-{}", pkgver(release), options.pkgrel);
+    fs::write(
+        staging.join(".PKGINFO"),
+        pkginfo(
+            &options.name,
+            &version,
+            &format!("Linux kernel {} built by rastOS", release),
+            tree_size(staging),
+            &["coreutils", "kmod"],
+        ),
+    )?;
+    fs::write(staging.join(".INSTALL"), install_script(&options.name, release))?;
+
+    let output = output_dir.join(format!("{}-{}-{}.pkg.tar.zst", options.name, version, arch()));
+    create_archive(staging, &output)?;
+    Ok(output)
+}
+
+/// Stage and archive the headers package for building out-of-tree modules
+pub fn package_headers(
+    options: &PackageOptions,
+    release: &str,
+    source_dir: &Path,
+    build_dir: &Path,
+    output_dir: &Path,
+) -> Result<PathBuf, KernelError> {
+    let name = format!("{}-headers", options.name);
+    let workspace = staging_workspace(output_dir, &name)?;
+    let staging = workspace.path();
+    let target = staging.join("usr/lib/modules").join(release).join("build");
+    fs::create_dir_all(&target)?;
+
+    let arch_include = format!("arch/{}/include", kernel_arch());
+    let arch_makefile = format!("arch/{}/Makefile", kernel_arch());
+    for (base, path) in [
+        (source_dir, "Makefile"),
+        (source_dir, "include"),
+        (source_dir, "scripts"),
+        (source_dir, arch_include.as_str()),
+        (source_dir, arch_makefile.as_str()),
+        (build_dir, ".config"),
+        (build_dir, "Module.symvers"),
+        (build_dir, "include"),
+        (build_dir, "scripts"),
+        (build_dir, arch_include.as_str()),
+        (build_dir, "tools/objtool/objtool"),
+    ] {
+        let from = base.join(path);
+        if from.exists() {
+            copy_tree(&from, &target.join(path))?;
+        }
+    }
+
+    let version = format!("{}-{}", pkgver(release), options.pkgrel);
+    fs::write(
+        staging.join(".PKGINFO"),
+        pkginfo(
+            &name,
+            &version,
+            &format!("Headers and scripts for building modules for Linux {}", release),
+            tree_size(staging),
+            &["make", "gcc"],
+        ),
+    )?;
+
+    let output = output_dir.join(format!("{}-{}-{}.pkg.tar.zst", name, version, arch()));
+    create_archive(staging, &output)?;
+    Ok(output)
+}
+
+/// Scratch directory for staging package `name`, removed when dropped
+///
+/// Staging happens next to the output so the archive is written on the same
+/// filesystem, and directories left by an interrupted build are cleaned up.
+fn staging_workspace(output_dir: &Path, name: &str) -> Result<Workspace, KernelError> {
+    let options = WorkspaceOptions::default().with_base(output_dir.join(".staging"));
+    Ok(Workspace::new(name, &options).map_err(std::io::Error::from)?)
+}
+
+/// Kernel architecture directory name for this machine
+fn kernel_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" | "x86" => "x86",
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        other => other,
+    }
+}
+
+/// Copy a file or directory tree, preserving symlinks and modes
+fn copy_tree(from: &Path, to: &Path) -> Result<(), KernelError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let output = Command::new("cp").arg("-a").arg("-T").arg(from).arg(to).output()?;
+    if !output.status.success() {
+        return Err(KernelError::command_error("cp -a", &output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkginfo() {
+        assert_eq!(pkgver("6.6.1-rastos"), "6.6.1.rastos");
+
+        let info = pkginfo("linux-rastos", "6.6.1.rastos-1", "Linux kernel", 1024, &["kmod"]);
+        assert!(info.contains("pkgname = linux-rastos\n"));
+        assert!(info.contains("pkgver = 6.6.1.rastos-1\n"));
+        assert!(info.contains("size = 1024\n"));
+        assert!(info.contains("depend = kmod\n"));
+    }
+
+    #[test]
+    fn test_install_script() {
+        let script = install_script("linux-rastos", "6.6.1-rastos");
+        assert!(script.contains("depmod 6.6.1-rastos"));
+        assert!(script.contains("/boot/vmlinuz-linux-rastos"));
+        assert!(script.contains("mkinitcpio -k 6.6.1-rastos -g /boot/initramfs-linux-rastos.img"));
+    }
+}
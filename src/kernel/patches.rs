@@ -0,0 +1,476 @@
+//! Kernel patch series
+//!
+//! Applies a declarative list of patches (local files or URLs pinned by
+//! SHA-256) to the kernel source before configuring. Applied patches are
+//! copied into a quilt-style directory inside the source tree so they can be
+//! reversed later, even if the series file has changed since.
+//!
+//! Series files are TOML:
+//!
+//! ```toml
+//! skip_broken = false
+//!
+//! [[patch]]
+//! name = "rt"
+//! url = "https://cdn.kernel.org/pub/linux/kernel/projects/rt/6.6/patch-6.6-rt15.patch.xz"
+//! sha256 = "..."
+//!
+//! [[patch]]
+//! path = "patches/0001-local-fix.patch"
+//! ```
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::error::KernelError;
+use crate::fs::create_private_dir;
+
+/// Directory inside the source tree recording applied patches
+pub const APPLIED_DIR: &str = ".rastos-patches";
+
+/// A single patch in a series
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelPatch {
+    /// Display name (defaults to the file name)
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Local patch file, relative to the series file
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// Remote patch URL
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Expected SHA-256 of the patch file (required for URLs)
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// Leading path components to strip (`patch -p`)
+    #[serde(default = "default_strip")]
+    pub strip: u32,
+}
+
+fn default_strip() -> u32 {
+    1
+}
+
+impl KernelPatch {
+    /// A local patch file
+    pub fn file<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            name: None,
+            path: Some(path.as_ref().to_path_buf()),
+            url: None,
+            sha256: None,
+            strip: default_strip(),
+        }
+    }
+
+    /// A remote patch pinned by hash
+    pub fn url<S: Into<String>>(url: S, sha256: S) -> Self {
+        Self {
+            name: None,
+            path: None,
+            url: Some(url.into()),
+            sha256: Some(sha256.into()),
+            strip: default_strip(),
+        }
+    }
+
+    /// Set the display name
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the strip level
+    pub fn with_strip(mut self, strip: u32) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    /// Display name of the patch
+    pub fn display_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let location = match (&self.path, &self.url) {
+            (Some(path), _) => path.to_string_lossy().into_owned(),
+            (None, Some(url)) => url.clone(),
+            (None, None) => return "unnamed".to_string(),
+        };
+        location.rsplit('/').next().unwrap_or(&location).to_string()
+    }
+
+    fn validate(&self) -> Result<(), KernelError> {
+        match (&self.path, &self.url) {
+            (Some(_), Some(_)) => Err(KernelError::InvalidConfig(format!(
+                "Patch '{}' has both a path and a URL",
+                self.display_name()
+            ))),
+            (None, None) => Err(KernelError::InvalidConfig("Patch without a path or URL".to_string())),
+            (None, Some(_)) if self.sha256.is_none() => Err(KernelError::InvalidConfig(format!(
+                "Remote patch '{}' must be pinned with a sha256",
+                self.display_name()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Outcome of applying a series
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchReport {
+    /// Patches applied, in order
+    pub applied: Vec<String>,
+    /// Patches skipped because they did not apply
+    pub skipped: Vec<String>,
+}
+
+/// An ordered list of patches
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchSeries {
+    /// Patches in application order
+    #[serde(rename = "patch", default)]
+    pub patches: Vec<KernelPatch>,
+
+    /// Skip patches that do not apply instead of failing
+    #[serde(default)]
+    pub skip_broken: bool,
+
+    /// Directory caching downloaded patches
+    ///
+    /// Defaults to `/var/cache/rastos/kernel-patches` for root and
+    /// `$XDG_CACHE_HOME/rastos/kernel-patches` for other users.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Directory relative patch paths are resolved against
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+impl PatchSeries {
+    /// Load a series file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, KernelError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        let mut series: Self = toml::from_str(&content)
+            .map_err(|e| KernelError::InvalidConfig(format!("Invalid patch series {}: {}", path.display(), e)))?;
+        series.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        series.validate()?;
+        Ok(series)
+    }
+
+    /// Append a patch
+    pub fn with_patch(mut self, patch: KernelPatch) -> Self {
+        self.patches.push(patch);
+        self
+    }
+
+    /// Skip patches that do not apply
+    pub fn with_skip_broken(mut self, skip_broken: bool) -> Self {
+        self.skip_broken = skip_broken;
+        self
+    }
+
+    /// Cache downloaded patches in a directory
+    pub fn with_cache_dir<P: AsRef<Path>>(mut self, cache_dir: P) -> Self {
+        self.cache_dir = Some(cache_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Check that every patch has a usable source
+    pub fn validate(&self) -> Result<(), KernelError> {
+        self.patches.iter().try_for_each(KernelPatch::validate)
+    }
+
+    /// Whether the series is empty
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    /// Fingerprint of the patch contents and options, for change detection
+    pub fn fingerprint(&self) -> Result<String, KernelError> {
+        let mut inputs = vec![format!("skip_broken={}", self.skip_broken)];
+        for patch in &self.patches {
+            let file = self.fetch(patch)?;
+            inputs.push(format!("{} -p{} {}", patch.display_name(), patch.strip, sha256_file(&file)?));
+        }
+        Ok(super::incremental::hash_inputs(inputs))
+    }
+
+    /// Resolve a patch to a local, hash-verified file
+    pub fn fetch(&self, patch: &KernelPatch) -> Result<PathBuf, KernelError> {
+        patch.validate()?;
+
+        let file = match (&patch.path, &patch.url) {
+            (Some(path), _) => {
+                let path = self.base_dir.join(path);
+                if !path.exists() {
+                    return Err(KernelError::MissingFile(path));
+                }
+                path
+            }
+            (None, Some(url)) => self.download(url, patch.sha256.as_deref().unwrap_or_default())?,
+            (None, None) => unreachable!("validated above"),
+        };
+
+        if let Some(expected) = &patch.sha256 {
+            let actual = sha256_file(&file)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(KernelError::InvalidConfig(format!(
+                    "Checksum mismatch for patch '{}': expected {}, got {}",
+                    patch.display_name(),
+                    expected,
+                    actual
+                )));
+            }
+        }
+        Ok(file)
+    }
+
+    fn download(&self, url: &str, sha256: &str) -> Result<PathBuf, KernelError> {
+        let cache_dir = self.cache_dir.clone().unwrap_or_else(default_cache_dir);
+        create_private_dir(&cache_dir).map_err(std::io::Error::from)?;
+
+        let file_name = url.rsplit('/').next().unwrap_or("patch");
+        let target = cache_dir.join(format!("{}-{}", &sha256[..sha256.len().min(12)], file_name));
+        if target.exists() {
+            if sha256_file(&target)?.eq_ignore_ascii_case(sha256) {
+                debug!("Using cached patch {}", target.display());
+                return Ok(target);
+            }
+            warn!("Cached patch {} is corrupt, downloading it again", target.display());
+            fs::remove_file(&target)?;
+        }
+
+        info!("Downloading patch {}", url);
+        let output = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--location", "--output"])
+            .arg(&target)
+            .arg(url)
+            .output()?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&target);
+            return Err(KernelError::command_error(format!("curl {}", url), &output));
+        }
+        Ok(target)
+    }
+
+    /// Apply the series to a source tree
+    ///
+    /// Patches from a previous run are reversed first. Without
+    /// `skip_broken`, a patch that does not apply reverses the ones applied
+    /// before it and fails, leaving the tree as it was.
+    pub fn apply(&self, source_dir: &Path) -> Result<PatchReport, KernelError> {
+        reverse(source_dir)?;
+
+        let applied_dir = source_dir.join(APPLIED_DIR);
+        let mut report = PatchReport::default();
+        let mut series = String::new();
+
+        for (index, patch) in self.patches.iter().enumerate() {
+            let name = patch.display_name();
+            let file = self.fetch(patch)?;
+            let contents = read_patch(&file)?;
+
+            if let Err(e) = run_patch(source_dir, patch.strip, &contents, &["--dry-run"]) {
+                if self.skip_broken {
+                    warn!("Skipping patch '{}': {}", name, e);
+                    report.skipped.push(name);
+                    continue;
+                }
+                reverse(source_dir)?;
+                return Err(KernelError::BuildFailed(format!("Patch '{}' does not apply: {}", name, e)));
+            }
+
+            run_patch(source_dir, patch.strip, &contents, &[])?;
+            info!("Applied patch '{}'", name);
+
+            // Record the patch so it can be reversed without the series file
+            fs::create_dir_all(&applied_dir)?;
+            let record = format!("{:04}.patch", index);
+            fs::write(applied_dir.join(&record), &contents)?;
+            series.push_str(&format!("{} -p{} {}\n", record, patch.strip, name));
+            fs::write(applied_dir.join("series"), &series)?;
+            report.applied.push(name);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Reverse previously applied patches, newest first
+///
+/// Returns the names of the reversed patches.
+pub fn reverse(source_dir: &Path) -> Result<Vec<String>, KernelError> {
+    let applied_dir = source_dir.join(APPLIED_DIR);
+    let series_file = applied_dir.join("series");
+    if !series_file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut reversed = Vec::new();
+    for line in fs::read_to_string(&series_file)?.lines().rev() {
+        let Some((record, strip, name)) = parse_series_line(line) else {
+            continue;
+        };
+        let contents = fs::read(applied_dir.join(record))?;
+        run_patch(source_dir, strip, &contents, &["--reverse"])?;
+        info!("Reversed patch '{}'", name);
+        reversed.push(name.to_string());
+    }
+
+    fs::remove_dir_all(&applied_dir)?;
+    Ok(reversed)
+}
+
+fn parse_series_line(line: &str) -> Option<(&str, u32, &str)> {
+    let mut parts = line.splitn(3, ' ');
+    let record = parts.next()?;
+    let strip = parts.next()?.strip_prefix("-p")?.parse().ok()?;
+    Some((record, strip, parts.next().unwrap_or(record)))
+}
+
+/// Read a patch, decompressing `.xz`, `.gz` and `.zst` files
+fn read_patch(path: &Path) -> Result<Vec<u8>, KernelError> {
+    let decompressor = match path.extension().and_then(|e| e.to_str()) {
+        Some("xz") => Some("xz"),
+        Some("gz") => Some("gzip"),
+        Some("zst") => Some("zstd"),
+        _ => None,
+    };
+
+    match decompressor {
+        Some(program) => {
+            let output = Command::new(program).arg("-dc").arg(path).output()?;
+            if !output.status.success() {
+                return Err(KernelError::command_error(program, &output));
+            }
+            Ok(output.stdout)
+        }
+        None => Ok(fs::read(path)?),
+    }
+}
+
+fn run_patch(source_dir: &Path, strip: u32, contents: &[u8], extra: &[&str]) -> Result<(), KernelError> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new("patch")
+        .arg("-d")
+        .arg(source_dir)
+        .arg(format!("-p{}", strip))
+        .args(["--forward", "--batch", "--silent"])
+        .args(extra)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().expect("piped stdin").write_all(contents)?;
+
+    let mut output = child.wait_with_output()?;
+    if !output.status.success() {
+        // patch reports rejects on stdout
+        output.stderr.extend_from_slice(&output.stdout);
+        return Err(KernelError::command_error("patch", &output));
+    }
+    Ok(())
+}
+
+/// Per-user directory for downloaded patches
+fn default_cache_dir() -> PathBuf {
+    if nix::unistd::Uid::effective().is_root() {
+        return PathBuf::from("/var/cache/rastos/kernel-patches");
+    }
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(|| PathBuf::from("/var/cache"));
+    base.join("rastos/kernel-patches")
+}
+
+fn sha256_file(path: &Path) -> Result<String, KernelError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_series() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let file = dir.path().join("series.toml");
+        fs::write(
+            &file,
+            r#"
+skip_broken = true
+
+[[patch]]
+name = "rt"
+url = "https://example.org/patch-6.6-rt15.patch.xz"
+sha256 = "abc"
+
+[[patch]]
+path = "patches/0001-fix.patch"
+strip = 0
+"#,
+        )?;
+
+        let series = PatchSeries::from_file(&file)?;
+        assert!(series.skip_broken);
+        assert_eq!(series.patches.len(), 2);
+        assert_eq!(series.patches[0].display_name(), "rt");
+        assert_eq!(series.patches[1].display_name(), "0001-fix.patch");
+        assert_eq!(series.patches[1].strip, 0);
+        assert_eq!(series.base_dir, dir.path());
+
+        fs::write(&file, "[[patch]]\nurl = \"https://example.org/x.patch\"\n")?;
+        assert!(PatchSeries::from_file(&file).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_verifies_hash() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let file = dir.path().join("fix.patch");
+        fs::write(&file, "")?;
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        let mut patch = KernelPatch::file(&file);
+        patch.sha256 = Some(empty.to_string());
+        assert_eq!(PatchSeries::default().fetch(&patch)?, file);
+
+        patch.sha256 = Some("00".to_string());
+        assert!(PatchSeries::default().fetch(&patch).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_series_line() {
+        assert_eq!(parse_series_line("0000.patch -p1 rt"), Some(("0000.patch", 1, "rt")));
+        assert_eq!(parse_series_line("garbage"), None);
+    }
+}
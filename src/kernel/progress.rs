@@ -0,0 +1,358 @@
+//! Compile progress and build logs
+//!
+//! Follows kbuild's `  CC      path/to/file.o` output to report real
+//! progress, and collects compiler warnings and errors into a structured log
+//! that is written to the build directory so failures can be inspected after
+//! the fact.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::cancel::BuildControl;
+use super::error::KernelError;
+use crate::fs::{walk, WalkOptions};
+use crate::progress::{Progress, Task};
+
+/// Raw make output, relative to the build directory
+pub const BUILD_LOG: &str = "build.log";
+
+/// Structured warnings and errors, relative to the build directory
+pub const BUILD_LOG_JSON: &str = "build-log.json";
+
+/// Compilation units of the last successful full build, used as the next estimate
+const OBJECT_COUNT: &str = ".rastos-object-count";
+
+/// Image left in the build directory by an earlier build
+const PREVIOUS_IMAGE: &str = "vmlinux";
+
+/// Severity of a compiler diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticLevel {
+    /// Compiler warning
+    Warning,
+    /// Compiler or make error
+    Error,
+}
+
+/// A warning or error reported during the build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Severity
+    pub level: DiagnosticLevel,
+    /// Source file, if reported
+    pub file: Option<String>,
+    /// Line number, if reported
+    pub line: Option<u32>,
+    /// Diagnostic text
+    pub message: String,
+}
+
+/// Structured log of a compile run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildLog {
+    /// Compilation units built
+    pub compiled: u64,
+    /// Compiler warnings
+    pub warnings: Vec<Diagnostic>,
+    /// Compiler and make errors
+    pub errors: Vec<Diagnostic>,
+    /// Whether the build succeeded
+    pub success: bool,
+}
+
+impl BuildLog {
+    /// Load the structured log of the last build in `build_dir`
+    pub fn load(build_dir: &Path) -> Result<Self, KernelError> {
+        let path = build_dir.join(BUILD_LOG_JSON);
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| KernelError::InvalidConfig(format!("Invalid build log {}: {}", path.display(), e)))
+    }
+
+    /// Write the structured log to `build_dir`
+    pub fn save(&self, build_dir: &Path) -> Result<(), KernelError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| KernelError::BuildFailed(format!("Could not serialize build log: {}", e)))?;
+        fs::write(build_dir.join(BUILD_LOG_JSON), json)?;
+        Ok(())
+    }
+
+    /// Record one line of make output, returning the unit being compiled
+    pub fn record(&mut self, line: &str) -> Option<String> {
+        if let Some(unit) = compiled_unit(line) {
+            self.compiled += 1;
+            return Some(unit.to_string());
+        }
+        if let Some(diagnostic) = parse_diagnostic(line) {
+            match diagnostic.level {
+                DiagnosticLevel::Warning => self.warnings.push(diagnostic),
+                DiagnosticLevel::Error => self.errors.push(diagnostic),
+            }
+        }
+        None
+    }
+
+    /// Short description of the first errors, for error messages
+    pub fn summary(&self, limit: usize) -> String {
+        self.errors
+            .iter()
+            .take(limit)
+            .map(|d| match (&d.file, d.line) {
+                (Some(file), Some(line)) => format!("{}:{}: {}", file, line, d.message),
+                _ => d.message.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Object file named by a kbuild `CC`/`AS` line
+fn compiled_unit(line: &str) -> Option<&str> {
+    let rest = line
+        .strip_prefix("  CC ")
+        .or_else(|| line.strip_prefix("  AS "))?
+        .trim_start();
+    let rest = rest.strip_prefix("[M]").unwrap_or(rest).trim();
+    (!rest.is_empty()).then_some(rest)
+}
+
+/// Parse `file:line:col: warning|error: message` and make `***` lines
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    if line.starts_with("make") && line.contains("***") {
+        return Some(Diagnostic {
+            level: DiagnosticLevel::Error,
+            file: None,
+            line: None,
+            message: line.to_string(),
+        });
+    }
+
+    for (marker, level) in [(": warning: ", DiagnosticLevel::Warning), (": error: ", DiagnosticLevel::Error)] {
+        if let Some((location, message)) = line.split_once(marker) {
+            let mut parts = location.split(':');
+            let file = parts.next().filter(|f| !f.is_empty()).map(str::to_string);
+            let line = parts.next().and_then(|l| l.parse().ok());
+            return Some(Diagnostic {
+                level,
+                file,
+                line,
+                message: message.to_string(),
+            });
+        }
+    }
+    None
+}
+
+/// Whether `build_dir` holds an earlier build, which make only updates
+fn is_incremental(build_dir: &Path) -> bool {
+    build_dir.join(PREVIOUS_IMAGE).exists()
+}
+
+/// Estimate how many units a build will compile
+///
+/// An incremental build recompiles an unknown share of the units, so there
+/// is no estimate for it. Full builds use the count recorded by the last
+/// full build; otherwise the number of C and assembly sources outside
+/// tooling directories, which overestimates for trimmed configs.
+pub fn estimate_units(source_dir: &Path, build_dir: &Path) -> Option<u64> {
+    if is_incremental(build_dir) {
+        return None;
+    }
+    if let Some(count) = fs::read_to_string(build_dir.join(OBJECT_COUNT))
+        .ok()
+        .and_then(|c| c.trim().parse().ok())
+    {
+        return Some(count);
+    }
+
+    let options = ["Documentation", "tools", "samples", "scripts", "build", "install", ".git"]
+        .iter()
+        .fold(WalkOptions::default(), |options, dir| options.with_exclude(format!("**/{}", dir)))
+        .with_include("**/*.c")
+        .with_include("**/*.S")
+        .with_parallel();
+    match walk(source_dir, options) {
+        Ok(entries) => Some(entries.filter_map(Result::ok).filter(|e| e.file_type.is_file()).count() as u64)
+            .filter(|&count| count > 0),
+        Err(_) => None,
+    }
+}
+
+/// Run make, reporting progress from its output to `progress`
+///
+/// Output is written to [`BUILD_LOG`] line by line as make prints it, and
+/// the structured log to [`BUILD_LOG_JSON`] in `build_dir`, on success,
+/// failure and interruption alike. Incremental builds report the units
+/// compiled without a total.
+pub fn run_make(
+    args: &[String],
+    env: &[(String, String)],
+    source_dir: &Path,
+    build_dir: &Path,
+    control: &BuildControl,
+    progress: &dyn Progress,
+) -> Result<BuildLog, KernelError> {
+    let full = !is_incremental(build_dir);
+    let task = Task::start(progress, "Compiling kernel", estimate_units(source_dir, build_dir));
+    let mut raw = BufWriter::new(File::create(build_dir.join(BUILD_LOG))?);
+
+    let mut child = control.spawn(
+        Command::new("make")
+            .args(args)
+            .envs(env.iter().map(|(k, v)| (k, v)))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    let (tx, rx) = mpsc::channel();
+    let readers = [
+        forward_lines(child.stdout.take().expect("piped stdout"), tx.clone()),
+        forward_lines(child.stderr.take().expect("piped stderr"), tx),
+    ];
+
+    let mut log = BuildLog::default();
+    // Reported once make has exited, so it is never left running
+    let mut write_error = None;
+    let status = loop {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(line) => {
+                keep_first(&mut write_error, writeln!(raw, "{}", line));
+                if let Some(unit) = log.record(&line) {
+                    // Never report completion before make has exited
+                    if task.total().is_some_and(|total| log.compiled >= total) {
+                        task.set_total(log.compiled + 1);
+                    }
+                    task.set_position(log.compiled, Some(unit));
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => keep_first(&mut write_error, raw.flush()),
+            // Both pipes closed: make is exiting
+            Err(mpsc::RecvTimeoutError::Disconnected) => break control.wait(&mut child),
+        }
+
+        match control.poll(&mut child) {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    for reader in readers {
+        let _ = reader.join();
+    }
+    // Drain what make printed before exiting
+    for line in rx.try_iter() {
+        keep_first(&mut write_error, writeln!(raw, "{}", line));
+        log.record(&line);
+    }
+    keep_first(&mut write_error, raw.flush());
+    if let Some(e) = write_error {
+        task.fail(format!("could not write {}: {}", BUILD_LOG, e));
+        return Err(e.into());
+    }
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            log.save(build_dir)?;
+            task.fail(format!("build stopped after {} units", log.compiled));
+            return Err(e);
+        }
+    };
+    log.success = status.success();
+    log.save(build_dir)?;
+
+    if !log.success {
+        task.fail(format!("build failed ({} errors)", log.errors.len()));
+        return Err(KernelError::BuildFailed(format!(
+            "make exited with {}: {} (full log: {})",
+            status,
+            log.summary(3),
+            log_path(build_dir).display()
+        )));
+    }
+
+    // An incremental build's count says nothing about the next full one
+    if full {
+        fs::write(build_dir.join(OBJECT_COUNT), log.compiled.to_string())?;
+    }
+    task.set_total(log.compiled.max(1));
+    task.finish(format!(
+        "Kernel compiled successfully ({} units, {} warnings)",
+        log.compiled,
+        log.warnings.len()
+    ));
+    Ok(log)
+}
+
+/// Path of the raw build log in `build_dir`
+pub fn log_path(build_dir: &Path) -> PathBuf {
+    build_dir.join(BUILD_LOG)
+}
+
+/// Remember `result`'s error unless an earlier one was kept
+fn keep_first(error: &mut Option<io::Error>, result: io::Result<()>) {
+    if let Err(e) = result {
+        error.get_or_insert(e);
+    }
+}
+
+fn forward_lines<R: Read + Send + 'static>(reader: R, tx: mpsc::Sender<String>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_output() {
+        let mut log = BuildLog::default();
+        assert_eq!(log.record("  CC      kernel/fork.o"), Some("kernel/fork.o".to_string()));
+        assert_eq!(log.record("  CC [M]  fs/btrfs/super.o"), Some("fs/btrfs/super.o".to_string()));
+        assert_eq!(log.record("  AS      arch/x86/entry/entry_64.o"), Some("arch/x86/entry/entry_64.o".to_string()));
+        assert_eq!(log.record("  LD      vmlinux.o"), None);
+        log.record("kernel/fork.c:120:5: warning: unused variable 'x' [-Wunused-variable]");
+        log.record("fs/btrfs/super.c:42:1: error: expected ';' before '}' token");
+        log.record("make[2]: *** [scripts/Makefile.build:243: fs/btrfs/super.o] Error 1");
+
+        assert_eq!(log.compiled, 3);
+        assert_eq!(log.warnings.len(), 1);
+        assert_eq!(log.warnings[0].line, Some(120));
+        assert_eq!(log.errors.len(), 2);
+        assert_eq!(log.summary(1), "fs/btrfs/super.c:42: expected ';' before '}' token");
+    }
+
+    #[test]
+    fn test_estimate_units() -> Result<(), Box<dyn std::error::Error>> {
+        let source = tempdir()?;
+        let build = tempdir()?;
+        fs::create_dir_all(source.path().join("kernel"))?;
+        fs::create_dir_all(source.path().join("tools"))?;
+        fs::write(source.path().join("kernel/fork.c"), "")?;
+        fs::write(source.path().join("kernel/entry.S"), "")?;
+        fs::write(source.path().join("tools/perf.c"), "")?;
+        assert_eq!(estimate_units(source.path(), build.path()), Some(2));
+
+        fs::write(build.path().join(OBJECT_COUNT), "1234\n")?;
+        assert_eq!(estimate_units(source.path(), build.path()), Some(1234));
+
+        // Incremental builds have no estimate
+        fs::write(build.path().join(PREVIOUS_IMAGE), "")?;
+        assert_eq!(estimate_units(source.path(), build.path()), None);
+        Ok(())
+    }
+}
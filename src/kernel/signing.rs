@@ -0,0 +1,293 @@
+//! Module signing and Secure Boot
+//!
+//! Manages the key pair used to sign kernel modules and the kernel image.
+//! Modules are signed by the kernel build itself (`CONFIG_MODULE_SIG_ALL`)
+//! with the managed key; the installed image is signed with `sbsign` and the
+//! certificate can be enrolled as a Machine Owner Key with `mokutil`.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use super::config::KernelProfile;
+use super::error::KernelError;
+use crate::fs::{walk, WalkOptions};
+
+/// Default directory holding the signing keys
+pub const DEFAULT_KEY_DIR: &str = "/etc/rast/kernel/keys";
+
+/// Marker appended to signed kernel modules
+const MODULE_SIG_MAGIC: &[u8] = b"~Module signature appended~\n";
+
+/// OpenSSL configuration for the signing certificate
+const X509_GENKEY: &str = "[ req ]
+default_bits = 4096
+distinguished_name = req_distinguished_name
+prompt = no
+string_mask = utf8only
+x509_extensions = myexts
+
+[ req_distinguished_name ]
+CN = rastOS kernel signing key
+
+[ myexts ]
+basicConstraints=critical,CA:FALSE
+keyUsage=digitalSignature
+extendedKeyUsage=codeSigning
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid
+";
+
+/// Module signing and Secure Boot settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningConfig {
+    /// Directory holding the key pair
+    pub key_dir: PathBuf,
+
+    /// Sign kernel modules and refuse unsigned ones at runtime
+    pub sign_modules: bool,
+
+    /// Sign the kernel image for Secure Boot
+    pub secure_boot: bool,
+
+    /// Queue the certificate for MOK enrollment on next boot
+    pub enroll_mok: bool,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            key_dir: PathBuf::from(DEFAULT_KEY_DIR),
+            sign_modules: false,
+            secure_boot: false,
+            enroll_mok: false,
+        }
+    }
+}
+
+impl SigningConfig {
+    /// Set the key directory
+    pub fn with_key_dir<P: AsRef<Path>>(mut self, key_dir: P) -> Self {
+        self.key_dir = key_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Enable module signing
+    pub fn with_module_signing(mut self, sign_modules: bool) -> Self {
+        self.sign_modules = sign_modules;
+        self
+    }
+
+    /// Enable Secure Boot image signing
+    pub fn with_secure_boot(mut self, secure_boot: bool) -> Self {
+        self.secure_boot = secure_boot;
+        self
+    }
+
+    /// Enable MOK enrollment
+    pub fn with_mok_enrollment(mut self, enroll_mok: bool) -> Self {
+        self.enroll_mok = enroll_mok;
+        self
+    }
+
+    /// Apply profile requirements (Production refuses unsigned modules, so it always signs)
+    pub fn for_profile(mut self, profile: KernelProfile) -> Self {
+        if profile == KernelProfile::Production {
+            self.sign_modules = true;
+        }
+        self
+    }
+
+    /// Whether any signing is requested
+    pub fn is_enabled(&self) -> bool {
+        self.sign_modules || self.secure_boot || self.enroll_mok
+    }
+
+    /// The managed key pair
+    pub fn keys(&self) -> SigningKeys {
+        SigningKeys::new(&self.key_dir)
+    }
+}
+
+/// A signing key pair on disk
+#[derive(Debug, Clone)]
+pub struct SigningKeys {
+    dir: PathBuf,
+}
+
+impl SigningKeys {
+    /// Key pair stored in `dir`
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Private key and certificate in one PEM file (as `CONFIG_MODULE_SIG_KEY` expects)
+    pub fn combined_pem(&self) -> PathBuf {
+        self.dir.join("signing_key.pem")
+    }
+
+    /// Private key (PEM)
+    pub fn key(&self) -> PathBuf {
+        self.dir.join("signing_key.key")
+    }
+
+    /// Certificate (PEM), used by sbsign
+    pub fn cert(&self) -> PathBuf {
+        self.dir.join("signing_key.crt")
+    }
+
+    /// Certificate (DER), used for MOK enrollment
+    pub fn cert_der(&self) -> PathBuf {
+        self.dir.join("signing_key.der")
+    }
+
+    /// Whether the key pair exists
+    pub fn exists(&self) -> bool {
+        [self.combined_pem(), self.key(), self.cert(), self.cert_der()]
+            .iter()
+            .all(|p| p.exists())
+    }
+
+    /// Generate the key pair unless it already exists
+    pub fn ensure(&self) -> Result<(), KernelError> {
+        if self.exists() {
+            return Ok(());
+        }
+
+        info!("Generating kernel signing key in {}", self.dir.display());
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+        // openssl truncates the existing file, keeping its mode
+        create_private(&self.key())?;
+        let genkey = self.dir.join("x509.genkey");
+        fs::write(&genkey, X509_GENKEY)?;
+
+        run(
+            "openssl",
+            &[
+                "req", "-new", "-nodes", "-utf8", "-sha512", "-days", "36500", "-batch", "-x509",
+                "-config", &path_str(&genkey), "-outform", "PEM",
+                "-out", &path_str(&self.cert()), "-keyout", &path_str(&self.key()),
+            ],
+        )?;
+        run(
+            "openssl",
+            &["x509", "-in", &path_str(&self.cert()), "-outform", "DER", "-out", &path_str(&self.cert_der())],
+        )?;
+
+        let combined = fs::read_to_string(self.key())? + &fs::read_to_string(self.cert())?;
+        create_private(&self.combined_pem())?.write_all(combined.as_bytes())?;
+        Ok(())
+    }
+
+    /// Kernel config overrides making the build sign modules with this key
+    pub fn kconfig(&self, enforce: bool) -> String {
+        format!(
+            "CONFIG_MODULES=y\nCONFIG_MODULE_SIG=y\nCONFIG_MODULE_SIG_ALL=y\nCONFIG_MODULE_SIG_SHA512=y\nCONFIG_MODULE_SIG_HASH=\"sha512\"\nCONFIG_MODULE_SIG_KEY=\"{}\"\n{}\n",
+            self.combined_pem().display(),
+            if enforce { "CONFIG_MODULE_SIG_FORCE=y" } else { "# CONFIG_MODULE_SIG_FORCE is not set" }
+        )
+    }
+
+    /// Sign a kernel image for Secure Boot, replacing it in place
+    pub fn sign_image(&self, image: &Path) -> Result<(), KernelError> {
+        let signed = image.with_extension("signed");
+        run(
+            "sbsign",
+            &[
+                "--key", &path_str(&self.key()),
+                "--cert", &path_str(&self.cert()),
+                "--output", &path_str(&signed),
+                &path_str(image),
+            ],
+        )?;
+        fs::rename(&signed, image)?;
+        Ok(())
+    }
+
+    /// Queue the certificate for MOK enrollment (confirmed in the MOK manager on next boot)
+    pub fn enroll_mok(&self) -> Result<(), KernelError> {
+        run("mokutil", &["--import", &path_str(&self.cert_der()), "--root-pw"])
+    }
+}
+
+/// Whether a kernel module file carries an appended signature
+pub fn is_module_signed(module: &Path) -> Result<bool, KernelError> {
+    let data = fs::read(module)?;
+    Ok(data.ends_with(MODULE_SIG_MAGIC))
+}
+
+/// Find unsigned `.ko` files below a directory
+pub fn unsigned_modules(dir: &Path) -> Result<Vec<PathBuf>, KernelError> {
+    let mut unsigned = Vec::new();
+    if !dir.exists() {
+        return Ok(unsigned);
+    }
+
+    let options = WalkOptions::default().with_include("**/*.ko");
+    for entry in walk(dir, options).map_err(std::io::Error::from)? {
+        let entry = entry.map_err(std::io::Error::from)?;
+        if entry.file_type.is_file() && !is_module_signed(&entry.path)? {
+            unsigned.push(entry.path);
+        }
+    }
+    Ok(unsigned)
+}
+
+/// Create an empty file only the owner can read, replacing any leftover
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), KernelError> {
+    debug!("Running: {} {}", program, args.join(" "));
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(KernelError::command_error(program, &output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_module_signature_detection() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let signed = dir.path().join("signed.ko");
+        let unsigned = dir.path().join("sub/unsigned.ko");
+        fs::create_dir_all(unsigned.parent().unwrap())?;
+        fs::write(&signed, [b"\x7fELF...".as_slice(), MODULE_SIG_MAGIC].concat())?;
+        fs::write(&unsigned, b"\x7fELF...")?;
+
+        assert!(is_module_signed(&signed)?);
+        assert_eq!(unsigned_modules(dir.path())?, vec![unsigned]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_signing_kconfig() {
+        let keys = SigningKeys::new("/etc/rast/kernel/keys");
+        let config = keys.kconfig(true);
+        assert!(config.contains("CONFIG_MODULE_SIG_KEY=\"/etc/rast/kernel/keys/signing_key.pem\""));
+        assert!(config.contains("CONFIG_MODULE_SIG_FORCE=y"));
+        assert!(keys.kconfig(false).contains("# CONFIG_MODULE_SIG_FORCE is not set"));
+    }
+}
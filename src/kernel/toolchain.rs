@@ -0,0 +1,174 @@
+//! Compiler toolchains
+//!
+//! Selects between GCC and Clang/LLVM builds, checks the installed versions
+//! against the kernel's minimums, and produces the config overrides for
+//! Clang LTO and Rust support (`CONFIG_RUST`, which requires LLVM).
+
+use std::path::Path;
+use std::process::Command;
+
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::error::KernelError;
+
+/// Oldest GCC supported by current kernels
+pub const MIN_GCC: (u32, u32) = (5, 1);
+
+/// Oldest Clang supported by current kernels
+pub const MIN_CLANG: (u32, u32) = (13, 0);
+
+/// Compiler toolchain used for the build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum Toolchain {
+    /// GCC and binutils
+    #[default]
+    Gcc,
+    /// Clang and the LLVM utilities (`LLVM=1`)
+    Llvm,
+}
+
+/// Link-time optimization (Clang only)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum LtoMode {
+    /// No LTO
+    #[default]
+    None,
+    /// ThinLTO
+    Thin,
+    /// Full LTO
+    Full,
+}
+
+/// Whether to build Rust support into the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+pub enum RustSupport {
+    /// Enable when building with LLVM and the Rust toolchain is usable
+    #[default]
+    Auto,
+    /// Require Rust support
+    On,
+    /// Never enable Rust support
+    Off,
+}
+
+impl Toolchain {
+    /// C compiler executable
+    pub fn compiler(&self) -> &'static str {
+        match self {
+            Self::Gcc => "gcc",
+            Self::Llvm => "clang",
+        }
+    }
+
+    /// make variables selecting the toolchain
+    pub fn make_args(&self) -> Vec<String> {
+        match self {
+            Self::Gcc => Vec::new(),
+            Self::Llvm => vec!["LLVM=1".to_string()],
+        }
+    }
+
+    /// Installed compiler version as (major, minor)
+    pub fn version(&self) -> Result<(u32, u32), KernelError> {
+        let compiler = self.compiler();
+        let output = Command::new(compiler)
+            .arg("--version")
+            .output()
+            .map_err(|_| KernelError::Unsupported(format!("{} is not installed", compiler)))?;
+        if !output.status.success() {
+            return Err(KernelError::command_error(format!("{} --version", compiler), &output));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        parse_version(stdout.lines().next().unwrap_or_default())
+            .ok_or_else(|| KernelError::Unsupported(format!("Could not parse {} version: {}", compiler, stdout.trim())))
+    }
+
+    /// Check that the compiler is recent enough and supports the requested LTO
+    pub fn validate(&self, lto: LtoMode) -> Result<(), KernelError> {
+        if lto != LtoMode::None && *self != Self::Llvm {
+            return Err(KernelError::Unsupported(format!("{:?} LTO requires the LLVM toolchain", lto)));
+        }
+
+        let version = self.version()?;
+        let minimum = match self {
+            Self::Gcc => MIN_GCC,
+            Self::Llvm => MIN_CLANG,
+        };
+        debug!("{} version {}.{}", self.compiler(), version.0, version.1);
+        if version < minimum {
+            return Err(KernelError::Unsupported(format!(
+                "{} {}.{} is too old, at least {}.{} is required",
+                self.compiler(),
+                version.0,
+                version.1,
+                minimum.0,
+                minimum.1
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the kernel tree can be built with Rust support (`make rustavailable`)
+    pub fn rust_available(&self, source_dir: &Path) -> bool {
+        if *self != Self::Llvm {
+            return false;
+        }
+        Command::new("make")
+            .arg("-C")
+            .arg(source_dir)
+            .args(self.make_args())
+            .arg("rustavailable")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+}
+
+/// Config overrides for the LTO mode and Rust support
+pub fn toolchain_kconfig(lto: LtoMode, rust: bool) -> String {
+    let mut config = String::new();
+    match lto {
+        LtoMode::None => {}
+        LtoMode::Thin => config.push_str("# CONFIG_LTO_NONE is not set\nCONFIG_LTO_CLANG_THIN=y\n"),
+        LtoMode::Full => config.push_str("# CONFIG_LTO_NONE is not set\nCONFIG_LTO_CLANG_FULL=y\n"),
+    }
+    if rust {
+        config.push_str("CONFIG_RUST=y\n");
+    }
+    config
+}
+
+/// Extract (major, minor) from a `--version` banner line
+fn parse_version(line: &str) -> Option<(u32, u32)> {
+    line.split_whitespace().find_map(|word| {
+        let mut parts = word.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((major, minor))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("gcc (GCC) 14.2.1 20240910"), Some((14, 2)));
+        assert_eq!(parse_version("clang version 18.1.8"), Some((18, 1)));
+        assert_eq!(parse_version("Ubuntu clang version 15.0.7-0ubuntu0.22.04.3"), Some((15, 0)));
+        assert_eq!(parse_version("no version here"), None);
+    }
+
+    #[test]
+    fn test_toolchain_kconfig() {
+        assert_eq!(toolchain_kconfig(LtoMode::None, false), "");
+        let config = toolchain_kconfig(LtoMode::Thin, true);
+        assert!(config.contains("CONFIG_LTO_CLANG_THIN=y"));
+        assert!(config.contains("CONFIG_RUST=y"));
+        assert!(Toolchain::Gcc.validate(LtoMode::Thin).is_err());
+    }
+}
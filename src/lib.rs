@@ -3,50 +3,67 @@
 //! This library provides the core functionality for the rastOS system,
 //! including container management, package management, system installation,
 //! and snapshot management.
+//!
+//! # Features
+//!
+//! The default features build everything but the daemon. Consumers that
+//! need less can turn `default-features` off and pick:
+//!
+//! - `cli`: the `rastos` command and the other command-line tools
+//! - `backup`: backups to local storage; `backup-s3` adds S3-compatible
+//!   storage and `backup-cloud` every cloud backend
+//! - `containers`: OCI bundles and images ([`oci`]) and AUR builds in
+//!   containers
+//! - `kernel`: kernel builds; boot entries and initramfs generation are
+//!   always available
+//! - `installer`: the installation engine ([`installer::Installer`]); the
+//!   install profile types are always available
+//! - `daemon`, `dashboard`, `dbus`: rastosd and its APIs
+//! - `alpm`, `btrfs-ioctl`, `statx`: native backends instead of spawning
+//!   `pacman` and `btrfs`
+//!
+//! # Privileges
+//!
+//! The tools and rastosd can run unprivileged: the few operations that need
+//! root are performed by `rastos-helper` on their behalf ([`helper`]).
 
 #![warn(missing_docs)]
 #![warn(rustdoc::missing_crate_level_docs)]
 //#![forbid(unsafe_code)]
 
-/// Authentication and authorization module
-/// 
-/// Provides a unified interface for API key authentication across different services.
-pub mod auth {
-    pub use crate::auth_internal::*;
-}
-
-mod auth_internal {
-    //! Internal implementation of authentication functionality
-    //! 
-    //! This module is re-exported by the parent `auth` module.
-    
-    /// API key management and validation
-    pub mod api_key;
-    
-    /// Configuration for authentication
-    pub mod config;
-    
-    /// Command-line interface for managing API keys
-    pub mod cli;
-    
-    // Re-export the main types for convenience
-    pub use api_key::{ApiKey, ApiKeyManager, AuthError};
-    pub use config::{ApiKeyConfig, ConfigError};
-    pub use cli::{ApiKeyCommand, AddKeyArgs, RemoveKeyArgs, GenerateKeyArgs};
-}
+pub mod auth;
 
 /// OCI (Open Container Initiative) runtime implementation
+#[cfg(feature = "containers")]
 pub mod oci;
 
 // Other core modules
+#[cfg(feature = "backup")]
+pub mod backup;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod dry_run;
+pub mod error;
+pub mod fs;
+pub mod helper;
 pub mod installer;
+pub mod jobs;
 pub mod kernel;
+pub mod lifecycle;
+pub mod output;
 pub mod package;
+pub mod progress;
+pub mod remote;
+pub mod sandbox;
+pub mod secrets;
 pub mod snapshot;
+pub mod sys;
 pub mod system;
+pub mod transaction;
 
 // Re-export commonly used types
+#[cfg(feature = "containers")]
 pub use oci::*;
-
-/// Type alias for the standard result type with our error type
-pub type Result<T> = std::result::Result<T, oci::ContainerError>;
+pub use error::{Context, ErrorCode, RastosError, Result};
@@ -0,0 +1,68 @@
+//! Lifecycles as state machines
+//!
+//! Things that go through a lifecycle — containers, backups — keep their
+//! state in an enum implementing [`Lifecycle`]. The state only changes
+//! through [`Lifecycle::apply`], which looks the operation up in the
+//! state's transition table and refuses operations the state does not
+//! allow, such as executing a command in a container that was never
+//! started. Since [`Lifecycle::STATES`] and [`Lifecycle::OPS`] list every
+//! state and operation, tests can check the whole table.
+
+use std::fmt::Display;
+
+use thiserror::Error;
+
+/// An operation the current state does not allow
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Cannot {op} a {kind} that is {state}")]
+pub struct InvalidTransition {
+    /// What has the lifecycle, such as `container`
+    pub kind: &'static str,
+    /// The refused operation
+    pub op: String,
+    /// The state it was refused in
+    pub state: String,
+}
+
+/// States of a lifecycle and the operations moving between them
+pub trait Lifecycle: Copy + Eq + Display + 'static {
+    /// Operations that change the state, or require a certain one
+    type Op: Copy + Display + 'static;
+
+    /// What has the lifecycle, for error messages
+    const KIND: &'static str;
+
+    /// Every state
+    const STATES: &'static [Self];
+
+    /// Every operation
+    const OPS: &'static [Self::Op];
+
+    /// The state after `op`, or `None` if this state does not allow it
+    ///
+    /// Operations that only require a state lead back to it.
+    fn next(self, op: Self::Op) -> Option<Self>;
+
+    /// The state after `op`, or the error refusing it
+    fn check(self, op: Self::Op) -> Result<Self, InvalidTransition> {
+        self.next(op).ok_or_else(|| InvalidTransition {
+            kind: Self::KIND,
+            op: op.to_string(),
+            state: self.to_string(),
+        })
+    }
+
+    /// Perform `op`, moving to the next state
+    fn apply(&mut self, op: Self::Op) -> Result<(), InvalidTransition> {
+        *self = self.check(op)?;
+        Ok(())
+    }
+}
+
+/// Every transition of `L`, including refused ones, in table order
+pub fn transitions<L: Lifecycle>() -> Vec<(L, L::Op, Option<L>)> {
+    L::STATES
+        .iter()
+        .flat_map(|&state| L::OPS.iter().map(move |&op| (state, op, state.next(op))))
+        .collect()
+}
@@ -1,9 +1,13 @@
 //! Container management for OCI runtime
 
 use super::*;
+use std::fmt::{self, Display};
 use std::path::{Path, PathBuf};
 use oci_spec::runtime::{Spec, SpecBuilder, LinuxBuilder, ProcessBuilder, RootBuilder};
 
+use crate::lifecycle::Lifecycle;
+use crate::system::mac::MacLabel;
+
 /// Represents an OCI container instance
 #[derive(Debug)]
 pub struct Container {
@@ -15,12 +19,14 @@ pub struct Container {
     bundle: PathBuf,
     /// OCI runtime specification
     spec: Spec,
-    /// Container state
-    #[allow(dead_code)]
+    /// Container state, only changed through its [`Lifecycle`]
     state: ContainerState,
 }
 
 /// Represents the state of a container
+///
+/// A container starts out created; once stopped it may be started again or
+/// deleted. See [`ContainerState::next`] for every transition.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ContainerState {
     /// Container has been created but not started
@@ -41,6 +47,91 @@ impl Default for ContainerState {
     }
 }
 
+impl ContainerState {
+    /// Lowercase name, as shown by the daemon's APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Running => "running",
+            Self::Stopped => "stopped",
+            Self::Paused => "paused",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl Display for ContainerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Operations on a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerOp {
+    /// Start the container's process
+    Start,
+    /// Freeze the running process
+    Pause,
+    /// Thaw a paused process
+    Resume,
+    /// Run another process in the container
+    Exec,
+    /// Stop the container's processes
+    Stop,
+    /// Record that the runtime failed
+    Fail,
+    /// Remove the container
+    Delete,
+}
+
+impl Display for ContainerOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Start => "start",
+            Self::Pause => "pause",
+            Self::Resume => "resume",
+            Self::Exec => "exec into",
+            Self::Stop => "stop",
+            Self::Fail => "fail",
+            Self::Delete => "delete",
+        })
+    }
+}
+
+impl Lifecycle for ContainerState {
+    type Op = ContainerOp;
+
+    const KIND: &'static str = "container";
+
+    const STATES: &'static [Self] = &[Self::Created, Self::Running, Self::Paused, Self::Stopped, Self::Error];
+
+    const OPS: &'static [ContainerOp] = &[
+        ContainerOp::Start,
+        ContainerOp::Pause,
+        ContainerOp::Resume,
+        ContainerOp::Exec,
+        ContainerOp::Stop,
+        ContainerOp::Fail,
+        ContainerOp::Delete,
+    ];
+
+    fn next(self, op: ContainerOp) -> Option<Self> {
+        use ContainerOp::*;
+        use ContainerState::*;
+        match (self, op) {
+            (Created | Stopped, Start) => Some(Running),
+            (Running, Pause) => Some(Paused),
+            (Paused, Resume) => Some(Running),
+            (Running, Exec) => Some(Running),
+            (Created | Running | Paused | Error, Stop) => Some(Stopped),
+            (Created | Running | Paused, Fail) => Some(Error),
+            (Created | Stopped | Error, Delete) => Some(self),
+            _ => None,
+        }
+    }
+}
+
 impl Container {
     /// Create a new container instance
     pub fn new(id: &str, bundle: &Path) -> Result<Self> {
@@ -63,14 +154,40 @@ impl Container {
         // 3. Set up rootfs
         // 4. Start the container process
         
-        self.state = ContainerState::Running;
+        self.state.apply(ContainerOp::Start)?;
         Ok(())
     }
     
     /// Stop the container
     pub fn stop(&mut self) -> Result<()> {
         // TODO: Implement container stop logic
-        self.state = ContainerState::Stopped;
+        self.state.apply(ContainerOp::Stop)?;
+        Ok(())
+    }
+
+    /// Pause the container
+    pub fn pause(&mut self) -> Result<()> {
+        // TODO: Freeze the container's cgroup
+        self.state.apply(ContainerOp::Pause)?;
+        Ok(())
+    }
+
+    /// Resume a paused container
+    pub fn resume(&mut self) -> Result<()> {
+        // TODO: Thaw the container's cgroup
+        self.state.apply(ContainerOp::Resume)?;
+        Ok(())
+    }
+
+    /// Record that the runtime failed to manage the container
+    pub fn fail(&mut self) -> Result<()> {
+        self.state.apply(ContainerOp::Fail)?;
+        Ok(())
+    }
+
+    /// Check that the container's state allows `op`, without performing it
+    pub fn check(&self, op: ContainerOp) -> Result<()> {
+        self.state.check(op)?;
         Ok(())
     }
     
@@ -93,6 +210,7 @@ pub struct ContainerBuilder {
     root: Option<PathBuf>,
     process: Option<ProcessBuilder>,
     linux: Option<LinuxBuilder>,
+    mac_label: Option<MacLabel>,
 }
 
 impl ContainerBuilder {
@@ -122,6 +240,12 @@ impl ContainerBuilder {
         self
     }
 
+    /// Confine the container with an SELinux or AppArmor label
+    pub fn mac_label(mut self, label: MacLabel) -> Self {
+        self.mac_label = Some(label);
+        self
+    }
+
     /// Build the container specification
     pub fn build(self) -> Result<Spec> {
         let process = self.process.ok_or_else(|| 
@@ -132,6 +256,14 @@ impl ContainerBuilder {
             ContainerError::InvalidConfig("Linux configuration is required".to_string())
         )?;
         
+        let (process, linux) = match self.mac_label {
+            Some(MacLabel::AppArmor(profile)) => (process.apparmor_profile(profile), linux),
+            Some(MacLabel::SELinux { process: label, mount }) => {
+                (process.selinux_label(label), linux.mount_label(mount))
+            }
+            None => (process, linux),
+        };
+
         let mut spec_builder = SpecBuilder::default()
             .process(process.build()?)
             .linux(linux.build()?);
@@ -186,9 +318,43 @@ mod tests {
         
         container.stop().unwrap();
         assert_eq!(container.status(), ContainerState::Stopped);
+        assert!(container.check(ContainerOp::Exec).is_err());
         
         Ok(())
     }
+
+    #[test]
+    fn test_container_transitions() {
+        use ContainerOp::*;
+        use ContainerState::*;
+        let allowed: Vec<_> = crate::lifecycle::transitions::<ContainerState>()
+            .into_iter()
+            .filter_map(|(state, op, next)| next.map(|next| (state, op, next)))
+            .collect();
+        assert_eq!(
+            allowed,
+            [
+                (Created, Start, Running),
+                (Created, Stop, Stopped),
+                (Created, Fail, Error),
+                (Created, Delete, Created),
+                (Running, Pause, Paused),
+                (Running, Exec, Running),
+                (Running, Stop, Stopped),
+                (Running, Fail, Error),
+                (Paused, Resume, Running),
+                (Paused, Stop, Stopped),
+                (Paused, Fail, Error),
+                (Stopped, Start, Running),
+                (Stopped, Delete, Stopped),
+                (Error, Stop, Stopped),
+                (Error, Delete, Error),
+            ]
+        );
+
+        let err = Created.check(Exec).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot exec into a container that is created");
+    }
     
     #[test]
     fn test_container_builder() -> Result<()> {
@@ -205,6 +371,16 @@ mod tests {
             
         assert!(spec.process().is_some());
         assert!(spec.linux().is_some());
+
+        let spec = ContainerBuilder::new("test-label")
+            .process(ProcessBuilder::default().cwd("/").args(vec!["/bin/sh".to_string()]))
+            .linux(LinuxBuilder::default())
+            .mac_label(MacLabel::AppArmor("rastos.container".to_string()))
+            .build()?;
+        assert_eq!(
+            spec.process().as_ref().and_then(|p| p.apparmor_profile().clone()),
+            Some("rastos.container".to_string())
+        );
         
         Ok(())
     }
@@ -22,6 +22,14 @@ pub enum ContainerError {
     #[error("Runtime error: {0}")]
     Runtime(String),
     
+    /// An operation the container's state does not allow
+    #[error(transparent)]
+    InvalidTransition(#[from] crate::lifecycle::InvalidTransition),
+    
+    /// Image pull, verification or unpack error
+    #[error("Image error: {0}")]
+    Image(String),
+    
     /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -0,0 +1,284 @@
+//! OCI images
+//!
+//! Pulls images into an OCI image layout with `skopeo` and unpacks their
+//! layers onto a directory, verifying every blob's digest and applying
+//! whiteouts so the result matches the image's final filesystem.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use log::{debug, info};
+use oci_spec::image::{Descriptor, ImageIndex, ImageManifest};
+use sha2::{Digest, Sha256};
+
+use super::error::ContainerError;
+use crate::fs::canonicalize_within;
+use crate::progress::{Progress, Task};
+use super::Result;
+
+/// Annotation naming an image in an OCI layout's index
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Whiteout prefix for deleted files
+const WHITEOUT: &str = ".wh.";
+
+/// Whiteout marking a directory whose lower contents are hidden
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// An image stored in an OCI image layout
+#[derive(Debug, Clone)]
+pub struct OciImage {
+    layout: PathBuf,
+    tag: String,
+}
+
+impl OciImage {
+    /// Pull `reference` (`registry/name:tag`) into the layout at `layout`
+    ///
+    /// skopeo's per-blob output is reported to `progress`.
+    pub fn pull<P: AsRef<Path>>(reference: &str, layout: P, progress: &dyn Progress) -> Result<Self> {
+        let layout = layout.as_ref();
+        let tag = reference_tag(reference).to_string();
+        fs::create_dir_all(layout)?;
+
+        info!("Pulling {}", reference);
+        let task = Task::start(progress, format!("Pulling {}", reference), None);
+        let mut child = Command::new("skopeo")
+            .arg("copy")
+            .arg(format!("docker://{}", reference))
+            .arg(format!("oci:{}:{}", layout.display(), tag))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| ContainerError::Image(format!("Could not run skopeo: {}", e)))?;
+
+        let mut stderr = child.stderr.take().expect("piped stderr");
+        let errors = thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+        let stdout = child.stdout.take().expect("piped stdout");
+        for line in BufReader::new(stdout).lines().map_while(std::result::Result::ok) {
+            let line = line.trim();
+            if !line.is_empty() {
+                task.message(line);
+            }
+        }
+        let status = child.wait()?;
+        let errors = errors.join().unwrap_or_default();
+        if !status.success() {
+            let error = format!("Pulling {} failed: {}", reference, errors.trim());
+            task.fail(&error);
+            return Err(ContainerError::Image(error));
+        }
+
+        let image = Self::open(layout, &tag)?;
+        task.finish(format!("Pulled {}", reference));
+        Ok(image)
+    }
+
+    /// Open the image tagged `tag` in an existing layout
+    pub fn open<P: AsRef<Path>>(layout: P, tag: &str) -> Result<Self> {
+        let image = Self {
+            layout: layout.as_ref().to_path_buf(),
+            tag: tag.to_string(),
+        };
+        image.manifest_descriptor()?;
+        Ok(image)
+    }
+
+    /// Image manifest
+    pub fn manifest(&self) -> Result<ImageManifest> {
+        let descriptor = self.manifest_descriptor()?;
+        let path = self.blob(&descriptor)?;
+        Ok(ImageManifest::from_file(path)?)
+    }
+
+    /// Layer blobs, bottom first, with verified digests
+    pub fn layers(&self) -> Result<Vec<PathBuf>> {
+        self.manifest()?.layers().iter().map(|layer| self.blob(layer)).collect()
+    }
+
+    /// Unpack the image's filesystem onto `dest`, one step per layer
+    pub fn unpack<P: AsRef<Path>>(&self, dest: P, progress: &dyn Progress) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        let layers = self.layers()?;
+        let task = Task::start(progress, format!("Unpacking {}", self.tag), Some(layers.len() as u64));
+        for (index, layer) in layers.iter().enumerate() {
+            info!("Unpacking layer {}/{}", index + 1, layers.len());
+            let entries = list_layer(layer)?;
+            apply_whiteouts(dest, &entries)?;
+
+            let output = Command::new("tar")
+                .args(["--extract", "--numeric-owner", "--same-permissions", "--xattrs", "--xattrs-include=*"])
+                .arg(format!("--exclude={}*", WHITEOUT))
+                .arg("--directory")
+                .arg(dest)
+                .arg("--file")
+                .arg(layer)
+                .output()?;
+            if !output.status.success() {
+                return Err(ContainerError::Image(format!(
+                    "Unpacking {} failed: {}",
+                    layer.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            task.advance(format!("Unpacked layer {}/{}", index + 1, layers.len()));
+        }
+        task.finish(format!("Unpacked {} layers", layers.len()));
+        Ok(())
+    }
+
+    /// Index entry of the tagged manifest (the only one if untagged)
+    fn manifest_descriptor(&self) -> Result<Descriptor> {
+        let index = ImageIndex::from_file(self.layout.join("index.json"))?;
+        let manifests = index.manifests();
+        let tagged = manifests.iter().find(|m| {
+            m.annotations()
+                .as_ref()
+                .and_then(|a| a.get(REF_NAME))
+                .is_some_and(|name| *name == self.tag)
+        });
+        match (tagged, manifests.as_slice()) {
+            (Some(descriptor), _) => Ok(descriptor.clone()),
+            (None, [only]) => Ok(only.clone()),
+            _ => Err(ContainerError::Image(format!(
+                "No image tagged {} in {}",
+                self.tag,
+                self.layout.display()
+            ))),
+        }
+    }
+
+    /// Path of a blob, after checking its digest
+    fn blob(&self, descriptor: &Descriptor) -> Result<PathBuf> {
+        let digest = descriptor.digest();
+        let hex = digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| ContainerError::Image(format!("Unsupported digest {}", digest)))?;
+        let path = self.layout.join("blobs/sha256").join(hex);
+
+        let mut file = fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != hex {
+            return Err(ContainerError::Image(format!(
+                "Blob {} is corrupt (sha256 {})",
+                digest, actual
+            )));
+        }
+        debug!("Verified blob {}", digest);
+        Ok(path)
+    }
+}
+
+/// Tag of an image reference, `latest` if none is given
+pub fn reference_tag(reference: &str) -> &str {
+    let name = reference.split('@').next().unwrap_or(reference);
+    match name.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') && !repo.is_empty() => tag,
+        _ => "latest",
+    }
+}
+
+/// Entries of a layer tarball
+fn list_layer(layer: &Path) -> Result<Vec<String>> {
+    let output = Command::new("tar").arg("--list").arg("--file").arg(layer).output()?;
+    if !output.status.success() {
+        return Err(ContainerError::Image(format!(
+            "Reading {} failed: {}",
+            layer.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect())
+}
+
+/// Delete what the layer's whiteouts hide from the layers below
+fn apply_whiteouts(dest: &Path, entries: &[String]) -> Result<()> {
+    for entry in entries {
+        let path = Path::new(entry.trim_start_matches("./"));
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        // Links unpacked by earlier layers must not redirect deletions outside the image
+        let parent = canonicalize_within(dest, path.parent().unwrap_or(Path::new("")))
+            .map_err(|e| ContainerError::Image(format!("Resolving whiteout {}: {}", entry, e)))?;
+
+        if name == OPAQUE_WHITEOUT {
+            if let Ok(children) = fs::read_dir(&parent) {
+                for child in children {
+                    remove(&child?.path())?;
+                }
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT) {
+            if hidden.is_empty() || hidden == "." || hidden == ".." || hidden.contains('/') {
+                return Err(ContainerError::Image(format!("Invalid whiteout {}", entry)));
+            }
+            remove(&parent.join(hidden))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reference_tag() {
+        assert_eq!(reference_tag("ghcr.io/rastos/base:2024.10"), "2024.10");
+        assert_eq!(reference_tag("localhost:5000/rastos/base"), "latest");
+        assert_eq!(reference_tag("rastos/base"), "latest");
+    }
+
+    #[test]
+    fn test_apply_whiteouts() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dest = tempdir()?;
+        fs::create_dir_all(dest.path().join("etc/old"))?;
+        fs::write(dest.path().join("etc/old/file"), "")?;
+        fs::write(dest.path().join("etc/keep"), "")?;
+        fs::create_dir_all(dest.path().join("var/cache"))?;
+        fs::write(dest.path().join("var/cache/stale"), "")?;
+
+        apply_whiteouts(
+            dest.path(),
+            &["./etc/.wh.old".to_string(), "var/cache/.wh..wh..opq".to_string()],
+        )?;
+        assert!(!dest.path().join("etc/old").exists());
+        assert!(dest.path().join("etc/keep").exists());
+        assert!(dest.path().join("var/cache").exists());
+        assert!(!dest.path().join("var/cache/stale").exists());
+
+        std::os::unix::fs::symlink("../../..", dest.path().join("etc/up"))?;
+        assert!(apply_whiteouts(dest.path(), &["etc/up/.wh.keep".to_string()]).is_err());
+        assert!(dest.path().join("etc/keep").exists());
+        assert!(apply_whiteouts(dest.path(), &["etc/.wh...".to_string()]).is_err());
+        assert!(dest.path().join("etc").exists());
+        Ok(())
+    }
+}
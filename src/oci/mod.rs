@@ -5,10 +5,12 @@
 
 mod container;
 mod error;
+pub mod image;
 
 // Re-export public interfaces
-pub use container::{Container, ContainerBuilder, ContainerState};
+pub use container::{Container, ContainerBuilder, ContainerOp, ContainerState};
 pub use error::ContainerError;
+pub use image::OciImage;
 
 // Re-export oci_spec types for convenience
 pub use oci_spec::runtime::{
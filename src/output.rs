@@ -0,0 +1,369 @@
+//! Output of the command-line tools
+//!
+//! Every command prints through an [`Output`] in the format selected with
+//! `--format` or `--json`: plain text and aligned tables for people, JSON or
+//! YAML for scripts. In the machine formats stdout carries exactly one
+//! serialized document, so status messages go to stderr instead. Field names
+//! are those of the serialized types; they are part of the interface and are
+//! only renamed in a major release.
+//!
+//! Progress of long-running operations goes to stderr, as bars or as JSON
+//! lines; see [`Output::progress`].
+//!
+//! How a command ended is reported through its [`ExitCode`]. A command that
+//! fails prints its error through [`Output::error`]; in the machine formats
+//! that is a document with an `error` key holding an
+//! [`ErrorReport`](crate::error::ErrorReport) and its stable code.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::dry_run::Plan;
+use crate::error::ErrorReport;
+use crate::progress::{JsonLines, Progress, TerminalProgress};
+
+/// Errors printing command output
+#[derive(Error, Debug)]
+pub enum OutputError {
+    /// Writing to stdout failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The value could not be serialized as JSON
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The value could not be serialized as YAML
+    #[error("YAML error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// Format of command output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Text and tables for people
+    #[default]
+    Text,
+    /// Pretty-printed JSON
+    Json,
+    /// YAML
+    Yaml,
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+        })
+    }
+}
+
+/// Exit status of the command-line tools
+///
+/// The values are stable so that scripts can tell the outcomes apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ExitCode {
+    /// The command succeeded
+    Success = 0,
+    /// The command failed
+    Failure = 1,
+    /// The command line was invalid; clap exits with this too
+    Usage = 2,
+    /// The command ran, but checks found warnings
+    Warning = 3,
+    /// The command ran, but checks failed: critical doctor findings, failed
+    /// preflight checks or a backup that does not verify
+    Critical = 4,
+    /// The command needs privileges the caller does not have
+    PermissionDenied = 5,
+}
+
+impl ExitCode {
+    /// Numeric exit status
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Exit code for a numeric exit status, such as a remote command's
+    ///
+    /// Statuses that are not one of the codes are reported as failures.
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            0 => ExitCode::Success,
+            2 => ExitCode::Usage,
+            3 => ExitCode::Warning,
+            4 => ExitCode::Critical,
+            5 => ExitCode::PermissionDenied,
+            _ => ExitCode::Failure,
+        }
+    }
+
+    /// Exit status for a failed command
+    ///
+    /// Walks the chain of sources, so a permission error wrapped by any of
+    /// the crate's error types is still reported as such.
+    pub fn from_error(err: &(dyn Error + 'static)) -> Self {
+        let denied = std::iter::successors(Some(err), |e| (*e).source())
+            .filter_map(|e| e.downcast_ref::<io::Error>())
+            .any(|e| e.kind() == io::ErrorKind::PermissionDenied);
+        if denied {
+            ExitCode::PermissionDenied
+        } else {
+            ExitCode::Failure
+        }
+    }
+
+    /// Exit the process with this status
+    pub fn exit(self) -> ! {
+        std::process::exit(self.code())
+    }
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> Self {
+        std::process::ExitCode::from(code as u8)
+    }
+}
+
+/// `--format` and `--json`, shared by every command-line tool
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct OutputArgs {
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Print JSON; short for `--format json`
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
+impl OutputArgs {
+    /// Output in the selected format
+    pub fn output(&self) -> Output {
+        Output::new(if self.json { OutputFormat::Json } else { self.format })
+    }
+}
+
+/// Prints command results in one format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    /// Create an output in the given format
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format }
+    }
+
+    /// The output format
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Whether output is for people rather than scripts
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    /// Print `value` serialized, or call `text` to print it for people
+    pub fn print<T: Serialize + ?Sized>(&self, value: &T, text: impl FnOnce()) -> Result<(), OutputError> {
+        match self.render(value)? {
+            Some(document) => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(document.as_bytes())?;
+                stdout.flush()?;
+            }
+            None => text(),
+        }
+        Ok(())
+    }
+
+    /// Print `rows` as a table with one row each, or serialized
+    pub fn table<T: Serialize>(
+        &self,
+        rows: &[T],
+        headers: &[&str],
+        row: impl Fn(&T) -> Vec<String>,
+    ) -> Result<(), OutputError> {
+        self.print(rows, || {
+            let mut table = Table::new(headers);
+            for r in rows {
+                table.push(row(r));
+            }
+            print!("{}", table);
+        })
+    }
+
+    /// Print a status message
+    ///
+    /// Status messages are for people: with a machine format they go to
+    /// stderr, leaving stdout to the serialized result.
+    pub fn status(&self, message: impl Display) {
+        if self.is_text() {
+            println!("{}", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// Print what a dry run would have done
+    pub fn plan(&self, plan: &Plan) -> Result<(), OutputError> {
+        self.print(plan, || {
+            if plan.is_empty() {
+                println!("Nothing would change");
+            }
+            for action in &plan.actions {
+                println!("Would {}", action);
+            }
+        })
+    }
+
+    /// Print the error a command failed with, returning its exit status
+    ///
+    /// People get the message and its causes on stderr; with a machine
+    /// format the report is the document on stdout.
+    pub fn error(&self, err: &(dyn Error + 'static)) -> ExitCode {
+        #[derive(Serialize)]
+        struct Failure {
+            error: ErrorReport,
+        }
+
+        let failure = Failure { error: ErrorReport::new(err) };
+        if self.print(&failure, || {}).is_err() || self.is_text() {
+            eprintln!("Error: {}", failure.error);
+        }
+        ExitCode::from_error(err)
+    }
+
+    /// Where long-running operations report their progress
+    ///
+    /// Bars and spinners for people; JSON lines on stderr otherwise.
+    pub fn progress(&self) -> Arc<dyn Progress> {
+        if self.is_text() {
+            Arc::new(TerminalProgress::new())
+        } else {
+            Arc::new(JsonLines::stderr())
+        }
+    }
+
+    /// `value` as a JSON or YAML document, or `None` for text output
+    pub fn render<T: Serialize + ?Sized>(&self, value: &T) -> Result<Option<String>, OutputError> {
+        Ok(match self.format {
+            OutputFormat::Text => None,
+            OutputFormat::Json => Some(serde_json::to_string_pretty(value)? + "\n"),
+            OutputFormat::Yaml => Some(serde_yaml::to_string(value)?),
+        })
+    }
+}
+
+/// Text table with left-aligned columns
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Create a table with these column headers
+    pub fn new(headers: &[&str]) -> Self {
+        Self {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Append a row
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    /// Add a row
+    pub fn with_row(mut self, row: Vec<String>) -> Self {
+        self.push(row);
+        self
+    }
+
+    /// Whether the table has no rows
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+}
+
+impl Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = std::iter::once(&self.headers).chain(&self.rows);
+        let mut widths = vec![0; self.headers.len()];
+        for line in lines.clone() {
+            for (i, cell) in line.iter().enumerate() {
+                if i >= widths.len() {
+                    widths.push(0);
+                }
+                widths[i] = widths[i].max(cell.chars().count());
+            }
+        }
+
+        for line in lines {
+            let mut text = String::new();
+            for (i, cell) in line.iter().enumerate() {
+                if i > 0 {
+                    text.push_str("  ");
+                }
+                text.push_str(&format!("{:<width$}", cell, width = widths[i]));
+            }
+            writeln!(f, "{}", text.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_alignment() {
+        let table = Table::new(&["ID", "NAME", "STATE"])
+            .with_row(vec!["1".into(), "root".into(), "active".into()])
+            .with_row(vec!["10".into(), "home-snapshot".into(), "".into()]);
+        assert_eq!(
+            table.to_string(),
+            "ID  NAME           STATE\n1   root           active\n10  home-snapshot\n"
+        );
+    }
+
+    #[test]
+    fn test_render_formats() {
+        #[derive(Serialize)]
+        struct Entry {
+            name: &'static str,
+            size: u64,
+        }
+        let entries = [Entry { name: "root", size: 42 }];
+
+        assert_eq!(Output::new(OutputFormat::Text).render(&entries).unwrap(), None);
+        let json = Output::new(OutputFormat::Json).render(&entries).unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap()[0]["size"], 42);
+        let yaml = Output::new(OutputFormat::Yaml).render(&entries).unwrap().unwrap();
+        assert_eq!(yaml, "- name: root\n  size: 42\n");
+    }
+
+    #[test]
+    fn test_exit_code_from_error() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(ExitCode::from_error(&denied), ExitCode::PermissionDenied);
+        let wrapped = crate::transaction::TransactionError::Io(denied);
+        assert_eq!(ExitCode::from_error(&wrapped), ExitCode::PermissionDenied);
+        assert_eq!(ExitCode::from_error(&io::Error::other("boom")), ExitCode::Failure);
+        assert_eq!(ExitCode::Critical.code(), 4);
+    }
+}
@@ -0,0 +1,295 @@
+//! Native libalpm backend
+//!
+//! Talks to libalpm directly instead of spawning `pacman`. Packages are added
+//! to the transaction one by one, libalpm progress and download callbacks are
+//! forwarded as [`AlpmEvent`]s, and transaction failures are reported as
+//! structured [`AlpmTransactionError`]s instead of scraped stderr. Packages are
+//! downloaded concurrently; download progress is reported as an aggregate over
+//! all running downloads.
+
+use std::sync::Arc;
+
+use alpm::{Alpm, AnyDownloadEvent, Progress, TransFlag};
+use thiserror::Error;
+
+use super::download::DownloadProgress;
+use super::{PackageError, PackageSpec};
+
+/// Default pacman configuration file
+pub const DEFAULT_PACMAN_CONF: &str = "/etc/pacman.conf";
+
+/// Structured errors reported by libalpm transactions
+#[derive(Error, Debug)]
+pub enum AlpmTransactionError {
+    /// Packages could not be found in any sync database
+    #[error("Target not found: {}", .0.join(", "))]
+    NotFound(Vec<String>),
+
+    /// Dependencies could not be satisfied
+    #[error("Unsatisfied dependencies: {}", .0.join(", "))]
+    MissingDependencies(Vec<String>),
+
+    /// Packages conflict with each other or with installed packages
+    #[error("Conflicting packages: {}", .0.iter().map(|(a, b)| format!("{} <-> {}", a, b)).collect::<Vec<_>>().join(", "))]
+    Conflicts(Vec<(String, String)>),
+
+    /// Any other libalpm error
+    #[error("libalpm error: {0}")]
+    Alpm(#[from] alpm::Error),
+}
+
+/// Events emitted while a transaction runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlpmEvent {
+    /// Progress of a transaction step for a package
+    Progress {
+        /// Package currently being processed
+        package: String,
+        /// Completion of the current package in percent
+        percent: i32,
+        /// Index of the current package (1-based)
+        current: usize,
+        /// Total number of packages
+        total: usize,
+    },
+
+    /// A package download finished
+    Downloaded {
+        /// File name of the downloaded archive
+        file: String,
+    },
+
+    /// Aggregated progress over all concurrent downloads
+    DownloadProgress {
+        /// Bytes downloaded so far
+        downloaded: u64,
+        /// Total bytes of all known downloads
+        total: u64,
+        /// Number of downloads still running
+        active: usize,
+    },
+}
+
+/// Callback receiving transaction events
+pub type AlpmCallback = Arc<dyn Fn(&AlpmEvent) + Send + Sync>;
+
+/// Download callback state shared across libalpm download events
+struct DownloadContext {
+    callback: AlpmCallback,
+    progress: DownloadProgress,
+}
+
+/// Package backend using libalpm directly
+#[derive(Clone)]
+pub struct AlpmBackend {
+    /// pacman configuration used to set up the handle
+    config_path: String,
+
+    /// Optional listener for transaction events
+    callback: Option<AlpmCallback>,
+
+    /// Number of concurrent downloads; pacman.conf decides when unset
+    parallel_downloads: Option<u32>,
+
+    /// Package cache replacing the ones from pacman.conf
+    cache_dir: Option<String>,
+}
+
+impl std::fmt::Debug for AlpmBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlpmBackend")
+            .field("config_path", &self.config_path)
+            .field("callback", &self.callback.is_some())
+            .field("parallel_downloads", &self.parallel_downloads)
+            .field("cache_dir", &self.cache_dir)
+            .finish()
+    }
+}
+
+impl Default for AlpmBackend {
+    fn default() -> Self {
+        Self::new(DEFAULT_PACMAN_CONF)
+    }
+}
+
+impl AlpmBackend {
+    /// Create a backend configured from the given pacman.conf
+    pub fn new<S: Into<String>>(config_path: S) -> Self {
+        Self {
+            config_path: config_path.into(),
+            callback: None,
+            parallel_downloads: None,
+            cache_dir: None,
+        }
+    }
+
+    /// Download up to `downloads` packages concurrently
+    pub fn with_parallel_downloads(mut self, downloads: u32) -> Self {
+        self.parallel_downloads = Some(downloads.max(1));
+        self
+    }
+
+    /// Keep downloaded packages in `dir` instead of the configured caches
+    pub fn with_cache_dir<P: AsRef<std::path::Path>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    /// Receive progress and download events
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&AlpmEvent) + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Open a libalpm handle with sync databases from pacman.conf
+    fn handle(&self) -> Result<Alpm, PackageError> {
+        let conf = pacmanconf::Config::from_file(&self.config_path)
+            .map_err(|e| PackageError::ParseError(format!("{}: {}", self.config_path, e)))?;
+        let mut handle = alpm_utils::alpm_with_conf(&conf).map_err(AlpmTransactionError::from)?;
+
+        if let Some(downloads) = self.parallel_downloads {
+            handle.set_parallel_downloads(downloads);
+        }
+        if let Some(dir) = &self.cache_dir {
+            handle
+                .set_cachedirs(std::slice::from_ref(dir).iter())
+                .map_err(AlpmTransactionError::from)?;
+        }
+
+        if let Some(callback) = &self.callback {
+            handle.set_progress_cb(
+                callback.clone(),
+                |_: Progress, pkgname: &str, percent: i32, howmany: usize, current: usize, cb: &mut AlpmCallback| {
+                    cb(&AlpmEvent::Progress {
+                        package: pkgname.to_string(),
+                        percent,
+                        current,
+                        total: howmany,
+                    });
+                },
+            );
+            let context = DownloadContext {
+                callback: callback.clone(),
+                progress: DownloadProgress::default(),
+            };
+            handle.set_dl_cb(context, |file: &str, event: AnyDownloadEvent, ctx: &mut DownloadContext| {
+                match event.event() {
+                    alpm::DownloadEvent::Progress(p) => {
+                        ctx.progress.update(file, p.downloaded.max(0) as u64, p.total.max(0) as u64);
+                    }
+                    alpm::DownloadEvent::Completed(_) => {
+                        ctx.progress.complete(file);
+                        (ctx.callback)(&AlpmEvent::Downloaded { file: file.to_string() });
+                    }
+                    _ => return,
+                }
+                (ctx.callback)(&AlpmEvent::DownloadProgress {
+                    downloaded: ctx.progress.downloaded(),
+                    total: ctx.progress.total(),
+                    active: ctx.progress.active(),
+                });
+            });
+        }
+
+        Ok(handle)
+    }
+
+    /// Install packages from the sync databases in a single transaction
+    pub fn install(&self, packages: &[&PackageSpec]) -> Result<(), PackageError> {
+        self.sync(packages, TransFlag::NEEDED)
+    }
+
+    /// Resolve and download packages into the package cache without installing them
+    ///
+    /// Installed packages are downloaded too, since the cache may be meant
+    /// for another machine.
+    pub fn download(&self, packages: &[&PackageSpec]) -> Result<(), PackageError> {
+        self.sync(packages, TransFlag::DOWNLOAD_ONLY)
+    }
+
+    fn sync(&self, packages: &[&PackageSpec], flags: TransFlag) -> Result<(), PackageError> {
+        let targets = packages
+            .iter()
+            .map(|p| p.target())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut handle = self.handle()?;
+
+        handle
+            .trans_init(flags)
+            .map_err(AlpmTransactionError::from)?;
+
+        let result = add_and_commit(&mut handle, &targets);
+        handle.trans_release().ok();
+        result.map_err(Into::into)
+    }
+
+    /// Remove installed packages in a single transaction
+    pub fn remove(&self, names: &[&str]) -> Result<(), PackageError> {
+        let mut handle = self.handle()?;
+
+        handle
+            .trans_init(TransFlag::RECURSE)
+            .map_err(AlpmTransactionError::from)?;
+
+        let result = (|| -> Result<(), AlpmTransactionError> {
+            let mut missing = Vec::new();
+            for name in names {
+                match handle.localdb().pkg(*name) {
+                    Ok(pkg) => handle.trans_remove_pkg(pkg)?,
+                    Err(_) => missing.push(name.to_string()),
+                }
+            }
+            if !missing.is_empty() {
+                return Err(AlpmTransactionError::NotFound(missing));
+            }
+            commit(&mut handle)
+        })();
+
+        handle.trans_release().ok();
+        result.map_err(Into::into)
+    }
+}
+
+fn add_and_commit(handle: &mut Alpm, targets: &[String]) -> Result<(), AlpmTransactionError> {
+    let mut missing = Vec::new();
+
+    for target in targets {
+        match handle.syncdbs().find_satisfier(target.as_str()) {
+            Some(pkg) => handle.trans_add_pkg(pkg)?,
+            None => missing.push(target.clone()),
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(AlpmTransactionError::NotFound(missing));
+    }
+
+    commit(handle)
+}
+
+fn commit(handle: &mut Alpm) -> Result<(), AlpmTransactionError> {
+    if let Err(err) = handle.trans_prepare() {
+        let (data, error) = (err.data(), err.error());
+        return Err(match data {
+            Some(alpm::PrepareData::UnsatisfiedDeps(deps)) => AlpmTransactionError::MissingDependencies(
+                deps.iter()
+                    .map(|d| format!("{} (required by {})", d.depend(), d.target()))
+                    .collect(),
+            ),
+            Some(alpm::PrepareData::ConflictingDeps(conflicts)) => AlpmTransactionError::Conflicts(
+                conflicts
+                    .iter()
+                    .map(|c| (c.package1().name().to_string(), c.package2().name().to_string()))
+                    .collect(),
+            ),
+            _ => AlpmTransactionError::Alpm(error),
+        });
+    }
+
+    handle
+        .trans_commit()
+        .map_err(|err| AlpmTransactionError::Alpm(err.error()))
+}
@@ -0,0 +1,246 @@
+//! Security advisory audit
+//!
+//! Matches the installed packages against the Arch Linux security tracker
+//! feed and reports every package with an open advisory, together with the
+//! version that fixes it when one has been released.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use super::localdb::InstalledPackage;
+use super::version::vercmp;
+use super::PackageError;
+
+/// Default security advisory feed (Arch Linux security tracker)
+pub const DEFAULT_ADVISORY_FEED: &str = "https://security.archlinux.org/issues/all.json";
+
+/// Severity of an advisory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    /// Severity not yet assessed
+    Unknown,
+    /// Low severity
+    Low,
+    /// Medium severity
+    Medium,
+    /// High severity
+    High,
+    /// Critical severity
+    Critical,
+}
+
+/// An advisory group from the security tracker feed
+#[derive(Debug, Clone, Deserialize)]
+pub struct Advisory {
+    /// Advisory group name (e.g. "AVG-2843")
+    pub name: String,
+
+    /// Affected packages
+    pub packages: Vec<String>,
+
+    /// Tracker status ("Vulnerable", "Fixed", "Not affected", ...)
+    pub status: String,
+
+    /// Severity
+    #[serde(default = "unknown_severity")]
+    pub severity: Severity,
+
+    /// Vulnerability type (e.g. "arbitrary code execution")
+    #[serde(rename = "type", default)]
+    pub kind: String,
+
+    /// First version known to be affected
+    pub affected: Option<String>,
+
+    /// Version fixing the issue, if released
+    pub fixed: Option<String>,
+
+    /// CVE identifiers covered by this advisory
+    #[serde(default)]
+    pub issues: Vec<String>,
+}
+
+fn unknown_severity() -> Severity {
+    Severity::Unknown
+}
+
+impl Advisory {
+    /// Whether the given installed version is affected by this advisory
+    ///
+    /// Versions older than the first affected one predate the issue.
+    pub fn affects(&self, version: &str) -> bool {
+        if self.affected.as_deref().is_some_and(|affected| vercmp(version, affected) == Ordering::Less) {
+            return false;
+        }
+        match self.status.as_str() {
+            "Not affected" => false,
+            _ => match &self.fixed {
+                Some(fixed) => vercmp(version, fixed) == Ordering::Less,
+                None => self.status == "Vulnerable" || self.status == "Unknown",
+            },
+        }
+    }
+}
+
+/// An installed package affected by an advisory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VulnerablePackage {
+    /// Package name
+    pub name: String,
+
+    /// Installed version
+    pub installed: String,
+
+    /// Version fixing the issue, if released
+    pub fixed: Option<String>,
+
+    /// Advisory group name
+    pub advisory: String,
+
+    /// Severity of the advisory
+    pub severity: Severity,
+
+    /// Vulnerability type
+    pub kind: String,
+
+    /// CVE identifiers
+    pub cves: Vec<String>,
+}
+
+impl VulnerablePackage {
+    /// Whether upgrading the package resolves the advisory
+    pub fn is_fixable(&self) -> bool {
+        self.fixed.is_some()
+    }
+}
+
+/// Result of a security audit
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AuditReport {
+    /// Number of installed packages checked
+    pub checked: usize,
+
+    /// Affected packages, most severe first
+    pub vulnerable: Vec<VulnerablePackage>,
+}
+
+impl AuditReport {
+    /// Match installed packages against advisories
+    pub fn from_advisories(installed: &[InstalledPackage], advisories: &[Advisory]) -> Self {
+        let versions: HashMap<&str, &str> = installed
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.as_str()))
+            .collect();
+
+        let mut vulnerable = Vec::new();
+        for advisory in advisories {
+            for name in &advisory.packages {
+                let Some(version) = versions.get(name.as_str()) else {
+                    continue;
+                };
+                if advisory.affects(version) {
+                    vulnerable.push(VulnerablePackage {
+                        name: name.clone(),
+                        installed: version.to_string(),
+                        fixed: advisory.fixed.clone(),
+                        advisory: advisory.name.clone(),
+                        severity: advisory.severity,
+                        kind: advisory.kind.clone(),
+                        cves: advisory.issues.clone(),
+                    });
+                }
+            }
+        }
+
+        vulnerable.sort_by(|a, b| b.severity.cmp(&a.severity).then_with(|| a.name.cmp(&b.name)));
+        Self {
+            checked: installed.len(),
+            vulnerable,
+        }
+    }
+
+    /// Whether no installed package is affected
+    pub fn is_clean(&self) -> bool {
+        self.vulnerable.is_empty()
+    }
+}
+
+/// Parse the JSON advisory feed
+pub fn parse_advisories(json: &str) -> Result<Vec<Advisory>, PackageError> {
+    serde_json::from_str(json).map_err(|e| PackageError::ParseError(format!("Invalid advisory feed: {}", e)))
+}
+
+/// Download and parse an advisory feed
+pub fn fetch_advisories(url: &str) -> Result<Vec<Advisory>, PackageError> {
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location", "--max-time", "60", url])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(PackageError::Network(format!(
+            "Failed to fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    parse_advisories(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::localdb::{read_local_db, tests::write_entry};
+    use super::*;
+    use tempfile::tempdir;
+
+    const FEED: &str = r#"[
+        {"name": "AVG-1", "packages": ["openssl"], "status": "Fixed", "severity": "High",
+         "type": "arbitrary code execution", "affected": "3.1.0-1", "fixed": "3.1.4-1",
+         "ticket": null, "issues": ["CVE-2023-0001"], "advisories": []},
+        {"name": "AVG-2", "packages": ["vim"], "status": "Vulnerable", "severity": "Medium",
+         "type": "denial of service", "affected": "9.0.1-1", "fixed": null,
+         "ticket": null, "issues": ["CVE-2023-0002", "CVE-2023-0003"], "advisories": []},
+        {"name": "AVG-3", "packages": ["bash"], "status": "Fixed", "severity": "Critical",
+         "type": "arbitrary code execution", "affected": "5.0-1", "fixed": "5.1-1",
+         "ticket": null, "issues": ["CVE-2023-0004"], "advisories": []},
+        {"name": "AVG-4", "packages": ["curl"], "status": "Not affected", "severity": "Low",
+         "type": "unknown", "affected": "8.0-1", "fixed": null,
+         "ticket": null, "issues": [], "advisories": []}
+    ]"#;
+
+    #[test]
+    fn test_audit_report() {
+        let root = tempdir().unwrap();
+        write_entry(root.path(), "openssl", "3.1.2-1", true);
+        write_entry(root.path(), "vim", "9.0.1-1", true);
+        write_entry(root.path(), "bash", "5.2.21-2", true);
+        write_entry(root.path(), "curl", "8.0-1", true);
+
+        let installed = read_local_db(root.path()).unwrap();
+        let report = AuditReport::from_advisories(&installed, &parse_advisories(FEED).unwrap());
+
+        assert_eq!(report.checked, 4);
+        let names: Vec<&str> = report.vulnerable.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["openssl", "vim"]);
+        assert_eq!(report.vulnerable[0].fixed.as_deref(), Some("3.1.4-1"));
+        assert!(!report.vulnerable[1].is_fixable());
+        assert_eq!(report.vulnerable[1].cves.len(), 2);
+    }
+
+    #[test]
+    fn test_affects_range() {
+        let advisories = parse_advisories(FEED).unwrap();
+        let openssl = &advisories[0];
+        assert!(!openssl.affects("3.0.13-1"));
+        assert!(openssl.affects("3.1.0-1"));
+        assert!(openssl.affects("3.1.2-1"));
+        assert!(!openssl.affects("3.1.4-1"));
+
+        // Unfixed issues still start at the first affected version
+        assert!(!advisories[1].affects("8.2.5-1"));
+        assert!(advisories[1].affects("9.1.0-1"));
+    }
+}
@@ -0,0 +1,396 @@
+//! Sandboxed AUR package builds
+//!
+//! AUR packages are built inside an ephemeral OCI container whose root
+//! filesystem is a throwaway copy of a clean build chroot. The resulting
+//! packages are imported into a local repository and installed from there, so
+//! nothing from a PKGBUILD ever runs directly on the host. The container runs
+//! in a user namespace: its root is an unprivileged user on the host, and the
+//! rootfs copy and sources are shifted into that user's ID range.
+
+use std::fs;
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use oci_spec::runtime::{LinuxIdMappingBuilder, LinuxNamespaceBuilder, LinuxNamespaceType, MountBuilder};
+
+use super::{PackageError, PackageSpec};
+use crate::fs::{walk, WalkOptions};
+use crate::oci::{Container, ContainerBuilder, LinuxBuilder, ProcessBuilder};
+
+/// Base URL of the AUR git repositories
+pub const AUR_GIT_BASE: &str = "https://aur.archlinux.org";
+
+/// First host ID the build container's IDs are mapped to
+pub const DEFAULT_ID_BASE: u32 = 0x4000_0000;
+
+/// IDs mapped into the build container
+const ID_COUNT: u32 = 65536;
+
+/// Packages installed into the clean build chroot
+const CHROOT_PACKAGES: &[&str] = &["base-devel", "git", "sudo"];
+
+/// Script executed inside the build container
+///
+/// `makepkg` refuses to run as root, so an unprivileged builder account is
+/// created with password-less sudo for dependency installation only.
+const BUILD_SCRIPT: &str = "\
+set -e
+pacman -Sy --noconfirm --needed
+useradd -m builder 2>/dev/null || true
+echo 'builder ALL=(ALL) NOPASSWD: /usr/bin/pacman' > /etc/sudoers.d/builder
+chown -R builder /build
+su builder -c 'cd /build && makepkg --syncdeps --noconfirm --cleanbuild'
+";
+
+/// Builds AUR packages inside ephemeral OCI containers
+#[derive(Debug, Clone)]
+pub struct AurBuilder {
+    /// Working directory for chroots, bundles and sources
+    work_dir: PathBuf,
+
+    /// Local repository receiving built packages
+    repo_dir: PathBuf,
+
+    /// Name of the local repository database
+    repo_name: String,
+
+    /// OCI runtime binary used to execute bundles
+    runtime: String,
+
+    /// First host ID of the range the container's IDs map to
+    id_base: u32,
+}
+
+impl AurBuilder {
+    /// Create a new AUR builder rooted at `work_dir`
+    pub fn new<P: AsRef<Path>>(work_dir: P) -> Self {
+        let work_dir = work_dir.as_ref().to_path_buf();
+        let repo_dir = work_dir.join("repo");
+
+        Self {
+            work_dir,
+            repo_dir,
+            repo_name: "rastos-aur".to_string(),
+            runtime: "crun".to_string(),
+            id_base: DEFAULT_ID_BASE,
+        }
+    }
+
+    /// Set the local repository directory
+    pub fn with_repo_dir<P: AsRef<Path>>(mut self, repo_dir: P) -> Self {
+        self.repo_dir = repo_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Set the local repository name
+    pub fn with_repo_name<S: Into<String>>(mut self, repo_name: S) -> Self {
+        self.repo_name = repo_name.into();
+        self
+    }
+
+    /// Set the OCI runtime used to run build containers
+    pub fn with_runtime<S: Into<String>>(mut self, runtime: S) -> Self {
+        self.runtime = runtime.into();
+        self
+    }
+
+    /// Map the container's root to host ID `base`, and the IDs after it
+    ///
+    /// The range should not belong to any user or `/etc/subuid` entry.
+    pub fn with_id_base(mut self, base: u32) -> Self {
+        self.id_base = base;
+        self
+    }
+
+    /// Path of the clean build chroot template
+    pub fn chroot_dir(&self) -> PathBuf {
+        self.work_dir.join("chroot")
+    }
+
+    /// Path of the local repository database
+    pub fn repo_db(&self) -> PathBuf {
+        self.repo_dir.join(format!("{}.db.tar.gz", self.repo_name))
+    }
+
+    /// Create the clean build chroot if it does not exist yet
+    pub fn prepare_chroot(&self) -> Result<(), PackageError> {
+        let chroot = self.chroot_dir();
+        if chroot.join("usr/bin/makepkg").exists() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&chroot)?;
+        let mut args = vec!["-c".to_string(), "-G".to_string(), "-M".to_string()];
+        args.push(chroot.to_string_lossy().into_owned());
+        args.extend(CHROOT_PACKAGES.iter().map(|p| p.to_string()));
+
+        run("pacstrap", &args)
+    }
+
+    /// Build a package and import it into the local repository
+    ///
+    /// Returns the paths of the imported package files.
+    pub fn build(&self, package: &PackageSpec) -> Result<Vec<PathBuf>, PackageError> {
+        validate_name(&package.name)?;
+        self.prepare_chroot()?;
+
+        let job_dir = self.work_dir.join("jobs").join(&package.name);
+        if job_dir.exists() {
+            fs::remove_dir_all(&job_dir)?;
+        }
+        fs::create_dir_all(&job_dir)?;
+
+        let result = self.build_in(&job_dir, package);
+
+        // The rootfs copy is disposable regardless of the outcome
+        if let Err(e) = fs::remove_dir_all(job_dir.join("bundle")) {
+            log::warn!("Failed to clean up build bundle for {}: {}", package.name, e);
+        }
+
+        result
+    }
+
+    fn build_in(&self, job_dir: &Path, package: &PackageSpec) -> Result<Vec<PathBuf>, PackageError> {
+        let source_dir = job_dir.join("source");
+        run(
+            "git",
+            &[
+                "clone".to_string(),
+                "--depth=1".to_string(),
+                format!("{}/{}.git", AUR_GIT_BASE, package.name),
+                source_dir.to_string_lossy().into_owned(),
+            ],
+        )?;
+
+        let bundle = job_dir.join("bundle");
+        let rootfs = bundle.join("rootfs");
+        fs::create_dir_all(&bundle)?;
+        run(
+            "cp",
+            &[
+                "-a".to_string(),
+                "--reflink=auto".to_string(),
+                self.chroot_dir().to_string_lossy().into_owned(),
+                rootfs.to_string_lossy().into_owned(),
+            ],
+        )?;
+
+        self.shift_ids(&rootfs)?;
+        self.shift_ids(&source_dir)?;
+        self.write_bundle(&bundle, &rootfs, &source_dir)?;
+
+        let container_id = format!("rastos-aur-{}-{}", package.name, uuid::Uuid::new_v4().simple());
+        // Load through the crate's runtime to validate the generated bundle
+        Container::new(&container_id, &bundle)
+            .map_err(|e| PackageError::OperationFailed(format!("Invalid build bundle: {}", e)))?;
+
+        run(
+            &self.runtime,
+            &[
+                "run".to_string(),
+                "--bundle".to_string(),
+                bundle.to_string_lossy().into_owned(),
+                container_id,
+            ],
+        )
+        .map_err(|e| PackageError::OperationFailed(format!("Build of {} failed: {}", package.name, e)))?;
+
+        self.import_packages(&source_dir)
+    }
+
+    /// Generate the OCI bundle configuration for a build
+    fn write_bundle(&self, bundle: &Path, rootfs: &Path, source_dir: &Path) -> Result<(), PackageError> {
+        let namespaces = [
+            LinuxNamespaceType::User,
+            LinuxNamespaceType::Pid,
+            LinuxNamespaceType::Mount,
+            LinuxNamespaceType::Ipc,
+            LinuxNamespaceType::Uts,
+        ]
+        .into_iter()
+        .map(|typ| LinuxNamespaceBuilder::default().typ(typ).build())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(oci_error)?;
+
+        let id_map = LinuxIdMappingBuilder::default()
+            .container_id(0u32)
+            .host_id(self.id_base)
+            .size(ID_COUNT)
+            .build()
+            .map_err(oci_error)?;
+
+        let process = ProcessBuilder::default()
+            .cwd("/build")
+            .args(vec!["/bin/sh".to_string(), "-c".to_string(), BUILD_SCRIPT.to_string()]);
+
+        let mut spec = ContainerBuilder::new("aur-build")
+            .root(rootfs)
+            .process(process)
+            .linux(
+                LinuxBuilder::default()
+                    .namespaces(namespaces)
+                    .uid_mappings(vec![id_map])
+                    .gid_mappings(vec![id_map]),
+            )
+            .build()
+            .map_err(oci_error)?;
+
+        let source_mount = MountBuilder::default()
+            .destination("/build")
+            .typ("bind")
+            .source(source_dir)
+            .options(vec!["rbind".to_string(), "rw".to_string()])
+            .build()
+            .map_err(oci_error)?;
+        let resolv_mount = MountBuilder::default()
+            .destination("/etc/resolv.conf")
+            .typ("bind")
+            .source("/etc/resolv.conf")
+            .options(vec!["bind".to_string(), "ro".to_string()])
+            .build()
+            .map_err(oci_error)?;
+
+        let mut mounts = spec.mounts().clone().unwrap_or_default();
+        mounts.push(source_mount);
+        mounts.push(resolv_mount);
+        spec.set_mounts(Some(mounts));
+
+        spec.save(bundle.join("config.json")).map_err(oci_error)?;
+        Ok(())
+    }
+
+    /// Move the owners of everything below `dir` into the container's ID range
+    fn shift_ids(&self, dir: &Path) -> Result<(), PackageError> {
+        let shift = |path: &Path| -> Result<(), PackageError> {
+            let meta = fs::symlink_metadata(path)?;
+            if meta.uid() >= ID_COUNT || meta.gid() >= ID_COUNT {
+                return Ok(());
+            }
+            lchown(path, Some(self.id_base + meta.uid()), Some(self.id_base + meta.gid()))?;
+            Ok(())
+        };
+        shift(dir)?;
+        for entry in walk(dir, WalkOptions::default()).map_err(std::io::Error::from)? {
+            shift(&entry.map_err(std::io::Error::from)?.path)?;
+        }
+        Ok(())
+    }
+
+    /// Copy built packages into the local repository and update its database
+    fn import_packages(&self, source_dir: &Path) -> Result<Vec<PathBuf>, PackageError> {
+        fs::create_dir_all(&self.repo_dir)?;
+
+        let mut imported = Vec::new();
+        for entry in fs::read_dir(source_dir)? {
+            let path = entry?.path();
+            if is_package_file(&path) {
+                // The build controls the directory; never follow its links
+                if !fs::symlink_metadata(&path)?.file_type().is_file() {
+                    return Err(PackageError::OperationFailed(format!(
+                        "{} is not a regular file",
+                        path.display()
+                    )));
+                }
+                let target = self.repo_dir.join(path.file_name().unwrap_or_default());
+                fs::copy(&path, &target)?;
+                imported.push(target);
+            }
+        }
+
+        if imported.is_empty() {
+            return Err(PackageError::OperationFailed(format!(
+                "No packages produced in {}",
+                source_dir.display()
+            )));
+        }
+
+        let mut args = vec![self.repo_db().to_string_lossy().into_owned()];
+        args.extend(imported.iter().map(|p| p.to_string_lossy().into_owned()));
+        run("repo-add", &args)?;
+
+        Ok(imported)
+    }
+}
+
+/// Check an AUR package name before it becomes part of a URL and a path
+///
+/// pacman allows lowercase alphanumerics and `@._+-`, and no leading `-` or `.`.
+fn validate_name(name: &str) -> Result<(), PackageError> {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "@._+-".contains(c);
+    if name.is_empty() || name.starts_with(['-', '.']) || !name.chars().all(allowed) {
+        return Err(PackageError::ParseError(format!("Invalid package name '{}'", name)));
+    }
+    Ok(())
+}
+
+/// Whether a path looks like a built package archive
+fn is_package_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.contains(".pkg.tar") && !n.ends_with(".sig"))
+        .unwrap_or(false)
+}
+
+fn oci_error<E: std::fmt::Display>(err: E) -> PackageError {
+    PackageError::OperationFailed(format!("OCI bundle error: {}", err))
+}
+
+fn run(program: &str, args: &[String]) -> Result<(), PackageError> {
+    log::debug!("Running: {} {}", program, args.join(" "));
+
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(PackageError::OperationFailed(format!(
+            "Command '{}' failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_is_package_file() {
+        assert!(is_package_file(Path::new("foo-1.0-1-x86_64.pkg.tar.zst")));
+        assert!(!is_package_file(Path::new("foo-1.0-1-x86_64.pkg.tar.zst.sig")));
+        assert!(!is_package_file(Path::new("PKGBUILD")));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("yay-bin").is_ok());
+        assert!(validate_name("libc++").is_ok());
+        for bad in ["", "-rf", ".hidden", "../etc", "Foo", "a/b", "x y"] {
+            assert!(validate_name(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_bundle_generation() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let builder = AurBuilder::new(dir.path()).with_repo_name("test-repo");
+        assert_eq!(builder.repo_db(), dir.path().join("repo/test-repo.db.tar.gz"));
+
+        let bundle = dir.path().join("bundle");
+        let rootfs = bundle.join("rootfs");
+        let source = dir.path().join("source");
+        fs::create_dir_all(&rootfs)?;
+        fs::create_dir_all(&source)?;
+
+        builder.write_bundle(&bundle, &rootfs, &source)?;
+
+        let container = Container::new("test", &bundle)?;
+        let mounts = container.spec().mounts().clone().unwrap_or_default();
+        assert!(mounts.iter().any(|m| m.destination() == Path::new("/build")));
+        let linux = container.spec().linux().clone().unwrap();
+        assert_eq!(linux.uid_mappings().as_ref().unwrap()[0].host_id(), DEFAULT_ID_BASE);
+        Ok(())
+    }
+}
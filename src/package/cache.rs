@@ -0,0 +1,333 @@
+//! Local package cache and repository
+//!
+//! Stores downloaded package archives in a directory that doubles as a pacman
+//! repository. The cache can be served to other machines on the LAN over
+//! plain HTTP and used for fully offline installs.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::PackageError;
+
+/// Default name of the repository database generated for the cache
+pub const DEFAULT_REPO_NAME: &str = "rastos-cache";
+
+/// A package archive stored in the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedPackage {
+    /// Package name
+    pub name: String,
+
+    /// Full version (`epoch:pkgver-pkgrel`)
+    pub version: String,
+
+    /// Target architecture
+    pub arch: String,
+
+    /// Path of the archive in the cache
+    pub path: PathBuf,
+}
+
+impl CachedPackage {
+    /// Parse a package archive file name (`name-pkgver-pkgrel-arch.pkg.tar.*`)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let path = path.as_ref();
+        let file_name = path.file_name()?.to_str()?;
+        if file_name.ends_with(".sig") {
+            return None;
+        }
+        let stem = &file_name[..file_name.find(".pkg.tar")?];
+
+        // name may itself contain dashes, so split from the right
+        let mut parts = stem.rsplitn(4, '-');
+        let arch = parts.next()?;
+        let pkgrel = parts.next()?;
+        let pkgver = parts.next()?;
+        let name = parts.next()?;
+
+        Some(Self {
+            name: name.to_string(),
+            version: format!("{}-{}", pkgver, pkgrel),
+            arch: arch.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// A directory of cached packages usable as a local repository
+#[derive(Debug, Clone)]
+pub struct PackageCache {
+    /// Directory holding the package archives
+    dir: PathBuf,
+
+    /// Name of the generated repository database
+    repo_name: String,
+}
+
+impl PackageCache {
+    /// Open (or create) a package cache directory
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, PackageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            repo_name: DEFAULT_REPO_NAME.to_string(),
+        })
+    }
+
+    /// Set the repository database name
+    pub fn with_repo_name<S: Into<String>>(mut self, repo_name: S) -> Self {
+        self.repo_name = repo_name.into();
+        self
+    }
+
+    /// Get the cache directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get the repository name
+    pub fn repo_name(&self) -> &str {
+        &self.repo_name
+    }
+
+    /// Copy a package archive (and its signature, if present) into the cache
+    pub fn store<P: AsRef<Path>>(&self, package: P) -> Result<CachedPackage, PackageError> {
+        let package = package.as_ref();
+        let file_name = package
+            .file_name()
+            .ok_or_else(|| PackageError::ParseError(format!("Invalid package path: {}", package.display())))?;
+        let target = self.dir.join(file_name);
+
+        let cached = CachedPackage::from_path(&target)
+            .ok_or_else(|| PackageError::ParseError(format!("Not a package archive: {}", package.display())))?;
+
+        fs::copy(package, &target)?;
+        let signature = super::keyring::signature_path(package);
+        if signature.exists() {
+            fs::copy(&signature, super::keyring::signature_path(&target))?;
+        }
+
+        Ok(cached)
+    }
+
+    /// List all cached packages
+    pub fn list(&self) -> Result<Vec<CachedPackage>, PackageError> {
+        let mut packages = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            if let Some(pkg) = CachedPackage::from_path(entry?.path()) {
+                packages.push(pkg);
+            }
+        }
+        packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+        Ok(packages)
+    }
+
+    /// Find cached archives of a package, optionally matching an exact version
+    pub fn lookup(&self, name: &str, version: Option<&str>) -> Result<Vec<CachedPackage>, PackageError> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter(|p| p.name == name && version.is_none_or(|v| p.version == v))
+            .collect())
+    }
+
+    /// Remove all but the newest `keep` versions of every package
+    ///
+    /// Returns the removed archives.
+    pub fn prune(&self, keep: usize) -> Result<Vec<CachedPackage>, PackageError> {
+        let before = self.list()?;
+
+        let output = Command::new("paccache")
+            .args(["--remove", "--keep", &keep.to_string(), "--cachedir"])
+            .arg(&self.dir)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(PackageError::OperationFailed(format!(
+                "paccache failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let remaining = self.list()?;
+        Ok(before.into_iter().filter(|p| !remaining.contains(p)).collect())
+    }
+
+    /// Regenerate the repository database from all cached archives
+    pub fn update_repo(&self) -> Result<PathBuf, PackageError> {
+        let db = self.dir.join(format!("{}.db.tar.gz", self.repo_name));
+        let packages = self.list()?;
+        if packages.is_empty() {
+            return Err(PackageError::OperationFailed("Package cache is empty".to_string()));
+        }
+
+        let output = Command::new("repo-add")
+            .arg("--remove")
+            .arg(&db)
+            .args(packages.iter().map(|p| &p.path))
+            .output()?;
+
+        if !output.status.success() {
+            return Err(PackageError::OperationFailed(format!(
+                "repo-add failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(db)
+    }
+
+    /// Render a pacman configuration that only uses this cache
+    ///
+    /// Used for offline installs where no network mirror is reachable.
+    /// Packages still need valid signatures from the system keyring; the
+    /// cache database itself is unsigned.
+    pub fn offline_pacman_conf(&self) -> String {
+        format!(
+            "[options]\nArchitecture = auto\nCacheDir = {dir}\nSigLevel = Required DatabaseOptional\nLocalFileSigLevel = Optional\n\n[{name}]\nSigLevel = Required DatabaseOptional\nServer = file://{dir}\n",
+            dir = self.dir.display(),
+            name = self.repo_name,
+        )
+    }
+
+    /// Write the offline pacman configuration next to the cache
+    pub fn write_offline_pacman_conf(&self) -> Result<PathBuf, PackageError> {
+        let path = self.dir.join("pacman.offline.conf");
+        fs::write(&path, self.offline_pacman_conf())?;
+        Ok(path)
+    }
+
+    /// Repository stanza other machines use to consume a served cache
+    ///
+    /// Peers verify package signatures against their own keyring.
+    pub fn peer_repo_stanza(&self, addr: &SocketAddr) -> String {
+        format!(
+            "[{}]\nSigLevel = Required DatabaseOptional\nServer = http://{}\n",
+            self.repo_name, addr
+        )
+    }
+
+    /// Serve the cache directory read-only over HTTP
+    ///
+    /// Runs until the listener fails. Only files directly inside the cache
+    /// directory are served.
+    pub async fn serve(&self, addr: SocketAddr) -> Result<(), PackageError> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("Serving package cache {} on http://{}", self.dir.display(), addr);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let dir = self.dir.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_request(stream, &dir).await {
+                    log::debug!("Cache request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Resolve a request path to a file inside the cache directory
+fn resolve_request(dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(request_path.trim_start_matches('/'));
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(dir.join(name)),
+        _ => None,
+    }
+}
+
+async fn handle_request(stream: TcpStream, dir: &Path) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    // Drain headers
+    let mut header = String::new();
+    while reader.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+
+    let mut stream = reader.into_inner();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let file = match (method, resolve_request(dir, path)) {
+        ("GET", Some(file)) | ("HEAD", Some(file)) if file.is_file() => file,
+        _ => {
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut handle = tokio::fs::File::open(&file).await?;
+    let len = handle.metadata().await?.len();
+    let headers = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        len
+    );
+    stream.write_all(headers.as_bytes()).await?;
+    if method == "GET" {
+        tokio::io::copy(&mut handle, &mut stream).await?;
+    }
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_package_file_name() {
+        let pkg = CachedPackage::from_path("/cache/python-requests-2.31.0-1-any.pkg.tar.zst").unwrap();
+        assert_eq!(pkg.name, "python-requests");
+        assert_eq!(pkg.version, "2.31.0-1");
+        assert_eq!(pkg.arch, "any");
+
+        let pkg = CachedPackage::from_path("linux-1:6.6.1.arch1-1-x86_64.pkg.tar.zst").unwrap();
+        assert_eq!(pkg.version, "1:6.6.1.arch1-1");
+
+        assert!(CachedPackage::from_path("foo-1.0-1-any.pkg.tar.zst.sig").is_none());
+        assert!(CachedPackage::from_path("rastos-cache.db.tar.gz").is_none());
+    }
+
+    #[test]
+    fn test_store_and_lookup() -> Result<(), Box<dyn std::error::Error>> {
+        let src = tempdir()?;
+        let cache_dir = tempdir()?;
+        let archive = src.path().join("foo-1.0-1-x86_64.pkg.tar.zst");
+        fs::write(&archive, b"pkg")?;
+        fs::write(src.path().join("foo-1.0-1-x86_64.pkg.tar.zst.sig"), b"sig")?;
+
+        let cache = PackageCache::new(cache_dir.path())?;
+        cache.store(&archive)?;
+
+        assert_eq!(cache.lookup("foo", Some("1.0-1"))?.len(), 1);
+        assert!(cache.lookup("foo", Some("2.0-1"))?.is_empty());
+        assert!(cache_dir.path().join("foo-1.0-1-x86_64.pkg.tar.zst.sig").exists());
+        assert!(cache.offline_pacman_conf().contains("Server = file://"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_request_stays_in_cache() {
+        let dir = Path::new("/var/cache/rastos");
+        assert_eq!(
+            resolve_request(dir, "/foo-1.0-1-any.pkg.tar.zst"),
+            Some(dir.join("foo-1.0-1-any.pkg.tar.zst"))
+        );
+        assert_eq!(resolve_request(dir, "/../etc/shadow"), None);
+        assert_eq!(resolve_request(dir, "/sub/file"), None);
+    }
+}
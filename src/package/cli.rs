@@ -0,0 +1,164 @@
+//! CLI interface for package management
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::dry_run::DryRun;
+use crate::output::{Output, OutputArgs};
+use crate::package::audit::{AuditReport, DEFAULT_ADVISORY_FEED};
+use crate::package::{PackageError, PackageManager};
+
+/// Package management commands
+#[derive(Debug, Parser)]
+#[command(name = "rast-package", about = "Manage rastOS packages")]
+pub struct PackageCli {
+    /// Command to run
+    #[command(subcommand)]
+    pub command: PackageCommand,
+
+    /// System root to operate on
+    #[arg(short, long, default_value = "/")]
+    pub root: PathBuf,
+
+    /// Enable verbose output
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Show what would change instead of changing it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Output format
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Package subcommands
+#[derive(Debug, Subcommand)]
+pub enum PackageCommand {
+    /// Check installed packages for known security advisories
+    Audit {
+        /// Advisory feed URL
+        #[arg(long, default_value = DEFAULT_ADVISORY_FEED)]
+        feed: String,
+    },
+
+    /// Show the package transaction history
+    History {
+        /// Only show the most recent transactions
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+    },
+
+    /// Revert the package changes of a transaction
+    Undo {
+        /// Transaction ID to revert
+        transaction_id: Uuid,
+    },
+}
+
+impl PackageCli {
+    /// Create a package manager from the CLI options
+    pub fn create_manager(&self) -> PackageManager {
+        PackageManager::new(&self.root.to_string_lossy()).verbose(self.verbose)
+    }
+
+    /// Execute the package command
+    pub fn execute(self) -> Result<(), PackageError> {
+        let dry_run = DryRun::enabled(self.dry_run);
+        let manager = self.create_manager().with_dry_run(dry_run.clone());
+        let output = self.output.output();
+
+        match &self.command {
+            PackageCommand::Audit { feed } => self.handle_audit(&manager, feed, output),
+            PackageCommand::History { limit } => self.handle_history(&manager, *limit, output),
+            PackageCommand::Undo { transaction_id } if dry_run.is_active() => {
+                manager.undo(transaction_id)?;
+                Ok(output.plan(&dry_run.plan())?)
+            }
+            PackageCommand::Undo { transaction_id } => self.handle_undo(&manager, transaction_id, output),
+        }
+    }
+
+    /// Handle the audit command
+    fn handle_audit(&self, manager: &PackageManager, feed: &str, output: Output) -> Result<(), PackageError> {
+        let report = manager.audit_feed(feed)?;
+        output.print(&report, || print_audit(&report))?;
+        Ok(())
+    }
+
+    /// Handle the history command
+    fn handle_history(&self, manager: &PackageManager, limit: Option<usize>, output: Output) -> Result<(), PackageError> {
+        let mut records = manager.history()?;
+        if let Some(limit) = limit {
+            records = records.split_off(records.len().saturating_sub(limit));
+        }
+
+        output.print(&records, || {
+            for record in &records {
+                println!(
+                    "{} {} {} ({} packages)",
+                    record.id,
+                    record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    record.action,
+                    record.changes.len()
+                );
+                if !record.snapshots.is_empty() {
+                    println!("  snapshots: {}", record.snapshots.join(", "));
+                }
+                if self.verbose {
+                    for change in &record.changes {
+                        println!(
+                            "  {} {} -> {}",
+                            change.name,
+                            change.from.as_deref().unwrap_or("(none)"),
+                            change.to.as_deref().unwrap_or("(removed)")
+                        );
+                    }
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Handle the undo command
+    fn handle_undo(&self, manager: &PackageManager, transaction_id: &Uuid, output: Output) -> Result<(), PackageError> {
+        let record = manager.undo(transaction_id)?;
+        output.print(&record, || {
+            println!(
+                "Reverted transaction {} ({} packages changed, recorded as {})",
+                transaction_id,
+                record.changes.len(),
+                record.id
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// Print an audit report for people
+fn print_audit(report: &AuditReport) {
+    if report.is_clean() {
+        println!("No known vulnerabilities in {} installed packages", report.checked);
+        return;
+    }
+
+    for pkg in &report.vulnerable {
+        let fix = match &pkg.fixed {
+            Some(version) => format!("upgrade to {}", version),
+            None => "no fix available".to_string(),
+        };
+        println!(
+            "{} {} [{:?}] {} ({}): {}",
+            pkg.name,
+            pkg.installed,
+            pkg.severity,
+            pkg.advisory,
+            pkg.cves.join(", "),
+            fix
+        );
+    }
+    println!("{} of {} packages affected", report.vulnerable.len(), report.checked);
+}
@@ -0,0 +1,132 @@
+//! Package set differences between system roots
+//!
+//! Compares the local pacman databases of two roots (typically two mounted
+//! Btrfs snapshots) and reports which packages were installed, removed,
+//! upgraded or downgraded going from the first to the second.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::localdb::InstalledPackage;
+use super::version::vercmp;
+
+/// A package whose version differs between two roots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionChange {
+    /// Package name
+    pub name: String,
+
+    /// Version in the older root
+    pub from: String,
+
+    /// Version in the newer root
+    pub to: String,
+}
+
+/// Differences between the package sets of two roots
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageDiff {
+    /// Packages only present in the newer root
+    pub installed: Vec<InstalledPackage>,
+
+    /// Packages only present in the older root
+    pub removed: Vec<InstalledPackage>,
+
+    /// Packages with a newer version in the newer root
+    pub upgraded: Vec<VersionChange>,
+
+    /// Packages with an older version in the newer root
+    pub downgraded: Vec<VersionChange>,
+}
+
+impl PackageDiff {
+    /// Compute the difference going from `old` to `new`
+    pub fn between(old: &[InstalledPackage], new: &[InstalledPackage]) -> Self {
+        let old: BTreeMap<&str, &InstalledPackage> = old.iter().map(|p| (p.name.as_str(), p)).collect();
+        let new: BTreeMap<&str, &InstalledPackage> = new.iter().map(|p| (p.name.as_str(), p)).collect();
+        let mut diff = Self::default();
+
+        for (name, pkg) in &old {
+            match new.get(name) {
+                None => diff.removed.push((*pkg).clone()),
+                Some(current) => {
+                    let change = || VersionChange {
+                        name: name.to_string(),
+                        from: pkg.version.clone(),
+                        to: current.version.clone(),
+                    };
+                    match vercmp(&pkg.version, &current.version) {
+                        Ordering::Less => diff.upgraded.push(change()),
+                        Ordering::Greater => diff.downgraded.push(change()),
+                        Ordering::Equal => {}
+                    }
+                }
+            }
+        }
+
+        diff.installed = new
+            .iter()
+            .filter(|(name, _)| !old.contains_key(*name))
+            .map(|(_, pkg)| (*pkg).clone())
+            .collect();
+
+        diff
+    }
+
+    /// Whether both roots have identical package sets
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty() && self.removed.is_empty() && self.upgraded.is_empty() && self.downgraded.is_empty()
+    }
+}
+
+impl fmt::Display for PackageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pkg in &self.installed {
+            writeln!(f, "+ {} {}", pkg.name, pkg.version)?;
+        }
+        for pkg in &self.removed {
+            writeln!(f, "- {} {}", pkg.name, pkg.version)?;
+        }
+        for change in &self.upgraded {
+            writeln!(f, "↑ {} {} -> {}", change.name, change.from, change.to)?;
+        }
+        for change in &self.downgraded {
+            writeln!(f, "↓ {} {} -> {}", change.name, change.from, change.to)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::localdb::{read_local_db, tests::write_entry};
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_diff_between_roots() {
+        let (a, b) = (tempdir().unwrap(), tempdir().unwrap());
+        write_entry(a.path(), "vim", "9.0.1-1", true);
+        write_entry(a.path(), "nano", "7.2-1", true);
+        write_entry(a.path(), "linux", "6.6.10.arch1-1", true);
+        write_entry(a.path(), "git", "2.43.0-1", true);
+
+        write_entry(b.path(), "vim", "9.0.2-1", true);
+        write_entry(b.path(), "linux", "6.6.9.arch1-1", true);
+        write_entry(b.path(), "git", "2.43.0-1", true);
+        write_entry(b.path(), "htop", "3.3.0-1", true);
+
+        let diff = PackageDiff::between(&read_local_db(a.path()).unwrap(), &read_local_db(b.path()).unwrap());
+        assert_eq!(diff.installed.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["htop"]);
+        assert_eq!(diff.removed.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["nano"]);
+        assert_eq!(diff.upgraded[0].name, "vim");
+        assert_eq!(diff.downgraded[0].to, "6.6.9.arch1-1");
+        assert!(!diff.is_empty());
+
+        let same = PackageDiff::between(&read_local_db(a.path()).unwrap(), &read_local_db(a.path()).unwrap());
+        assert!(same.is_empty());
+    }
+}
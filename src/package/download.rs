@@ -0,0 +1,169 @@
+//! Parallel package downloads
+//!
+//! pacman only reads `ParallelDownloads` from its configuration file, so the
+//! effective configuration is rewritten with the requested concurrency. For
+//! the libalpm backend the concurrency is set on the handle directly and
+//! per-file download progress is aggregated with [`DownloadProgress`].
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use tempfile::NamedTempFile;
+
+use super::PackageError;
+
+/// Default number of concurrent downloads
+pub const DEFAULT_PARALLEL_DOWNLOADS: u32 = 5;
+
+/// Set `ParallelDownloads` in the `[options]` section of a pacman configuration
+///
+/// An existing (possibly commented out) setting is replaced; otherwise the
+/// option is added at the top of `[options]`.
+pub fn with_parallel_downloads(conf: &str, downloads: u32) -> String {
+    let setting = format!("ParallelDownloads = {}", downloads.max(1));
+    let mut lines = Vec::new();
+    let mut section = String::new();
+    let mut written = false;
+
+    for line in conf.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            section = trimmed.to_string();
+            lines.push(line.to_string());
+            if section == "[options]" && !written && !conf_has_setting(conf) {
+                lines.push(setting.clone());
+                written = true;
+            }
+            continue;
+        }
+
+        let key = trimmed.trim_start_matches('#').split('=').next().unwrap_or_default().trim();
+        if section == "[options]" && key == "ParallelDownloads" {
+            if !written {
+                lines.push(setting.clone());
+                written = true;
+            }
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+
+    if !written {
+        lines.insert(0, setting);
+        lines.insert(0, "[options]".to_string());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn conf_has_setting(conf: &str) -> bool {
+    conf.lines().any(|l| {
+        l.trim().trim_start_matches('#').split('=').next().unwrap_or_default().trim() == "ParallelDownloads"
+    })
+}
+
+/// Write a copy of a pacman configuration with the given download concurrency
+///
+/// The copy is a new file in `dir`, created exclusively and deleted when the
+/// returned handle is dropped, so keep it until pacman has exited.
+pub fn write_pacman_conf(base: &str, downloads: u32, dir: &Path) -> Result<NamedTempFile, PackageError> {
+    write_temp_conf(&with_parallel_downloads(base, downloads), dir)
+}
+
+/// Write a pacman configuration to a new temporary file in `dir`
+pub fn write_temp_conf(content: &str, dir: &Path) -> Result<NamedTempFile, PackageError> {
+    fs::create_dir_all(dir)?;
+    let mut file = tempfile::Builder::new().prefix("pacman.").suffix(".conf").tempfile_in(dir)?;
+    file.write_all(content.as_bytes())?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// Aggregated progress over concurrently running downloads
+#[derive(Debug, Clone, Default)]
+pub struct DownloadProgress {
+    /// Downloaded and total bytes per file
+    files: HashMap<String, (u64, u64)>,
+
+    /// Files whose download finished
+    completed: usize,
+}
+
+impl DownloadProgress {
+    /// Record progress of a single file
+    pub fn update(&mut self, file: &str, downloaded: u64, total: u64) {
+        self.files.insert(file.to_string(), (downloaded, total));
+    }
+
+    /// Mark a file as finished
+    pub fn complete(&mut self, file: &str) {
+        if let Some((downloaded, total)) = self.files.get_mut(file) {
+            *downloaded = (*total).max(*downloaded);
+        }
+        self.completed += 1;
+    }
+
+    /// Bytes downloaded over all files
+    pub fn downloaded(&self) -> u64 {
+        self.files.values().map(|(d, _)| d).sum()
+    }
+
+    /// Total bytes over all known files
+    pub fn total(&self) -> u64 {
+        self.files.values().map(|(_, t)| t).sum()
+    }
+
+    /// Number of finished files
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    /// Number of files still downloading
+    pub fn active(&self) -> usize {
+        self.files.len().saturating_sub(self.completed)
+    }
+
+    /// Overall completion in percent
+    pub fn percent(&self) -> u8 {
+        match self.total() {
+            0 => 0,
+            total => ((self.downloaded() * 100) / total).min(100) as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_downloads_setting() {
+        let conf = "[options]\nArchitecture = auto\n#ParallelDownloads = 5\n\n[core]\nInclude = /etc/pacman.d/mirrorlist\n";
+        let rewritten = with_parallel_downloads(conf, 10);
+        assert!(rewritten.contains("\nParallelDownloads = 10\n"));
+        assert!(!rewritten.contains("#ParallelDownloads"));
+        assert!(rewritten.contains("[core]"));
+
+        let rewritten = with_parallel_downloads("[options]\nArchitecture = auto\n", 3);
+        assert!(rewritten.starts_with("[options]\nParallelDownloads = 3\n"));
+
+        let rewritten = with_parallel_downloads("[core]\nServer = file:///repo\n", 0);
+        assert!(rewritten.starts_with("[options]\nParallelDownloads = 1\n[core]"));
+    }
+
+    #[test]
+    fn test_progress_aggregate() {
+        let mut progress = DownloadProgress::default();
+        progress.update("vim.pkg.tar.zst", 50, 100);
+        progress.update("git.pkg.tar.zst", 0, 300);
+        assert_eq!(progress.percent(), 12);
+        assert_eq!(progress.active(), 2);
+
+        progress.complete("vim.pkg.tar.zst");
+        assert_eq!(progress.downloaded(), 100);
+        assert_eq!(progress.active(), 1);
+        assert_eq!(progress.total(), 400);
+    }
+}
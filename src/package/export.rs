@@ -0,0 +1,131 @@
+//! Export of the installed package set
+//!
+//! Captures the explicitly installed packages of an existing system into the
+//! declarative `PackageList` format, so a hand-managed machine can be turned
+//! into configuration. Packages not found in any sync database are treated as
+//! AUR packages and listed after the repository packages.
+
+use std::collections::HashSet;
+
+use super::localdb::InstalledPackage;
+use super::{PackageList, PackageSpec};
+
+/// Options controlling what an export records
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Pin each package to its installed version
+    pub versions: bool,
+
+    /// Record the source ("official" or "aur") of each package
+    pub sources: bool,
+
+    /// Include packages installed as dependencies
+    pub dependencies: bool,
+
+    /// Include installed Flatpak remotes and applications
+    pub flatpak: bool,
+}
+
+impl ExportOptions {
+    /// Pin exported packages to their installed versions
+    pub fn with_versions(mut self, versions: bool) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    /// Record package sources
+    pub fn with_sources(mut self, sources: bool) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Also export packages installed as dependencies
+    pub fn with_dependencies(mut self, dependencies: bool) -> Self {
+        self.dependencies = dependencies;
+        self
+    }
+
+    /// Also export Flatpak remotes and applications
+    pub fn with_flatpak(mut self, flatpak: bool) -> Self {
+        self.flatpak = flatpak;
+        self
+    }
+}
+
+/// Build a package list from installed packages
+///
+/// `foreign` holds the names of packages not available from any sync
+/// database (AUR or locally built packages).
+pub fn export_list(
+    installed: &[InstalledPackage],
+    foreign: &HashSet<String>,
+    options: &ExportOptions,
+) -> PackageList {
+    let to_spec = |pkg: &InstalledPackage, source: &str| {
+        let mut spec = PackageSpec::new(pkg.name.clone());
+        if options.versions {
+            spec.version = Some(format!("={}", pkg.version));
+        }
+        if options.sources {
+            spec.source = Some(source.to_string());
+        }
+        spec
+    };
+
+    let selected: Vec<&InstalledPackage> = installed
+        .iter()
+        .filter(|p| options.dependencies || p.is_explicit())
+        .collect();
+
+    let mut packages: Vec<PackageSpec> = selected
+        .iter()
+        .filter(|p| !foreign.contains(&p.name))
+        .map(|p| to_spec(p, "official"))
+        .collect();
+    packages.extend(
+        selected
+            .iter()
+            .filter(|p| foreign.contains(&p.name))
+            .map(|p| to_spec(p, "aur")),
+    );
+
+    PackageList {
+        packages,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::localdb::{read_local_db, tests::write_entry};
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_export_list() {
+        let root = tempdir().unwrap();
+        write_entry(root.path(), "vim", "9.0.1-1", true);
+        write_entry(root.path(), "gpm", "1.20.7-4", false);
+        write_entry(root.path(), "paru-bin", "2.0.1-1", true);
+        write_entry(root.path(), "bash", "5.2.21-2", true);
+
+        let installed = read_local_db(root.path()).unwrap();
+        let foreign: HashSet<String> = ["paru-bin".to_string()].into();
+        let options = ExportOptions::default().with_versions(true).with_sources(true);
+
+        let list = export_list(&installed, &foreign, &options);
+        let names: Vec<&str> = list.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["bash", "vim", "paru-bin"]);
+        assert_eq!(list.packages[1].version.as_deref(), Some("=9.0.1-1"));
+        assert_eq!(list.packages[2].source.as_deref(), Some("aur"));
+
+        // The exported file parses back into an equivalent list
+        let content = toml::to_string(&list).unwrap();
+        let parsed: PackageList = toml::from_str(&content).unwrap();
+        assert_eq!(parsed.packages.len(), 3);
+        assert_eq!(parsed.packages[0].source.as_deref(), Some("official"));
+
+        let bare = export_list(&installed, &foreign, &ExportOptions::default());
+        assert!(bare.packages.iter().all(|p| p.version.is_none() && p.source.is_none()));
+    }
+}
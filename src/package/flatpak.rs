@@ -0,0 +1,406 @@
+//! Flatpak applications
+//!
+//! Flatpak remotes and applications are declared in the `[flatpak]` table of a
+//! `PackageList` next to the native packages. The backend drives the
+//! `flatpak` command to add remotes, install, update and remove applications,
+//! and reports the installed state for export.
+
+use std::collections::HashSet;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use super::PackageError;
+
+/// A Flatpak remote repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlatpakRemote {
+    /// Remote name (e.g. "flathub")
+    pub name: String,
+
+    /// URL of the `.flatpakrepo` file or repository
+    pub url: String,
+}
+
+/// A Flatpak application
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlatpakApp {
+    /// Application ID (e.g. "org.mozilla.firefox")
+    pub id: String,
+
+    /// Remote to install from; flatpak picks one when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+
+    /// Branch to install (e.g. "stable")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+impl FlatpakApp {
+    /// Create an application entry with no remote or branch
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        Self {
+            id: id.into(),
+            remote: None,
+            branch: None,
+        }
+    }
+
+    /// Installation reference (`id` or `id//branch`)
+    pub fn reference(&self) -> String {
+        match &self.branch {
+            Some(branch) => format!("{}//{}", self.id, branch),
+            None => self.id.clone(),
+        }
+    }
+}
+
+/// The `[flatpak]` table of a package list
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlatpakConfig {
+    /// Install into the per-user installation instead of the system one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub user: bool,
+
+    /// Remotes to configure
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remotes: Vec<FlatpakRemote>,
+
+    /// Applications to install
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub apps: Vec<FlatpakApp>,
+}
+
+impl FlatpakConfig {
+    /// Whether nothing is declared
+    pub fn is_empty(&self) -> bool {
+        self.remotes.is_empty() && self.apps.is_empty()
+    }
+
+    /// Merge another config into this one; the other config wins on conflicts
+    pub fn merge(&mut self, other: FlatpakConfig) {
+        self.user |= other.user;
+        for remote in other.remotes {
+            match self.remotes.iter_mut().find(|r| r.name == remote.name) {
+                Some(existing) => *existing = remote,
+                None => self.remotes.push(remote),
+            }
+        }
+        for app in other.apps {
+            match self.apps.iter_mut().find(|a| a.id == app.id) {
+                Some(existing) => *existing = app,
+                None => self.apps.push(app),
+            }
+        }
+    }
+}
+
+/// Changes made while reconciling installed applications
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlatpakChanges {
+    /// Applications that were installed
+    pub installed: Vec<String>,
+
+    /// Applications that were removed
+    pub removed: Vec<String>,
+}
+
+/// Manages Flatpak remotes and applications
+#[derive(Debug, Clone, Default)]
+pub struct FlatpakBackend {
+    /// Operate on the per-user installation
+    user: bool,
+
+    /// Download applications without deploying them
+    no_deploy: bool,
+
+    /// Whether to show verbose output
+    verbose: bool,
+}
+
+impl FlatpakBackend {
+    /// Create a backend for the system installation
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Operate on the per-user installation
+    pub fn user(mut self, user: bool) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Only download applications, without deploying them
+    pub fn download_only(mut self, download_only: bool) -> Self {
+        self.no_deploy = download_only;
+        self
+    }
+
+    /// Enable verbose output
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Whether the `flatpak` command is available
+    pub fn is_available() -> bool {
+        Command::new("flatpak")
+            .arg("--version")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Add remotes that are not configured yet
+    pub fn add_remotes(&self, remotes: &[FlatpakRemote]) -> Result<(), PackageError> {
+        for remote in remotes {
+            self.run(&self.remote_add_args(remote))?;
+        }
+        Ok(())
+    }
+
+    /// Install applications
+    pub fn install(&self, apps: &[FlatpakApp]) -> Result<(), PackageError> {
+        for app in apps {
+            if self.verbose {
+                println!("Installing flatpak {}...", app.id);
+            }
+            self.run(&self.install_args(app))?;
+        }
+        Ok(())
+    }
+
+    /// Update all installed applications and runtimes
+    pub fn update(&self) -> Result<(), PackageError> {
+        let mut args = vec!["update".to_string()];
+        args.extend(self.common_args());
+        if self.no_deploy {
+            args.push("--no-deploy".to_string());
+        }
+        self.run(&args)
+    }
+
+    /// Remove applications by ID
+    pub fn remove(&self, ids: &[&str]) -> Result<(), PackageError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut args = vec!["uninstall".to_string()];
+        args.extend(self.common_args());
+        args.extend(ids.iter().map(|id| id.to_string()));
+        self.run(&args)
+    }
+
+    /// Add remotes and install the applications of a config
+    pub fn apply(&self, config: &FlatpakConfig) -> Result<(), PackageError> {
+        self.add_remotes(&config.remotes)?;
+        self.install(&config.apps)
+    }
+
+    /// Bring installed applications in line with a config
+    ///
+    /// Missing applications are installed. With `prune`, installed
+    /// applications that are not declared are removed.
+    pub fn reconcile(&self, config: &FlatpakConfig, prune: bool) -> Result<FlatpakChanges, PackageError> {
+        self.add_remotes(&config.remotes)?;
+
+        let installed: HashSet<String> = self.installed()?.into_iter().map(|a| a.id).collect();
+        let declared: HashSet<&str> = config.apps.iter().map(|a| a.id.as_str()).collect();
+        let mut changes = FlatpakChanges::default();
+
+        let missing: Vec<FlatpakApp> = config
+            .apps
+            .iter()
+            .filter(|a| !installed.contains(&a.id))
+            .cloned()
+            .collect();
+        self.install(&missing)?;
+        changes.installed = missing.into_iter().map(|a| a.id).collect();
+
+        if prune {
+            let mut extra: Vec<&str> = installed
+                .iter()
+                .map(String::as_str)
+                .filter(|id| !declared.contains(id))
+                .collect();
+            extra.sort();
+            self.remove(&extra)?;
+            changes.removed = extra.into_iter().map(String::from).collect();
+        }
+
+        Ok(changes)
+    }
+
+    /// List installed applications
+    pub fn installed(&self) -> Result<Vec<FlatpakApp>, PackageError> {
+        let mut args = vec!["list".to_string(), "--app".to_string(), "--columns=application,origin,branch".to_string()];
+        args.push(self.installation_arg().to_string());
+        Ok(parse_app_list(&self.output(&args)?))
+    }
+
+    /// List configured remotes
+    pub fn remotes(&self) -> Result<Vec<FlatpakRemote>, PackageError> {
+        let args = ["remotes".to_string(), "--columns=name,url".to_string(), self.installation_arg().to_string()];
+        Ok(parse_remote_list(&self.output(&args)?))
+    }
+
+    /// Capture the installed state as a config
+    pub fn export(&self) -> Result<FlatpakConfig, PackageError> {
+        Ok(FlatpakConfig {
+            user: self.user,
+            remotes: self.remotes()?,
+            apps: self.installed()?,
+        })
+    }
+
+    fn installation_arg(&self) -> &'static str {
+        if self.user { "--user" } else { "--system" }
+    }
+
+    fn common_args(&self) -> Vec<String> {
+        vec![
+            self.installation_arg().to_string(),
+            "--noninteractive".to_string(),
+            "-y".to_string(),
+        ]
+    }
+
+    fn remote_add_args(&self, remote: &FlatpakRemote) -> Vec<String> {
+        vec![
+            "remote-add".to_string(),
+            self.installation_arg().to_string(),
+            "--if-not-exists".to_string(),
+            remote.name.clone(),
+            remote.url.clone(),
+        ]
+    }
+
+    fn install_args(&self, app: &FlatpakApp) -> Vec<String> {
+        let mut args = vec!["install".to_string()];
+        args.extend(self.common_args());
+        if self.no_deploy {
+            args.push("--no-deploy".to_string());
+        }
+        if let Some(remote) = &app.remote {
+            args.push(remote.clone());
+        }
+        args.push(app.reference());
+        args
+    }
+
+    fn run(&self, args: &[String]) -> Result<(), PackageError> {
+        self.output(args).map(|_| ())
+    }
+
+    fn output(&self, args: &[String]) -> Result<String, PackageError> {
+        let output = Command::new("flatpak").args(args).output()?;
+
+        if !output.status.success() {
+            return Err(PackageError::OperationFailed(format!(
+                "Command 'flatpak {}' failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Parse `flatpak list --columns=application,origin,branch` output
+fn parse_app_list(output: &str) -> Vec<FlatpakApp> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut cols = line.split('\t').map(str::trim);
+            let id = cols.next().filter(|id| !id.is_empty())?;
+            let non_empty = |s: Option<&str>| s.filter(|s| !s.is_empty()).map(String::from);
+            Some(FlatpakApp {
+                id: id.to_string(),
+                remote: non_empty(cols.next()),
+                branch: non_empty(cols.next()),
+            })
+        })
+        .collect()
+}
+
+/// Parse `flatpak remotes --columns=name,url` output
+fn parse_remote_list(output: &str) -> Vec<FlatpakRemote> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, url) = line.split_once('\t')?;
+            Some(FlatpakRemote {
+                name: name.trim().to_string(),
+                url: url.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flatpak_table() {
+        let config: FlatpakConfig = toml::from_str(
+            r#"
+            remotes = [{ name = "flathub", url = "https://dl.flathub.org/repo/flathub.flatpakrepo" }]
+
+            [[apps]]
+            id = "org.mozilla.firefox"
+            remote = "flathub"
+
+            [[apps]]
+            id = "org.gimp.GIMP"
+            branch = "beta"
+            "#,
+        )
+        .unwrap();
+
+        assert!(!config.user);
+        assert_eq!(config.remotes[0].name, "flathub");
+        assert_eq!(config.apps[1].reference(), "org.gimp.GIMP//beta");
+    }
+
+    #[test]
+    fn test_install_args() {
+        let backend = FlatpakBackend::new().user(true).download_only(true);
+        let mut app = FlatpakApp::new("org.mozilla.firefox");
+        app.remote = Some("flathub".to_string());
+
+        assert_eq!(
+            backend.install_args(&app),
+            vec!["install", "--user", "--noninteractive", "-y", "--no-deploy", "flathub", "org.mozilla.firefox"]
+        );
+    }
+
+    #[test]
+    fn test_parse_list_output() {
+        let apps = parse_app_list("org.mozilla.firefox\tflathub\tstable\norg.gimp.GIMP\tflathub-beta\tbeta\n\n");
+        assert_eq!(apps.len(), 2);
+        assert_eq!(apps[0].remote.as_deref(), Some("flathub"));
+        assert_eq!(apps[1].branch.as_deref(), Some("beta"));
+
+        let remotes = parse_remote_list("flathub\thttps://dl.flathub.org/repo/\n");
+        assert_eq!(remotes[0].url, "https://dl.flathub.org/repo/");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = FlatpakConfig::default();
+        base.apps.push(FlatpakApp::new("org.gimp.GIMP"));
+
+        let mut other = FlatpakConfig::default();
+        let mut gimp = FlatpakApp::new("org.gimp.GIMP");
+        gimp.branch = Some("beta".to_string());
+        other.apps.push(gimp);
+        other.apps.push(FlatpakApp::new("org.mozilla.firefox"));
+
+        base.merge(other);
+        assert_eq!(base.apps.len(), 2);
+        assert_eq!(base.apps[0].branch.as_deref(), Some("beta"));
+    }
+}
@@ -0,0 +1,220 @@
+//! Package transaction history
+//!
+//! Every transaction is appended to a JSON lines database below the system
+//! root, recording which packages changed between which versions and the
+//! snapshots taken around it. The recorded versions are what allows a
+//! transaction to be undone later.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::diff::PackageDiff;
+use super::PackageError;
+
+/// Location of the history database relative to the system root
+pub const HISTORY_PATH: &str = "var/lib/rastos/package-history.jsonl";
+
+/// A version change of one package in a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageChange {
+    /// Package name
+    pub name: String,
+
+    /// Version before the transaction; `None` if newly installed
+    pub from: Option<String>,
+
+    /// Version after the transaction; `None` if removed
+    pub to: Option<String>,
+}
+
+/// A recorded package transaction
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    /// Transaction ID (also exported to hooks as `RASTOS_TRANSACTION_ID`)
+    pub id: Uuid,
+
+    /// When the transaction finished
+    pub timestamp: DateTime<Utc>,
+
+    /// Transaction action (e.g. "install", "undo")
+    pub action: String,
+
+    /// Package changes made by the transaction
+    pub changes: Vec<PackageChange>,
+
+    /// Snapshots associated with the transaction
+    #[serde(default)]
+    pub snapshots: Vec<String>,
+
+    /// Transaction this one reverted, if it is an undo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverts: Option<Uuid>,
+}
+
+impl TransactionRecord {
+    /// Create a record from the package database difference of a transaction
+    pub fn from_diff<S: Into<String>>(id: Uuid, action: S, diff: &PackageDiff) -> Self {
+        let mut changes: Vec<PackageChange> = Vec::new();
+        changes.extend(diff.installed.iter().map(|p| PackageChange {
+            name: p.name.clone(),
+            from: None,
+            to: Some(p.version.clone()),
+        }));
+        changes.extend(diff.removed.iter().map(|p| PackageChange {
+            name: p.name.clone(),
+            from: Some(p.version.clone()),
+            to: None,
+        }));
+        changes.extend(diff.upgraded.iter().chain(&diff.downgraded).map(|c| PackageChange {
+            name: c.name.clone(),
+            from: Some(c.from.clone()),
+            to: Some(c.to.clone()),
+        }));
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            id,
+            timestamp: Utc::now(),
+            action: action.into(),
+            changes,
+            snapshots: Vec::new(),
+            reverts: None,
+        }
+    }
+}
+
+/// The transaction history database of a system root
+#[derive(Debug, Clone)]
+pub struct TransactionHistory {
+    /// Path of the JSON lines database
+    path: PathBuf,
+}
+
+impl TransactionHistory {
+    /// Open the history database below a system root
+    pub fn open<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            path: root.as_ref().join(HISTORY_PATH),
+        }
+    }
+
+    /// Path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a record
+    pub fn append(&self, record: &TransactionRecord) -> Result<(), PackageError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let line = serde_json::to_string(record)
+            .map_err(|e| PackageError::ParseError(format!("Failed to serialize transaction: {}", e)))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    /// All records, oldest first
+    pub fn list(&self) -> Result<Vec<TransactionRecord>, PackageError> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                serde_json::from_str(l)
+                    .map_err(|e| PackageError::ParseError(format!("{}: {}", self.path.display(), e)))
+            })
+            .collect()
+    }
+
+    /// Look up a record by ID
+    pub fn get(&self, id: &Uuid) -> Result<Option<TransactionRecord>, PackageError> {
+        Ok(self.list()?.into_iter().find(|r| r.id == *id))
+    }
+
+    /// Associate a snapshot with a recorded transaction
+    pub fn attach_snapshot(&self, id: &Uuid, snapshot: &str) -> Result<(), PackageError> {
+        let mut records = self.list()?;
+        let record = records
+            .iter_mut()
+            .find(|r| r.id == *id)
+            .ok_or_else(|| PackageError::OperationFailed(format!("Unknown transaction: {}", id)))?;
+        record.snapshots.push(snapshot.to_string());
+
+        let mut content = String::new();
+        for record in &records {
+            content.push_str(
+                &serde_json::to_string(record)
+                    .map_err(|e| PackageError::ParseError(format!("Failed to serialize transaction: {}", e)))?,
+            );
+            content.push('\n');
+        }
+
+        // Replace atomically so a crash never truncates the history
+        let tmp = self.path.with_extension("jsonl.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::diff::VersionChange;
+    use super::super::localdb::{InstallReason, InstalledPackage};
+    use super::*;
+    use tempfile::tempdir;
+
+    fn package(name: &str, version: &str) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            reason: InstallReason::Explicit,
+            description: None,
+            packager: None,
+            db_entry: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn test_history_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let root = tempdir()?;
+        let history = TransactionHistory::open(root.path());
+        assert!(history.list()?.is_empty());
+
+        let diff = PackageDiff {
+            installed: vec![package("htop", "3.3.0-1")],
+            removed: vec![package("nano", "7.2-1")],
+            upgraded: vec![VersionChange {
+                name: "vim".to_string(),
+                from: "9.0.1-1".to_string(),
+                to: "9.0.2-1".to_string(),
+            }],
+            downgraded: Vec::new(),
+        };
+        let record = TransactionRecord::from_diff(Uuid::new_v4(), "install", &diff);
+        history.append(&record)?;
+        history.append(&TransactionRecord::from_diff(Uuid::new_v4(), "install", &PackageDiff::default()))?;
+
+        history.attach_snapshot(&record.id, "@root-42")?;
+
+        let records = history.list()?;
+        assert_eq!(records.len(), 2);
+        let stored = history.get(&record.id)?.unwrap();
+        assert_eq!(stored.snapshots, vec!["@root-42".to_string()]);
+        assert_eq!(stored.changes.len(), 3);
+        assert_eq!(stored.changes[0].name, "htop");
+        assert_eq!(stored.changes[0].from, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Package and transaction hooks
+//!
+//! Hooks are shell commands declared in a `PackageList`, either for a single
+//! package or for the whole transaction. They run in a restricted shell inside
+//! a [`Sandbox`] that may only write the package directories, with a minimal
+//! environment describing the transaction, and are killed when they exceed
+//! their timeout.
+
+use std::collections::HashMap;
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::PackageError;
+use crate::sandbox::{Sandbox, SandboxConfig};
+
+/// Default hook timeout in seconds
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+/// Search path available to hook commands
+const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/bin";
+
+/// Directories hooks may write, where packages install their files
+const HOOK_WRITABLE: &[&str] = &["/boot", "/etc", "/opt", "/usr", "/var"];
+
+/// Hooks attached to a single package
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageHooks {
+    /// Commands run before the package is installed
+    #[serde(default)]
+    pub pre_install: Vec<String>,
+
+    /// Commands run after the package is installed
+    #[serde(default)]
+    pub post_install: Vec<String>,
+}
+
+/// Hooks attached to a whole transaction
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransactionHooks {
+    /// Commands run before any package in the transaction is touched
+    #[serde(default)]
+    pub pre_transaction: Vec<String>,
+
+    /// Commands run after every package in the transaction was installed
+    #[serde(default)]
+    pub post_transaction: Vec<String>,
+
+    /// Timeout applied to each hook command, in seconds
+    ///
+    /// Unset means [`DEFAULT_HOOK_TIMEOUT`]; see [`TransactionHooks::timeout`].
+    #[serde(default)]
+    pub timeout: Option<u64>,
+
+    /// Sandbox the hook commands run in
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+}
+
+impl PackageHooks {
+    /// Whether no hooks are declared
+    pub fn is_empty(&self) -> bool {
+        self.pre_install.is_empty() && self.post_install.is_empty()
+    }
+}
+
+impl TransactionHooks {
+    /// Whether no hook commands are declared
+    pub fn is_empty(&self) -> bool {
+        self.pre_transaction.is_empty() && self.post_transaction.is_empty()
+    }
+
+    /// Timeout applied to each hook command
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT))
+    }
+}
+
+/// The point in a transaction at which a hook runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPhase {
+    /// Before the transaction
+    PreTransaction,
+    /// Before a package is installed
+    PreInstall,
+    /// After a package is installed
+    PostInstall,
+    /// After the transaction
+    PostTransaction,
+}
+
+impl HookPhase {
+    /// Stable name exported to hooks as `RASTOS_HOOK_PHASE`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PreTransaction => "pre-transaction",
+            Self::PreInstall => "pre-install",
+            Self::PostInstall => "post-install",
+            Self::PostTransaction => "post-transaction",
+        }
+    }
+}
+
+/// Description of the transaction a hook runs in
+#[derive(Debug, Clone)]
+pub struct TransactionContext {
+    /// Unique transaction ID
+    pub id: Uuid,
+
+    /// Transaction action (e.g. "install")
+    pub action: String,
+
+    /// Names of all packages in the transaction
+    pub packages: Vec<String>,
+}
+
+impl TransactionContext {
+    /// Create a context for a new transaction
+    pub fn new<S: Into<String>>(action: S, packages: Vec<String>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            action: action.into(),
+            packages,
+        }
+    }
+
+    /// Environment variables describing the transaction
+    pub fn env(&self, phase: HookPhase) -> HashMap<String, String> {
+        let mut env = HashMap::new();
+        env.insert("PATH".to_string(), HOOK_PATH.to_string());
+        env.insert("RASTOS_TRANSACTION_ID".to_string(), self.id.to_string());
+        env.insert("RASTOS_TRANSACTION_ACTION".to_string(), self.action.clone());
+        env.insert("RASTOS_TRANSACTION_PACKAGES".to_string(), self.packages.join(" "));
+        env.insert("RASTOS_HOOK_PHASE".to_string(), phase.as_str().to_string());
+        env
+    }
+}
+
+/// Executes hook commands in a restricted shell
+#[derive(Debug, Clone)]
+pub struct HookRunner {
+    /// Shell used for hook commands
+    shell: String,
+
+    /// Per-command timeout
+    timeout: Duration,
+
+    /// Sandbox the commands run in
+    sandbox: Sandbox,
+
+    /// Whether to echo hook commands
+    verbose: bool,
+}
+
+impl HookRunner {
+    /// Create a hook runner with the given per-command timeout
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            shell: "bash".to_string(),
+            timeout,
+            sandbox: Self::sandbox(SandboxConfig::default(), timeout),
+            verbose: false,
+        }
+    }
+
+    /// Run commands in a sandbox configured by `config`
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.sandbox = Self::sandbox(config, self.timeout);
+        self
+    }
+
+    fn sandbox(config: SandboxConfig, timeout: Duration) -> Sandbox {
+        HOOK_WRITABLE
+            .iter()
+            .fold(Sandbox::new(config), |sandbox, path| sandbox.with_writable(path))
+            .with_timeout(timeout)
+    }
+
+    /// Enable verbose output
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Run transaction-level hooks
+    pub fn run_transaction_hooks(
+        &self,
+        commands: &[String],
+        phase: HookPhase,
+        ctx: &TransactionContext,
+    ) -> Result<(), PackageError> {
+        let env = ctx.env(phase);
+        self.run_all(commands, phase, &env)
+    }
+
+    /// Run hooks of a single package
+    pub fn run_package_hooks(
+        &self,
+        commands: &[String],
+        phase: HookPhase,
+        ctx: &TransactionContext,
+        package: &str,
+        version: Option<&str>,
+    ) -> Result<(), PackageError> {
+        let mut env = ctx.env(phase);
+        env.insert("RASTOS_PACKAGE_NAME".to_string(), package.to_string());
+        env.insert("RASTOS_PACKAGE_VERSION".to_string(), version.unwrap_or("").to_string());
+        self.run_all(commands, phase, &env)
+    }
+
+    fn run_all(
+        &self,
+        commands: &[String],
+        phase: HookPhase,
+        env: &HashMap<String, String>,
+    ) -> Result<(), PackageError> {
+        for cmd in commands {
+            if self.verbose {
+                println!("Running {} hook: {}", phase.as_str(), cmd);
+            }
+
+            self.run(cmd, env).map_err(|e| {
+                PackageError::OperationFailed(format!("{} hook failed: {} - {}", phase.as_str(), cmd, e))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Run a single command, killing it if it exceeds the timeout
+    pub fn run(&self, command: &str, env: &HashMap<String, String>) -> Result<(), PackageError> {
+        let status = self
+            .sandbox
+            .status(
+                Command::new(&self.shell)
+                    .args(["--restricted", "--noprofile", "--norc", "-c", command])
+                    .env_clear()
+                    .envs(env)
+                    .stdin(Stdio::null()),
+            )
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::TimedOut => PackageError::OperationFailed(e.to_string()),
+                _ => e.into(),
+            })?;
+        if !status.success() {
+            return Err(PackageError::OperationFailed(format!(
+                "exited with code {}",
+                status.code().unwrap_or(-1)
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_environment() {
+        let ctx = TransactionContext::new("install", vec!["vim".to_string(), "git".to_string()]);
+        let env = ctx.env(HookPhase::PostTransaction);
+        assert_eq!(env["RASTOS_TRANSACTION_PACKAGES"], "vim git");
+        assert_eq!(env["RASTOS_HOOK_PHASE"], "post-transaction");
+        assert_eq!(env["RASTOS_TRANSACTION_ID"], ctx.id.to_string());
+    }
+
+    #[test]
+    fn test_hook_exit_status() {
+        let runner = HookRunner::new(Duration::from_secs(5));
+        let ctx = TransactionContext::new("install", vec![]);
+        let env = ctx.env(HookPhase::PreTransaction);
+
+        assert!(runner.run("test \"$RASTOS_HOOK_PHASE\" = pre-transaction", &env).is_ok());
+        assert!(runner.run("exit 3", &env).is_err());
+        // Restricted shells refuse to change directory
+        assert!(runner.run("cd /tmp", &env).is_err());
+    }
+
+    #[test]
+    fn test_hook_timeout() {
+        let runner = HookRunner::new(Duration::from_millis(200));
+        let env = TransactionContext::new("install", vec![]).env(HookPhase::PreInstall);
+        let err = runner.run("sleep 5", &env).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[test]
+    fn test_hooks_deserialize() {
+        let hooks: TransactionHooks = toml::from_str("pre_transaction = [\"true\"]").unwrap();
+        assert_eq!(hooks.timeout(), Duration::from_secs(DEFAULT_HOOK_TIMEOUT));
+        assert!(hooks.sandbox.enabled);
+        assert_eq!(hooks.pre_transaction, vec!["true".to_string()]);
+    }
+}
@@ -0,0 +1,399 @@
+//! File ownership and integrity checks
+//!
+//! Answers which package owns a file and verifies the files of an installed
+//! package against the mtree metadata pacman stores in the local database
+//! (type, permissions, ownership, size, modification time and SHA-256). Used
+//! by drift detection and rollback tooling to tell package-managed changes
+//! from local modifications.
+
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+
+use super::localdb::{read_local_db, InstalledPackage};
+use crate::fs::hash::sha256_file;
+use super::PackageError;
+
+/// File type recorded in an mtree entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    /// Regular file
+    File,
+    /// Directory
+    Dir,
+    /// Symbolic link
+    Link,
+}
+
+/// Metadata recorded for one path in a package's mtree
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtreeEntry {
+    /// Path relative to the root, without a leading `./`
+    pub path: PathBuf,
+
+    /// Entry type
+    pub kind: EntryType,
+
+    /// Permission bits
+    pub mode: Option<u32>,
+
+    /// Owning user ID
+    pub uid: Option<u32>,
+
+    /// Owning group ID
+    pub gid: Option<u32>,
+
+    /// File size in bytes
+    pub size: Option<u64>,
+
+    /// Modification time in seconds since the epoch
+    pub mtime: Option<i64>,
+
+    /// SHA-256 digest of the contents
+    pub sha256: Option<String>,
+
+    /// Symbolic link target
+    pub link: Option<String>,
+}
+
+/// A mismatch between a file and its recorded metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileIssue {
+    /// The file does not exist
+    Missing,
+    /// The file type differs
+    Type,
+    /// The permission bits differ
+    Permissions {
+        /// Recorded mode
+        expected: u32,
+        /// Mode on disk
+        actual: u32,
+    },
+    /// The owning user or group differs
+    Ownership,
+    /// The size differs
+    Size,
+    /// The modification time differs
+    ModificationTime,
+    /// The contents differ
+    Checksum,
+    /// The symbolic link points elsewhere
+    LinkTarget,
+}
+
+/// A file that failed verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProblem {
+    /// Path relative to the root
+    pub path: PathBuf,
+
+    /// What differs
+    pub issue: FileIssue,
+
+    /// Whether the file is a configuration file expected to change
+    pub backup: bool,
+}
+
+/// Result of verifying one package
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Package name
+    pub package: String,
+
+    /// Number of paths checked
+    pub checked: usize,
+
+    /// Detected problems
+    pub problems: Vec<FileProblem>,
+}
+
+impl VerifyReport {
+    /// Whether all files match, ignoring modified configuration files
+    pub fn is_intact(&self) -> bool {
+        self.problems.iter().all(|p| p.backup)
+    }
+}
+
+/// Find the installed package owning a path below `root`
+///
+/// `path` may be given relative to the root or as an absolute path inside the
+/// root. Directories are often shared by several packages; the first owner in
+/// name order is returned.
+pub fn owner_of<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P) -> Result<Option<InstalledPackage>, PackageError> {
+    let root = root.as_ref();
+    let path = path.as_ref();
+    let relative = path
+        .strip_prefix(root)
+        .or_else(|_| path.strip_prefix("/"))
+        .unwrap_or(path)
+        .to_string_lossy()
+        .trim_end_matches('/')
+        .to_string();
+    let as_dir = format!("{}/", relative);
+
+    for pkg in read_local_db(root)? {
+        if pkg.files()?.iter().any(|f| *f == relative || *f == as_dir) {
+            return Ok(Some(pkg));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Verify the files of an installed package below `root`
+pub fn verify_package<R: AsRef<Path>>(root: R, name: &str) -> Result<VerifyReport, PackageError> {
+    let root = root.as_ref();
+    let pkg = read_local_db(root)?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| PackageError::OperationFailed(format!("Package not installed: {}", name)))?;
+
+    let backup = pkg.backup_files()?;
+    let entries = read_mtree(&pkg.db_entry.join("mtree"))?;
+
+    let mut report = VerifyReport {
+        package: pkg.name.clone(),
+        ..Default::default()
+    };
+    for entry in &entries {
+        report.checked += 1;
+        let is_backup = backup.iter().any(|b| Path::new(b) == entry.path);
+        for issue in check_entry(root, entry)? {
+            report.problems.push(FileProblem {
+                path: entry.path.clone(),
+                issue,
+                backup: is_backup,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Read a gzip-compressed mtree file
+pub fn read_mtree(path: &Path) -> Result<Vec<MtreeEntry>, PackageError> {
+    let mut content = String::new();
+    GzDecoder::new(fs::File::open(path)?).read_to_string(&mut content)?;
+    Ok(parse_mtree(&content))
+}
+
+/// Parse mtree text, skipping package metadata files (`.PKGINFO`, ...)
+pub fn parse_mtree(content: &str) -> Vec<MtreeEntry> {
+    let mut defaults: Vec<(String, String)> = Vec::new();
+    let mut entries = Vec::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+        let keywords: Vec<(String, String)> = fields
+            .map(|f| match f.split_once('=') {
+                Some((k, v)) => (k.to_string(), v.to_string()),
+                None => (f.to_string(), String::new()),
+            })
+            .collect();
+
+        match first {
+            "/set" => {
+                for (k, v) in keywords {
+                    defaults.retain(|(dk, _)| *dk != k);
+                    defaults.push((k, v));
+                }
+            }
+            "/unset" => defaults.retain(|(dk, _)| !keywords.iter().any(|(k, _)| k == dk)),
+            path => {
+                let path = unescape(path.trim_start_matches("./"));
+                if path.starts_with('.') || path == "." {
+                    continue;
+                }
+                let get = |key: &str| {
+                    keywords
+                        .iter()
+                        .chain(defaults.iter())
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| v.as_str())
+                };
+
+                entries.push(MtreeEntry {
+                    path: PathBuf::from(&path),
+                    kind: match get("type") {
+                        Some("dir") => EntryType::Dir,
+                        Some("link") => EntryType::Link,
+                        _ => EntryType::File,
+                    },
+                    mode: get("mode").and_then(|m| u32::from_str_radix(m, 8).ok()),
+                    uid: get("uid").and_then(|v| v.parse().ok()),
+                    gid: get("gid").and_then(|v| v.parse().ok()),
+                    size: get("size").and_then(|v| v.parse().ok()),
+                    mtime: get("time").and_then(|t| t.split('.').next()?.parse().ok()),
+                    sha256: get("sha256digest").map(String::from),
+                    link: get("link").map(unescape),
+                });
+            }
+        }
+    }
+
+    entries
+}
+
+/// Decode the `\ooo` octal escapes used in mtree paths
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let value = bytes[i + 1..i + 4].iter().fold(0u32, |acc, b| acc * 8 + u32::from(b - b'0'));
+            out.push(value as u8);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Compare one mtree entry with the file on disk
+fn check_entry(root: &Path, entry: &MtreeEntry) -> Result<Vec<FileIssue>, PackageError> {
+    let path = root.join(&entry.path);
+    let meta = match fs::symlink_metadata(&path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![FileIssue::Missing]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let actual_kind = if meta.file_type().is_symlink() {
+        EntryType::Link
+    } else if meta.is_dir() {
+        EntryType::Dir
+    } else {
+        EntryType::File
+    };
+    if actual_kind != entry.kind {
+        return Ok(vec![FileIssue::Type]);
+    }
+
+    let mut issues = Vec::new();
+
+    if entry.kind == EntryType::Link {
+        let target = fs::read_link(&path)?;
+        if entry.link.as_deref().is_some_and(|l| Path::new(l) != target) {
+            issues.push(FileIssue::LinkTarget);
+        }
+        return Ok(issues);
+    }
+
+    if let Some(expected) = entry.mode {
+        let actual = meta.mode() & 0o7777;
+        if actual != expected {
+            issues.push(FileIssue::Permissions { expected, actual });
+        }
+    }
+    if entry.uid.is_some_and(|uid| uid != meta.uid()) || entry.gid.is_some_and(|gid| gid != meta.gid()) {
+        issues.push(FileIssue::Ownership);
+    }
+
+    if entry.kind == EntryType::File {
+        if entry.size.is_some_and(|size| size != meta.len()) {
+            issues.push(FileIssue::Size);
+        }
+        if entry.mtime.is_some_and(|mtime| mtime != meta.mtime()) {
+            issues.push(FileIssue::ModificationTime);
+        }
+        if let Some(expected) = &entry.sha256 {
+            if !sha256_file(&path).map_err(std::io::Error::from)?.eq_ignore_ascii_case(expected) {
+                issues.push(FileIssue::Checksum);
+            }
+        }
+    }
+
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::localdb::{tests::write_entry, LOCAL_DB_PATH};
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn write_files(root: &Path, name: &str, version: &str, files: &str, mtree: &str) {
+        let dir = root.join(LOCAL_DB_PATH).join(format!("{}-{}", name, version));
+        fs::write(dir.join("files"), files).unwrap();
+        let mut gz = GzEncoder::new(fs::File::create(dir.join("mtree")).unwrap(), Compression::default());
+        gz.write_all(mtree.as_bytes()).unwrap();
+        gz.finish().unwrap();
+    }
+
+    #[test]
+    fn test_parse_mtree() {
+        let entries = parse_mtree(
+            "#mtree\n/set type=file uid=0 gid=0 mode=644\n\
+             ./.PKGINFO time=1 size=10\n\
+             ./etc time=1700000000.0 mode=755 type=dir\n\
+             ./etc/my\\040app.conf time=1700000000.5 size=5 sha256digest=abc\n\
+             ./usr/bin/vi time=1 mode=777 type=link link=vim\n",
+        );
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].kind, EntryType::Dir);
+        assert_eq!(entries[0].mode, Some(0o755));
+        assert_eq!(entries[1].path, PathBuf::from("etc/my app.conf"));
+        assert_eq!(entries[1].mode, Some(0o644));
+        assert_eq!(entries[1].mtime, Some(1_700_000_000));
+        assert_eq!(entries[2].link.as_deref(), Some("vim"));
+    }
+
+    #[test]
+    fn test_owner_and_verify() {
+        let root = tempdir().unwrap();
+        write_entry(root.path(), "hello", "1.0-1", true);
+
+        let file = root.path().join("usr/bin/hello");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "hello").unwrap();
+        let meta = fs::metadata(&file).unwrap();
+        let digest = sha256_file(&file).unwrap();
+
+        write_files(
+            root.path(),
+            "hello",
+            "1.0-1",
+            "%FILES%\nusr/\nusr/bin/\nusr/bin/hello\n\n",
+            &format!(
+                "#mtree\n./usr/bin/hello type=file mode={:o} uid={} gid={} size=5 time={}.0 sha256digest={}\n",
+                meta.mode() & 0o7777,
+                meta.uid(),
+                meta.gid(),
+                meta.mtime(),
+                digest
+            ),
+        );
+
+        let owner = owner_of(root.path(), "/usr/bin/hello").unwrap().unwrap();
+        assert_eq!(owner.name, "hello");
+        assert!(owner_of(root.path(), root.path().join("usr/bin")).unwrap().is_some());
+        assert!(owner_of(root.path(), "/usr/bin/other").unwrap().is_none());
+
+        assert!(verify_package(root.path(), "hello").unwrap().is_intact());
+
+        fs::write(&file, "HELLO").unwrap();
+        let report = verify_package(root.path(), "hello").unwrap();
+        assert!(!report.is_intact());
+        assert!(report.problems.iter().any(|p| p.issue == FileIssue::Checksum));
+
+        fs::remove_file(&file).unwrap();
+        let report = verify_package(root.path(), "hello").unwrap();
+        assert_eq!(report.problems[0].issue, FileIssue::Missing);
+    }
+}
@@ -0,0 +1,338 @@
+//! Package keyring and signature management
+//!
+//! Wraps `pacman-key` and `gpg` to initialize and refresh the package keyring,
+//! import distribution keys and verify detached package signatures before
+//! packages are handed to the installer.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use thiserror::Error;
+
+/// Default pacman GnuPG home directory
+pub const DEFAULT_GPG_DIR: &str = "/etc/pacman.d/gnupg";
+
+/// Default keyserver used for key lookups and refreshes
+pub const DEFAULT_KEYSERVER: &str = "hkps://keyserver.ubuntu.com";
+
+/// Errors that can occur during keyring and signature operations
+#[derive(Error, Debug)]
+pub enum KeyringError {
+    /// An I/O error occurred
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A keyserver or other network operation failed
+    #[error("Network error while contacting keyserver: {0}")]
+    Network(String),
+
+    /// The signature does not match the package contents
+    #[error("Bad signature for {0}")]
+    BadSignature(PathBuf),
+
+    /// No detached signature was found for the package
+    #[error("Missing signature for {0}")]
+    MissingSignature(PathBuf),
+
+    /// The signing key is not in the keyring
+    #[error("Unknown signing key: {0}")]
+    UnknownKey(String),
+
+    /// The signing key has expired or been revoked
+    #[error("Signing key {0} is expired or revoked")]
+    ExpiredKey(String),
+
+    /// The signing key is known but not fully trusted
+    #[error("Signing key {0} is not trusted")]
+    UntrustedKey(String),
+
+    /// A received or refreshed key failed verification
+    #[error("Key verification failed: {0}")]
+    Verification(String),
+
+    /// A keyring command failed for a non-network reason
+    #[error("Keyring command '{command}' failed: {message}")]
+    CommandFailed {
+        /// The command that was executed
+        command: String,
+        /// The error output of the command
+        message: String,
+    },
+}
+
+/// Result type for keyring operations
+pub type Result<T> = std::result::Result<T, KeyringError>;
+
+/// Information about a successfully verified signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureInfo {
+    /// Long key ID of the signing key
+    pub key_id: String,
+
+    /// Full fingerprint of the signing key, if reported
+    pub fingerprint: Option<String>,
+
+    /// User ID of the signer
+    pub signer: String,
+}
+
+/// Manages the package signing keyring
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    /// GnuPG home directory of the keyring
+    gpg_dir: PathBuf,
+
+    /// Keyserver used for receiving and refreshing keys
+    keyserver: String,
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Self::new(DEFAULT_GPG_DIR)
+    }
+}
+
+impl Keyring {
+    /// Create a keyring handle for the given GnuPG home directory
+    pub fn new<P: AsRef<Path>>(gpg_dir: P) -> Self {
+        Self {
+            gpg_dir: gpg_dir.as_ref().to_path_buf(),
+            keyserver: DEFAULT_KEYSERVER.to_string(),
+        }
+    }
+
+    /// Use a different keyserver
+    pub fn with_keyserver<S: Into<String>>(mut self, keyserver: S) -> Self {
+        self.keyserver = keyserver.into();
+        self
+    }
+
+    /// Get the GnuPG home directory
+    pub fn gpg_dir(&self) -> &Path {
+        &self.gpg_dir
+    }
+
+    /// Initialize the keyring, generating the local master key
+    pub fn init(&self) -> Result<()> {
+        self.pacman_key(&["--init"])
+    }
+
+    /// Populate the keyring from installed distribution keyrings
+    ///
+    /// An empty list populates all keyrings shipped in `/usr/share/pacman/keyrings`.
+    pub fn populate(&self, keyrings: &[&str]) -> Result<()> {
+        let mut args = vec!["--populate"];
+        args.extend_from_slice(keyrings);
+        self.pacman_key(&args)
+    }
+
+    /// Refresh all keys from the keyserver
+    pub fn refresh(&self) -> Result<()> {
+        self.pacman_key(&["--keyserver", &self.keyserver, "--refresh-keys"])
+            .map_err(classify_keyserver_error)
+    }
+
+    /// Receive a key from the keyserver and locally sign it
+    pub fn receive_key(&self, key_id: &str) -> Result<()> {
+        self.pacman_key(&["--keyserver", &self.keyserver, "--recv-keys", key_id])
+            .map_err(classify_keyserver_error)?;
+        self.pacman_key(&["--lsign-key", key_id])
+    }
+
+    /// Import a distribution key from a file and locally sign it
+    pub fn import_key<P: AsRef<Path>>(&self, key_file: P, key_id: &str) -> Result<()> {
+        let key_file = key_file.as_ref();
+        if !key_file.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Key file not found: {}", key_file.display()),
+            )
+            .into());
+        }
+
+        let path = key_file.to_string_lossy();
+        self.pacman_key(&["--add", &path])?;
+        self.pacman_key(&["--lsign-key", key_id])
+    }
+
+    /// Verify a package against its detached `.sig` signature
+    pub fn verify_package<P: AsRef<Path>>(&self, package: P) -> Result<SignatureInfo> {
+        let package = package.as_ref();
+        let signature = signature_path(package);
+
+        if !signature.exists() {
+            return Err(KeyringError::MissingSignature(package.to_path_buf()));
+        }
+
+        self.verify_signature(package, &signature)
+    }
+
+    /// Verify a file against an explicit detached signature
+    pub fn verify_signature(&self, file: &Path, signature: &Path) -> Result<SignatureInfo> {
+        let output = Command::new("gpg")
+            .arg("--homedir")
+            .arg(&self.gpg_dir)
+            .args(["--batch", "--status-fd", "1", "--verify"])
+            .arg(signature)
+            .arg(file)
+            .output()?;
+
+        let status = String::from_utf8_lossy(&output.stdout);
+        parse_verify_status(&status, file)
+    }
+
+    fn pacman_key(&self, args: &[&str]) -> Result<()> {
+        let output = Command::new("pacman-key")
+            .arg("--gpgdir")
+            .arg(&self.gpg_dir)
+            .args(args)
+            .output()?;
+
+        check_output("pacman-key", args, &output)
+    }
+}
+
+/// Path of the detached signature for a package file
+pub fn signature_path(package: &Path) -> PathBuf {
+    let mut sig = package.as_os_str().to_owned();
+    sig.push(".sig");
+    PathBuf::from(sig)
+}
+
+/// Interpret `gpg --status-fd` output of a verify operation
+///
+/// A good signature only counts when the key is fully or ultimately trusted,
+/// as pacman's default `SigLevel` requires.
+pub fn parse_verify_status(status: &str, file: &Path) -> Result<SignatureInfo> {
+    let mut info: Option<SignatureInfo> = None;
+    let mut fingerprint = None;
+    let mut trusted = false;
+
+    for line in status.lines() {
+        let mut fields = line.trim().strip_prefix("[GNUPG:] ").unwrap_or("").splitn(3, ' ');
+        let keyword = fields.next().unwrap_or("");
+        let key_id = fields.next().unwrap_or("").to_string();
+        let rest = fields.next().unwrap_or("").to_string();
+
+        match keyword {
+            "GOODSIG" => {
+                info = Some(SignatureInfo {
+                    key_id,
+                    fingerprint: None,
+                    signer: rest,
+                })
+            }
+            "VALIDSIG" => fingerprint = Some(key_id),
+            "TRUST_FULLY" | "TRUST_ULTIMATE" => trusted = true,
+            "BADSIG" => return Err(KeyringError::BadSignature(file.to_path_buf())),
+            "NO_PUBKEY" => return Err(KeyringError::UnknownKey(key_id)),
+            "EXPKEYSIG" | "REVKEYSIG" => return Err(KeyringError::ExpiredKey(key_id)),
+            _ => {}
+        }
+    }
+
+    match info {
+        Some(info) if !trusted => Err(KeyringError::UntrustedKey(info.key_id)),
+        Some(mut info) => {
+            info.fingerprint = fingerprint;
+            Ok(info)
+        }
+        None => Err(KeyringError::BadSignature(file.to_path_buf())),
+    }
+}
+
+fn check_output(program: &str, args: &[&str], output: &Output) -> Result<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(KeyringError::CommandFailed {
+        command: format!("{} {}", program, args.join(" ")),
+        message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    })
+}
+
+/// Messages of keys that were received but rejected
+const VERIFICATION_FAILURES: &[&str] = &["bad signature", "no valid user ids", "verification failed", "invalid key"];
+
+/// Keyserver operations fail for network reasons far more often than anything else
+///
+/// Keys that arrived but failed verification are reported as such.
+fn classify_keyserver_error(err: KeyringError) -> KeyringError {
+    match err {
+        KeyringError::CommandFailed { message, .. } => {
+            let lower = message.to_lowercase();
+            if VERIFICATION_FAILURES.iter().any(|failure| lower.contains(failure)) {
+                KeyringError::Verification(message)
+            } else {
+                KeyringError::Network(message)
+            }
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_good_signature() {
+        let status = "\
+[GNUPG:] NEWSIG
+[GNUPG:] GOODSIG 786C63F330D7CB92 Packager <packager@example.org>
+[GNUPG:] VALIDSIG ABCDEF0123456789ABCDEF0123456789ABCDEF01 2024-01-01 1704067200
+[GNUPG:] TRUST_FULLY 0 pgp
+";
+        let info = parse_verify_status(status, Path::new("pkg.tar.zst")).unwrap();
+        assert_eq!(info.key_id, "786C63F330D7CB92");
+        assert_eq!(info.signer, "Packager <packager@example.org>");
+        assert!(info.fingerprint.is_some());
+    }
+
+    #[test]
+    fn test_parse_failures() {
+        let file = Path::new("pkg.tar.zst");
+        assert!(matches!(
+            parse_verify_status("[GNUPG:] BADSIG 786C63F330D7CB92 Packager", file),
+            Err(KeyringError::BadSignature(_))
+        ));
+        assert!(matches!(
+            parse_verify_status("[GNUPG:] NO_PUBKEY 786C63F330D7CB92", file),
+            Err(KeyringError::UnknownKey(id)) if id == "786C63F330D7CB92"
+        ));
+        assert!(matches!(
+            parse_verify_status("[GNUPG:] EXPKEYSIG 786C63F330D7CB92 Packager", file),
+            Err(KeyringError::ExpiredKey(_))
+        ));
+        assert!(matches!(parse_verify_status("", file), Err(KeyringError::BadSignature(_))));
+        assert!(matches!(
+            parse_verify_status("[GNUPG:] GOODSIG 786C63F330D7CB92 Packager\n[GNUPG:] TRUST_UNDEFINED 0 pgp", file),
+            Err(KeyringError::UntrustedKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_keyserver_errors() {
+        let failed = |message: &str| KeyringError::CommandFailed {
+            command: "pacman-key --recv-keys".to_string(),
+            message: message.to_string(),
+        };
+        assert!(matches!(
+            classify_keyserver_error(failed("gpg: keyserver receive failed: No route to host")),
+            KeyringError::Network(_)
+        ));
+        assert!(matches!(
+            classify_keyserver_error(failed("gpg: key 786C63F330D7CB92: no valid user IDs")),
+            KeyringError::Verification(_)
+        ));
+    }
+
+    #[test]
+    fn test_signature_path() {
+        assert_eq!(
+            signature_path(Path::new("/var/cache/pacman/pkg/foo-1.0-1-x86_64.pkg.tar.zst")),
+            PathBuf::from("/var/cache/pacman/pkg/foo-1.0-1-x86_64.pkg.tar.zst.sig")
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Read-only access to the pacman local database
+//!
+//! Parses `var/lib/pacman/local/*/desc` below an arbitrary root, so the
+//! installed package set of the running system and of mounted snapshots can
+//! be inspected without invoking pacman.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::PackageError;
+
+/// Location of the local database relative to the system root
+pub const LOCAL_DB_PATH: &str = "var/lib/pacman/local";
+
+/// Why a package is installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    /// Explicitly requested by the user
+    Explicit,
+    /// Pulled in as a dependency
+    Dependency,
+}
+
+/// A package recorded in the local database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    /// Package name
+    pub name: String,
+
+    /// Installed version (`epoch:pkgver-pkgrel`)
+    pub version: String,
+
+    /// Install reason
+    pub reason: InstallReason,
+
+    /// Package description
+    pub description: Option<String>,
+
+    /// Packager responsible for the build
+    pub packager: Option<String>,
+
+    /// Directory of the package entry in the local database
+    #[serde(skip)]
+    pub db_entry: PathBuf,
+}
+
+/// Parse a pacman database file (`%SECTION%` headers followed by values)
+pub fn parse_db_file(content: &str) -> HashMap<String, Vec<String>> {
+    let mut sections = HashMap::new();
+    let mut current: Option<String> = None;
+
+    for line in content.lines() {
+        let line = line.trim_end();
+        if line.len() > 2 && line.starts_with('%') && line.ends_with('%') {
+            current = Some(line.trim_matches('%').to_string());
+            continue;
+        }
+        if let (Some(section), false) = (&current, line.is_empty()) {
+            sections
+                .entry(section.clone())
+                .or_insert_with(Vec::new)
+                .push(line.to_string());
+        }
+    }
+
+    sections
+}
+
+impl InstalledPackage {
+    /// Parse a package from the contents of its `desc` file
+    pub fn from_desc(content: &str, db_entry: PathBuf) -> Option<Self> {
+        let sections = parse_db_file(content);
+        let first = |key: &str| sections.get(key).and_then(|v| v.first()).cloned();
+
+        Some(Self {
+            name: first("NAME")?,
+            version: first("VERSION")?,
+            reason: match first("REASON").as_deref() {
+                Some("1") => InstallReason::Dependency,
+                _ => InstallReason::Explicit,
+            },
+            description: first("DESC"),
+            packager: first("PACKAGER"),
+            db_entry,
+        })
+    }
+
+    /// Whether the package was explicitly installed
+    pub fn is_explicit(&self) -> bool {
+        self.reason == InstallReason::Explicit
+    }
+
+    /// Read the files file of this package's database entry
+    fn files_sections(&self) -> Result<HashMap<String, Vec<String>>, PackageError> {
+        let content = fs::read_to_string(self.db_entry.join("files"))?;
+        Ok(parse_db_file(&content))
+    }
+
+    /// Paths owned by the package, relative to the root
+    ///
+    /// Directories carry a trailing `/`, as recorded by pacman.
+    pub fn files(&self) -> Result<Vec<String>, PackageError> {
+        Ok(self.files_sections()?.remove("FILES").unwrap_or_default())
+    }
+
+    /// Configuration files that are expected to be modified locally
+    pub fn backup_files(&self) -> Result<Vec<String>, PackageError> {
+        Ok(self
+            .files_sections()?
+            .remove("BACKUP")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|line| line.split('\t').next().map(String::from))
+            .collect())
+    }
+}
+
+/// Read all installed packages from the local database below `root`
+///
+/// Returns packages sorted by name.
+pub fn read_local_db<P: AsRef<Path>>(root: P) -> Result<Vec<InstalledPackage>, PackageError> {
+    let db_dir = root.as_ref().join(LOCAL_DB_PATH);
+    if !db_dir.is_dir() {
+        return Err(PackageError::OperationFailed(format!(
+            "No pacman database found at {}",
+            db_dir.display()
+        )));
+    }
+
+    let mut packages = Vec::new();
+    for entry in fs::read_dir(&db_dir)? {
+        let entry = entry?.path();
+        let desc = entry.join("desc");
+        if !desc.is_file() {
+            continue;
+        }
+
+        let content = fs::read_to_string(&desc)?;
+        match InstalledPackage::from_desc(&content, entry.clone()) {
+            Some(pkg) => packages.push(pkg),
+            None => log::warn!("Skipping malformed database entry {}", entry.display()),
+        }
+    }
+
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Create a fake local database entry below `root`
+    pub(crate) fn write_entry(root: &Path, name: &str, version: &str, explicit: bool) {
+        let dir = root.join(LOCAL_DB_PATH).join(format!("{}-{}", name, version));
+        fs::create_dir_all(&dir).unwrap();
+        let reason = if explicit { "" } else { "%REASON%\n1\n\n" };
+        fs::write(
+            dir.join("desc"),
+            format!("%NAME%\n{}\n\n%VERSION%\n{}\n\n%DESC%\nTest package\n\n{}", name, version, reason),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_read_local_db() {
+        let root = tempdir().unwrap();
+        write_entry(root.path(), "vim", "9.0.1-1", true);
+        write_entry(root.path(), "gpm", "1.20.7-4", false);
+
+        let packages = read_local_db(root.path()).unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].name, "gpm");
+        assert_eq!(packages[0].reason, InstallReason::Dependency);
+        assert!(packages[1].is_explicit());
+        assert_eq!(packages[1].description.as_deref(), Some("Test package"));
+    }
+
+    #[test]
+    fn test_missing_db() {
+        let root = tempdir().unwrap();
+        assert!(read_local_db(root.path()).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Repository mirror management and ranking
+//!
+//! Fetches the upstream mirror list, measures latency and throughput of each
+//! candidate and writes an optimized `mirrorlist` for pacman. Ranking can be
+//! scheduled through a generated systemd timer.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use super::PackageError;
+
+/// Default location of the pacman mirror list
+pub const DEFAULT_MIRRORLIST: &str = "/etc/pacman.d/mirrorlist";
+
+/// Default upstream mirror list generator
+pub const DEFAULT_MIRROR_SOURCE: &str = "https://archlinux.org/mirrorlist/?country=all&protocol=https&use_mirror_status=on";
+
+/// A single package repository mirror
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mirror {
+    /// Server URL template (may contain `$repo` and `$arch`)
+    pub url: String,
+
+    /// Country the mirror is located in, if known
+    pub country: Option<String>,
+
+    /// Measured connection latency
+    pub latency: Option<Duration>,
+
+    /// Measured download throughput in bytes per second
+    pub throughput: Option<f64>,
+}
+
+impl Mirror {
+    /// Create a new, unmeasured mirror
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self {
+            url: url.into(),
+            country: None,
+            latency: None,
+            throughput: None,
+        }
+    }
+
+    /// Resolve the URL of a file in the given repository and architecture
+    pub fn file_url(&self, repo: &str, arch: &str, file: &str) -> String {
+        let base = self.url.replace("$repo", repo).replace("$arch", arch);
+        format!("{}/{}", base.trim_end_matches('/'), file)
+    }
+
+    /// Whether this mirror has been measured successfully
+    pub fn is_measured(&self) -> bool {
+        self.latency.is_some() && self.throughput.is_some()
+    }
+}
+
+/// Options controlling how mirrors are fetched and ranked
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    /// URL of the upstream mirror list
+    pub source_url: String,
+
+    /// Only keep mirrors from these countries (empty means all)
+    pub countries: Vec<String>,
+
+    /// Timeout for each mirror measurement
+    pub timeout: Duration,
+
+    /// Maximum number of mirrors to keep in the ranked list
+    pub max_mirrors: usize,
+
+    /// Number of mirrors measured at the same time
+    pub parallel: usize,
+
+    /// Repository used for the throughput test
+    pub test_repo: String,
+
+    /// Architecture used for the throughput test
+    pub arch: String,
+}
+
+impl Default for MirrorOptions {
+    fn default() -> Self {
+        Self {
+            source_url: DEFAULT_MIRROR_SOURCE.to_string(),
+            countries: Vec::new(),
+            timeout: Duration::from_secs(5),
+            max_mirrors: 10,
+            parallel: 8,
+            test_repo: "core".to_string(),
+            arch: "x86_64".to_string(),
+        }
+    }
+}
+
+impl MirrorOptions {
+    /// Restrict mirrors to the given countries
+    pub fn with_countries<S: Into<String>>(mut self, countries: impl IntoIterator<Item = S>) -> Self {
+        self.countries = countries.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the per-mirror measurement timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of ranked mirrors to keep
+    pub fn with_max_mirrors(mut self, max_mirrors: usize) -> Self {
+        self.max_mirrors = max_mirrors;
+        self
+    }
+
+    /// Set how many mirrors are measured at the same time
+    pub fn with_parallel(mut self, parallel: usize) -> Self {
+        self.parallel = parallel;
+        self
+    }
+}
+
+/// Parse a pacman-style mirror list
+///
+/// Both active (`Server = ...`) and commented (`#Server = ...`) entries are
+/// returned. `## Country` headings are attached to the following entries,
+/// up to the next heading or blank line; other `##` comments, such as the
+/// generation date, are not taken for countries.
+pub fn parse_mirrorlist(content: &str) -> Vec<Mirror> {
+    let mut mirrors = Vec::new();
+    let mut country = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line == "##" {
+            country = None;
+            continue;
+        }
+        if let Some(heading) = line.strip_prefix("## ") {
+            let heading = heading.trim();
+            country = is_country(heading).then(|| heading.to_string());
+            continue;
+        }
+
+        let entry = line.trim_start_matches('#').trim();
+        if let Some((key, value)) = entry.split_once('=') {
+            if key.trim() == "Server" {
+                let mut mirror = Mirror::new(value.trim());
+                mirror.country = country.clone();
+                mirrors.push(mirror);
+            }
+        }
+    }
+
+    mirrors
+}
+
+/// Whether a `##` heading has the form of a country name ("Germany", "Côte d'Ivoire")
+fn is_country(heading: &str) -> bool {
+    !heading.is_empty()
+        && heading.chars().all(|c| c.is_alphabetic() || matches!(c, ' ' | '-' | '\'' | '.' | ','))
+}
+
+/// Render mirrors as a pacman mirror list
+pub fn render_mirrorlist(mirrors: &[Mirror]) -> String {
+    let mut out = String::from("# Generated by rastOS mirror ranking\n\n");
+
+    for mirror in mirrors {
+        if let Some(country) = &mirror.country {
+            out.push_str(&format!("## {}\n", country));
+        }
+        if let (Some(latency), Some(throughput)) = (mirror.latency, mirror.throughput) {
+            out.push_str(&format!(
+                "# latency: {} ms, throughput: {:.0} KiB/s\n",
+                latency.as_millis(),
+                throughput / 1024.0
+            ));
+        }
+        out.push_str(&format!("Server = {}\n", mirror.url));
+    }
+
+    out
+}
+
+/// Fetch the upstream mirror list
+pub fn fetch_mirrors(options: &MirrorOptions) -> Result<Vec<Mirror>, PackageError> {
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location"])
+        .arg("--max-time")
+        .arg(options.timeout.as_secs().max(1).saturating_mul(6).to_string())
+        .arg(&options.source_url)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(PackageError::Network(format!(
+            "Failed to fetch mirror list from {}: {}",
+            options.source_url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let mirrors = parse_mirrorlist(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .filter(|m| {
+            options.countries.is_empty()
+                || m.country
+                    .as_ref()
+                    .map(|c| options.countries.iter().any(|w| w.eq_ignore_ascii_case(c)))
+                    .unwrap_or(false)
+        })
+        .collect();
+
+    Ok(mirrors)
+}
+
+/// Measure latency and throughput of a single mirror
+///
+/// Downloads the test repository database and records the connect time and
+/// average download speed reported by curl.
+pub fn measure_mirror(mirror: &mut Mirror, options: &MirrorOptions) -> Result<(), PackageError> {
+    let url = mirror.file_url(&options.test_repo, &options.arch, &format!("{}.db", options.test_repo));
+
+    let output = Command::new("curl")
+        .args(["--silent", "--fail", "--location", "--output", "/dev/null"])
+        .args(["--write-out", "%{time_connect} %{speed_download}"])
+        .arg("--max-time")
+        .arg(options.timeout.as_secs().max(1).to_string())
+        .arg(&url)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(PackageError::Network(format!("Mirror {} is unreachable", mirror.url)));
+    }
+
+    let (latency, throughput) = parse_curl_timing(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| PackageError::ParseError(format!("Unexpected curl output for {}", url)))?;
+
+    mirror.latency = Some(latency);
+    mirror.throughput = Some(throughput);
+    Ok(())
+}
+
+/// Parse the `%{time_connect} %{speed_download}` output of curl
+fn parse_curl_timing(output: &str) -> Option<(Duration, f64)> {
+    let mut parts = output.split_whitespace();
+    let connect: f64 = parts.next()?.parse().ok()?;
+    let speed: f64 = parts.next()?.parse().ok()?;
+    Some((Duration::from_secs_f64(connect), speed))
+}
+
+/// Sort measured mirrors by throughput (descending), then latency
+///
+/// Mirrors that could not be measured are dropped.
+pub fn sort_by_rank(mirrors: &mut Vec<Mirror>) {
+    mirrors.retain(Mirror::is_measured);
+    mirrors.sort_by(|a, b| {
+        b.throughput
+            .partial_cmp(&a.throughput)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.latency.cmp(&b.latency))
+    });
+}
+
+/// Measure a set of mirrors, up to `options.parallel` at a time
+///
+/// Unreachable mirrors are left unmeasured.
+pub fn measure_mirrors(mirrors: &mut [Mirror], options: &MirrorOptions) {
+    let queue = Mutex::new(mirrors.iter_mut());
+    thread::scope(|scope| {
+        for _ in 0..options.parallel.max(1) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                let Some(mirror) = next else {
+                    break;
+                };
+                if let Err(e) = measure_mirror(mirror, options) {
+                    log::debug!("Skipping mirror {}: {}", mirror.url, e);
+                }
+            });
+        }
+    });
+}
+
+/// Fetch, measure and rank mirrors
+///
+/// Up to `options.parallel` mirrors are measured at a time. Returns at most
+/// `options.max_mirrors` mirrors, fastest first.
+pub fn rank_mirrors(options: &MirrorOptions) -> Result<Vec<Mirror>, PackageError> {
+    let mut mirrors = fetch_mirrors(options)?;
+    measure_mirrors(&mut mirrors, options);
+    sort_by_rank(&mut mirrors);
+    mirrors.truncate(options.max_mirrors);
+
+    if mirrors.is_empty() {
+        return Err(PackageError::Network("No reachable mirrors found".to_string()));
+    }
+
+    Ok(mirrors)
+}
+
+/// Write the given mirrors to a pacman mirror list
+///
+/// The previous mirror list is kept next to the target with a `.bak` suffix.
+pub fn set_mirrors<P: AsRef<Path>>(mirrors: &[Mirror], path: P) -> Result<(), PackageError> {
+    let path = path.as_ref();
+
+    if mirrors.is_empty() {
+        return Err(PackageError::OperationFailed("Refusing to write an empty mirror list".to_string()));
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if path.exists() {
+        fs::copy(path, path.with_extension("bak"))?;
+    }
+
+    fs::write(path, render_mirrorlist(mirrors))?;
+    Ok(())
+}
+
+/// Periodic mirror ranking schedule rendered as systemd units
+#[derive(Debug, Clone)]
+pub struct MirrorSchedule {
+    /// systemd `OnCalendar=` expression
+    pub on_calendar: String,
+
+    /// Command executed by the service unit
+    pub command: String,
+}
+
+impl Default for MirrorSchedule {
+    fn default() -> Self {
+        Self {
+            on_calendar: "weekly".to_string(),
+            command: "/usr/bin/rastos package mirrors rank --write".to_string(),
+        }
+    }
+}
+
+impl MirrorSchedule {
+    /// Set the systemd calendar expression
+    pub fn with_calendar<S: Into<String>>(mut self, on_calendar: S) -> Self {
+        self.on_calendar = on_calendar.into();
+        self
+    }
+
+    /// Render the service unit
+    pub fn service_unit(&self) -> String {
+        format!(
+            "[Unit]\nDescription=Rank rastOS package mirrors\nWants=network-online.target\nAfter=network-online.target\n\n[Service]\nType=oneshot\nExecStart={}\n",
+            self.command
+        )
+    }
+
+    /// Render the timer unit
+    pub fn timer_unit(&self) -> String {
+        format!(
+            "[Unit]\nDescription=Periodically rank rastOS package mirrors\n\n[Timer]\nOnCalendar={}\nPersistent=true\nRandomizedDelaySec=1h\n\n[Install]\nWantedBy=timers.target\n",
+            self.on_calendar
+        )
+    }
+
+    /// Write the service and timer units into a systemd unit directory
+    pub fn install<P: AsRef<Path>>(&self, unit_dir: P) -> Result<Vec<PathBuf>, PackageError> {
+        let unit_dir = unit_dir.as_ref();
+        fs::create_dir_all(unit_dir)?;
+
+        let service = unit_dir.join("rastos-mirrors.service");
+        let timer = unit_dir.join("rastos-mirrors.timer");
+        fs::write(&service, self.service_unit())?;
+        fs::write(&timer, self.timer_unit())?;
+
+        Ok(vec![service, timer])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const SAMPLE: &str = "\
+##
+## Arch Linux repository mirrorlist
+## Generated on 2024-05-01
+##
+
+#Server = https://worldwide.example/$repo/os/$arch
+
+## Germany
+#Server = https://mirror.example.de/archlinux/$repo/os/$arch
+## Sweden
+Server = https://ftp.example.se/mirror/archlinux/$repo/os/$arch
+";
+
+    #[test]
+    fn test_parse_mirrorlist() {
+        let mirrors = parse_mirrorlist(SAMPLE);
+        assert_eq!(mirrors.len(), 3);
+        assert_eq!(mirrors[0].country, None);
+        assert_eq!(mirrors[1].country.as_deref(), Some("Germany"));
+        assert_eq!(mirrors[2].url, "https://ftp.example.se/mirror/archlinux/$repo/os/$arch");
+        assert_eq!(
+            mirrors[1].file_url("core", "x86_64", "core.db"),
+            "https://mirror.example.de/archlinux/core/os/x86_64/core.db"
+        );
+    }
+
+    #[test]
+    fn test_sort_by_rank() {
+        let mut fast = Mirror::new("https://fast");
+        fast.latency = Some(Duration::from_millis(20));
+        fast.throughput = Some(5_000_000.0);
+
+        let mut slow = Mirror::new("https://slow");
+        slow.latency = Some(Duration::from_millis(10));
+        slow.throughput = Some(100_000.0);
+
+        let mut mirrors = vec![slow, Mirror::new("https://dead"), fast];
+        sort_by_rank(&mut mirrors);
+
+        assert_eq!(mirrors.len(), 2);
+        assert_eq!(mirrors[0].url, "https://fast");
+    }
+
+    #[test]
+    fn test_set_mirrors_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("mirrorlist");
+
+        let mut mirror = Mirror::new("https://mirror.example/$repo/os/$arch");
+        mirror.country = Some("Example".to_string());
+        set_mirrors(&[mirror.clone()], &path)?;
+
+        let parsed = parse_mirrorlist(&fs::read_to_string(&path)?);
+        assert_eq!(parsed, vec![mirror]);
+        assert!(parse_curl_timing("0.012 1048576.000").is_some());
+        Ok(())
+    }
+}
@@ -1,11 +1,63 @@
 //! Package management for rastOS
 
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use thiserror::Error;
-use std::fs;
+use uuid::Uuid;
 use std::path::PathBuf;
 
+use crate::dry_run::{Action, DryRun};
+use crate::transaction::{Executor, TransactionError};
+
+#[cfg(feature = "alpm")]
+pub mod alpm;
+pub mod audit;
+#[cfg(feature = "containers")]
+pub mod aur;
+pub mod cache;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod diff;
+pub mod download;
+pub mod export;
+pub mod flatpak;
+pub mod history;
+pub mod hooks;
+pub mod integrity;
+pub mod keyring;
+pub mod localdb;
+pub mod mirrors;
+pub mod profile;
+pub mod transaction;
+pub mod version;
+
+#[cfg(feature = "alpm")]
+pub use self::alpm::{AlpmBackend, AlpmEvent, AlpmTransactionError};
+pub use audit::{AuditReport, Severity, VulnerablePackage};
+#[cfg(feature = "containers")]
+pub use aur::AurBuilder;
+pub use cache::{CachedPackage, PackageCache};
+pub use diff::{PackageDiff, VersionChange};
+pub use download::DownloadProgress;
+pub use export::ExportOptions;
+pub use history::{PackageChange, TransactionHistory, TransactionRecord};
+pub use flatpak::{FlatpakApp, FlatpakBackend, FlatpakChanges, FlatpakConfig, FlatpakRemote};
+pub use hooks::{HookPhase, HookRunner, PackageHooks, TransactionContext, TransactionHooks};
+pub use integrity::{FileIssue, FileProblem, VerifyReport};
+pub use keyring::{Keyring, KeyringError, SignatureInfo};
+pub use localdb::{read_local_db, InstallReason, InstalledPackage};
+pub use mirrors::{rank_mirrors, set_mirrors, Mirror, MirrorOptions};
+pub use profile::{load_package_list, HostProfile, PackageGroup};
+pub use transaction::PackageInstall;
+pub use version::{vercmp, VersionConstraint};
+
+/// System pacman configuration
+const PACMAN_CONF: &str = "/etc/pacman.conf";
+
+/// pacman package cache relative to the system root
+const PACMAN_CACHE_DIR: &str = "var/cache/pacman/pkg";
+
 /// Error type for package management operations
 #[derive(Error, Debug)]
 pub enum PackageError {
@@ -20,6 +72,27 @@ pub enum PackageError {
     /// A package operation failed to complete successfully
     #[error("Package operation failed: {0}")]
     OperationFailed(String),
+    
+    /// A network request (mirror list, package download) failed
+    #[error("Network error: {0}")]
+    Network(String),
+    
+    /// Package signature or keyring verification failed
+    #[error("Signature verification failed: {0}")]
+    Signature(#[from] KeyringError),
+    
+    /// The install journal could not be written or rolled back
+    #[error("Transaction journal error: {0}")]
+    Journal(#[from] TransactionError),
+
+    /// Command output could not be printed
+    #[error("Output error: {0}")]
+    Output(#[from] crate::output::OutputError),
+    
+    /// A libalpm transaction failed
+    #[cfg(feature = "alpm")]
+    #[error("Transaction failed: {0}")]
+    Transaction(#[from] AlpmTransactionError),
 }
 
 /// Package specification with version constraints
@@ -36,29 +109,102 @@ pub struct PackageSpec {
     
     /// Optional installation options
     pub options: Option<Vec<String>>,
+    
+    /// Hooks run around the installation of this package
+    #[serde(default, skip_serializing_if = "PackageHooks::is_empty")]
+    pub hooks: PackageHooks,
+}
+
+impl PackageSpec {
+    /// Create a spec for the named package with no constraints
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            version: None,
+            source: None,
+            options: None,
+            hooks: PackageHooks::default(),
+        }
+    }
+    
+    /// Parse the version constraint of this spec
+    pub fn constraint(&self) -> Result<VersionConstraint, PackageError> {
+        match &self.version {
+            Some(v) => VersionConstraint::parse(v),
+            None => Ok(VersionConstraint::Any),
+        }
+    }
+    
+    /// Render the install target (`name>=1.0`) understood by pacman, paru and libalpm
+    pub fn target(&self) -> Result<String, PackageError> {
+        Ok(self.constraint()?.target(&self.name))
+    }
 }
 
 /// Package list specification
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PackageList {
+    /// Other package list files merged into this one, relative to this file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
+    
     /// List of packages to install
+    #[serde(default)]
     pub packages: Vec<PackageSpec>,
     
-    /// Optional pre-installation commands
-    pub pre_install: Option<Vec<String>>,
+    /// Named package groups
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, PackageGroup>,
+    
+    /// Host profiles composed of groups
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, HostProfile>,
     
-    /// Optional post-installation commands
-    pub post_install: Option<Vec<String>>,
+    /// Hooks run around the whole transaction
+    #[serde(default, skip_serializing_if = "TransactionHooks::is_empty")]
+    pub hooks: TransactionHooks,
+    
+    /// Flatpak remotes and applications
+    #[serde(default, skip_serializing_if = "FlatpakConfig::is_empty")]
+    pub flatpak: FlatpakConfig,
 }
 
 /// Manages system packages
 pub struct PackageManager {
     /// The base path for package management operations
-    #[allow(dead_code)]
     base_path: PathBuf,
     
     /// Whether to show verbose output
     verbose: bool,
+    
+    /// Keyring used to verify local package files before install
+    keyring: Option<Keyring>,
+    
+    /// Sandboxed builder for AUR packages; `paru` is used when unset
+    #[cfg(feature = "containers")]
+    aur_builder: Option<AurBuilder>,
+    
+    /// Local cache used as the only package source for offline installs
+    offline_cache: Option<PackageCache>,
+    
+    /// Resolve and download packages without installing them
+    download_only: bool,
+    
+    /// Cache receiving downloaded packages in download-only mode
+    warm_cache: Option<PackageCache>,
+    
+    /// Number of concurrent package downloads; pacman.conf decides when unset
+    parallel_downloads: Option<u32>,
+    
+    /// Snapshots recorded with the transactions of this manager
+    snapshots: Vec<String>,
+    
+    /// Records package commands and transactions instead of running them
+    dry_run: DryRun,
+    
+    /// Native libalpm backend used instead of spawning `pacman`
+    #[cfg(feature = "alpm")]
+    alpm: Option<AlpmBackend>,
 }
 
 impl PackageManager {
@@ -67,6 +213,17 @@ impl PackageManager {
         Self {
             base_path: PathBuf::from(base_path),
             verbose: false,
+            keyring: None,
+            #[cfg(feature = "containers")]
+            aur_builder: None,
+            offline_cache: None,
+            download_only: false,
+            warm_cache: None,
+            parallel_downloads: None,
+            snapshots: Vec::new(),
+            dry_run: DryRun::off(),
+            #[cfg(feature = "alpm")]
+            alpm: None,
         }
     }
     
@@ -76,114 +233,531 @@ impl PackageManager {
         self
     }
     
+    /// Verify package signatures with the given keyring before installing local files
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+    
+    /// Build AUR packages in sandboxed containers instead of on the host
+    #[cfg(feature = "containers")]
+    pub fn with_aur_builder(mut self, builder: AurBuilder) -> Self {
+        self.aur_builder = Some(builder);
+        self
+    }
+    
+    /// Install exclusively from a local package cache or mounted repository
+    ///
+    /// No network mirrors are contacted; every package must already be
+    /// present in the cache.
+    pub fn offline(mut self, cache: PackageCache) -> Self {
+        self.offline_cache = Some(cache);
+        self
+    }
+    
+    /// Only resolve and download packages, leaving the system untouched
+    ///
+    /// Useful for staging upgrades ahead of a maintenance window. Hooks are
+    /// not run in this mode.
+    pub fn download_only(mut self, download_only: bool) -> Self {
+        self.download_only = download_only;
+        self
+    }
+    
+    /// Download packages into a local package cache without installing them
+    ///
+    /// Implies download-only mode. The cache repository database is
+    /// regenerated afterwards so the cache can serve later offline installs.
+    pub fn warm_cache(mut self, cache: PackageCache) -> Self {
+        self.download_only = true;
+        self.warm_cache = Some(cache);
+        self
+    }
+    
+    /// Download up to `downloads` packages concurrently
+    pub fn parallel_downloads(mut self, downloads: u32) -> Self {
+        self.parallel_downloads = Some(downloads.max(1));
+        self
+    }
+    
+    /// Associate a snapshot with every transaction recorded by this manager
+    pub fn with_snapshot<S: Into<String>>(mut self, snapshot: S) -> Self {
+        self.snapshots.push(snapshot.into());
+        self
+    }
+    
+    /// Record package commands and transactions in `dry_run` instead of running them
+    ///
+    /// Hooks are not run in a dry run and nothing is added to the history.
+    pub fn with_dry_run(mut self, dry_run: DryRun) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+    
+    /// Use libalpm directly for repository installs instead of `pacman`
+    #[cfg(feature = "alpm")]
+    pub fn with_alpm(mut self, backend: AlpmBackend) -> Self {
+        self.alpm = Some(backend);
+        self
+    }
+    
+    /// Compare the installed packages of two snapshots
+    ///
+    /// `a` and `b` are the roots of mounted Btrfs snapshots (or any system
+    /// roots); the result describes the changes going from `a` to `b`.
+    pub fn diff_snapshots<P: AsRef<Path>>(&self, a: P, b: P) -> Result<PackageDiff, PackageError> {
+        let old = read_local_db(a)?;
+        let new = read_local_db(b)?;
+        let diff = PackageDiff::between(&old, &new);
+        
+        if self.verbose {
+            println!(
+                "{} installed, {} removed, {} upgraded, {} downgraded",
+                diff.installed.len(),
+                diff.removed.len(),
+                diff.upgraded.len(),
+                diff.downgraded.len()
+            );
+        }
+        
+        Ok(diff)
+    }
+    
+    /// Find the installed package owning a file
+    pub fn owner_of<P: AsRef<Path>>(&self, path: P) -> Result<Option<InstalledPackage>, PackageError> {
+        integrity::owner_of(&self.base_path, path)
+    }
+    
+    /// Verify the files of an installed package against the package database
+    ///
+    /// Checks type, permissions, ownership, size, modification time and
+    /// checksum of every file recorded for the package.
+    pub fn verify_package(&self, name: &str) -> Result<VerifyReport, PackageError> {
+        let report = integrity::verify_package(&self.base_path, name)?;
+        
+        if self.verbose {
+            for problem in &report.problems {
+                println!("{}: {} {:?}", name, problem.path.display(), problem.issue);
+            }
+        }
+        
+        Ok(report)
+    }
+    
+    /// Check installed packages against the distribution security advisories
+    pub fn audit(&self) -> Result<AuditReport, PackageError> {
+        self.audit_feed(audit::DEFAULT_ADVISORY_FEED)
+    }
+    
+    /// Check installed packages against the advisories of the given feed
+    pub fn audit_feed(&self, url: &str) -> Result<AuditReport, PackageError> {
+        let installed = read_local_db(&self.base_path)?;
+        let advisories = audit::fetch_advisories(url)?;
+        let report = AuditReport::from_advisories(&installed, &advisories);
+        
+        if self.verbose {
+            println!(
+                "Checked {} packages against {} advisories, {} vulnerable",
+                report.checked,
+                advisories.len(),
+                report.vulnerable.len()
+            );
+        }
+        
+        Ok(report)
+    }
+    
+    /// Export the explicitly installed packages of this system to a package list file
+    pub fn export<P: AsRef<Path>>(&self, path: P) -> Result<(), PackageError> {
+        let options = ExportOptions::default()
+            .with_sources(true)
+            .with_flatpak(FlatpakBackend::is_available());
+        self.export_with(path, &options)
+    }
+    
+    /// Export installed packages to a package list file with the given options
+    pub fn export_with<P: AsRef<Path>>(&self, path: P, options: &ExportOptions) -> Result<(), PackageError> {
+        let installed = read_local_db(&self.base_path)?;
+        let foreign = self.foreign_packages()?;
+        let mut list = export::export_list(&installed, &foreign, options);
+        if options.flatpak {
+            list.flatpak = FlatpakBackend::new().export()?;
+        }
+        
+        let content = toml::to_string(&list)
+            .map_err(|e| PackageError::ParseError(format!("Failed to serialize package list: {}", e)))?;
+        std::fs::write(
+            path.as_ref(),
+            format!("# Exported from {}\n\n{}", self.base_path.display(), content),
+        )?;
+        
+        if self.verbose {
+            println!("Exported {} packages to {}", list.packages.len(), path.as_ref().display());
+        }
+        
+        Ok(())
+    }
+    
+    /// Names of installed packages not found in any sync database
+    fn foreign_packages(&self) -> Result<HashSet<String>, PackageError> {
+        Ok(crate::sys::pacman::foreign_packages(&self.base_path)?)
+    }
+    
+    /// Install local package files, verifying their signatures first
+    ///
+    /// Every file must have a valid detached `.sig` signature when a keyring
+    /// is configured. Nothing is installed if any verification fails.
+    pub fn install_files<P: AsRef<Path>>(&self, files: &[P]) -> Result<(), PackageError> {
+        if let Some(keyring) = &self.keyring {
+            for file in files {
+                let info = keyring.verify_package(file)?;
+                if self.verbose {
+                    println!("Verified {} (signed by {})", file.as_ref().display(), info.signer);
+                }
+            }
+        }
+        
+        self.install_package_files(files)
+    }
+    
+    /// Run `pacman -U` on local package files without signature checks
+    fn install_package_files<P: AsRef<Path>>(&self, files: &[P]) -> Result<(), PackageError> {
+        let paths: Vec<String> = files.iter()
+            .map(|f| f.as_ref().to_string_lossy().into_owned())
+            .collect();
+        let mut args = vec!["-U", "--noconfirm"];
+        args.extend(paths.iter().map(String::as_str));
+        
+        self.run_command("pacman", &args)
+    }
+    
     /// Install packages from a declarative package list file
+    ///
+    /// Included files are merged; only the top-level packages are installed.
     pub fn install_from_file<P: AsRef<Path>>(&self, path: P) -> Result<(), PackageError> {
-        let content = fs::read_to_string(&path)?;
-        let pkg_list: PackageList = toml::from_str(&content)
-            .map_err(|e| PackageError::ParseError(e.to_string()))?;
-        
+        let pkg_list = load_package_list(path)?;
         self.install_list(&pkg_list)
     }
     
+    /// Install the packages of the given groups or host profiles from a package list file
+    pub fn install_profile<P: AsRef<Path>>(&self, path: P, selection: &[&str]) -> Result<(), PackageError> {
+        let pkg_list = load_package_list(path)?;
+        self.install_list(&pkg_list.select(selection)?)
+    }
+    
     /// Install packages from a PackageList
+    ///
+    /// Transaction hooks wrap the whole install; package hooks run before and
+    /// after the batch containing their package. The install is journaled
+    /// and rolled back if any batch fails (see [`transaction`]).
     pub fn install_list(&self, pkg_list: &PackageList) -> Result<(), PackageError> {
-        if let Some(cmds) = &pkg_list.pre_install {
-            self.run_commands(cmds, "pre-install")?;
+        if self.download_only {
+            return self.download_list(pkg_list);
         }
         
-        // Group packages by source for batch processing
-        let mut official_pkgs = Vec::new();
-        let mut aur_pkgs = Vec::new();
+        let ctx = TransactionContext::new(
+            "install",
+            pkg_list.packages.iter().map(|p| p.name.clone()).collect(),
+        );
+        let hooks = HookRunner::new(pkg_list.hooks.timeout())
+            .with_sandbox(pkg_list.hooks.sandbox.clone())
+            .verbose(self.verbose);
+        
+        let dry_run = self.dry_run.is_active();
+        if !dry_run {
+            hooks.run_transaction_hooks(&pkg_list.hooks.pre_transaction, HookPhase::PreTransaction, &ctx)?;
+        }
+        let mut install = PackageInstall::new(self, pkg_list, &hooks, &ctx);
+        let executor = Executor::with_root(&self.base_path).with_dry_run(self.dry_run.clone());
+        if let Err(e) = executor.run(&mut install) {
+            return Err(e.downcast::<PackageError>()?);
+        }
+        if !dry_run {
+            hooks.run_transaction_hooks(&pkg_list.hooks.post_transaction, HookPhase::PostTransaction, &ctx)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Recorded package transactions, oldest first
+    pub fn history(&self) -> Result<Vec<TransactionRecord>, PackageError> {
+        TransactionHistory::open(&self.base_path).list()
+    }
+    
+    /// Associate a snapshot with a recorded transaction
+    pub fn attach_snapshot(&self, transaction_id: &Uuid, snapshot: &str) -> Result<(), PackageError> {
+        TransactionHistory::open(&self.base_path).attach_snapshot(transaction_id, snapshot)
+    }
+    
+    /// Revert the package changes of a recorded transaction
+    ///
+    /// Newly installed packages are removed and previous versions are
+    /// reinstalled from the pacman package cache. The undo is recorded as a
+    /// transaction of its own.
+    pub fn undo(&self, transaction_id: &Uuid) -> Result<TransactionRecord, PackageError> {
+        let history = TransactionHistory::open(&self.base_path);
+        let records = history.list()?;
+        let record = records
+            .iter()
+            .find(|r| r.id == *transaction_id)
+            .ok_or_else(|| PackageError::OperationFailed(format!("Unknown transaction: {}", transaction_id)))?;
+        if let Some(undo) = records.iter().find(|r| r.reverts == Some(*transaction_id)) {
+            return Err(PackageError::OperationFailed(format!(
+                "Transaction {} was already undone by {}",
+                transaction_id, undo.id
+            )));
+        }
         
-        for pkg in &pkg_list.packages {
-            match pkg.source.as_deref() {
-                Some("aur") | None => aur_pkgs.push(pkg),
-                _ => official_pkgs.push(pkg),
+        // Previous versions must still be in the package cache
+        let cache = PackageCache::new(self.base_path.join(PACMAN_CACHE_DIR))?;
+        let mut restore = Vec::new();
+        let mut missing = Vec::new();
+        for change in &record.changes {
+            if let Some(version) = &change.from {
+                match cache.lookup(&change.name, Some(version))?.into_iter().next() {
+                    Some(pkg) => restore.push(pkg.path),
+                    None => missing.push(format!("{}-{}", change.name, version)),
+                }
             }
         }
+        if !missing.is_empty() {
+            return Err(PackageError::OperationFailed(format!(
+                "Cannot undo {}: not in package cache: {}",
+                transaction_id,
+                missing.join(", ")
+            )));
+        }
+        
+        let remove: Vec<&str> = record.changes
+            .iter()
+            .filter(|c| c.from.is_none())
+            .map(|c| c.name.as_str())
+            .collect();
+        
+        let before = read_local_db(&self.base_path).ok();
+        if !restore.is_empty() {
+            self.install_package_files(&restore)?;
+        }
+        if !remove.is_empty() {
+            let mut args = vec!["-R", "--noconfirm"];
+            args.extend(remove);
+            self.run_command("pacman", &args)?;
+        }
+        if self.dry_run.is_active() {
+            // Nothing changed, so there is no difference to record
+            return Ok(TransactionRecord {
+                reverts: Some(*transaction_id),
+                ..TransactionRecord::from_diff(Uuid::new_v4(), "undo", &PackageDiff::default())
+            });
+        }
+        
+        self.record_transaction(Uuid::new_v4(), "undo", before, Some(*transaction_id))?
+            .ok_or_else(|| PackageError::OperationFailed("Package database unavailable".to_string()))
+    }
+    
+    /// Append a transaction to the history, diffing the package database
+    ///
+    /// Nothing is recorded when the package database could not be read
+    /// before the transaction.
+    fn record_transaction(
+        &self,
+        id: Uuid,
+        action: &str,
+        before: Option<Vec<InstalledPackage>>,
+        reverts: Option<Uuid>,
+    ) -> Result<Option<TransactionRecord>, PackageError> {
+        let Some(before) = before else {
+            log::warn!("Package database not readable, transaction {} not recorded", id);
+            return Ok(None);
+        };
+        let after = read_local_db(&self.base_path)?;
+        
+        let mut record = TransactionRecord::from_diff(id, action, &PackageDiff::between(&before, &after));
+        record.snapshots = self.snapshots.clone();
+        record.reverts = reverts;
+        TransactionHistory::open(&self.base_path).append(&record)?;
+        
+        Ok(Some(record))
+    }
+    
+    /// Download the packages of a list without installing them
+    fn download_list(&self, pkg_list: &PackageList) -> Result<(), PackageError> {
+        let (aur_pkgs, official_pkgs): (Vec<&PackageSpec>, Vec<&PackageSpec>) = pkg_list
+            .packages
+            .iter()
+            .partition(|p| matches!(p.source.as_deref(), Some("aur") | None));
         
-        // Install official packages
         if !official_pkgs.is_empty() {
             self.install_official_packages(&official_pkgs)?;
         }
-        
-        // Install AUR packages
         if !aur_pkgs.is_empty() {
             self.install_aur_packages(&aur_pkgs)?;
         }
         
-        if let Some(cmds) = &pkg_list.post_install {
-            self.run_commands(cmds, "post-install")?;
+        if !pkg_list.flatpak.is_empty() {
+            self.flatpak_backend(&pkg_list.flatpak).apply(&pkg_list.flatpak)?;
+        }
+        
+        if let Some(cache) = &self.warm_cache {
+            cache.update_repo()?;
         }
         
         Ok(())
     }
     
+    /// Flatpak backend matching this manager and a list's `[flatpak]` table
+    fn flatpak_backend(&self, config: &FlatpakConfig) -> FlatpakBackend {
+        FlatpakBackend::new()
+            .user(config.user)
+            .download_only(self.download_only)
+            .verbose(self.verbose)
+    }
+    
+    /// Run the per-package hooks of a batch for the given phase
+    fn run_package_hooks(
+        &self,
+        hooks: &HookRunner,
+        packages: &[&PackageSpec],
+        phase: HookPhase,
+        ctx: &TransactionContext,
+    ) -> Result<(), PackageError> {
+        for pkg in packages {
+            let commands = match phase {
+                HookPhase::PreInstall => &pkg.hooks.pre_install,
+                _ => &pkg.hooks.post_install,
+            };
+            hooks.run_package_hooks(commands, phase, ctx, &pkg.name, pkg.version.as_deref())?;
+        }
+        Ok(())
+    }
+    
     /// Install official repository packages
     fn install_official_packages(&self, packages: &[&PackageSpec]) -> Result<(), PackageError> {
         if self.verbose {
-            println!("Installing {} official packages...", packages.len());
+            println!("{} {} official packages...", self.action_verb(), packages.len());
         }
         
-        // Convert package specs to pacman format
-        let pkg_args: Vec<String> = packages.iter()
-            .map(|p| {
-                if let Some(ver) = &p.version {
-                    format!("{} {}", p.name, ver)
-                } else {
-                    p.name.clone()
-                }
-            })
-            .collect();
+        #[cfg(feature = "alpm")]
+        if let Some(backend) = &self.alpm {
+            let mut backend = match self.parallel_downloads {
+                Some(downloads) => backend.clone().with_parallel_downloads(downloads),
+                None => backend.clone(),
+            };
+            if let Some(cache) = &self.warm_cache {
+                backend = backend.with_cache_dir(cache.dir());
+            }
+            if self.download_only {
+                return backend.download(packages);
+            }
+            return backend.install(packages);
+        }
         
-        // Execute pacman command
-        self.run_command("pacman", &["-S", "--noconfirm", "--needed", &pkg_args.join(" ")])?;
+        // The configuration file is deleted when dropped, after pacman exits
+        let config = self.pacman_config()?;
+        let args = self.pacman_install_args(config.as_ref().map(|c| c.path()), packages)?;
+        self.run_command("pacman", &args)?;
         
         Ok(())
     }
     
+    /// pacman configuration for this run, if the system one does not do
+    fn pacman_config(&self) -> Result<Option<tempfile::NamedTempFile>, PackageError> {
+        // Point pacman at the local cache only when installing offline
+        let config = match (&self.offline_cache, self.parallel_downloads) {
+            (Some(cache), Some(downloads)) => Some(download::write_pacman_conf(
+                &cache.offline_pacman_conf(),
+                downloads,
+                cache.dir(),
+            )?),
+            (Some(cache), None) => Some(download::write_temp_conf(&cache.offline_pacman_conf(), cache.dir())?),
+            (None, Some(downloads)) => Some(download::write_pacman_conf(
+                &std::fs::read_to_string(PACMAN_CONF)?,
+                downloads,
+                &std::env::temp_dir(),
+            )?),
+            (None, None) => None,
+        };
+        Ok(config)
+    }
+    
+    /// Build the `pacman -S` argument list, one argument per package
+    fn pacman_install_args(&self, config: Option<&Path>, packages: &[&PackageSpec]) -> Result<Vec<String>, PackageError> {
+        let mut args = Vec::new();
+        if let Some(config) = config {
+            args.push("--config".to_string());
+            args.push(config.to_string_lossy().into_owned());
+        }
+        
+        args.extend(["-S", "--noconfirm"].map(String::from));
+        // Installed packages still belong in a cache being filled
+        if self.download_only {
+            args.push("--downloadonly".to_string());
+        } else {
+            args.push("--needed".to_string());
+        }
+        if let Some(cache) = &self.warm_cache {
+            args.push("--cachedir".to_string());
+            args.push(cache.dir().to_string_lossy().into_owned());
+        }
+        for pkg in packages {
+            args.push(pkg.target()?);
+        }
+        
+        Ok(args)
+    }
+    
     /// Install AUR packages
     fn install_aur_packages(&self, packages: &[&PackageSpec]) -> Result<(), PackageError> {
         if self.verbose {
-            println!("Installing {} AUR packages...", packages.len());
+            println!("{} {} AUR packages...", self.action_verb(), packages.len());
         }
         
-        // Convert package specs to AUR helper format
-        let pkg_args: Vec<String> = packages.iter()
-            .map(|p| {
-                if let Some(ver) = &p.version {
-                    format!("{}@{}", p.name, ver)
-                } else {
-                    p.name.clone()
+        #[cfg(feature = "containers")]
+        if let Some(builder) = &self.aur_builder {
+            let mut built = Vec::new();
+            for pkg in packages {
+                if self.verbose {
+                    println!("Building {} in a sandbox...", pkg.name);
                 }
-            })
-            .collect();
+                built.extend(builder.build(pkg)?);
+            }
+            if self.download_only {
+                // Built packages stay in the builder repository (and the warmed cache)
+                if let Some(cache) = &self.warm_cache {
+                    for file in &built {
+                        cache.store(file)?;
+                    }
+                }
+                return Ok(());
+            }
+            // Locally built packages are unsigned; they never left the sandbox
+            return self.install_package_files(&built);
+        }
         
         // Use paru as AUR helper
-        self.run_command("paru", &["-S", "--noconfirm", "--needed", &pkg_args.join(" ")])?;
+        let mut args = paru_install_args(packages)?;
+        if self.download_only {
+            args.retain(|arg| arg != "--needed");
+            args.insert(1, "--downloadonly".to_string());
+        }
+        self.run_command("paru", &args)?;
         
         Ok(())
     }
     
-    /// Run system commands with error handling
-    fn run_commands(&self, commands: &[String], context: &str) -> Result<(), PackageError> {
-        for cmd in commands {
-            if self.verbose {
-                println!("Running {} command: {}", context, cmd);
-            }
-            
-            self.run_command("sh", &["-c", cmd])
-                .map_err(|e| PackageError::OperationFailed(
-                    format!("{} command failed: {} - {}", context, cmd, e)
-                ))?;
-        }
-        Ok(())
+    /// Progress verb for the current mode
+    fn action_verb(&self) -> &'static str {
+        if self.download_only { "Downloading" } else { "Installing" }
     }
     
     /// Execute a system command
-    fn run_command(&self, cmd: &str, args: &[&str]) -> Result<(), PackageError> {
+    fn run_command<S: AsRef<str>>(&self, cmd: &str, args: &[S]) -> Result<(), PackageError> {
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+        if self.dry_run.skip(Action::run(format!("{} {}", cmd, args.join(" ")))) {
+            return Ok(());
+        }
         let output = std::process::Command::new(cmd)
-            .args(args)
+            .args(&args)
             .output()
             .map_err(|e| PackageError::Io(e))?;
             
@@ -205,6 +779,15 @@ impl PackageManager {
     }
 }
 
+/// Build the `paru -S` argument list, one argument per package
+fn paru_install_args(packages: &[&PackageSpec]) -> Result<Vec<String>, PackageError> {
+    let mut args: Vec<String> = ["-S", "--noconfirm", "--needed", "--aur"].map(String::from).to_vec();
+    for pkg in packages {
+        args.push(pkg.target()?);
+    }
+    Ok(args)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -218,4 +801,99 @@ mod tests {
         temp_dir.close()?;
         Ok(())
     }
+    
+    #[test]
+    fn test_parse_package_list_hooks() {
+        let content = r#"
+            [hooks]
+            pre_transaction = ["echo start"]
+            timeout = 30
+
+            [[packages]]
+            name = "nginx"
+            source = "official"
+
+            [packages.hooks]
+            post_install = ["systemctl enable nginx"]
+        "#;
+        
+        let list: PackageList = toml::from_str(content).unwrap();
+        assert_eq!(list.hooks.timeout, Some(30));
+        assert_eq!(list.hooks.pre_transaction, vec!["echo start".to_string()]);
+        assert_eq!(list.packages[0].hooks.post_install, vec!["systemctl enable nginx".to_string()]);
+        assert!(list.packages[0].hooks.pre_install.is_empty());
+    }
+    
+    #[test]
+    fn test_pacman_command_line() {
+        let pm = PackageManager::new("/");
+        let mut vim = PackageSpec::new("vim");
+        vim.version = Some(">=9.0".to_string());
+        let git = PackageSpec::new("git");
+        
+        let args = pm.pacman_install_args(None, &[&vim, &git]).unwrap();
+        assert_eq!(args, vec!["-S", "--noconfirm", "--needed", "vim>=9.0", "git"]);
+    }
+    
+    #[test]
+    fn test_download_only_command_line() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let cache = PackageCache::new(dir.path())?;
+        let pm = PackageManager::new("/").warm_cache(cache);
+        let vim = PackageSpec::new("vim");
+        
+        let args = pm.pacman_install_args(None, &[&vim])?;
+        assert_eq!(
+            args,
+            vec![
+                "-S".to_string(),
+                "--noconfirm".to_string(),
+                "--downloadonly".to_string(),
+                "--cachedir".to_string(),
+                dir.path().to_string_lossy().into_owned(),
+                "vim".to_string(),
+            ]
+        );
+        Ok(())
+    }
+    
+    #[test]
+    fn test_parallel_downloads_config() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let pm = PackageManager::new("/")
+            .offline(PackageCache::new(dir.path())?)
+            .parallel_downloads(8);
+        
+        let config = pm.pacman_config()?.expect("offline configuration");
+        let args = pm.pacman_install_args(Some(config.path()), &[&PackageSpec::new("vim")])?;
+        assert_eq!(args[0], "--config");
+        let conf = std::fs::read_to_string(&args[1])?;
+        assert!(conf.contains("ParallelDownloads = 8"));
+        assert!(conf.contains(&format!("Server = file://{}", dir.path().display())));
+        
+        // Removed once pacman is done with it
+        drop(config);
+        assert!(!Path::new(&args[1]).exists());
+        Ok(())
+    }
+    
+    #[test]
+    fn test_paru_command_line() {
+        let mut yay = PackageSpec::new("yay-bin");
+        yay.version = Some("12.3.5-1".to_string());
+        let mut old = PackageSpec::new("old-tool");
+        old.version = Some("<2".to_string());
+        
+        let args = paru_install_args(&[&yay, &old]).unwrap();
+        assert_eq!(args, vec!["-S", "--noconfirm", "--needed", "--aur", "yay-bin=12.3.5-1", "old-tool<2"]);
+    }
+    
+    #[test]
+    fn test_invalid_constraint_is_rejected() {
+        let pm = PackageManager::new("/");
+        let mut bad = PackageSpec::new("vim");
+        bad.version = Some(">= 1; reboot".to_string());
+        
+        assert!(matches!(pm.pacman_install_args(None, &[&bad]), Err(PackageError::ParseError(_))));
+    }
 }
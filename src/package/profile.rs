@@ -0,0 +1,258 @@
+//! Package groups and host profiles
+//!
+//! A `PackageList` may define named groups (`base`, `desktop-kde`,
+//! `dev-rust`), host profiles composed of groups, and include other package
+//! list files. This module loads a list together with its includes and
+//! resolves a selection of groups or profiles into a flat package set.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{PackageError, PackageList, PackageSpec};
+
+/// A named, reusable set of packages
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PackageGroup {
+    /// Human readable description
+    pub description: Option<String>,
+
+    /// Other groups this group builds on
+    #[serde(default)]
+    pub includes: Vec<String>,
+
+    /// Packages in this group
+    #[serde(default)]
+    pub packages: Vec<PackageSpec>,
+}
+
+/// A machine profile composed of package groups
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostProfile {
+    /// Human readable description
+    pub description: Option<String>,
+
+    /// Groups installed on hosts using this profile
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// Extra packages specific to this profile
+    #[serde(default)]
+    pub packages: Vec<PackageSpec>,
+}
+
+/// Load a package list file and merge all files it includes
+///
+/// Include paths are relative to the including file. Included files are
+/// merged first, so definitions in the including file take precedence.
+pub fn load_package_list<P: AsRef<Path>>(path: P) -> Result<PackageList, PackageError> {
+    let mut visiting = HashSet::new();
+    load_recursive(path.as_ref(), &mut visiting)
+}
+
+fn load_recursive(path: &Path, visiting: &mut HashSet<PathBuf>) -> Result<PackageList, PackageError> {
+    let canonical = fs::canonicalize(path)?;
+    if !visiting.insert(canonical.clone()) {
+        return Err(PackageError::ParseError(format!(
+            "Include cycle detected at {}",
+            path.display()
+        )));
+    }
+
+    let content = fs::read_to_string(&canonical)?;
+    let list: PackageList = toml::from_str(&content)
+        .map_err(|e| PackageError::ParseError(format!("{}: {}", path.display(), e)))?;
+
+    let base_dir = canonical.parent().unwrap_or_else(|| Path::new("/")).to_path_buf();
+    let mut merged = PackageList::default();
+    for include in &list.include {
+        let included = load_recursive(&base_dir.join(include), visiting)?;
+        merged.merge(included);
+    }
+    merged.merge(list);
+    merged.include.clear();
+
+    visiting.remove(&canonical);
+    Ok(merged)
+}
+
+impl PackageList {
+    /// Merge another list into this one; the other list wins on conflicts
+    pub fn merge(&mut self, other: PackageList) {
+        self.packages = merge_specs(std::mem::take(&mut self.packages), other.packages);
+        self.groups.extend(other.groups);
+        self.profiles.extend(other.profiles);
+        self.include.extend(other.include);
+        self.hooks.pre_transaction.extend(other.hooks.pre_transaction);
+        self.hooks.post_transaction.extend(other.hooks.post_transaction);
+        self.hooks.timeout = other.hooks.timeout.or(self.hooks.timeout);
+        self.flatpak.merge(other.flatpak);
+    }
+
+    /// Resolve groups and profiles into a flat package list
+    ///
+    /// Each name is looked up as a profile first and as a group second. The
+    /// top-level `packages` are always included. Later definitions of the same
+    /// package override earlier ones.
+    pub fn resolve(&self, selection: &[&str]) -> Result<Vec<PackageSpec>, PackageError> {
+        let mut packages = self.packages.clone();
+
+        for name in selection {
+            if let Some(profile) = self.profiles.get(*name) {
+                for group in &profile.groups {
+                    packages = merge_specs(packages, self.expand_group(group, &mut Vec::new())?);
+                }
+                packages = merge_specs(packages, profile.packages.clone());
+            } else {
+                packages = merge_specs(packages, self.expand_group(name, &mut Vec::new())?);
+            }
+        }
+
+        Ok(packages)
+    }
+
+    /// Produce a list containing only the resolved packages of a selection
+    pub fn select(&self, selection: &[&str]) -> Result<PackageList, PackageError> {
+        Ok(PackageList {
+            packages: self.resolve(selection)?,
+            hooks: self.hooks.clone(),
+            flatpak: self.flatpak.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn expand_group(&self, name: &str, stack: &mut Vec<String>) -> Result<Vec<PackageSpec>, PackageError> {
+        if stack.iter().any(|n| n == name) {
+            stack.push(name.to_string());
+            return Err(PackageError::ParseError(format!(
+                "Group include cycle: {}",
+                stack.join(" -> ")
+            )));
+        }
+
+        let group = self
+            .groups
+            .get(name)
+            .ok_or_else(|| PackageError::ParseError(format!("Unknown package group or profile: {}", name)))?;
+
+        stack.push(name.to_string());
+        let mut packages = Vec::new();
+        for include in &group.includes {
+            packages = merge_specs(packages, self.expand_group(include, stack)?);
+        }
+        stack.pop();
+
+        Ok(merge_specs(packages, group.packages.clone()))
+    }
+}
+
+/// Concatenate spec lists, replacing earlier specs with later ones of the same name
+fn merge_specs(mut base: Vec<PackageSpec>, overrides: Vec<PackageSpec>) -> Vec<PackageSpec> {
+    for spec in overrides {
+        match base.iter_mut().find(|s| s.name == spec.name) {
+            Some(existing) => *existing = spec,
+            None => base.push(spec),
+        }
+    }
+    base
+}
+
+/// Names of all groups and profiles defined in a list
+pub fn available_selections(list: &PackageList) -> BTreeMap<String, Option<String>> {
+    let mut names = BTreeMap::new();
+    for (name, group) in &list.groups {
+        names.insert(name.clone(), group.description.clone());
+    }
+    for (name, profile) in &list.profiles {
+        names.insert(name.clone(), profile.description.clone());
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const BASE: &str = r#"
+        [groups.base]
+        packages = [{ name = "linux" }, { name = "vim" }]
+
+        [groups.dev-rust]
+        includes = ["base"]
+        packages = [{ name = "rustup" }, { name = "vim", version = ">=9.0" }]
+    "#;
+
+    const HOST: &str = r#"
+        include = ["base.toml"]
+
+        [[packages]]
+        name = "htop"
+
+        [groups.desktop-kde]
+        packages = [{ name = "plasma-meta" }]
+
+        [profiles.workstation]
+        groups = ["dev-rust", "desktop-kde"]
+        packages = [{ name = "firefox" }]
+    "#;
+
+    #[test]
+    fn test_includes_and_profiles() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("base.toml"), BASE)?;
+        fs::write(dir.path().join("host.toml"), HOST)?;
+
+        let list = load_package_list(dir.path().join("host.toml"))?;
+        assert!(list.groups.contains_key("base"));
+
+        let resolved = list.resolve(&["workstation"])?;
+        let names: Vec<&str> = resolved.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["htop", "linux", "vim", "rustup", "plasma-meta", "firefox"]);
+
+        // The dev-rust override of vim wins over the base definition
+        let vim = resolved.iter().find(|p| p.name == "vim").unwrap();
+        assert_eq!(vim.version.as_deref(), Some(">=9.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_later_hook_timeout_wins() {
+        let mut list = PackageList::default();
+        list.merge(toml::from_str("[hooks]\ntimeout = 600").unwrap());
+        list.merge(toml::from_str("[hooks]\ntimeout = 60").unwrap());
+        assert_eq!(list.hooks.timeout, Some(60));
+        // Layers without a timeout keep the one set before
+        list.merge(PackageList::default());
+        assert_eq!(list.hooks.timeout, Some(60));
+    }
+
+    #[test]
+    fn test_group_cycle_is_rejected() {
+        let list: PackageList = toml::from_str(
+            r#"
+            [groups.a]
+            includes = ["b"]
+            [groups.b]
+            includes = ["a"]
+            "#,
+        )
+        .unwrap();
+
+        let err = list.resolve(&["a"]).unwrap_err();
+        assert!(err.to_string().contains("a -> b -> a"));
+        assert!(list.resolve(&["missing"]).is_err());
+    }
+
+    #[test]
+    fn test_include_cycle_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]")?;
+        fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]")?;
+
+        assert!(load_package_list(dir.path().join("a.toml")).is_err());
+        Ok(())
+    }
+}
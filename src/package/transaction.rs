@@ -0,0 +1,203 @@
+//! Package installs as journaled transactions
+//!
+//! [`PackageManager::install_list`](super::PackageManager::install_list) runs
+//! a [`PackageInstall`] through the [`Executor`](crate::transaction::Executor):
+//! repository and AUR packages are one step, recorded in the transaction
+//! history under an ID fixed when the install is planned, and Flatpak
+//! applications another. A failed install is rolled back by undoing the
+//! recorded transaction and removing the Flatpak applications it added, so
+//! half-installed batches no longer stay behind.
+
+use uuid::Uuid;
+
+use crate::transaction::{Step, Transaction};
+
+use super::history::TransactionHistory;
+use super::hooks::{HookPhase, HookRunner, TransactionContext};
+use super::localdb::read_local_db;
+use super::{PackageError, PackageList, PackageManager, PackageSpec};
+
+/// Kind of package install transactions
+pub const KIND: &str = "package-install";
+
+/// Repository, AUR and Flatpak installs of a package list
+pub struct PackageInstall<'a> {
+    manager: &'a PackageManager,
+    list: &'a PackageList,
+    hooks: &'a HookRunner,
+    ctx: &'a TransactionContext,
+}
+
+impl<'a> PackageInstall<'a> {
+    /// Install `list` with `manager`, running package hooks from `hooks`
+    ///
+    /// The packages are recorded in the history under `ctx.id`.
+    pub fn new(
+        manager: &'a PackageManager,
+        list: &'a PackageList,
+        hooks: &'a HookRunner,
+        ctx: &'a TransactionContext,
+    ) -> Self {
+        Self { manager, list, hooks, ctx }
+    }
+
+    /// Install the repository and AUR batches, running their package hooks
+    fn install_packages(&self) -> Result<(), PackageError> {
+        let (aur_pkgs, official_pkgs): (Vec<&PackageSpec>, Vec<&PackageSpec>) = self
+            .list
+            .packages
+            .iter()
+            .partition(|p| matches!(p.source.as_deref(), Some("aur") | None));
+
+        if !official_pkgs.is_empty() {
+            self.manager.run_package_hooks(self.hooks, &official_pkgs, HookPhase::PreInstall, self.ctx)?;
+            self.manager.install_official_packages(&official_pkgs)?;
+            self.manager.run_package_hooks(self.hooks, &official_pkgs, HookPhase::PostInstall, self.ctx)?;
+        }
+        if !aur_pkgs.is_empty() {
+            self.manager.run_package_hooks(self.hooks, &aur_pkgs, HookPhase::PreInstall, self.ctx)?;
+            self.manager.install_aur_packages(&aur_pkgs)?;
+            self.manager.run_package_hooks(self.hooks, &aur_pkgs, HookPhase::PostInstall, self.ctx)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transaction for PackageInstall<'_> {
+    type Error = PackageError;
+
+    fn kind(&self) -> &str {
+        KIND
+    }
+
+    fn plan(&mut self) -> Result<Vec<Step>, PackageError> {
+        for pkg in &self.list.packages {
+            pkg.constraint()?;
+        }
+
+        let mut steps = Vec::new();
+        if !self.list.packages.is_empty() {
+            let names: Vec<&str> = self.list.packages.iter().map(|p| p.name.as_str()).collect();
+            steps.push(
+                Step::new("packages")
+                    .with("transaction", self.ctx.id)
+                    .with("packages", names.join(" ")),
+            );
+        }
+        if !self.list.flatpak.is_empty() {
+            steps.push(Step::new("flatpak"));
+        }
+        Ok(steps)
+    }
+
+    fn execute(&mut self, step: &mut Step) -> Result<(), PackageError> {
+        match step.name.as_str() {
+            "packages" => {
+                let before = read_local_db(&self.manager.base_path).ok();
+                let result = self.install_packages();
+                // Recorded even when a batch failed, so rollback can undo what got installed
+                let recorded = self.manager.record_transaction(self.ctx.id, &self.ctx.action, before, None);
+                result?;
+                recorded.map(|_| ())
+            }
+            "flatpak" => {
+                let backend = self.manager.flatpak_backend(&self.list.flatpak);
+                let installed = backend.installed()?;
+                let added: Vec<&str> = self
+                    .list
+                    .flatpak
+                    .apps
+                    .iter()
+                    .map(|app| app.id.as_str())
+                    .filter(|id| !installed.iter().any(|app| app.id == *id))
+                    .collect();
+                step.set("apps", added.join(","));
+                backend.apply(&self.list.flatpak)
+            }
+            other => Err(PackageError::OperationFailed(format!("Unknown install step: {}", other))),
+        }
+    }
+
+    /// Check the version constraints of the listed packages
+    ///
+    /// Names missing from the package database are skipped: they may be
+    /// groups or virtual packages satisfied by another package.
+    fn verify(&mut self) -> Result<(), PackageError> {
+        let Ok(installed) = read_local_db(&self.manager.base_path) else {
+            return Ok(());
+        };
+        for pkg in &self.list.packages {
+            let constraint = pkg.constraint()?;
+            if let Some(found) = installed.iter().find(|p| p.name == pkg.name) {
+                if !constraint.matches(&found.version) {
+                    return Err(PackageError::OperationFailed(format!(
+                        "{}-{} installed, {}{} requested",
+                        found.name, found.version, pkg.name, constraint
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self, step: &Step) -> Result<(), PackageError> {
+        match step.name.as_str() {
+            "packages" => {
+                let Some(id) = step.get("transaction").and_then(|id| Uuid::parse_str(id).ok()) else {
+                    return Ok(());
+                };
+                let history = TransactionHistory::open(&self.manager.base_path).list()?;
+                let recorded = history.iter().any(|r| r.id == id);
+                let undone = history.iter().any(|r| r.reverts == Some(id));
+                if recorded && !undone {
+                    self.manager.undo(&id)?;
+                }
+                Ok(())
+            }
+            "flatpak" => {
+                let apps: Vec<&str> = step
+                    .get("apps")
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter(|id| !id.is_empty())
+                    .collect();
+                if apps.is_empty() {
+                    return Ok(());
+                }
+                let backend = self.manager.flatpak_backend(&self.list.flatpak);
+                let installed = backend.installed()?;
+                let remove: Vec<&str> = apps
+                    .into_iter()
+                    .filter(|id| installed.iter().any(|app| app.id == *id))
+                    .collect();
+                if remove.is_empty() {
+                    return Ok(());
+                }
+                backend.remove(&remove)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_plan_fixes_history_id() {
+        let manager = PackageManager::new("/");
+        let mut list = PackageList::default();
+        list.packages.push(PackageSpec::new("vim"));
+        let hooks = HookRunner::new(Duration::from_secs(1));
+        let ctx = TransactionContext::new("install", vec!["vim".to_string()]);
+
+        let steps = PackageInstall::new(&manager, &list, &hooks, &ctx).plan().unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].get("transaction"), Some(ctx.id.to_string().as_str()));
+
+        list.packages[0].version = Some(">=".to_string());
+        assert!(PackageInstall::new(&manager, &list, &hooks, &ctx).plan().is_err());
+    }
+}
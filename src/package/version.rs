@@ -0,0 +1,273 @@
+//! Package version constraints
+//!
+//! Parses the constraint strings used in `PackageSpec.version` (`>=1.2.3`,
+//! `=1.0-2`, `<2`) and renders them in the dependency syntax understood by
+//! pacman, paru and libalpm (`name>=1.2.3`). Versions are compared with the
+//! same rules as pacman's `vercmp`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::PackageError;
+
+/// A version constraint on a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionConstraint {
+    /// Any version
+    Any,
+    /// Exactly this version
+    Exact(String),
+    /// This version or newer
+    AtLeast(String),
+    /// Strictly newer than this version
+    GreaterThan(String),
+    /// This version or older
+    AtMost(String),
+    /// Strictly older than this version
+    LessThan(String),
+}
+
+impl VersionConstraint {
+    /// Parse a constraint string
+    ///
+    /// A bare version (`1.2.3`) is treated as an exact match.
+    pub fn parse(constraint: &str) -> Result<Self, PackageError> {
+        let constraint = constraint.trim();
+        if constraint.is_empty() || constraint == "*" {
+            return Ok(Self::Any);
+        }
+
+        let (ctor, version): (fn(String) -> Self, &str) = if let Some(v) = constraint.strip_prefix(">=") {
+            (Self::AtLeast, v)
+        } else if let Some(v) = constraint.strip_prefix("<=") {
+            (Self::AtMost, v)
+        } else if let Some(v) = constraint.strip_prefix('>') {
+            (Self::GreaterThan, v)
+        } else if let Some(v) = constraint.strip_prefix('<') {
+            (Self::LessThan, v)
+        } else if let Some(v) = constraint.strip_prefix('=') {
+            (Self::Exact, v.trim_start_matches('='))
+        } else {
+            (Self::Exact, constraint)
+        };
+
+        let version = version.trim();
+        if version.is_empty() || !version.chars().all(is_version_char) {
+            return Err(PackageError::ParseError(format!("Invalid version constraint: '{}'", constraint)));
+        }
+
+        Ok(ctor(version.to_string()))
+    }
+
+    /// Comparison operator in pacman dependency syntax
+    pub fn operator(&self) -> &'static str {
+        match self {
+            Self::Any => "",
+            Self::Exact(_) => "=",
+            Self::AtLeast(_) => ">=",
+            Self::GreaterThan(_) => ">",
+            Self::AtMost(_) => "<=",
+            Self::LessThan(_) => "<",
+        }
+    }
+
+    /// The version the constraint refers to, if any
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            Self::Any => None,
+            Self::Exact(v) | Self::AtLeast(v) | Self::GreaterThan(v) | Self::AtMost(v) | Self::LessThan(v) => Some(v),
+        }
+    }
+
+    /// Whether `version` satisfies the constraint
+    pub fn matches(&self, version: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact(v) => vercmp(version, v) == Ordering::Equal,
+            Self::AtLeast(v) => vercmp(version, v) != Ordering::Less,
+            Self::GreaterThan(v) => vercmp(version, v) == Ordering::Greater,
+            Self::AtMost(v) => vercmp(version, v) != Ordering::Greater,
+            Self::LessThan(v) => vercmp(version, v) == Ordering::Less,
+        }
+    }
+
+    /// Render a dependency target (`name>=1.2.3`) for pacman, paru and libalpm
+    pub fn target(&self, name: &str) -> String {
+        match self.version() {
+            Some(v) => format!("{}{}{}", name, self.operator(), v),
+            None => name.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version() {
+            Some(v) => write!(f, "{}{}", self.operator(), v),
+            None => write!(f, "*"),
+        }
+    }
+}
+
+/// Compare two package versions (`epoch:pkgver-pkgrel`) like pacman's `vercmp`
+pub fn vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let (epoch_a, ver_a, rel_a) = split_evr(a);
+    let (epoch_b, ver_b, rel_b) = split_evr(b);
+
+    rpmvercmp(epoch_a, epoch_b)
+        .then_with(|| rpmvercmp(ver_a, ver_b))
+        .then_with(|| match (rel_a, rel_b) {
+            (Some(ra), Some(rb)) => rpmvercmp(ra, rb),
+            _ => Ordering::Equal,
+        })
+}
+
+/// Split a version into epoch, version and optional release
+fn split_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, rest) = match evr.split_once(':') {
+        Some((e, rest)) if !e.is_empty() && e.bytes().all(|b| b.is_ascii_digit()) => (e, rest),
+        _ => ("0", evr),
+    };
+
+    match rest.rsplit_once('-') {
+        Some((ver, rel)) => (epoch, ver, Some(rel)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Segment-wise comparison of version strings (rpm/alpm algorithm)
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        let (start_i, start_j) = (i, j);
+        while i < a.len() && !a[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        while j < b.len() && !b[j].is_ascii_alphanumeric() {
+            j += 1;
+        }
+
+        if i >= a.len() || j >= b.len() {
+            break;
+        }
+
+        // More separators means a newer version
+        if i - start_i != j - start_j {
+            return (i - start_i).cmp(&(j - start_j));
+        }
+
+        let numeric = a[i].is_ascii_digit();
+        let take = |s: &[u8], mut k: usize| {
+            let start = k;
+            while k < s.len() && (if numeric { s[k].is_ascii_digit() } else { s[k].is_ascii_alphabetic() }) {
+                k += 1;
+            }
+            (start, k)
+        };
+        let (sa, ea) = take(a, i);
+        let (sb, eb) = take(b, j);
+        i = ea;
+        j = eb;
+
+        if sb == eb {
+            // Numeric segments are newer than alpha segments
+            return if numeric { Ordering::Greater } else { Ordering::Less };
+        }
+
+        let (seg_a, seg_b) = (&a[sa..ea], &b[sb..eb]);
+        let ord = if numeric {
+            let trim = |s: &[u8]| {
+                let zeros = s.iter().take_while(|c| **c == b'0').count();
+                s[zeros..].to_vec()
+            };
+            let (na, nb) = (trim(seg_a), trim(seg_b));
+            na.len().cmp(&nb.len()).then_with(|| na.cmp(&nb))
+        } else {
+            seg_a.cmp(seg_b)
+        };
+
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    let (rest_a, rest_b) = (&a[i.min(a.len())..], &b[j.min(b.len())..]);
+    if rest_a.is_empty() && rest_b.is_empty() {
+        return Ordering::Equal;
+    }
+
+    // A remaining alpha segment never beats an empty string
+    let a_alpha = rest_a.first().is_some_and(u8::is_ascii_alphabetic);
+    let b_alpha = rest_b.first().is_some_and(u8::is_ascii_alphabetic);
+    if (rest_a.is_empty() && !b_alpha) || a_alpha {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+/// Characters allowed in pacman versions (`epoch:pkgver-pkgrel`)
+fn is_version_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '+' | '-' | ':' | '~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_constraints() {
+        assert_eq!(VersionConstraint::parse("").unwrap(), VersionConstraint::Any);
+        assert_eq!(VersionConstraint::parse(">=1.2.3").unwrap(), VersionConstraint::AtLeast("1.2.3".into()));
+        assert_eq!(VersionConstraint::parse("> 2").unwrap(), VersionConstraint::GreaterThan("2".into()));
+        assert_eq!(VersionConstraint::parse("<3.0").unwrap(), VersionConstraint::LessThan("3.0".into()));
+        assert_eq!(VersionConstraint::parse("==1:1.0-2").unwrap(), VersionConstraint::Exact("1:1.0-2".into()));
+        assert_eq!(VersionConstraint::parse("9.0").unwrap(), VersionConstraint::Exact("9.0".into()));
+    }
+
+    #[test]
+    fn test_invalid_constraints() {
+        assert!(VersionConstraint::parse(">=").is_err());
+        assert!(VersionConstraint::parse("1.0 || 2.0").is_err());
+        assert!(VersionConstraint::parse(">=1.0; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn test_constraint_matches() {
+        assert!(VersionConstraint::Any.matches("0.1"));
+        assert!(VersionConstraint::AtLeast("1.2".into()).matches("1.10"));
+        assert!(!VersionConstraint::LessThan("2".into()).matches("2.0"));
+        assert!(VersionConstraint::Exact("1.0".into()).matches("1.0-1"));
+    }
+
+    #[test]
+    fn test_vercmp() {
+        assert_eq!(vercmp("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(vercmp("1.0-1", "1.0-2"), Ordering::Less);
+        assert_eq!(vercmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(vercmp("1:1.0", "2.0"), Ordering::Greater);
+        assert_eq!(vercmp("1.0a", "1.0"), Ordering::Less);
+        assert_eq!(vercmp("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(vercmp("1.0.a", "1.0.1"), Ordering::Less);
+        assert_eq!(vercmp("1.0..1", "1.0.1"), Ordering::Greater);
+        assert_eq!(vercmp("6.6.1.arch1-1", "6.6.10.arch1-1"), Ordering::Less);
+        assert_eq!(vercmp("1.0-1", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(VersionConstraint::parse(">=1.2").unwrap().target("vim"), "vim>=1.2");
+        assert_eq!(VersionConstraint::parse("1.2").unwrap().target("vim"), "vim=1.2");
+        assert_eq!(VersionConstraint::Any.target("vim"), "vim");
+    }
+}
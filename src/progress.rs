@@ -0,0 +1,444 @@
+//! Progress of long-running operations
+//!
+//! Kernel builds, backups, image pulls and installations report what they
+//! are doing through a [`Progress`], without knowing who is watching. The
+//! command-line tools render it with [`TerminalProgress`], or print it as
+//! JSON lines with [`JsonLines`] when a machine format is selected; the
+//! daemon hands it to API clients through an [`EventForwarder`].
+//!
+//! Operations usually drive a [`Task`] rather than emitting events by hand.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Something an operation reports about one of its tasks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ProgressEvent {
+    /// A task started; `total` steps are expected if known
+    Started {
+        /// Task name
+        task: String,
+        /// Expected number of steps
+        total: Option<u64>,
+    },
+    /// A task completed more steps
+    Advanced {
+        /// Task name
+        task: String,
+        /// Steps completed so far
+        done: u64,
+        /// Expected number of steps, if known
+        total: Option<u64>,
+        /// What the task is working on
+        message: Option<String>,
+    },
+    /// A task reports what it is doing without advancing
+    Message {
+        /// Task name
+        task: String,
+        /// What the task is doing
+        message: String,
+    },
+    /// A task finished successfully
+    Finished {
+        /// Task name
+        task: String,
+        /// Summary of the result
+        message: Option<String>,
+    },
+    /// A task failed
+    Failed {
+        /// Task name
+        task: String,
+        /// Why it failed
+        error: String,
+    },
+}
+
+impl ProgressEvent {
+    /// Name of the task the event is about
+    pub fn task(&self) -> &str {
+        match self {
+            ProgressEvent::Started { task, .. }
+            | ProgressEvent::Advanced { task, .. }
+            | ProgressEvent::Message { task, .. }
+            | ProgressEvent::Finished { task, .. }
+            | ProgressEvent::Failed { task, .. } => task,
+        }
+    }
+}
+
+impl Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::Started { task, .. } => write!(f, "{}: started", task),
+            ProgressEvent::Advanced {
+                task,
+                done,
+                total,
+                message,
+            } => {
+                match total {
+                    Some(total) => write!(f, "{}: {}/{}", task, done, total)?,
+                    None => write!(f, "{}: {}", task, done)?,
+                }
+                match message {
+                    Some(message) => write!(f, " {}", message),
+                    None => Ok(()),
+                }
+            }
+            ProgressEvent::Message { task, message } => write!(f, "{}: {}", task, message),
+            ProgressEvent::Finished { task, message: Some(message) } => write!(f, "{}: {}", task, message),
+            ProgressEvent::Finished { task, message: None } => write!(f, "{}: done", task),
+            ProgressEvent::Failed { task, error } => write!(f, "{}: failed: {}", task, error),
+        }
+    }
+}
+
+/// Receives the progress of an operation
+///
+/// Reporting must not fail the operation, so sinks swallow their own errors.
+pub trait Progress: fmt::Debug + Send + Sync {
+    /// Handle one event
+    fn report(&self, event: ProgressEvent);
+}
+
+/// Discards all progress
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Progress for Silent {
+    fn report(&self, _event: ProgressEvent) {}
+}
+
+/// A task reporting to a [`Progress`]
+///
+/// A task that is dropped without [`finish`](Task::finish) or
+/// [`fail`](Task::fail), for example when `?` returns early, reports that
+/// it failed.
+#[derive(Debug)]
+pub struct Task<'a> {
+    progress: &'a dyn Progress,
+    name: String,
+    done: AtomicU64,
+    /// Zero while the total is unknown
+    total: AtomicU64,
+    ended: AtomicBool,
+}
+
+impl<'a> Task<'a> {
+    /// Start a task of `total` steps, or of an unknown number
+    pub fn start(progress: &'a dyn Progress, name: impl Into<String>, total: Option<u64>) -> Self {
+        let name = name.into();
+        progress.report(ProgressEvent::Started {
+            task: name.clone(),
+            total,
+        });
+        Self {
+            progress,
+            name,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(total.unwrap_or(0)),
+            ended: AtomicBool::new(false),
+        }
+    }
+
+    /// Task name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Expected number of steps, if known
+    pub fn total(&self) -> Option<u64> {
+        match self.total.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(total),
+        }
+    }
+
+    /// Change the expected number of steps
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Complete one step, working on `message` next
+    pub fn advance(&self, message: impl Into<String>) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.report_position(done, Some(message.into()));
+    }
+
+    /// Set the number of completed steps
+    pub fn set_position(&self, done: u64, message: Option<String>) {
+        self.done.store(done, Ordering::Relaxed);
+        self.report_position(done, message);
+    }
+
+    /// Report what the task is doing without advancing
+    pub fn message(&self, message: impl Into<String>) {
+        self.progress.report(ProgressEvent::Message {
+            task: self.name.clone(),
+            message: message.into(),
+        });
+    }
+
+    /// Finish the task with a summary
+    pub fn finish(self, message: impl Into<String>) {
+        self.ended.store(true, Ordering::Relaxed);
+        self.progress.report(ProgressEvent::Finished {
+            task: self.name.clone(),
+            message: Some(message.into()),
+        });
+    }
+
+    /// Report that the task failed
+    pub fn fail(self, error: impl Display) {
+        self.ended.store(true, Ordering::Relaxed);
+        self.progress.report(ProgressEvent::Failed {
+            task: self.name.clone(),
+            error: error.to_string(),
+        });
+    }
+
+    fn report_position(&self, done: u64, message: Option<String>) {
+        self.progress.report(ProgressEvent::Advanced {
+            task: self.name.clone(),
+            done,
+            total: self.total(),
+            message,
+        });
+    }
+}
+
+impl Drop for Task<'_> {
+    fn drop(&mut self) {
+        if !self.ended.load(Ordering::Relaxed) {
+            self.progress.report(ProgressEvent::Failed {
+                task: self.name.clone(),
+                error: "interrupted".to_string(),
+            });
+        }
+    }
+}
+
+/// Renders progress as bars and spinners on the terminal
+///
+/// Tasks with a known total get a progress bar, others a spinner; tasks
+/// running at the same time are drawn below each other.
+#[derive(Debug, Default)]
+pub struct TerminalProgress {
+    bars: MultiProgress,
+    tasks: Mutex<HashMap<String, ProgressBar>>,
+}
+
+impl TerminalProgress {
+    /// Create a renderer drawing to stderr
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bar(total: Option<u64>) -> ProgressBar {
+        match total {
+            Some(total) => {
+                let pb = ProgressBar::new(total);
+                pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}% {wide_msg}")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                );
+                pb
+            }
+            None => {
+                let pb = ProgressBar::new_spinner();
+                pb.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {wide_msg}").unwrap());
+                pb.enable_steady_tick(std::time::Duration::from_millis(100));
+                pb
+            }
+        }
+    }
+}
+
+impl Progress for TerminalProgress {
+    fn report(&self, event: ProgressEvent) {
+        let mut tasks = self.tasks.lock().unwrap();
+        match event {
+            ProgressEvent::Started { task, total } => {
+                let pb = self.bars.add(Self::bar(total));
+                pb.set_message(task.clone());
+                if let Some(old) = tasks.insert(task, pb) {
+                    old.finish_and_clear();
+                }
+            }
+            ProgressEvent::Advanced {
+                task,
+                done,
+                total,
+                message,
+            } => {
+                let Some(pb) = tasks.get(&task) else { return };
+                if let Some(total) = total {
+                    // Never draw a bar as complete before the task finishes
+                    pb.set_length(total.max(done + 1));
+                }
+                pb.set_position(done);
+                if let Some(message) = message {
+                    pb.set_message(message);
+                }
+            }
+            ProgressEvent::Message { task, message } => match tasks.get(&task) {
+                Some(pb) => pb.set_message(message),
+                None => {
+                    let _ = self.bars.println(message);
+                }
+            },
+            ProgressEvent::Finished { task, message } => {
+                if let Some(pb) = tasks.remove(&task) {
+                    if let Some(length) = pb.length() {
+                        pb.set_position(length);
+                    }
+                    pb.finish_with_message(format!("✓ {}", message.unwrap_or(task)));
+                }
+            }
+            ProgressEvent::Failed { task, error } => {
+                if let Some(pb) = tasks.remove(&task) {
+                    pb.abandon_with_message(format!("✗ {}: {}", task, error));
+                }
+            }
+        }
+    }
+}
+
+/// Writes each event as one line of JSON
+#[derive(Debug)]
+pub struct JsonLines<W: Write + Send> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    /// Write events to `writer`
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Recover the writer
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl JsonLines<io::Stderr> {
+    /// Write events to stderr, leaving stdout to the command's result
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+}
+
+impl<W: Write + Send + fmt::Debug> Progress for JsonLines<W> {
+    fn report(&self, event: ProgressEvent) {
+        let Ok(line) = serde_json::to_string(&event) else { return };
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+    }
+}
+
+/// Forwards events to subscribers, such as daemon API clients
+///
+/// Events are dropped while nobody is subscribed, and a subscriber that
+/// falls too far behind misses the oldest ones.
+#[derive(Debug, Clone)]
+pub struct EventForwarder {
+    sender: broadcast::Sender<ProgressEvent>,
+}
+
+impl EventForwarder {
+    /// Create a forwarder buffering up to `capacity` events per subscriber
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Receive the events reported from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventForwarder {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+impl Progress for EventForwarder {
+    fn report(&self, event: ProgressEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<ProgressEvent>>);
+
+    impl Progress for Recorder {
+        fn report(&self, event: ProgressEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_task_events() {
+        let recorder = Recorder::default();
+        let task = Task::start(&recorder, "backup", Some(2));
+        task.advance("uploading");
+        task.finish("backed up");
+        {
+            let _interrupted = Task::start(&recorder, "restore", None);
+        }
+
+        let events = recorder.0.into_inner().unwrap();
+        assert_eq!(
+            events[1],
+            ProgressEvent::Advanced {
+                task: "backup".into(),
+                done: 1,
+                total: Some(2),
+                message: Some("uploading".into()),
+            }
+        );
+        assert!(matches!(events[2], ProgressEvent::Finished { .. }));
+        assert_eq!(events[4].task(), "restore");
+        assert!(matches!(events[4], ProgressEvent::Failed { .. }));
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let sink = JsonLines::new(Vec::new());
+        sink.report(ProgressEvent::Started {
+            task: "pull".into(),
+            total: None,
+        });
+        sink.report(ProgressEvent::Failed {
+            task: "pull".into(),
+            error: "offline".into(),
+        });
+
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], r#"{"event":"started","task":"pull","total":null}"#);
+        let failed: ProgressEvent = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(failed.to_string(), "pull: failed: offline");
+    }
+}
@@ -0,0 +1,196 @@
+//! Calling another machine's daemon over mutual TLS
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Response, Status};
+
+use super::{HostConfig, RemoteError, Result, DEFAULT_GRPC_PORT};
+use crate::daemon::grpc::pb;
+use crate::daemon::grpc::pb::backups_client::BackupsClient;
+use crate::daemon::grpc::pb::snapshots_client::SnapshotsClient;
+use crate::daemon::SnapshotInfo;
+
+/// A backup on another machine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RemoteBackup {
+    /// Backup ID
+    pub id: String,
+    /// Name of the backup
+    pub name: String,
+    /// Description of the backup
+    pub description: Option<String>,
+    /// Subvolume that was backed up
+    pub subvolume: PathBuf,
+    /// Size in bytes
+    pub size: u64,
+    /// Creation time (RFC 3339)
+    pub created_at: String,
+    /// Whether only the changes since `parent_id` were stored
+    pub incremental: bool,
+    /// Backup this one is based on
+    pub parent_id: Option<String>,
+}
+
+impl From<pb::BackupInfo> for RemoteBackup {
+    fn from(info: pb::BackupInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            description: info.description,
+            subvolume: PathBuf::from(info.subvolume),
+            size: info.size,
+            created_at: info.created_at,
+            incremental: info.incremental,
+            parent_id: info.parent_id,
+        }
+    }
+}
+
+impl From<pb::SnapshotInfo> for SnapshotInfo {
+    fn from(info: pb::SnapshotInfo) -> Self {
+        Self {
+            name: info.name,
+            path: PathBuf::from(info.path),
+        }
+    }
+}
+
+/// Client of another machine's daemon
+#[derive(Debug, Clone)]
+pub struct GrpcClient {
+    host: String,
+    channel: Channel,
+}
+
+impl GrpcClient {
+    /// Connect to the daemon of `host`
+    ///
+    /// The daemon's certificate is checked against `host.ca`, and `host.cert`
+    /// must be pinned on the daemon to be let in.
+    pub async fn connect(host: &HostConfig) -> Result<Self> {
+        let setting = |path: &Option<PathBuf>, setting| {
+            path.clone().ok_or_else(|| RemoteError::MissingTls {
+                host: host.address.clone(),
+                setting,
+            })
+        };
+        let cert = fs::read(setting(&host.cert, "cert")?)?;
+        let key = fs::read(setting(&host.key, "key")?)?;
+        let ca = fs::read(setting(&host.ca, "ca")?)?;
+        let tls = ClientTlsConfig::new()
+            .domain_name(host.address.clone())
+            .ca_certificate(Certificate::from_pem(ca))
+            .identity(Identity::from_pem(cert, key));
+
+        let port = host.port.unwrap_or(DEFAULT_GRPC_PORT);
+        let uri = if host.address.contains(':') {
+            format!("https://[{}]:{}", host.address, port)
+        } else {
+            format!("https://{}:{}", host.address, port)
+        };
+        let connect_error = |source| RemoteError::Connect {
+            host: host.address.clone(),
+            source,
+        };
+        let channel = Channel::from_shared(uri)
+            .map_err(|_| RemoteError::InvalidHost(host.address.clone()))?
+            .tls_config(tls)
+            .map_err(connect_error)?
+            .connect()
+            .await
+            .map_err(connect_error)?;
+        Ok(Self {
+            host: host.address.clone(),
+            channel,
+        })
+    }
+
+    /// Snapshot `source` on the host
+    pub async fn create_snapshot(&self, source: &Path, name: &str, writable: bool) -> Result<SnapshotInfo> {
+        let request = pb::CreateSnapshotRequest {
+            source: source.to_string_lossy().into_owned(),
+            name: name.to_string(),
+            writable,
+        };
+        let info = self.call(self.snapshot_client().create(request).await)?;
+        Ok(info.into())
+    }
+
+    /// Snapshots on the host
+    pub async fn snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        let list = self.call(self.snapshot_client().list(pb::Empty {}).await)?;
+        Ok(list.snapshots.into_iter().map(Into::into).collect())
+    }
+
+    /// Delete a snapshot on the host
+    pub async fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let request = pb::SnapshotRequest { name: name.to_string() };
+        self.call(self.snapshot_client().delete(request).await)?;
+        Ok(())
+    }
+
+    /// Back up `subvolume` on the host
+    pub async fn create_backup(&self, subvolume: &str, description: Option<String>) -> Result<RemoteBackup> {
+        let request = pb::CreateBackupRequest {
+            subvolume: subvolume.to_string(),
+            name: None,
+            description,
+            parent_id: None,
+        };
+        let info = self.call(self.backup_client().create(request).await)?;
+        Ok(info.into())
+    }
+
+    /// Backups of the host
+    pub async fn backups(&self) -> Result<Vec<RemoteBackup>> {
+        let list = self.call(self.backup_client().list(pb::Empty {}).await)?;
+        Ok(list.backups.into_iter().map(Into::into).collect())
+    }
+
+    /// A backup of the host
+    pub async fn backup(&self, id: &str) -> Result<RemoteBackup> {
+        let request = pb::BackupRequest { id: id.to_string() };
+        Ok(self.call(self.backup_client().get(request).await)?.into())
+    }
+
+    /// Restore a backup on the host to `target`
+    pub async fn restore_backup(&self, id: &str, target: &Path) -> Result<()> {
+        let request = pb::RestoreBackupRequest {
+            id: id.to_string(),
+            target: target.to_string_lossy().into_owned(),
+        };
+        self.call(self.backup_client().restore(request).await)?;
+        Ok(())
+    }
+
+    /// Verify a backup of the host
+    pub async fn verify_backup(&self, id: &str) -> Result<bool> {
+        let request = pb::BackupRequest { id: id.to_string() };
+        Ok(self.call(self.backup_client().verify(request).await)?.valid)
+    }
+
+    /// Delete a backup of the host
+    pub async fn delete_backup(&self, id: &str) -> Result<()> {
+        let request = pb::BackupRequest { id: id.to_string() };
+        self.call(self.backup_client().delete(request).await)?;
+        Ok(())
+    }
+
+    fn snapshot_client(&self) -> SnapshotsClient<Channel> {
+        SnapshotsClient::new(self.channel.clone())
+    }
+
+    fn backup_client(&self) -> BackupsClient<Channel> {
+        BackupsClient::new(self.channel.clone())
+    }
+
+    fn call<T>(&self, response: std::result::Result<Response<T>, Status>) -> Result<T> {
+        response.map(Response::into_inner).map_err(|status| RemoteError::Call {
+            host: self.host.clone(),
+            status: Box::new(status),
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! Management of other rastOS machines
+//!
+//! `rastos --host <host>` runs a command on another machine instead of this
+//! one, over one of two transports:
+//!
+//! - **ssh** runs `rastos` on the host with the same arguments, so every
+//!   command works as long as the account can log in without a prompt.
+//! - **grpc** calls the host daemon's gRPC API over mutual TLS (its
+//!   `remote_listen` address), authenticating with a client certificate
+//!   pinned on the host. Only snapshot and backup commands have a gRPC
+//!   counterpart.
+//!
+//! Hosts are named in [`REMOTE_CONFIG`]; names that are not configured are
+//! reached over ssh, and `ssh://user@host:port` or `grpc://host:port` pick
+//! the transport explicitly:
+//!
+//! ```toml
+//! [hosts.nas]
+//! address = "nas.lan"
+//! transport = "grpc"
+//! cert = "/etc/rast/remote/laptop.crt"
+//! key = "/etc/rast/remote/laptop.key"
+//! ca = "/etc/rast/remote/fleet-ca.crt"
+//!
+//! [hosts.builder]
+//! address = "10.0.0.12"
+//! user = "admin"
+//! ```
+
+#[cfg(feature = "daemon")]
+pub mod grpc;
+pub mod ssh;
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Hosts known to `rastos --host`
+pub const REMOTE_CONFIG: &str = "/etc/rast/remote.toml";
+
+/// Port of a host's gRPC listener unless given
+pub const DEFAULT_GRPC_PORT: u16 = 7443;
+
+/// Error type for remote management
+#[derive(Error, Debug)]
+pub enum RemoteError {
+    /// I/O error, such as ssh that cannot be started
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Host configuration that could not be parsed
+    #[error("Invalid remote configuration: {0}")]
+    Config(#[from] toml::de::Error),
+
+    /// Host name or URL that cannot be used
+    #[error("Invalid host '{0}'")]
+    InvalidHost(String),
+
+    /// ssh could not reach or log in to the host
+    #[error("ssh to {host} failed with status {status}")]
+    Ssh {
+        /// Host
+        host: String,
+        /// Exit status of ssh
+        status: i32,
+    },
+
+    /// A TLS setting the gRPC transport needs is not configured
+    #[error("No {setting} configured for {host}")]
+    MissingTls {
+        /// Host
+        host: String,
+        /// `cert`, `key` or `ca`
+        setting: &'static str,
+    },
+
+    /// The host's gRPC listener could not be reached
+    #[cfg(feature = "daemon")]
+    #[error("Connection to {host} failed: {source}")]
+    Connect {
+        /// Host
+        host: String,
+        /// Transport error
+        source: tonic::transport::Error,
+    },
+
+    /// The host's daemon refused or failed the call
+    #[cfg(feature = "daemon")]
+    #[error("{host}: {}", .status.message())]
+    Call {
+        /// Host
+        host: String,
+        /// Status returned by the daemon
+        status: Box<tonic::Status>,
+    },
+
+    /// The command has no counterpart on the selected transport
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+/// Result type for remote management
+pub type Result<T> = std::result::Result<T, RemoteError>;
+
+/// How a host is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Run `rastos` on the host over ssh
+    #[default]
+    Ssh,
+    /// Call the host daemon's gRPC API over mutual TLS
+    Grpc,
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Ssh => "ssh",
+            Transport::Grpc => "grpc",
+        })
+    }
+}
+
+/// A machine managed remotely
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostConfig {
+    /// Host name or IP address
+    pub address: String,
+    /// How the host is reached
+    pub transport: Transport,
+    /// Port; 22 for ssh and [`DEFAULT_GRPC_PORT`] for gRPC unless set
+    pub port: Option<u16>,
+    /// ssh login; the ssh configuration's default unless set
+    pub user: Option<String>,
+    /// ssh private key; the ssh configuration's default unless set
+    pub identity_file: Option<PathBuf>,
+    /// Command run on the host over ssh
+    pub command: String,
+    /// Client certificate presented to the daemon (PEM)
+    pub cert: Option<PathBuf>,
+    /// Private key of the client certificate (PEM)
+    pub key: Option<PathBuf>,
+    /// Certificate authority the daemon's server certificate is checked against (PEM)
+    pub ca: Option<PathBuf>,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            transport: Transport::default(),
+            port: None,
+            user: None,
+            identity_file: None,
+            command: "rastos".to_string(),
+            cert: None,
+            key: None,
+            ca: None,
+        }
+    }
+}
+
+impl HostConfig {
+    /// Parse `ssh://[user@]host[:port]`, `grpc://host[:port]` or a plain host name
+    pub fn parse(host: &str) -> Result<Self> {
+        let invalid = || RemoteError::InvalidHost(host.to_string());
+        let (transport, rest) = match host.split_once("://") {
+            Some(("ssh", rest)) => (Transport::Ssh, rest),
+            Some(("grpc", rest)) => (Transport::Grpc, rest),
+            Some(_) => return Err(invalid()),
+            None => (Transport::Ssh, host),
+        };
+        let (user, rest) = match rest.rsplit_once('@') {
+            Some((user, rest)) if transport == Transport::Ssh && !user.is_empty() => (Some(user.to_string()), rest),
+            Some(_) => return Err(invalid()),
+            None => (None, rest),
+        };
+        // Bracketed IPv6 addresses keep their colons
+        let (address, port) = match rest.rsplit_once(':') {
+            Some((address, port)) if !address.ends_with(':') && !rest.ends_with(']') => {
+                (address, Some(port.parse().map_err(|_| invalid())?))
+            }
+            _ => (rest, None),
+        };
+        let address = address.trim_start_matches('[').trim_end_matches(']');
+        if address.is_empty() || address.contains(['/', ' ']) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            address: address.to_string(),
+            transport,
+            port,
+            user,
+            ..Self::default()
+        })
+    }
+}
+
+/// Hosts known by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteConfig {
+    /// Hosts by name
+    pub hosts: BTreeMap<String, HostConfig>,
+}
+
+impl RemoteConfig {
+    /// Load the configuration from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Load [`REMOTE_CONFIG`], or no hosts if it does not exist
+    pub fn load() -> Result<Self> {
+        match Self::from_file(REMOTE_CONFIG) {
+            Err(RemoteError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            result => result,
+        }
+    }
+
+    /// Settings of `host`, a configured name or anything [`HostConfig::parse`] accepts
+    pub fn host(&self, host: &str) -> Result<HostConfig> {
+        match self.hosts.get(host) {
+            Some(config) if config.address.is_empty() => Ok(HostConfig {
+                address: host.to_string(),
+                ..config.clone()
+            }),
+            Some(config) => Ok(config.clone()),
+            None => HostConfig::parse(host),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host() {
+        let plain = HostConfig::parse("nas.lan").unwrap();
+        assert_eq!((plain.address.as_str(), plain.transport, plain.port), ("nas.lan", Transport::Ssh, None));
+
+        let ssh = HostConfig::parse("ssh://admin@10.0.0.12:2222").unwrap();
+        assert_eq!(ssh.user.as_deref(), Some("admin"));
+        assert_eq!((ssh.address.as_str(), ssh.port), ("10.0.0.12", Some(2222)));
+
+        let grpc = HostConfig::parse("grpc://[fd00::1]:7443").unwrap();
+        assert_eq!((grpc.address.as_str(), grpc.transport, grpc.port), ("fd00::1", Transport::Grpc, Some(7443)));
+        let grpc = HostConfig::parse("grpc://[fd00::1]").unwrap();
+        assert_eq!((grpc.address.as_str(), grpc.port), ("fd00::1", None));
+
+        assert!(HostConfig::parse("http://nas.lan").is_err());
+        assert!(HostConfig::parse("grpc://admin@nas.lan").is_err());
+        assert!(HostConfig::parse("nas.lan:ssh").is_err());
+    }
+
+    #[test]
+    fn test_configured_hosts() {
+        let config: RemoteConfig = toml::from_str(
+            r#"
+            [hosts.nas]
+            address = "nas.lan"
+            transport = "grpc"
+
+            [hosts."builder.lan"]
+            user = "admin"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.host("nas").unwrap().transport, Transport::Grpc);
+        let builder = config.host("builder.lan").unwrap();
+        assert_eq!((builder.address.as_str(), builder.user.as_deref()), ("builder.lan", Some("admin")));
+        assert_eq!(config.host("other.lan").unwrap().command, "rastos");
+    }
+}
@@ -0,0 +1,97 @@
+//! Running `rastos` on another machine over ssh
+
+use std::ffi::{OsStr, OsString};
+use std::process::Command;
+
+use log::debug;
+
+use super::{HostConfig, RemoteError, Result};
+
+/// Exit status of ssh itself failing, as opposed to the remote command
+const SSH_FAILURE: i32 = 255;
+
+/// Runs commands on a host over ssh
+#[derive(Debug, Clone)]
+pub struct SshClient {
+    host: HostConfig,
+}
+
+impl SshClient {
+    /// Create a client for `host`
+    pub fn new(host: HostConfig) -> Self {
+        Self { host }
+    }
+
+    /// The `ssh` invocation running the host's `rastos` with `args`
+    pub fn command<I, S>(&self, args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new("ssh");
+        // Never stop at a password or host key prompt
+        command.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.host.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.host.identity_file {
+            command.arg("-i").arg(identity);
+        }
+        if let Some(user) = &self.host.user {
+            command.arg("-l").arg(user);
+        }
+        // The remote shell splits the command line again
+        let mut remote = OsString::from(&self.host.command);
+        for arg in args {
+            remote.push(" ");
+            remote.push(shell_quote(&arg.as_ref().to_string_lossy()));
+        }
+        command.arg(&self.host.address).arg("--").arg(remote);
+        command
+    }
+
+    /// Run the host's `rastos` with `args`, returning its exit status
+    ///
+    /// The remote command shares this terminal, so its output is printed as
+    /// is and interactive prompts keep working.
+    pub fn run<I, S>(&self, args: I) -> Result<i32>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = self.command(args);
+        debug!("Running {:?}", command);
+        let status = command.status()?.code().unwrap_or(SSH_FAILURE);
+        if status == SSH_FAILURE {
+            return Err(RemoteError::Ssh {
+                host: self.host.address.clone(),
+                status,
+            });
+        }
+        Ok(status)
+    }
+}
+
+/// Quote `arg` for a POSIX shell
+pub fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("@home"), "@home");
+        assert_eq!(shell_quote("--name=/srv/data"), "--name=/srv/data");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("nightly backup"), "'nightly backup'");
+        assert_eq!(shell_quote("it's; rm -rf /"), r"'it'\''s; rm -rf /'");
+    }
+}
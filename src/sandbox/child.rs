@@ -0,0 +1,576 @@
+//! Setting up the sandbox in the spawned process
+//!
+//! Runs between `fork` and `exec`, where another thread of the parent may
+//! have held the allocator's lock at the time of the fork: nothing here may
+//! allocate, lock or panic. [`Setup::new`] prepares every path and ID map in
+//! the parent instead, and errors are raw `errno` values.
+//!
+//! Three processes take part:
+//!
+//! 1. the spawned process waits for the second one to create its user
+//!    namespace, writes the namespace's ID maps and waits for the command
+//!    to be executed, then exits with the command's status;
+//! 2. the second one creates the namespaces, sets up the mounts and forks
+//!    the third, which is needed as a new PID namespace only applies to
+//!    children;
+//! 3. the third one is PID 1 of the new namespace: it starts a session of
+//!    its own, mounts `/proc`, changes its root, applies the resource
+//!    limits and the seccomp filter and returns to execute the command.
+//!
+//! Each of the last two is killed when its parent dies, so killing the
+//! spawned process takes down the whole sandbox.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use libc::{c_int, c_uint, c_ulong, pid_t};
+use log::debug;
+use nix::sys::resource::Resource;
+use nix::unistd::{Gid, Uid};
+
+use super::SandboxConfig;
+use crate::sys::seccomp::Filter;
+
+/// Exit status of a sandbox process whose setup failed
+const SETUP_FAILED: c_int = 127;
+
+/// Device nodes of the host bound into the sandbox's `/dev`
+const DEVICES: [&str; 6] = ["full", "null", "random", "tty", "urandom", "zero"];
+
+/// Symbolic links created in the sandbox's `/dev`
+const DEV_LINKS: [(&str, &str); 4] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// Directories replaced by an empty `tmpfs`, with its options
+const TMPFS: [(&str, &str); 2] = [("tmp", "mode=1777"), ("run", "mode=0755")];
+
+/// `struct mount_attr` in `linux/mount.h`
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+// Mount attributes from `linux/mount.h`
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+const MOUNT_ATTR_NOSUID: u64 = 0x2;
+const MOUNT_ATTR_NODEV: u64 = 0x4;
+
+/// `AT_RECURSIVE` in `linux/fcntl.h`
+const AT_RECURSIVE: c_uint = 0x8000;
+
+/// Everything the sandbox processes need, prepared before the fork
+pub(super) struct Setup {
+    /// `unshare` flags
+    namespaces: c_int,
+    /// Whether the caller is root, whose IDs are mapped unchanged
+    privileged: bool,
+    /// Contents of `uid_map`
+    uid_map: Vec<u8>,
+    /// Contents of `gid_map`
+    gid_map: Vec<u8>,
+    /// The sandbox's root directory
+    root: CString,
+    /// Whether that is the current root, so there is nothing to pivot
+    same_root: bool,
+    /// Directories that stay writable
+    writable: Vec<CString>,
+    /// The sandbox's `/dev`, if the root has one
+    dev: Option<CString>,
+    /// Device nodes of the host and where they are bound
+    devices: Vec<(CString, CString)>,
+    /// Symbolic links in `/dev` and their targets
+    links: Vec<(CString, CString)>,
+    /// `tmpfs` mount points and their options
+    tmpfs: Vec<(CString, CString)>,
+    /// The sandbox's `/proc`, if the root has one
+    proc: Option<CString>,
+    /// Resource limits
+    limits: Vec<(Resource, libc::rlim_t)>,
+    /// The seccomp filter
+    filter: Filter,
+}
+
+impl Setup {
+    /// Prepare a sandbox rooted at `root` in which `writable`, relative to
+    /// the root, can be written
+    pub(super) fn new(config: &SandboxConfig, root: &Path, writable: &[PathBuf]) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        let inside = |path: &Path| root.join(path.strip_prefix("/").unwrap_or(path));
+        let existing = |path: PathBuf| path.is_dir().then_some(path);
+
+        let mut namespaces = libc::CLONE_NEWUSER
+            | libc::CLONE_NEWNS
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWCGROUP;
+        if !config.network {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+
+        let privileged = Uid::effective().is_root();
+        let (uid_map, gid_map) = if privileged {
+            ("0 0 4294967295\n".to_string(), "0 0 4294967295\n".to_string())
+        } else {
+            (format!("0 {} 1\n", Uid::effective()), format!("0 {} 1\n", Gid::effective()))
+        };
+
+        let mut paths = Vec::new();
+        for path in writable {
+            match existing(inside(path)) {
+                Some(path) => paths.push(c_path(&path)?),
+                None => debug!("Not making {} writable in the sandbox, it does not exist", path.display()),
+            }
+        }
+
+        let dev = existing(inside(Path::new("dev")));
+        let mut devices = Vec::new();
+        let mut links = Vec::new();
+        if let Some(dev) = &dev {
+            for name in DEVICES {
+                devices.push((c_path(&Path::new("/dev").join(name))?, c_path(&dev.join(name))?));
+            }
+            for (name, target) in DEV_LINKS {
+                links.push((c_path(Path::new(target))?, c_path(&dev.join(name))?));
+            }
+        }
+
+        let mut tmpfs = Vec::new();
+        for (dir, options) in TMPFS {
+            if let Some(dir) = existing(inside(Path::new(dir))) {
+                tmpfs.push((c_path(&dir)?, CString::new(options)?));
+            }
+        }
+
+        let mib = |mib: u64| mib.saturating_mul(1024 * 1024);
+        let mut limits = vec![(Resource::RLIMIT_CORE, 0)];
+        limits.extend(config.memory_mib.map(|limit| (Resource::RLIMIT_AS, mib(limit))));
+        limits.extend(config.cpu_secs.map(|limit| (Resource::RLIMIT_CPU, limit)));
+        limits.extend(config.file_size_mib.map(|limit| (Resource::RLIMIT_FSIZE, mib(limit))));
+        limits.extend(config.open_files.map(|limit| (Resource::RLIMIT_NOFILE, limit)));
+
+        Ok(Self {
+            namespaces,
+            privileged,
+            uid_map: uid_map.into_bytes(),
+            gid_map: gid_map.into_bytes(),
+            same_root: root == Path::new("/"),
+            writable: paths,
+            dev: dev.as_deref().map(c_path).transpose()?,
+            devices,
+            links,
+            tmpfs,
+            proc: existing(inside(Path::new("proc"))).as_deref().map(c_path).transpose()?,
+            limits,
+            filter: Filter::sandbox(),
+            root: c_path(&root)?,
+        })
+    }
+
+    /// Enter the sandbox
+    ///
+    /// Returns in the process that goes on to execute the command; must
+    /// only be called between `fork` and `exec`.
+    pub(super) fn enter(&self) -> io::Result<()> {
+        // SAFETY: only async-signal-safe calls are made in the child
+        unsafe {
+            let parent = libc::getpid();
+            let ready = pipe()?;
+            let go = pipe()?;
+            let status = pipe()?;
+            let pid = check(libc::fork())?;
+            if pid == 0 {
+                libc::close(ready.0);
+                libc::close(go.1);
+                libc::close(status.0);
+                return match self.namespace(parent, ready.1, go.0, status.1) {
+                    Ok(()) => Ok(()),
+                    Err(e) => fail(status.1, &e),
+                };
+            }
+            libc::close(ready.1);
+            libc::close(go.0);
+            libc::close(status.1);
+
+            let mapped = self.map_ids(pid, ready.0, go.1);
+            libc::close(go.1);
+            if let Err(e) = mapped {
+                libc::kill(pid, libc::SIGKILL);
+                reap(pid);
+                return Err(reported(status.0).unwrap_or(e));
+            }
+
+            // The end of the status pipe closes on exec, or brings the setup's error
+            if let Some(e) = reported(status.0) {
+                reap(pid);
+                return Err(e);
+            }
+
+            // Let the parent know the command runs, by closing its exec error pipe
+            close_range(3, c_uint::MAX);
+            wait_and_exit(pid)
+        }
+    }
+
+    /// Write the ID maps of the second process once it created its user namespace
+    fn map_ids(&self, pid: pid_t, ready: c_int, go: c_int) -> io::Result<()> {
+        let mut byte = [0];
+        if !read_exact(ready, &mut byte)? {
+            return Err(io::Error::from_raw_os_error(libc::ECHILD));
+        }
+        if !self.privileged {
+            // Unprivileged processes may only map their own group without it
+            write_file(StackPath::proc(pid, b"setgroups").as_c_str(), b"deny")?;
+        }
+        write_file(StackPath::proc(pid, b"uid_map").as_c_str(), &self.uid_map)?;
+        write_file(StackPath::proc(pid, b"gid_map").as_c_str(), &self.gid_map)?;
+        write_all(go, &byte)
+    }
+
+    /// Create the namespaces and mounts, then fork PID 1 of the sandbox
+    ///
+    /// Returns in PID 1 only.
+    fn namespace(&self, parent: pid_t, ready: c_int, go: c_int, status: c_int) -> io::Result<()> {
+        // SAFETY: only async-signal-safe calls
+        unsafe {
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+            if libc::getppid() != parent {
+                libc::_exit(SETUP_FAILED);
+            }
+            check(libc::unshare(self.namespaces))?;
+            write_all(ready, b"1")?;
+            let mut byte = [0];
+            if !read_exact(go, &mut byte)? {
+                return Err(io::Error::from_raw_os_error(libc::ECHILD));
+            }
+            libc::close(ready);
+            libc::close(go);
+            // Whatever else was inherited, including the parent's exec error pipe
+            close_range(3, (status - 1) as c_uint);
+            close_range(status as c_uint + 1, c_uint::MAX);
+
+            self.mounts()?;
+
+            let pid = check(libc::fork())?;
+            if pid == 0 {
+                return self.init();
+            }
+            libc::close(status);
+            wait_and_exit(pid)
+        }
+    }
+
+    /// Set up the sandbox's filesystem in the new mount namespace
+    fn mounts(&self) -> io::Result<()> {
+        // Nothing propagates back to the host
+        mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        if !self.same_root {
+            // pivot_root needs a mount point
+            mount(Some(&self.root), &self.root, None, libc::MS_BIND | libc::MS_REC, None)?;
+        }
+
+        // The host's devices, reachable after /dev is hidden
+        let mut fds = [-1; DEVICES.len()];
+        for (fd, (device, _)) in fds.iter_mut().zip(&self.devices) {
+            // SAFETY: opening a path
+            *fd = unsafe { libc::open(device.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        }
+
+        set_attr(&self.root, AT_RECURSIVE, MOUNT_ATTR_RDONLY | MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV, 0)?;
+        for path in &self.writable {
+            mount(Some(path), path, None, libc::MS_BIND | libc::MS_REC, None)?;
+            match set_attr(path, AT_RECURSIVE, 0, MOUNT_ATTR_RDONLY) {
+                // A mount below is read-only on the host too, and stays so
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    ignore_locked(set_attr(path, 0, 0, MOUNT_ATTR_RDONLY))?
+                }
+                result => result?,
+            }
+        }
+
+        if let Some(dev) = &self.dev {
+            let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            mount(Some(c"tmpfs"), dev, Some(c"tmpfs"), flags, Some(c"mode=0755"))?;
+            for (&fd, (_, node)) in fds.iter().zip(&self.devices) {
+                if fd == -1 {
+                    continue;
+                }
+                // SAFETY: creating a file and closing its descriptor
+                unsafe {
+                    let file = check(libc::open(node.as_ptr(), libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC, 0o666))?;
+                    libc::close(file);
+                }
+                mount(Some(StackPath::fd(fd).as_c_str()), node, None, libc::MS_BIND, None)?;
+                set_attr(node, 0, 0, MOUNT_ATTR_NODEV)?;
+                // SAFETY: closing a descriptor opened above
+                unsafe { libc::close(fd) };
+            }
+            for (target, link) in &self.links {
+                // SAFETY: creating a symbolic link
+                check(unsafe { libc::symlink(target.as_ptr(), link.as_ptr()) })?;
+            }
+        }
+
+        for (dir, options) in &self.tmpfs {
+            mount(Some(c"tmpfs"), dir, Some(c"tmpfs"), libc::MS_NOSUID | libc::MS_NODEV, Some(options))?;
+        }
+        Ok(())
+    }
+
+    /// Finish the setup as PID 1 of the sandbox
+    fn init(&self) -> io::Result<()> {
+        // SAFETY: only async-signal-safe calls
+        unsafe {
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+            // Without a controlling terminal, an inherited terminal cannot
+            // be used to push input to the shell that started us
+            check(libc::setsid())?;
+            if let Some(proc) = &self.proc {
+                let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+                mount(Some(c"proc"), proc, Some(c"proc"), flags, None)?;
+            }
+            if !self.same_root {
+                check(libc::chdir(self.root.as_ptr()))?;
+                check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as c_int)?;
+                // The old root was stacked below the new one
+                check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            }
+            check(libc::chdir(c"/".as_ptr()))?;
+        }
+        for &(resource, limit) in &self.limits {
+            nix::sys::resource::setrlimit(resource, limit, limit)?;
+        }
+        self.filter.install()
+    }
+}
+
+/// A path built without allocating
+struct StackPath {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl StackPath {
+    /// `/proc/<pid>/<name>`
+    fn proc(pid: pid_t, name: &[u8]) -> Self {
+        let mut path = Self { buf: [0; 64], len: 0 };
+        path.push(b"/proc/");
+        path.push_number(pid as u32);
+        path.push(b"/");
+        path.push(name);
+        path
+    }
+
+    /// `/proc/self/fd/<fd>`, the file a descriptor refers to
+    fn fd(fd: c_int) -> Self {
+        let mut path = Self { buf: [0; 64], len: 0 };
+        path.push(b"/proc/self/fd/");
+        path.push_number(fd as u32);
+        path
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        // One byte is kept for the terminating NUL
+        let len = bytes.len().min(self.buf.len() - 1 - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_number(&mut self, mut n: u32) {
+        let mut digits = [0; 10];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..]);
+    }
+
+    fn as_c_str(&mut self) -> &CStr {
+        self.buf[self.len] = 0;
+        // SAFETY: the bytes up to len hold no NUL, as pushed numbers and names have none
+        unsafe { CStr::from_bytes_with_nul_unchecked(&self.buf[..=self.len]) }
+    }
+}
+
+/// `path` as a C string
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} contains a NUL byte", path.display()))
+    })
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn pipe() -> io::Result<(c_int, c_int)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for both ends
+    check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+    Ok((fds[0], fds[1]))
+}
+
+fn close_range(first: c_uint, last: c_uint) {
+    if first <= last {
+        // SAFETY: no descriptor in the range is used afterwards
+        unsafe { libc::syscall(libc::SYS_close_range, first, last, 0) };
+    }
+}
+
+fn write_all(fd: c_int, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        // SAFETY: data is valid for its length
+        match unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            -1 => return Err(io::Error::last_os_error()),
+            n => data = &data[n as usize..],
+        }
+    }
+    Ok(())
+}
+
+/// Fill `buf`, returning false at the end of the file
+fn read_exact(fd: c_int, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        // SAFETY: the rest of buf is valid for writes
+        match unsafe { libc::read(fd, buf[filled..].as_mut_ptr().cast(), buf.len() - filled) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Ok(false),
+            n => filled += n as usize,
+        }
+    }
+    Ok(true)
+}
+
+fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    // SAFETY: path is a valid C string
+    let fd = check(unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) })?;
+    let result = write_all(fd, data);
+    // SAFETY: fd was opened above
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn mount(
+    source: Option<&CStr>,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: c_ulong,
+    data: Option<&CStr>,
+) -> io::Result<()> {
+    let ptr = |s: Option<&CStr>| s.map_or(ptr::null(), CStr::as_ptr);
+    // SAFETY: every pointer is a valid C string or null
+    check(unsafe { libc::mount(ptr(source), target.as_ptr(), ptr(fstype), flags, ptr(data).cast()) })?;
+    Ok(())
+}
+
+/// Change the attributes of the mount at `path` with `mount_setattr(2)`
+fn set_attr(path: &CStr, flags: c_uint, set: u64, clear: u64) -> io::Result<()> {
+    let attr = MountAttr {
+        attr_set: set,
+        attr_clr: clear,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    // SAFETY: path is a valid C string and attr lives through the call
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            flags,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Accept attributes the host locked, which no namespace may lift
+fn ignore_locked(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => Ok(()),
+        result => result,
+    }
+}
+
+/// Report a setup error through the status pipe and exit
+fn fail(status: c_int, err: &io::Error) -> ! {
+    let errno = err.raw_os_error().unwrap_or(libc::EINVAL);
+    let _ = write_all(status, &errno.to_ne_bytes());
+    // SAFETY: exiting without running the parent's exit handlers
+    unsafe { libc::_exit(SETUP_FAILED) }
+}
+
+/// The setup error sent through the status pipe, if any
+fn reported(status: c_int) -> Option<io::Error> {
+    let mut errno = [0; 4];
+    match read_exact(status, &mut errno) {
+        Ok(true) => Some(io::Error::from_raw_os_error(i32::from_ne_bytes(errno))),
+        _ => None,
+    }
+}
+
+fn reap(pid: pid_t) {
+    let mut status = 0;
+    // SAFETY: waiting for a child
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1
+        && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+    {}
+}
+
+/// Wait for `pid` and exit with its status, or 128 plus the signal that killed it
+fn wait_and_exit(pid: pid_t) -> ! {
+    let mut status = 0;
+    let code = loop {
+        // SAFETY: waiting for a child
+        match unsafe { libc::waitpid(pid, &mut status, 0) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            -1 => break SETUP_FAILED,
+            _ if libc::WIFEXITED(status) => break libc::WEXITSTATUS(status),
+            _ if libc::WIFSIGNALED(status) => break 128 + libc::WTERMSIG(status),
+            _ => {}
+        }
+    };
+    // SAFETY: exiting without running the parent's exit handlers
+    unsafe { libc::_exit(code) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_path() {
+        assert_eq!(StackPath::proc(4242, b"uid_map").as_c_str(), c"/proc/4242/uid_map");
+        assert_eq!(StackPath::fd(0).as_c_str(), c"/proc/self/fd/0");
+        // Overlong paths are cut, not overflowed
+        let mut path = StackPath::proc(1, &[b'x'; 100]);
+        assert_eq!(path.as_c_str().to_bytes().len(), 63);
+    }
+}
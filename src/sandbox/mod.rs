@@ -0,0 +1,218 @@
+//! Sandboxed commands
+//!
+//! Hooks — package and transaction hooks, post-install hooks of installer
+//! profiles, update validation commands and key renewal commands — come
+//! from configuration files and package lists, yet rastOS runs them as
+//! root. A [`Sandbox`] keeps such a command from taking over the host:
+//!
+//! - **namespaces**: the command runs in new user, mount, PID, IPC, UTS and
+//!   cgroup namespaces and, unless [`SandboxConfig::network`] is set, a new
+//!   network namespace without any interfaces but loopback.
+//! - **filesystem**: the root is mounted read-only, `nosuid` and `nodev`,
+//!   except for the paths the kind of hook needs and
+//!   [`SandboxConfig::writable`]. `/tmp` and `/run` are empty, which also
+//!   hides the sockets of system services, and `/dev` only holds `null`,
+//!   `zero`, `full`, `random`, `urandom` and `tty`.
+//! - **system calls**: the [`crate::sys::seccomp`] filter refuses mounting,
+//!   creating namespaces, tracing, loading kernel modules and the like.
+//! - **resources**: memory, CPU time, file size and open files are limited
+//!   with `setrlimit`, and the whole sandbox is killed after its timeout.
+//!
+//! Root in the sandbox is the caller: root stays root for files, without
+//! any capability over the host, and an unprivileged caller appears as
+//! root to the command. User namespaces must be available to the caller;
+//! where they are not, [`SandboxConfig::enabled`] turns the sandbox off and
+//! commands run as they used to, only changing their root directory.
+//!
+//! Commands start in the sandbox's root directory. A command killed by a
+//! signal exits with 128 plus the signal number, like in a shell.
+
+mod child;
+
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use child::Setup;
+
+/// Limits and permissions of sandboxed commands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Run commands in a sandbox; turn off on kernels without user namespaces
+    pub enabled: bool,
+    /// Let commands reach the network
+    pub network: bool,
+    /// Paths commands may write besides the ones their kind of hook needs
+    pub writable: Vec<PathBuf>,
+    /// Address space limit in MiB
+    pub memory_mib: Option<u64>,
+    /// CPU time limit in seconds
+    pub cpu_secs: Option<u64>,
+    /// Largest file commands may write, in MiB
+    pub file_size_mib: Option<u64>,
+    /// Most files a command may have open
+    pub open_files: Option<u64>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            network: false,
+            writable: Vec::new(),
+            memory_mib: Some(8192),
+            cpu_secs: None,
+            file_size_mib: None,
+            open_files: Some(4096),
+        }
+    }
+}
+
+/// Runs commands in a sandbox
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    config: SandboxConfig,
+    root: PathBuf,
+    timeout: Option<Duration>,
+}
+
+impl Sandbox {
+    /// A sandbox of the current root directory, without a timeout
+    pub fn new(config: SandboxConfig) -> Self {
+        Self {
+            config,
+            root: PathBuf::from("/"),
+            timeout: None,
+        }
+    }
+
+    /// Use `root` as the commands' root directory
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Let commands write `path`, given relative to the root directory
+    pub fn with_writable<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config.writable.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Let commands reach the network, whatever the configuration says
+    pub fn with_network(mut self) -> Self {
+        self.config.network = true;
+        self
+    }
+
+    /// Kill commands running longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Start `command` in the sandbox
+    ///
+    /// The timeout only applies to [`Sandbox::status`] and [`Sandbox::output`].
+    pub fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        if !self.config.enabled {
+            warn!("Running {:?} without a sandbox", command.get_program());
+            if self.root != Path::new("/") {
+                let root = CString::new(self.root.as_os_str().as_bytes())?;
+                // SAFETY: chroot and chdir are system calls
+                unsafe {
+                    command.pre_exec(move || {
+                        if libc::chroot(root.as_ptr()) == -1 || libc::chdir(c"/".as_ptr()) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            return command.spawn();
+        }
+
+        let setup = Setup::new(&self.config, &self.root, &self.config.writable)?;
+        debug!("Running {:?} in a sandbox of {}", command.get_program(), self.root.display());
+        // SAFETY: the setup makes system calls only, without allocating
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+        command.spawn()
+    }
+
+    /// Run `command` in the sandbox and wait for it
+    pub fn status(&self, command: &mut Command) -> io::Result<ExitStatus> {
+        let mut child = self.spawn(command)?;
+        self.wait(&mut child)
+    }
+
+    /// Run `command` in the sandbox, collecting its output
+    pub fn output(&self, command: &mut Command) -> io::Result<Output> {
+        let mut child = self.spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+        let stdout = child.stdout.take().map(collect);
+        let stderr = child.stderr.take().map(collect);
+        let status = self.wait(&mut child)?;
+        let join = |reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>| match reader {
+            Some(reader) => reader.join().unwrap_or_else(|_| Ok(Vec::new())),
+            None => Ok(Vec::new()),
+        };
+        Ok(Output {
+            status,
+            stdout: join(stdout)?,
+            stderr: join(stderr)?,
+        })
+    }
+
+    /// Wait for `child`, killing it after the timeout
+    fn wait(&self, child: &mut Child) -> io::Result<ExitStatus> {
+        let Some(timeout) = self.timeout else {
+            return child.wait();
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {:?}", timeout),
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Read a pipe to its end on a thread of its own
+fn collect<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_deserialize() {
+        let config: SandboxConfig = toml::from_str("network = true\nwritable = [\"/srv\"]").unwrap();
+        assert!(config.enabled && config.network);
+        assert_eq!(config.writable, vec![PathBuf::from("/srv")]);
+        assert_eq!(config.memory_mib, SandboxConfig::default().memory_mib);
+    }
+}
@@ -0,0 +1,125 @@
+//! File fallback for secret storage
+//!
+//! Each secret is a file named after it in a directory only its owner can
+//! enter. This protects secrets from other users but not from anyone who
+//! can read the disk, so prefer an encrypted home or root filesystem.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::{Result, SecretBackend};
+use crate::fs::{atomic_write_with_options, create_dir_with_options, shred, CreateOptions, FsError, WriteOptions};
+
+/// Secrets directory for root
+const SYSTEM_SECRETS_DIR: &str = "/var/lib/rast/secrets";
+
+/// Random overwrites of a secret file before it is deleted
+const SHRED_PASSES: usize = 1;
+
+/// Secrets stored as files in a private directory
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    /// Store secrets in `dir`, which is created with mode 0700 when needed
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Store secrets in the default directory for the current user
+    ///
+    /// Root uses `/var/lib/rast/secrets`; other users
+    /// `$XDG_DATA_HOME/rast/secrets`, which defaults to
+    /// `~/.local/share/rast/secrets`.
+    pub fn open_default() -> Result<Self> {
+        if users::get_effective_uid() == 0 {
+            return Ok(Self::new(SYSTEM_SECRETS_DIR));
+        }
+        let data_home = match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let home = std::env::var_os("HOME")
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+                PathBuf::from(home).join(".local/share")
+            }
+        };
+        Ok(Self::new(data_home.join("rast/secrets")))
+    }
+
+    /// Directory holding the secrets
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn ensure_dir(&self) -> Result<()> {
+        if let Some(parent) = self.dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let options = CreateOptions::default().with_mode(0o700).with_exact_mode();
+        match create_dir_with_options(&self.dir, &options) {
+            Ok(()) | Err(FsError::AlreadyExists(_)) => Ok(()),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+}
+
+impl SecretBackend for FileBackend {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        match fs::metadata(&path) {
+            Ok(meta) if meta.permissions().mode() & 0o077 != 0 => {
+                warn!("Secret file {} is accessible by other users", path.display());
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Some(fs::read(&path)?))
+    }
+
+    fn set(&self, name: &str, secret: &[u8]) -> Result<()> {
+        self.ensure_dir()?;
+        let options = WriteOptions::default().with_mode(0o600);
+        atomic_write_with_options(self.dir.join(name), secret, &options).map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        match shred(self.dir.join(name), SHRED_PASSES) {
+            Ok(()) => Ok(true),
+            Err(FsError::NotFound(_)) => Ok(false),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_file_permissions() -> Result<()> {
+        let dir = tempdir()?;
+        let backend = FileBackend::new(dir.path().join("secrets"));
+        backend.set("backup-key", &[0u8, 1, 2, 255])?;
+
+        assert_eq!(backend.get("backup-key")?, Some(vec![0u8, 1, 2, 255]));
+        assert_eq!(fs::metadata(backend.dir())?.permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(backend.dir().join("backup-key"))?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(backend.get("missing")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Linux kernel keyring backend
+//!
+//! Secrets are `user` keys described as `rast:<name>` in a kernel keyring.
+//! They never touch the disk, but they also do not survive a reboot: the
+//! user keyring lasts while the user has processes, the session keyring
+//! while the login session does. Load secrets at boot, for example from a
+//! credential passed by systemd, and read them here afterwards.
+
+use std::ffi::CString;
+use std::io;
+
+use super::{Result, SecretBackend, SecretError};
+
+/// `keyctl` operations, from `linux/keyctl.h`
+const KEYCTL_UNLINK: libc::c_long = 9;
+const KEYCTL_SEARCH: libc::c_long = 10;
+const KEYCTL_READ: libc::c_long = 11;
+
+/// Special keyring IDs, from `linux/keyctl.h`
+const KEY_SPEC_SESSION_KEYRING: libc::c_long = -3;
+const KEY_SPEC_USER_KEYRING: libc::c_long = -4;
+
+/// Largest payload of a `user` key
+const MAX_PAYLOAD: usize = 32767;
+
+/// Secrets in a kernel keyring
+#[derive(Debug, Clone)]
+pub struct KernelKeyring {
+    keyring: libc::c_long,
+}
+
+impl KernelKeyring {
+    /// Use the user keyring, shared by all processes of the user
+    pub fn user() -> Self {
+        Self {
+            keyring: KEY_SPEC_USER_KEYRING,
+        }
+    }
+
+    /// Use the session keyring of the current login session
+    pub fn session() -> Self {
+        Self {
+            keyring: KEY_SPEC_SESSION_KEYRING,
+        }
+    }
+
+    /// Serial number of the key for `name`, if it exists
+    fn search(&self, name: &str) -> Result<Option<libc::c_long>> {
+        let description = description(name)?;
+        // SAFETY: both strings are NUL-terminated and outlive the call
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_SEARCH,
+                self.keyring,
+                c"user".as_ptr(),
+                description.as_ptr(),
+                0 as libc::c_long,
+            )
+        };
+        if serial >= 0 {
+            return Ok(Some(serial));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ENOKEY) | Some(libc::EKEYEXPIRED) | Some(libc::EKEYREVOKED) => Ok(None),
+            _ => Err(keyring_error(err)),
+        }
+    }
+}
+
+impl SecretBackend for KernelKeyring {
+    fn name(&self) -> &'static str {
+        "kernel-keyring"
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(serial) = self.search(name)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        // SAFETY: the kernel writes at most `buf.len()` bytes into `buf`
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                serial,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(keyring_error(io::Error::last_os_error()));
+        }
+        buf.truncate(len as usize);
+        Ok(Some(buf))
+    }
+
+    fn set(&self, name: &str, secret: &[u8]) -> Result<()> {
+        if secret.len() > MAX_PAYLOAD {
+            return Err(SecretError::Backend(format!(
+                "Secret '{}' exceeds the kernel keyring limit of {} bytes",
+                name, MAX_PAYLOAD
+            )));
+        }
+        let description = description(name)?;
+        // SAFETY: the strings are NUL-terminated and `secret` is valid for
+        // its length; an existing key with the same description is updated
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                c"user".as_ptr(),
+                description.as_ptr(),
+                secret.as_ptr(),
+                secret.len(),
+                self.keyring,
+            )
+        };
+        if serial < 0 {
+            return Err(keyring_error(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        let Some(serial) = self.search(name)? else {
+            return Ok(false);
+        };
+        // SAFETY: plain integer arguments
+        let ret = unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_UNLINK, serial, self.keyring) };
+        if ret < 0 {
+            return Err(keyring_error(io::Error::last_os_error()));
+        }
+        Ok(true)
+    }
+}
+
+fn description(name: &str) -> Result<CString> {
+    CString::new(format!("rast:{}", name)).map_err(|_| SecretError::InvalidName(name.to_string()))
+}
+
+fn keyring_error(err: io::Error) -> SecretError {
+    match err.raw_os_error() {
+        Some(libc::ENOSYS) => SecretError::Unavailable("the kernel has no key retention service".to_string()),
+        _ => err.into(),
+    }
+}
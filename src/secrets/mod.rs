@@ -0,0 +1,217 @@
+//! Secret storage for rastOS
+//!
+//! API keys, backup encryption keys and similar secrets should not be kept
+//! in configuration files. A [`SecretStore`] keeps them by name in one of
+//! several backends instead, and configuration refers to the name:
+//!
+//! - [`SecretService`]: the freedesktop.org Secret Service (GNOME Keyring,
+//!   KWallet), for desktop sessions
+//! - [`KernelKeyring`]: the Linux kernel key retention service, for secrets
+//!   loaded at boot that must not touch the disk
+//! - [`FileBackend`]: one file per secret, readable only by its owner, for
+//!   headless systems without the above
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use rastos::secrets::{BackendKind, SecretStore};
+//!
+//! let store = SecretStore::open(BackendKind::Auto).unwrap();
+//! store.set("backup-s3", b"AKIA...").unwrap();
+//! let key = store.get_string("backup-s3").unwrap();
+//! ```
+
+mod file;
+mod kernel_keyring;
+mod secret_service;
+
+use std::fmt;
+use std::sync::Arc;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub use file::FileBackend;
+pub use kernel_keyring::KernelKeyring;
+pub use secret_service::SecretService;
+
+/// Longest secret name accepted
+const MAX_NAME_LEN: usize = 128;
+
+/// Error type for secret storage
+#[derive(Error, Debug)]
+pub enum SecretError {
+    /// No secret with this name
+    #[error("Secret not found: {0}")]
+    NotFound(String),
+
+    /// Name with characters that are not allowed
+    #[error("Invalid secret name: {0}")]
+    InvalidName(String),
+
+    /// Backend that cannot be used on this system
+    #[error("Secret backend unavailable: {0}")]
+    Unavailable(String),
+
+    /// Failure reported by the backend
+    #[error("Secret backend error: {0}")]
+    Backend(String),
+
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Result type for secret storage
+pub type Result<T> = std::result::Result<T, SecretError>;
+
+/// Storage for named secrets
+pub trait SecretBackend: Send + Sync + fmt::Debug {
+    /// Short name of the backend for messages
+    fn name(&self) -> &'static str;
+
+    /// Read a secret, or `None` if it does not exist
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Create or replace a secret
+    fn set(&self, name: &str, secret: &[u8]) -> Result<()>;
+
+    /// Delete a secret, returning whether it existed
+    fn delete(&self, name: &str) -> Result<bool>;
+}
+
+/// Backend selection as written in configuration files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// The Secret Service if a session bus offers it, otherwise files
+    #[default]
+    Auto,
+    /// The freedesktop.org Secret Service
+    SecretService,
+    /// The kernel's user keyring
+    KernelKeyring,
+    /// Files in the default secrets directory
+    File,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::Auto => "auto",
+            BackendKind::SecretService => "secret-service",
+            BackendKind::KernelKeyring => "kernel-keyring",
+            BackendKind::File => "file",
+        })
+    }
+}
+
+/// Named secrets in a backend
+///
+/// Cloning is cheap; clones share the backend.
+#[derive(Debug, Clone)]
+pub struct SecretStore {
+    backend: Arc<dyn SecretBackend>,
+}
+
+impl SecretStore {
+    /// Create a store using `backend`
+    pub fn new<B: SecretBackend + 'static>(backend: B) -> Self {
+        Self {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Open the backend selected by `kind`
+    pub fn open(kind: BackendKind) -> Result<Self> {
+        let store = match kind {
+            BackendKind::Auto if SecretService::is_available() => Self::new(SecretService::new()),
+            BackendKind::Auto | BackendKind::File => Self::new(FileBackend::open_default()?),
+            BackendKind::SecretService => {
+                if !SecretService::is_available() {
+                    return Err(SecretError::Unavailable(
+                        "no Secret Service on the session bus, or secret-tool is not installed".to_string(),
+                    ));
+                }
+                Self::new(SecretService::new())
+            }
+            BackendKind::KernelKeyring => Self::new(KernelKeyring::user()),
+        };
+        debug!("Using the {} secret backend", store.backend_name());
+        Ok(store)
+    }
+
+    /// Name of the backend in use
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    /// Read a secret
+    pub fn get(&self, name: &str) -> Result<Vec<u8>> {
+        validate_name(name)?;
+        self.backend
+            .get(name)?
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))
+    }
+
+    /// Read a secret that holds text, such as an API key
+    pub fn get_string(&self, name: &str) -> Result<String> {
+        String::from_utf8(self.get(name)?)
+            .map_err(|_| SecretError::Backend(format!("Secret '{}' is not valid UTF-8", name)))
+    }
+
+    /// Check whether a secret exists
+    pub fn contains(&self, name: &str) -> Result<bool> {
+        validate_name(name)?;
+        Ok(self.backend.get(name)?.is_some())
+    }
+
+    /// Create or replace a secret
+    pub fn set(&self, name: &str, secret: &[u8]) -> Result<()> {
+        validate_name(name)?;
+        self.backend.set(name, secret)
+    }
+
+    /// Delete a secret, returning whether it existed
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        validate_name(name)?;
+        self.backend.delete(name)
+    }
+}
+
+/// Check that `name` is usable as a file name and keyring description
+///
+/// Names consist of ASCII letters, digits, `.`, `_` and `-`, and do not
+/// start with a dot.
+fn validate_name(name: &str) -> Result<()> {
+    if name.len() <= MAX_NAME_LEN && crate::fs::is_plain_name(name) {
+        Ok(())
+    } else {
+        Err(SecretError::InvalidName(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_store() -> Result<()> {
+        let dir = tempdir()?;
+        let store = SecretStore::new(FileBackend::new(dir.path().join("secrets")));
+
+        store.set("llm-api", b"sk-test")?;
+        assert_eq!(store.get_string("llm-api")?, "sk-test");
+        assert!(store.contains("llm-api")?);
+        assert!(store.delete("llm-api")?);
+        assert!(!store.delete("llm-api")?);
+        assert!(matches!(store.get("llm-api"), Err(SecretError::NotFound(_))));
+
+        for name in ["", "../passwd", ".hidden", "with space"] {
+            assert!(matches!(store.set(name, b"x"), Err(SecretError::InvalidName(_))));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! freedesktop.org Secret Service backend
+//!
+//! Talks to the session's Secret Service, such as GNOME Keyring or KWallet,
+//! through libsecret's `secret-tool`. Items carry the attributes
+//! `application=rast` and `name=<name>`, so they can also be managed with
+//! tools like Seahorse. The Secret Service stores text, so secrets that are
+//! not UTF-8 are hex-encoded and marked with `encoding=hex`.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use log::debug;
+
+use super::{Result, SecretBackend, SecretError};
+
+/// Value of the `application` attribute on rastOS items
+const APPLICATION: &str = "rast";
+
+/// Secrets in the freedesktop.org Secret Service
+#[derive(Debug, Clone, Default)]
+pub struct SecretService {
+    _private: (),
+}
+
+impl SecretService {
+    /// Use the Secret Service of the current session
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check for a session bus and `secret-tool`
+    ///
+    /// Whether a Secret Service daemon answers on the bus is only known once
+    /// it is used.
+    pub fn is_available() -> bool {
+        let session_bus = std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some()
+            || std::env::var_os("XDG_RUNTIME_DIR").is_some_and(|dir| Path::new(&dir).join("bus").exists());
+        session_bus && in_path("secret-tool")
+    }
+
+    fn lookup(&self, name: &str, hex: bool) -> Result<Option<String>> {
+        let mut args = vec!["lookup", "application", APPLICATION, "name", name];
+        if hex {
+            args.extend(["encoding", "hex"]);
+        }
+        let output = secret_tool(&args, None)?;
+        if output.status.success() {
+            return Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()));
+        }
+        // A missing item is a failure without a message
+        if output.stderr.is_empty() {
+            return Ok(None);
+        }
+        Err(command_error("lookup", &output))
+    }
+}
+
+impl SecretBackend for SecretService {
+    fn name(&self) -> &'static str {
+        "secret-service"
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(encoded) = self.lookup(name, true)? {
+            return decode_hex(encoded.trim())
+                .map(Some)
+                .ok_or_else(|| SecretError::Backend(format!("Secret '{}' is not valid hex", name)));
+        }
+        Ok(self.lookup(name, false)?.map(String::into_bytes))
+    }
+
+    fn set(&self, name: &str, secret: &[u8]) -> Result<()> {
+        // Replacing the item must also drop one with a different encoding
+        self.delete(name)?;
+        let label = format!("rastOS secret {}", name);
+        let mut args = vec!["store", "--label", label.as_str(), "application", APPLICATION, "name", name];
+        let text = match std::str::from_utf8(secret) {
+            Ok(text) if !text.contains('\0') => text.to_string(),
+            _ => {
+                args.extend(["encoding", "hex"]);
+                encode_hex(secret)
+            }
+        };
+        let output = secret_tool(&args, Some(text.as_bytes()))?;
+        if !output.status.success() {
+            return Err(command_error("store", &output));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        if self.lookup(name, false)?.is_none() {
+            return Ok(false);
+        }
+        let output = secret_tool(&["clear", "application", APPLICATION, "name", name], None)?;
+        if !output.status.success() {
+            return Err(command_error("clear", &output));
+        }
+        Ok(true)
+    }
+}
+
+/// Run `secret-tool`, writing `input` to its stdin
+fn secret_tool(args: &[&str], input: Option<&[u8]>) -> Result<Output> {
+    // Secrets are passed on stdin, so logging the arguments is safe
+    debug!("Running: secret-tool {}", args.join(" "));
+    let mut child = Command::new("secret-tool")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    if let Some(input) = input {
+        stdin.write_all(input)?;
+    }
+    drop(stdin);
+    Ok(child.wait_with_output()?)
+}
+
+fn command_error(action: &str, output: &Output) -> SecretError {
+    SecretError::Backend(format!(
+        "secret-tool {} failed: {}",
+        action,
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let key = [0u8, 0x7f, 0x80, 0xff];
+        assert_eq!(encode_hex(&key), "007f80ff");
+        assert_eq!(decode_hex("007f80ff"), Some(key.to_vec()));
+        assert_eq!(decode_hex("007"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -2,6 +2,10 @@
 //!
 //! This module provides types and functions for managing Btrfs snapshots in a tree structure,
 //! allowing for efficient tracking of parent-child relationships between snapshots.
+//!
+//! [`SnapshotTree::rollback_to`] reverts a root subvolume to any snapshot in the
+//! tree. The root is swapped by renaming subvolumes, so mount options such as
+//! `subvol=@` in `/etc/fstab` and the boot entries keep working unchanged.
 
 use std::collections::{HashMap, HashSet};
 // use std::ffi::CString;
@@ -16,6 +20,23 @@ use uuid::Uuid;
 use btrfsutil::error::{BtrfsUtilError, LibError};
 use btrfsutil_sys::*;
 
+pub mod transaction;
+
+/// Where snapshots for backups are kept unless a local storage path is configured
+pub const DEFAULT_SNAPSHOT_DIR: &str = "/var/lib/rast/backups/snapshots";
+
+/// Time of the last successful backup, in RFC 3339
+///
+/// Written by the backup manager and checked by `rast doctor`, which is
+/// built without the `backup` feature too.
+pub const LAST_BACKUP_STAMP: &str = "/var/lib/rast/backups/last-success";
+
+/// Suffix of the writable copy of the target while a rollback prepares it
+const ROLLBACK_STAGING_SUFFIX: &str = ".rollback";
+
+/// Suffix the replaced root is renamed to until it is deleted
+const ROLLBACK_REPLACED_SUFFIX: &str = ".replaced";
+
 // Import local modules
 // use crate::fs::btrfs;
 /// Re-export BtrfsError for convenience
@@ -326,6 +347,71 @@ impl SnapshotTree {
         
         Ok(new_id)
     }
+
+    /// Revert the root subvolume at `root` to the snapshot `id`
+    ///
+    /// A read-only snapshot of the current root is taken first, next to it
+    /// as `<root>-pre-rollback-<time>`, and added to the tree as a new root
+    /// snapshot; its ID is returned so the rollback can be undone with
+    /// another one. A writable copy of the target then replaces `root` by
+    /// renaming, which is atomic for anything opening `root` afterwards.
+    ///
+    /// The replaced subvolume is deleted if it is not in use. A booted root
+    /// is, so its files stay reachable as `<root>.replaced` and the running
+    /// system keeps using them until the next boot.
+    pub fn rollback_to(&mut self, id: &Uuid, root: &Path) -> Result<Uuid, SnapshotTreeError> {
+        let target = self.get_snapshot(id)
+            .ok_or(SnapshotTreeError::SnapshotNotFound(*id))?;
+        if target.path == root {
+            return Err(SnapshotTreeError::InvalidRelationship);
+        }
+        let (Some(dir), Some(name)) = (root.parent(), root.file_name()) else {
+            return Err(SnapshotTreeError::InvalidPath(root.display().to_string()));
+        };
+        let name = name.to_string_lossy();
+        let target_path = target.path.clone();
+        let system_version = target.system_version.clone();
+
+        // Clear what an interrupted rollback left behind before recording anything
+        let staging = dir.join(format!("{}{}", name, ROLLBACK_STAGING_SUFFIX));
+        if staging.exists() {
+            crate::sys::btrfs::delete_subvolume(&staging, true)?;
+        }
+        let replaced = dir.join(format!("{}{}", name, ROLLBACK_REPLACED_SUFFIX));
+        if replaced.exists() {
+            crate::sys::btrfs::delete_subvolume(&replaced, true)?;
+        }
+
+        // Keep the current state, so the rollback can be reverted
+        let stamp = Utc::now().format("%Y%m%d%H%M%S");
+        let pre_name = format!("{}-pre-rollback-{}", name, stamp);
+        let pre_path = dir.join(&pre_name);
+        crate::sys::btrfs::snapshot(root, &pre_path, true)?;
+        let pre = Snapshot::new(&pre_name, &pre_path, None)
+            .with_description(&format!("{} before rolling back to {}", root.display(), id))
+            .with_metadata("rollback_target", &id.to_string());
+        let pre_id = pre.id;
+        self.add_snapshot(pre)?;
+
+        crate::sys::btrfs::snapshot(&target_path, &staging, false)?;
+        std::fs::rename(root, &replaced)?;
+        if let Err(e) = std::fs::rename(&staging, root) {
+            std::fs::rename(&replaced, root)?;
+            if let Err(cleanup) = crate::sys::btrfs::delete_subvolume(&staging, true) {
+                log::warn!("Failed to remove {}: {}", staging.display(), cleanup);
+            }
+            return Err(e.into());
+        }
+        if let Err(e) = crate::sys::btrfs::delete_subvolume(&replaced, true) {
+            log::info!("Keeping {} until it is no longer in use: {}", replaced.display(), e);
+        }
+
+        log::info!(
+            "Rolled {} back to snapshot {} ({}), previous state saved as {}",
+            root.display(), id, system_version.as_deref().unwrap_or("unknown version"), pre_name
+        );
+        Ok(pre_id)
+    }
 }
 
 #[cfg(test)]
@@ -403,4 +489,30 @@ mod tests {
         assert!(tree.get_snapshot(&child_id).is_some());
         assert_eq!(tree.get_parent(&child_id).unwrap().id, root_id);
     }
+
+    #[test]
+    fn test_rollback_to_unknown_snapshot() {
+        let mut tree = SnapshotTree::new();
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let root_path = temp_dir.path().join("@");
+        std::fs::create_dir_all(&root_path).expect("Failed to create root dir");
+
+        let snapshot = Snapshot::new("base", &root_path, None);
+        let snapshot_id = snapshot.id;
+        tree.add_snapshot(snapshot).unwrap();
+
+        // Unknown targets are refused before the root is touched
+        let unknown = Uuid::new_v4();
+        assert!(matches!(
+            tree.rollback_to(&unknown, &root_path),
+            Err(SnapshotTreeError::SnapshotNotFound(id)) if id == unknown
+        ));
+        // So is rolling the root back to itself
+        assert!(matches!(
+            tree.rollback_to(&snapshot_id, &root_path),
+            Err(SnapshotTreeError::InvalidRelationship)
+        ));
+        assert_eq!(tree.get_all_snapshots().len(), 1);
+        assert!(root_path.exists());
+    }
 }
@@ -0,0 +1,104 @@
+//! Snapshot creation as a journaled transaction
+//!
+//! A [`SnapshotSet`] snapshots several subvolumes together, for example the
+//! root and home subvolumes before an update: either every snapshot is
+//! created or the ones already taken are deleted again.
+
+use std::path::PathBuf;
+
+use crate::fs::{self, BtrfsError, FsError};
+use crate::transaction::{Step, Transaction};
+
+/// Kind of snapshot transactions
+pub const KIND: &str = "snapshot-create";
+
+/// Snapshots created together
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotSet {
+    snapshots: Vec<(PathBuf, PathBuf)>,
+    read_only: bool,
+}
+
+impl SnapshotSet {
+    /// An empty set of read-only snapshots
+    pub fn new() -> Self {
+        Self {
+            snapshots: Vec::new(),
+            read_only: true,
+        }
+    }
+
+    /// Snapshot `source` to `dest`
+    pub fn with_snapshot<S: Into<PathBuf>, D: Into<PathBuf>>(mut self, source: S, dest: D) -> Self {
+        self.snapshots.push((source.into(), dest.into()));
+        self
+    }
+
+    /// Create writable snapshots
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.read_only = !writable;
+        self
+    }
+}
+
+impl Transaction for SnapshotSet {
+    type Error = BtrfsError;
+
+    fn kind(&self) -> &str {
+        KIND
+    }
+
+    /// One step per snapshot; fails if a destination already exists
+    fn plan(&mut self) -> Result<Vec<Step>, BtrfsError> {
+        self.snapshots
+            .iter()
+            .map(|(source, dest)| {
+                if !fs::is_subvolume(source) {
+                    return Err(BtrfsError::SubvolumeNotFound(source.clone()));
+                }
+                if dest.exists() {
+                    return Err(FsError::AlreadyExists(dest.clone()).into());
+                }
+                Ok(Step::new(dest.display().to_string())
+                    .with("source", source.display())
+                    .with("dest", dest.display()))
+            })
+            .collect()
+    }
+
+    fn execute(&mut self, step: &mut Step) -> Result<(), BtrfsError> {
+        let (Some(source), Some(dest)) = (step.get("source"), step.get("dest")) else {
+            return Err(BtrfsError::InvalidPath(format!("incomplete step {}", step.name)));
+        };
+        fs::create_snapshot(source, dest, self.read_only)
+    }
+
+    fn verify(&mut self) -> Result<(), BtrfsError> {
+        match self.snapshots.iter().find(|(_, dest)| !fs::is_subvolume(dest)) {
+            Some((_, dest)) => Err(BtrfsError::SubvolumeNotFound(dest.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Delete the snapshot; planning made sure it did not exist before
+    fn rollback(&mut self, step: &Step) -> Result<(), BtrfsError> {
+        match step.get("dest") {
+            Some(dest) if fs::is_subvolume(dest) => fs::delete_subvolume(dest),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_plan_rejects_plain_directory() {
+        let dir = tempdir().unwrap();
+        let mut set = SnapshotSet::new().with_snapshot(dir.path(), dir.path().join("snap"));
+        // A plain directory is not a subvolume
+        assert!(matches!(set.plan(), Err(BtrfsError::SubvolumeNotFound(_))));
+    }
+}
@@ -0,0 +1,326 @@
+//! Btrfs subvolumes through ioctls
+//!
+//! Creates, snapshots and deletes subvolumes with the ioctls `btrfs
+//! subvolume` itself uses. Subvolumes are recognised by their root inode
+//! number, so finding them needs neither root nor `btrfs subvolume list`.
+//! Without the `btrfs-ioctl` feature, or on kernels that reject an ioctl as
+//! unknown, the `btrfs` command is run instead. Creating, snapshotting and
+//! deleting go through the privileged helper when the kernel refuses them.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::debug;
+
+use super::stat::lstat;
+use crate::helper::Request;
+
+/// Inode number of the root directory of every subvolume
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+/// Longest name in `struct btrfs_ioctl_vol_args`
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+
+/// Longest name in `struct btrfs_ioctl_vol_args_v2`
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+
+/// Subvolume flag: read-only
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+/// `_IOW(0x94, 14, struct btrfs_ioctl_vol_args)`: create a subvolume
+const BTRFS_IOC_SUBVOL_CREATE: u64 = 0x5000_940e;
+
+/// `_IOW(0x94, 15, struct btrfs_ioctl_vol_args)`: delete a subvolume
+const BTRFS_IOC_SNAP_DESTROY: u64 = 0x5000_940f;
+
+/// `_IOW(0x94, 23, struct btrfs_ioctl_vol_args_v2)`: snapshot a subvolume
+const BTRFS_IOC_SNAP_CREATE_V2: u64 = 0x5000_9417;
+
+/// `_IOR(0x94, 25, __u64)`: read subvolume flags
+const BTRFS_IOC_SUBVOL_GETFLAGS: u64 = 0x8008_9419;
+
+/// `struct btrfs_ioctl_vol_args` in `linux/btrfs.h`
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+/// `struct btrfs_ioctl_vol_args_v2` in `linux/btrfs.h`, without qgroup inheritance
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+
+/// Whether `path` is on a Btrfs filesystem
+pub fn is_btrfs<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let c_path = super::c_path(path.as_ref())?;
+    // SAFETY: statfs only writes to the zeroed struct it is given
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type == libc::BTRFS_SUPER_MAGIC)
+}
+
+/// Whether `path` is the root of a Btrfs subvolume
+pub fn is_subvolume<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let path = path.as_ref();
+    let stat = lstat(path)?;
+    Ok(stat.is_dir() && stat.ino == BTRFS_FIRST_FREE_OBJECTID && is_btrfs(path)?)
+}
+
+/// Create an empty subvolume at `path`
+pub fn create_subvolume<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    super::escalate(create(path), || Request::CreateSubvolume { path: path.to_path_buf() })
+}
+
+/// Snapshot the subvolume `source` to `dest`
+pub fn snapshot<S: AsRef<Path>, D: AsRef<Path>>(source: S, dest: D, read_only: bool) -> io::Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    super::escalate(take_snapshot(source, dest, read_only), || Request::Snapshot {
+        source: source.to_path_buf(),
+        dest: dest.to_path_buf(),
+        read_only,
+    })
+}
+
+/// Delete the subvolume at `path`
+///
+/// With `recursive`, subvolumes nested in it are deleted first; otherwise
+/// the kernel refuses to delete a subvolume that still contains any.
+pub fn delete_subvolume<P: AsRef<Path>>(path: P, recursive: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    super::escalate(delete(path, recursive), || Request::DeleteSubvolume {
+        path: path.to_path_buf(),
+        recursive,
+    })
+}
+
+fn create(path: &Path) -> io::Result<()> {
+    if cfg!(feature = "btrfs-ioctl") {
+        match ioctl_create(path) {
+            Err(e) if super::unsupported(&e) => debug!("Subvolume ioctl unavailable: {}", e),
+            result => return result,
+        }
+    }
+    super::run(Command::new("btrfs").args(["subvolume", "create"]).arg(path)).map(drop)
+}
+
+fn take_snapshot(source: &Path, dest: &Path, read_only: bool) -> io::Result<()> {
+    if cfg!(feature = "btrfs-ioctl") {
+        match ioctl_snapshot(source, dest, read_only) {
+            Err(e) if super::unsupported(&e) => debug!("Snapshot ioctl unavailable: {}", e),
+            result => return result,
+        }
+    }
+    let mut command = Command::new("btrfs");
+    command.args(["subvolume", "snapshot"]);
+    if read_only {
+        command.arg("-r");
+    }
+    super::run(command.arg(source).arg(dest)).map(drop)
+}
+
+fn delete(path: &Path, recursive: bool) -> io::Result<()> {
+    if cfg!(feature = "btrfs-ioctl") {
+        if recursive {
+            for nested in nested_subvolumes(path)? {
+                delete(&path.join(nested), true)?;
+            }
+        }
+        match ioctl_destroy(path) {
+            Err(e) if super::unsupported(&e) => debug!("Subvolume ioctl unavailable: {}", e),
+            result => return result,
+        }
+    }
+    let mut command = Command::new("btrfs");
+    command.args(["subvolume", "delete"]);
+    if recursive {
+        command.arg("--recursive");
+    }
+    super::run(command.arg(path)).map(drop)
+}
+
+/// Whether the subvolume at `path` is read-only
+pub fn is_read_only<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let path = path.as_ref();
+    if cfg!(feature = "btrfs-ioctl") {
+        match ioctl_flags(path) {
+            Err(e) if super::unsupported(&e) => debug!("Subvolume ioctl unavailable: {}", e),
+            result => return result.map(|flags| flags & BTRFS_SUBVOL_RDONLY != 0),
+        }
+    }
+    let output = super::run(Command::new("btrfs").args(["property", "get", "-ts"]).arg(path).arg("ro"))?;
+    Ok(output.trim() == "ro=true")
+}
+
+/// Subvolumes directly below the subvolume at `path`, relative to it
+///
+/// Subvolumes nested in those are not included, nor are other filesystems
+/// mounted below `path`.
+pub fn nested_subvolumes<P: AsRef<Path>>(path: P) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !cfg!(feature = "btrfs-ioctl") {
+        let output = super::run(Command::new("btrfs").args(["subvolume", "list", "-o"]).arg(path))?;
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        return Ok(parse_subvolume_list(&output, &name));
+    }
+
+    let mut found = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        for entry in fs::read_dir(path.join(&relative))? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let stat = lstat(entry.path())?;
+            if stat.mount_root == Some(true) {
+                continue;
+            }
+            let child = relative.join(entry.file_name());
+            if stat.ino == BTRFS_FIRST_FREE_OBJECTID {
+                found.push(child);
+            } else {
+                dirs.push(child);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Paths below `parent` in `btrfs subvolume list` output
+fn parse_subvolume_list(output: &str, parent: &str) -> Vec<PathBuf> {
+    let prefix = format!("{}/", parent);
+    output
+        .lines()
+        .filter_map(|line| line.split_once(" path ").map(|(_, path)| path.trim()))
+        .map(|path| path.trim_start_matches("<FS_TREE>/"))
+        .filter_map(|path| path.strip_prefix(&prefix))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Open a directory for use as an ioctl target
+fn open_dir(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(path)
+}
+
+/// Open the parent of `path` and copy its last component into `name`
+fn parent_and_name(path: &Path, name: &mut [u8]) -> io::Result<File> {
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no parent directory", path.display()),
+        ));
+    };
+    let bytes = file_name.as_bytes();
+    // The name must stay NUL-terminated
+    if bytes.len() >= name.len() {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    name[..bytes.len()].copy_from_slice(bytes);
+    let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+    open_dir(parent)
+}
+
+fn ioctl_create(path: &Path) -> io::Result<()> {
+    let mut args = VolArgs {
+        fd: 0,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    let parent = parent_and_name(path, &mut args.name)?;
+    // SAFETY: the ioctl reads a `struct btrfs_ioctl_vol_args`, which `args`
+    // matches in layout, and it outlives the call
+    if unsafe { libc::ioctl(parent.as_raw_fd(), BTRFS_IOC_SUBVOL_CREATE as _, &args as *const VolArgs) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ioctl_snapshot(source: &Path, dest: &Path, read_only: bool) -> io::Result<()> {
+    let source = open_dir(source)?;
+    let mut args = VolArgsV2 {
+        fd: i64::from(source.as_raw_fd()),
+        transid: 0,
+        flags: if read_only { BTRFS_SUBVOL_RDONLY } else { 0 },
+        unused: [0; 4],
+        name: [0; BTRFS_SUBVOL_NAME_MAX + 1],
+    };
+    let parent = parent_and_name(dest, &mut args.name)?;
+    // SAFETY: as in `ioctl_create`, with `struct btrfs_ioctl_vol_args_v2`;
+    // the source stays open for the duration of the call
+    if unsafe { libc::ioctl(parent.as_raw_fd(), BTRFS_IOC_SNAP_CREATE_V2 as _, &args as *const VolArgsV2) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ioctl_destroy(path: &Path) -> io::Result<()> {
+    let mut args = VolArgs {
+        fd: 0,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    let parent = parent_and_name(path, &mut args.name)?;
+    // SAFETY: as in `ioctl_create`
+    if unsafe { libc::ioctl(parent.as_raw_fd(), BTRFS_IOC_SNAP_DESTROY as _, &args as *const VolArgs) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ioctl_flags(path: &Path) -> io::Result<u64> {
+    let dir = open_dir(path)?;
+    let mut flags: u64 = 0;
+    // SAFETY: the ioctl writes one `__u64` to `flags`
+    if unsafe { libc::ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_GETFLAGS as _, &mut flags as *mut u64) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ioctl_argument_layout() {
+        // The ioctl numbers encode these sizes
+        assert_eq!(std::mem::size_of::<VolArgs>(), 4096);
+        assert_eq!(std::mem::size_of::<VolArgsV2>(), 4096);
+    }
+
+    #[test]
+    fn test_plain_directory_is_not_a_subvolume() {
+        let dir = tempdir().unwrap();
+        assert!(!is_subvolume(dir.path()).unwrap());
+        assert!(is_subvolume(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_parse_subvolume_list() {
+        let output = "ID 258 gen 31 top level 257 path @interrupted/swap\n\
+                      ID 259 gen 32 top level 257 path <FS_TREE>/@interrupted/var/lib/machines\n\
+                      ID 260 gen 33 top level 5 path @home\n";
+        assert_eq!(
+            parse_subvolume_list(output, "@interrupted"),
+            vec![PathBuf::from("swap"), PathBuf::from("var/lib/machines")]
+        );
+    }
+}
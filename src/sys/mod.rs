@@ -0,0 +1,78 @@
+//! Native system interfaces
+//!
+//! Thin wrappers around the kernel and library interfaces that rastOS used
+//! to reach by spawning tools and parsing their locale-dependent output:
+//! Btrfs ioctls instead of `btrfs subvolume`, `statx(2)` instead of `stat`,
+//! and libalpm instead of `pacman -Q`. Each native path sits behind a
+//! feature flag (`btrfs-ioctl`, `statx`, `alpm`). Without it, or when the
+//! running kernel does not support the call, the tool is spawned as before.
+//!
+//! Everything here returns [`io::Error`], so callers keep their own error
+//! types through their existing `From<io::Error>` conversions.
+//!
+//! Operations that need root — subvolumes, mounts and user namespace ID
+//! maps — are retried through the privileged [`crate::helper`] when the
+//! kernel refuses them to an unprivileged caller.
+//!
+//! [`seccomp`] builds the system call filter of [`crate::sandbox`].
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+
+use log::debug;
+
+use crate::helper::{Client, HelperError, Request};
+
+pub mod btrfs;
+pub mod mount;
+pub mod pacman;
+pub mod seccomp;
+pub mod stat;
+pub mod userns;
+
+/// Whether a native call failed because the kernel lacks it, not because it was refused
+fn unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::ENOTTY | libc::EOPNOTSUPP))
+}
+
+/// Retry an operation the kernel refused through the privileged helper
+///
+/// Without a helper installed the original error is kept.
+fn escalate(result: io::Result<()>, request: impl FnOnce() -> Request) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !nix::unistd::Uid::effective().is_root() => {
+            let request = request();
+            debug!("Not permitted to {} ({}), asking the helper", request, e);
+            match Client::connect() {
+                Ok(mut client) => Ok(client.call(&request)?),
+                Err(HelperError::Unavailable) => Err(e),
+                Err(helper) => Err(helper.into()),
+            }
+        }
+        result => result,
+    }
+}
+
+/// `path` as a C string
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} contains a NUL byte", path.display()))
+    })
+}
+
+/// Run a fallback command and return its standard output
+fn run(command: &mut Command) -> io::Result<String> {
+    debug!("Running: {:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,71 @@
+//! Mounting filesystems through `mount(2)`
+//!
+//! Unlike `mount` and `umount`, these neither consult fstab nor print
+//! localized messages. Mounts never honour setuid bits or device files.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::debug;
+
+use crate::helper::Request;
+
+/// Mount the block device `source` on `target`, creating `target` if missing
+///
+/// `options` are the filesystem's own, such as `subvolid=5`.
+pub fn mount<S: AsRef<Path>, T: AsRef<Path>>(source: S, target: T, fstype: &str, options: Option<&str>) -> io::Result<()> {
+    let (source, target) = (source.as_ref(), target.as_ref());
+    super::escalate(mount_here(source, target, fstype, options), || Request::Mount {
+        source: source.to_path_buf(),
+        target: target.to_path_buf(),
+        fstype: fstype.to_string(),
+        options: options.map(str::to_string),
+    })
+}
+
+/// Unmount the filesystem mounted on `target`
+pub fn unmount<P: AsRef<Path>>(target: P) -> io::Result<()> {
+    let target = target.as_ref();
+    super::escalate(unmount_here(target), || Request::Unmount { target: target.to_path_buf() })
+}
+
+fn mount_here(source: &Path, target: &Path, fstype: &str, options: Option<&str>) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+    let c_source = super::c_path(source)?;
+    let c_target = super::c_path(target)?;
+    let c_fstype = c_string(fstype)?;
+    let c_options = options.map(c_string).transpose()?;
+    debug!("Mounting {} on {}", source.display(), target.display());
+    let data: *const libc::c_void = c_options.as_ref().map_or(std::ptr::null(), |options| options.as_ptr().cast());
+    // SAFETY: every pointer is a NUL-terminated string or null, alive for the call
+    let result = unsafe {
+        libc::mount(
+            c_source.as_ptr(),
+            c_target.as_ptr(),
+            c_fstype.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            data,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `s` as a C string
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} contains a NUL byte", s)))
+}
+
+fn unmount_here(target: &Path) -> io::Result<()> {
+    let c_target = super::c_path(target)?;
+    debug!("Unmounting {}", target.display());
+    // SAFETY: the path is a NUL-terminated string alive for the call
+    if unsafe { libc::umount2(c_target.as_ptr(), libc::UMOUNT_NOFOLLOW) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Package database queries through libalpm
+//!
+//! With the `alpm` feature the sync databases are read through libalpm,
+//! configured from the system's `pacman.conf`; otherwise `pacman -Q` is run
+//! against the same root.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+#[cfg(not(feature = "alpm"))]
+use std::process::Command;
+
+/// System pacman configuration
+#[cfg(feature = "alpm")]
+const PACMAN_CONF: &str = "/etc/pacman.conf";
+
+/// Names of installed packages found in no sync database, like `pacman -Qqm`
+pub fn foreign_packages<P: AsRef<Path>>(root: P) -> io::Result<HashSet<String>> {
+    let root = root.as_ref();
+
+    #[cfg(feature = "alpm")]
+    {
+        use alpm_utils::DbListExt;
+
+        let root = root.to_string_lossy();
+        let conf = pacmanconf::Config::with_opts(None, Some(PACMAN_CONF), Some(&root))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let handle = alpm_utils::alpm_with_conf(&conf).map_err(|e| io::Error::other(e.to_string()))?;
+        let syncdbs = handle.syncdbs();
+        Ok(handle
+            .localdb()
+            .pkgs()
+            .iter()
+            .filter(|pkg| syncdbs.pkg(pkg.name()).is_err())
+            .map(|pkg| pkg.name().to_string())
+            .collect())
+    }
+
+    #[cfg(not(feature = "alpm"))]
+    {
+        let output = Command::new("pacman")
+            .arg("--root")
+            .arg(root)
+            .arg("--dbpath")
+            .arg(root.join("var/lib/pacman"))
+            .arg("-Qqm")
+            .output()?;
+        // pacman exits non-zero when there are no foreign packages
+        if !output.status.success() && !output.stderr.is_empty() {
+            return Err(io::Error::other(format!(
+                "pacman -Qqm failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+}
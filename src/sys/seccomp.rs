@@ -0,0 +1,246 @@
+//! Seccomp filters
+//!
+//! A classic BPF program run by the kernel on every system call. The
+//! sandbox filter refuses the calls that reach past a process's namespaces
+//! — mounting, loading kernel modules, tracing other processes, creating
+//! namespaces, setting the clock, faking terminal input — and allows
+//! everything else.
+
+use std::io;
+
+/// `struct seccomp_data` offset of the system call number
+const NR_OFFSET: u32 = 0;
+
+/// `struct seccomp_data` offset of the audit architecture
+const ARCH_OFFSET: u32 = 4;
+
+/// `struct seccomp_data` offset of the low word of the first argument on little-endian machines
+const ARG0_OFFSET: u32 = 16;
+
+/// `struct seccomp_data` offset of the low word of the second argument on little-endian machines
+const ARG1_OFFSET: u32 = 24;
+
+/// `BPF_LD | BPF_W | BPF_ABS` in `linux/bpf_common.h`: load a word of the data
+const BPF_LD_W_ABS: u16 = 0x20;
+
+/// `BPF_JMP | BPF_JEQ | BPF_K`: jump if equal to a constant
+const BPF_JMP_JEQ_K: u16 = 0x15;
+
+/// `BPF_JMP | BPF_JGE | BPF_K`: jump if at least a constant
+#[cfg(target_arch = "x86_64")]
+const BPF_JMP_JGE_K: u16 = 0x35;
+
+/// `BPF_JMP | BPF_JSET | BPF_K`: jump if any bit of a constant is set
+const BPF_JMP_JSET_K: u16 = 0x45;
+
+/// `BPF_RET | BPF_K`: return a constant action
+const BPF_RET_K: u16 = 0x06;
+
+// Actions from `linux/seccomp.h`
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// `AUDIT_ARCH_X86_64` in `linux/audit.h`
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+
+/// `AUDIT_ARCH_AARCH64` in `linux/audit.h`
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// System call numbers with this bit set are the x32 ABI, which has numbers of its own
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// System calls the sandbox refuses with `EPERM`
+const DENIED: &[libc::c_long] = &[
+    // Changing mounts and the root directory
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_move_mount,
+    libc::SYS_open_tree,
+    libc::SYS_mount_setattr,
+    // Entering other namespaces
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    // Other processes' memory
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    // The kernel itself
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_syslog,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_userfaultfd,
+    // The kernel keyring
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    // Files by handle, which bypasses the mount namespace
+    libc::SYS_open_by_handle_at,
+    // The clock
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    // Port I/O
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+/// `ioctl` requests the sandbox refuses with `EPERM`
+///
+/// Both push input to a terminal, which the shell owning it then reads as
+/// typed by the user.
+const DENIED_IOCTLS: &[libc::c_ulong] = &[libc::TIOCSTI as libc::c_ulong, libc::TIOCLINUX as libc::c_ulong];
+
+/// `clone` flags creating namespaces
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWCGROUP;
+
+/// A compiled filter, ready to be installed
+#[derive(Clone)]
+pub struct Filter {
+    program: Vec<libc::sock_filter>,
+}
+
+impl Filter {
+    /// The sandbox filter
+    ///
+    /// Besides the [`DENIED`] calls, the [`DENIED_IOCTLS`] requests and
+    /// `clone` creating namespaces are refused, and `clone3`, whose flags a
+    /// filter cannot inspect, reports `ENOSYS` so the C library falls back
+    /// to `clone`. Calls of another architecture or ABI kill the process.
+    pub fn sandbox() -> Self {
+        let mut program = Vec::new();
+        let arch = AUDIT_ARCH.unwrap_or(0);
+        program.push(stmt(BPF_LD_W_ABS, ARCH_OFFSET));
+        program.push(jump(BPF_JMP_JEQ_K, arch, 1, 0));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+        program.push(stmt(BPF_LD_W_ABS, NR_OFFSET));
+        #[cfg(target_arch = "x86_64")]
+        {
+            program.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+            program.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+        }
+        let refuse = |program: &mut Vec<_>, nr: libc::c_long, errno: libc::c_int| {
+            program.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+            program.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | errno as u32));
+        };
+        for &nr in DENIED {
+            refuse(&mut program, nr, libc::EPERM);
+        }
+        refuse(&mut program, libc::SYS_clone3, libc::ENOSYS);
+        // The kernel truncates the request to an int, so the low word is all that counts
+        program.push(jump(BPF_JMP_JEQ_K, libc::SYS_ioctl as u32, 0, (DENIED_IOCTLS.len() * 2 + 2) as u8));
+        program.push(stmt(BPF_LD_W_ABS, ARG1_OFFSET));
+        for &request in DENIED_IOCTLS {
+            program.push(jump(BPF_JMP_JEQ_K, request as u32, 0, 1));
+            program.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        }
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        // Last, as it replaces the system call number with the flags
+        program.push(jump(BPF_JMP_JEQ_K, libc::SYS_clone as u32, 0, 3));
+        program.push(stmt(BPF_LD_W_ABS, ARG0_OFFSET));
+        program.push(jump(BPF_JMP_JSET_K, NAMESPACE_FLAGS as u32, 0, 1));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        program.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        Self { program }
+    }
+
+    /// Install the filter on the calling thread, for good
+    ///
+    /// Sets `no_new_privs` first, which installing a filter requires of
+    /// processes without `CAP_SYS_ADMIN`. Only makes system calls, so it is
+    /// safe between `fork` and `exec`.
+    pub fn install(&self) -> io::Result<()> {
+        if AUDIT_ARCH.is_none() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        let program = libc::sock_fprog {
+            len: self.program.len() as libc::c_ushort,
+            filter: self.program.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: prctl only reads `program`, which points to the live filter
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+                || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_filter_layout() {
+        let program = Filter::sandbox().program;
+        // The kernel rejects programs longer than BPF_MAXINSNS
+        assert!(program.len() < 4096);
+        assert_eq!(program[0].k, ARCH_OFFSET);
+        let last = program.last().unwrap();
+        assert_eq!((last.code, last.k), (BPF_RET_K, SECCOMP_RET_ALLOW));
+        // Every jump lands inside the program
+        for (i, insn) in program.iter().enumerate().filter(|(_, insn)| insn.code & 0x07 == 0x05) {
+            assert!(i + 1 + usize::from(insn.jt.max(insn.jf)) < program.len());
+        }
+    }
+
+    #[test]
+    fn test_sandbox_filter_refuses_tiocsti() {
+        let program = Filter::sandbox().program;
+        let ioctl = program
+            .iter()
+            .position(|insn| insn.code == BPF_JMP_JEQ_K && insn.k == libc::SYS_ioctl as u32)
+            .unwrap();
+        // Other system calls skip the whole ioctl check
+        let skipped = ioctl + 1 + usize::from(program[ioctl].jf);
+        assert_eq!((program[skipped - 1].code, program[skipped - 1].k), (BPF_RET_K, SECCOMP_RET_ALLOW));
+        assert_eq!(program[ioctl + 1].k, ARG1_OFFSET);
+        let tiocsti = program[ioctl..skipped]
+            .iter()
+            .position(|insn| insn.code == BPF_JMP_JEQ_K && insn.k == libc::TIOCSTI as u32)
+            .unwrap();
+        assert_eq!(program[ioctl + tiocsti + 1].k, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    }
+}
@@ -0,0 +1,139 @@
+//! File status through `statx(2)`
+//!
+//! Besides what `stat` reports, `statx` tells whether a directory is the
+//! root of a mount and when a file was created. Without the `statx` feature
+//! or on kernels older than 4.11, [`std::fs::symlink_metadata`] is used and
+//! the extra fields are left unknown.
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+/// `STATX_ATTR_MOUNT_ROOT` in `linux/stat.h`, Linux 5.8
+const STATX_ATTR_MOUNT_ROOT: u64 = 0x2000;
+
+/// Status of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// Inode number
+    pub ino: u64,
+    /// Device the inode lives on
+    pub dev: u64,
+    /// File type and permission bits
+    pub mode: u32,
+    /// Number of hard links
+    pub nlink: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// Bytes allocated on disk
+    pub allocated: u64,
+    /// Last modification
+    pub modified: SystemTime,
+    /// Creation, if the filesystem records it
+    pub created: Option<SystemTime>,
+    /// Whether the file is the root of a mount, if the kernel reports it
+    pub mount_root: Option<bool>,
+}
+
+impl Stat {
+    /// Whether the file is a directory
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+}
+
+/// Status of `path`, not following a final symbolic link
+pub fn lstat<P: AsRef<Path>>(path: P) -> io::Result<Stat> {
+    let path = path.as_ref();
+    if cfg!(feature = "statx") {
+        match statx(path) {
+            Err(e) if super::unsupported(&e) => debug!("statx unavailable: {}", e),
+            result => return result,
+        }
+    }
+    let meta = std::fs::symlink_metadata(path)?;
+    Ok(Stat {
+        ino: meta.ino(),
+        dev: meta.dev(),
+        mode: meta.mode(),
+        nlink: meta.nlink(),
+        size: meta.size(),
+        allocated: meta.blocks() * 512,
+        modified: meta.modified()?,
+        created: meta.created().ok(),
+        mount_root: None,
+    })
+}
+
+/// Whether `path` is a mount point
+///
+/// Falls back to comparing devices with the parent directory, which misses
+/// bind mounts within one filesystem.
+pub fn is_mount_point<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let path = path.as_ref();
+    let stat = lstat(path)?;
+    if let Some(mount_root) = stat.mount_root {
+        return Ok(mount_root);
+    }
+    match path.parent() {
+        Some(parent) => Ok(lstat(parent)?.dev != stat.dev),
+        None => Ok(true),
+    }
+}
+
+fn statx(path: &Path) -> io::Result<Stat> {
+    let c_path = super::c_path(path)?;
+    // SAFETY: statx only writes to the zeroed struct it is given
+    let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_BASIC_STATS | libc::STATX_BTIME,
+            &mut buf,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let time = |t: libc::statx_timestamp| UNIX_EPOCH + Duration::new(t.tv_sec as u64, t.tv_nsec);
+    Ok(Stat {
+        ino: buf.stx_ino,
+        dev: libc::makedev(buf.stx_dev_major, buf.stx_dev_minor),
+        mode: u32::from(buf.stx_mode),
+        nlink: u64::from(buf.stx_nlink),
+        size: buf.stx_size,
+        allocated: buf.stx_blocks * 512,
+        modified: time(buf.stx_mtime),
+        created: (buf.stx_mask & libc::STATX_BTIME != 0).then(|| time(buf.stx_btime)),
+        mount_root: (buf.stx_attributes_mask & STATX_ATTR_MOUNT_ROOT != 0)
+            .then_some(buf.stx_attributes & STATX_ATTR_MOUNT_ROOT != 0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_lstat_matches_metadata() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"hello").unwrap();
+
+        let stat = lstat(&file).unwrap();
+        let meta = std::fs::symlink_metadata(&file).unwrap();
+        assert_eq!(stat.ino, meta.ino());
+        assert_eq!(stat.dev, meta.dev());
+        assert_eq!(stat.size, 5);
+        assert!(!stat.is_dir());
+        assert!(lstat(dir.path()).unwrap().is_dir());
+        assert!(!is_mount_point(dir.path()).unwrap());
+    }
+}
@@ -0,0 +1,55 @@
+//! ID maps of user namespaces
+//!
+//! A process may only map its own user and group ID into a user namespace.
+//! Mapping the ranges `/etc/subuid` and `/etc/subgid` delegate to a user,
+//! as `newuidmap` and `newgidmap` do, goes through the privileged helper.
+//!
+//! Unless every group in the map was delegated through `/etc/subgid`,
+//! `setgroups(2)` is denied in the namespace first: otherwise it could drop
+//! a group that denies access to files (CVE-2018-7169).
+
+use std::fs;
+use std::io;
+
+use crate::helper::{IdMap, Request};
+
+/// Write the user and group ID maps of the user namespace of process `pid`
+///
+/// Either map may be empty to leave it unwritten. Each map can be written
+/// once, before the namespace runs anything that depends on it.
+pub fn write_id_maps(pid: u32, uid_map: &[IdMap], gid_map: &[IdMap]) -> io::Result<()> {
+    // Unprivileged processes only map their own group, which is never delegated
+    let setgroups = nix::unistd::Uid::effective().is_root();
+    super::escalate(write_here(pid, uid_map, gid_map, setgroups), || Request::MapIds {
+        pid,
+        uid_map: uid_map.to_vec(),
+        gid_map: gid_map.to_vec(),
+    })
+}
+
+/// Write the ID maps as root, keeping `setgroups(2)` only if `setgroups` is set
+///
+/// For the helper, which has checked the maps against the delegated ranges.
+pub fn write_id_maps_privileged(pid: u32, uid_map: &[IdMap], gid_map: &[IdMap], setgroups: bool) -> io::Result<()> {
+    write_here(pid, uid_map, gid_map, setgroups)
+}
+
+fn write_here(pid: u32, uid_map: &[IdMap], gid_map: &[IdMap], setgroups: bool) -> io::Result<()> {
+    if !uid_map.is_empty() {
+        fs::write(format!("/proc/{}/uid_map", pid), format_map(uid_map))?;
+    }
+    if !gid_map.is_empty() {
+        if !setgroups {
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+        }
+        fs::write(format!("/proc/{}/gid_map", pid), format_map(gid_map))?;
+    }
+    Ok(())
+}
+
+/// The lines of `/proc/<pid>/uid_map`, which must be written in one go
+fn format_map(map: &[IdMap]) -> String {
+    map.iter()
+        .map(|range| format!("{} {} {}\n", range.inside, range.outside, range.count))
+        .collect()
+}
@@ -0,0 +1,642 @@
+//! CLI interface for system commands
+
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::system::config::{ConfigEngine, MachineManifest, ManifestDiff, MANIFEST_PATH};
+use crate::system::doctor::{Doctor, DoctorReport, Severity};
+use crate::kernel::BootEntryConfig;
+use crate::output::{ExitCode, Output, OutputArgs};
+use crate::system::mac::{Enforcement, Mac};
+use crate::system::network::{Network, NetworkConfig, NETWORK_CONFIG_PATH};
+use crate::system::power::{Power, PowerAction};
+use crate::system::reset::FactoryReset;
+use crate::system::update::{UpdateConfig, UpdateOptions, Updater};
+
+/// System commands
+#[derive(Debug, Parser)]
+#[command(name = "rast", about = "Manage a rastOS system")]
+pub struct RastCli {
+    /// Command to run
+    #[command(subcommand)]
+    pub command: RastCommand,
+
+    /// System root to operate on
+    #[arg(short, long, default_value = "/")]
+    pub root: PathBuf,
+
+    /// Output format
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// System subcommands
+#[derive(Debug, Subcommand)]
+pub enum RastCommand {
+    /// Check the health of the system
+    Doctor(DoctorArgs),
+
+    /// Configure networking
+    #[command(subcommand)]
+    Network(NetworkCommand),
+
+    /// Update the system into a new root, keeping the old one for rollback
+    #[command(subcommand)]
+    Update(UpdateCommand),
+
+    /// Reboot, power off or suspend, honouring inhibitor locks
+    #[command(subcommand)]
+    Power(PowerCommand),
+
+    /// Apply the machine manifest as numbered generations
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Reset the system to the state it was installed in
+    Reset(ResetArgs),
+
+    /// Manage SELinux or AppArmor
+    #[command(subcommand)]
+    Mac(MacCommand),
+}
+
+/// Arguments for `rast doctor`
+#[derive(Debug, Args)]
+pub struct DoctorArgs {
+    /// Show passed checks too
+    #[arg(short, long)]
+    pub all: bool,
+
+    /// Days after which the newest snapshot counts as stale
+    #[arg(long, default_value_t = 7)]
+    pub snapshot_days: u64,
+
+    /// Days after which the last backup counts as overdue
+    #[arg(long, default_value_t = 7)]
+    pub backup_days: u64,
+}
+
+/// Arguments for `rast reset`
+#[derive(Debug, Args)]
+pub struct ResetArgs {
+    /// Keep home directories
+    #[arg(long)]
+    pub keep_home: bool,
+
+    /// Delete the old systems earlier resets kept instead of resetting
+    #[arg(long, conflicts_with = "keep_home")]
+    pub purge: bool,
+
+    /// Confirm the reset
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Boot partition with the systemd-boot entries
+    #[arg(long, default_value = "/boot")]
+    pub boot_dir: PathBuf,
+}
+
+/// Mandatory access control subcommands
+#[derive(Debug, Subcommand)]
+pub enum MacCommand {
+    /// Show the active module, its mode and the loaded profiles
+    Status,
+
+    /// Deny policy violations
+    Enforce {
+        /// Keep the mode after a reboot
+        #[arg(long)]
+        persist: bool,
+    },
+
+    /// Only log policy violations
+    Permissive {
+        /// Keep the mode after a reboot
+        #[arg(long)]
+        persist: bool,
+    },
+
+    /// List the profiles shipped with rastOS
+    Profiles,
+
+    /// Install and load a shipped profile
+    Install {
+        /// Profile name
+        name: String,
+    },
+
+    /// Unload and remove a profile
+    Remove {
+        /// Profile name
+        name: String,
+    },
+}
+
+/// Network subcommands
+#[derive(Debug, Subcommand)]
+pub enum NetworkCommand {
+    /// Render the network configuration and apply it, rolling back on loss of connectivity
+    Apply {
+        /// Network configuration file
+        #[arg(short, long, default_value = NETWORK_CONFIG_PATH)]
+        config: PathBuf,
+
+        /// Print the generated files instead of applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Show the state of every link
+    Status,
+}
+
+/// Update subcommands
+#[derive(Debug, Subcommand)]
+pub enum UpdateCommand {
+    /// Upgrade into a new root and boot it once on the next boot
+    Stage {
+        /// Kernel package to install into the new root
+        #[arg(long)]
+        kernel_package: Option<PathBuf>,
+
+        /// Upgrade from the package cache without downloading
+        #[arg(long)]
+        offline: bool,
+
+        /// Command run inside the new root to validate it (can be repeated)
+        #[arg(long = "validate")]
+        validate: Vec<String>,
+
+        /// Boot partition with the systemd-boot entries
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+    },
+
+    /// Make the booted root the default after a successful boot
+    Commit {
+        /// Boot partition with the systemd-boot entries
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+    },
+
+    /// Boot the previous root by default
+    Rollback {
+        /// Boot partition with the systemd-boot entries
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+    },
+
+    /// Show the booted root and the deployments
+    Status {
+        /// Boot partition with the systemd-boot entries
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+    },
+}
+
+/// Configuration subcommands
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Apply the manifest as a new generation
+    Apply {
+        /// Machine manifest
+        #[arg(short, long, default_value = MANIFEST_PATH)]
+        manifest: PathBuf,
+
+        /// Do not snapshot the root first
+        #[arg(long)]
+        no_snapshot: bool,
+    },
+
+    /// Show what applying the manifest would change
+    Diff {
+        /// Machine manifest
+        #[arg(short, long, default_value = MANIFEST_PATH)]
+        manifest: PathBuf,
+    },
+
+    /// Go back to an earlier generation
+    Rollback {
+        /// Generation to go back to; defaults to the one before the current
+        generation: Option<u32>,
+    },
+
+    /// List the generations
+    List,
+}
+
+/// Power subcommands
+#[derive(Debug, Subcommand)]
+pub enum PowerCommand {
+    /// Restart the machine
+    Reboot {
+        /// Only reboot if an update is waiting for one
+        #[arg(long)]
+        if_pending: bool,
+
+        /// Ignore inhibitor locks
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Turn the machine off
+    Poweroff {
+        /// Ignore inhibitor locks
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Suspend to RAM
+    Suspend {
+        /// Ignore inhibitor locks
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Suspend to disk
+    Hibernate {
+        /// Ignore inhibitor locks
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// List inhibitor locks
+    Inhibitors,
+
+    /// Reboot daily at a time until the machine has rebooted
+    Schedule {
+        /// Time of day as HH:MM
+        time: String,
+
+        /// Only reboot if an update is waiting for one
+        #[arg(long)]
+        if_pending: bool,
+    },
+
+    /// Cancel a scheduled reboot
+    Cancel,
+
+    /// Boot a Btrfs snapshot once, then reboot
+    RebootInto {
+        /// Subvolume of the snapshot, relative to the Btrfs top level
+        subvolume: String,
+
+        /// Boot partition with the systemd-boot entries
+        #[arg(long, default_value = "/boot")]
+        boot_dir: PathBuf,
+
+        /// Ignore inhibitor locks
+        #[arg(short, long)]
+        force: bool,
+    },
+}
+
+impl RastCommand {
+    /// Read the machine manifest or network configuration from `path`
+    pub fn use_config(&mut self, path: &Path) {
+        match self {
+            RastCommand::Network(NetworkCommand::Apply { config, .. }) => *config = path.to_path_buf(),
+            RastCommand::Config(ConfigCommand::Apply { manifest, .. } | ConfigCommand::Diff { manifest, .. }) => {
+                *manifest = path.to_path_buf()
+            }
+            _ => {}
+        }
+    }
+}
+
+impl RastCli {
+    /// Execute the command, returning the process exit code
+    pub fn execute(self) -> Result<ExitCode, Box<dyn std::error::Error>> {
+        let output = self.output.output();
+        match &self.command {
+            RastCommand::Doctor(args) => self.handle_doctor(args, output),
+            RastCommand::Network(command) => self.handle_network(command, output).map(|()| ExitCode::Success),
+            RastCommand::Update(command) => self.handle_update(command, output).map(|()| ExitCode::Success),
+            RastCommand::Power(command) => self.handle_power(command, output).map(|()| ExitCode::Success),
+            RastCommand::Config(command) => self.handle_config(command, output).map(|()| ExitCode::Success),
+            RastCommand::Reset(args) => self.handle_reset(args, output).map(|()| ExitCode::Success),
+            RastCommand::Mac(command) => self.handle_mac(command, output).map(|()| ExitCode::Success),
+        }
+    }
+
+    /// Handle the mandatory access control commands
+    fn handle_mac(&self, command: &MacCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        let mac = Mac::new().with_root(&self.root);
+
+        match command {
+            MacCommand::Status => {
+                let status = mac.status()?;
+                output.print(&status, || {
+                    if let (Some(lsm), Some(mode)) = (&status.lsm, &status.mode) {
+                        println!("{} is {}", lsm, mode);
+                        for profile in &status.profiles {
+                            println!("  {:<10} {}", profile.mode, profile.name);
+                        }
+                    } else {
+                        println!("Neither SELinux nor AppArmor is active");
+                    }
+                })?;
+            }
+            MacCommand::Enforce { persist } => {
+                mac.set_mode(Enforcement::Enforcing, *persist)?;
+                output.status("Enforcing");
+            }
+            MacCommand::Permissive { persist } => {
+                mac.set_mode(Enforcement::Permissive, *persist)?;
+                output.status("Permissive");
+            }
+            MacCommand::Profiles => {
+                let profiles = mac.available_profiles()?;
+                output.print(&profiles, || {
+                    for name in &profiles {
+                        println!("{}", name);
+                    }
+                })?;
+            }
+            MacCommand::Install { name } => {
+                mac.install_profile(name)?;
+                output.status(format!("Installed profile {}", name));
+            }
+            MacCommand::Remove { name } => {
+                mac.remove_profile(name)?;
+                output.status(format!("Removed profile {}", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the reset command
+    fn handle_reset(&self, args: &ResetArgs, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        if self.root != Path::new("/") {
+            return Err("a reset is only possible on the running system".into());
+        }
+        let reset = FactoryReset::new().with_boot_dir(&args.boot_dir).keep_home(args.keep_home);
+
+        if args.purge {
+            let purged = reset.purge()?;
+            output.print(&purged, || {
+                if purged.is_empty() {
+                    println!("Nothing to purge");
+                }
+                for name in &purged {
+                    println!("Deleted {}", name);
+                }
+            })?;
+            return Ok(());
+        }
+        if !args.yes {
+            let homes = if args.keep_home { "kept" } else { "discarded" };
+            return Err(format!(
+                "this resets the system to its installed state and home directories are {}; run again with --yes",
+                homes
+            )
+            .into());
+        }
+        let outcome = reset.reset()?;
+        output.print(&outcome, || {
+            println!("Old system kept as {}", outcome.archived.join(", "));
+            println!("Removed {} secret(s); the machine gets a new ID on the next boot", outcome.wiped.len());
+            if outcome.first_boot {
+                println!("First-boot provisioning runs again");
+            }
+        })?;
+        output.status("Reboot to start the reset system, then run `rast reset --purge`");
+        Ok(())
+    }
+
+    /// Handle the configuration commands
+    fn handle_config(&self, command: &ConfigCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        let engine = ConfigEngine::new().with_root(&self.root);
+
+        match command {
+            ConfigCommand::Apply { manifest, no_snapshot } => {
+                let manifest = MachineManifest::from_file(manifest)?;
+                let generation = engine.with_snapshots(!*no_snapshot).apply(&manifest)?;
+                output.print(&generation, || println!("Generation {} is current", generation.number))?;
+            }
+            ConfigCommand::Diff { manifest } => {
+                let diff = engine.diff(&MachineManifest::from_file(manifest)?)?;
+                output.print(&diff, || print_diff(&diff))?;
+            }
+            ConfigCommand::Rollback { generation } => {
+                let generation = engine.rollback(*generation)?;
+                output.print(&generation, || println!("Generation {} is current", generation.number))?;
+            }
+            ConfigCommand::List => {
+                let generations = engine.generations()?;
+                let current = engine.current()?;
+                output.table(&generations, &["", "GENERATION", "CREATED", "SNAPSHOT"], |generation| {
+                    vec![
+                        if Some(generation.number) == current { "*" } else { "" }.to_string(),
+                        generation.number.to_string(),
+                        generation.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                        generation.snapshot.as_deref().map_or("-".into(), |p| p.display().to_string()),
+                    ]
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the power commands
+    fn handle_power(&self, command: &PowerCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        match command {
+            PowerCommand::Reboot { if_pending, force } => {
+                let power = Power::new().force(*force);
+                if *if_pending && !power.reboot_pending() {
+                    output.status("No reboot pending");
+                    return Ok(());
+                }
+                power.reboot()?;
+            }
+            PowerCommand::Poweroff { force } => Power::new().force(*force).perform(PowerAction::Poweroff)?,
+            PowerCommand::Suspend { force } => Power::new().force(*force).perform(PowerAction::Suspend)?,
+            PowerCommand::Hibernate { force } => Power::new().force(*force).perform(PowerAction::Hibernate)?,
+            PowerCommand::Inhibitors => {
+                let inhibitors = Power::new().inhibitors()?;
+                output.print(&inhibitors, || {
+                    if inhibitors.is_empty() {
+                        println!("No inhibitor locks");
+                    } else {
+                        for inhibitor in &inhibitors {
+                            println!("{:<6} {:<16} {}", inhibitor.mode, inhibitor.what.join(":"), inhibitor);
+                        }
+                    }
+                })?;
+            }
+            PowerCommand::Schedule { time, if_pending } => {
+                Power::new().schedule_reboot(time, *if_pending)?;
+                let condition = if *if_pending { " if an update is pending" } else { "" };
+                output.status(format!("Rebooting at {}{}", time, condition));
+            }
+            PowerCommand::Cancel => {
+                Power::new().cancel_scheduled_reboot()?;
+                output.status("Scheduled reboot cancelled");
+            }
+            PowerCommand::RebootInto { subvolume, boot_dir, force } => {
+                Power::new()
+                    .force(*force)
+                    .with_boot_config(BootEntryConfig::default().with_boot_dir(boot_dir))
+                    .reboot_into_snapshot(subvolume)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the update commands
+    fn handle_update(&self, command: &UpdateCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        match command {
+            UpdateCommand::Stage { kernel_package, offline, validate, boot_dir } => {
+                let mut config = UpdateConfig::default().with_boot_dir(boot_dir);
+                config.validate = validate.clone();
+                let mut options = UpdateOptions::default().offline(*offline);
+                if let Some(package) = kernel_package {
+                    options = options.with_kernel_package(package);
+                }
+                let deployment = Updater::new(config).stage(&options)?;
+                output.print(&deployment, || {
+                    println!(
+                        "Staged {} with kernel {}; reboot to try it, then run `rast update commit`",
+                        deployment.id, deployment.kernel
+                    )
+                })?;
+            }
+            UpdateCommand::Commit { boot_dir } => {
+                let deployment = Updater::new(UpdateConfig::default().with_boot_dir(boot_dir)).commit()?;
+                output.print(&deployment, || println!("Deployment {} is now the default", deployment.id))?;
+            }
+            UpdateCommand::Rollback { boot_dir } => {
+                let entry = Updater::new(UpdateConfig::default().with_boot_dir(boot_dir)).rollback()?;
+                output.status(format!("Default boot entry is now {}; reboot to use it", entry));
+            }
+            UpdateCommand::Status { boot_dir } => {
+                let status = Updater::new(UpdateConfig::default().with_boot_dir(boot_dir)).status()?;
+                output.print(&status, || {
+                    println!("Booted: {}", status.booted);
+                    println!("Default entry: {}", status.default_entry.as_deref().unwrap_or("-"));
+                    for deployment in &status.deployments {
+                        let marker = if deployment.subvolume == status.booted { "*" } else { " " };
+                        println!(
+                            "{} {}  kernel {}  from {}",
+                            marker, deployment.id, deployment.kernel, deployment.parent
+                        );
+                    }
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the network commands
+    fn handle_network(&self, command: &NetworkCommand, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new().with_root(&self.root);
+
+        match command {
+            NetworkCommand::Apply { config, dry_run } => {
+                let config = NetworkConfig::from_file(config)?;
+                if *dry_run {
+                    let files = config.render()?;
+                    output.print(&files, || {
+                        for (path, content) in &files {
+                            println!("# {}", self.root.join(path).display());
+                            println!("{}", content);
+                        }
+                    })?;
+                } else {
+                    network.apply(&config)?;
+                    output.status("Applied network configuration");
+                }
+            }
+            NetworkCommand::Status => {
+                let links = network.links()?;
+                output.table(&links, &["IDX", "LINK", "TYPE", "OPERATIONAL", "SETUP"], |link| {
+                    vec![
+                        link.index.to_string(),
+                        link.name.to_string(),
+                        link.kind.to_string(),
+                        link.operational.to_string(),
+                        link.setup.to_string(),
+                    ]
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the doctor command; exits with [`ExitCode::Warning`] on warnings and
+    /// [`ExitCode::Critical`] on critical findings
+    fn handle_doctor(&self, args: &DoctorArgs, output: Output) -> Result<ExitCode, Box<dyn std::error::Error>> {
+        let report = Doctor::new()
+            .with_root(&self.root)
+            .with_snapshot_max_age(Duration::from_secs(args.snapshot_days * 86400))
+            .with_backup_max_age(Duration::from_secs(args.backup_days * 86400))
+            .run();
+
+        output.print(&report, || print_report(&report, args.all))?;
+
+        Ok(match report.worst() {
+            Severity::Critical => ExitCode::Critical,
+            Severity::Warning => ExitCode::Warning,
+            _ => ExitCode::Success,
+        })
+    }
+}
+
+fn print_diff(diff: &ManifestDiff) {
+    if diff.is_empty() {
+        println!("No changes");
+        return;
+    }
+    for package in &diff.packages_added {
+        println!("+ package {}", package);
+    }
+    for package in &diff.packages_removed {
+        println!("- package {}", package);
+    }
+    for (unit, state) in &diff.services {
+        println!("~ service {} -> {}", unit, state);
+    }
+    for user in &diff.users_changed {
+        println!("~ user {}", user);
+    }
+    for user in &diff.users_removed {
+        println!("- user {} (locked)", user);
+    }
+    if diff.sysctl_changed {
+        println!("~ sysctl");
+    }
+    for path in &diff.files_changed {
+        println!("~ file {}", path.display());
+    }
+    for path in &diff.files_removed {
+        println!("- file {}", path.display());
+    }
+}
+
+fn print_report(report: &DoctorReport, all: bool) {
+    if let Some(hardware) = &report.hardware {
+        println!(
+            "{} ({} threads), {} MiB memory",
+            hardware.cpu.model,
+            hardware.cpu.threads,
+            hardware.memory.total_kib / 1024
+        );
+        println!();
+    }
+
+    let findings: Vec<_> = if all { report.findings.iter().collect() } else { report.problems() };
+    for finding in &findings {
+        println!("[{:>8}] {}: {}", finding.severity, finding.check, finding.summary);
+        if let Some(fix) = &finding.fix {
+            println!("           fix: {}", fix);
+        }
+    }
+
+    if report.passed() {
+        println!("No problems found");
+    } else {
+        println!();
+        println!("{} problem(s) found", report.problems().len());
+    }
+}
@@ -0,0 +1,743 @@
+//! Declarative system configuration in generations
+//!
+//! A single machine manifest, usually `/etc/rast/machine.toml`, declares
+//! the packages, services, users, sysctls and files of a machine:
+//!
+//! ```toml
+//! packages = ["vim", "openssh"]
+//!
+//! [services]
+//! enable = ["sshd.service"]
+//! mask = ["bluetooth.service"]
+//!
+//! [[user]]
+//! name = "alice"
+//! groups = ["wheel"]
+//!
+//! [sysctl]
+//! "vm.swappiness" = "10"
+//!
+//! [[file]]
+//! path = "/etc/motd"
+//! content = "Managed by rastOS\n"
+//! ```
+//!
+//! Applying a manifest creates a numbered [`Generation`]. Before anything
+//! changes the root is snapshotted read-only, so the state before every
+//! generation can still be booted with `rast power reboot-into`. Only the
+//! differences to the current generation are applied, and things dropped
+//! from the manifest are undone: packages removed, services disabled, files
+//! deleted and users locked (their files are kept). If a step fails the
+//! previous generation's manifest is applied again.
+//!
+//! Rolling back applies the manifest of an earlier generation and makes it
+//! current again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::fs::{atomic_write_with_options, create_snapshot, is_subvolume, WriteOptions};
+use crate::installer::{InstallerError, UserConfig};
+
+/// Default location of the machine manifest
+pub const MANIFEST_PATH: &str = "/etc/rast/machine.toml";
+
+/// Where generations are recorded, relative to the root
+const GENERATIONS_DIR: &str = "var/lib/rast/generations";
+
+/// Read-only snapshots taken before each generation, relative to the root
+const SNAPSHOTS_DIR: &str = ".snapshots/rast-generations";
+
+/// sysctl drop-in owned by the manifest
+const SYSCTL_FILE: &str = "etc/sysctl.d/90-rast-manifest.conf";
+
+/// Error type for declarative configuration
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Manifest that cannot be parsed
+    #[error("Failed to parse manifest: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    /// Manifest that cannot be serialized
+    #[error("Failed to serialize manifest: {0}")]
+    Serialize(#[from] toml::ser::Error),
+
+    /// Generation metadata that cannot be read or written
+    #[error("Invalid generation metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+
+    /// Manifest that is inconsistent
+    #[error("Invalid manifest: {0}")]
+    Invalid(String),
+
+    /// Account error
+    #[error("Account error: {0}")]
+    Account(#[from] InstallerError),
+
+    /// A step failed while applying
+    #[error("Applying {step} failed: {message}{}", restored(.rolled_back))]
+    Step {
+        /// The step that failed
+        step: &'static str,
+        /// What went wrong
+        message: String,
+        /// Whether the previous generation was applied again
+        rolled_back: bool,
+    },
+
+    /// No generation with that number
+    #[error("Unknown generation {0}")]
+    UnknownGeneration(u32),
+}
+
+fn restored(rolled_back: &bool) -> &'static str {
+    if *rolled_back { "; previous generation restored" } else { "" }
+}
+
+/// Result type for declarative configuration
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+/// systemd units to enable, disable or mask
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Services {
+    /// Units enabled and started
+    pub enable: BTreeSet<String>,
+    /// Units disabled and stopped
+    pub disable: BTreeSet<String>,
+    /// Units masked so nothing can start them
+    pub mask: BTreeSet<String>,
+}
+
+/// A file whose content the manifest owns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManagedFile {
+    /// Absolute path
+    pub path: PathBuf,
+    /// Content
+    pub content: String,
+    /// Permission bits
+    #[serde(default = "default_mode")]
+    pub mode: u32,
+}
+
+fn default_mode() -> u32 {
+    0o644
+}
+
+/// Everything a machine should look like
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MachineManifest {
+    /// Packages that must be installed
+    pub packages: BTreeSet<String>,
+
+    /// systemd units
+    pub services: Services,
+
+    /// Users that must exist
+    #[serde(rename = "user")]
+    pub users: Vec<UserConfig>,
+
+    /// Kernel parameters
+    pub sysctl: BTreeMap<String, String>,
+
+    /// Files with fixed content
+    #[serde(rename = "file")]
+    pub files: Vec<ManagedFile>,
+}
+
+impl MachineManifest {
+    /// Load a manifest from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let manifest: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Check users, units, sysctl keys and file paths
+    pub fn validate(&self) -> Result<()> {
+        for user in &self.users {
+            user.validate()?;
+        }
+        let s = &self.services;
+        if let Some(unit) = s.enable.iter().find(|u| s.disable.contains(*u) || s.mask.contains(*u)) {
+            return Err(ConfigError::Invalid(format!("{} is both enabled and disabled", unit)));
+        }
+        if let Some(key) = self
+            .sysctl
+            .keys()
+            .find(|k| k.is_empty() || !k.chars().all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c)))
+        {
+            return Err(ConfigError::Invalid(format!("invalid sysctl key '{}'", key)));
+        }
+        let mut paths = BTreeSet::new();
+        for file in &self.files {
+            if !file.path.is_absolute() || file.path.components().any(|c| c == std::path::Component::ParentDir) {
+                return Err(ConfigError::Invalid(format!("{} is not a plain absolute path", file.path.display())));
+            }
+            if !paths.insert(&file.path) {
+                return Err(ConfigError::Invalid(format!("{} is declared twice", file.path.display())));
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA-256 of the serialized manifest
+    pub fn digest(&self) -> Result<String> {
+        let serialized = toml::to_string(self)?;
+        Ok(format!("{:x}", Sha256::digest(serialized.as_bytes())))
+    }
+
+    fn user_names(&self) -> BTreeSet<&str> {
+        self.users.iter().map(|u| u.name.as_str()).collect()
+    }
+}
+
+/// What applying one manifest over another changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestDiff {
+    /// Packages to install
+    pub packages_added: Vec<String>,
+    /// Packages to remove
+    pub packages_removed: Vec<String>,
+    /// Units whose state changes, with the new state (`enable`, `disable`, `mask` or `unmanaged`)
+    pub services: BTreeMap<String, String>,
+    /// Users to create or update
+    pub users_changed: Vec<String>,
+    /// Users to lock
+    pub users_removed: Vec<String>,
+    /// Whether the sysctl drop-in changes
+    pub sysctl_changed: bool,
+    /// Files to write
+    pub files_changed: Vec<PathBuf>,
+    /// Files to delete
+    pub files_removed: Vec<PathBuf>,
+}
+
+impl ManifestDiff {
+    /// Changes going from `old` to `new`
+    pub fn between(old: &MachineManifest, new: &MachineManifest) -> Self {
+        let mut services = BTreeMap::new();
+        let state = |s: &Services, unit: &str| {
+            if s.enable.contains(unit) {
+                "enable"
+            } else if s.disable.contains(unit) {
+                "disable"
+            } else if s.mask.contains(unit) {
+                "mask"
+            } else {
+                "unmanaged"
+            }
+        };
+        let units: BTreeSet<&String> = [&old.services, &new.services]
+            .iter()
+            .flat_map(|s| s.enable.iter().chain(&s.disable).chain(&s.mask))
+            .collect();
+        for unit in units {
+            let (before, after) = (state(&old.services, unit), state(&new.services, unit));
+            if before != after {
+                services.insert(unit.clone(), after.to_string());
+            }
+        }
+
+        let old_users = old.user_names();
+        let old_files: BTreeMap<_, _> = old.files.iter().map(|f| (&f.path, f)).collect();
+        let new_files: BTreeSet<_> = new.files.iter().map(|f| &f.path).collect();
+        Self {
+            packages_added: new.packages.difference(&old.packages).cloned().collect(),
+            packages_removed: old.packages.difference(&new.packages).cloned().collect(),
+            services,
+            users_changed: new
+                .users
+                .iter()
+                .filter(|u| !old.users.iter().any(|o| same_user(o, u)))
+                .map(|u| u.name.clone())
+                .collect(),
+            users_removed: old_users
+                .difference(&new.user_names())
+                .map(|name| name.to_string())
+                .collect(),
+            sysctl_changed: old.sysctl != new.sysctl,
+            files_changed: new
+                .files
+                .iter()
+                .filter(|f| old_files.get(&f.path) != Some(f))
+                .map(|f| f.path.clone())
+                .collect(),
+            files_removed: old
+                .files
+                .iter()
+                .filter(|f| !new_files.contains(&f.path))
+                .map(|f| f.path.clone())
+                .collect(),
+        }
+    }
+
+    /// Whether nothing changes
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Whether two user entries describe the same account
+///
+/// Plain-text passwords are not stored with a generation, so they only take
+/// effect when a user is created or otherwise changes; use `password_hash`
+/// to manage a password declaratively.
+fn same_user(a: &UserConfig, b: &UserConfig) -> bool {
+    a.name == b.name
+        && a.full_name == b.full_name
+        && a.groups == b.groups
+        && a.shell == b.shell
+        && a.password_hash == b.password_hash
+}
+
+/// A recorded application of a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// Generation number, counting from 1
+    pub number: u32,
+    /// When it was applied
+    pub created_at: DateTime<Utc>,
+    /// Digest of its manifest
+    pub digest: String,
+    /// Read-only snapshot of the root taken before it was applied
+    pub snapshot: Option<PathBuf>,
+    /// Generation that was current before a rollback to this one
+    #[serde(default)]
+    pub rollback_of: Option<u32>,
+}
+
+/// Applies machine manifests to the system at a root directory
+#[derive(Debug, Clone)]
+pub struct ConfigEngine {
+    root: PathBuf,
+    snapshots: bool,
+}
+
+impl Default for ConfigEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigEngine {
+    /// An engine for the running system
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::from("/"),
+            snapshots: true,
+        }
+    }
+
+    /// Configure the system installed at `root` instead
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Whether to snapshot the root before each generation
+    pub fn with_snapshots(mut self, snapshots: bool) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    fn is_live(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    /// Recorded generations, oldest first
+    pub fn generations(&self) -> Result<Vec<Generation>> {
+        let dir = self.root.join(GENERATIONS_DIR);
+        let mut generations = Vec::new();
+        if !dir.exists() {
+            return Ok(generations);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path().join("generation.json");
+            if path.exists() {
+                generations.push(serde_json::from_slice::<Generation>(&fs::read(path)?)?);
+            }
+        }
+        generations.sort_by_key(|g| g.number);
+        Ok(generations)
+    }
+
+    /// Number of the current generation
+    pub fn current(&self) -> Result<Option<u32>> {
+        match fs::read_to_string(self.root.join(GENERATIONS_DIR).join("current")) {
+            Ok(number) => Ok(number.trim().parse().ok()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Manifest of a generation
+    pub fn manifest(&self, number: u32) -> Result<MachineManifest> {
+        let path = self.generation_dir(number).join("manifest.toml");
+        if !path.exists() {
+            return Err(ConfigError::UnknownGeneration(number));
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// What applying `manifest` would change compared to the current generation
+    pub fn diff(&self, manifest: &MachineManifest) -> Result<ManifestDiff> {
+        Ok(ManifestDiff::between(&self.current_manifest()?, manifest))
+    }
+
+    /// Apply `manifest` as a new generation
+    ///
+    /// Returns the current generation unchanged when the manifest is the same.
+    pub fn apply(&self, manifest: &MachineManifest) -> Result<Generation> {
+        manifest.validate()?;
+        let current = self.current()?;
+        let previous = self.current_manifest()?;
+        let diff = ManifestDiff::between(&previous, manifest);
+        if let (Some(number), true) = (current, diff.is_empty()) {
+            info!("Manifest unchanged, staying at generation {}", number);
+            return self.generation(number);
+        }
+
+        let number = self.generations()?.last().map_or(1, |g| g.number + 1);
+        let snapshot = self.snapshot(number)?;
+        self.switch(&previous, manifest, &diff)?;
+        self.record(number, manifest, snapshot)
+    }
+
+    /// Go back to generation `target`, or the one before the current
+    pub fn rollback(&self, target: Option<u32>) -> Result<Generation> {
+        let current = self.current()?.ok_or(ConfigError::UnknownGeneration(0))?;
+        let target = match target {
+            Some(target) => target,
+            None => self
+                .generations()?
+                .iter()
+                .rev()
+                .map(|g| g.number)
+                .find(|&n| n < current)
+                .ok_or(ConfigError::UnknownGeneration(current.saturating_sub(1)))?,
+        };
+        let manifest = self.manifest(target)?;
+        let previous = self.manifest(current)?;
+        self.switch(&previous, &manifest, &ManifestDiff::between(&previous, &manifest))?;
+        self.set_current(target)?;
+        info!("Rolled back from generation {} to {}", current, target);
+        let mut generation = self.generation(target)?;
+        generation.rollback_of = Some(current);
+        Ok(generation)
+    }
+
+    fn generation(&self, number: u32) -> Result<Generation> {
+        let path = self.generation_dir(number).join("generation.json");
+        if !path.exists() {
+            return Err(ConfigError::UnknownGeneration(number));
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    fn current_manifest(&self) -> Result<MachineManifest> {
+        match self.current()? {
+            Some(number) => self.manifest(number),
+            None => Ok(MachineManifest::default()),
+        }
+    }
+
+    fn generation_dir(&self, number: u32) -> PathBuf {
+        self.root.join(GENERATIONS_DIR).join(number.to_string())
+    }
+
+    fn set_current(&self, number: u32) -> Result<()> {
+        atomic_write_with_options(
+            self.root.join(GENERATIONS_DIR).join("current"),
+            format!("{}\n", number),
+            &WriteOptions::default(),
+        )
+        .map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    /// Snapshot the root read-only, if it is a Btrfs subvolume
+    fn snapshot(&self, number: u32) -> Result<Option<PathBuf>> {
+        if !self.snapshots || !is_subvolume(&self.root) {
+            debug!("Not snapshotting {}", self.root.display());
+            return Ok(None);
+        }
+        let path = self.root.join(SNAPSHOTS_DIR).join(number.to_string());
+        fs::create_dir_all(self.root.join(SNAPSHOTS_DIR))?;
+        create_snapshot(&self.root, &path, true)
+            .map_err(|e| ConfigError::Step { step: "snapshot", message: e.to_string(), rolled_back: false })?;
+        info!("Snapshotted {} to {}", self.root.display(), path.display());
+        Ok(Some(path))
+    }
+
+    fn record(&self, number: u32, manifest: &MachineManifest, snapshot: Option<PathBuf>) -> Result<Generation> {
+        let generation = Generation {
+            number,
+            created_at: Utc::now(),
+            digest: manifest.digest()?,
+            snapshot,
+            rollback_of: None,
+        };
+        let dir = self.generation_dir(number);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("manifest.toml"), toml::to_string(manifest)?)?;
+        fs::write(dir.join("generation.json"), serde_json::to_vec_pretty(&generation)?)?;
+        self.set_current(number)?;
+        info!("Generation {} is current", number);
+        Ok(generation)
+    }
+
+    /// Apply `diff`, going back to `previous` if a step fails
+    fn switch(&self, previous: &MachineManifest, manifest: &MachineManifest, diff: &ManifestDiff) -> Result<()> {
+        let Err((step, message)) = self.apply_diff(manifest, diff) else {
+            return Ok(());
+        };
+        warn!("Applying {} failed: {}", step, message);
+        let undo = ManifestDiff::between(manifest, previous);
+        let rolled_back = match self.apply_diff(previous, &undo) {
+            Ok(()) => true,
+            Err((undo_step, e)) => {
+                warn!("Restoring the previous generation failed at {}: {}", undo_step, e);
+                false
+            }
+        };
+        Err(ConfigError::Step { step, message, rolled_back })
+    }
+
+    /// Apply every step, stopping at the first failure
+    fn apply_diff(
+        &self,
+        manifest: &MachineManifest,
+        diff: &ManifestDiff,
+    ) -> std::result::Result<(), (&'static str, String)> {
+        let step = |name: &'static str, result: Result<()>| result.map_err(|e| (name, e.to_string()));
+        step("files", self.apply_files(manifest, diff))?;
+        step("sysctl", self.apply_sysctl(manifest, diff))?;
+        step("packages", self.apply_packages(diff))?;
+        step("users", self.apply_users(manifest, diff))?;
+        step("services", self.apply_services(diff))?;
+        Ok(())
+    }
+
+    fn apply_files(&self, manifest: &MachineManifest, diff: &ManifestDiff) -> Result<()> {
+        for file in manifest.files.iter().filter(|f| diff.files_changed.contains(&f.path)) {
+            let target = self.path(&file.path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let options = WriteOptions::default().with_mode(file.mode);
+            atomic_write_with_options(&target, &file.content, &options).map_err(io::Error::from)?;
+        }
+        for path in &diff.files_removed {
+            match fs::remove_file(self.path(path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_sysctl(&self, manifest: &MachineManifest, diff: &ManifestDiff) -> Result<()> {
+        if !diff.sysctl_changed {
+            return Ok(());
+        }
+        let path = self.root.join(SYSCTL_FILE);
+        if manifest.sysctl.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        let mut content = String::from("# Generated from the rastOS machine manifest\n");
+        for (key, value) in &manifest.sysctl {
+            content.push_str(&format!("{} = {}\n", key, value));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        atomic_write_with_options(&path, content, &WriteOptions::default()).map_err(io::Error::from)?;
+        if self.is_live() {
+            run(Command::new("sysctl").arg("-p").arg(&path))?;
+        }
+        Ok(())
+    }
+
+    fn apply_packages(&self, diff: &ManifestDiff) -> Result<()> {
+        if !diff.packages_added.is_empty() {
+            info!("Installing {}", diff.packages_added.join(" "));
+            run(self.pacman().args(["-S", "--needed", "--noconfirm"]).args(&diff.packages_added))?;
+        }
+        if !diff.packages_removed.is_empty() {
+            info!("Removing {}", diff.packages_removed.join(" "));
+            run(self.pacman().args(["-Rns", "--noconfirm"]).args(&diff.packages_removed))?;
+        }
+        Ok(())
+    }
+
+    fn apply_users(&self, manifest: &MachineManifest, diff: &ManifestDiff) -> Result<()> {
+        let passwd = fs::read_to_string(self.root.join("etc/passwd")).unwrap_or_default();
+        let exists = |name: &str| passwd.lines().any(|line| line.split(':').next() == Some(name));
+
+        for user in manifest.users.iter().filter(|u| diff.users_changed.contains(&u.name)) {
+            let mut command = if exists(&user.name) {
+                info!("Updating user {}", user.name);
+                let mut command = Command::new("usermod");
+                // Users locked by an earlier generation come back
+                command.arg("--root").arg(&self.root).args(["--unlock", "--expiredate", ""]);
+                command
+            } else {
+                info!("Creating user {}", user.name);
+                let mut command = Command::new("useradd");
+                command.arg("--root").arg(&self.root).arg("--create-home");
+                command
+            };
+            command.args(["--shell", &user.shell]);
+            if !user.groups.is_empty() {
+                command.args(["--groups", &user.groups.join(",")]);
+            }
+            if let Some(full_name) = &user.full_name {
+                command.args(["--comment", full_name]);
+            }
+            if let Some(hash) = user.shadow_hash()? {
+                command.args(["--password", &hash]);
+            }
+            run(command.arg(&user.name))?;
+        }
+        for name in diff.users_removed.iter().filter(|name| exists(name)) {
+            info!("Locking user {}", name);
+            run(Command::new("usermod")
+                .arg("--root")
+                .arg(&self.root)
+                .args(["--lock", "--expiredate", "1", name]))?;
+        }
+        Ok(())
+    }
+
+    fn apply_services(&self, diff: &ManifestDiff) -> Result<()> {
+        for (unit, state) in &diff.services {
+            let verbs: &[&str] = match state.as_str() {
+                "enable" => &["unmask", "enable"],
+                "mask" => &["disable", "mask"],
+                // Units dropped from the manifest go back to their vendor default
+                "unmanaged" => &["unmask", "preset"],
+                _ => &["unmask", "disable"],
+            };
+            for verb in verbs {
+                let mut command = Command::new("systemctl");
+                if self.is_live() {
+                    command.arg(verb);
+                    if matches!(*verb, "enable" | "disable" | "mask") {
+                        command.arg("--now");
+                    }
+                } else {
+                    command.arg(format!("--root={}", self.root.display())).arg(verb);
+                }
+                run(command.arg(unit))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn pacman(&self) -> Command {
+        let mut command = Command::new("pacman");
+        if !self.is_live() {
+            command.arg("--root").arg(&self.root);
+        }
+        command
+    }
+
+    fn path(&self, absolute: &Path) -> PathBuf {
+        self.root.join(absolute.strip_prefix("/").unwrap_or(absolute))
+    }
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    debug!("Running: {:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(ConfigError::Invalid(format!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn manifest(motd: &str) -> MachineManifest {
+        toml::from_str(&format!(
+            "[sysctl]\n\"vm.swappiness\" = \"10\"\n\n[[file]]\npath = \"/etc/motd\"\ncontent = \"{}\"\n\n[[file]]\npath = \"/etc/issue\"\ncontent = \"rastOS\"\nmode = 0o600\n",
+            motd
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: MachineManifest = toml::from_str(
+            "packages = [\"vim\", \"nano\"]\n[services]\nenable = [\"sshd.service\"]\n[[user]]\nname = \"alice\"\n",
+        )
+        .unwrap();
+        let new: MachineManifest = toml::from_str(
+            "packages = [\"vim\", \"git\"]\n[services]\nmask = [\"sshd.service\"]\n[[user]]\nname = \"bob\"\n",
+        )
+        .unwrap();
+
+        let diff = ManifestDiff::between(&old, &new);
+        assert_eq!(diff.packages_added, ["git"]);
+        assert_eq!(diff.packages_removed, ["nano"]);
+        assert_eq!(diff.services["sshd.service"], "mask");
+        assert_eq!(diff.users_changed, ["bob"]);
+        assert_eq!(diff.users_removed, ["alice"]);
+        assert!(ManifestDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_generations() {
+        let root = tempdir().unwrap();
+        let engine = ConfigEngine::new().with_root(root.path()).with_snapshots(false);
+
+        let first = engine.apply(&manifest("hello")).unwrap();
+        assert_eq!(first.number, 1);
+        assert_eq!(fs::read_to_string(root.path().join("etc/motd")).unwrap(), "hello");
+        assert!(fs::read_to_string(root.path().join(SYSCTL_FILE)).unwrap().contains("vm.swappiness = 10\n"));
+        assert_eq!(engine.apply(&manifest("hello")).unwrap().number, 1);
+
+        let mut second = manifest("goodbye");
+        second.files.retain(|f| f.path != Path::new("/etc/issue"));
+        second.sysctl.clear();
+        assert_eq!(engine.apply(&second).unwrap().number, 2);
+        assert!(!root.path().join("etc/issue").exists());
+        assert!(!root.path().join(SYSCTL_FILE).exists());
+
+        let back = engine.rollback(None).unwrap();
+        assert_eq!((back.number, back.rollback_of), (1, Some(2)));
+        assert_eq!(engine.current().unwrap(), Some(1));
+        assert_eq!(fs::read_to_string(root.path().join("etc/motd")).unwrap(), "hello");
+        assert!(root.path().join("etc/issue").exists());
+        assert_eq!(engine.generations().unwrap().len(), 2);
+
+        // The next generation is numbered after the newest, not the current one
+        assert_eq!(engine.apply(&manifest("again")).unwrap().number, 3);
+    }
+}
@@ -0,0 +1,532 @@
+//! System health checks
+//!
+//! The doctor runs a battery of read-only checks against a running system and
+//! collects what it finds into a [`DoctorReport`]. Each [`Finding`] carries a
+//! severity and, where there is one, a command that fixes the problem:
+//!
+//! - failed systemd units
+//! - Btrfs device errors recorded by the kernel
+//! - filesystems running out of space
+//! - snapshots that have not been refreshed in a while
+//! - backups that are overdue
+//! - reboots left pending after a kernel or package update
+//!
+//! Checks read `proc`, `sys` and `run` below the configured root, so they can
+//! be pointed at a fake tree. The failed-unit check talks to the running
+//! systemd and is skipped for other roots.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::{DEFAULT_SNAPSHOT_DIR, LAST_BACKUP_STAMP};
+use crate::system::HardwareInventory;
+
+/// Marker left by package updates that need a reboot
+const REBOOT_REQUIRED: &str = "run/reboot-required";
+
+/// EFI variable naming the systemd-boot entry to boot once
+const LOADER_ENTRY_ONESHOT: &str = "sys/firmware/efi/efivars/LoaderEntryOneShot-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// Filesystem types that never hold user data
+const VIRTUAL_FILESYSTEMS: &[&str] = &["squashfs", "iso9660", "overlay"];
+
+/// How serious a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The check passed
+    Ok,
+    /// Worth knowing, nothing to do
+    Info,
+    /// Needs attention soon
+    Warning,
+    /// Needs attention now
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Ok => "ok",
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The outcome of one check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    /// Name of the check, such as `disk-space`
+    pub check: String,
+    /// How serious it is
+    pub severity: Severity,
+    /// What was found
+    pub summary: String,
+    /// Suggested fix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn new(check: &str, severity: Severity, summary: impl Into<String>) -> Self {
+        Self {
+            check: check.to_string(),
+            severity,
+            summary: summary.into(),
+            fix: None,
+        }
+    }
+
+    fn with_fix(mut self, fix: impl Into<String>) -> Self {
+        self.fix = Some(fix.into());
+        self
+    }
+}
+
+/// The result of a doctor run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoctorReport {
+    /// When the checks ran
+    pub checked_at: DateTime<Utc>,
+    /// Everything the checks found, passes included
+    pub findings: Vec<Finding>,
+    /// Hardware of the machine, if it could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardware: Option<HardwareInventory>,
+}
+
+impl DoctorReport {
+    /// The most serious severity found
+    pub fn worst(&self) -> Severity {
+        self.findings.iter().map(|f| f.severity).max().unwrap_or(Severity::Ok)
+    }
+
+    /// Whether nothing needs attention
+    pub fn passed(&self) -> bool {
+        self.worst() <= Severity::Info
+    }
+
+    /// Findings that need attention, most serious first
+    pub fn problems(&self) -> Vec<&Finding> {
+        let mut problems: Vec<_> = self.findings.iter().filter(|f| f.severity > Severity::Info).collect();
+        problems.sort_by_key(|f| std::cmp::Reverse(f.severity));
+        problems
+    }
+}
+
+/// Runs the health checks
+#[derive(Debug, Clone)]
+pub struct Doctor {
+    root: PathBuf,
+    disk_warn_percent: u8,
+    disk_critical_percent: u8,
+    snapshot_max_age: Duration,
+    backup_max_age: Duration,
+}
+
+impl Default for Doctor {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/"),
+            disk_warn_percent: 85,
+            disk_critical_percent: 95,
+            snapshot_max_age: Duration::from_secs(7 * 86400),
+            backup_max_age: Duration::from_secs(7 * 86400),
+        }
+    }
+}
+
+impl Doctor {
+    /// A doctor for the running system with the default thresholds
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the system below `root` instead of `/`
+    pub fn with_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Percentages of a filesystem in use that raise a warning and a critical finding
+    pub fn with_disk_thresholds(mut self, warn_percent: u8, critical_percent: u8) -> Self {
+        self.disk_warn_percent = warn_percent;
+        self.disk_critical_percent = critical_percent;
+        self
+    }
+
+    /// Age after which the newest snapshot counts as stale
+    pub fn with_snapshot_max_age(mut self, max_age: Duration) -> Self {
+        self.snapshot_max_age = max_age;
+        self
+    }
+
+    /// Age after which the last successful backup counts as overdue
+    pub fn with_backup_max_age(mut self, max_age: Duration) -> Self {
+        self.backup_max_age = max_age;
+        self
+    }
+
+    /// Run every check
+    pub fn run(&self) -> DoctorReport {
+        let now = SystemTime::now();
+        let mut findings = Vec::new();
+        findings.extend(self.check_failed_units());
+        findings.extend(self.check_btrfs_errors());
+        findings.extend(self.check_disk_space());
+        findings.push(self.check_snapshots(now));
+        findings.push(self.check_backups(now));
+        findings.push(self.check_reboot());
+
+        DoctorReport {
+            checked_at: Utc::now(),
+            findings,
+            hardware: HardwareInventory::collect_at(&self.root).ok(),
+        }
+    }
+
+    /// Units systemd reports as failed
+    pub fn check_failed_units(&self) -> Vec<Finding> {
+        const CHECK: &str = "systemd-units";
+        if self.root != Path::new("/") {
+            return vec![Finding::new(CHECK, Severity::Info, "Skipped; not the running system")];
+        }
+
+        let output = Command::new("systemctl")
+            .args(["list-units", "--state=failed", "--plain", "--no-legend", "--no-pager"])
+            .output();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return vec![Finding::new(CHECK, Severity::Info, format!("Skipped; systemctl failed: {}", stderr.trim()))];
+            }
+            Err(e) => return vec![Finding::new(CHECK, Severity::Info, format!("Skipped; cannot run systemctl: {}", e))],
+        };
+
+        let failed: Vec<_> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect();
+        if failed.is_empty() {
+            return vec![Finding::new(CHECK, Severity::Ok, "No failed units")];
+        }
+        failed
+            .into_iter()
+            .map(|unit| {
+                Finding::new(CHECK, Severity::Warning, format!("Unit {} has failed", unit)).with_fix(format!(
+                    "journalctl -b -u {0}; fix the cause, then systemctl reset-failed {0}",
+                    unit
+                ))
+            })
+            .collect()
+    }
+
+    /// Errors the kernel has counted on Btrfs devices
+    pub fn check_btrfs_errors(&self) -> Vec<Finding> {
+        const CHECK: &str = "btrfs-errors";
+        let mut findings = Vec::new();
+
+        for (fsid, devid, counters) in read_btrfs_error_stats(&self.root) {
+            let errors: Vec<_> = counters.iter().filter(|(_, count)| *count > 0).collect();
+            if errors.is_empty() {
+                continue;
+            }
+            let list = errors
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let corrupt = errors.iter().any(|(name, _)| name == "corruption_errs" || name == "generation_errs");
+            findings.push(
+                Finding::new(
+                    CHECK,
+                    if corrupt { Severity::Critical } else { Severity::Warning },
+                    format!("Btrfs filesystem {} device {} has errors: {}", fsid, devid, list),
+                )
+                .with_fix(format!(
+                    "Check the drive's health, run btrfs scrub start -B on the mount point, then btrfs device stats --reset; filesystem {}",
+                    fsid
+                )),
+            );
+        }
+
+        if findings.is_empty() {
+            findings.push(Finding::new(CHECK, Severity::Ok, "No Btrfs device errors"));
+        }
+        findings
+    }
+
+    /// Mounted filesystems that are close to full
+    pub fn check_disk_space(&self) -> Vec<Finding> {
+        const CHECK: &str = "disk-space";
+        let mounts = match fs::read_to_string(self.root.join("proc/self/mounts")) {
+            Ok(mounts) => mounts,
+            Err(e) => return vec![Finding::new(CHECK, Severity::Info, format!("Skipped; cannot read mounts: {}", e))],
+        };
+
+        let mut findings = Vec::new();
+        let mut seen = Vec::new();
+        for (device, mount_point, fstype) in parse_mounts(&mounts) {
+            // Btrfs subvolumes of one filesystem share its space
+            if !device.starts_with("/dev/") || VIRTUAL_FILESYSTEMS.contains(&fstype.as_str()) || seen.contains(&device) {
+                continue;
+            }
+            let path = self.root.join(mount_point.strip_prefix("/").unwrap_or(&mount_point));
+            let stat = match nix::sys::statvfs::statvfs(&path) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            seen.push(device);
+
+            let fragment = stat.fragment_size() as u64;
+            let total = stat.blocks() as u64 * fragment;
+            let available = stat.blocks_available() as u64 * fragment;
+            if let Some(finding) = self.disk_finding(&mount_point, total, available) {
+                findings.push(finding);
+            }
+        }
+
+        if findings.is_empty() {
+            findings.push(Finding::new(CHECK, Severity::Ok, "All filesystems have enough free space"));
+        }
+        findings
+    }
+
+    fn disk_finding(&self, mount_point: &Path, total: u64, available: u64) -> Option<Finding> {
+        if total == 0 {
+            return None;
+        }
+        let used_percent = 100 - available * 100 / total;
+        let severity = if used_percent >= self.disk_critical_percent as u64 {
+            Severity::Critical
+        } else if used_percent >= self.disk_warn_percent as u64 {
+            Severity::Warning
+        } else {
+            return None;
+        };
+        Some(
+            Finding::new(
+                "disk-space",
+                severity,
+                format!(
+                    "{} is {}% full ({} MiB free)",
+                    mount_point.display(),
+                    used_percent,
+                    available / (1024 * 1024)
+                ),
+            )
+            .with_fix("Remove old snapshots with rast-snapshot, then clean package caches"),
+        )
+    }
+
+    /// Whether snapshots are still being taken
+    pub fn check_snapshots(&self, now: SystemTime) -> Finding {
+        const CHECK: &str = "snapshots";
+        let dir = self.root.join(DEFAULT_SNAPSHOT_DIR.trim_start_matches('/'));
+        let newest = fs::read_dir(&dir).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+                .max()
+        });
+
+        match newest {
+            None => Finding::new(CHECK, Severity::Info, format!("No snapshots in {}", DEFAULT_SNAPSHOT_DIR)),
+            Some(newest) => {
+                let age = now.duration_since(newest).unwrap_or_default();
+                if age > self.snapshot_max_age {
+                    Finding::new(CHECK, Severity::Warning, format!("Newest snapshot is {} old", format_age(age)))
+                        .with_fix("Check the snapshot timer with systemctl list-timers, or take one with rast-backup create")
+                } else {
+                    Finding::new(CHECK, Severity::Ok, format!("Newest snapshot is {} old", format_age(age)))
+                }
+            }
+        }
+    }
+
+    /// Whether backups are still succeeding
+    pub fn check_backups(&self, now: SystemTime) -> Finding {
+        const CHECK: &str = "backups";
+        let stamp = self.root.join(LAST_BACKUP_STAMP.trim_start_matches('/'));
+        let last = fs::read_to_string(&stamp)
+            .ok()
+            .and_then(|s| DateTime::parse_from_rfc3339(s.trim()).ok())
+            .map(SystemTime::from);
+
+        match last {
+            None => Finding::new(CHECK, Severity::Warning, "No successful backup recorded")
+                .with_fix("Configure a storage backend and run rast-backup create"),
+            Some(last) => {
+                let age = now.duration_since(last).unwrap_or_default();
+                if age > self.backup_max_age {
+                    Finding::new(CHECK, Severity::Warning, format!("Last successful backup was {} ago", format_age(age)))
+                        .with_fix("Check the backup service logs with journalctl -u rast-backup, then run rast-backup create")
+                } else {
+                    Finding::new(CHECK, Severity::Ok, format!("Last successful backup was {} ago", format_age(age)))
+                }
+            }
+        }
+    }
+
+    /// Whether an update is waiting for a reboot
+    pub fn check_reboot(&self) -> Finding {
+        const CHECK: &str = "reboot";
+        if self.root.join(REBOOT_REQUIRED).exists() {
+            return Finding::new(CHECK, Severity::Warning, "An update requires a reboot").with_fix("systemctl reboot");
+        }
+
+        // A staged update deployment is selected for the next boot only
+        if let Ok(var) = fs::read(self.root.join(LOADER_ENTRY_ONESHOT)) {
+            // Four bytes of attributes, then a UTF-16LE string
+            let name: Vec<u16> = var
+                .get(4..)
+                .unwrap_or_default()
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect();
+            let entry = String::from_utf16_lossy(&name);
+            if entry.starts_with("rastos-deploy-") {
+                return Finding::new(CHECK, Severity::Warning, format!("Update {} is staged for the next boot", entry))
+                    .with_fix("systemctl reboot, then rast update commit");
+            }
+        }
+
+        // The running kernel's modules disappear when its package is upgraded
+        if let Ok(release) = fs::read_to_string(self.root.join("proc/sys/kernel/osrelease")) {
+            let release = release.trim();
+            if !release.is_empty() && !self.root.join("usr/lib/modules").join(release).exists() {
+                return Finding::new(
+                    CHECK,
+                    Severity::Warning,
+                    format!("Running kernel {} is no longer installed", release),
+                )
+                .with_fix("systemctl reboot");
+            }
+        }
+        Finding::new(CHECK, Severity::Ok, "No reboot pending")
+    }
+}
+
+/// Error counters of one Btrfs device, as `(fsid, devid, counters)`
+type DeviceErrorStats = (String, String, Vec<(String, u64)>);
+
+/// Error counters of every Btrfs device
+fn read_btrfs_error_stats(root: &Path) -> Vec<DeviceErrorStats> {
+    let mut stats = Vec::new();
+    let Ok(filesystems) = fs::read_dir(root.join("sys/fs/btrfs")) else {
+        return stats;
+    };
+    for fs_entry in filesystems.flatten() {
+        let fsid = fs_entry.file_name().to_string_lossy().into_owned();
+        let Ok(devices) = fs::read_dir(fs_entry.path().join("devinfo")) else {
+            continue;
+        };
+        for dev_entry in devices.flatten() {
+            let Ok(content) = fs::read_to_string(dev_entry.path().join("error_stats")) else {
+                continue;
+            };
+            let counters = content
+                .lines()
+                .filter_map(|line| {
+                    let (name, count) = line.split_once(char::is_whitespace)?;
+                    Some((name.to_string(), count.trim().parse().ok()?))
+                })
+                .collect();
+            stats.push((fsid.clone(), dev_entry.file_name().to_string_lossy().into_owned(), counters));
+        }
+    }
+    stats.sort();
+    stats
+}
+
+/// Device, mount point and type of each entry in a mounts table
+fn parse_mounts(mounts: &str) -> Vec<(String, PathBuf, String)> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mount_point = fields.next()?.replace("\\040", " ");
+            let fstype = fields.next()?;
+            Some((device.to_string(), PathBuf::from(mount_point), fstype.to_string()))
+        })
+        .collect()
+}
+
+/// Format a duration in the largest whole unit
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs >= 86400 {
+        format!("{} days", secs / 86400)
+    } else if secs >= 3600 {
+        format!("{} hours", secs / 3600)
+    } else {
+        format!("{} minutes", secs / 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checks_on_fake_root() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let devinfo = root.join("sys/fs/btrfs/1f2e/devinfo/1");
+        fs::create_dir_all(&devinfo).unwrap();
+        fs::write(
+            devinfo.join("error_stats"),
+            "write_errs 0\nread_errs 3\nflush_errs 0\ncorruption_errs 0\ngeneration_errs 0\n",
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("proc/sys/kernel")).unwrap();
+        fs::write(root.join("proc/sys/kernel/osrelease"), "6.9.1-rast\n").unwrap();
+        let stamp = root.join(LAST_BACKUP_STAMP.trim_start_matches('/'));
+        fs::create_dir_all(stamp.parent().unwrap()).unwrap();
+        fs::write(&stamp, "2024-01-01T00:00:00Z\n").unwrap();
+
+        let doctor = Doctor::new().with_root(root);
+
+        let btrfs = doctor.check_btrfs_errors();
+        assert_eq!(btrfs.len(), 1);
+        assert_eq!(btrfs[0].severity, Severity::Warning);
+        assert!(btrfs[0].summary.contains("read_errs 3"));
+
+        let reboot = doctor.check_reboot();
+        assert_eq!(reboot.severity, Severity::Warning);
+        fs::create_dir_all(root.join("usr/lib/modules/6.9.1-rast")).unwrap();
+        assert_eq!(doctor.check_reboot().severity, Severity::Ok);
+
+        let stamped = SystemTime::from(DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap());
+        assert_eq!(doctor.check_backups(stamped + Duration::from_secs(86400)).severity, Severity::Ok);
+        assert_eq!(doctor.check_backups(stamped + Duration::from_secs(30 * 86400)).severity, Severity::Warning);
+
+        let report = doctor.run();
+        assert_eq!(report.worst(), Severity::Warning);
+        assert!(!report.passed());
+        assert_eq!(report.problems()[0].check, "btrfs-errors");
+    }
+
+    #[test]
+    fn test_disk_thresholds() {
+        let doctor = Doctor::new().with_disk_thresholds(80, 90);
+        let gib = 1024 * 1024 * 1024;
+        assert!(doctor.disk_finding(Path::new("/"), 100 * gib, 50 * gib).is_none());
+        assert_eq!(doctor.disk_finding(Path::new("/"), 100 * gib, 15 * gib).unwrap().severity, Severity::Warning);
+        assert_eq!(doctor.disk_finding(Path::new("/"), 100 * gib, 5 * gib).unwrap().severity, Severity::Critical);
+    }
+}
@@ -0,0 +1,554 @@
+//! Hardware inventory
+//!
+//! Collects the CPU, memory, block devices, GPUs and network interfaces of
+//! the machine from `/proc`, `/sys` and the udev database, without running
+//! any command. Filesystem types and UUIDs come from udev, so devices that
+//! appeared before udev ran may lack them. Every struct serializes to JSON
+//! for front ends and `rast doctor`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Size of the sectors `/sys/block/*/size` counts in
+const SECTOR_SIZE: u64 = 512;
+
+/// Block devices that are not storage hardware
+const VIRTUAL_BLOCK_PREFIXES: &[&str] = &["loop", "ram", "zram", "dm-", "md", "sr", "fd"];
+
+/// PCI vendors of common graphics hardware
+const GPU_VENDORS: &[(&str, &str)] = &[
+    ("0x8086", "Intel"),
+    ("0x1002", "AMD"),
+    ("0x10de", "NVIDIA"),
+    ("0x1af4", "virtio"),
+    ("0x1b36", "QEMU"),
+    ("0x15ad", "VMware"),
+    ("0x1234", "Bochs"),
+];
+
+/// Everything the inventory knows about the machine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardwareInventory {
+    /// Processors
+    pub cpu: CpuInfo,
+    /// Installed memory and swap
+    pub memory: MemoryInfo,
+    /// Disks, with their partitions
+    pub storage: Vec<StorageDevice>,
+    /// Graphics adapters
+    pub gpus: Vec<Gpu>,
+    /// Network interfaces, except loopback
+    pub network: Vec<NetworkInterface>,
+}
+
+/// Processors
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuInfo {
+    /// Model name reported by the first processor
+    pub model: String,
+    /// Vendor ID, such as `GenuineIntel`
+    pub vendor: Option<String>,
+    /// Architecture rastOS was built for
+    pub architecture: String,
+    /// Physical packages
+    pub sockets: usize,
+    /// Physical cores
+    pub cores: usize,
+    /// Logical processors
+    pub threads: usize,
+    /// Highest frequency in MHz, if cpufreq reports it
+    pub max_mhz: Option<u64>,
+}
+
+/// Installed memory and swap, in KiB
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct MemoryInfo {
+    /// Usable memory
+    pub total_kib: u64,
+    /// Memory available without swapping
+    pub available_kib: u64,
+    /// Swap space
+    pub swap_total_kib: u64,
+}
+
+impl MemoryInfo {
+    /// Parse `/proc/meminfo`; `None` without a `MemTotal` line
+    pub fn parse(meminfo: &str) -> Option<Self> {
+        let field = |name: &str| {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|rest| rest.split_whitespace().next())
+                .and_then(|kib| kib.parse().ok())
+        };
+        Some(Self {
+            total_kib: field("MemTotal")?,
+            available_kib: field("MemAvailable").unwrap_or(0),
+            swap_total_kib: field("SwapTotal").unwrap_or(0),
+        })
+    }
+}
+
+/// A disk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageDevice {
+    /// Kernel name, such as `nvme0n1`
+    pub name: String,
+    /// Device node
+    pub path: PathBuf,
+    /// Model reported by the device
+    pub model: Option<String>,
+    /// Serial number
+    pub serial: Option<String>,
+    /// Capacity in bytes
+    pub size_bytes: u64,
+    /// Whether the device has spinning platters
+    pub rotational: bool,
+    /// Whether the medium is removable
+    pub removable: bool,
+    /// Filesystem on the whole device, without a partition table
+    pub filesystem: Option<Filesystem>,
+    /// Partitions, in kernel order
+    pub partitions: Vec<Partition>,
+}
+
+/// A partition of a disk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partition {
+    /// Kernel name, such as `nvme0n1p2`
+    pub name: String,
+    /// Device node
+    pub path: PathBuf,
+    /// Size in bytes
+    pub size_bytes: u64,
+    /// Filesystem on the partition
+    pub filesystem: Option<Filesystem>,
+}
+
+/// A filesystem found on a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Filesystem {
+    /// Type, such as `btrfs` or `vfat`
+    pub fstype: String,
+    /// Filesystem UUID
+    pub uuid: Option<String>,
+    /// Label
+    pub label: Option<String>,
+    /// For Btrfs, the filesystem the device belongs to
+    pub btrfs: Option<BtrfsMembership>,
+}
+
+/// Membership of a device in a Btrfs filesystem
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BtrfsMembership {
+    /// Filesystem UUID shared by all members
+    pub fsid: String,
+    /// Device nodes of all members found, including this one
+    pub members: Vec<PathBuf>,
+    /// Whether the filesystem is mounted
+    pub mounted: bool,
+}
+
+/// A graphics adapter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gpu {
+    /// DRM card name, such as `card0`
+    pub card: String,
+    /// PCI address
+    pub pci_slot: Option<String>,
+    /// Vendor name, or the PCI vendor ID if unknown
+    pub vendor: String,
+    /// PCI device ID
+    pub device_id: Option<String>,
+    /// Kernel driver in use
+    pub driver: Option<String>,
+    /// Whether the firmware used this adapter to boot
+    pub boot_vga: bool,
+}
+
+/// Kind of a network interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterfaceKind {
+    /// Wired Ethernet
+    Ethernet,
+    /// Wi-Fi
+    Wireless,
+    /// Bridges, tunnels, veth pairs and other software interfaces
+    Virtual,
+    /// Anything else, such as InfiniBand
+    Other,
+}
+
+/// A network interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    /// Interface name
+    pub name: String,
+    /// Kind of interface
+    pub kind: InterfaceKind,
+    /// Hardware address
+    pub mac: Option<String>,
+    /// Operational state, such as `up` or `down`
+    pub state: String,
+    /// MTU in bytes
+    pub mtu: Option<u32>,
+    /// Link speed in Mbit/s, while the link is up
+    pub speed_mbps: Option<u32>,
+    /// Kernel driver
+    pub driver: Option<String>,
+}
+
+impl HardwareInventory {
+    /// Collect the inventory of this machine
+    pub fn collect() -> io::Result<Self> {
+        Self::collect_at(Path::new("/"))
+    }
+
+    /// Collect the inventory from `proc`, `sys` and `run/udev` below `root`
+    pub fn collect_at(root: &Path) -> io::Result<Self> {
+        let cpuinfo = fs::read_to_string(root.join("proc/cpuinfo"))?;
+        let meminfo = fs::read_to_string(root.join("proc/meminfo"))?;
+        Ok(Self {
+            cpu: read_cpu(root, &cpuinfo),
+            memory: MemoryInfo::parse(&meminfo)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MemTotal in /proc/meminfo"))?,
+            storage: read_storage(root),
+            gpus: read_gpus(root),
+            network: read_network(root),
+        })
+    }
+
+    /// All Btrfs filesystems, by UUID, with their member devices
+    pub fn btrfs_filesystems(&self) -> BTreeMap<String, &BtrfsMembership> {
+        self.storage
+            .iter()
+            .flat_map(|disk| disk.filesystem.iter().chain(disk.partitions.iter().filter_map(|p| p.filesystem.as_ref())))
+            .filter_map(|fs| fs.btrfs.as_ref())
+            .map(|btrfs| (btrfs.fsid.clone(), btrfs))
+            .collect()
+    }
+}
+
+fn read_cpu(root: &Path, cpuinfo: &str) -> CpuInfo {
+    let mut threads = 0;
+    let mut model = None;
+    let mut vendor = None;
+    let mut sockets = BTreeSet::new();
+    let mut cores = BTreeSet::new();
+    let mut physical_id = None;
+    for line in cpuinfo.lines().chain(std::iter::once("")) {
+        let Some((key, value)) = line.split_once(':') else {
+            // A blank line ends a processor's block
+            physical_id = None;
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "processor" => threads += 1,
+            "model name" | "Processor" if model.is_none() => model = Some(value.to_string()),
+            "vendor_id" if vendor.is_none() => vendor = Some(value.to_string()),
+            "physical id" => {
+                sockets.insert(value.to_string());
+                physical_id = Some(value.to_string());
+            }
+            "core id" => {
+                cores.insert((physical_id.clone(), value.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    let max_mhz = read_trimmed(&root.join("sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq"))
+        .and_then(|khz| khz.parse::<u64>().ok())
+        .map(|khz| khz / 1000);
+    CpuInfo {
+        model: model.unwrap_or_else(|| "unknown".to_string()),
+        vendor,
+        architecture: std::env::consts::ARCH.to_string(),
+        sockets: sockets.len().max(1),
+        // Without topology information, count every thread as a core
+        cores: if cores.is_empty() { threads } else { cores.len() },
+        threads,
+        max_mhz,
+    }
+}
+
+fn read_storage(root: &Path) -> Vec<StorageDevice> {
+    let block = root.join("sys/block");
+    let mut disks: Vec<StorageDevice> = read_dir_names(&block)
+        .into_iter()
+        .filter(|name| !VIRTUAL_BLOCK_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+        .map(|name| {
+            let dir = block.join(&name);
+            let mut partitions: Vec<Partition> = read_dir_names(&dir)
+                .into_iter()
+                .filter(|part| dir.join(part).join("partition").exists())
+                .map(|part| Partition {
+                    path: PathBuf::from("/dev").join(&part),
+                    size_bytes: read_size(&dir.join(&part)),
+                    filesystem: read_filesystem(root, &dir.join(&part)),
+                    name: part,
+                })
+                .collect();
+            partitions.sort_by_key(|p| read_number(&dir.join(&p.name).join("partition")).unwrap_or(0));
+            StorageDevice {
+                path: PathBuf::from("/dev").join(&name),
+                model: read_trimmed(&dir.join("device/model")),
+                serial: read_trimmed(&dir.join("device/serial")),
+                size_bytes: read_size(&dir),
+                rotational: read_number(&dir.join("queue/rotational")) == Some(1),
+                removable: read_number(&dir.join("removable")) == Some(1),
+                filesystem: if partitions.is_empty() { read_filesystem(root, &dir) } else { None },
+                partitions,
+                name,
+            }
+        })
+        .filter(|disk| disk.size_bytes > 0)
+        .collect();
+
+    // Group Btrfs devices by filesystem now that every device is known
+    let mut members: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for (path, fs) in filesystems_mut(&mut disks) {
+        if let Some(btrfs) = &fs.btrfs {
+            members.entry(btrfs.fsid.clone()).or_default().push(path);
+        }
+    }
+    for (_, fs) in filesystems_mut(&mut disks) {
+        if let Some(btrfs) = &mut fs.btrfs {
+            btrfs.members = members[&btrfs.fsid].clone();
+        }
+    }
+    disks
+}
+
+/// Every filesystem on the disks, with the device node holding it
+fn filesystems_mut(disks: &mut [StorageDevice]) -> Vec<(PathBuf, &mut Filesystem)> {
+    let mut found = Vec::new();
+    for disk in disks {
+        if let Some(fs) = &mut disk.filesystem {
+            found.push((disk.path.clone(), fs));
+        }
+        for partition in &mut disk.partitions {
+            if let Some(fs) = &mut partition.filesystem {
+                found.push((partition.path.clone(), fs));
+            }
+        }
+    }
+    found
+}
+
+/// Filesystem of a block device according to the udev database
+fn read_filesystem(root: &Path, sys_dir: &Path) -> Option<Filesystem> {
+    let dev = read_trimmed(&sys_dir.join("dev"))?;
+    let data = fs::read_to_string(root.join("run/udev/data").join(format!("b{}", dev))).ok()?;
+    let property = |key: &str| {
+        data.lines()
+            .find_map(|line| line.strip_prefix("E:")?.strip_prefix(key)?.strip_prefix('='))
+            .map(str::to_string)
+            .filter(|value| !value.is_empty())
+    };
+
+    let fstype = property("ID_FS_TYPE")?;
+    let uuid = property("ID_FS_UUID");
+    let btrfs = match (fstype.as_str(), &uuid) {
+        ("btrfs", Some(fsid)) => Some(BtrfsMembership {
+            fsid: fsid.clone(),
+            members: Vec::new(),
+            mounted: root.join("sys/fs/btrfs").join(fsid).is_dir(),
+        }),
+        _ => None,
+    };
+    Some(Filesystem {
+        fstype,
+        uuid,
+        label: property("ID_FS_LABEL"),
+        btrfs,
+    })
+}
+
+fn read_gpus(root: &Path) -> Vec<Gpu> {
+    let drm = root.join("sys/class/drm");
+    read_dir_names(&drm)
+        .into_iter()
+        // Connectors are named like card0-HDMI-A-1
+        .filter(|name| name.starts_with("card") && !name.contains('-'))
+        .filter_map(|card| {
+            let device = drm.join(&card).join("device");
+            let vendor_id = read_trimmed(&device.join("vendor"))?;
+            let vendor = GPU_VENDORS
+                .iter()
+                .find(|(id, _)| *id == vendor_id)
+                .map_or(vendor_id.clone(), |(_, name)| name.to_string());
+            Some(Gpu {
+                pci_slot: link_name(&device),
+                vendor,
+                device_id: read_trimmed(&device.join("device")),
+                driver: link_name(&device.join("driver")),
+                boot_vga: read_number(&device.join("boot_vga")) == Some(1),
+                card,
+            })
+        })
+        .collect()
+}
+
+fn read_network(root: &Path) -> Vec<NetworkInterface> {
+    let net = root.join("sys/class/net");
+    read_dir_names(&net)
+        .into_iter()
+        .filter(|name| name != "lo")
+        .map(|name| {
+            let dir = net.join(&name);
+            let has_device = dir.join("device").exists();
+            let kind = if dir.join("wireless").exists() || dir.join("phy80211").exists() {
+                InterfaceKind::Wireless
+            } else if !has_device {
+                InterfaceKind::Virtual
+            } else if read_number(&dir.join("type")) == Some(1) {
+                InterfaceKind::Ethernet
+            } else {
+                InterfaceKind::Other
+            };
+            NetworkInterface {
+                kind,
+                mac: read_trimmed(&dir.join("address")).filter(|mac| mac != "00:00:00:00:00:00"),
+                state: read_trimmed(&dir.join("operstate")).unwrap_or_else(|| "unknown".to_string()),
+                mtu: read_number(&dir.join("mtu")).and_then(|mtu| u32::try_from(mtu).ok()),
+                // Reading the speed fails while the link is down
+                speed_mbps: read_trimmed(&dir.join("speed"))
+                    .and_then(|speed| speed.parse::<i64>().ok())
+                    .and_then(|speed| u32::try_from(speed).ok()),
+                driver: link_name(&dir.join("device/driver")),
+                name,
+            }
+        })
+        .collect()
+}
+
+fn read_dir_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    read_trimmed(path)?.parse().ok()
+}
+
+fn read_size(dir: &Path) -> u64 {
+    read_number(&dir.join("size")).unwrap_or(0) * SECTOR_SIZE
+}
+
+/// Last component of the target of a sysfs link, such as a driver or PCI slot
+fn link_name(path: &Path) -> Option<String> {
+    let target = fs::read_link(path).ok()?;
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_collect_inventory() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        write(
+            root,
+            "proc/cpuinfo",
+            "processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Test CPU\nphysical id\t: 0\ncore id\t: 0\n\n\
+             processor\t: 1\nvendor_id\t: GenuineIntel\nmodel name\t: Test CPU\nphysical id\t: 0\ncore id\t: 0\n\n\
+             processor\t: 2\nvendor_id\t: GenuineIntel\nmodel name\t: Test CPU\nphysical id\t: 0\ncore id\t: 1\n",
+        );
+        write(root, "proc/meminfo", "MemTotal: 16318480 kB\nMemAvailable: 8000000 kB\nSwapTotal: 0 kB\n");
+        write(root, "sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq", "4200000\n");
+
+        // Two disks in one Btrfs filesystem: a partition and a whole disk
+        write(root, "sys/block/nvme0n1/size", "2000000\n");
+        write(root, "sys/block/nvme0n1/device/model", "Test NVMe  \n");
+        write(root, "sys/block/nvme0n1/queue/rotational", "0\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p1/partition", "1\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p1/size", "1000\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p1/dev", "259:1\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p2/partition", "2\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p2/size", "1990000\n");
+        write(root, "sys/block/nvme0n1/nvme0n1p2/dev", "259:2\n");
+        write(root, "sys/block/sda/size", "4000000\n");
+        write(root, "sys/block/sda/dev", "8:0\n");
+        write(root, "sys/block/sda/queue/rotational", "1\n");
+        write(root, "sys/block/loop0/size", "100\n");
+        write(root, "run/udev/data/b259:1", "E:ID_FS_TYPE=vfat\nE:ID_FS_UUID=ABCD-1234\n");
+        write(root, "run/udev/data/b259:2", "E:ID_FS_TYPE=btrfs\nE:ID_FS_UUID=fs-uuid\nE:ID_FS_LABEL=rastos\n");
+        write(root, "run/udev/data/b8:0", "E:ID_FS_TYPE=btrfs\nE:ID_FS_UUID=fs-uuid\n");
+        fs::create_dir_all(root.join("sys/fs/btrfs/fs-uuid")).unwrap();
+
+        write(root, "sys/class/drm/card0/device/vendor", "0x8086\n");
+        write(root, "sys/class/drm/card0/device/boot_vga", "1\n");
+        write(root, "sys/class/drm/card0-HDMI-A-1/status", "connected\n");
+        write(root, "sys/class/net/enp3s0/type", "1\n");
+        write(root, "sys/class/net/enp3s0/operstate", "up\n");
+        write(root, "sys/class/net/enp3s0/speed", "1000\n");
+        write(root, "sys/class/net/enp3s0/device/vendor", "0x8086\n");
+        write(root, "sys/class/net/br0/operstate", "down\n");
+        write(root, "sys/class/net/lo/operstate", "unknown\n");
+        fs::create_dir_all(root.join("drivers/e1000e")).unwrap();
+        symlink(root.join("drivers/e1000e"), root.join("sys/class/net/enp3s0/device/driver")).unwrap();
+
+        let inventory = HardwareInventory::collect_at(root).unwrap();
+        assert_eq!(inventory.cpu.model, "Test CPU");
+        assert_eq!((inventory.cpu.sockets, inventory.cpu.cores, inventory.cpu.threads), (1, 2, 3));
+        assert_eq!(inventory.cpu.max_mhz, Some(4200));
+        assert_eq!(inventory.memory.total_kib, 16318480);
+
+        assert_eq!(inventory.storage.len(), 2);
+        let nvme = &inventory.storage[0];
+        assert_eq!(nvme.model.as_deref(), Some("Test NVMe"));
+        assert_eq!(nvme.partitions.len(), 2);
+        assert_eq!(nvme.partitions[0].filesystem.as_ref().unwrap().fstype, "vfat");
+        let btrfs = nvme.partitions[1].filesystem.as_ref().unwrap().btrfs.as_ref().unwrap();
+        assert_eq!(btrfs.members, vec![PathBuf::from("/dev/nvme0n1p2"), PathBuf::from("/dev/sda")]);
+        assert!(btrfs.mounted);
+        assert!(inventory.storage[1].rotational);
+        assert_eq!(inventory.btrfs_filesystems().len(), 1);
+
+        assert_eq!(inventory.gpus.len(), 1);
+        assert_eq!(inventory.gpus[0].vendor, "Intel");
+        assert!(inventory.gpus[0].boot_vga);
+
+        assert_eq!(inventory.network.len(), 2);
+        assert_eq!(inventory.network[0].kind, InterfaceKind::Virtual);
+        let wired = &inventory.network[1];
+        assert_eq!(wired.kind, InterfaceKind::Ethernet);
+        assert_eq!(wired.speed_mbps, Some(1000));
+        assert_eq!(wired.driver.as_deref(), Some("e1000e"));
+
+        let json = serde_json::to_string(&inventory).unwrap();
+        assert_eq!(serde_json::from_str::<HardwareInventory>(&json).unwrap(), inventory);
+    }
+}
@@ -0,0 +1,456 @@
+//! Hostname, machine ID and machine identity
+//!
+//! On the running system hostnames are changed through `hostnamectl`, so
+//! systemd-hostnamed updates the kernel and notifies other services; when
+//! it cannot be reached, as in a chroot or an installation target, the
+//! files in `/etc` are written directly.
+//!
+//! The machine ID in `/etc/machine-id` must stay private, so rastOS never
+//! sends it anywhere. [`Identity::machine_identity`] derives an ID specific
+//! to rastOS from it instead, the same way `systemd-id128 machine-id
+//! --app-specific=` does. Backups and remote management use it to tell
+//! machines apart.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info, warn};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::fs::{atomic_write_with_options, WriteOptions};
+
+/// Application ID from which the rastOS machine identity is derived
+const RASTOS_APP_ID: [u8; 16] = [
+    0xd7, 0x37, 0x11, 0x1d, 0xce, 0x4a, 0x43, 0x46, 0x50, 0xdc, 0x69, 0xa9, 0x46, 0xa0, 0x0b, 0x2d,
+];
+
+/// Longest hostname the kernel accepts
+const HOST_NAME_MAX: usize = 64;
+
+/// Contents of `/etc/machine-id` that make systemd create an ID on the next boot
+const UNINITIALIZED: &str = "uninitialized\n";
+
+/// Error type for identity operations
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Hostname that is not allowed
+    #[error("Invalid hostname '{0}'")]
+    InvalidHostname(String),
+
+    /// Missing or malformed machine ID
+    #[error("Invalid machine ID: {0}")]
+    InvalidMachineId(String),
+
+    /// Operation that needs the running system
+    #[error("{0} is only possible on the running system")]
+    NotLive(&'static str),
+}
+
+/// Result type for identity operations
+pub type Result<T> = std::result::Result<T, IdentityError>;
+
+/// The hostnames systemd distinguishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostnameKind {
+    /// The configured hostname in `/etc/hostname`
+    Static,
+    /// A free-form name for humans in `/etc/machine-info`
+    Pretty,
+    /// The kernel's current hostname, which DHCP may change
+    Transient,
+}
+
+impl HostnameKind {
+    fn flag(self) -> &'static str {
+        match self {
+            HostnameKind::Static => "--static",
+            HostnameKind::Pretty => "--pretty",
+            HostnameKind::Transient => "--transient",
+        }
+    }
+}
+
+/// Identity of this machine, safe to share
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineIdentity {
+    /// rastOS-specific ID derived from the machine ID, as 32 hex digits
+    pub id: String,
+    /// Static hostname, if one is set
+    pub hostname: Option<String>,
+}
+
+/// Reads and changes the identity of the system at a root directory
+#[derive(Debug, Clone)]
+pub struct Identity {
+    root: PathBuf,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Identity {
+    /// The identity of the running system
+    pub fn new() -> Self {
+        Self { root: PathBuf::from("/") }
+    }
+
+    /// Work on the system installed at `root` instead
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    fn is_live(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    /// Get a hostname; `None` if it is not set
+    pub fn hostname(&self, kind: HostnameKind) -> Result<Option<String>> {
+        let value = match kind {
+            HostnameKind::Static => read_optional(&self.path("etc/hostname"))?.and_then(|content| {
+                content
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string)
+            }),
+            HostnameKind::Pretty => read_optional(&self.path("etc/machine-info"))?
+                .and_then(|content| env_file_value(&content, "PRETTY_HOSTNAME")),
+            HostnameKind::Transient => {
+                if !self.is_live() {
+                    return Err(IdentityError::NotLive("Reading the transient hostname"));
+                }
+                read_optional(Path::new("/proc/sys/kernel/hostname"))?.map(|name| name.trim().to_string())
+            }
+        };
+        Ok(value.filter(|name| !name.is_empty()))
+    }
+
+    /// Set a hostname
+    ///
+    /// Static and transient hostnames must be valid DNS names of at most 64
+    /// characters; pretty hostnames may be any single line.
+    pub fn set_hostname(&self, kind: HostnameKind, name: &str) -> Result<()> {
+        match kind {
+            HostnameKind::Pretty => validate_pretty_hostname(name)?,
+            _ => validate_hostname(name)?,
+        }
+
+        if self.is_live() {
+            match hostnamectl(&["set-hostname", kind.flag(), name]) {
+                Ok(()) => {
+                    info!("Set the {:?} hostname to '{}'", kind, name);
+                    return Ok(());
+                }
+                Err(e) => debug!("hostnamectl failed, writing files instead: {}", e),
+            }
+        }
+
+        match kind {
+            HostnameKind::Static => write_file(&self.path("etc/hostname"), format!("{}\n", name), 0o644)?,
+            HostnameKind::Pretty => {
+                let path = self.path("etc/machine-info");
+                let content = read_optional(&path)?.unwrap_or_default();
+                write_file(&path, set_env_file_value(&content, "PRETTY_HOSTNAME", name), 0o644)?;
+            }
+            HostnameKind::Transient => {
+                if !self.is_live() {
+                    return Err(IdentityError::NotLive("Setting the transient hostname"));
+                }
+                fs::write("/proc/sys/kernel/hostname", name)?;
+            }
+        }
+        info!("Set the {:?} hostname to '{}'", kind, name);
+        Ok(())
+    }
+
+    /// The machine ID, as 32 hex digits
+    pub fn machine_id(&self) -> Result<String> {
+        let content = read_optional(&self.path("etc/machine-id"))?.unwrap_or_default();
+        parse_machine_id(content.trim()).map(|id| hex(&id))
+    }
+
+    /// Replace the machine ID with a new random one
+    ///
+    /// The D-Bus copy in `/var/lib/dbus/machine-id` is updated unless it is
+    /// a link, and the persistent journal is moved to the new ID's
+    /// directory so old logs stay visible. Running services keep the old ID
+    /// until the next boot. Returns the new ID.
+    pub fn regenerate_machine_id(&self) -> Result<String> {
+        let old = self.machine_id().ok();
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let new = hex(&make_v4_uuid(id));
+
+        write_file(&self.path("etc/machine-id"), format!("{}\n", new), 0o444)?;
+
+        let dbus = self.path("var/lib/dbus/machine-id");
+        if fs::symlink_metadata(&dbus).is_ok_and(|meta| meta.file_type().is_file()) {
+            write_file(&dbus, format!("{}\n", new), 0o444)?;
+        }
+
+        if let Some(old) = &old {
+            let journal = self.path("var/log/journal");
+            let (from, to) = (journal.join(old), journal.join(&new));
+            if from.is_dir() && !to.exists() {
+                fs::rename(&from, &to)?;
+            }
+        }
+
+        if self.is_live() {
+            warn!("Machine ID changed; reboot so all services use the new one");
+        }
+        info!("Regenerated the machine ID of {}", self.root.display());
+        Ok(new)
+    }
+
+    /// Mark the machine ID for regeneration on the next boot
+    ///
+    /// Use this before imaging or cloning a system, so every copy gets its
+    /// own ID.
+    pub fn reset_machine_id(&self) -> Result<()> {
+        write_file(&self.path("etc/machine-id"), UNINITIALIZED, 0o444)?;
+        let dbus = self.path("var/lib/dbus/machine-id");
+        if fs::symlink_metadata(&dbus).is_ok_and(|meta| meta.file_type().is_file()) {
+            fs::remove_file(&dbus)?;
+        }
+        Ok(())
+    }
+
+    /// The rastOS machine identity
+    ///
+    /// Stable for as long as the machine ID is, and does not reveal it.
+    pub fn machine_identity(&self) -> Result<MachineIdentity> {
+        let machine_id = parse_machine_id(&self.machine_id()?)?;
+        Ok(MachineIdentity {
+            id: hex(&app_specific_id(&machine_id, &RASTOS_APP_ID)),
+            hostname: self.hostname(HostnameKind::Static)?,
+        })
+    }
+}
+
+/// Check a static or transient hostname
+pub fn validate_hostname(name: &str) -> Result<()> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if name.len() > HOST_NAME_MAX || !name.split('.').all(valid_label) {
+        return Err(IdentityError::InvalidHostname(name.to_string()));
+    }
+    Ok(())
+}
+
+fn validate_pretty_hostname(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(char::is_control) {
+        return Err(IdentityError::InvalidHostname(name.to_string()));
+    }
+    Ok(())
+}
+
+fn hostnamectl(args: &[&str]) -> io::Result<()> {
+    let output = Command::new("hostnamectl").args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(())
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_file<C: AsRef<[u8]>>(path: &Path, content: C, mode: u32) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let options = WriteOptions::default().with_mode(mode);
+    atomic_write_with_options(path, content, &options).map_err(io::Error::from)
+}
+
+/// Value of `key` in a shell-style environment file such as `/etc/machine-info`
+fn env_file_value(content: &str, key: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let value = line.trim().strip_prefix(key)?.strip_prefix('=')?;
+        Some(unquote(value))
+    })
+}
+
+/// Replace or add `key` in an environment file, keeping the other lines
+fn set_env_file_value(content: &str, key: &str, value: &str) -> String {
+    let assignment = format!("{}={}", key, quote(value));
+    let mut found = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| {
+            if line.trim().strip_prefix(key).is_some_and(|rest| rest.starts_with('=')) {
+                found = true;
+                assignment.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(assignment);
+    }
+    lines.join("\n") + "\n"
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')));
+    match inner {
+        Some(inner) => {
+            let mut unquoted = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(if c == '\\' { chars.next().unwrap_or('\\') } else { c });
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+fn parse_machine_id(text: &str) -> Result<[u8; 16]> {
+    if text.len() != 32 || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        let reason = if text.is_empty() || text == UNINITIALIZED.trim() {
+            "not initialized".to_string()
+        } else {
+            format!("'{}' is not 32 hex digits", text)
+        };
+        return Err(IdentityError::InvalidMachineId(reason));
+    }
+    let mut id = [0u8; 16];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).expect("checked hex digits");
+    }
+    Ok(id)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Mark 128 bits as a random (version 4) UUID, as systemd does for its IDs
+fn make_v4_uuid(mut id: [u8; 16]) -> [u8; 16] {
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+/// systemd's `sd_id128_get_machine_app_specific`: HMAC-SHA256 of the app ID keyed with the machine ID
+fn app_specific_id(machine_id: &[u8; 16], app_id: &[u8; 16]) -> [u8; 16] {
+    const BLOCK_SIZE: usize = 64;
+    let mut key = [0u8; BLOCK_SIZE];
+    key[..machine_id.len()].copy_from_slice(machine_id);
+
+    let mut inner = Sha256::new();
+    inner.update(key.map(|b| b ^ 0x36));
+    inner.update(app_id);
+    let mut outer = Sha256::new();
+    outer.update(key.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&outer.finalize()[..16]);
+    make_v4_uuid(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hostnames_in_target() {
+        let root = tempdir().unwrap();
+        let identity = Identity::new().with_root(root.path());
+        assert_eq!(identity.hostname(HostnameKind::Static).unwrap(), None);
+
+        identity.set_hostname(HostnameKind::Static, "rastos-01").unwrap();
+        fs::write(root.path().join("etc/machine-info"), "CHASSIS=laptop\nPRETTY_HOSTNAME=old\n").unwrap();
+        identity.set_hostname(HostnameKind::Pretty, "Build \"box\" $HOME").unwrap();
+
+        assert_eq!(identity.hostname(HostnameKind::Static).unwrap().as_deref(), Some("rastos-01"));
+        assert_eq!(
+            identity.hostname(HostnameKind::Pretty).unwrap().as_deref(),
+            Some("Build \"box\" $HOME")
+        );
+        let machine_info = fs::read_to_string(root.path().join("etc/machine-info")).unwrap();
+        assert!(machine_info.starts_with("CHASSIS=laptop\n"));
+
+        assert!(identity.set_hostname(HostnameKind::Static, "-bad").is_err());
+        assert!(identity.set_hostname(HostnameKind::Static, "under_score").is_err());
+        assert!(identity.set_hostname(HostnameKind::Static, &"a".repeat(65)).is_err());
+        assert!(identity.hostname(HostnameKind::Transient).is_err());
+    }
+
+    #[test]
+    fn test_regenerate_machine_id() {
+        let root = tempdir().unwrap();
+        let identity = Identity::new().with_root(root.path());
+        assert!(identity.machine_id().is_err());
+
+        let first = identity.regenerate_machine_id().unwrap();
+        fs::create_dir_all(root.path().join("var/log/journal").join(&first)).unwrap();
+        let second = identity.regenerate_machine_id().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(identity.machine_id().unwrap(), second);
+        assert!(root.path().join("var/log/journal").join(&second).is_dir());
+
+        identity.reset_machine_id().unwrap();
+        assert!(matches!(identity.machine_id(), Err(IdentityError::InvalidMachineId(_))));
+    }
+
+    #[test]
+    fn test_machine_identity() {
+        let root = tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(root.path().join("etc/machine-id"), "0123456789abcdef0123456789abcdef\n").unwrap();
+
+        let identity = Identity::new().with_root(root.path()).machine_identity().unwrap();
+        // Same as `systemd-id128 machine-id --app-specific=d737111dce4a434650dc69a946a00b2d`
+        assert_eq!(identity.id, "87733df0ad8c404291115996e84f8157");
+        assert_eq!(identity.hostname, None);
+    }
+}
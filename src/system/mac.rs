@@ -0,0 +1,496 @@
+//! Mandatory access control: SELinux and AppArmor
+//!
+//! rastOS boots with whichever of the two the kernel command line selects.
+//! [`Mac::status`] reports the active module and its enforcement mode, and
+//! [`Mac::set_mode`] switches between enforcing and permissive, optionally
+//! persistently. SELinux has one mode for the whole system; for AppArmor the
+//! mode applies to the profiles rastOS ships.
+//!
+//! Per-application profiles ship in `/usr/share/rastos/mac`, as AppArmor
+//! profiles in `apparmor/<name>` and SELinux policy modules in
+//! `selinux/<name>.pp`. Installed ones confine containers started with
+//! [`Mac::container_label`].
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Profiles shipped with rastOS, relative to the root
+const SHIPPED_DIR: &str = "usr/share/rastos/mac";
+
+/// Active security modules, relative to the root
+const LSM_LIST: &str = "sys/kernel/security/lsm";
+
+/// SELinux enforcement switch, relative to the root
+const SELINUX_ENFORCE: &str = "sys/fs/selinux/enforce";
+
+/// SELinux configuration read at boot, relative to the root
+const SELINUX_CONFIG: &str = "etc/selinux/config";
+
+/// Loaded AppArmor profiles, relative to the root
+const APPARMOR_PROFILES: &str = "sys/kernel/security/apparmor/profiles";
+
+/// Where AppArmor profiles are installed, relative to the root
+const APPARMOR_DIR: &str = "etc/apparmor.d";
+
+/// Prefix of the AppArmor profiles rastOS installs
+const PROFILE_PREFIX: &str = "rastos.";
+
+/// Profile of containers without their own profile
+const GENERIC_CONTAINER_PROFILE: &str = "container";
+
+/// SELinux context of container processes without their own profile
+const SELINUX_CONTAINER_PROCESS: &str = "system_u:system_r:container_t:s0";
+
+/// SELinux context of container files
+const SELINUX_CONTAINER_FILE: &str = "system_u:object_r:container_file_t:s0";
+
+/// Error type for mandatory access control
+#[derive(Error, Debug)]
+pub enum MacError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A command failed
+    #[error("{0}")]
+    Command(String),
+
+    /// Neither SELinux nor AppArmor is active
+    #[error("Neither SELinux nor AppArmor is active")]
+    Inactive,
+
+    /// No profile with that name ships with rastOS
+    #[error("No {lsm} profile '{name}' ships with rastOS")]
+    UnknownProfile {
+        /// Security module
+        lsm: Lsm,
+        /// Profile name
+        name: String,
+    },
+
+    /// Profile name that is not a plain file name
+    #[error("Invalid profile name '{0}'")]
+    InvalidName(String),
+}
+
+/// Result type for mandatory access control
+pub type Result<T> = std::result::Result<T, MacError>;
+
+/// A mandatory access control security module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lsm {
+    /// SELinux
+    SELinux,
+    /// AppArmor
+    AppArmor,
+}
+
+impl fmt::Display for Lsm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Lsm::SELinux => "SELinux",
+            Lsm::AppArmor => "AppArmor",
+        })
+    }
+}
+
+/// How a module or profile treats policy violations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Enforcement {
+    /// Denied and logged
+    Enforcing,
+    /// Allowed and logged
+    Permissive,
+}
+
+impl fmt::Display for Enforcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Enforcement::Enforcing => "enforcing",
+            Enforcement::Permissive => "permissive",
+        })
+    }
+}
+
+/// A loaded profile and its mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileState {
+    /// Profile name
+    pub name: String,
+    /// Enforcement mode
+    pub mode: Enforcement,
+}
+
+/// State of mandatory access control
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MacStatus {
+    /// Active module, if any
+    pub lsm: Option<Lsm>,
+    /// Mode of the module; for AppArmor, enforcing if every rastOS profile is
+    pub mode: Option<Enforcement>,
+    /// Loaded AppArmor profiles; SELinux has no per-profile modes
+    pub profiles: Vec<ProfileState>,
+}
+
+/// Security label for a container's OCI runtime spec
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacLabel {
+    /// AppArmor profile for the container process
+    AppArmor(String),
+    /// SELinux contexts for the container process and its mounts
+    SELinux {
+        /// Process context
+        process: String,
+        /// Context of mounted files
+        mount: String,
+    },
+}
+
+/// Manages SELinux or AppArmor on the system at a root directory
+#[derive(Debug, Clone)]
+pub struct Mac {
+    root: PathBuf,
+}
+
+impl Default for Mac {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mac {
+    /// Manage the running system
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::from("/"),
+        }
+    }
+
+    /// Read state below `root` instead; for tests and installation targets
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// The active module, if any
+    pub fn active(&self) -> Result<Option<Lsm>> {
+        match fs::read_to_string(self.root.join(LSM_LIST)) {
+            Ok(list) => Ok(parse_lsm_list(&list)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The active module, its mode and, for AppArmor, its profiles
+    pub fn status(&self) -> Result<MacStatus> {
+        let lsm = self.active()?;
+        let (mode, profiles) = match lsm {
+            Some(Lsm::SELinux) => (Some(self.selinux_mode()?), Vec::new()),
+            Some(Lsm::AppArmor) => {
+                let profiles = parse_apparmor_profiles(&fs::read_to_string(self.root.join(APPARMOR_PROFILES))?);
+                let permissive = profiles
+                    .iter()
+                    .any(|p| p.name.starts_with(PROFILE_PREFIX) && p.mode == Enforcement::Permissive);
+                let mode = if permissive { Enforcement::Permissive } else { Enforcement::Enforcing };
+                (Some(mode), profiles)
+            }
+            None => (None, Vec::new()),
+        };
+        Ok(MacStatus { lsm, mode, profiles })
+    }
+
+    /// Switch between enforcing and permissive
+    ///
+    /// With `persist` the mode also applies after a reboot: SELinux's
+    /// `/etc/selinux/config` is updated, and AppArmor profiles are installed
+    /// with the mode flag. Otherwise only the running kernel changes.
+    pub fn set_mode(&self, mode: Enforcement, persist: bool) -> Result<()> {
+        match self.active()?.ok_or(MacError::Inactive)? {
+            Lsm::SELinux => {
+                let value = if mode == Enforcement::Enforcing { "1" } else { "0" };
+                fs::write(self.root.join(SELINUX_ENFORCE), value)?;
+                if persist {
+                    let path = self.root.join(SELINUX_CONFIG);
+                    let config = fs::read_to_string(&path).unwrap_or_default();
+                    fs::write(&path, set_selinux_config(&config, mode))?;
+                }
+            }
+            Lsm::AppArmor => {
+                for profile in self.installed_apparmor()? {
+                    let path = self.root.join(APPARMOR_DIR).join(&profile);
+                    if persist {
+                        fs::write(&path, set_complain_flag(&fs::read_to_string(&path)?, mode))?;
+                        run(Command::new("apparmor_parser").arg("--replace").arg(&path))?;
+                    } else {
+                        let mut command = Command::new("apparmor_parser");
+                        if mode == Enforcement::Permissive {
+                            command.arg("--complain");
+                        }
+                        run(command.arg("--replace").arg(&path))?;
+                    }
+                }
+            }
+        }
+        info!("Mandatory access control is now {}", mode);
+        Ok(())
+    }
+
+    /// Profiles shipped with rastOS for the active module
+    pub fn available_profiles(&self) -> Result<Vec<String>> {
+        let lsm = self.active()?.ok_or(MacError::Inactive)?;
+        let dir = self.shipped_dir(lsm);
+        let mut names = Vec::new();
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let name = entry?.file_name().to_string_lossy().into_owned();
+                    match lsm {
+                        Lsm::AppArmor => names.push(name),
+                        Lsm::SELinux => names.extend(name.strip_suffix(".pp").map(str::to_string)),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Install and load the profile `name`
+    pub fn install_profile(&self, name: &str) -> Result<()> {
+        validate_name(name)?;
+        let lsm = self.active()?.ok_or(MacError::Inactive)?;
+        if !self.available_profiles()?.iter().any(|p| p == name) {
+            return Err(MacError::UnknownProfile { lsm, name: name.to_string() });
+        }
+        match lsm {
+            Lsm::AppArmor => {
+                let target = self.root.join(APPARMOR_DIR).join(format!("{}{}", PROFILE_PREFIX, name));
+                fs::copy(self.shipped_dir(lsm).join(name), &target)?;
+                run(Command::new("apparmor_parser").arg("--replace").arg(&target))?;
+            }
+            Lsm::SELinux => {
+                run(Command::new("semodule")
+                    .arg("--install")
+                    .arg(self.shipped_dir(lsm).join(format!("{}.pp", name))))?;
+            }
+        }
+        info!("Installed {} profile {}", lsm, name);
+        Ok(())
+    }
+
+    /// Unload and remove the profile `name`
+    pub fn remove_profile(&self, name: &str) -> Result<()> {
+        validate_name(name)?;
+        let lsm = self.active()?.ok_or(MacError::Inactive)?;
+        match lsm {
+            Lsm::AppArmor => {
+                let target = self.root.join(APPARMOR_DIR).join(format!("{}{}", PROFILE_PREFIX, name));
+                if !target.exists() {
+                    return Err(MacError::UnknownProfile { lsm, name: name.to_string() });
+                }
+                run(Command::new("apparmor_parser").arg("--remove").arg(&target))?;
+                fs::remove_file(&target)?;
+            }
+            Lsm::SELinux => run(Command::new("semodule").args(["--remove", name]))?,
+        }
+        info!("Removed {} profile {}", lsm, name);
+        Ok(())
+    }
+
+    /// Label confining a container with the profile `name`
+    ///
+    /// Without a profile, containers get the generic container label of the
+    /// active module; for AppArmor that is the shipped `container` profile. Returns `None` when neither module is active.
+    pub fn container_label(&self, name: Option<&str>) -> Result<Option<MacLabel>> {
+        if let Some(name) = name {
+            validate_name(name)?;
+        }
+        Ok(self.active()?.map(|lsm| match lsm {
+            Lsm::AppArmor => MacLabel::AppArmor(match name {
+                Some(name) => format!("{}{}", PROFILE_PREFIX, name),
+                None => format!("{}{}", PROFILE_PREFIX, GENERIC_CONTAINER_PROFILE),
+            }),
+            Lsm::SELinux => MacLabel::SELinux {
+                process: match name {
+                    Some(name) => format!("system_u:system_r:{}_t:s0", name),
+                    None => SELINUX_CONTAINER_PROCESS.to_string(),
+                },
+                mount: SELINUX_CONTAINER_FILE.to_string(),
+            },
+        }))
+    }
+
+    fn selinux_mode(&self) -> Result<Enforcement> {
+        let value = fs::read_to_string(self.root.join(SELINUX_ENFORCE))?;
+        Ok(if value.trim() == "1" { Enforcement::Enforcing } else { Enforcement::Permissive })
+    }
+
+    /// File names of the installed rastOS AppArmor profiles
+    fn installed_apparmor(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.root.join(APPARMOR_DIR))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(PROFILE_PREFIX) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn shipped_dir(&self, lsm: Lsm) -> PathBuf {
+        let dir = match lsm {
+            Lsm::SELinux => "selinux",
+            Lsm::AppArmor => "apparmor",
+        };
+        self.root.join(SHIPPED_DIR).join(dir)
+    }
+}
+
+/// The MAC module in a comma-separated LSM list
+fn parse_lsm_list(list: &str) -> Option<Lsm> {
+    list.trim().split(',').find_map(|name| match name {
+        "selinux" => Some(Lsm::SELinux),
+        "apparmor" => Some(Lsm::AppArmor),
+        _ => None,
+    })
+}
+
+/// Parse AppArmor's `profiles` file: `name (mode)` per line
+fn parse_apparmor_profiles(content: &str) -> Vec<ProfileState> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (name, mode) = line.trim().rsplit_once(" (")?;
+            let mode = match mode.trim_end_matches(')') {
+                "enforce" => Enforcement::Enforcing,
+                // "kill" and "unconfined" profiles are neither; only complain mode is permissive
+                "complain" => Enforcement::Permissive,
+                _ => Enforcement::Enforcing,
+            };
+            Some(ProfileState {
+                name: name.to_string(),
+                mode,
+            })
+        })
+        .collect()
+}
+
+/// Set `SELINUX=` in `/etc/selinux/config`
+fn set_selinux_config(config: &str, mode: Enforcement) -> String {
+    let setting = format!("SELINUX={}", mode);
+    let mut found = false;
+    let mut lines: Vec<String> = config
+        .lines()
+        .map(|line| {
+            if line.trim_start().starts_with("SELINUX=") {
+                found = true;
+                setting.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(setting);
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Add or remove the `complain` flag of an AppArmor profile's first block
+fn set_complain_flag(profile: &str, mode: Enforcement) -> String {
+    let mut done = false;
+    profile
+        .lines()
+        .map(|line| {
+            if done || !line.trim_end().ends_with('{') || line.trim_start().starts_with('#') {
+                return line.to_string();
+            }
+            done = true;
+            let head = line.trim_end().trim_end_matches('{').trim_end();
+            let head = match head.rsplit_once(" flags=") {
+                Some((head, _)) => head,
+                None => head,
+            };
+            match mode {
+                Enforcement::Permissive => format!("{} flags=(complain) {{", head),
+                Enforcement::Enforcing => format!("{} {{", head),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if crate::fs::is_plain_name(name) { Ok(()) } else { Err(MacError::InvalidName(name.to_string())) }
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    debug!("Running: {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| MacError::Command(format!("cannot run {}: {}", command.get_program().to_string_lossy(), e)))?;
+    if !output.status.success() {
+        return Err(MacError::Command(format!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_status() {
+        let root = tempdir().unwrap();
+        let mac = Mac::new().with_root(root.path());
+        assert_eq!(mac.status().unwrap().lsm, None);
+
+        fs::create_dir_all(root.path().join("sys/kernel/security/apparmor")).unwrap();
+        fs::write(root.path().join(LSM_LIST), "capability,landlock,lockdown,yama,apparmor,bpf").unwrap();
+        fs::write(
+            root.path().join(APPARMOR_PROFILES),
+            "rastos.firefox (complain)\n/usr/bin/man (enforce)\nunix-chkpwd (enforce)\n",
+        )
+        .unwrap();
+        let status = mac.status().unwrap();
+        assert_eq!(status.lsm, Some(Lsm::AppArmor));
+        assert_eq!(status.mode, Some(Enforcement::Permissive));
+        assert_eq!(status.profiles[1].name, "/usr/bin/man");
+
+        assert_eq!(
+            mac.container_label(Some("firefox")).unwrap(),
+            Some(MacLabel::AppArmor("rastos.firefox".to_string()))
+        );
+        assert!(mac.container_label(Some("../etc")).is_err());
+    }
+
+    #[test]
+    fn test_persist_mode() {
+        assert_eq!(
+            set_selinux_config("# comment\nSELINUX=enforcing\nSELINUXTYPE=targeted\n", Enforcement::Permissive),
+            "# comment\nSELINUX=permissive\nSELINUXTYPE=targeted\n"
+        );
+
+        let profile = "#include <tunables/global>\nprofile firefox /usr/lib/firefox/firefox {\n  #include <abstractions/base>\n}\n";
+        let complain = set_complain_flag(profile, Enforcement::Permissive);
+        assert!(complain.contains("profile firefox /usr/lib/firefox/firefox flags=(complain) {\n"));
+        assert_eq!(set_complain_flag(&complain, Enforcement::Enforcing), profile);
+    }
+}
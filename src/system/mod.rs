@@ -1,5 +1,27 @@
 //! System-level operations for rastOS
 
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod config;
+pub mod doctor;
+pub mod hardware;
+pub mod identity;
+pub mod mac;
+pub mod network;
+pub mod power;
+pub mod reset;
+pub mod update;
+
+pub use config::{ConfigEngine, ConfigError, Generation, MachineManifest, ManifestDiff};
+pub use doctor::{Doctor, DoctorReport, Finding, Severity};
+pub use hardware::HardwareInventory;
+pub use identity::{HostnameKind, Identity, IdentityError, MachineIdentity};
+pub use mac::{Enforcement, Lsm, Mac, MacError, MacLabel, MacStatus};
+pub use network::{InterfaceConfig, LinkState, LinkType, Network, NetworkConfig, NetworkError};
+pub use power::{Inhibitor, Power, PowerAction, PowerError};
+pub use reset::{FactoryReset, ResetError, ResetOutcome};
+pub use update::{Deployment, UpdateConfig, UpdateError, UpdateOptions, Updater};
+
 /// Handles system-level operations
 pub struct SystemManager {
     // Implementation will be added later
@@ -0,0 +1,685 @@
+//! Network configuration with systemd-networkd and iwd
+//!
+//! Interfaces are declared in a [`NetworkConfig`], usually read from
+//! `/etc/rast/network.toml`, and rendered into `.network` and `.netdev`
+//! files for systemd-networkd plus `.psk` files for iwd. rastOS only touches
+//! the files it generated, which all start with [`FILE_PREFIX`], so
+//! hand-written configuration keeps working next to them.
+//!
+//! ```toml
+//! [[interface]]
+//! name = "enp3s0"
+//! dhcp = false
+//!
+//! [[interface]]
+//! name = "br0"
+//! type = "bridge"
+//! ports = ["enp3s0"]
+//! address = ["192.168.1.10/24"]
+//! gateway = "192.168.1.1"
+//!
+//! [[interface]]
+//! name = "wlan0"
+//! type = "wifi"
+//! ssid = "home"
+//! passphrase = "correct horse battery staple"
+//! ```
+//!
+//! Applying a configuration to the running system reloads networkd and waits
+//! for connectivity. If the machine does not come back online in time, the
+//! previous files are restored, so a typo cannot lock out a remote admin.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::fs::{atomic_write_with_options, WriteOptions};
+
+/// Default location of the network configuration
+pub const NETWORK_CONFIG_PATH: &str = "/etc/rast/network.toml";
+
+/// Prefix of every file rastOS generates
+pub const FILE_PREFIX: &str = "50-rast-";
+
+/// systemd-networkd configuration directory
+const NETWORKD_DIR: &str = "etc/systemd/network";
+
+/// iwd network profile directory
+const IWD_DIR: &str = "var/lib/iwd";
+
+/// Longest interface name the kernel accepts
+const IFNAMSIZ: usize = 15;
+
+/// Error type for network operations
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Configuration that cannot be read
+    #[error("Failed to parse network configuration: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    /// Configuration that is inconsistent
+    #[error("Invalid interface '{name}': {reason}")]
+    Invalid {
+        /// The interface
+        name: String,
+        /// What is wrong with it
+        reason: String,
+    },
+
+    /// `networkctl` or another tool failed
+    #[error("{0}")]
+    Command(String),
+
+    /// The system lost connectivity and the previous configuration was restored
+    #[error("No connectivity after applying the configuration; previous configuration restored")]
+    RolledBack,
+}
+
+/// Result type for network operations
+pub type Result<T> = std::result::Result<T, NetworkError>;
+
+/// Kind of interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkType {
+    /// A physical wired interface
+    #[default]
+    Ethernet,
+    /// A tagged VLAN on top of a `parent` interface
+    Vlan,
+    /// A software bridge, for example for containers
+    Bridge,
+    /// A wireless interface managed by iwd
+    Wifi,
+}
+
+/// A declared interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceConfig {
+    /// Interface name, such as `enp3s0`, `br0` or `enp3s0.10`
+    pub name: String,
+
+    /// Kind of interface
+    #[serde(rename = "type", default)]
+    pub kind: LinkType,
+
+    /// Match a physical interface by MAC address instead of name
+    #[serde(default)]
+    pub mac: Option<String>,
+
+    /// Get an address with DHCP
+    #[serde(default = "default_dhcp")]
+    pub dhcp: bool,
+
+    /// Static addresses with prefix length, such as `192.168.1.10/24`
+    #[serde(default)]
+    pub address: Vec<String>,
+
+    /// Default gateway for static addresses
+    #[serde(default)]
+    pub gateway: Option<String>,
+
+    /// DNS servers
+    #[serde(default)]
+    pub dns: Vec<String>,
+
+    /// MTU in bytes
+    #[serde(default)]
+    pub mtu: Option<u32>,
+
+    /// Underlying interface of a VLAN
+    #[serde(default)]
+    pub parent: Option<String>,
+
+    /// VLAN ID
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+
+    /// Interfaces enslaved to a bridge
+    #[serde(default)]
+    pub ports: Vec<String>,
+
+    /// Wi-Fi network name
+    #[serde(default)]
+    pub ssid: Option<String>,
+
+    /// Wi-Fi passphrase; open networks have none
+    #[serde(default)]
+    pub passphrase: Option<String>,
+}
+
+fn default_dhcp() -> bool {
+    true
+}
+
+impl InterfaceConfig {
+    /// An interface with DHCP and nothing else configured
+    pub fn new<S: Into<String>>(name: S, kind: LinkType) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            mac: None,
+            dhcp: true,
+            address: Vec::new(),
+            gateway: None,
+            dns: Vec::new(),
+            mtu: None,
+            parent: None,
+            vlan_id: None,
+            ports: Vec::new(),
+            ssid: None,
+            passphrase: None,
+        }
+    }
+
+    fn invalid(&self, reason: impl Into<String>) -> NetworkError {
+        NetworkError::Invalid {
+            name: self.name.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// How to tell whether the system is still online after a change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectivityCheck {
+    /// Host to ping; without one, any routable link counts as online
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// Seconds to wait before rolling back
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+impl Default for ConnectivityCheck {
+    fn default() -> Self {
+        Self {
+            host: None,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+/// Declared network configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Declared interfaces
+    #[serde(default, rename = "interface")]
+    pub interfaces: Vec<InterfaceConfig>,
+
+    /// Connectivity check used when applying
+    #[serde(default)]
+    pub connectivity: ConnectivityCheck,
+}
+
+impl NetworkConfig {
+    /// Load the configuration from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the declared interfaces fit together
+    pub fn validate(&self) -> Result<()> {
+        let mut names = BTreeSet::new();
+        for iface in &self.interfaces {
+            let reserved = iface.name == "." || iface.name == "..";
+            if iface.name.is_empty() || iface.name.len() > IFNAMSIZ || reserved || iface.name.contains(['/', ' ', ':']) {
+                return Err(iface.invalid("not a valid interface name"));
+            }
+            if !names.insert(iface.name.as_str()) {
+                return Err(iface.invalid("declared twice"));
+            }
+            if let Some(address) = iface.address.iter().find(|a| !a.contains('/')) {
+                return Err(iface.invalid(format!("address {} has no prefix length", address)));
+            }
+            match iface.kind {
+                LinkType::Vlan => {
+                    let parent = iface.parent.as_deref().ok_or_else(|| iface.invalid("VLAN without a parent"))?;
+                    if !self.interfaces.iter().any(|other| other.name == parent) {
+                        return Err(iface.invalid(format!("parent {} is not declared", parent)));
+                    }
+                    if !matches!(iface.vlan_id, Some(1..=4094)) {
+                        return Err(iface.invalid("VLAN ID must be between 1 and 4094"));
+                    }
+                }
+                LinkType::Wifi if iface.ssid.as_deref().unwrap_or_default().is_empty() => {
+                    return Err(iface.invalid("Wi-Fi without an SSID"));
+                }
+                _ => {}
+            }
+        }
+
+        // A bridge port gets its configuration from the bridge
+        for bridge in self.interfaces.iter().filter(|i| i.kind == LinkType::Bridge) {
+            for port in &bridge.ports {
+                if let Some(declared) = self.interfaces.iter().find(|i| &i.name == port) {
+                    if declared.dhcp || !declared.address.is_empty() {
+                        return Err(declared.invalid(format!("port of bridge {} cannot have addresses", bridge.name)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the networkd and iwd files, keyed by path relative to the root
+    pub fn render(&self) -> Result<BTreeMap<PathBuf, String>> {
+        self.validate()?;
+        let mut files = BTreeMap::new();
+
+        for iface in &self.interfaces {
+            let base = Path::new(NETWORKD_DIR).join(format!("{}{}", FILE_PREFIX, iface.name));
+
+            match iface.kind {
+                LinkType::Vlan => {
+                    let netdev = format!(
+                        "[NetDev]\nName={}\nKind=vlan\n\n[VLAN]\nId={}\n",
+                        iface.name,
+                        iface.vlan_id.unwrap_or_default()
+                    );
+                    files.insert(base.with_extension("netdev"), netdev);
+                }
+                LinkType::Bridge => {
+                    files.insert(
+                        base.with_extension("netdev"),
+                        format!("[NetDev]\nName={}\nKind=bridge\n", iface.name),
+                    );
+                }
+                LinkType::Wifi => {
+                    let ssid = iface.ssid.as_deref().unwrap_or_default();
+                    let (extension, security) = match &iface.passphrase {
+                        Some(passphrase) => ("psk", format!("[Security]\nPassphrase={}\n", passphrase)),
+                        None => ("open", String::new()),
+                    };
+                    let profile = format!("{}.{}", iwd_file_stem(ssid), extension);
+                    files.insert(Path::new(IWD_DIR).join(profile), format!("{}[Settings]\nAutoConnect=true\n", security));
+                }
+                LinkType::Ethernet => {}
+            }
+
+            files.insert(base.with_extension("network"), self.render_network(iface));
+        }
+
+        // Bridge ports that were not declared themselves
+        for bridge in self.interfaces.iter().filter(|i| i.kind == LinkType::Bridge) {
+            for port in bridge.ports.iter().filter(|p| !self.interfaces.iter().any(|i| &i.name == *p)) {
+                let path = Path::new(NETWORKD_DIR).join(format!("{}{}.network", FILE_PREFIX, port));
+                files.insert(path, format!("[Match]\nName={}\n\n[Network]\nBridge={}\n", port, bridge.name));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn render_network(&self, iface: &InterfaceConfig) -> String {
+        let mut out = String::from("[Match]\n");
+        match &iface.mac {
+            Some(mac) => writeln!(out, "MACAddress={}", mac).unwrap(),
+            None => writeln!(out, "Name={}", iface.name).unwrap(),
+        }
+
+        if let Some(mtu) = iface.mtu {
+            write!(out, "\n[Link]\nMTUBytes={}\n", mtu).unwrap();
+        }
+
+        out.push_str("\n[Network]\n");
+        let bridge = self
+            .interfaces
+            .iter()
+            .find(|b| b.kind == LinkType::Bridge && b.ports.contains(&iface.name));
+        if let Some(bridge) = bridge {
+            writeln!(out, "Bridge={}", bridge.name).unwrap();
+        } else {
+            if iface.dhcp {
+                out.push_str("DHCP=yes\n");
+            } else if iface.address.is_empty() {
+                out.push_str("LinkLocalAddressing=no\n");
+            }
+            for address in &iface.address {
+                writeln!(out, "Address={}", address).unwrap();
+            }
+            if let Some(gateway) = &iface.gateway {
+                writeln!(out, "Gateway={}", gateway).unwrap();
+            }
+            for dns in &iface.dns {
+                writeln!(out, "DNS={}", dns).unwrap();
+            }
+        }
+        for vlan in self
+            .interfaces
+            .iter()
+            .filter(|v| v.kind == LinkType::Vlan && v.parent.as_deref() == Some(iface.name.as_str()))
+        {
+            writeln!(out, "VLAN={}", vlan.name).unwrap();
+        }
+        if iface.kind == LinkType::Bridge {
+            // Containers attach late; do not wait for them to come up
+            out.push_str("ConfigureWithoutCarrier=yes\n");
+        }
+        out
+    }
+}
+
+/// iwd names profiles after the SSID, hex-encoded when it has unusual characters
+fn iwd_file_stem(ssid: &str) -> String {
+    if ssid.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '_' || c == '-') {
+        ssid.to_string()
+    } else {
+        let hex: String = ssid.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("={}", hex)
+    }
+}
+
+/// State of a link as reported by networkd
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkState {
+    /// Interface index
+    pub index: u32,
+    /// Interface name
+    pub name: String,
+    /// Link type, such as `ether`, `wlan` or `bridge`
+    pub kind: String,
+    /// Operational state, such as `routable`, `carrier` or `off`
+    pub operational: String,
+    /// Setup state, such as `configured`, `configuring` or `unmanaged`
+    pub setup: String,
+}
+
+impl LinkState {
+    /// Whether the link has a route to other networks
+    pub fn is_routable(&self) -> bool {
+        self.operational == "routable"
+    }
+
+    /// Parse the output of `networkctl list --no-legend`
+    pub fn parse_list(output: &str) -> Vec<Self> {
+        output
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                if fields.len() < 5 {
+                    return None;
+                }
+                Some(Self {
+                    index: fields[0].parse().ok()?,
+                    name: fields[1].to_string(),
+                    kind: fields[2].to_string(),
+                    operational: fields[3].to_string(),
+                    setup: fields[4].to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Applies network configuration to the system at a root directory
+#[derive(Debug, Clone)]
+pub struct Network {
+    root: PathBuf,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Network {
+    /// The network of the running system
+    pub fn new() -> Self {
+        Self { root: PathBuf::from("/") }
+    }
+
+    /// Configure the system installed at `root` instead
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    fn is_live(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    /// Write the rendered configuration, replacing previously generated files
+    ///
+    /// On the running system networkd is reloaded afterwards. If the
+    /// connectivity check fails, the previous files are restored and
+    /// [`NetworkError::RolledBack`] is returned.
+    pub fn apply(&self, config: &NetworkConfig) -> Result<()> {
+        let files = config.render()?;
+        let previous = self.generated_files(&files)?;
+
+        self.replace(&previous, &files)?;
+        if !self.is_live() {
+            return Ok(());
+        }
+
+        let result = self
+            .reload(config)
+            .and_then(|()| self.wait_online(&config.connectivity));
+        if let Err(e) = result {
+            warn!("Network configuration failed ({}), restoring previous configuration", e);
+            self.replace(&files, &previous)?;
+            self.reload(config)?;
+            return Err(NetworkError::RolledBack);
+        }
+        info!("Applied network configuration for {} interfaces", config.interfaces.len());
+        Ok(())
+    }
+
+    /// State of every link
+    pub fn links(&self) -> Result<Vec<LinkState>> {
+        let output = networkctl(&["list", "--no-legend", "--no-pager"])?;
+        Ok(LinkState::parse_list(&output))
+    }
+
+    /// Generated files currently on disk, plus any that `files` would overwrite
+    fn generated_files(&self, files: &BTreeMap<PathBuf, String>) -> Result<BTreeMap<PathBuf, String>> {
+        let mut existing = BTreeMap::new();
+        if let Ok(entries) = fs::read_dir(self.root.join(NETWORKD_DIR)) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(FILE_PREFIX) {
+                    let relative = Path::new(NETWORKD_DIR).join(entry.file_name());
+                    existing.insert(relative, fs::read_to_string(entry.path())?);
+                }
+            }
+        }
+        for path in files.keys().filter(|p| p.starts_with(IWD_DIR)) {
+            if let Ok(content) = fs::read_to_string(self.root.join(path)) {
+                existing.insert(path.clone(), content);
+            }
+        }
+        Ok(existing)
+    }
+
+    /// Remove the files of `old` missing from `new`, then write `new`
+    fn replace(&self, old: &BTreeMap<PathBuf, String>, new: &BTreeMap<PathBuf, String>) -> Result<()> {
+        for path in old.keys().filter(|p| !new.contains_key(*p)) {
+            match fs::remove_file(self.root.join(path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        for (path, content) in new {
+            let target = self.root.join(path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // networkd reads its files as an unprivileged user; Wi-Fi secrets stay private
+            let mode = if path.starts_with(IWD_DIR) { 0o600 } else { 0o644 };
+            let options = WriteOptions::default().with_mode(mode);
+            atomic_write_with_options(&target, content, &options).map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+
+    fn reload(&self, config: &NetworkConfig) -> Result<()> {
+        networkctl(&["reload"])?;
+        let mut args = vec!["reconfigure"];
+        let links = self.links()?;
+        args.extend(
+            config
+                .interfaces
+                .iter()
+                .map(|i| i.name.as_str())
+                .filter(|name| links.iter().any(|l| l.name == *name)),
+        );
+        if args.len() > 1 {
+            networkctl(&args)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the connectivity check passes or times out
+    fn wait_online(&self, check: &ConnectivityCheck) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(check.timeout_secs);
+        loop {
+            let routable = self.links()?.iter().any(|l| l.kind != "loopback" && l.is_routable());
+            let reachable = match &check.host {
+                Some(host) => Command::new("ping")
+                    .args(["-c", "1", "-W", "2", host])
+                    .output()
+                    .map(|o| o.status.success())
+                    .unwrap_or(false),
+                None => true,
+            };
+            if routable && reachable {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(NetworkError::Command(format!(
+                    "not online after {} seconds",
+                    check.timeout_secs
+                )));
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+fn networkctl(args: &[&str]) -> Result<String> {
+    let output = Command::new("networkctl")
+        .args(args)
+        .output()
+        .map_err(|e| NetworkError::Command(format!("cannot run networkctl: {}", e)))?;
+    if !output.status.success() {
+        return Err(NetworkError::Command(format!(
+            "networkctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const CONFIG: &str = r#"
+[[interface]]
+name = "enp3s0"
+dhcp = false
+
+[[interface]]
+name = "enp3s0.10"
+type = "vlan"
+parent = "enp3s0"
+vlan_id = 10
+address = ["10.0.10.2/24"]
+
+[[interface]]
+name = "br0"
+type = "bridge"
+ports = ["enp4s0"]
+address = ["192.168.1.10/24"]
+gateway = "192.168.1.1"
+dns = ["192.168.1.1"]
+
+[[interface]]
+name = "wlan0"
+type = "wifi"
+ssid = "café"
+passphrase = "secret"
+"#;
+
+    #[test]
+    fn test_render_and_apply() {
+        let config: NetworkConfig = toml::from_str(CONFIG).unwrap();
+        let files = config.render().unwrap();
+
+        let parent = &files[Path::new("etc/systemd/network/50-rast-enp3s0.network")];
+        assert!(parent.contains("LinkLocalAddressing=no\nVLAN=enp3s0.10\n"));
+        assert!(files[Path::new("etc/systemd/network/50-rast-enp3s0.10.netdev")].contains("Kind=vlan\n\n[VLAN]\nId=10\n"));
+        let bridge = &files[Path::new("etc/systemd/network/50-rast-br0.network")];
+        assert!(bridge.contains("Address=192.168.1.10/24\nGateway=192.168.1.1\nDNS=192.168.1.1\n"));
+        assert!(!bridge.contains("DHCP"));
+        assert!(files[Path::new("etc/systemd/network/50-rast-enp4s0.network")].contains("Bridge=br0"));
+        assert!(files[Path::new("var/lib/iwd/=636166c3a9.psk")].contains("Passphrase=secret"));
+
+        let root = tempdir().unwrap();
+        let stale = root.path().join("etc/systemd/network/50-rast-old.network");
+        fs::create_dir_all(stale.parent().unwrap()).unwrap();
+        fs::write(&stale, "").unwrap();
+        fs::write(root.path().join("etc/systemd/network/20-wired.network"), "").unwrap();
+
+        Network::new().with_root(root.path()).apply(&config).unwrap();
+        assert!(!stale.exists());
+        assert!(root.path().join("etc/systemd/network/20-wired.network").exists());
+        assert!(root.path().join("etc/systemd/network/50-rast-br0.netdev").exists());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut vlan = InterfaceConfig::new("eth0.5", LinkType::Vlan);
+        vlan.parent = Some("eth0".to_string());
+        vlan.vlan_id = Some(5);
+        let config = NetworkConfig {
+            interfaces: vec![vlan],
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(NetworkError::Invalid { .. })));
+
+        let mut bridge = InterfaceConfig::new("br0", LinkType::Bridge);
+        bridge.ports = vec!["eth0".to_string()];
+        let mut config = NetworkConfig {
+            interfaces: vec![InterfaceConfig::new("eth0", LinkType::Ethernet), bridge],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.interfaces[0].dhcp = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let output = "  1 lo      loopback carrier    unmanaged\n  2 enp3s0  ether    routable   configured\n";
+        let links = LinkState::parse_list(output);
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[1].name, "enp3s0");
+        assert!(links[1].is_routable());
+        assert!(!links[0].is_routable());
+    }
+}
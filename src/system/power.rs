@@ -0,0 +1,356 @@
+//! Power management through systemd-logind
+//!
+//! Reboot, power off, suspend and hibernate go through logind, which honours
+//! inhibitor locks: a package transaction or a backup holding a `block`
+//! lock stops the action unless it is forced. [`Power::inhibitors`] lists
+//! the locks so a caller can say what is in the way.
+//!
+//! Reboots can also be scheduled, for example for 03:00 only if an update
+//! is waiting, and [`Power::reboot_into_snapshot`] boots a Btrfs snapshot
+//! once through a systemd-boot entry.
+
+use std::fmt;
+use std::process::Command;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::kernel::{BootEntryConfig, KernelError};
+use crate::system::doctor::{Doctor, Severity};
+
+/// Unit name of the scheduled reboot timer
+pub const SCHEDULED_REBOOT_UNIT: &str = "rast-scheduled-reboot";
+
+/// Error type for power management
+#[derive(Error, Debug)]
+pub enum PowerError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A command failed
+    #[error("{0}")]
+    Command(String),
+
+    /// Inhibitor locks block the action
+    #[error("{action} is blocked by {}", describe(.inhibitors))]
+    Inhibited {
+        /// The blocked action
+        action: PowerAction,
+        /// The blocking locks
+        inhibitors: Vec<Inhibitor>,
+    },
+
+    /// Time that is not `HH:MM`
+    #[error("Invalid time '{0}'; expected HH:MM")]
+    InvalidTime(String),
+
+    /// Boot entry error
+    #[error("Boot entry error: {0}")]
+    Boot(#[from] KernelError),
+}
+
+fn describe(inhibitors: &[Inhibitor]) -> String {
+    inhibitors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Result type for power management
+pub type Result<T> = std::result::Result<T, PowerError>;
+
+/// A power state change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    /// Restart the machine
+    Reboot,
+    /// Turn the machine off
+    Poweroff,
+    /// Suspend to RAM
+    Suspend,
+    /// Suspend to disk
+    Hibernate,
+}
+
+impl PowerAction {
+    /// The `systemctl` verb
+    pub fn verb(self) -> &'static str {
+        match self {
+            PowerAction::Reboot => "reboot",
+            PowerAction::Poweroff => "poweroff",
+            PowerAction::Suspend => "suspend",
+            PowerAction::Hibernate => "hibernate",
+        }
+    }
+
+    /// The inhibitor lock type that can block the action
+    fn lock(self) -> &'static str {
+        match self {
+            PowerAction::Reboot | PowerAction::Poweroff => "shutdown",
+            PowerAction::Suspend | PowerAction::Hibernate => "sleep",
+        }
+    }
+}
+
+impl fmt::Display for PowerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.verb())
+    }
+}
+
+/// An inhibitor lock held with logind
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inhibitor {
+    /// Lock types, such as `shutdown` or `sleep`
+    pub what: Vec<String>,
+    /// Program holding the lock
+    pub who: String,
+    /// Why it holds the lock
+    pub why: String,
+    /// `block` stops the action, `delay` only postpones it
+    pub mode: String,
+    /// User ID of the holder
+    pub uid: u32,
+    /// Process ID of the holder
+    pub pid: u32,
+}
+
+impl Inhibitor {
+    /// Whether the lock stops `action`
+    pub fn blocks(&self, action: PowerAction) -> bool {
+        self.mode == "block" && self.what.iter().any(|w| w == action.lock())
+    }
+
+    /// Parse the JSON output of logind's `ListInhibitors` from `busctl --json=short`
+    pub fn parse_list(json: &str) -> Result<Vec<Self>> {
+        /// `(what, who, why, mode, uid, pid)`
+        type Entry = (String, String, String, String, u32, u32);
+        #[derive(Deserialize)]
+        struct Reply {
+            data: Vec<Vec<Entry>>,
+        }
+        let reply: Reply = serde_json::from_str(json)
+            .map_err(|e| PowerError::Command(format!("unexpected ListInhibitors reply: {}", e)))?;
+        Ok(reply
+            .data
+            .into_iter()
+            .flatten()
+            .map(|(what, who, why, mode, uid, pid)| Self {
+                what: what.split(':').map(str::to_string).collect(),
+                who,
+                why,
+                mode,
+                uid,
+                pid,
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for Inhibitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pid {}): {}", self.who, self.pid, self.why)
+    }
+}
+
+/// Changes the power state of the running system
+#[derive(Debug, Clone, Default)]
+pub struct Power {
+    force: bool,
+    boot: BootEntryConfig,
+}
+
+impl Power {
+    /// Power management honouring inhibitor locks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore inhibitor locks
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Use these boot entry settings for snapshot boots
+    pub fn with_boot_config(mut self, boot: BootEntryConfig) -> Self {
+        self.boot = boot;
+        self
+    }
+
+    /// Inhibitor locks currently held
+    pub fn inhibitors(&self) -> Result<Vec<Inhibitor>> {
+        let output = command(
+            "busctl",
+            &[
+                "call",
+                "--json=short",
+                "org.freedesktop.login1",
+                "/org/freedesktop/login1",
+                "org.freedesktop.login1.Manager",
+                "ListInhibitors",
+            ],
+        )?;
+        Inhibitor::parse_list(&output)
+    }
+
+    /// Perform `action`, unless a lock blocks it and the action is not forced
+    pub fn perform(&self, action: PowerAction) -> Result<()> {
+        if !self.force {
+            let blocking: Vec<_> = self.inhibitors()?.into_iter().filter(|i| i.blocks(action)).collect();
+            if !blocking.is_empty() {
+                return Err(PowerError::Inhibited {
+                    action,
+                    inhibitors: blocking,
+                });
+            }
+        }
+        info!("Requesting {}", action);
+        let mut args = vec![action.verb()];
+        if self.force {
+            args.push("--check-inhibitors=no");
+        }
+        command("systemctl", &args).map(|_| ())
+    }
+
+    /// Restart the machine
+    pub fn reboot(&self) -> Result<()> {
+        self.perform(PowerAction::Reboot)
+    }
+
+    /// Turn the machine off
+    pub fn poweroff(&self) -> Result<()> {
+        self.perform(PowerAction::Poweroff)
+    }
+
+    /// Suspend to RAM
+    pub fn suspend(&self) -> Result<()> {
+        self.perform(PowerAction::Suspend)
+    }
+
+    /// Suspend to disk
+    pub fn hibernate(&self) -> Result<()> {
+        self.perform(PowerAction::Hibernate)
+    }
+
+    /// Whether an update is waiting for a reboot
+    ///
+    /// True when an installed package asks for one, the running kernel was
+    /// replaced, or an update deployment is staged for the next boot.
+    pub fn reboot_pending(&self) -> bool {
+        Doctor::new().check_reboot().severity != Severity::Ok
+    }
+
+    /// Reboot at `time` (`HH:MM`), optionally only if a reboot is pending then
+    ///
+    /// The reboot runs from a transient timer, [`SCHEDULED_REBOOT_UNIT`],
+    /// which fires daily until the machine reboots; a skipped reboot is
+    /// retried the next day. Replaces an earlier schedule.
+    pub fn schedule_reboot(&self, time: &str, only_if_pending: bool) -> Result<()> {
+        let calendar = parse_time(time)?;
+        self.cancel_scheduled_reboot()?;
+
+        let exe = std::env::current_exe()?;
+        let mut args = vec![
+            format!("--unit={}", SCHEDULED_REBOOT_UNIT),
+            format!("--on-calendar={}", calendar),
+            "--timer-property=AccuracySec=1min".to_string(),
+            "--description=Scheduled reboot".to_string(),
+            exe.to_string_lossy().into_owned(),
+            "power".to_string(),
+            "reboot".to_string(),
+        ];
+        if only_if_pending {
+            args.push("--if-pending".to_string());
+        }
+        if self.force {
+            args.push("--force".to_string());
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        command("systemd-run", &args)?;
+        info!("Scheduled reboot at {}", calendar);
+        Ok(())
+    }
+
+    /// Remove a scheduled reboot; does nothing without one
+    pub fn cancel_scheduled_reboot(&self) -> Result<()> {
+        let timer = format!("{}.timer", SCHEDULED_REBOOT_UNIT);
+        let active = Command::new("systemctl")
+            .args(["is-active", "--quiet", &timer])
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        if active {
+            command("systemctl", &["stop", &timer])?;
+        }
+        Ok(())
+    }
+
+    /// Boot the Btrfs subvolume `subvolume` once, then reboot
+    ///
+    /// The next boot after that starts the default entry again, so a
+    /// snapshot that does not come up cannot lock the machine out.
+    pub fn reboot_into_snapshot(&self, subvolume: &str) -> Result<()> {
+        let entry = self.boot.snapshot_entry(subvolume)?;
+        self.boot.set_oneshot(&entry)?;
+        info!("Booting {} once through {}", subvolume, entry);
+        self.reboot()
+    }
+}
+
+/// Turn `HH:MM` into a daily systemd calendar expression
+fn parse_time(time: &str) -> Result<String> {
+    let invalid = || PowerError::InvalidTime(time.to_string());
+    let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+    let hour: u32 = hour.parse().map_err(|_| invalid())?;
+    let minute: u32 = minute.parse().map_err(|_| invalid())?;
+    if hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+    Ok(format!("*-*-* {:02}:{:02}:00", hour, minute))
+}
+
+fn command(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| PowerError::Command(format!("cannot run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(PowerError::Command(format!(
+            "{} {} failed: {}",
+            program,
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inhibitors() {
+        let json = r#"{"type":"a(ssssuu)","data":[[["shutdown:sleep","rast-package","Package transaction","block",0,812],["sleep","NetworkManager","NetworkManager needs to turn off networks","delay",0,640]]]}"#;
+        let inhibitors = Inhibitor::parse_list(json).unwrap();
+        assert_eq!(inhibitors.len(), 2);
+        assert!(inhibitors[0].blocks(PowerAction::Reboot));
+        assert!(inhibitors[0].blocks(PowerAction::Suspend));
+        assert!(!inhibitors[1].blocks(PowerAction::Suspend));
+
+        let error = PowerError::Inhibited {
+            action: PowerAction::Reboot,
+            inhibitors: vec![inhibitors[0].clone()],
+        };
+        assert_eq!(error.to_string(), "reboot is blocked by rast-package (pid 812): Package transaction");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("03:00").unwrap(), "*-*-* 03:00:00");
+        assert_eq!(parse_time("3:5").unwrap(), "*-*-* 03:05:00");
+        assert!(parse_time("24:00").is_err());
+        assert!(parse_time("noon").is_err());
+    }
+}
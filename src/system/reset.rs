@@ -0,0 +1,337 @@
+//! Factory reset
+//!
+//! The installer keeps a read-only snapshot of the freshly installed system
+//! in `@factory`. A reset builds a new `@` from it, wipes the secrets that
+//! make a machine unique from the copy and arms the first-boot tasks again,
+//! then swaps it in for the current root. The old root is kept as
+//! `@reset-<time>` until [`FactoryReset::purge`] deletes it, so a reset
+//! takes effect on the next boot and can still be undone before then.
+//!
+//! Home directories can be kept: a `@home` subvolume is left in place and
+//! stays mounted, and homes inside the root are copied into the new one.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::Utc;
+use log::{debug, info, warn};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::installer::provision::rearm_first_boot;
+use crate::installer::{InstallerError, FACTORY_SUBVOLUME, ROOT_SUBVOLUME};
+use crate::system::identity::{Identity, IdentityError};
+use crate::system::update::{delete_subvolume, root_mount, set_default, ToplevelMount, UpdateError, BASE_ENTRY};
+
+/// Mount point of the root filesystem's top level during a reset
+const TOPLEVEL_MOUNT: &str = "/run/rastos-reset/toplevel";
+
+/// Prefix of the subvolumes a reset moves the old system to
+const ARCHIVE_PREFIX: &str = "@reset-";
+
+/// Name of the new root while it is prepared
+const STAGING_SUBVOLUME: &str = "@reset-staging";
+
+/// Subvolume users may have created for `/home`
+const HOME_SUBVOLUME: &str = "@home";
+
+/// Machine-specific secrets removed from the reset system, relative to its root
+///
+/// Entries ending in `*` match by prefix.
+const SECRETS: &[&str] = &[
+    "etc/ssh/ssh_host_*",
+    "etc/rast/auth",
+    "var/lib/systemd/credential.secret",
+    "var/lib/systemd/random-seed",
+    "var/lib/rast/generations",
+];
+
+/// Error type for factory resets
+#[derive(Error, Debug)]
+pub enum ResetError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Btrfs or boot entry error
+    #[error("{0}")]
+    Update(#[from] UpdateError),
+
+    /// Machine ID error
+    #[error("Identity error: {0}")]
+    Identity(#[from] IdentityError),
+
+    /// First-boot provisioning error
+    #[error("Provisioning error: {0}")]
+    Provision(#[from] InstallerError),
+
+    /// The installer kept no factory snapshot
+    #[error("No factory snapshot {0} found; the system was not installed by the rastOS installer")]
+    NoFactorySnapshot(&'static str),
+
+    /// A command failed
+    #[error("{0}")]
+    Command(String),
+}
+
+/// Result type for factory resets
+pub type Result<T> = std::result::Result<T, ResetError>;
+
+/// What a reset did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResetOutcome {
+    /// Subvolumes the old system was moved to
+    pub archived: Vec<String>,
+    /// Secrets removed from the new root, relative to it
+    pub wiped: Vec<PathBuf>,
+    /// Whether first-boot tasks will run
+    pub first_boot: bool,
+}
+
+/// Resets the running system to the state it was installed in
+#[derive(Debug, Clone)]
+pub struct FactoryReset {
+    boot_dir: PathBuf,
+    toplevel: PathBuf,
+    keep_home: bool,
+}
+
+impl Default for FactoryReset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FactoryReset {
+    /// A reset discarding home directories
+    pub fn new() -> Self {
+        Self {
+            boot_dir: PathBuf::from("/boot"),
+            toplevel: PathBuf::from(TOPLEVEL_MOUNT),
+            keep_home: false,
+        }
+    }
+
+    /// Boot partition with the systemd-boot entries
+    pub fn with_boot_dir<P: AsRef<Path>>(mut self, boot_dir: P) -> Self {
+        self.boot_dir = boot_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Keep home directories
+    pub fn keep_home(mut self, keep: bool) -> Self {
+        self.keep_home = keep;
+        self
+    }
+
+    /// Replace the root with a fresh copy of the factory snapshot
+    ///
+    /// The running system is not touched; reboot to start the new one. The
+    /// factory entry becomes the boot default, so updates deployed since the
+    /// installation are no longer booted.
+    pub fn reset(&self) -> Result<ResetOutcome> {
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+        let factory = self.toplevel.join(FACTORY_SUBVOLUME);
+        if !factory.exists() {
+            return Err(ResetError::NoFactorySnapshot(FACTORY_SUBVOLUME));
+        }
+
+        let staging = self.toplevel.join(STAGING_SUBVOLUME);
+        if staging.exists() {
+            delete_subvolume(&staging)?;
+        }
+        crate::sys::btrfs::snapshot(&factory, &staging, false)?;
+
+        let mut outcome = match self.prepare(&staging, &booted) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                if let Err(cleanup) = delete_subvolume(&staging) {
+                    warn!("Failed to remove {}: {}", staging.display(), cleanup);
+                }
+                return Err(e);
+            }
+        };
+
+        let stamp = Utc::now().format("%Y%m%d%H%M%S");
+        let archive = format!("{}{}", ARCHIVE_PREFIX, stamp);
+        let root = self.toplevel.join(ROOT_SUBVOLUME);
+        fs::rename(&root, self.toplevel.join(&archive))?;
+        if let Err(e) = fs::rename(&staging, &root) {
+            fs::rename(self.toplevel.join(&archive), &root)?;
+            return Err(e.into());
+        }
+        info!("Moved the old root to {}", archive);
+        outcome.archived.push(archive);
+
+        let home = self.toplevel.join(HOME_SUBVOLUME);
+        if !self.keep_home && home.exists() {
+            let archive = format!("{}{}-home", ARCHIVE_PREFIX, stamp);
+            fs::rename(&home, self.toplevel.join(&archive))?;
+            info!("Moved {} to {}", HOME_SUBVOLUME, archive);
+            outcome.archived.push(archive);
+        }
+
+        // Deployments were made from the old root; boot the reset one
+        run(Command::new("bootctl").args(["set-oneshot", ""]))?;
+        set_default(&self.boot_dir, BASE_ENTRY)?;
+        info!("Reset to the factory state; reboot to start it");
+        Ok(outcome)
+    }
+
+    /// Delete the subvolumes earlier resets moved old systems to
+    ///
+    /// Returns the deleted subvolumes. The booted root is never deleted.
+    pub fn purge(&self) -> Result<Vec<String>> {
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+        let mut purged = Vec::new();
+        for entry in fs::read_dir(&self.toplevel)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(ARCHIVE_PREFIX) || name == STAGING_SUBVOLUME || name == booted {
+                continue;
+            }
+            delete_subvolume(&self.toplevel.join(&name))?;
+            info!("Deleted {}", name);
+            purged.push(name);
+        }
+        Ok(purged)
+    }
+
+    /// Turn the copy of the factory snapshot at `root` into a fresh system
+    fn prepare(&self, root: &Path, booted: &str) -> Result<ResetOutcome> {
+        let wiped = wipe_secrets(root)?;
+        let first_boot = rearm_first_boot(root)?;
+        if self.keep_home {
+            self.keep_homes(root, booted)?;
+        }
+        Ok(ResetOutcome {
+            archived: Vec::new(),
+            wiped,
+            first_boot,
+        })
+    }
+
+    /// Carry the home directories of the booted root over to `root`
+    fn keep_homes(&self, root: &Path, booted: &str) -> Result<()> {
+        let current = self.toplevel.join(booted);
+        if self.toplevel.join(HOME_SUBVOLUME).exists() {
+            // The factory fstab predates the subvolume; keep mounting it
+            let fstab = fs::read_to_string(current.join("etc/fstab"))?;
+            let Some(line) = fstab.lines().find(|line| line.split_whitespace().nth(1) == Some("/home")) else {
+                return Ok(());
+            };
+            let mut new = fs::read_to_string(root.join("etc/fstab")).unwrap_or_default();
+            if !new.lines().any(|l| l.split_whitespace().nth(1) == Some("/home")) {
+                new.push_str(line);
+                new.push('\n');
+                fs::write(root.join("etc/fstab"), new)?;
+            }
+            return Ok(());
+        }
+        let homes = current.join("home");
+        if homes.is_dir() {
+            info!("Copying home directories");
+            fs::create_dir_all(root.join("home"))?;
+            run(Command::new("cp")
+                .args(["-a", "--reflink=auto"])
+                .arg(homes.join("."))
+                .arg(root.join("home")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove machine-specific secrets from the system at `root`
+///
+/// The machine ID is reset so systemd generates a new one on the next boot;
+/// SSH host keys are generated again by sshd. Returns what was removed,
+/// relative to `root`.
+pub fn wipe_secrets(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut wiped = Vec::new();
+    for pattern in SECRETS {
+        let paths = match pattern.strip_suffix('*') {
+            Some(prefix) => {
+                let prefix = Path::new(prefix);
+                let (Some(dir), Some(name)) = (prefix.parent(), prefix.file_name()) else {
+                    continue;
+                };
+                let name = name.to_string_lossy();
+                match fs::read_dir(root.join(dir)) {
+                    Ok(entries) => entries
+                        .flatten()
+                        .filter(|e| e.file_name().to_string_lossy().starts_with(name.as_ref()))
+                        .map(|e| dir.join(e.file_name()))
+                        .collect(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            None => vec![PathBuf::from(pattern)],
+        };
+        for path in paths {
+            let full = root.join(&path);
+            let result = match fs::symlink_metadata(&full) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(&full),
+                Ok(_) => fs::remove_file(&full),
+                Err(_) => continue,
+            };
+            result?;
+            debug!("Removed {}", full.display());
+            wiped.push(path);
+        }
+    }
+    Identity::new().with_root(root).reset_machine_id()?;
+    Ok(wiped)
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    debug!("Running: {:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(ResetError::Command(format!(
+            "{} failed: {}",
+            command.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wipe_secrets() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let root = tempdir()?;
+        for file in [
+            "etc/ssh/ssh_host_ed25519_key",
+            "etc/ssh/ssh_host_ed25519_key.pub",
+            "etc/ssh/sshd_config",
+            "etc/rast/auth/keys.toml",
+            "etc/machine-id",
+        ] {
+            let path = root.path().join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "secret\n")?;
+        }
+
+        let mut wiped = wipe_secrets(root.path())?;
+        wiped.sort();
+        assert_eq!(
+            wiped,
+            [
+                Path::new("etc/rast/auth"),
+                Path::new("etc/ssh/ssh_host_ed25519_key"),
+                Path::new("etc/ssh/ssh_host_ed25519_key.pub"),
+            ]
+        );
+        assert!(root.path().join("etc/ssh/sshd_config").exists());
+        assert_eq!(fs::read_to_string(root.path().join("etc/machine-id"))?, "uninitialized\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,656 @@
+//! Atomic A/B system updates
+//!
+//! An update never touches the running root. [`Updater::stage`] takes a
+//! writable snapshot of it below [`DEPLOYMENTS_SUBVOLUME`], upgrades the
+//! packages (and optionally installs a kernel package) inside the snapshot,
+//! validates the result and writes a systemd-boot entry that boots it with
+//! `rastos.snapshot=`. Each deployment gets its own copy of the kernel and
+//! initramfs on the ESP, so every root keeps booting the kernel its modules
+//! belong to.
+//!
+//! The new entry is only selected for the next boot. If that boot fails,
+//! the following one starts the old root again. Once the new root came up,
+//! [`Updater::commit`] makes it the default; [`Updater::rollback`] goes back
+//! to the previous root at any time.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::installer::ROOT_SUBVOLUME;
+use crate::kernel::bootloader::{filter_cmdline, snapshot_cmdline};
+use crate::sandbox::{Sandbox, SandboxConfig};
+use crate::sys::btrfs;
+
+/// Subvolume on the Btrfs top level holding the deployments
+pub const DEPLOYMENTS_SUBVOLUME: &str = "@deployments";
+
+/// Where the Btrfs top level is mounted while updating
+const TOPLEVEL_MOUNT: &str = "/run/rastos-update/toplevel";
+
+/// Directory on the ESP holding the kernels of the deployments
+const ESP_DIR: &str = "rastos";
+
+/// Prefix of deployment boot entries
+const ENTRY_PREFIX: &str = "rastos-deploy-";
+
+/// Boot entry of the installed root
+pub(crate) const BASE_ENTRY: &str = "rastos.conf";
+
+/// Error type for system updates
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A command failed
+    #[error("{program} failed: {stderr}")]
+    Command {
+        /// The program that failed
+        program: String,
+        /// What it printed on stderr
+        stderr: String,
+    },
+
+    /// The updated root failed validation and was discarded
+    #[error("Validation failed: {0}")]
+    Validation(String),
+
+    /// No deployment with that ID, or none to roll back to
+    #[error("Unknown deployment: {0}")]
+    UnknownDeployment(String),
+
+    /// The root filesystem or bootloader does not support A/B updates
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    /// Deployment metadata that cannot be read or written
+    #[error("Invalid deployment metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+}
+
+/// Result type for system updates
+pub type Result<T> = std::result::Result<T, UpdateError>;
+
+/// Update settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateConfig {
+    /// ESP holding the boot entries
+    #[serde(default = "default_boot_dir")]
+    pub boot_dir: PathBuf,
+
+    /// Deployments to keep, including the booted and the new one
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+
+    /// Commands run inside the updated root; any failure discards the update
+    #[serde(default)]
+    pub validate: Vec<String>,
+
+    /// Sandbox the validation commands run in, which may not change the root
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+
+    /// Kernel command line (defaults to the running kernel's)
+    #[serde(default)]
+    pub cmdline: Option<String>,
+}
+
+fn default_boot_dir() -> PathBuf {
+    PathBuf::from("/boot")
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        Self {
+            boot_dir: default_boot_dir(),
+            keep: default_keep(),
+            validate: Vec::new(),
+            sandbox: SandboxConfig::default(),
+            cmdline: None,
+        }
+    }
+}
+
+impl UpdateConfig {
+    /// Set the ESP
+    pub fn with_boot_dir<P: AsRef<Path>>(mut self, boot_dir: P) -> Self {
+        self.boot_dir = boot_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Keep this many deployments (at least 2, so there is always a fallback)
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Run a validation command inside every updated root
+    pub fn with_validation<S: Into<String>>(mut self, command: S) -> Self {
+        self.validate.push(command.into());
+        self
+    }
+
+    /// Set the kernel command line
+    pub fn with_cmdline<S: Into<String>>(mut self, cmdline: S) -> Self {
+        self.cmdline = Some(cmdline.into());
+        self
+    }
+}
+
+/// What an update changes besides upgrading all packages
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Kernel package to install, such as one built by `kernel-builder`
+    pub kernel_package: Option<PathBuf>,
+
+    /// Upgrade from the package cache without downloading
+    pub offline: bool,
+}
+
+impl UpdateOptions {
+    /// Install a kernel package into the new root
+    pub fn with_kernel_package<P: AsRef<Path>>(mut self, package: P) -> Self {
+        self.kernel_package = Some(package.as_ref().to_path_buf());
+        self
+    }
+
+    /// Do not download packages
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+}
+
+/// A root filesystem produced by an update
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deployment {
+    /// Deployment ID, the creation time as `YYYYMMDDHHMMSS`
+    pub id: String,
+    /// Subvolume relative to the Btrfs top level
+    pub subvolume: String,
+    /// Subvolume the deployment was created from
+    pub parent: String,
+    /// Kernel release booted by the deployment
+    pub kernel: String,
+    /// When the deployment was created
+    pub created_at: DateTime<Utc>,
+}
+
+impl Deployment {
+    /// Boot entry file name
+    pub fn entry(&self) -> String {
+        format!("{}{}.conf", ENTRY_PREFIX, self.id)
+    }
+}
+
+/// Status of the deployments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateStatus {
+    /// Subvolume the running system was booted from
+    pub booted: String,
+    /// Default boot entry
+    pub default_entry: Option<String>,
+    /// Deployments, oldest first
+    pub deployments: Vec<Deployment>,
+}
+
+impl UpdateStatus {
+    /// Whether a staged deployment waits for a reboot
+    pub fn pending(&self) -> bool {
+        self.deployments
+            .last()
+            .is_some_and(|d| d.subvolume != self.booted && d.parent == self.booted)
+    }
+}
+
+/// Stages, commits and rolls back A/B updates
+#[derive(Debug, Clone)]
+pub struct Updater {
+    config: UpdateConfig,
+    toplevel: PathBuf,
+}
+
+impl Updater {
+    /// An updater for the running system
+    pub fn new(config: UpdateConfig) -> Self {
+        Self {
+            config,
+            toplevel: PathBuf::from(TOPLEVEL_MOUNT),
+        }
+    }
+
+    /// Create and validate a new deployment and boot it next time
+    ///
+    /// On any failure the new snapshot is deleted and the boot menu is left
+    /// unchanged.
+    pub fn stage(&self, options: &UpdateOptions) -> Result<Deployment> {
+        if !self.config.boot_dir.join("loader").is_dir() {
+            return Err(UpdateError::Unsupported(format!(
+                "no systemd-boot loader in {}",
+                self.config.boot_dir.display()
+            )));
+        }
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+
+        let now = Utc::now();
+        let id = now.format("%Y%m%d%H%M%S").to_string();
+        let subvolume = format!("{}/{}", DEPLOYMENTS_SUBVOLUME, id);
+        let target = self.toplevel.join(&subvolume);
+
+        let deployments_dir = self.toplevel.join(DEPLOYMENTS_SUBVOLUME);
+        if !deployments_dir.exists() {
+            btrfs::create_subvolume(&deployments_dir)?;
+        }
+        info!("Snapshotting {} to {}", booted, subvolume);
+        btrfs::snapshot(self.toplevel.join(&booted), &target, false)?;
+
+        let deployment = Deployment {
+            id,
+            subvolume,
+            parent: booted,
+            kernel: String::new(),
+            created_at: now,
+        };
+        let staged = self.prepare(&target, options).and_then(|kernel| {
+            let deployment = Deployment { kernel, ..deployment.clone() };
+            self.install_entry(&target, &deployment)?;
+            fs::write(self.metadata_path(&deployment.id), serde_json::to_vec_pretty(&deployment)?)?;
+            run(Command::new("bootctl").arg("set-oneshot").arg(deployment.entry()))?;
+            Ok(deployment)
+        });
+        let deployment = match staged {
+            Ok(deployment) => deployment,
+            Err(e) => {
+                warn!("Update failed, discarding {}: {}", deployment.subvolume, e);
+                self.remove(&deployment);
+                return Err(e);
+            }
+        };
+
+        info!("Deployment {} boots next; commit it after a successful boot", deployment.id);
+        self.prune(&deployment)?;
+        Ok(deployment)
+    }
+
+    /// Make the booted deployment the default once it came up
+    pub fn commit(&self) -> Result<Deployment> {
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+        let deployment = self
+            .deployments()?
+            .into_iter()
+            .find(|d| d.subvolume == booted)
+            .ok_or_else(|| UpdateError::UnknownDeployment(format!("booted root {} is not a deployment", booted)))?;
+        set_default(&self.config.boot_dir, &deployment.entry())?;
+        info!("Deployment {} is now the default", deployment.id);
+        Ok(deployment)
+    }
+
+    /// Boot the root the booted deployment was created from by default
+    ///
+    /// Returns the entry that is now the default; reboot to use it.
+    pub fn rollback(&self) -> Result<String> {
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+        let deployments = self.deployments()?;
+
+        // Roll back from the booted deployment, or from a staged one on the old root
+        let current = deployments
+            .iter()
+            .find(|d| d.subvolume == booted)
+            .or_else(|| deployments.iter().rev().find(|d| d.parent == booted));
+        let Some(current) = current else {
+            return Err(UpdateError::UnknownDeployment("nothing to roll back".to_string()));
+        };
+        let entry = if current.subvolume != booted {
+            // Not booted yet: cancel the one-time boot, the running root stays the default
+            run(Command::new("bootctl").args(["set-oneshot", ""]))?;
+            self.default_entry_for(&booted, &deployments)
+        } else {
+            self.default_entry_for(&current.parent, &deployments)
+        };
+        set_default(&self.config.boot_dir, &entry)?;
+        info!("Default boot entry is now {}", entry);
+        Ok(entry)
+    }
+
+    /// The booted root, the default entry and every deployment
+    pub fn status(&self) -> Result<UpdateStatus> {
+        let (device, booted) = root_mount()?;
+        let _toplevel = ToplevelMount::mount(&device, &self.toplevel)?;
+        Ok(UpdateStatus {
+            booted,
+            default_entry: read_default(&self.config.boot_dir),
+            deployments: self.deployments()?,
+        })
+    }
+
+    /// Upgrade and validate the new root, returning its kernel release
+    fn prepare(&self, target: &Path, options: &UpdateOptions) -> Result<String> {
+        if let Some(package) = &options.kernel_package {
+            let file_name = package
+                .file_name()
+                .ok_or_else(|| UpdateError::Validation(format!("not a package file: {}", package.display())))?;
+            let staged = target.join("var/cache/pacman/pkg").join(file_name);
+            fs::create_dir_all(staged.parent().unwrap_or(target))?;
+            fs::copy(package, &staged)?;
+        }
+
+        let sync = if options.offline { "-Su" } else { "-Syu" };
+        info!("Upgrading packages in {}", target.display());
+        run(Command::new("arch-chroot").arg(target).args(["pacman", sync, "--noconfirm"]))?;
+        if let Some(package) = &options.kernel_package {
+            let file_name = package.file_name().unwrap_or_default().to_string_lossy();
+            let path = format!("/var/cache/pacman/pkg/{}", file_name);
+            run(Command::new("arch-chroot").arg(target).args(["pacman", "-U", "--noconfirm", &path]))?;
+        }
+
+        self.validate(target)
+    }
+
+    /// Check that the new root can boot, returning its kernel release
+    fn validate(&self, target: &Path) -> Result<String> {
+        let (release, image, initramfs) = find_kernel(target)?;
+        if !initramfs.exists() {
+            return Err(UpdateError::Validation(format!("no initramfs at {}", initramfs.display())));
+        }
+        debug!("Kernel {} at {}", release, image.display());
+        if !target.join("etc/fstab").exists() {
+            return Err(UpdateError::Validation("no /etc/fstab".to_string()));
+        }
+        run(Command::new("arch-chroot").arg(target).args(["pacman", "-Dk"]))
+            .map_err(|e| UpdateError::Validation(format!("package database: {}", e)))?;
+        let sandbox = Sandbox::new(self.config.sandbox.clone()).with_root(target);
+        for command in &self.config.validate {
+            let output = sandbox
+                .output(Command::new("sh").args(["-c", command]))
+                .map_err(|e| UpdateError::Validation(format!("{}: {}", command, e)))?;
+            if !output.status.success() {
+                let e = command_error("sh", &output);
+                return Err(UpdateError::Validation(format!("{}: {}", command, e)));
+            }
+        }
+        Ok(release)
+    }
+
+    /// Copy the deployment's kernel to the ESP and write its boot entry
+    fn install_entry(&self, target: &Path, deployment: &Deployment) -> Result<()> {
+        let (_, image, initramfs) = find_kernel(target)?;
+        let dir = self.config.boot_dir.join(ESP_DIR).join(&deployment.id);
+        fs::create_dir_all(&dir)?;
+        fs::copy(&image, dir.join("vmlinuz"))?;
+        fs::copy(&initramfs, dir.join("initramfs.img"))?;
+
+        let cmdline = match &self.config.cmdline {
+            Some(cmdline) => cmdline.clone(),
+            None => filter_cmdline(&fs::read_to_string("/proc/cmdline")?),
+        };
+        let entries = self.config.boot_dir.join("loader/entries");
+        fs::create_dir_all(&entries)?;
+        fs::write(entries.join(deployment.entry()), render_entry(deployment, &cmdline))?;
+        Ok(())
+    }
+
+    /// Delete the oldest deployments beyond the configured count
+    ///
+    /// The booted root, its parent and the new deployment are always kept.
+    fn prune(&self, staged: &Deployment) -> Result<()> {
+        let keep = self.config.keep.max(2);
+        let deployments = self.deployments()?;
+        let excess = deployments.len().saturating_sub(keep);
+        for deployment in deployments
+            .iter()
+            .filter(|d| d.id != staged.id && d.subvolume != staged.parent)
+            .take(excess)
+        {
+            info!("Removing old deployment {}", deployment.id);
+            self.remove(deployment);
+        }
+        Ok(())
+    }
+
+    /// Delete a deployment's subvolume, metadata and boot files, as far as they exist
+    fn remove(&self, deployment: &Deployment) {
+        let subvolume = self.toplevel.join(&deployment.subvolume);
+        if subvolume.exists() {
+            if let Err(e) = delete_subvolume(&subvolume) {
+                warn!("Failed to delete {}: {}", subvolume.display(), e);
+            }
+        }
+        let _ = fs::remove_file(self.metadata_path(&deployment.id));
+        let _ = fs::remove_file(self.config.boot_dir.join("loader/entries").join(deployment.entry()));
+        let _ = fs::remove_dir_all(self.config.boot_dir.join(ESP_DIR).join(&deployment.id));
+    }
+
+    /// Recorded deployments, oldest first
+    fn deployments(&self) -> Result<Vec<Deployment>> {
+        let mut deployments = Vec::new();
+        let dir = self.toplevel.join(DEPLOYMENTS_SUBVOLUME);
+        if !dir.exists() {
+            return Ok(deployments);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                deployments.push(serde_json::from_slice::<Deployment>(&fs::read(&path)?)?);
+            }
+        }
+        deployments.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(deployments)
+    }
+
+    fn metadata_path(&self, id: &str) -> PathBuf {
+        self.toplevel.join(DEPLOYMENTS_SUBVOLUME).join(format!("{}.json", id))
+    }
+
+    /// Boot entry of a root: its deployment entry, or the installer's for `@`
+    fn default_entry_for(&self, subvolume: &str, deployments: &[Deployment]) -> String {
+        deployments
+            .iter()
+            .find(|d| d.subvolume == subvolume)
+            .map(Deployment::entry)
+            .unwrap_or_else(|| BASE_ENTRY.to_string())
+    }
+}
+
+/// Mount of the Btrfs top level, unmounted when dropped
+pub(crate) struct ToplevelMount<'a> {
+    path: &'a Path,
+}
+
+impl<'a> ToplevelMount<'a> {
+    pub(crate) fn mount(device: &str, path: &'a Path) -> Result<Self> {
+        crate::sys::mount::mount(device, path, "btrfs", Some("subvolid=5"))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ToplevelMount<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = crate::sys::mount::unmount(self.path) {
+            warn!("Failed to unmount {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Device and subvolume of the running root
+pub(crate) fn root_mount() -> Result<(String, String)> {
+    let output = Command::new("findmnt")
+        .args(["--noheadings", "--output", "FSTYPE,SOURCE,FSROOT", "/"])
+        .output()?;
+    if !output.status.success() {
+        return Err(command_error("findmnt", &output));
+    }
+    parse_root_mount(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse `findmnt --output FSTYPE,SOURCE,FSROOT`
+fn parse_root_mount(output: &str) -> Result<(String, String)> {
+    let fields: Vec<_> = output.split_whitespace().collect();
+    match fields.as_slice() {
+        ["btrfs", source, fsroot] => {
+            // findmnt appends the subvolume to the source: /dev/sda2[/@]
+            let device = source.split('[').next().unwrap_or(source);
+            let subvolume = fsroot.trim_start_matches('/');
+            let subvolume = if subvolume.is_empty() { ROOT_SUBVOLUME } else { subvolume };
+            Ok((device.to_string(), subvolume.to_string()))
+        }
+        [fstype, ..] => Err(UpdateError::Unsupported(format!("root filesystem is {}, not Btrfs", fstype))),
+        [] => Err(UpdateError::Unsupported("cannot find the root filesystem".to_string())),
+    }
+}
+
+/// Kernel release, image and initramfs installed in a root
+///
+/// Arch kernel packages keep the image in `/usr/lib/modules/<release>/vmlinuz`
+/// and name it after the package in `pkgbase`; mkinitcpio writes the
+/// initramfs to `/boot/initramfs-<pkgbase>.img`. With several kernels the
+/// newest release is used.
+fn find_kernel(root: &Path) -> Result<(String, PathBuf, PathBuf)> {
+    let modules = root.join("usr/lib/modules");
+    let mut releases: Vec<String> = fs::read_dir(&modules)
+        .map_err(|e| UpdateError::Validation(format!("{}: {}", modules.display(), e)))?
+        .flatten()
+        .filter(|entry| entry.path().join("vmlinuz").exists())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    releases.sort_by(|a, b| crate::package::vercmp(&a.replace('-', "."), &b.replace('-', ".")));
+    let release = releases
+        .pop()
+        .ok_or_else(|| UpdateError::Validation("no kernel installed".to_string()))?;
+
+    let dir = modules.join(&release);
+    let pkgbase = fs::read_to_string(dir.join("pkgbase")).unwrap_or_else(|_| "linux".to_string());
+    let initramfs = root.join("boot").join(format!("initramfs-{}.img", pkgbase.trim()));
+    Ok((release, dir.join("vmlinuz"), initramfs))
+}
+
+/// Render the boot entry of a deployment
+fn render_entry(deployment: &Deployment, cmdline: &str) -> String {
+    format!(
+        "title   rastOS (update {})\nversion {}\nlinux   /{dir}/{id}/vmlinuz\ninitrd  /{dir}/{id}/initramfs.img\noptions {}\n",
+        deployment.id,
+        deployment.kernel,
+        snapshot_cmdline(cmdline, &deployment.subvolume),
+        dir = ESP_DIR,
+        id = deployment.id,
+    )
+}
+
+/// The `default` of systemd-boot's `loader.conf`
+fn read_default(boot_dir: &Path) -> Option<String> {
+    fs::read_to_string(boot_dir.join("loader/loader.conf"))
+        .ok()?
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix("default ").map(|s| s.trim().to_string()))
+}
+
+/// Make an entry the systemd-boot default
+pub(crate) fn set_default(boot_dir: &Path, entry: &str) -> Result<()> {
+    if !boot_dir.join("loader/entries").join(entry).exists() {
+        return Err(UpdateError::UnknownDeployment(format!("no boot entry {}", entry)));
+    }
+    let loader_conf = boot_dir.join("loader/loader.conf");
+    let existing = fs::read_to_string(&loader_conf).unwrap_or_default();
+    let mut lines: Vec<&str> = existing
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("default "))
+        .collect();
+    let default = format!("default {}", entry);
+    lines.insert(0, &default);
+    fs::write(&loader_conf, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+pub(crate) fn delete_subvolume(path: &Path) -> Result<()> {
+    Ok(btrfs::delete_subvolume(path, true)?)
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    debug!("Running: {:?}", command);
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(command_error(&command.get_program().to_string_lossy(), &output));
+    }
+    Ok(())
+}
+
+fn command_error(program: &str, output: &std::process::Output) -> UpdateError {
+    UpdateError::Command {
+        program: program.to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn deployment() -> Deployment {
+        Deployment {
+            id: "20240601030000".to_string(),
+            subvolume: "@deployments/20240601030000".to_string(),
+            parent: "@".to_string(),
+            kernel: "6.9.3-arch1-1".to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_parse_root_mount() {
+        let (device, subvolume) = parse_root_mount("btrfs /dev/nvme0n1p2[/@] /@\n").unwrap();
+        assert_eq!(device, "/dev/nvme0n1p2");
+        assert_eq!(subvolume, "@");
+        let (_, subvolume) = parse_root_mount("btrfs /dev/sda2[/@deployments/1] /@deployments/1\n").unwrap();
+        assert_eq!(subvolume, "@deployments/1");
+        assert!(matches!(parse_root_mount("ext4 /dev/sda2 /\n"), Err(UpdateError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_entry_and_default() {
+        let entry = render_entry(&deployment(), "root=UUID=abc rw rootflags=subvol=@,compress=zstd quiet");
+        assert!(entry.contains("linux   /rastos/20240601030000/vmlinuz\n"));
+        assert!(entry.contains(
+            "options root=UUID=abc rw rootflags=compress=zstd quiet rastos.snapshot=@deployments/20240601030000\n"
+        ));
+
+        let esp = tempdir().unwrap();
+        fs::create_dir_all(esp.path().join("loader/entries")).unwrap();
+        fs::write(esp.path().join("loader/loader.conf"), "default rastos.conf\ntimeout 3\n").unwrap();
+        assert!(set_default(esp.path(), &deployment().entry()).is_err());
+
+        fs::write(esp.path().join("loader/entries").join(deployment().entry()), entry).unwrap();
+        set_default(esp.path(), &deployment().entry()).unwrap();
+        assert_eq!(read_default(esp.path()).as_deref(), Some("rastos-deploy-20240601030000.conf"));
+        assert!(fs::read_to_string(esp.path().join("loader/loader.conf")).unwrap().ends_with("timeout 3\n"));
+    }
+
+    #[test]
+    fn test_find_kernel() {
+        let root = tempdir().unwrap();
+        for release in ["6.9.3-arch1-1", "6.10.1-arch1-1"] {
+            let dir = root.path().join("usr/lib/modules").join(release);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("vmlinuz"), release).unwrap();
+            fs::write(dir.join("pkgbase"), "linux\n").unwrap();
+        }
+        let (release, image, initramfs) = find_kernel(root.path()).unwrap();
+        assert_eq!(release, "6.10.1-arch1-1");
+        assert!(image.ends_with("6.10.1-arch1-1/vmlinuz"));
+        assert_eq!(initramfs, root.path().join("boot/initramfs-linux.img"));
+    }
+}
